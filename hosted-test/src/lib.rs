@@ -0,0 +1,19 @@
+//! Host build of the hypervisor parts that touch no hardware
+//!
+//! The guest page walker and the instruction decoder are included from `src/` as they
+//! are, the rest of the hypervisor only builds for its target. The tests in `tests/`
+//! run them against synthetic page tables and encodings:
+//!
+//! `make hosted_test`, or `cargo test --target <host triple>` in this directory, the
+//! hypervisor's `.cargo/config.toml` would build for riscv otherwise.
+
+/// the constants of `src/constants.rs` the included sources use
+pub mod constants {
+    pub const PAGE_SIZE: usize = 0x1000;
+    pub const PAGE_SIZE_BITS: usize = 0xc;
+}
+
+pub mod page_table;
+
+#[path = "../../src/guest/decode.rs"]
+pub mod decode;
@@ -0,0 +1,13 @@
+//! `src/page_table` without the page tables of the hypervisor itself
+
+#[path = "../../../src/page_table/address.rs"]
+#[allow(dead_code)]
+mod address;
+#[path = "../../../src/page_table/pte.rs"]
+mod pte;
+#[path = "../../../src/page_table/walk.rs"]
+mod walk;
+
+pub use pte::{ PTEFlags, PageTableEntry };
+pub use address::PhysPageNum;
+pub use walk::{ AccessType, Privilege, WalkContext, WalkFault, GuestTranslation, walk_guest };
@@ -0,0 +1,28 @@
+//! `decode_inst` on raw encodings
+
+use hypocaust_2_hosted_test::decode::decode_inst;
+use riscv_decode::Instruction;
+
+#[test]
+fn full_size() {
+    // ld a0, 8(a1)
+    let (len, inst) = decode_inst(0x0085_b503);
+    assert_eq!(len, 4);
+    match inst {
+        Some(Instruction::Ld(i)) => assert_eq!((i.rd(), i.rs1(), i.imm()), (10, 11, 8)),
+        other => panic!("decoded to {:?}", other)
+    }
+    assert!(matches!(decode_inst(0x1050_0073), (4, Some(Instruction::Wfi))));
+}
+
+#[test]
+fn compressed_ignores_upper_half() {
+    // c.nop with garbage from the next instruction above it
+    assert_eq!(decode_inst(0xdead_0001).0, 2);
+}
+
+#[test]
+fn longer_encodings() {
+    // 48-bit encoding, no supported extension uses them
+    assert!(matches!(decode_inst(0x001f), (6, None)));
+}
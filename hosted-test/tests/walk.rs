@@ -0,0 +1,151 @@
+//! `walk_guest` against synthetic two-stage page tables
+//!
+//! Guest ram is `RAM`, stage 2 maps it to the host at `HOST_OFFSET` and nothing else.
+//! The VS-stage tables are Sv39 with the root at `ROOT`, see `guest_memory`.
+
+use std::collections::HashMap;
+use std::ops::Range;
+
+use hypocaust_2_hosted_test::page_table::{ AccessType, Privilege, WalkContext, WalkFault, GuestTranslation, walk_guest };
+
+const RAM: Range<usize> = 0x8000_0000..0x8100_0000;
+const HOST_OFFSET: usize = 0x1_0000_0000;
+const ROOT: usize = 0x8000_0000;
+const L1: usize = 0x8000_1000;
+const L0: usize = 0x8000_2000;
+/// outside guest ram, stage 2 faults
+const HOLE: usize = 0x9000_0000;
+
+const V: usize = 1 << 0;
+const R: usize = 1 << 1;
+const W: usize = 1 << 2;
+const X: usize = 1 << 3;
+const U: usize = 1 << 4;
+const A: usize = 1 << 6;
+const D: usize = 1 << 7;
+
+const SV39: usize = 8 << 60;
+
+fn pte(pa: usize, flags: usize) -> usize {
+    (pa >> 12) << 10 | flags
+}
+
+/// guest physical address of each VS-stage entry and its value
+fn guest_memory() -> HashMap<usize, usize> {
+    let entry = |table: usize, index: usize| table + index * 8;
+    HashMap::from([
+        // 0x4000_0000.. through L1 and L0
+        (entry(ROOT, 1), pte(L1, V)),
+        (entry(L1, 0), pte(L0, V)),
+        (entry(L0, 1), pte(0x8040_0000, V | R | W | X | A | D)),
+        (entry(L0, 2), pte(0x8040_1000, V | R | W | U | A | D)),
+        (entry(L0, 3), pte(0x8040_2000, V | X | A)),
+        (entry(L0, 4), pte(HOLE, V | R | W | A | D)),
+        (entry(L0, 5), pte(0x8040_3000, V | R | W)),
+        (entry(L0, 6), pte(0x8040_4000, V | R | W | A)),
+        // 2 MiB pages at 0x4020_0000, the second one misaligned
+        (entry(L1, 1), pte(0x8060_0000, V | R | A)),
+        (entry(L1, 2), pte(0x8060_1000, V | R | A)),
+        // 0x8000_0000.. has its next table outside guest ram
+        (entry(ROOT, 2), pte(HOLE, V)),
+    ])
+}
+
+fn supervisor(vsatp: usize) -> WalkContext {
+    WalkContext { vsatp, privilege: Privilege::Supervisor, sum: false, mxr: false }
+}
+
+fn walk(guest_va: usize, access: AccessType, walk: &WalkContext) -> Result<GuestTranslation, WalkFault> {
+    let memory = guest_memory();
+    let stage2 = |guest_pa: usize, _| if RAM.contains(&guest_pa) { Some(guest_pa + HOST_OFFSET) } else { None };
+    let read_pte = |host_pa: usize| memory.get(&(host_pa - HOST_OFFSET)).copied().unwrap_or(0);
+    walk_guest(guest_va, access, walk, stage2, read_pte)
+}
+
+fn guest_pa(result: Result<GuestTranslation, WalkFault>) -> Result<usize, WalkFault> {
+    result.map(|translation| translation.guest_pa)
+}
+
+#[test]
+fn bare_is_stage2_only() {
+    let ctx = supervisor(0);
+    let translation = walk(0x8000_0123, AccessType::Write, &ctx).unwrap();
+    assert_eq!(translation.host_pa, 0x8000_0123 + HOST_OFFSET);
+    assert!(translation.pte.is_none());
+    assert_eq!(walk(HOLE, AccessType::Read, &ctx).unwrap_err(), WalkFault::GuestPageFault { guest_pa: HOLE, implicit: false });
+}
+
+#[test]
+fn sv39_page() {
+    let translation = walk(0x4000_1234, AccessType::Read, &supervisor(SV39 | ROOT >> 12)).unwrap();
+    assert_eq!(translation.guest_pa, 0x8040_0234);
+    assert_eq!(translation.host_pa, 0x8040_0234 + HOST_OFFSET);
+    assert_eq!(translation.level, 0);
+}
+
+#[test]
+fn superpages() {
+    let ctx = supervisor(SV39 | ROOT >> 12);
+    let translation = walk(0x4020_1234, AccessType::Read, &ctx).unwrap();
+    assert_eq!((translation.guest_pa, translation.level), (0x8060_1234, 1));
+    assert_eq!(guest_pa(walk(0x4020_1234, AccessType::Write, &ctx)), Err(WalkFault::PageFault));
+    // a superpage must be aligned to its size
+    assert_eq!(guest_pa(walk(0x4040_0000, AccessType::Read, &ctx)), Err(WalkFault::PageFault));
+}
+
+#[test]
+fn unmapped_and_noncanonical() {
+    let ctx = supervisor(SV39 | ROOT >> 12);
+    assert_eq!(guest_pa(walk(0x4000_7000, AccessType::Read, &ctx)), Err(WalkFault::PageFault));
+    assert_eq!(guest_pa(walk(0xc000_0000, AccessType::Read, &ctx)), Err(WalkFault::PageFault));
+    // bit 38 clear but bits above it set
+    assert_eq!(guest_pa(walk(0xff00_0000_4000_1000, AccessType::Read, &ctx)), Err(WalkFault::PageFault));
+    // unknown vsatp mode
+    assert_eq!(guest_pa(walk(0x4000_1000, AccessType::Read, &supervisor(1 << 60 | ROOT >> 12))), Err(WalkFault::PageFault));
+}
+
+#[test]
+fn user_pages() {
+    let ctx = supervisor(SV39 | ROOT >> 12);
+    let sum = WalkContext { sum: true, ..ctx };
+    let user = ctx.with_privilege(Privilege::User);
+    assert_eq!(guest_pa(walk(0x4000_2000, AccessType::Read, &ctx)), Err(WalkFault::PageFault));
+    assert_eq!(guest_pa(walk(0x4000_2000, AccessType::Read, &sum)), Ok(0x8040_1000));
+    assert_eq!(guest_pa(walk(0x4000_2000, AccessType::Execute, &sum)), Err(WalkFault::PageFault));
+    assert_eq!(guest_pa(walk(0x4000_2000, AccessType::Write, &user)), Ok(0x8040_1000));
+    // supervisor pages are not reachable from user mode
+    assert_eq!(guest_pa(walk(0x4000_1000, AccessType::Read, &user)), Err(WalkFault::PageFault));
+}
+
+#[test]
+fn execute_only() {
+    let ctx = supervisor(SV39 | ROOT >> 12);
+    let mxr = WalkContext { mxr: true, ..ctx };
+    assert_eq!(guest_pa(walk(0x4000_3000, AccessType::Read, &ctx)), Err(WalkFault::PageFault));
+    assert_eq!(guest_pa(walk(0x4000_3000, AccessType::Read, &mxr)), Ok(0x8040_2000));
+    assert_eq!(guest_pa(walk(0x4000_3000, AccessType::Execute, &ctx)), Ok(0x8040_2000));
+}
+
+#[test]
+fn accessed_and_dirty() {
+    let ctx = supervisor(SV39 | ROOT >> 12);
+    assert_eq!(guest_pa(walk(0x4000_5000, AccessType::Read, &ctx)), Err(WalkFault::PageFault));
+    assert_eq!(guest_pa(walk(0x4000_6000, AccessType::Read, &ctx)), Ok(0x8040_4000));
+    assert_eq!(guest_pa(walk(0x4000_6000, AccessType::Write, &ctx)), Err(WalkFault::PageFault));
+}
+
+#[test]
+fn stage2_faults() {
+    let ctx = supervisor(SV39 | ROOT >> 12);
+    assert_eq!(guest_pa(walk(0x4000_4000, AccessType::Read, &ctx)), Err(WalkFault::GuestPageFault { guest_pa: HOLE, implicit: false }));
+    // the next table is read at entry 0 of the table outside guest ram
+    assert_eq!(guest_pa(walk(0x8000_0000, AccessType::Read, &ctx)), Err(WalkFault::GuestPageFault { guest_pa: HOLE, implicit: true }));
+}
+
+#[test]
+fn context_from_csrs() {
+    let ctx = WalkContext::from_csrs(SV39, 1 << 18, 1 << 8);
+    assert_eq!((ctx.privilege, ctx.sum, ctx.mxr), (Privilege::Supervisor, true, false));
+    let ctx = WalkContext::from_csrs(SV39, 1 << 19, 0);
+    assert_eq!((ctx.privilege, ctx.sum, ctx.mxr), (Privilege::User, false, true));
+}
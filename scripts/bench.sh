@@ -0,0 +1,4 @@
+riscv64-unknown-elf-gcc -nostdlib -march=rv64gc -mabi=lp64d -T ./guest/bench/linker.ld -o ./guest/bench/bench.elf ./guest/bench/bench.S
+cp ./guest/bench/bench.elf ./guest.elf
+rust-objcopy --binary-architecture=riscv64 --strip-all -O binary ./guest/bench/bench.elf ./guest.bin
+dtc -I dts -O dtb -o ./guest.dtb ./guest/rCore-Tutorial-v3/rCore-Tutorial-v3.dts
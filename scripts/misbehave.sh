@@ -0,0 +1,4 @@
+riscv64-unknown-elf-gcc -nostdlib -march=rv64gc -mabi=lp64d -T ./guest/misbehave/linker.ld -o ./guest/misbehave/misbehave.elf ./guest/misbehave/misbehave.S
+cp ./guest/misbehave/misbehave.elf ./guest.elf
+rust-objcopy --binary-architecture=riscv64 --strip-all -O binary ./guest/misbehave/misbehave.elf ./guest.bin
+dtc -I dts -O dtb -o ./guest.dtb ./guest/rCore-Tutorial-v3/rCore-Tutorial-v3.dts
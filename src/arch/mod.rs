@@ -0,0 +1,226 @@
+//! Architecture layer
+//!
+//! The mm, guest and device_emu layers reach hart state that has no portable form, TLBs,
+//! caches, the stage-2 root, the trap entries, trap causes and interrupt lines, through
+//! this module instead of inline assembly. `Arch` lists what a virtualization backend
+//! provides, `riscv64` implements it with the H extension, and the free functions forward
+//! to the backend of the target. A LoongArch backend on LVZ adds `loongarch64` with the
+//! same trait, the stage-2 root then being the GPA table of the guest, the TLB operations
+//! `invtlb` flavours and the guest interrupt lines `GINTC`.
+//!
+//! Traps are decoded here into `GuestExit` and `HostTrap`, the raw cause is only kept to
+//! be forwarded to the guest or reported. Still RISC-V specific above this layer: the SBI
+//! emulation, the world switch in `guest/guest.S`, the VS CSRs saved with a vcpu, and the
+//! emulation of RISC-V state, the firmware of `guest::vmachine`, counters, triggers and
+//! `henvcfg`.
+
+#[cfg(target_arch = "riscv64")]
+mod riscv64;
+#[cfg(target_arch = "riscv64")]
+pub use riscv64::Riscv64 as Current;
+
+use crate::page_table::AccessType;
+
+/// interrupt lines of a hart, of the hypervisor or of a virtual hart
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Irq {
+    /// inter-processor interrupt
+    Software,
+    Timer,
+    External,
+    /// a performance counter overflowed
+    CounterOverflow
+}
+
+/// why a guest left the cpu
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum GuestExit {
+    /// call of the guest kernel into the hypervisor, SBI on RISC-V
+    Hypercall,
+    /// system call of guest user code, never delegated to the hypervisor
+    UserCall,
+    /// privileged instruction the hardware leaves to the hypervisor
+    VirtualInstruction,
+    /// instruction the hart does not implement, e.g. of a guest on its own firmware
+    IllegalInstruction,
+    /// no stage-2 translation for the access
+    GuestPageFault(AccessType),
+    /// misaligned load or store the hart does not handle
+    Misaligned(AccessType),
+    Irq(Irq),
+    /// exceptions the guest handles itself, forwarded with their raw cause
+    Other
+}
+
+/// trap the hypervisor took itself
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HostTrap {
+    Irq(Irq),
+    /// load or store without a valid translation
+    PageFault,
+    /// load or store rejected by physical memory protection
+    AccessFault,
+    Breakpoint,
+    Other
+}
+
+/// raw cause, pc and faulting address of a trap, as the hart reported it
+#[derive(Clone, Copy, Debug)]
+pub struct TrapInfo {
+    pub cause: usize,
+    pub pc: usize,
+    pub addr: usize
+}
+
+/// exceptions the hypervisor raises in a guest
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum GuestException {
+    IllegalInstruction,
+    FetchAccessFault,
+    LoadAccessFault,
+    StoreAccessFault,
+    LoadPageFault,
+    StorePageFault
+}
+
+pub trait Arch {
+    /// name of the architecture, as shown in reports
+    const NAME: &'static str;
+    /// drop every cached guest-physical to host-physical translation of the hart
+    fn flush_stage2_tlb();
+    /// drop the cached guest-virtual translations of the guest on the hart
+    fn flush_guest_tlb();
+    /// drop the cached translations of the hypervisor
+    fn flush_host_tlb();
+    /// drop the cached translation of the hypervisor page at `va`
+    fn flush_host_page(va: usize);
+    /// make instructions written to memory visible to instruction fetch
+    fn sync_icache();
+    /// wait until an interrupt is pending
+    fn wait_for_interrupt();
+    /// load the stage-2 root `token` of a guest, `PageTable::token` of its table,
+    /// the stage-2 TLB is flushed as entries of the previous guest may remain
+    fn load_stage2(token: usize);
+    /// take traps of the hypervisor at `entry`, on the stack below `stack_top`
+    fn set_host_trap_entry(entry: usize, stack_top: usize);
+    /// take traps of the guest at the vector table `vectors`
+    fn set_guest_trap_entry(vectors: usize);
+    /// decode the raw `cause` of a guest trap
+    fn guest_exit(cause: usize) -> GuestExit;
+    /// the trap the hypervisor is handling
+    fn host_trap() -> (HostTrap, TrapInfo);
+    /// raw cause of `exception`
+    fn exception_cause(exception: GuestException) -> usize;
+    /// enter the trap handler of the guest kernel with the raw `cause` and `tval` as if
+    /// the guest trapped at `pc`, returns the pc of the handler
+    fn enter_guest_handler(pc: usize, cause: usize, tval: usize) -> usize;
+    /// let `irq` reach the hypervisor
+    fn unmask_irq(irq: Irq);
+    fn mask_irq(irq: Irq);
+    /// `irq` of the hypervisor is pending
+    fn irq_raised(irq: Irq) -> bool;
+    /// drop a pending `irq` of the hypervisor, software interrupts only clear this way
+    fn clear_irq(irq: Irq);
+    /// make `irq` pending in the running guest
+    fn raise_guest_irq(irq: Irq);
+    fn lower_guest_irq(irq: Irq);
+    /// pending interrupt lines of the running guest, saved with its vcpu
+    fn guest_irqs() -> usize;
+    fn set_guest_irqs(lines: usize);
+    /// bit of `irq` in the lines of `guest_irqs`, for harts not on the cpu
+    fn guest_irq_line(irq: Irq) -> usize;
+    /// an unmasked interrupt of the hypervisor or an interrupt of the guest is pending
+    fn irq_pending() -> bool;
+}
+
+pub fn flush_stage2_tlb() {
+    Current::flush_stage2_tlb()
+}
+
+pub fn flush_guest_tlb() {
+    Current::flush_guest_tlb()
+}
+
+pub fn flush_host_tlb() {
+    Current::flush_host_tlb()
+}
+
+pub fn flush_host_page(va: usize) {
+    Current::flush_host_page(va)
+}
+
+pub fn sync_icache() {
+    Current::sync_icache()
+}
+
+pub fn wait_for_interrupt() {
+    Current::wait_for_interrupt()
+}
+
+pub fn load_stage2(token: usize) {
+    Current::load_stage2(token)
+}
+
+pub fn set_host_trap_entry(entry: usize, stack_top: usize) {
+    Current::set_host_trap_entry(entry, stack_top)
+}
+
+pub fn set_guest_trap_entry(vectors: usize) {
+    Current::set_guest_trap_entry(vectors)
+}
+
+pub fn guest_exit(cause: usize) -> GuestExit {
+    Current::guest_exit(cause)
+}
+
+pub fn host_trap() -> (HostTrap, TrapInfo) {
+    Current::host_trap()
+}
+
+pub fn exception_cause(exception: GuestException) -> usize {
+    Current::exception_cause(exception)
+}
+
+pub fn enter_guest_handler(pc: usize, cause: usize, tval: usize) -> usize {
+    Current::enter_guest_handler(pc, cause, tval)
+}
+
+pub fn unmask_irq(irq: Irq) {
+    Current::unmask_irq(irq)
+}
+
+pub fn mask_irq(irq: Irq) {
+    Current::mask_irq(irq)
+}
+
+pub fn irq_raised(irq: Irq) -> bool {
+    Current::irq_raised(irq)
+}
+
+pub fn clear_irq(irq: Irq) {
+    Current::clear_irq(irq)
+}
+
+pub fn raise_guest_irq(irq: Irq) {
+    Current::raise_guest_irq(irq)
+}
+
+pub fn lower_guest_irq(irq: Irq) {
+    Current::lower_guest_irq(irq)
+}
+
+pub fn guest_irqs() -> usize {
+    Current::guest_irqs()
+}
+
+pub fn set_guest_irqs(lines: usize) {
+    Current::set_guest_irqs(lines)
+}
+
+pub fn guest_irq_line(irq: Irq) -> usize {
+    Current::guest_irq_line(irq)
+}
+
+pub fn irq_pending() -> bool {
+    Current::irq_pending()
+}
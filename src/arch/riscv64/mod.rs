@@ -0,0 +1,210 @@
+//! RISC-V backend, on the H extension
+
+use core::arch::{ asm, global_asm };
+use riscv::register::{ hgatp, scause, sepc, sscratch, stval, stvec, vstvec };
+
+use super::{ Arch, GuestException, GuestExit, HostTrap, Irq, TrapInfo };
+use crate::errata::{ self, Workarounds };
+use crate::page_table::AccessType;
+
+/// interrupt bit of `scause`
+const INTERRUPT: usize = 1 << (usize::BITS - 1);
+/// interrupts of the guest in `hvip`: VSSIP, VSTIP and VSEIP
+const HVIP_VS: usize = 1 << 2 | 1 << 6 | 1 << 10;
+
+/// interrupt code of `irq`, its bit in `sie` and `sip`
+fn irq_code(irq: Irq) -> usize {
+    match irq {
+        Irq::Software => 1,
+        Irq::Timer => 5,
+        Irq::External => 9,
+        Irq::CounterOverflow => 13
+    }
+}
+
+fn irq_of(code: usize) -> Option<Irq> {
+    match code {
+        1 => Some(Irq::Software),
+        5 => Some(Irq::Timer),
+        9 => Some(Irq::External),
+        13 => Some(Irq::CounterOverflow),
+        _ => None
+    }
+}
+
+/// `hfence.vvma` after `hfence.gvma` on cores where the latter leaves guest translations
+fn hfence_erratum() {
+    if errata::active(Workarounds::HFENCE) {
+        unsafe{ core::arch::riscv64::hfence_vvma_all() };
+    }
+}
+
+// trap entry and return of guests and of the hypervisor
+global_asm!(include_str!("trap.S"));
+
+pub struct Riscv64;
+
+impl Arch for Riscv64 {
+    const NAME: &'static str = "riscv64";
+
+    fn flush_stage2_tlb() {
+        unsafe{ core::arch::riscv64::hfence_gvma_all() };
+        hfence_erratum();
+    }
+
+    fn flush_guest_tlb() {
+        // the guest TLB is tagged with its VMID, `hfence.vvma` only flushes the running guest
+        unsafe{ core::arch::riscv64::hfence_vvma_all() };
+    }
+
+    fn flush_host_tlb() {
+        unsafe{ core::arch::riscv64::sfence_vma_all() };
+    }
+
+    fn flush_host_page(va: usize) {
+        unsafe{ core::arch::riscv64::sfence_vma_vaddr(va) };
+    }
+
+    fn sync_icache() {
+        unsafe{ asm!("fence.i") };
+    }
+
+    fn wait_for_interrupt() {
+        unsafe{ asm!("wfi") };
+    }
+
+    fn load_stage2(token: usize) {
+        let hgatp = hgatp::Hgatp::from_bits(token);
+        unsafe{
+            hgatp.write();
+            core::arch::riscv64::hfence_gvma_all();
+        }
+        hfence_erratum();
+        debug_assert_eq!(hgatp.bits(), hgatp::read().bits());
+    }
+
+    fn set_host_trap_entry(entry: usize, stack_top: usize) {
+        unsafe{
+            stvec::write(entry, stvec::TrapMode::Direct);
+            sscratch::write(stack_top);
+        }
+    }
+
+    fn set_guest_trap_entry(vectors: usize) {
+        unsafe{ stvec::write(vectors, stvec::TrapMode::Vectored) };
+    }
+
+    fn guest_exit(cause: usize) -> GuestExit {
+        if cause & INTERRUPT != 0 {
+            return irq_of(cause & !INTERRUPT).map_or(GuestExit::Other, GuestExit::Irq)
+        }
+        match cause {
+            2 => GuestExit::IllegalInstruction,
+            4 => GuestExit::Misaligned(AccessType::Read),
+            6 => GuestExit::Misaligned(AccessType::Write),
+            8 => GuestExit::UserCall,
+            10 => GuestExit::Hypercall,
+            20 => GuestExit::GuestPageFault(AccessType::Execute),
+            21 => GuestExit::GuestPageFault(AccessType::Read),
+            22 => GuestExit::VirtualInstruction,
+            23 => GuestExit::GuestPageFault(AccessType::Write),
+            _ => GuestExit::Other
+        }
+    }
+
+    fn host_trap() -> (HostTrap, TrapInfo) {
+        let cause = scause::read().bits();
+        let trap = if cause & INTERRUPT != 0 {
+            irq_of(cause & !INTERRUPT).map_or(HostTrap::Other, HostTrap::Irq)
+        }else{
+            match cause {
+                3 => HostTrap::Breakpoint,
+                5 | 7 => HostTrap::AccessFault,
+                13 | 15 => HostTrap::PageFault,
+                _ => HostTrap::Other
+            }
+        };
+        (trap, TrapInfo { cause, pc: sepc::read(), addr: stval::read() })
+    }
+
+    fn exception_cause(exception: GuestException) -> usize {
+        match exception {
+            GuestException::FetchAccessFault => 1,
+            GuestException::IllegalInstruction => 2,
+            GuestException::LoadAccessFault => 5,
+            GuestException::StoreAccessFault => 7,
+            GuestException::LoadPageFault => 13,
+            GuestException::StorePageFault => 15
+        }
+    }
+
+    fn enter_guest_handler(pc: usize, cause: usize, tval: usize) -> usize {
+        unsafe{
+            asm!(
+                "csrw vsepc, {sepc}",
+                "csrw vscause, {scause}",
+                "csrw vstval, {stval}",
+                sepc = in(reg) pc,
+                scause = in(reg) cause,
+                stval = in(reg) tval
+            )
+        }
+        // exceptions enter at the base of a vectored `vstvec` too
+        vstvec::read().bits() & !0b11
+    }
+
+    fn unmask_irq(irq: Irq) {
+        unsafe{ asm!("csrs sie, {}", in(reg) 1usize << irq_code(irq)) };
+    }
+
+    fn mask_irq(irq: Irq) {
+        unsafe{ asm!("csrc sie, {}", in(reg) 1usize << irq_code(irq)) };
+    }
+
+    fn irq_raised(irq: Irq) -> bool {
+        let sip: usize;
+        unsafe{ asm!("csrr {}, sip", out(reg) sip) };
+        sip & 1 << irq_code(irq) != 0
+    }
+
+    fn clear_irq(irq: Irq) {
+        unsafe{ asm!("csrc sip, {}", in(reg) 1usize << irq_code(irq)) };
+    }
+
+    fn raise_guest_irq(irq: Irq) {
+        unsafe{ asm!("csrs hvip, {}", in(reg) Self::guest_irq_line(irq)) };
+    }
+
+    fn lower_guest_irq(irq: Irq) {
+        unsafe{ asm!("csrc hvip, {}", in(reg) Self::guest_irq_line(irq)) };
+    }
+
+    fn guest_irqs() -> usize {
+        let hvip: usize;
+        unsafe{ asm!("csrr {}, hvip", out(reg) hvip) };
+        hvip
+    }
+
+    fn set_guest_irqs(lines: usize) {
+        unsafe{ asm!("csrw hvip, {}", in(reg) lines) };
+    }
+
+    fn guest_irq_line(irq: Irq) -> usize {
+        match irq {
+            // VSSIP, VSTIP and VSEIP sit one above the supervisor bits
+            Irq::Software | Irq::Timer | Irq::External => 1 << (irq_code(irq) + 1),
+            // injected through `hvien`, see `guest::pmu`
+            Irq::CounterOverflow => 1 << 13
+        }
+    }
+
+    fn irq_pending() -> bool {
+        let (sip, sie, hvip): (usize, usize, usize);
+        unsafe{
+            asm!("csrr {}, sip", out(reg) sip);
+            asm!("csrr {}, sie", out(reg) sie);
+            asm!("csrr {}, hvip", out(reg) hvip);
+        }
+        sip & sie != 0 || hvip & HVIP_VS != 0
+    }
+}
@@ -0,0 +1,46 @@
+//! Sipeed Lichee RV, Allwinner D1
+//!
+//! One C906 hart, PLIC context 1 is its S-mode. The C906 has no H extension: the
+//! hypervisor stops at its check in `boot::early` unless the core is emulated.
+
+use crate::drivers::uart::UartKind;
+use super::{ ConsoleDesc, Platform };
+
+pub const CLOCK_FREQ: usize = 24_000_000;
+
+/// start of DRAM, 512M on the smallest board, the firmware takes the first 2M
+pub const MEMORY_START: usize = 0x4000_0000;
+/// end of the frames of the hypervisor
+pub const MEMORY_END: usize = 0x4800_0000;
+pub const GUEST_DTB_ADDR: usize = 0x5000_0000;
+pub const GUEST_START_PA: usize = 0x5020_0000;
+
+pub const MMIO: &[(usize, usize)] = &[];
+
+/// no PCIe
+pub const PCI_MMIO: (usize, usize) = (0, 0);
+
+pub struct LicheeRv;
+
+impl Platform for LicheeRv {
+    fn name(&self) -> &'static str {
+        "Lichee RV"
+    }
+
+    fn compatible(&self) -> &'static [&'static str] {
+        &["sipeed,lichee-rv", "allwinner,sun20i-d1"]
+    }
+
+    fn clock_freq(&self) -> usize {
+        CLOCK_FREQ
+    }
+
+    /// uart0, a DesignWare uart with 32-bit registers
+    fn console(&self) -> Option<ConsoleDesc> {
+        Some(ConsoleDesc { base: 0x0250_0000, kind: UartKind::Ns16550, reg_shift: 2 })
+    }
+
+    fn plic_s_contexts(&self) -> &'static [usize] {
+        &[1]
+    }
+}
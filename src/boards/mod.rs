@@ -0,0 +1,105 @@
+//! Boards the hypervisor runs on
+//!
+//! A board implements `Platform` with what the device tree of its firmware does not tell,
+//! or tells in a way the hypervisor cannot use: its console, its timer frequency, the
+//! layout of its PLIC and how it powers off and reboots. Porting to a new board means
+//! adding a module with its `Platform` and listing it in `BOARDS`.
+//!
+//! `board_visionfive2` or `board_licheerv` picks the board the hypervisor is built for,
+//! qemu `virt` without them. Its constants size the hypervisor and lay out its memory at
+//! compile time: `src/boards/<board>.ld`, which `make BOARD=<board>` picks, links the
+//! hypervisor 2M above `MEMORY_START`, the guest device tree at `GUEST_DTB_ADDR` and the
+//! guest kernel at `GUEST_START_PA`. At boot `init` picks the board whose `compatible`
+//! matches the root of the host device tree, the built one if none does, and warns when
+//! it is not the built one.
+
+use fdt::Fdt;
+use spin::Once;
+
+use crate::drivers::uart::UartKind;
+use crate::hypervisor::fdt::{ MachineMeta, SysconAction };
+
+mod qemu;
+mod visionfive2;
+mod licheerv;
+
+#[cfg(all(feature = "board_visionfive2", feature = "board_licheerv"))]
+compile_error!("the hypervisor is built for one board");
+
+#[cfg(feature = "board_visionfive2")]
+pub use visionfive2::{ VisionFive2 as Built, CLOCK_FREQ, MEMORY_START, MEMORY_END, GUEST_DTB_ADDR, GUEST_START_PA, MMIO, PCI_MMIO };
+#[cfg(feature = "board_licheerv")]
+pub use licheerv::{ LicheeRv as Built, CLOCK_FREQ, MEMORY_START, MEMORY_END, GUEST_DTB_ADDR, GUEST_START_PA, MMIO, PCI_MMIO };
+#[cfg(not(any(feature = "board_visionfive2", feature = "board_licheerv")))]
+pub use qemu::{ Qemu as Built, CLOCK_FREQ, MEMORY_START, MEMORY_END, GUEST_DTB_ADDR, GUEST_START_PA, MMIO, PCI_MMIO };
+
+/// maximum size of a device tree blob
+pub const MAX_DTB_SIZE: usize = 0x20_0000;
+
+/// polled uart of a board, see `drivers::uart`
+#[derive(Clone, Copy, Debug)]
+pub struct ConsoleDesc {
+    pub base: usize,
+    pub kind: UartKind,
+    /// register stride of ns16550 compatibles
+    pub reg_shift: usize
+}
+
+pub trait Platform: Sync {
+    fn name(&self) -> &'static str;
+
+    /// `compatible` strings of the root node of its device tree
+    fn compatible(&self) -> &'static [&'static str];
+
+    /// frequency of `time`
+    fn clock_freq(&self) -> usize;
+
+    /// console uart when the device tree names none the early console drives
+    fn console(&self) -> Option<ConsoleDesc> {
+        None
+    }
+
+    /// PLIC contexts of the S-mode of the harts, harts without S-mode have none
+    fn plic_s_contexts(&self) -> &'static [usize];
+
+    /// register powering the machine off without SBI SRST, base and write
+    fn poweroff(&self) -> Option<(usize, SysconAction)> {
+        None
+    }
+
+    /// register rebooting the machine without SBI SRST, base and write
+    fn reboot(&self) -> Option<(usize, SysconAction)> {
+        None
+    }
+
+    /// description of the machine when the device tree of the firmware is unusable
+    fn fallback_machine(&self) -> Option<MachineMeta> {
+        None
+    }
+}
+
+static BOARDS: &[&dyn Platform] = &[&qemu::Qemu, &visionfive2::VisionFive2, &licheerv::LicheeRv];
+
+static PLATFORM: Once<&'static dyn Platform> = Once::new();
+
+/// pick the board from the root `compatible` of the host device tree, needs no heap
+pub fn init(dtb: usize) {
+    let compatible = unsafe{ Fdt::from_ptr(dtb as *const u8) }.ok()
+        .and_then(|fdt| {
+            let root = fdt.find_node("/")?;
+            let compatible = root.compatible()?;
+            BOARDS.iter().copied().find(|board| compatible.all().any(|c| board.compatible().contains(&c)))
+        });
+    let board = *PLATFORM.call_once(|| compatible.unwrap_or(&Built));
+    if board.name() != Built.name() {
+        hwarning!("running on {} with a hypervisor built for {}", board.name(), Built.name());
+    }
+    if board.clock_freq() != CLOCK_FREQ {
+        hwarning!("{} counts time at {}Hz, built for {}Hz: time slices and guest timers are off", board.name(), board.clock_freq(), CLOCK_FREQ);
+    }
+}
+
+/// the board running the hypervisor, the built one before `init`
+pub fn platform() -> &'static dyn Platform {
+    PLATFORM.get().copied().unwrap_or(&Built)
+}
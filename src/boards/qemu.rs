@@ -1,8 +1,99 @@
-//! Constants used in rCore for qemu
+//! qemu `virt` machine
+
+use alloc::vec::Vec;
+use arrayvec::ArrayVec;
+use crate::hypervisor::fdt::{ CpuMeta, Device, MachineMeta, MemoryRegion, SysconAction };
+use super::Platform;
 
 pub const CLOCK_FREQ: usize = 12500000;
 
+/// start of ram, the firmware takes the first 2M
+pub const MEMORY_START: usize = 0x8000_0000;
+/// end of the frames of the hypervisor
+pub const MEMORY_END: usize = 0x8800_0000;
+pub const GUEST_DTB_ADDR: usize = 0x9000_0000;
+pub const GUEST_START_PA: usize = 0x9020_0000;
+
 pub const MMIO: &[(usize, usize)] = &[
     (0x0010_0000, 0x00_2000), // VIRT_TEST/RTC  in virt machine
     (0x1000_1000, 0x00_1000), // Virtio Block in virt machine
 ];
+
+/// 32-bit memory window of the PCIe host bridge (base, size)
+pub const PCI_MMIO: (usize, usize) = (0x4000_0000, 0x4000_0000);
+
+/// sifive test finisher
+const TEST_FINISHER: usize = 0x10_0000;
+const POWEROFF: SysconAction = SysconAction { offset: 0, value: 0x5555, mask: 0xffff_ffff };
+const REBOOT: SysconAction = SysconAction { offset: 0, value: 0x7777, mask: 0xffff_ffff };
+
+/// S-mode context of hart n is 2n + 1
+const PLIC_S_CONTEXTS: [usize; 16] = {
+    let mut contexts = [0; 16];
+    let mut hart = 0;
+    while hart < contexts.len() {
+        contexts[hart] = 2 * hart + 1;
+        hart += 1;
+    }
+    contexts
+};
+
+fn device(base_address: usize, size: usize, irq: Option<usize>) -> Device {
+    Device { base_address, size, irq, interrupt_parent: None }
+}
+
+pub struct Qemu;
+
+impl Platform for Qemu {
+    fn name(&self) -> &'static str {
+        "qemu virt"
+    }
+
+    fn compatible(&self) -> &'static [&'static str] {
+        &["riscv-virtio"]
+    }
+
+    fn clock_freq(&self) -> usize {
+        CLOCK_FREQ
+    }
+
+    fn plic_s_contexts(&self) -> &'static [usize] {
+        &PLIC_S_CONTEXTS
+    }
+
+    fn poweroff(&self) -> Option<(usize, SysconAction)> {
+        Some((TEST_FINISHER, POWEROFF))
+    }
+
+    fn reboot(&self) -> Option<(usize, SysconAction)> {
+        Some((TEST_FINISHER, REBOOT))
+    }
+
+    /// one hart, memory is the least the hypervisor and one guest need
+    fn fallback_machine(&self) -> Option<MachineMeta> {
+        let memory = MemoryRegion { base: MEMORY_START, size: 512 * 1024 * 1024 };
+        let mut virtio = ArrayVec::new();
+        for slot in 0..8 {
+            virtio.push(device(0x1000_1000 + slot * 0x1000, 0x1000, Some(1 + slot)));
+        }
+        let mut cpus = Vec::new();
+        cpus.push(CpuMeta { hart_id: 0, isa: "rv64imafdch".into(), mmu_type: Some("riscv,sv39".into()), enabled: true });
+        Some(MachineMeta {
+            physical_memory_offset: memory.base,
+            physical_memory_size: memory.size,
+            memory: alloc::vec![memory],
+            cpus,
+            timebase_frequency: Some(CLOCK_FREQ),
+            virtio,
+            test_finisher_address: Some(device(TEST_FINISHER, 0x1000, None)),
+            uart: Some(device(0x1000_0000, 0x100, Some(10))),
+            clint: Some(device(0x200_0000, 0x1_0000, None)),
+            plic: Some(device(0xc00_0000, 0x60_0000, None)),
+            pci: Some(device(0x3000_0000, 0x1000_0000, None)),
+            rtc: Some(device(0x10_1000, 0x1000, Some(11))),
+            syscon_reboot: Some(REBOOT),
+            syscon_poweroff: Some(POWEROFF),
+            ..MachineMeta::default()
+        })
+    }
+}
@@ -0,0 +1,46 @@
+//! StarFive VisionFive 2, JH7110
+//!
+//! Hart 0 is the S7 monitor core, it has no S-mode: the U74 harts 1 to 4 have the PLIC
+//! contexts 2 to 8 in S-mode. The PMIC powers the board off through the firmware.
+
+use crate::drivers::uart::UartKind;
+use super::{ ConsoleDesc, Platform };
+
+pub const CLOCK_FREQ: usize = 4_000_000;
+
+/// start of DRAM, the firmware takes the first 2M
+pub const MEMORY_START: usize = 0x4000_0000;
+/// end of the frames of the hypervisor
+pub const MEMORY_END: usize = 0x4800_0000;
+pub const GUEST_DTB_ADDR: usize = 0x5000_0000;
+pub const GUEST_START_PA: usize = 0x5020_0000;
+
+pub const MMIO: &[(usize, usize)] = &[];
+
+/// 32-bit memory window of PCIe0 (base, size)
+pub const PCI_MMIO: (usize, usize) = (0x3000_0000, 0x0800_0000);
+
+pub struct VisionFive2;
+
+impl Platform for VisionFive2 {
+    fn name(&self) -> &'static str {
+        "VisionFive 2"
+    }
+
+    fn compatible(&self) -> &'static [&'static str] {
+        &["starfive,visionfive-2-v1.2a", "starfive,visionfive-2-v1.3b", "starfive,jh7110"]
+    }
+
+    fn clock_freq(&self) -> usize {
+        CLOCK_FREQ
+    }
+
+    /// uart0, a DesignWare uart with 32-bit registers
+    fn console(&self) -> Option<ConsoleDesc> {
+        Some(ConsoleDesc { base: 0x1000_0000, kind: UartKind::Ns16550, reg_shift: 2 })
+    }
+
+    fn plic_s_contexts(&self) -> &'static [usize] {
+        &[2, 4, 6, 8]
+    }
+}
@@ -0,0 +1,730 @@
+//! Boot stages of the hypervisor
+//!
+//! The boot hart, see `boothart`, runs the stages in order. Each one hands what it found
+//! to the next in `BootInfo`:
+//!
+//! - `early`: physical addresses, no heap at first. Clears `.bss`, sets up the hart-local
+//!   area and the early console, checks the hardware, sets up the heap, parses the device
+//!   trees and the command line and checks the guest images
+//! - `claim_devices`: takes the devices of the hypervisor out of the guest device tree
+//! - `configure_guest`: the configuration of the guests from the command line
+//! - `host`: builds the host memory set, sets up the hypervisor CSRs and `HOST_VMM`
+//! - `late`: virtual addresses. Enables paging and the trap entry, lets the other harts
+//!   in, creates the guests and enters the first one
+//!
+//! The other harts run `secondary` once the boot hart let them in.
+
+use alloc::boxed::Box;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::ops::RangeInclusive;
+
+use crate::{ boothart, board, bootprof, cmdline, console, detect, device_emu, drivers, errata, guest, hyp_alloc, ioservice, mm, net, percpu, sched, secure_boot };
+use crate::{ GUEST, GUEST_DTB };
+use crate::bootprof::BootPhase;
+use crate::constants::MAX_VCPUS;
+use crate::constants::sched::{ BIG_STRIDE, DEFAULT_PRIORITY, DEFAULT_WEIGHT };
+use crate::constants::layout::{ GUEST_DEFAULT_SIZE, GUEST_START_PA, GUEST_DTB_ADDR, GUEST_WINDOW_SIZE };
+use crate::device_emu::pci::{ AssignedFunction, BarAllocator, Bdf };
+use crate::device_emu::virtio::{ EmulatedVirtio, rng::VirtioRng };
+use crate::drivers::imsic::Imsic;
+use crate::drivers::iopmp::Iopmp;
+use crate::drivers::virtio::VirtioMmio;
+use crate::drivers::virtio::blk::VirtioBlk;
+use crate::drivers::virtio::net::VirtioNet;
+use crate::guest::{ Guest, GuestConfig, GuestImage, SharedText, IsaMask, IrqCoalesce, KernelLayout, RtPartition };
+use crate::guest::counters::CounterMask;
+use crate::guest::envcfg::EnvCfg;
+use crate::guest::wfi::WfiPolicy;
+use crate::guest::quirks::{ GuestOs, Quirks };
+use crate::guest::pmap::gpa2hpa;
+use crate::guest::vmexit::hart_entry_1;
+use crate::hypervisor::{ self, init_vmm, HOST_VMM, add_guest_queue };
+use crate::hypervisor::fdt::{ Device, Framebuffer, MachineMeta };
+use crate::mm::{ HostMemorySet, GuestMemorySet, MemorySet };
+use crate::page_table::PageTableSv39;
+use crate::secure_boot::BootVerdict;
+use crate::VmmResult;
+
+#[cfg(feature = "monitor")]
+use crate::monitor;
+#[cfg(feature = "profiler")]
+use crate::profiler;
+
+/// devices taken by the hypervisor, hidden from the guest
+#[derive(Default)]
+pub struct HostDevices {
+    /// base and interrupt of the NIC of `hvc.net`
+    pub nic: Option<(usize, Option<usize>)>,
+    /// virtio console of the log sink
+    pub log_console: Option<usize>,
+    /// disk of `hvc.disk` and its interrupt
+    pub disk: Option<(VirtioBlk, Option<usize>)>,
+    /// interrupt of the console uart with `hvc.uartirq=on`
+    pub uart_irq: Option<usize>
+}
+
+/// what the stages found so far
+pub struct BootInfo {
+    pub hart_id: usize,
+    /// physical address of the host device tree
+    pub dtb: usize,
+    pub machine: MachineMeta,
+    /// device tree of the guest, without the devices of the hypervisor
+    pub guest_machine: MachineMeta,
+    pub boot_verdict: BootVerdict,
+    pub devices: HostDevices,
+    /// set by `configure_guest`, its kernel by `late`
+    pub config: GuestConfig,
+    /// guests booted, set by `configure_guest`
+    pub guests: usize,
+    /// `hvc.weight` and `hvc.priority` by guest id, the default past their end
+    pub weights: Vec<usize>,
+    pub priorities: Vec<usize>,
+    /// virtio slot of the emulated virtio-rng of the guest
+    pub rng_slot: Option<Device>,
+    /// PCI functions passed through to the guest
+    pub pci_functions: Vec<AssignedFunction>,
+    pub bar_allocator: BarAllocator,
+    /// frames are mapped in the hypervisor only while they are allocated
+    pub framemap_demand: bool
+}
+
+/// clear BSS segment
+fn clear_bss() {
+    extern "C" {
+        fn sbss();
+        fn ebss();
+    }
+    unsafe {
+        core::slice::from_raw_parts_mut(sbss as usize as *mut u8, ebss as usize - sbss as usize)
+            .fill(0);
+    }
+}
+
+/// first stage, on physical addresses with the boot stack
+pub fn early(hart_id: usize, dtb: usize) -> BootInfo {
+    clear_bss();
+    percpu::init(hart_id);
+    bootprof::start();
+    // the console of the board is the early console when the device tree names none
+    board::init(dtb);
+    // before anything is printed, so that early messages do not depend on the SBI console
+    drivers::uart::init(dtb);
+    hdebug!("guest entry: {:#x}, guest size: {:#x}", GUEST.as_ptr() as usize, GUEST.len());
+    hdebug!("guest dtb addr: {:#x}", GUEST_DTB.as_ptr() as usize);
+    hdebug!("Hello Hypocaust-2!");
+    hdebug!("boot hart id: {}, dtb: {:#x}", hart_id, dtb);
+    // detect h extension
+    if sbi_rt::probe_extension(sbi_rt::Hsm).is_unavailable() {
+        panic!("no HSM extension exist on current SBI environment");
+    }
+    if !detect::detect_h_extension() {
+        panic!("no RISC-V hypervisor H extension on current environment")
+    }
+    hdebug!("Hypocaust-2 > running with hardware RISC-V H ISA acceration!");
+
+    // initialize heap
+    hyp_alloc::heap_init();
+    hdebug!("host dtb: {:#x}", dtb);
+    let machine = MachineMeta::from_dtb(dtb, board::MAX_DTB_SIZE);
+    // `hvc.log`, `hvc.guests` and `hvc.schedule`, see `cmdline`
+    cmdline::init(&machine);
+    // quirks of the cpu, `hvc.errata`
+    errata::init(&machine);
+    // parse guest fdt
+    hdebug!("guest dtb: {:#x}", GUEST_DTB.as_ptr() as usize);
+    let guest_machine = MachineMeta::from_dtb(GUEST_DTB.as_ptr() as usize, GUEST_DTB.len());
+    bootprof::mark(BootPhase::FdtParse);
+    device_emu::rtc::init_wall_clock(&machine);
+    sched::init_idle(&machine);
+    // `hvc.scrub=off|now|lazy` zeroes freed frames, see `hyp_alloc::ScrubPolicy`
+    match machine.bootarg("hvc.scrub") {
+        Some("now") => hyp_alloc::set_scrub_policy(hyp_alloc::ScrubPolicy::Immediate),
+        Some("lazy") => hyp_alloc::set_scrub_policy(hyp_alloc::ScrubPolicy::Lazy),
+        Some("off") | None => {},
+        Some(_) => hwarning!("invalid hvc.scrub, freed frames are not scrubbed")
+    }
+    drivers::entropy::init(&machine);
+    guest::sealing::init(&machine);
+    ioservice::init(&machine);
+    #[cfg(feature = "profiler")]
+    profiler::init(&machine);
+    let boot_verdict = secure_boot::verify(&GUEST, &GUEST_DTB);
+    if !secure_boot::allowed(&machine, boot_verdict) {
+        panic!("secure boot: refusing to start an unsigned or tampered guest");
+    }
+    BootInfo {
+        hart_id,
+        dtb,
+        machine,
+        guest_machine,
+        boot_verdict,
+        devices: HostDevices::default(),
+        config: GuestConfig::default(),
+        guests: 1,
+        weights: Vec::new(),
+        priorities: Vec::new(),
+        rng_slot: None,
+        pci_functions: Vec::new(),
+        bar_allocator: BarAllocator::new(),
+        framemap_demand: true
+    }
+}
+
+/// take the devices of the hypervisor and of the guest-only emulated devices out of the
+/// guest device tree
+pub fn claim_devices(boot: &mut BootInfo) {
+    let machine = &boot.machine;
+    let guest_machine = &mut boot.guest_machine;
+    // `hvc.net=<virtio-mmio base> hvc.ip=<a.b.c.d>` gives a NIC to the hypervisor,
+    // set up before the log sink which may send to the network
+    boot.devices.nic = machine.bootarg("hvc.net")
+        .and_then(|base| usize::from_str_radix(base.trim_start_matches("0x"), 16).ok())
+        .and_then(|base| drivers::virtio::claim(machine, guest_machine, base))
+        .and_then(|(dev, device)| match VirtioNet::new(dev) {
+            Ok(nic) => Some((nic, device.irq)),
+            Err(err) => {
+                hwarning!("failed to initialize host nic: {:?}", err);
+                None
+            }
+        })
+        .map(|(nic, irq)| {
+            let base = nic.base();
+            // qemu user networking address by default
+            let ip = machine.bootarg("hvc.ip").and_then(net::parse_ipv4).unwrap_or([10, 0, 2, 15]);
+            net::init(nic, ip);
+            (base, irq)
+        });
+    // select hypervisor log sink, a virtio console taken by the hypervisor is hidden from the guest
+    boot.devices.log_console = console::init_log_sink(machine, guest_machine);
+    // `hvc.disk=<virtio-mmio base>` gives a disk to the hypervisor
+    boot.devices.disk = machine.bootarg("hvc.disk")
+        .and_then(|base| usize::from_str_radix(base.trim_start_matches("0x"), 16).ok())
+        .and_then(|base| drivers::virtio::claim(machine, guest_machine, base))
+        .and_then(|(dev, device)| match VirtioBlk::new(dev) {
+            Ok(blk) => Some((blk, device.irq)),
+            Err(err) => {
+                hwarning!("failed to initialize host disk: {:?}", err);
+                None
+            }
+        });
+    // `hvc.uartirq=on` gives the console uart to the hypervisor: input is read on RX
+    // interrupts and no longer mapped into the guest, which keeps the SBI console
+    boot.devices.uart_irq = match machine.bootarg("hvc.uartirq") {
+        // console input is routed by the monitor
+        Some("on") if !cfg!(feature = "monitor") => {
+            hwarning!("hvc.uartirq needs the monitor feature, input is polled");
+            None
+        },
+        Some("on") => {
+            let early_base = drivers::uart::early_uart().map(|uart| uart.base());
+            let irq = machine.uart.as_ref()
+                .filter(|uart| Some(uart.base_address) == early_base)
+                .and_then(|uart| uart.irq);
+            if irq.is_none() {
+                hwarning!("no interrupt for the console uart, input is polled");
+            }
+            irq
+        },
+        Some("off") | None => None,
+        Some(_) => {
+            hwarning!("invalid hvc.uartirq, input is polled");
+            None
+        }
+    };
+    if boot.devices.uart_irq.is_some() {
+        guest_machine.uart = None;
+    }
+    // `hvc.fb=on` assigns the host `simple-framebuffer` to the guest, `hvc.fb=<base>,<width>x<height>`
+    // a framebuffer set up without a device tree node. Its interrupt, if any, reaches the
+    // guest through the PLIC like the ones of other passthrough devices.
+    guest_machine.framebuffer = match machine.bootarg("hvc.fb") {
+        Some("on") => machine.framebuffer.clone(),
+        Some(arg) => Framebuffer::from_bootarg(arg),
+        None => None
+    };
+    if let Some(fb) = guest_machine.framebuffer.as_ref() {
+        hdebug!("framebuffer {:#x} {}x{} assigned to the guest", fb.device.base_address, fb.width, fb.height);
+    }
+    // `hvc.rng=<virtio-mmio base>|off` places a virtio-rng in a virtio slot of the guest,
+    // by default in the first slot without a host device
+    boot.rng_slot = match machine.bootarg("hvc.rng") {
+        Some("off") => None,
+        Some(base) => usize::from_str_radix(base.trim_start_matches("0x"), 16).ok()
+            .and_then(|base| guest_machine.virtio.iter().find(|dev| dev.base_address == base)),
+        None => guest_machine.virtio.iter().find(|dev| VirtioMmio::probe(dev.base_address).is_none())
+    }.cloned();
+    if let Some(slot) = boot.rng_slot.as_ref() {
+        guest_machine.virtio.retain(|dev| dev.base_address != slot.base_address);
+    }
+    // `hvc.pci=<bus:dev.fn>,...` passes PCI functions through to the guest
+    let bar_allocator = &mut boot.bar_allocator;
+    boot.pci_functions = match (machine.bootarg("hvc.pci"), machine.pci.as_ref()) {
+        (Some(list), Some(pci)) => list.split(',')
+            .filter_map(|bdf| match Bdf::parse(bdf).map(|bdf| AssignedFunction::assign(pci.base_address, bdf, bar_allocator)) {
+                Some(Ok(function)) => Some(function),
+                Some(Err(err)) => {
+                    hwarning!("failed to assign pci function {}: {:?}", bdf, err);
+                    None
+                },
+                None => {
+                    hwarning!("invalid pci function {}", bdf);
+                    None
+                }
+            })
+            .collect(),
+        _ => Vec::new()
+    };
+}
+
+/// the configuration of the guests from the command line
+pub fn configure_guest(boot: &mut BootInfo) {
+    let machine = &boot.machine;
+    // `hvc.hide=<extension letters>` hides extensions from the guest, e.g. `hvc.hide=vh`
+    let hidden_isa = match machine.bootarg("hvc.hide").map(IsaMask::parse) {
+        Some(Some(mask)) => mask,
+        Some(None) => {
+            hwarning!("invalid hvc.hide, no extension hidden");
+            IsaMask::empty()
+        },
+        None => IsaMask::empty()
+    };
+    // `hvc.counters=<list>` lets the guest read only these counters directly, see `guest::counters`
+    let counters = match machine.bootarg("hvc.counters").map(CounterMask::parse) {
+        Some(Some(mask)) => mask,
+        Some(None) => {
+            hwarning!("invalid hvc.counters, the guest reads all counters");
+            CounterMask::all()
+        },
+        None => CounterMask::all()
+    };
+    // `hvc.envcfg=<list>` grants the guest only these `henvcfg` behaviors, see `guest::envcfg`
+    let envcfg = match machine.bootarg("hvc.envcfg").map(EnvCfg::parse) {
+        Some(Some(cfg)) => cfg,
+        Some(None) => {
+            hwarning!("invalid hvc.envcfg, the guest gets all behaviors");
+            EnvCfg::all()
+        },
+        None => EnvCfg::all()
+    };
+    // `hvc.vcpus=<n>` gives the guest n vcpus, at most one per cpu node of its device tree
+    let max_vcpus = boot.guest_machine.hart_count().clamp(1, MAX_VCPUS);
+    let vcpus = match machine.bootarg("hvc.vcpus").map(|arg| arg.parse::<usize>()) {
+        Some(Ok(vcpus)) if (1..=max_vcpus).contains(&vcpus) => vcpus,
+        Some(_) => {
+            hwarning!("invalid hvc.vcpus, the guest has {} cpus", max_vcpus);
+            max_vcpus
+        },
+        None => 1
+    };
+    // `hvc.freeze=off` lets guest time run on while the guest is paused
+    let freeze_on_pause = match machine.bootarg("hvc.freeze") {
+        Some("off") => false,
+        Some("on") | None => true,
+        Some(_) => {
+            hwarning!("invalid hvc.freeze, guest time is frozen while paused");
+            true
+        }
+    };
+    // `hvc.sharetext=<hex bytes>` maps the start of the kernel read-only from the pristine image
+    let shared_text = match machine.bootarg("hvc.sharetext").map(|arg| usize::from_str_radix(arg.trim_start_matches("0x"), 16)) {
+        Some(Ok(len)) => len,
+        Some(Err(_)) => {
+            hwarning!("invalid hvc.sharetext, kernel text not shared");
+            0
+        },
+        None => 0
+    };
+    // `hvc.enclave=on` makes the guest an enclave guest, see `mm::transform`
+    let enclave = match machine.bootarg("hvc.enclave") {
+        Some("on") => true,
+        Some("off") | None => false,
+        Some(_) => {
+            hwarning!("invalid hvc.enclave, the guest is not an enclave");
+            false
+        }
+    };
+    // `hvc.paranoid=on` cleans the hart on every switch away from the guest, see `guest::paranoid`
+    let paranoid_switch = match machine.bootarg("hvc.paranoid") {
+        Some("on") => true,
+        Some("off") | None => false,
+        Some(_) => {
+            hwarning!("invalid hvc.paranoid, paranoid switch off");
+            false
+        }
+    };
+    // `hvc.cap=<percent>` caps the cpu time of the guest, see `sched`
+    let cap = match machine.bootarg("hvc.cap").map(|arg| arg.parse::<usize>()) {
+        Some(Ok(cap)) if (1..=100).contains(&cap) => Some(cap),
+        Some(_) => {
+            hwarning!("invalid hvc.cap, the guest is not capped");
+            None
+        },
+        None => None
+    };
+    // `hvc.rt=<offset>,<length>|dedicated` makes the guest a real-time guest, see `sched`
+    let rt = match machine.bootarg("hvc.rt").map(RtPartition::parse) {
+        Some(Some(rt)) => Some(rt),
+        Some(None) => {
+            hwarning!("invalid hvc.rt, the guest is not real-time");
+            None
+        },
+        None => None
+    };
+    // the budget of a real-time guest is its window
+    let cap = if rt.is_some() && cap.is_some() {
+        hwarning!("hvc.rt: the real-time guest is not capped");
+        None
+    }else{
+        cap
+    };
+    // `hvc.coalesce=<count>,<usecs>[@<irq>][;...]` coalesces the interrupts of emulated
+    // virtio devices, of those on line `irq` or of all others
+    let irq_coalesce = match machine.bootarg("hvc.coalesce").map(IrqCoalesce::parse_list) {
+        Some(Some(coalesce)) => coalesce,
+        Some(None) => {
+            hwarning!("invalid hvc.coalesce, one interrupt per completion");
+            Vec::new()
+        },
+        None => Vec::new()
+    };
+    // `hvc.wfi=native|yield|spin,<usecs>` traps `wfi` of the guest, see `guest::wfi`
+    let wfi = match machine.bootarg("hvc.wfi").map(WfiPolicy::parse) {
+        Some(Some(policy)) => policy,
+        Some(None) => {
+            hwarning!("invalid hvc.wfi, wfi runs natively");
+            WfiPolicy::Native
+        },
+        None => WfiPolicy::Native
+    };
+    // `hvc.os=linux|xv6|rcore|auto` names the OS of the guest, `hvc.quirks=<list>|none`
+    // overrides its quirks, see `guest::quirks`
+    let os = match machine.bootarg("hvc.os").map(GuestOs::parse) {
+        Some(Some(os)) => os,
+        Some(None) => {
+            hwarning!("invalid hvc.os, the guest OS is detected");
+            GuestOs::Unknown
+        },
+        None => GuestOs::Unknown
+    };
+    let quirks = match machine.bootarg("hvc.quirks").map(Quirks::parse) {
+        Some(Some(quirks)) => Some(quirks),
+        Some(None) => {
+            hwarning!("invalid hvc.quirks, the guest gets those of its OS");
+            None
+        },
+        None => None
+    };
+    // `hvc.firmware=on` boots a guest image starting with its own M-mode firmware, see `guest::vmachine`
+    let firmware = match machine.bootarg("hvc.firmware") {
+        Some("on") => true,
+        Some("off") | None => false,
+        Some(_) => {
+            hwarning!("invalid hvc.firmware, the guest boots without firmware");
+            false
+        }
+    };
+    let vcpus = if firmware && vcpus > 1 {
+        hwarning!("hvc.firmware=on: the firmware of the guest runs on one vcpu");
+        1
+    }else{
+        vcpus
+    };
+    if firmware && boot.guest_machine.clint.is_none() {
+        hwarning!("hvc.firmware=on: the guest has no CLINT, its firmware gets no timer");
+    }
+    // frames are mapped in the hypervisor only while they are allocated,
+    // `hvc.framemap=linear` maps the whole frame pool and guest ram, see `mm::framemap`
+    boot.framemap_demand = match machine.bootarg("hvc.framemap") {
+        Some("demand") | None => true,
+        Some("linear") => false,
+        Some(_) => {
+            hwarning!("invalid hvc.framemap, frames are mapped on demand");
+            true
+        }
+    };
+    // `hvc.guests=<n>` boots n guests of the embedded images, guest `i` in the host
+    // memory `i` windows above the first one, see `GUEST_WINDOW_SIZE`
+    let windows = machine.memory.iter()
+        .find(|region| (region.base..region.base + region.size).contains(&GUEST_DTB_ADDR))
+        .map_or(1, |region| ((region.base + region.size - GUEST_DTB_ADDR) / GUEST_WINDOW_SIZE).max(1));
+    let guest_machine = &boot.guest_machine;
+    let one_bank = guest_machine.memory.len() == 1
+        && guest_machine.physical_memory_offset == GUEST_START_PA
+        && guest_machine.physical_memory_size <= GUEST_DEFAULT_SIZE;
+    boot.guests = match cmdline::get().guests {
+        guests if guests > 1 && !one_bank => {
+            hwarning!("hvc.guests: the guest memory is not one bank at {:#x}, one guest is booted", GUEST_START_PA);
+            1
+        },
+        guests if guests > windows => {
+            hwarning!("hvc.guests: host memory holds {} guests", windows);
+            windows
+        },
+        guests => guests
+    };
+    // `hvc.weight=<weight>,...` and `hvc.priority=<priority>,...` give guest `i` the `i`th
+    // weight and priority, see `sched`
+    boot.weights = match machine.bootarg("hvc.weight").map(|arg| parse_per_guest(arg, 1..=BIG_STRIDE)) {
+        Some(Some(weights)) => weights,
+        Some(None) => {
+            hwarning!("invalid hvc.weight, guests get the default weight");
+            Vec::new()
+        },
+        None => Vec::new()
+    };
+    boot.priorities = match machine.bootarg("hvc.priority").map(|arg| parse_per_guest(arg, 0..=usize::MAX)) {
+        Some(Some(priorities)) => priorities,
+        Some(None) => {
+            hwarning!("invalid hvc.priority, guests get the default priority");
+            Vec::new()
+        },
+        None => Vec::new()
+    };
+    let weight = boot.weights.first().copied().unwrap_or(DEFAULT_WEIGHT);
+    let priority = boot.priorities.first().copied().unwrap_or(DEFAULT_PRIORITY);
+    boot.config = GuestConfig { policy: cmdline::get().sched_policy, weight, priority, rt, cap, hidden_isa, counters, envcfg, vcpus, freeze_on_pause, shared_text, enclave, paranoid_switch, irq_coalesce, wfi, os, quirks, firmware, ..GuestConfig::default() };
+}
+
+/// the values of a comma separated list, one per guest, `None` unless all of them are
+/// numbers in `range`
+fn parse_per_guest(arg: &str, range: RangeInclusive<usize>) -> Option<Vec<usize>> {
+    arg.split(',').map(|item| item.parse::<usize>().ok().filter(|value| range.contains(value))).collect()
+}
+
+/// build the host memory set and `HOST_VMM` and hand it the devices of the hypervisor
+pub unsafe fn host(boot: &mut BootInfo) {
+    // initialize vmm
+    let hpm = HostMemorySet::<PageTableSv39>::new_host_vmm(&boot.machine);
+    if boot.framemap_demand {
+        mm::framemap::map_on_demand(hpm.token());
+    }
+    init_vmm(hpm, boot.machine.clone());
+
+    let mut host_vmm = HOST_VMM.get_mut().unwrap().lock();
+    host_vmm.host_virtio.extend(boot.devices.log_console);
+    if let Some((blk, irq)) = boot.devices.disk.take() {
+        host_vmm.host_virtio.push(blk.base());
+        host_vmm.host_blk = Some(blk);
+        host_vmm.host_blk_irq = irq;
+        if let Some(irq) = irq {
+            host_vmm.register_host_irq(irq);
+        }
+    }
+    if let Some((base, irq)) = boot.devices.nic {
+        host_vmm.host_virtio.push(base);
+        host_vmm.host_net_irq = irq;
+        if let Some(irq) = irq {
+            host_vmm.register_host_irq(irq);
+        }
+    }
+    if let Some(irq) = boot.devices.uart_irq {
+        host_vmm.host_uart_irq = Some(irq);
+        host_vmm.register_host_irq(irq);
+        drivers::uart::early_uart().unwrap().enable_rx_irq();
+        #[cfg(feature = "monitor")]
+        monitor::set_irq_input(true);
+    }
+    if let Some(imsic) = host_vmm.host_machine.imsic.clone() {
+        host_vmm.host_imsic = Some(Imsic::new(&imsic));
+    }
+    if let Some(iopmp) = host_vmm.host_machine.iopmp.clone() {
+        host_vmm.host_iopmp = Iopmp::new(&iopmp);
+    }
+    // the hypervisor programs MSI-X tables in the BARs of assigned functions
+    let (bar_base, bar_size) = boot.bar_allocator.allocated();
+    if bar_size > 0 {
+        host_vmm.hpm.map_guest(bar_base, bar_size);
+    }
+    host_vmm.bar_allocator = core::mem::replace(&mut boot.bar_allocator, BarAllocator::new());
+    // `hvc.monitor=<udp port>` serves monitor commands on the hypervisor NIC to requesters
+    // knowing `hvc.monitor.key`
+    #[cfg(feature = "monitor")]
+    if let Some(port) = host_vmm.host_machine.bootarg("hvc.monitor").and_then(|port| port.parse().ok()) {
+        match host_vmm.host_machine.bootarg("hvc.monitor.key").map(monitor::remote::parse_key) {
+            Some(Some(key)) => if let Err(err) = monitor::remote::init(port, key) {
+                hwarning!("failed to start remote monitor: {:?}", err);
+            },
+            Some(None) => hwarning!("invalid hvc.monitor.key, expected 32 hex digits, remote monitor disabled"),
+            None => hwarning!("no hvc.monitor.key, remote monitor disabled")
+        }
+    }
+    // guest ram, the dtb windows included, is mapped for the time of each access of the
+    // hypervisor unless the pool is mapped linearly
+    for guest_id in 0..boot.guests {
+        let window = gpa2hpa(GUEST_DTB_ADDR, guest_id);
+        if mm::framemap::on_demand() {
+            host_vmm.hpm.reserve_guest(window, GUEST_WINDOW_SIZE);
+        }else{
+            host_vmm.hpm.map_guest(window, GUEST_WINDOW_SIZE);
+        }
+    }
+}
+
+/// last stage: enable paging, let the other harts in, create the guests and enter the
+/// first one
+pub unsafe fn late(boot: BootInfo) -> ! {
+    let BootInfo { machine, guest_machine, boot_verdict, mut config, guests, weights, priorities, rng_slot, pci_functions, .. } = boot;
+    // a flat binary or the segments of an ELF file, see `guest::loader`
+    let kernel = KernelLayout::parse(&GUEST).expect("guest kernel does not fit in guest memory");
+    // the shared text is the start of the image, an ELF kernel is not loaded as it is
+    if config.shared_text > 0 && !kernel.verbatim() {
+        hwarning!("hvc.sharetext ignored for an ELF guest kernel");
+        config.shared_text = 0;
+    }
+    // keep pristine copies of the images before the guest modifies them,
+    // without them the guest still boots, it just cannot be reset
+    let images = if GUEST.len() > 0 {
+        match (GuestImage::new(&GUEST), GuestImage::new(&GUEST_DTB)) {
+            (Ok(image), Ok(dtb_image)) => Some((Arc::new(image), dtb_image)),
+            _ => {
+                hwarning!("no memory for pristine guest images, guest reset disabled");
+                None
+            }
+        }
+    }else{
+        None
+    };
+    bootprof::mark(BootPhase::ImageCopy);
+    let text = images.as_ref()
+        .filter(|_| config.shared_text > 0)
+        .map(|(image, _)| SharedText::new(image, config.shared_text));
+    // create guest memory set
+    #[cfg(feature = "alloc_debug")]
+    let owner = hyp_alloc::leak::OwnerScope::guest(0);
+    let mut gpm = GuestMemorySet::<PageTableSv39>::new_guest_without_load(0, &guest_machine, text.as_ref());
+    if config.firmware {
+        guest::vmachine::reserve_clint(&mut gpm, &guest_machine).expect("out of frames for the CLINT of the guest");
+    }
+    #[cfg(feature = "alloc_debug")]
+    drop(owner);
+    bootprof::mark(BootPhase::Stage2Build);
+
+    // hypervisor enable paging
+    mm::enable_paging();
+    // trap init
+    guest::vmexit::trap_init();
+    // other harts entering from now on find the hypervisor set up
+    boothart::set_ready();
+    // `hvc.iohart=<hart>` runs asynchronous device models there, see `ioservice`
+    ioservice::start();
+    // memory translation test
+    mm::remap_test();
+    // the emulated virtio devices against the virtio spec, see `virtio::selftest`
+    #[cfg(feature = "virtio_selftest")]
+    if !device_emu::virtio::selftest::run() {
+        panic!("emulated virtio devices violate the virtio spec");
+    }
+    // an ELF kernel is embedded as a file at the start of guest memory, its segments
+    // are copied over it from the pristine image
+    if !kernel.verbatim() {
+        let (image, _) = images.as_ref().expect("no memory for the pristine image of an ELF guest kernel");
+        image.load_kernel(0, &kernel);
+    }
+    // machine of the other guests of `hvc.guests`, without the devices of the first one
+    let mut other_machine = guest_machine.clone();
+    // create guest struct
+    config.kernel = kernel;
+    let hidden_isa = config.hidden_isa;
+    let other_config = config.clone();
+    let mut guest = Guest::new(0, gpm, guest_machine, config);
+    if let Some((image, dtb_image)) = images {
+        guest.image = Some(image);
+        guest.dtb_image = Some(dtb_image);
+    }
+    guest.shared_text = text;
+    guest.detect_os();
+    guest.measure();
+    guest.boot_verdict = boot_verdict;
+    hypervisor::fdt::mask_isa(GUEST_DTB_ADDR, GUEST_DTB.len(), hidden_isa);
+    pci_functions.into_iter().for_each(|function| guest.pci.assign(function));
+    if let Some(slot) = rng_slot.clone() {
+        hdebug!("virtio-rng for the guest at {:#x}", slot.base_address);
+        guest.virtio.push(EmulatedVirtio::new(0, slot, Box::new(VirtioRng)));
+    }
+    let image = guest.image.clone().filter(|_| guest.dtb_image.is_some());
+    add_guest_queue(guest).expect("failed to start the guest");
+    // `hvc.guests`: the others boot the same images from the pristine copies
+    match image {
+        Some(image) if guests > 1 => {
+            // the devices of the first guest are hidden, virtio slots without a device stay
+            other_machine.uart = None;
+            let hidden: Vec<usize> = machine.uart.as_ref().map(|uart| uart.base_address).into_iter()
+                .chain(other_machine.framebuffer.take().map(|fb| fb.device.base_address))
+                .chain(other_machine.pci.take().map(|pci| pci.base_address))
+                .collect();
+            other_machine.virtio.retain(|dev| VirtioMmio::probe(dev.base_address).is_none());
+            // the real-time window is the first guest's
+            let config = GuestConfig { rt: None, ..other_config };
+            for guest_id in 1..guests {
+                let config = GuestConfig {
+                    weight: weights.get(guest_id).copied().unwrap_or(DEFAULT_WEIGHT),
+                    priority: priorities.get(guest_id).copied().unwrap_or(DEFAULT_PRIORITY),
+                    ..config.clone()
+                };
+                let started = create_guest(guest_id, &image, &hidden, &other_machine, config)
+                    .and_then(|mut guest| {
+                        guest.boot_verdict = boot_verdict;
+                        if let Some(slot) = rng_slot.clone() {
+                            guest.virtio.push(EmulatedVirtio::new(guest_id, slot, Box::new(VirtioRng)));
+                        }
+                        add_guest_queue(guest)
+                    });
+                if let Err(err) = started {
+                    hwarning!("hvc.guests: guest {} not started: {:?}", guest_id, err);
+                    break
+                }
+            }
+        },
+        None if guests > 1 => hwarning!("hvc.guests: no pristine guest images, one guest is booted"),
+        _ => {}
+    }
+    bootprof::mark(BootPhase::GuestCreate);
+    hdebug!("Jump to guest......");
+    hart_entry_1()
+}
+
+/// guest `guest_id` of `hvc.guests` booting `image` in its own window of host memory,
+/// its device tree is the one of the first guest without the devices at `hidden`
+fn create_guest(
+    guest_id: usize, image: &Arc<GuestImage>, hidden: &[usize], guest_machine: &MachineMeta, config: GuestConfig
+) -> VmmResult<Guest<PageTableSv39>> {
+    let mut blob = GUEST_DTB.to_vec();
+    hypervisor::fdt::hide_devices(&mut blob, hidden);
+    let dtb_image = GuestImage::new(&blob)?;
+    let text = (config.shared_text > 0).then(|| SharedText::new(image, config.shared_text));
+    #[cfg(feature = "alloc_debug")]
+    let owner = hyp_alloc::leak::OwnerScope::guest(guest_id);
+    let mut gpm = GuestMemorySet::<PageTableSv39>::try_new_guest_without_load(guest_id, guest_machine, text.as_ref())?;
+    if config.firmware {
+        guest::vmachine::reserve_clint(&mut gpm, guest_machine)?;
+    }
+    #[cfg(feature = "alloc_debug")]
+    drop(owner);
+    let dtb_addr = gpa2hpa(GUEST_DTB_ADDR, guest_id);
+    dtb_image.load(dtb_addr);
+    hypervisor::fdt::mask_isa(dtb_addr, blob.len(), config.hidden_isa);
+    image.load_kernel(guest_id, &config.kernel);
+    let mut guest = Guest::new(guest_id, gpm, guest_machine.clone(), config);
+    guest.image = Some(image.clone());
+    guest.dtb_image = Some(dtb_image);
+    guest.shared_text = text;
+    guest.detect_os();
+    guest.measure();
+    hdebug!("guest {} in host memory at {:#x}", guest_id, dtb_addr);
+    Ok(guest)
+}
+
+/// other harts, once the boot hart let them in: the service hart runs the I/O jobs, the
+/// others are parked
+pub unsafe fn secondary(hart_id: usize) -> ! {
+    boothart::wait_ready();
+    if !ioservice::is_service_hart(hart_id) {
+        boothart::park(hart_id)
+    }
+    percpu::init(hart_id);
+    mm::enable_paging();
+    guest::vmexit::trap_init();
+    ioservice::service_main(hart_id)
+}
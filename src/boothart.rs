@@ -0,0 +1,53 @@
+//! Election of the boot hart
+//!
+//! Firmware may enter the hypervisor on any hart, on some boards on several harts at
+//! once. The first hart reaching `hentry` wins `elect`, sets the hypervisor up and stays
+//! `boot_hart` for its lifetime: it runs the guest and takes the completions of the I/O
+//! hart. Every other hart waits in `wait_ready` until the boot hart is done, then runs the
+//! I/O jobs if it is the service hart or parks itself with SBI HSM hart_stop, so that it
+//! can be started again later.
+//!
+//! The statics are initialized to non-zero values to be placed in `.data`: the boot hart
+//! clears `.bss` while the other harts may be reading them.
+
+use core::sync::atomic::{ AtomicUsize, Ordering };
+
+use crate::sbi;
+
+const NONE: usize = usize::MAX;
+const READY: usize = 1;
+const SETTING_UP: usize = 2;
+
+/// hart that won the election, `NONE` before it
+static BOOT_HART: AtomicUsize = AtomicUsize::new(NONE);
+/// `READY` once the boot hart set the hypervisor up
+static STATE: AtomicUsize = AtomicUsize::new(SETTING_UP);
+
+/// true on the first hart to call it, which becomes the boot hart
+pub fn elect(hart_id: usize) -> bool {
+    BOOT_HART.compare_exchange(NONE, hart_id, Ordering::AcqRel, Ordering::Acquire).is_ok()
+}
+
+/// hart setting up and running the hypervisor
+pub fn boot_hart() -> usize {
+    BOOT_HART.load(Ordering::Acquire)
+}
+
+/// called by the boot hart once the other harts may start, before it starts any of them
+pub fn set_ready() {
+    STATE.store(READY, Ordering::Release);
+}
+
+/// wait until the boot hart set the hypervisor up
+pub fn wait_ready() {
+    while STATE.load(Ordering::Acquire) != READY {
+        core::hint::spin_loop();
+    }
+}
+
+/// stop the calling hart, it is back in the HSM stopped state
+pub fn park(hart_id: usize) -> ! {
+    hdebug!("hart {} parked", hart_id);
+    let error = sbi::hart_stop();
+    panic!("hart {} failed to stop: {}", hart_id, error)
+}
@@ -0,0 +1,109 @@
+//! Guest boot latency profiling
+//!
+//! Each phase of bringing up the first guest is stamped once with the `time` csr.
+//! When every phase has been reached a report is printed, one `key=value` line per
+//! phase, so boot regressions can be picked up by scripts:
+//!
+//! `bootprof phase=stage2_build end_us=1520 delta_us=230`
+
+use core::fmt::Write;
+use core::sync::atomic::{ AtomicUsize, AtomicBool, Ordering };
+use riscv::register::time;
+
+use crate::console;
+use crate::constants::CLOCK_FREQ;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BootPhase {
+    /// guest device tree parsed
+    FdtParse,
+    /// pristine guest images copied
+    ImageCopy,
+    /// guest stage-2 page table built
+    Stage2Build,
+    /// guest struct created and queued
+    GuestCreate,
+    /// first switch into the guest
+    FirstEntry,
+    /// first timer interrupt taken while the guest runs
+    FirstTimer,
+    /// first byte of guest console output
+    FirstConsole
+}
+
+const PHASES: [BootPhase; 7] = [
+    BootPhase::FdtParse, BootPhase::ImageCopy, BootPhase::Stage2Build, BootPhase::GuestCreate,
+    BootPhase::FirstEntry, BootPhase::FirstTimer, BootPhase::FirstConsole
+];
+
+impl BootPhase {
+    fn name(self) -> &'static str {
+        match self {
+            BootPhase::FdtParse => "fdt_parse",
+            BootPhase::Stage2Build => "stage2_build",
+            BootPhase::ImageCopy => "image_copy",
+            BootPhase::GuestCreate => "guest_create",
+            BootPhase::FirstEntry => "first_entry",
+            BootPhase::FirstTimer => "first_timer",
+            BootPhase::FirstConsole => "first_console"
+        }
+    }
+}
+
+const UNSET: AtomicUsize = AtomicUsize::new(0);
+/// time of hypervisor entry
+static BOOT_START: AtomicUsize = AtomicUsize::new(0);
+/// end time of each phase, 0 if not reached yet
+static STAMPS: [AtomicUsize; PHASES.len()] = [UNSET; PHASES.len()];
+static REPORTED: AtomicBool = AtomicBool::new(false);
+
+/// start profiling, called on hypervisor entry
+pub fn start() {
+    BOOT_START.store(time::read(), Ordering::Relaxed);
+}
+
+/// cycles since hypervisor entry
+pub fn uptime() -> usize {
+    time::read().wrapping_sub(BOOT_START.load(Ordering::Relaxed))
+}
+
+/// record the end of `phase`, only the first call per phase counts
+pub fn mark(phase: BootPhase) {
+    let stamp = &STAMPS[phase as usize];
+    if stamp.load(Ordering::Relaxed) != 0 {
+        return
+    }
+    if stamp.compare_exchange(0, time::read(), Ordering::Relaxed, Ordering::Relaxed).is_err() {
+        return
+    }
+    if STAMPS.iter().all(|s| s.load(Ordering::Relaxed) != 0) && !REPORTED.swap(true, Ordering::Relaxed) {
+        report();
+    }
+}
+
+fn cycles_to_us(cycles: usize) -> usize {
+    cycles * 1_000_000 / CLOCK_FREQ
+}
+
+/// print the phases reached so far
+pub fn report() {
+    report_to(&mut console::Stdout);
+}
+
+/// write the phases reached so far to `out`
+pub fn report_to(out: &mut dyn Write) {
+    let start = BOOT_START.load(Ordering::Relaxed);
+    let mut last = start;
+    for &phase in PHASES.iter() {
+        let stamp = STAMPS[phase as usize].load(Ordering::Relaxed);
+        if stamp == 0 {
+            let _ = writeln!(out, "bootprof phase={} end_us=none", phase.name());
+            continue
+        }
+        let _ = writeln!(
+            out, "bootprof phase={} end_us={} delta_us={}",
+            phase.name(), cycles_to_us(stamp - start), cycles_to_us(stamp.saturating_sub(last))
+        );
+        last = stamp;
+    }
+}
@@ -0,0 +1,97 @@
+//! Command line of the hypervisor, the `bootargs` of `/chosen` in the host device tree
+//!
+//! Options shaping the whole hypervisor are parsed once at startup by `init` into a global
+//! `Cmdline`, read with `get`. Options of a single subsystem are read where it is set up
+//! with `MachineMeta::bootarg`. An invalid option is reported and its default kept.
+//!
+//! - `hvc.log=<sink>,<level>`: sink `uart|memory|virtio|udp`, level `error|warning|debug`,
+//!   both optional and in any order, e.g. `hvc.log=debug` or `hvc.log=udp,warning`
+//! - `hvc.guests=<n>`: guests booted from the embedded kernel
+//! - `hvc.schedule=<policy>,<slice>`: policy `rr|stride` of guests not configured otherwise,
+//!   time slice in `ms` or `us`, either optional, e.g. `hvc.schedule=rr,10ms`
+
+use spin::Once;
+
+use crate::console::{ self, LogLevel, LogSinkKind };
+use crate::constants::{ CLOCK_FREQ, MAX_GUESTS };
+use crate::constants::sched::{ DEFAULT_POLICY, TIME_SLICE };
+use crate::hypervisor::fdt::MachineMeta;
+use crate::sched::SchedPolicy;
+
+static CMDLINE: Once<Cmdline> = Once::new();
+
+#[derive(Clone, Copy, Debug)]
+pub struct Cmdline {
+    pub log_sink: LogSinkKind,
+    /// messages less severe are dropped
+    pub log_level: LogLevel,
+    pub guests: usize,
+    /// policy of guests whose config names none, see `GuestConfig::policy`
+    pub sched_policy: SchedPolicy,
+    /// time slice of a guest in cycles
+    pub time_slice: usize
+}
+
+impl Cmdline {
+    const DEFAULT: Self = Self {
+        log_sink: LogSinkKind::Uart,
+        log_level: LogLevel::Debug,
+        guests: 1,
+        sched_policy: DEFAULT_POLICY,
+        time_slice: TIME_SLICE
+    };
+
+    fn parse(machine: &MachineMeta) -> Self {
+        let mut cmdline = Self::DEFAULT;
+        if let Some(arg) = machine.bootarg("hvc.log") {
+            for item in arg.split(',') {
+                match (LogSinkKind::parse(item), LogLevel::parse(item)) {
+                    (Some(sink), _) => cmdline.log_sink = sink,
+                    (_, Some(level)) => cmdline.log_level = level,
+                    _ => hwarning!("invalid hvc.log item {}, ignored", item)
+                }
+            }
+        }
+        match machine.bootarg("hvc.guests").map(|arg| arg.parse::<usize>()) {
+            Some(Ok(guests)) if (1..=MAX_GUESTS).contains(&guests) => cmdline.guests = guests,
+            Some(_) => hwarning!("invalid hvc.guests, one guest is booted"),
+            None => {}
+        }
+        if let Some(arg) = machine.bootarg("hvc.schedule") {
+            for item in arg.split(',') {
+                match (SchedPolicy::parse(item), parse_duration(item)) {
+                    (Some(policy), _) => cmdline.sched_policy = policy,
+                    (_, Some(slice)) if slice > 0 => cmdline.time_slice = slice,
+                    _ => hwarning!("invalid hvc.schedule item {}, ignored", item)
+                }
+            }
+        }
+        cmdline
+    }
+}
+
+/// cycles of `<n>ms` or `<n>us`
+pub fn parse_duration(arg: &str) -> Option<usize> {
+    if let Some(ms) = arg.strip_suffix("ms") {
+        ms.parse::<usize>().ok()?.checked_mul(CLOCK_FREQ / 1000)
+    }else{
+        let us = arg.strip_suffix("us")?.parse::<usize>().ok()?;
+        us.checked_mul(CLOCK_FREQ).map(|cycles| cycles / 1_000_000)
+    }
+}
+
+/// parse the command line of the host machine, called once by the boot hart before the
+/// subsystems reading it are set up
+pub fn init(machine: &MachineMeta) {
+    let cmdline = CMDLINE.call_once(|| Cmdline::parse(machine));
+    console::set_log_level(cmdline.log_level);
+    hdebug!(
+        "command line: log {:?} {:?}, {} guests, {:?} scheduling with {} cycle slices",
+        cmdline.log_sink, cmdline.log_level, cmdline.guests, cmdline.sched_policy, cmdline.time_slice
+    );
+}
+
+/// the parsed command line, the defaults before `init`
+pub fn get() -> &'static Cmdline {
+    CMDLINE.get().unwrap_or(&Cmdline::DEFAULT)
+}
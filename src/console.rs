@@ -1,14 +1,262 @@
-//! SBI console driver, for text output
+//! Hypervisor and guest console output
+//!
+//! Hypervisor messages go to the `LogSink` selected at boot, those below the level set
+//! with `hvc.log` are dropped. Guest output always goes to the physical uart.
 
+use alloc::boxed::Box;
+#[cfg(feature = "monitor")]
+use alloc::string::String;
+use alloc::vec::Vec;
 use crate::sbi::console_putchar;
+use crate::constants::MAX_GUESTS;
+use crate::bootprof::{ self, BootPhase };
+use crate::cmdline;
+use crate::drivers::virtio::{ VirtioMmio, console::VirtioConsole };
+use crate::drivers::uart::early_uart;
+use crate::hypervisor::fdt::MachineMeta;
+use crate::net::{ self, Ipv4Addr, UDP_MAX_PAYLOAD };
 use core::fmt::{self, Write};
+use core::sync::atomic::{ AtomicBool, AtomicU8, Ordering };
+use crate::sync::SpinIrqSave;
 
-struct Stdout;
+/// write to the physical uart, through SBI if there is no early uart driver
+pub fn uart_write(bytes: &[u8]) {
+    match early_uart() {
+        Some(uart) => bytes.iter().for_each(|&c| uart.putchar(c)),
+        None => bytes.iter().for_each(|&c| console_putchar(c as usize))
+    }
+}
+
+/// formatted output to the physical uart, bypassing the log sink
+pub struct UartWriter;
+
+impl Write for UartWriter {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        uart_write(s.as_bytes());
+        Ok(())
+    }
+}
+
+/// destination of hypervisor messages
+pub trait LogSink: Send {
+    fn write_bytes(&mut self, bytes: &[u8]);
+}
+
+/// the physical uart shared with guests
+pub struct UartSink;
+
+impl LogSink for UartSink {
+    fn write_bytes(&mut self, bytes: &[u8]) {
+        uart_write(bytes);
+    }
+}
+
+/// keep messages in the trace buffer only, they are read with the monitor `log` command
+pub struct MemorySink;
+
+impl LogSink for MemorySink {
+    fn write_bytes(&mut self, bytes: &[u8]) {
+        TRACE_BUFFER.lock().write(bytes);
+    }
+}
+
+/// a virtio console dedicated to the hypervisor
+pub struct VirtioConsoleSink(pub VirtioConsole);
+
+impl LogSink for VirtioConsoleSink {
+    fn write_bytes(&mut self, bytes: &[u8]) {
+        self.0.write(bytes);
+    }
+}
+
+/// udp source port of hypervisor log messages
+const UDP_LOG_PORT: u16 = 6665;
+
+/// send messages as udp datagrams to a log server, e.g. `nc -ul 6666`
+pub struct UdpSink {
+    pub server: Ipv4Addr,
+    pub port: u16
+}
+
+impl LogSink for UdpSink {
+    fn write_bytes(&mut self, bytes: &[u8]) {
+        let sent = net::with_stack(|stack| {
+            bytes.chunks(UDP_MAX_PAYLOAD)
+                .all(|chunk| stack.send_to(UDP_LOG_PORT, self.server, self.port, chunk).is_ok())
+        });
+        // messages logged from inside the network stack or lost on the way
+        if sent != Some(true) {
+            uart_write(bytes);
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LogSinkKind {
+    Uart,
+    Memory,
+    VirtioConsole,
+    Udp
+}
+
+impl LogSinkKind {
+    /// `uart|memory|virtio|udp` in `hvc.log`, see `cmdline`
+    pub fn parse(arg: &str) -> Option<Self> {
+        match arg {
+            "memory" => Some(LogSinkKind::Memory),
+            "virtio" => Some(LogSinkKind::VirtioConsole),
+            "uart" => Some(LogSinkKind::Uart),
+            "udp" => Some(LogSinkKind::Udp),
+            _ => None
+        }
+    }
+}
+
+/// severity of hypervisor messages, `hdebug!` down to `herror!`
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum LogLevel {
+    Error,
+    Warning,
+    Debug
+}
+
+impl LogLevel {
+    /// `error|warning|debug` in `hvc.log`, see `cmdline`
+    pub fn parse(arg: &str) -> Option<Self> {
+        match arg {
+            "error" => Some(LogLevel::Error),
+            "warning" => Some(LogLevel::Warning),
+            "debug" => Some(LogLevel::Debug),
+            _ => None
+        }
+    }
+}
+
+/// most verbose level printed, everything until the command line is parsed
+static LOG_LEVEL: AtomicU8 = AtomicU8::new(LogLevel::Debug as u8);
+
+pub fn set_log_level(level: LogLevel) {
+    LOG_LEVEL.store(level as u8, Ordering::Relaxed);
+}
+
+/// whether messages of `level` are printed
+pub fn log_enabled(level: LogLevel) -> bool {
+    level as u8 <= LOG_LEVEL.load(Ordering::Relaxed)
+}
+
+/// size of the in-memory trace buffer
+const TRACE_BUFFER_SIZE: usize = 64 * 1024;
+
+/// ring buffer keeping the most recent bytes written to it
+struct RingBuffer<const SIZE: usize> {
+    buf: [u8; SIZE],
+    /// next byte to write
+    head: usize,
+    len: usize
+}
+
+impl<const SIZE: usize> RingBuffer<SIZE> {
+    const fn new() -> Self {
+        Self { buf: [0; SIZE], head: 0, len: 0 }
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for &c in bytes {
+            self.buf[self.head] = c;
+            self.head = (self.head + 1) % SIZE;
+        }
+        self.len = (self.len + bytes.len()).min(SIZE);
+    }
+
+    /// oldest part first
+    fn chunks(&self) -> (&[u8], &[u8]) {
+        let start = (self.head + SIZE - self.len) % SIZE;
+        if start < self.head || self.len == 0 {
+            (&self.buf[start..self.head], &[])
+        }else{
+            (&self.buf[start..], &self.buf[..self.head])
+        }
+    }
+
+    fn contents(&self) -> Vec<u8> {
+        let (first, second) = self.chunks();
+        let mut contents = Vec::with_capacity(first.len() + second.len());
+        contents.extend_from_slice(first);
+        contents.extend_from_slice(second);
+        contents
+    }
+}
+
+static TRACE_BUFFER: SpinIrqSave<RingBuffer<TRACE_BUFFER_SIZE>> = SpinIrqSave::new(RingBuffer::new());
+/// `None` until a sink is selected at boot, messages go to the uart meanwhile
+static LOG_SINK: SpinIrqSave<Option<Box<dyn LogSink>>> = SpinIrqSave::new(None);
+
+pub fn set_log_sink(sink: Box<dyn LogSink>) {
+    *LOG_SINK.lock() = Some(sink);
+}
+
+/// write the trace buffer to the uart
+pub fn dump_trace_buffer() {
+    let trace = TRACE_BUFFER.lock();
+    let (first, second) = trace.chunks();
+    uart_write(first);
+    uart_write(second);
+}
+
+/// copy of the trace buffer, oldest message first
+pub fn trace_buffer_contents() -> Vec<u8> {
+    TRACE_BUFFER.lock().contents()
+}
+
+/// select the hypervisor log sink from the command line, see `cmdline`.
+/// A virtio console used by the hypervisor is removed from `guest_machine`, its base
+/// address is returned.
+pub fn init_log_sink(machine: &MachineMeta, guest_machine: &mut MachineMeta) -> Option<usize> {
+    match cmdline::get().log_sink {
+        LogSinkKind::Uart => set_log_sink(Box::new(UartSink)),
+        LogSinkKind::Memory => set_log_sink(Box::new(MemorySink)),
+        LogSinkKind::VirtioConsole => {
+            let console = machine.virtio.iter()
+                .filter_map(|dev| VirtioMmio::probe(dev.base_address))
+                .find_map(|dev| VirtioConsole::new(dev).ok());
+            match console {
+                Some(console) => {
+                    let base = console.base();
+                    guest_machine.virtio.retain(|dev| dev.base_address != base);
+                    set_log_sink(Box::new(VirtioConsoleSink(console)));
+                    hdebug!("hypervisor log on virtio console {:#x}", base);
+                    return Some(base)
+                },
+                None => hwarning!("no virtio console found, hypervisor log stays on uart")
+            }
+        },
+        LogSinkKind::Udp => {
+            // `hvc.logserver=<a.b.c.d>:<port>`, requires a NIC given with `hvc.net`
+            let server = machine.bootarg("hvc.logserver").and_then(net::parse_endpoint);
+            match server {
+                Some((server, port)) if net::with_stack(|_| ()).is_some() => {
+                    set_log_sink(Box::new(UdpSink { server, port }));
+                    hdebug!("hypervisor log on udp {}.{}.{}.{}:{}", server[0], server[1], server[2], server[3], port);
+                },
+                _ => hwarning!("no hypervisor nic or log server, hypervisor log stays on uart")
+            }
+        }
+    }
+    None
+}
+
+/// formatted output to the hypervisor log sink
+pub struct Stdout;
 
 impl Write for Stdout {
     fn write_str(&mut self, s: &str) -> fmt::Result {
-        for c in s.chars() {
-            console_putchar(c as usize);
+        // a message printed from inside a sink (e.g. a panic) falls back to the uart
+        match LOG_SINK.try_lock() {
+            Some(mut sink) => match sink.as_mut() {
+                Some(sink) => sink.write_bytes(s.as_bytes()),
+                None => uart_write(s.as_bytes())
+            },
+            None => uart_write(s.as_bytes())
         }
         Ok(())
     }
@@ -18,6 +266,118 @@ pub fn print(args: fmt::Arguments) {
     Stdout.write_fmt(args).unwrap();
 }
 
+/// bytes buffered per guest before they are written out even without a newline
+const GUEST_OUTPUT_SIZE: usize = 256;
+
+/// console output of one guest, written to the uart a line at a time
+struct GuestOutput {
+    buf: [u8; GUEST_OUTPUT_SIZE],
+    len: usize,
+    /// the next byte written out starts a new line
+    line_start: bool,
+    /// bare `\n` line ends of the guest are written out as `\r\n`, see `guest::quirks`
+    crlf: bool,
+    /// last byte the guest wrote, `guest_putchar` hands one byte per call
+    last: u8
+}
+
+impl GuestOutput {
+    const fn new() -> Self {
+        Self { buf: [0; GUEST_OUTPUT_SIZE], len: 0, line_start: true, crlf: false, last: 0 }
+    }
+
+    fn push(&mut self, guest_id: usize, c: u8) {
+        self.buf[self.len] = c;
+        self.len += 1;
+        if c == b'\n' || self.len == GUEST_OUTPUT_SIZE {
+            self.flush(guest_id);
+        }
+    }
+
+    fn flush(&mut self, guest_id: usize) {
+        if self.len == 0 {
+            return
+        }
+        if self.line_start && GUEST_PREFIX.load(Ordering::Relaxed) {
+            UartWriter.write_fmt(format_args!("[guest {}] ", guest_id)).unwrap();
+        }
+        uart_write(&self.buf[..self.len]);
+        self.line_start = self.buf[self.len - 1] == b'\n';
+        self.len = 0;
+    }
+}
+
+const EMPTY_OUTPUT: GuestOutput = GuestOutput::new();
+static GUEST_OUTPUT: SpinIrqSave<[GuestOutput; MAX_GUESTS]> = SpinIrqSave::new([EMPTY_OUTPUT; MAX_GUESTS]);
+/// tag every guest line with the id of the guest
+static GUEST_PREFIX: AtomicBool = AtomicBool::new(false);
+
+/// scrollback kept per guest for the monitor
+#[cfg(feature = "monitor")]
+const GUEST_HISTORY_SIZE: usize = 16 * 1024;
+
+#[cfg(feature = "monitor")]
+const EMPTY_HISTORY: RingBuffer<GUEST_HISTORY_SIZE> = RingBuffer::new();
+/// recent output of every guest, also what was still buffered when the guest died
+#[cfg(feature = "monitor")]
+static GUEST_HISTORY: SpinIrqSave<[RingBuffer<GUEST_HISTORY_SIZE>; MAX_GUESTS]> = SpinIrqSave::new([EMPTY_HISTORY; MAX_GUESTS]);
+
+pub fn set_guest_crlf(guest_id: usize, enable: bool) {
+    GUEST_OUTPUT.lock()[guest_id].crlf = enable;
+}
+
+pub fn set_guest_prefix(enable: bool) {
+    GUEST_PREFIX.store(enable, Ordering::Relaxed);
+}
+
+/// buffer guest output, the buffer is flushed on newline or when it is full
+pub fn guest_write(guest_id: usize, bytes: &[u8]) {
+    bootprof::mark(BootPhase::FirstConsole);
+    #[cfg(feature = "monitor")]
+    GUEST_HISTORY.lock()[guest_id].write(bytes);
+    let mut outputs = GUEST_OUTPUT.lock();
+    let output = &mut outputs[guest_id];
+    for &c in bytes {
+        if c == b'\n' && output.crlf && output.last != b'\r' {
+            output.push(guest_id, b'\r');
+        }
+        output.push(guest_id, c);
+        output.last = c;
+    }
+}
+
+pub fn guest_putchar(guest_id: usize, c: u8) {
+    guest_write(guest_id, &[c]);
+}
+
+/// write out the partial line of one guest, e.g. a shell prompt waiting for input
+pub fn flush_guest(guest_id: usize) {
+    GUEST_OUTPUT.lock()[guest_id].flush(guest_id);
+}
+
+/// write out partial lines of every guest, done once per tick
+pub fn flush_guest_output() {
+    let mut outputs = GUEST_OUTPUT.lock();
+    for (guest_id, output) in outputs.iter_mut().enumerate() {
+        output.flush(guest_id);
+    }
+}
+
+/// write the last `lines` lines of output of a guest to `out`
+#[cfg(feature = "monitor")]
+pub fn replay_guest_history(guest_id: usize, lines: usize, out: &mut dyn Write) {
+    let history = GUEST_HISTORY.lock()[guest_id].contents();
+    // a trailing newline does not start another line
+    let end = history.len() - history.ends_with(b"\n") as usize;
+    let start = history[..end].iter().enumerate().rev()
+        .filter(|&(_, &c)| c == b'\n')
+        .nth(lines.saturating_sub(1))
+        .map_or(0, |(i, _)| i + 1);
+    if lines > 0 {
+        let _ = out.write_str(&String::from_utf8_lossy(&history[start..]));
+    }
+}
+
 #[macro_export]
 /// print string macro
 macro_rules! print {
@@ -37,21 +397,28 @@ macro_rules! println {
 #[macro_export]
 macro_rules! hdebug {
     ($fmt: literal $(, $($arg: tt)+)?) => {
-        $crate::console::print(format_args!(concat!("[Hypervisor] ", $fmt, "\n") $(, $($arg)+)?));
+        if $crate::console::log_enabled($crate::console::LogLevel::Debug) {
+            $crate::console::print(format_args!(concat!("[Hypervisor] ", $fmt, "\n") $(, $($arg)+)?));
+        }
     }
 }
 
 #[macro_export]
 macro_rules! hwarning {
     ($fmt: literal $(, $($arg: tt)+)?) => {
-        $crate::console::print(format_args!(concat!("[Warning] ", $fmt, "\n") $(, $($arg)+)?));
+        if $crate::console::log_enabled($crate::console::LogLevel::Warning) {
+            $crate::console::print(format_args!(concat!("[Warning] ", $fmt, "\n") $(, $($arg)+)?));
+        }
     }
 }
 
 #[macro_export]
 macro_rules! htracking {
     ($fmt: literal $(, $($arg: tt)+)?) => {
-        $crate::console::print(format_args!(concat!("\x1b[1;32m[Tracking] ", $fmt, "\x1b[0m\n") $(, $($arg)+)?));
+        // arguments stay type checked and used without the `tracing` feature
+        if cfg!(feature = "tracing") {
+            $crate::console::print(format_args!(concat!("\x1b[1;32m[Tracking] ", $fmt, "\x1b[0m\n") $(, $($arg)+)?));
+        }
     }
 }
 
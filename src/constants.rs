@@ -6,33 +6,73 @@ pub const KERNEL_HEAP_SIZE: usize = 0x30_0000;
 
 
 pub const MAX_GUESTS: usize = 4;
+/// physical harts the hypervisor can run on
+pub const MAX_HARTS: usize = 8;
 pub const MAX_GUEST_HARTS: usize = 16;
+/// vcpus of a guest, bounded by the width of an SBI hart mask
+pub const MAX_VCPUS: usize = 8;
 /// Number of contexts for the PLIC. Value is twice the max number of harts because each hart will
 /// have on M-mode context and one S-mode context.
 pub const MAX_CONTEXTS: usize = 16 * 2;
 
 pub use crate::board::CLOCK_FREQ;
 
+pub mod sched {
+    use super::CLOCK_FREQ;
+    use crate::sched::SchedPolicy;
+
+    /// scheduling policy of a guest without explicit configuration, see `hvc.schedule`
+    pub const DEFAULT_POLICY: SchedPolicy = SchedPolicy::Stride;
+    /// default time slice of a guest (10ms)
+    pub const TIME_SLICE: usize = CLOCK_FREQ / 100;
+    /// weight of a guest without explicit configuration
+    pub const DEFAULT_WEIGHT: usize = 1024;
+    /// priority of a guest without explicit configuration
+    pub const DEFAULT_PRIORITY: usize = 0;
+    /// pass increment per consumed cycle is `BIG_STRIDE / weight`
+    pub const BIG_STRIDE: usize = 1 << 20;
+    /// major frame of real-time partitions (100ms), RT windows repeat every major frame
+    pub const RT_MAJOR_FRAME: usize = CLOCK_FREQ / 10;
+    /// time slice of a vcpu inside the slice of its guest (4ms)
+    pub const VCPU_SLICE: usize = CLOCK_FREQ / 250;
+    /// a vcpu with a pending interrupt preempts a sibling only after the sibling ran this long (1ms)
+    pub const VCPU_MIN_RUN: usize = CLOCK_FREQ / 1000;
+    /// a capped guest gets its cpu budget again every period (100ms)
+    pub const CAP_PERIOD: usize = CLOCK_FREQ / 10;
+    /// an idle hart waits this long in `wfi` before it is suspended through SBI HSM (100ms)
+    pub const IDLE_SUSPEND_DELAY: usize = CLOCK_FREQ / 10;
+}
+
+pub mod pci {
+    /// part of the host ECAM window mapped by the hypervisor, buses 0 and 1
+    pub const ECAM_HOST_WINDOW: usize = 0x20_0000;
+    /// host addresses of the BARs of devices assigned to guests
+    pub use crate::board::PCI_MMIO as HOST_BAR_WINDOW;
+}
+
 pub mod layout {
     use super::PAGE_SIZE;
 
-    pub const MEMORY_START: usize = 0x8000_0000;
-    pub const MEMORY_END: usize = 0x8800_0000;
+    pub use crate::board::{ MEMORY_START, MEMORY_END, GUEST_DTB_ADDR, GUEST_START_PA, MMIO };
 
     /// 跳板页虚拟地址
+    /// hypervisor virtual address only, guests run on their own page tables and
+    /// nothing of the hypervisor is mapped into their guest physical address space
     pub const TRAMPOLINE: usize = usize::MAX - PAGE_SIZE + 1;
 
     /// 上下文切换数据存储虚拟地址
     pub const TRAP_CONTEXT: usize = TRAMPOLINE - PAGE_SIZE;
 
-    pub const GUEST_START_PA: usize = 0x9020_0000;
-    pub const GUEST_START_VA: usize = 0x9020_0000;
+    pub const GUEST_START_VA: usize = GUEST_START_PA;
 
     pub const GUEST_DEFAULT_SIZE: usize = 128 * 1024 * 1024;
 
-    pub const GUEST_DTB_ADDR: usize = 0x9000_0000;
+    /// the device tree of a guest sits in this window below its first memory bank
+    pub const GUEST_DTB_SIZE: usize = GUEST_START_PA - GUEST_DTB_ADDR;
 
-    pub use crate::board::MMIO;
+    /// host memory of a guest, its device tree window and its ram. Every guest sees the
+    /// window at `GUEST_DTB_ADDR`, guest `i` has the one `i` windows above in the host.
+    pub const GUEST_WINDOW_SIZE: usize = GUEST_DTB_SIZE + GUEST_DEFAULT_SIZE;
 }
 
 pub mod csr {
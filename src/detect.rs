@@ -19,6 +19,106 @@ pub fn detect_h_extension() -> bool {
     ans != 2
 }
 
+// Detect if `henvcfg` and `senvcfg` exist (privileged spec 1.12)
+//
+// This function tries to read henvcfg and returns false if the read operation failed.
+pub fn detect_envcfg() -> bool {
+    let ans = with_detect_trap(0, || unsafe {
+        asm!("csrr  {}, 0x60a", out(reg) _, options(nomem, nostack)); // 0x60a => henvcfg
+    });
+    ans != 2
+}
+
+/// `sstatus.VS`
+const SSTATUS_VS: usize = 0b11 << 9;
+
+// Detect the vector extension, returns `vlenb` or 0 without V
+//
+// `sstatus.VS` is turned on first, `vlenb` cannot be read with the vector state off.
+pub fn detect_vlenb() -> usize {
+    let mut vlenb: usize = 0;
+    let ans = with_detect_trap(0, || unsafe {
+        asm!(
+            ".option push",
+            ".option arch, +v",
+            "csrs  sstatus, {vs}",
+            "csrr  {vlenb}, vlenb",
+            ".option pop",
+            vs = in(reg) SSTATUS_VS,
+            vlenb = inout(reg) vlenb,
+            options(nomem, nostack)
+        );
+    });
+    if ans == 2 { 0 } else { vlenb }
+}
+
+/// `sie.LCOFIE` and `hvien.LCOFI`
+const LCOFI: usize = 1 << 13;
+
+// Detect if counter overflow interrupts can be taken and injected into guests
+//
+// `sie.LCOFIE` only sticks with Sscofpmf, `hvien` only exists with AIA. The bits are left
+// set when they stick.
+pub fn detect_lcofi() -> bool {
+    let mut sie: usize = 0;
+    let mut hvien: usize = 0;
+    let ans = with_detect_trap(0, || unsafe {
+        asm!(
+            "csrs  sie, {lcofi}",
+            "csrr  {sie}, sie",
+            "csrs  0x608, {lcofi}", // 0x608 => hvien
+            "csrr  {hvien}, 0x608",
+            lcofi = in(reg) LCOFI,
+            sie = inout(reg) sie,
+            hvien = inout(reg) hvien,
+            options(nomem, nostack)
+        );
+    });
+    ans != 2 && sie & LCOFI != 0 && hvien & LCOFI != 0
+}
+
+/// `henvcfg.ADUE`
+const HENVCFG_ADUE: usize = 1 << 61;
+
+// Detect if the hardware updates A/D bits of G-stage page table entries (Svadu)
+//
+// `henvcfg.ADUE` is read-only zero unless the firmware enabled Svadu in `menvcfg`, which
+// also turns on hardware updating for G-stage translation. The bit is set to see if it
+// sticks and cleared again, guests keep faulting on their own A/D bits like without Svadu.
+pub fn detect_svadu() -> bool {
+    let mut henvcfg: usize = 0;
+    let ans = with_detect_trap(0, || unsafe {
+        asm!(
+            "csrs  0x60a, {adue}", // 0x60a => henvcfg
+            "csrr  {value}, 0x60a",
+            "csrc  0x60a, {adue}",
+            adue = in(reg) HENVCFG_ADUE,
+            value = inout(reg) henvcfg,
+            options(nomem, nostack)
+        );
+    });
+    ans != 2 && henvcfg & HENVCFG_ADUE != 0
+}
+
+// Detect if `hcontext` exists and holds at least the context ids of the guests
+//
+// `hcontext` is optional in Sdtrig and may be read-only zero. A guest id is written to see
+// if it sticks, the register is cleared again.
+pub fn detect_hcontext(max_context: usize) -> bool {
+    let mut hcontext: usize = 0;
+    let ans = with_detect_trap(0, || unsafe {
+        asm!(
+            "csrw  0x6a8, {context}", // 0x6a8 => hcontext
+            "csrr  {value}, 0x6a8",
+            "csrw  0x6a8, zero",
+            context = in(reg) max_context,
+            value = inout(reg) hcontext,
+            options(nomem, nostack)
+        );
+    });
+    ans != 2 && hcontext == max_context
+}
+
 // Tries to execute all instructions defined in clojure `f`.
 // If resulted in an exception, this function returns its exception id.
 //
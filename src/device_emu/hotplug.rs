@@ -0,0 +1,185 @@
+//! Attaching devices to running guests and detaching them
+//!
+//! The guest device tree is fixed, devices come and go where the guest already looks
+//! for them: an emulated virtio device takes a virtio-mmio slot, a PCI function shows
+//! up in the ECAM window. Each change is queued as a `DeviceEvent`, the guest fetches
+//! it with `HYPERCALL_DEVICE_EVENT_FID` and rescans the bus.
+
+use alloc::boxed::Box;
+
+use crate::arch;
+use crate::device_emu::DeviceLifecycle;
+use crate::device_emu::mmio::MmioDevice;
+use crate::device_emu::pci::{ AssignedFunction, Bdf };
+use crate::device_emu::virtio::{ EmulatedVirtio, rng::VirtioRng };
+use crate::drivers::virtio::VirtioMmio;
+use crate::guest::page_table::GuestPageTable;
+use crate::hypervisor::HostVmm;
+use crate::ioservice;
+use crate::mm::{ MapArea, MapPermission, MapType, MemorySet };
+use crate::page_table::PageTable;
+use crate::{ VmmError, VmmResult };
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HotplugDevice {
+    /// virtio-rng in the virtio-mmio slot at the address, on attach `None` takes the
+    /// first free slot
+    Rng(Option<usize>),
+    Pci(Bdf)
+}
+
+impl HotplugDevice {
+    /// `rng [<slot base>]` or `pci <bus:dev.func>`
+    pub fn parse<'a>(mut args: impl Iterator<Item = &'a str>) -> Option<Self> {
+        match args.next()? {
+            "rng" => match args.next() {
+                Some(base) => usize::from_str_radix(base.trim_start_matches("0x"), 16).ok().map(|base| HotplugDevice::Rng(Some(base))),
+                None => Some(HotplugDevice::Rng(None))
+            },
+            "pci" => args.next().and_then(Bdf::parse).map(HotplugDevice::Pci),
+            _ => None
+        }
+    }
+}
+
+/// change of the devices of a guest, `addr` is the virtio-mmio slot or the config
+/// space of the PCI function
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DeviceEvent {
+    Attached(usize),
+    Detached(usize)
+}
+
+impl DeviceEvent {
+    /// page aligned address with the kind of event in the low bits: 1 attached, 2 detached
+    pub fn encode(&self) -> usize {
+        match *self {
+            DeviceEvent::Attached(addr) => addr | 1,
+            DeviceEvent::Detached(addr) => addr | 2
+        }
+    }
+}
+
+impl<P: PageTable, G: GuestPageTable> HostVmm<P, G> {
+    pub fn attach_device(&mut self, guest_id: usize, device: HotplugDevice) -> VmmResult {
+        let event = match device {
+            HotplugDevice::Rng(slot) => self.attach_rng(guest_id, slot)?,
+            HotplugDevice::Pci(bdf) => self.attach_pci(guest_id, bdf)?
+        };
+        hdebug!("{:?} attached to guest {}", device, guest_id);
+        self.notify_device_event(guest_id, event);
+        Ok(())
+    }
+
+    pub fn detach_device(&mut self, guest_id: usize, device: HotplugDevice) -> VmmResult {
+        let event = match device {
+            HotplugDevice::Rng(Some(slot)) => self.retry_on_oom(guest_id, |vmm| vmm.detach_virtio(guest_id, slot))?,
+            HotplugDevice::Rng(None) => return Err(VmmError::NoFound),
+            HotplugDevice::Pci(bdf) => self.detach_pci(guest_id, bdf)?
+        };
+        hdebug!("{:?} detached from guest {}", device, guest_id);
+        self.notify_device_event(guest_id, event);
+        Ok(())
+    }
+
+    fn notify_device_event(&mut self, guest_id: usize, event: DeviceEvent) {
+        if let Some(guest) = self.guests.get_mut(guest_id) {
+            guest.device_events.push_back(event);
+        }
+        if guest_id == self.guest_id {
+            arch::flush_stage2_tlb();
+        }
+    }
+
+    /// an emulated device takes a passthrough slot without a host device behind it
+    fn attach_rng(&mut self, guest_id: usize, slot: Option<usize>) -> VmmResult<DeviceEvent> {
+        let host_virtio = &self.host_virtio;
+        let guest = self.guests.get_mut(guest_id).ok_or(VmmError::NoFound)?;
+        let device = guest.guest_machine.virtio.iter()
+            .filter(|dev| slot.map_or(true, |slot| dev.base_address == slot))
+            .find(|dev| !host_virtio.contains(&dev.base_address) && VirtioMmio::probe(dev.base_address).is_none())
+            .cloned()
+            .ok_or(VmmError::DeviceNotFound)?;
+        let (base, size) = (device.base_address, device.size);
+        guest.gpm.remove_area(base.into());
+        guest.gpm.reserve_mmio(base, size, MmioDevice::Virtio)?;
+        guest.guest_machine.virtio.retain(|dev| dev.base_address != base);
+        guest.virtio.push(EmulatedVirtio::new(guest_id, device, Box::new(VirtioRng)));
+        Ok(DeviceEvent::Attached(base))
+    }
+
+    /// the slot goes back to the (empty) host slot
+    fn detach_virtio(&mut self, guest_id: usize, slot: usize) -> VmmResult<DeviceEvent> {
+        ioservice::quiesce(guest_id);
+        let guest = self.guests.get_mut(guest_id).ok_or(VmmError::NoFound)?;
+        let index = guest.virtio.iter().position(|dev| dev.device.base_address == slot).ok_or(VmmError::DeviceNotFound)?;
+        let (base, size) = (guest.virtio[index].device.base_address, guest.virtio[index].device.size);
+        // map the slot first, the device stays attached if that fails
+        guest.gpm.remove_area(base.into());
+        let mapped = guest.gpm.try_push(MapArea::new(
+            base.into(),
+            (base + size).into(),
+            Some(base.into()),
+            Some((base + size).into()),
+            MapType::Linear,
+            MapPermission::R | MapPermission::W | MapPermission::U
+        ), None);
+        if let Err(err) = mapped {
+            guest.gpm.reserve_mmio(base, size, MmioDevice::Virtio)?;
+            return Err(err)
+        }
+        let mut dev = guest.virtio.remove(index);
+        dev.destroy();
+        let device = dev.device;
+        if let Some(irq) = device.irq {
+            guest.pending_irqs.retain(|&pending| pending != irq as u32);
+        }
+        guest.guest_machine.virtio.push(device);
+        guest.guest_machine.virtio.sort_unstable_by_key(|dev| dev.base_address);
+        Ok(DeviceEvent::Detached(slot))
+    }
+
+    fn attach_pci(&mut self, guest_id: usize, bdf: Bdf) -> VmmResult<DeviceEvent> {
+        let ecam_base = self.host_machine.pci.as_ref().ok_or(VmmError::DeviceNotFound)?.base_address;
+        let assigned = self.guests.iter()
+            .any(|guest| guest.pci.functions.iter().any(|function| function.bdf == bdf));
+        if assigned {
+            return Err(VmmError::InvalidState)
+        }
+        let guest_ecam = self.guests.get(guest_id).ok_or(VmmError::NoFound)?
+            .guest_machine.pci.as_ref().ok_or(VmmError::DeviceNotFound)?.base_address;
+        let (_, mapped) = self.bar_allocator.allocated();
+        // the function is not handed over while its DMA cannot be confined
+        self.iopmp_assign(guest_id, bdf.requester_id())?;
+        let function = match AssignedFunction::assign(ecam_base, bdf, &mut self.bar_allocator) {
+            Ok(function) => function,
+            Err(err) => {
+                self.iopmp_release(bdf.requester_id());
+                return Err(err)
+            }
+        };
+        // the hypervisor programs the MSI-X tables of the new BARs
+        let (bar_base, allocated) = self.bar_allocator.allocated();
+        if allocated > mapped {
+            self.hpm.map_guest(bar_base + mapped, allocated - mapped);
+            arch::flush_host_tlb();
+        }
+        self.guests.get_mut(guest_id).ok_or(VmmError::NoFound)?.pci.assign(function);
+        Ok(DeviceEvent::Attached(guest_ecam + bdf.ecam_offset()))
+    }
+
+    fn detach_pci(&mut self, guest_id: usize, bdf: Bdf) -> VmmResult<DeviceEvent> {
+        let guest = self.guests.get_mut(guest_id).ok_or(VmmError::NoFound)?;
+        let guest_ecam = guest.guest_machine.pci.as_ref().ok_or(VmmError::DeviceNotFound)?.base_address;
+        let index = guest.pci.functions.iter().position(|function| function.bdf == bdf).ok_or(VmmError::DeviceNotFound)?;
+        let mut function = guest.pci.functions.remove(index);
+        let host_ids = function.release(&mut guest.gpm);
+        self.iopmp_release(bdf.requester_id());
+        for id in host_ids {
+            if let Some(route) = self.msi_routes.remove(&id) {
+                guest.pending_irqs.retain(|&pending| pending != route.irq);
+            }
+        }
+        Ok(DeviceEvent::Detached(guest_ecam + bdf.ecam_offset()))
+    }
+}
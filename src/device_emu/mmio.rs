@@ -0,0 +1,226 @@
+//! Emulated MMIO windows and decoding of guest loads and stores to them
+//!
+//! Every emulated register window is reserved in the guest memory set as a
+//! `MapType::Mmio` area naming the device model that owns it. These areas are never
+//! mapped, accesses fault and `guest_page_fault_handler` routes them to the owner.
+//! Areas cannot overlap a reserved window, so a device cannot be emulated and passed
+//! through at the same address by accident. Registers that are read much more often than
+//! written can live in a `MapType::Shadow` area instead, where only writes fault.
+//!
+//! Besides loads and stores, `MmioAccess::decode` takes the AMOs of the A extension, 32
+//! and 64 bits wide, compressed loads and stores go through `decode_compressed`. A device
+//! model emulates an AMO as a read and a write of the register, `complete_read` and
+//! `written`, under the `HOST_VMM` lock: no other vcpu reaches the register in between.
+//! `lr`/`sc` are not emulated.
+
+use riscv_decode::Instruction;
+
+use crate::device_emu::virtio::EmulatedVirtio;
+use crate::device_emu::virtio_slot::VIRTIO_MMIO_SIZE;
+use crate::guest::page_table::GuestPageTable;
+use crate::guest::vmexit::TrapContext;
+use crate::hypervisor::HostVmm;
+use crate::hypervisor::fdt::MachineMeta;
+use crate::mm::GuestMemorySet;
+use crate::page_table::PageTable;
+use crate::{ VmmError, VmmResult };
+
+/// device model owning an MMIO window of a guest
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MmioDevice {
+    /// priorities and enables are shadowed, the other registers are emulated
+    Plic,
+    /// qemu test finisher
+    Syscon,
+    /// goldfish RTC
+    Rtc,
+    /// SiFive watchdog
+    Watchdog,
+    /// ECAM window and the MSI-X table pages of assigned functions
+    Pci,
+    /// virtio device emulated by the hypervisor
+    Virtio,
+    /// virtio slot of a device of the hypervisor or of another guest, shown to the guest
+    /// as empty
+    HostVirtio,
+    /// CLINT of a guest booting its own firmware, see `guest::vmachine`
+    Clint
+}
+
+impl<P: PageTable, G: GuestPageTable> HostVmm<P, G> {
+    /// reserve the virtio slots emulated for a guest, they are not in its machine description.
+    /// The other slots of the host the guest does not own, those of the devices of the
+    /// hypervisor and of the devices passed through to other guests, are shown as empty.
+    pub fn reserve_virtio_windows(&self, gpm: &mut GuestMemorySet<G>, virtio: &[EmulatedVirtio], guest_machine: &MachineMeta) -> VmmResult {
+        for dev in virtio.iter() {
+            gpm.reserve_mmio(dev.device.base_address, dev.device.size, MmioDevice::Virtio)?;
+        }
+        for &base in self.host_virtio.iter() {
+            gpm.reserve_mmio(base, VIRTIO_MMIO_SIZE, MmioDevice::HostVirtio)?;
+        }
+        let placed = |base: usize| self.host_virtio.contains(&base)
+            || virtio.iter().any(|dev| dev.device.base_address == base)
+            || guest_machine.virtio.iter().any(|dev| dev.base_address == base);
+        for dev in self.host_machine.virtio.iter().filter(|dev| !placed(dev.base_address)) {
+            gpm.reserve_mmio(dev.base_address, VIRTIO_MMIO_SIZE, MmioDevice::HostVirtio)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum MmioAccess {
+    Load { rd: usize, width: usize, signed: bool },
+    /// `value` is truncated to `width` bytes
+    Store { value: usize, width: usize },
+    /// `rd` gets the old value of the register, sign extended, and the register
+    /// `op(old, value)`, `value` is truncated to `width` bytes
+    Amo { rd: usize, value: usize, width: usize, op: AmoOp }
+}
+
+/// operation of an AMO instruction
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AmoOp {
+    Swap,
+    Add,
+    Xor,
+    And,
+    Or,
+    Min,
+    Max,
+    Minu,
+    Maxu
+}
+
+/// `value` truncated to `width` bytes
+fn truncate(value: usize, width: usize) -> usize {
+    if width == 8 { value } else { value & ((1 << (8 * width)) - 1) }
+}
+
+impl AmoOp {
+    /// new value of a `width` bytes register holding `old`
+    pub fn apply(self, old: usize, operand: usize, width: usize) -> usize {
+        let (old, operand) = (truncate(old, width), truncate(operand, width));
+        let signed = |value: usize| if width == 4 { value as u32 as i32 as isize } else { value as isize };
+        let new = match self {
+            AmoOp::Swap => operand,
+            AmoOp::Add => old.wrapping_add(operand),
+            AmoOp::Xor => old ^ operand,
+            AmoOp::And => old & operand,
+            AmoOp::Or => old | operand,
+            AmoOp::Min => if signed(old) <= signed(operand) { old } else { operand },
+            AmoOp::Max => if signed(old) >= signed(operand) { old } else { operand },
+            AmoOp::Minu => old.min(operand),
+            AmoOp::Maxu => old.max(operand)
+        };
+        truncate(new, width)
+    }
+}
+
+impl MmioAccess {
+    pub fn decode(ctx: &TrapContext, instruction: Instruction) -> VmmResult<Self> {
+        let load = |rd: u32, width: usize, signed: bool| -> VmmResult<Self> { Ok(MmioAccess::Load { rd: rd as usize, width, signed }) };
+        let store = |rs2: u32, width: usize| -> VmmResult<Self> {
+            Ok(MmioAccess::Store { value: truncate(ctx.x[rs2 as usize], width), width })
+        };
+        let amo = |rd: u32, rs2: u32, width: usize, op: AmoOp| -> VmmResult<Self> {
+            Ok(MmioAccess::Amo { rd: rd as usize, value: truncate(ctx.x[rs2 as usize], width), width, op })
+        };
+        match instruction {
+            Instruction::Lb(i) => load(i.rd(), 1, true),
+            Instruction::Lbu(i) => load(i.rd(), 1, false),
+            Instruction::Lh(i) => load(i.rd(), 2, true),
+            Instruction::Lhu(i) => load(i.rd(), 2, false),
+            Instruction::Lw(i) => load(i.rd(), 4, true),
+            Instruction::Lwu(i) => load(i.rd(), 4, false),
+            Instruction::Ld(i) => load(i.rd(), 8, false),
+            Instruction::Sb(i) => store(i.rs2(), 1),
+            Instruction::Sh(i) => store(i.rs2(), 2),
+            Instruction::Sw(i) => store(i.rs2(), 4),
+            Instruction::Sd(i) => store(i.rs2(), 8),
+            Instruction::AmoswapW(i) => amo(i.rd(), i.rs2(), 4, AmoOp::Swap),
+            Instruction::AmoaddW(i) => amo(i.rd(), i.rs2(), 4, AmoOp::Add),
+            Instruction::AmoxorW(i) => amo(i.rd(), i.rs2(), 4, AmoOp::Xor),
+            Instruction::AmoandW(i) => amo(i.rd(), i.rs2(), 4, AmoOp::And),
+            Instruction::AmoorW(i) => amo(i.rd(), i.rs2(), 4, AmoOp::Or),
+            Instruction::AmominW(i) => amo(i.rd(), i.rs2(), 4, AmoOp::Min),
+            Instruction::AmomaxW(i) => amo(i.rd(), i.rs2(), 4, AmoOp::Max),
+            Instruction::AmominuW(i) => amo(i.rd(), i.rs2(), 4, AmoOp::Minu),
+            Instruction::AmomaxuW(i) => amo(i.rd(), i.rs2(), 4, AmoOp::Maxu),
+            Instruction::AmoswapD(i) => amo(i.rd(), i.rs2(), 8, AmoOp::Swap),
+            Instruction::AmoaddD(i) => amo(i.rd(), i.rs2(), 8, AmoOp::Add),
+            Instruction::AmoxorD(i) => amo(i.rd(), i.rs2(), 8, AmoOp::Xor),
+            Instruction::AmoandD(i) => amo(i.rd(), i.rs2(), 8, AmoOp::And),
+            Instruction::AmoorD(i) => amo(i.rd(), i.rs2(), 8, AmoOp::Or),
+            Instruction::AmominD(i) => amo(i.rd(), i.rs2(), 8, AmoOp::Min),
+            Instruction::AmomaxD(i) => amo(i.rd(), i.rs2(), 8, AmoOp::Max),
+            Instruction::AmominuD(i) => amo(i.rd(), i.rs2(), 8, AmoOp::Minu),
+            Instruction::AmomaxuD(i) => amo(i.rd(), i.rs2(), 8, AmoOp::Maxu),
+            _ => Err(VmmError::UnexpectedInst)
+        }
+    }
+
+    /// integer loads and stores of the C extension, `riscv_decode` does not expand them
+    pub fn decode_compressed(ctx: &TrapContext, inst: usize) -> Option<Self> {
+        // rd'/rs2' of the CL/CS formats and rd/rs2 of the stack-pointer based ones
+        let short_reg = 8 + (inst >> 2 & 0x7);
+        let rd = inst >> 7 & 0x1f;
+        let rs2 = inst >> 2 & 0x1f;
+        let store = |rs2: usize, width: usize| Some(MmioAccess::Store { value: truncate(ctx.x[rs2], width), width });
+        match (inst & 0x3, inst >> 13 & 0x7) {
+            // c.lw, c.ld
+            (0b00, 0b010) => Some(MmioAccess::Load { rd: short_reg, width: 4, signed: true }),
+            (0b00, 0b011) => Some(MmioAccess::Load { rd: short_reg, width: 8, signed: false }),
+            // c.sw, c.sd
+            (0b00, 0b110) => store(short_reg, 4),
+            (0b00, 0b111) => store(short_reg, 8),
+            // c.lwsp, c.ldsp
+            (0b10, 0b010) => Some(MmioAccess::Load { rd, width: 4, signed: true }),
+            (0b10, 0b011) => Some(MmioAccess::Load { rd, width: 8, signed: false }),
+            // c.swsp, c.sdsp
+            (0b10, 0b110) => store(rs2, 4),
+            (0b10, 0b111) => store(rs2, 8),
+            _ => None
+        }
+    }
+
+    pub fn width(&self) -> usize {
+        match *self {
+            MmioAccess::Load { width, .. } | MmioAccess::Store { width, .. } | MmioAccess::Amo { width, .. } => width
+        }
+    }
+
+    /// give `old`, the value of the register before the access, to a load or an AMO
+    pub fn complete_read(&self, ctx: &mut TrapContext, old: usize) {
+        match *self {
+            MmioAccess::Load { rd, width, signed } => Self::complete_load(ctx, rd, width, signed, old),
+            MmioAccess::Amo { rd, width, .. } => Self::complete_load(ctx, rd, width, true, old),
+            MmioAccess::Store { .. } => {}
+        }
+    }
+
+    /// value a store or an AMO leaves in the register holding `old`, `None` for a load
+    pub fn written(&self, old: usize) -> Option<usize> {
+        match *self {
+            MmioAccess::Load { .. } => None,
+            MmioAccess::Store { value, .. } => Some(value),
+            MmioAccess::Amo { value, width, op, .. } => Some(op.apply(old, value, width))
+        }
+    }
+
+    /// write the value of an emulated load to its destination register
+    pub fn complete_load(ctx: &mut TrapContext, rd: usize, width: usize, signed: bool, value: usize) {
+        if rd == 0 {
+            return
+        }
+        ctx.x[rd] = match (width, signed) {
+            (1, true) => value as i8 as usize,
+            (2, true) => value as i16 as usize,
+            (4, true) => value as i32 as usize,
+            (1, false) => value as u8 as usize,
+            (2, false) => value as u16 as usize,
+            (4, false) => value as u32 as usize,
+            _ => value
+        };
+    }
+}
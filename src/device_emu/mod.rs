@@ -1 +1,44 @@
-pub mod plic;
\ No newline at end of file
+pub mod plic;
+pub mod virtio_slot;
+pub mod rtc;
+pub mod syscon;
+pub mod watchdog;
+pub mod mmio;
+pub mod pci;
+pub mod virtio;
+pub mod hotplug;
+
+/// power events of an emulated device of a guest, delivered in the order of
+/// `Guest::devices_mut` by the guest lifecycle, see `guest::lifecycle`
+pub trait DeviceLifecycle {
+    /// back to the power-on state, the guest reboots
+    fn reset(&mut self);
+
+    /// finish work the guest handed to the device, e.g. cached writes or buffered output.
+    /// Called when the guest pauses, powers off or reboots.
+    fn flush(&mut self) {}
+
+    /// the guest goes away for good, its memory is still there
+    fn destroy(&mut self) {
+        self.flush();
+    }
+}
+
+impl DeviceLifecycle for rtc::GoldfishRtc {
+    fn reset(&mut self) {
+        rtc::GoldfishRtc::reset(self)
+    }
+}
+
+impl DeviceLifecycle for watchdog::SifiveWatchdog {
+    fn reset(&mut self) {
+        watchdog::SifiveWatchdog::reset(self)
+    }
+}
+
+impl DeviceLifecycle for pci::VirtualEcam {
+    /// BAR mappings went away with the old stage-2 table
+    fn reset(&mut self) {
+        pci::VirtualEcam::reset(self)
+    }
+}
@@ -0,0 +1,607 @@
+//! Virtual PCI ECAM
+//!
+//! The ECAM window of a guest is reserved as an MMIO window: config space accesses
+//! trap and are filtered here. Devices assigned to the guest are forwarded to the host config
+//! space, everything else reads as all-ones (no device). BARs of assigned devices
+//! are virtual: the hypervisor places the device on the host at boot, the guest
+//! programs its own addresses and the stage-2 table maps those onto the host BARs.
+//!
+//! The pages of a BAR holding an MSI-X table are reserved as well. Guest table entries
+//! are kept here, the host entries target the IMSIC of the hypervisor which routes
+//! the MSIs back to the guest (see `drivers::irq`).
+
+use alloc::vec::Vec;
+
+use crate::arch;
+use crate::constants::PAGE_SIZE;
+use crate::constants::pci::{ ECAM_HOST_WINDOW, HOST_BAR_WINDOW };
+use crate::device_emu::mmio::{ MmioAccess, MmioDevice };
+use crate::guest::page_table::GuestPageTable;
+use crate::guest::vmexit::TrapContext;
+use crate::hypervisor::HostVmm;
+use crate::mm::{ MapArea, MapPermission, MapType, MemorySet, GuestMemorySet };
+use crate::page_table::PageTable;
+use crate::{ VmmError, VmmResult };
+
+pub mod regs {
+    pub const VENDOR_ID: usize = 0x00;
+    pub const COMMAND: usize = 0x04;
+    pub const STATUS: usize = 0x06;
+    pub const HEADER_TYPE: usize = 0x0e;
+    pub const BAR0: usize = 0x10;
+    pub const BAR5: usize = 0x24;
+    pub const ROM: usize = 0x30;
+    pub const CAPABILITIES: usize = 0x34;
+
+    pub const STATUS_CAPABILITIES: u32 = 1 << 4;
+    pub const CAP_ID_MSIX: u32 = 0x11;
+    /// MSI-X capability: message control, table offset/BIR
+    pub const MSIX_CONTROL: usize = 0x02;
+    pub const MSIX_TABLE: usize = 0x04;
+    pub const MSIX_ENTRY_SIZE: usize = 16;
+
+    pub const COMMAND_MEMORY: u16 = 1 << 1;
+    pub const COMMAND_MASTER: u16 = 1 << 2;
+
+    pub const BAR_IO: u32 = 1 << 0;
+    pub const BAR_TYPE_64: u32 = 0b10 << 1;
+    pub const BAR_FLAGS: u32 = 0xf;
+}
+
+/// bus/device/function
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Bdf {
+    pub bus: u8,
+    pub dev: u8,
+    pub func: u8
+}
+
+impl Bdf {
+    fn from_ecam_offset(offset: usize) -> Self {
+        Self { bus: (offset >> 20) as u8, dev: ((offset >> 15) & 0x1f) as u8, func: ((offset >> 12) & 0x7) as u8 }
+    }
+
+    /// requester id of the function on the bus, tags its DMA
+    pub fn requester_id(&self) -> usize {
+        (self.bus as usize) << 8 | (self.dev as usize) << 3 | self.func as usize
+    }
+
+    pub fn ecam_offset(&self) -> usize {
+        (self.bus as usize) << 20 | (self.dev as usize) << 15 | (self.func as usize) << 12
+    }
+
+    /// `bus:dev.func` in hex, e.g. `00:02.0`
+    pub fn parse(s: &str) -> Option<Self> {
+        let (bus, rest) = s.split_once(':')?;
+        let (dev, func) = rest.split_once('.')?;
+        let bdf = Self {
+            bus: u8::from_str_radix(bus, 16).ok()?,
+            dev: u8::from_str_radix(dev, 16).ok()?,
+            func: u8::from_str_radix(func, 16).ok()?
+        };
+        if bdf.dev > 0x1f || bdf.func > 7 {
+            return None
+        }
+        Some(bdf)
+    }
+}
+
+/// config space of a function on the host
+#[derive(Clone, Copy, Debug)]
+pub struct HostFunction {
+    base: usize
+}
+
+impl HostFunction {
+    pub fn new(ecam_base: usize, bdf: Bdf) -> Self {
+        Self { base: ecam_base + bdf.ecam_offset() }
+    }
+
+    pub fn read(&self, reg: usize, width: usize) -> u32 {
+        unsafe{
+            match width {
+                1 => core::ptr::read_volatile((self.base + reg) as *const u8) as u32,
+                2 => core::ptr::read_volatile((self.base + reg) as *const u16) as u32,
+                _ => core::ptr::read_volatile((self.base + reg) as *const u32)
+            }
+        }
+    }
+
+    pub fn write(&self, reg: usize, width: usize, value: u32) {
+        unsafe{
+            match width {
+                1 => core::ptr::write_volatile((self.base + reg) as *mut u8, value as u8),
+                2 => core::ptr::write_volatile((self.base + reg) as *mut u16, value as u16),
+                _ => core::ptr::write_volatile((self.base + reg) as *mut u32, value)
+            }
+        }
+    }
+}
+
+/// memory BAR of an assigned device
+#[derive(Clone, Copy, Debug)]
+struct VirtualBar {
+    /// host physical address the device decodes
+    host: usize,
+    size: usize,
+    /// type and prefetch bits
+    flags: u32,
+    is_64: bool,
+    /// address programmed by the guest
+    guest: u64,
+    /// guest address currently mapped onto `host`
+    mapped: Option<usize>
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct MsixEntry {
+    pub addr: u64,
+    pub data: u32,
+    pub masked: bool,
+    /// IMSIC identity the host entry targets
+    pub host_id: Option<usize>
+}
+
+/// MSI-X table of an assigned function as seen by the guest
+#[derive(Clone, Debug)]
+struct VirtualMsix {
+    table_bar: usize,
+    table_offset: usize,
+    entries: Vec<MsixEntry>
+}
+
+impl VirtualMsix {
+    /// page aligned part of the BAR left unmapped, offsets into the BAR
+    fn hole(&self) -> (usize, usize) {
+        let start = self.table_offset & !(PAGE_SIZE - 1);
+        let end = (self.table_offset + self.entries.len() * regs::MSIX_ENTRY_SIZE + PAGE_SIZE - 1) & !(PAGE_SIZE - 1);
+        (start, end)
+    }
+}
+
+/// function passed through to a guest
+#[derive(Clone, Debug)]
+pub struct AssignedFunction {
+    pub bdf: Bdf,
+    host: HostFunction,
+    bars: [Option<VirtualBar>; 6],
+    msix: Option<VirtualMsix>,
+    /// memory decoding enabled by the guest, BARs are mapped only then
+    memory_enabled: bool
+}
+
+/// bump allocator for host BAR addresses
+pub struct BarAllocator {
+    next: usize,
+    end: usize
+}
+
+impl BarAllocator {
+    pub fn new() -> Self {
+        Self { next: HOST_BAR_WINDOW.0, end: HOST_BAR_WINDOW.0 + HOST_BAR_WINDOW.1 }
+    }
+
+    /// host range holding all allocated BARs (base, size)
+    pub fn allocated(&self) -> (usize, usize) {
+        (HOST_BAR_WINDOW.0, self.next - HOST_BAR_WINDOW.0)
+    }
+
+    fn alloc(&mut self, size: usize) -> Option<usize> {
+        // BARs are naturally aligned, whole pages so they can be mapped to guests
+        let size = size.max(PAGE_SIZE);
+        let addr = (self.next + size - 1) & !(size - 1);
+        if addr + size > self.end {
+            return None
+        }
+        self.next = addr + size;
+        Some(addr)
+    }
+}
+
+impl AssignedFunction {
+    /// size the memory BARs of a host function and place them in the host window.
+    /// Runs at boot with the ECAM window identity mapped.
+    pub fn assign(ecam_base: usize, bdf: Bdf, allocator: &mut BarAllocator) -> VmmResult<Self> {
+        if bdf.ecam_offset() >= ECAM_HOST_WINDOW {
+            return Err(VmmError::NotSupported)
+        }
+        let host = HostFunction::new(ecam_base, bdf);
+        if host.read(regs::VENDOR_ID, 2) == 0xffff {
+            return Err(VmmError::DeviceNotFound)
+        }
+        // bridges are not assigned
+        if host.read(regs::HEADER_TYPE, 1) & 0x7f != 0 {
+            return Err(VmmError::NotSupported)
+        }
+        let command = host.read(regs::COMMAND, 2) as u16;
+        host.write(regs::COMMAND, 2, (command & !regs::COMMAND_MEMORY) as u32);
+        let mut bars = [None; 6];
+        let mut index = 0;
+        while index < 6 {
+            let reg = regs::BAR0 + 4 * index;
+            let original = host.read(reg, 4);
+            host.write(reg, 4, 0xffff_ffff);
+            let low = host.read(reg, 4);
+            host.write(reg, 4, original);
+            if low == 0 || original & regs::BAR_IO != 0 {
+                // unimplemented or I/O space BAR, hidden from the guest
+                index += 1;
+                continue
+            }
+            let is_64 = original & 0b110 == regs::BAR_TYPE_64;
+            let mut mask = (low & !regs::BAR_FLAGS) as u64 | 0xffff_ffff_0000_0000;
+            if is_64 {
+                let high_reg = reg + 4;
+                let original_high = host.read(high_reg, 4);
+                host.write(high_reg, 4, 0xffff_ffff);
+                mask = (host.read(high_reg, 4) as u64) << 32 | (low & !regs::BAR_FLAGS) as u64;
+                host.write(high_reg, 4, original_high);
+            }
+            let size = (!mask).wrapping_add(1) as usize;
+            let addr = allocator.alloc(size).ok_or(VmmError::NotSupported)?;
+            host.write(reg, 4, addr as u32 | (original & regs::BAR_FLAGS));
+            if is_64 {
+                host.write(reg + 4, 4, (addr >> 32) as u32);
+            }
+            hdebug!("pci {:02x}:{:02x}.{} bar{}: {:#x} size {:#x}", bdf.bus, bdf.dev, bdf.func, index, addr, size);
+            bars[index] = Some(VirtualBar {
+                host: addr, size, flags: original & regs::BAR_FLAGS, is_64, guest: 0, mapped: None
+            });
+            index += if is_64 { 2 } else { 1 };
+        }
+        host.write(regs::COMMAND, 2, (command | regs::COMMAND_MEMORY | regs::COMMAND_MASTER) as u32);
+        let msix = Self::find_capability(&host, regs::CAP_ID_MSIX).and_then(|cap| {
+            let entries = (host.read(cap + regs::MSIX_CONTROL, 2) & 0x7ff) as usize + 1;
+            let table = host.read(cap + regs::MSIX_TABLE, 4);
+            let table_bar = (table & 0x7) as usize;
+            let table_offset = (table & !0x7) as usize;
+            bars.get(table_bar).copied().flatten()?;
+            hdebug!("pci {:02x}:{:02x}.{} msix: {} entries in bar{}", bdf.bus, bdf.dev, bdf.func, entries, table_bar);
+            let entry = MsixEntry { addr: 0, data: 0, masked: true, host_id: None };
+            Some(VirtualMsix { table_bar, table_offset, entries: alloc::vec![entry; entries] })
+        });
+        Ok(Self { bdf, host, bars, msix, memory_enabled: false })
+    }
+
+    fn find_capability(host: &HostFunction, id: u32) -> Option<usize> {
+        if host.read(regs::STATUS, 2) & regs::STATUS_CAPABILITIES == 0 {
+            return None
+        }
+        let mut ptr = (host.read(regs::CAPABILITIES, 1) & 0xfc) as usize;
+        // bounded in case of a looping list
+        for _ in 0..48 {
+            if ptr == 0 {
+                return None
+            }
+            if host.read(ptr, 1) == id {
+                return Some(ptr)
+            }
+            ptr = (host.read(ptr + 1, 1) & 0xfc) as usize;
+        }
+        None
+    }
+
+    /// BAR containing register `reg`, and whether `reg` is the upper half of a 64-bit BAR
+    fn bar_of(&mut self, reg: usize) -> Option<(&mut VirtualBar, bool)> {
+        let index = (reg - regs::BAR0) / 4;
+        if self.bars[index].is_some() {
+            return self.bars[index].as_mut().map(|bar| (bar, false))
+        }
+        if index > 0 && self.bars[index - 1].map_or(false, |bar| bar.is_64) {
+            return self.bars[index - 1].as_mut().map(|bar| (bar, true))
+        }
+        None
+    }
+
+    fn read_bar(&mut self, reg: usize) -> u32 {
+        match self.bar_of(reg) {
+            Some((bar, false)) => bar.guest as u32 | bar.flags,
+            Some((bar, true)) => (bar.guest >> 32) as u32,
+            None => 0
+        }
+    }
+
+    fn write_bar(&mut self, reg: usize, value: u32) {
+        if let Some((bar, upper)) = self.bar_of(reg) {
+            // the size of the BAR shows in the address bits that stay zero
+            let mask = !(bar.size as u64 - 1);
+            bar.guest = if upper {
+                (bar.guest & 0xffff_ffff | (value as u64) << 32) & mask
+            }else{
+                (bar.guest & 0xffff_ffff_0000_0000 | (value & !regs::BAR_FLAGS) as u64) & mask
+            };
+            if !bar.is_64 {
+                bar.guest &= 0xffff_ffff;
+            }
+        }
+    }
+
+    /// map the BARs at the addresses programmed by the guest, returns whether the
+    /// stage-2 table changed. A BAR that the guest placed over its ram, an emulated
+    /// device or another BAR stays unmapped. The guest TLB must be flushed in that case too.
+    fn sync_mappings<G: GuestPageTable>(&mut self, gpm: &mut GuestMemorySet<G>) -> VmmResult<bool> {
+        let mut changed = false;
+        for (index, bar) in self.bars.iter_mut().enumerate() {
+            let bar = match bar {
+                Some(bar) => bar,
+                None => continue
+            };
+            let size = bar.size.max(PAGE_SIZE);
+            let target = bar.guest as usize;
+            // zero before the guest placed the BAR
+            let wanted = if self.memory_enabled && target != 0 { Some(target) } else { None };
+            if wanted == bar.mapped {
+                continue
+            }
+            // the MSI-X table is cut out of the mapping
+            let (hole_start, hole_end) = match &self.msix {
+                Some(msix) if msix.table_bar == index => msix.hole(),
+                _ => (size, size)
+            };
+            let pieces = [(0, hole_start), (hole_start, hole_end), (hole_end, size)];
+            if let Some(old) = bar.mapped.take() {
+                for &(start, end) in pieces.iter().filter(|(start, end)| start < end) {
+                    gpm.remove_area((old + start).into());
+                }
+            }
+            if let Some(target) = wanted {
+                match map_bar(gpm, target, bar.host, &pieces) {
+                    Ok(()) => bar.mapped = Some(target),
+                    Err(VmmError::InvalidState) => hwarning!("BAR at {:#x} overlaps guest memory or a device, left unmapped", target),
+                    Err(err) => return Err(err)
+                }
+            }
+            changed = true;
+        }
+        Ok(changed)
+    }
+
+    /// offset into the MSI-X BAR of a guest access to the unmapped table pages
+    fn msix_offset(&self, guest_pa: usize) -> Option<usize> {
+        let msix = self.msix.as_ref()?;
+        let base = self.bars[msix.table_bar]?.mapped?;
+        let (hole_start, hole_end) = msix.hole();
+        let offset = guest_pa.checked_sub(base)?;
+        if offset >= hole_start && offset < hole_end { Some(offset) } else { None }
+    }
+
+    /// emulate an access to the MSI-X table pages, returns the entry to reprogram on the host
+    fn handle_msix_access(&mut self, ctx: &mut TrapContext, offset: usize, access: MmioAccess) -> Option<usize> {
+        let msix = self.msix.as_mut()?;
+        let host = self.bars[msix.table_bar]?.host + offset;
+        let table_len = msix.entries.len() * regs::MSIX_ENTRY_SIZE;
+        if offset < msix.table_offset || offset >= msix.table_offset + table_len {
+            // pending bit array or other registers sharing the page
+            match access {
+                MmioAccess::Load { rd, width, signed } => {
+                    let value = unsafe{ match width {
+                        8 => core::ptr::read_volatile(host as *const u64) as usize,
+                        _ => core::ptr::read_volatile(host as *const u32) as usize
+                    } };
+                    MmioAccess::complete_load(ctx, rd, width, signed, value);
+                },
+                MmioAccess::Store { value, width } => unsafe{ match width {
+                    8 => core::ptr::write_volatile(host as *mut u64, value as u64),
+                    _ => core::ptr::write_volatile(host as *mut u32, value as u32)
+                } },
+                // refused by `handle_pci_access`
+                MmioAccess::Amo { .. } => {}
+            }
+            return None
+        }
+        let index = (offset - msix.table_offset) / regs::MSIX_ENTRY_SIZE;
+        let field = (offset - msix.table_offset) % regs::MSIX_ENTRY_SIZE;
+        let entry = &mut msix.entries[index];
+        match access {
+            MmioAccess::Load { rd, width, signed } => {
+                let value = match (field, width) {
+                    (0, 8) => entry.addr as usize,
+                    (0, _) => entry.addr as u32 as usize,
+                    (4, _) => (entry.addr >> 32) as usize,
+                    (8, _) => entry.data as usize,
+                    (12, _) => entry.masked as usize,
+                    _ => 0
+                };
+                MmioAccess::complete_load(ctx, rd, width, signed, value);
+                None
+            },
+            MmioAccess::Store { value, width } => {
+                match (field, width) {
+                    (0, 8) => entry.addr = value as u64,
+                    (0, _) => entry.addr = entry.addr & !0xffff_ffff | value as u32 as u64,
+                    (4, _) => entry.addr = entry.addr & 0xffff_ffff | (value as u64) << 32,
+                    (8, _) => entry.data = value as u32,
+                    (12, _) => entry.masked = value & 1 != 0,
+                    _ => return None
+                }
+                Some(index)
+            },
+            MmioAccess::Amo { .. } => None
+        }
+    }
+
+    /// MSI-X entry of the guest
+    pub fn msix_entry(&self, index: usize) -> Option<MsixEntry> {
+        self.msix.as_ref()?.entries.get(index).copied()
+    }
+
+    pub fn set_msix_host_id(&mut self, index: usize, id: usize) {
+        if let Some(entry) = self.msix.as_mut().and_then(|msix| msix.entries.get_mut(index)) {
+            entry.host_id = Some(id);
+        }
+    }
+
+    /// program a host MSI-X entry, `None` masks it
+    pub fn write_host_msix(&self, index: usize, msi: Option<(usize, u32)>) {
+        let msix = match self.msix.as_ref() {
+            Some(msix) => msix,
+            None => return
+        };
+        let host_bar = match self.bars[msix.table_bar] {
+            Some(bar) => bar.host,
+            None => return
+        };
+        let entry = host_bar + msix.table_offset + index * regs::MSIX_ENTRY_SIZE;
+        unsafe{
+            match msi {
+                Some((addr, data)) => {
+                    core::ptr::write_volatile(entry as *mut u32, addr as u32);
+                    core::ptr::write_volatile((entry + 4) as *mut u32, (addr >> 32) as u32);
+                    core::ptr::write_volatile((entry + 8) as *mut u32, data);
+                    core::ptr::write_volatile((entry + 12) as *mut u32, 0);
+                },
+                None => core::ptr::write_volatile((entry + 12) as *mut u32, 1)
+            }
+        }
+    }
+
+    /// take the function away from the guest: unmap its BARs, mask its MSI-X entries
+    /// and stop the device. Returns the IMSIC identities its entries were routed to.
+    pub fn release<G: GuestPageTable>(&mut self, gpm: &mut GuestMemorySet<G>) -> Vec<usize> {
+        self.memory_enabled = false;
+        // unmapping takes no frames
+        let _ = self.sync_mappings(gpm);
+        let host_ids = self.msix.as_ref()
+            .map(|msix| msix.entries.iter().filter_map(|entry| entry.host_id).collect())
+            .unwrap_or_default();
+        self.reset();
+        let command = self.host.read(regs::COMMAND, 2) as u16;
+        self.host.write(regs::COMMAND, 2, (command & !(regs::COMMAND_MEMORY | regs::COMMAND_MASTER)) as u32);
+        host_ids
+    }
+
+    /// device back to its boot state, the stage-2 table is rebuilt by the caller
+    fn reset(&mut self) {
+        self.memory_enabled = false;
+        if let Some(msix) = self.msix.as_mut() {
+            for (index, entry) in msix.entries.iter_mut().enumerate() {
+                entry.masked = true;
+                if let Some(bar) = self.bars[msix.table_bar] {
+                    let control = bar.host + msix.table_offset + index * regs::MSIX_ENTRY_SIZE + 12;
+                    unsafe{ core::ptr::write_volatile(control as *mut u32, 1) };
+                }
+            }
+        }
+        for bar in self.bars.iter_mut().flatten() {
+            bar.guest = 0;
+            bar.mapped = None;
+        }
+    }
+}
+
+/// config space of a guest
+#[derive(Default)]
+pub struct VirtualEcam {
+    pub functions: Vec<AssignedFunction>
+}
+
+impl VirtualEcam {
+    pub fn assign(&mut self, function: AssignedFunction) {
+        self.functions.push(function);
+    }
+
+    pub fn reset(&mut self) {
+        self.functions.iter_mut().for_each(|function| function.reset());
+    }
+}
+
+/// map the pieces of a BAR placed at `target`, the middle one is the MSI-X hole reserved
+/// for emulation. A BAR overlapping any area of the guest is refused with `InvalidState`,
+/// nothing is mapped on failure.
+fn map_bar<G: GuestPageTable>(gpm: &mut GuestMemorySet<G>, target: usize, host: usize, pieces: &[(usize, usize); 3]) -> VmmResult {
+    let size = pieces[2].1;
+    if target.checked_add(size).is_none() || !gpm.is_free(target, size) {
+        return Err(VmmError::InvalidState)
+    }
+    for (i, &(start, end)) in pieces.iter().enumerate().filter(|(_, (start, end))| start < end) {
+        let mapped = if i == 1 {
+            gpm.reserve_mmio(target + start, end - start, MmioDevice::Pci)
+        }else{
+            gpm.try_push(MapArea::new(
+                (target + start).into(),
+                (target + end).into(),
+                Some((host + start).into()),
+                Some((host + end).into()),
+                MapType::Linear,
+                MapPermission::R | MapPermission::W | MapPermission::U
+            ), None)
+        };
+        if let Err(err) = mapped {
+            for &(start, end) in pieces[..i].iter().filter(|(start, end)| start < end) {
+                gpm.remove_area((target + start).into());
+            }
+            return Err(err)
+        }
+    }
+    Ok(())
+}
+
+impl<P: PageTable, G: GuestPageTable> HostVmm<P, G> {
+    /// access to the ECAM window or to an MSI-X table of the guest
+    pub fn handle_pci_access(&mut self, ctx: &mut TrapContext, guest_pa: usize, access: MmioAccess) -> VmmResult {
+        if let MmioAccess::Amo { .. } = access {
+            return Err(VmmError::UnexpectedInst)
+        }
+        let guest_id = self.guest_id;
+        let guest = self.guests.get_mut(guest_id).ok_or(VmmError::NoFound)?;
+        let msix = guest.pci.functions.iter_mut().enumerate()
+            .find_map(|(index, function)| function.msix_offset(guest_pa).map(|offset| (index, function, offset)));
+        if let Some((function_index, function, offset)) = msix {
+            if let Some(entry) = function.handle_msix_access(ctx, offset, access) {
+                self.route_msix(guest_id, function_index, entry);
+            }
+            return Ok(())
+        }
+        let offset = guest_pa - guest.guest_machine.pci.as_ref().ok_or(VmmError::DeviceNotFound)?.base_address;
+        let bdf = Bdf::from_ecam_offset(offset);
+        let reg = offset & 0xfff;
+        let function = guest.pci.functions.iter_mut().find(|function| function.bdf == bdf);
+        match (access, function) {
+            // no device
+            (MmioAccess::Load { rd, width, .. }, None) => MmioAccess::complete_load(ctx, rd, width, false, usize::MAX),
+            (MmioAccess::Store { .. }, None) => {},
+            (MmioAccess::Load { rd, width, .. }, Some(function)) => {
+                let value = match reg {
+                    regs::BAR0..=0x27 if width == 4 => function.read_bar(reg),
+                    // expansion ROM is not exposed
+                    regs::ROM..=0x33 => 0,
+                    _ => function.host.read(reg, width)
+                };
+                MmioAccess::complete_load(ctx, rd, width, false, value as usize);
+            },
+            (MmioAccess::Store { value, width }, Some(function)) => {
+                match reg {
+                    regs::BAR0..=0x27 => {
+                        if width == 4 {
+                            function.write_bar(reg, value as u32);
+                        }
+                    },
+                    regs::ROM..=0x33 => {},
+                    // host decoding and bus mastering stay on, guest decoding only
+                    // decides whether the BARs are mapped
+                    regs::COMMAND if width >= 2 => {
+                        function.memory_enabled = value as u16 & regs::COMMAND_MEMORY != 0;
+                        let command = value as u16 | regs::COMMAND_MEMORY | regs::COMMAND_MASTER;
+                        function.host.write(reg, 2, command as u32);
+                    },
+                    _ => function.host.write(reg, width, value as u32)
+                }
+                // the guest may have moved its BARs or turned decoding on or off
+                return self.retry_on_oom(guest_id, |vmm| vmm.sync_bars(guest_id, bdf))
+            },
+            (MmioAccess::Amo { .. }, _) => {}
+        }
+        Ok(())
+    }
+
+    /// map the BARs of `bdf` where the guest placed them
+    fn sync_bars(&mut self, guest_id: usize, bdf: Bdf) -> VmmResult {
+        let guest = self.guests.get_mut(guest_id).ok_or(VmmError::NoFound)?;
+        let function = guest.pci.functions.iter_mut().find(|function| function.bdf == bdf).ok_or(VmmError::DeviceNotFound)?;
+        let synced = function.sync_mappings(&mut guest.gpm);
+        if synced != Ok(false) {
+            arch::flush_stage2_tlb();
+        }
+        synced.map(|_| ())
+    }
+}
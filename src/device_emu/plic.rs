@@ -1,17 +1,38 @@
-use riscv::register::hvip;
-use riscv_decode::Instruction;
+//! PLIC of the guests
+//!
+//! Priorities and enables are read-mostly, a guest reads them from shadow pages mapped
+//! read-only at their place (`MapType::Shadow`) without a trap. Its writes trap and are
+//! applied to the physical PLIC, the enables of a guest context to the physical context
+//! of the vcpu, then copied to the shadow. Sources of host devices cannot be changed by
+//! guests and read as 0. Pending bits, threshold and claim/complete are emulated.
+//!
+//! The claim register of a real-time guest is refilled from the physical PLIC as soon as
+//! the guest completes an interrupt or reads it empty, an interrupt pending meanwhile is
+//! delivered without the exit of its own trap.
+//!
+//! Registers are 32 bits wide. Besides loads and stores the guest may use AMOs on them,
+//! emulated as a read and a write of the register, e.g. an `amoor.w` on an enable word
+//! reads the shadow and applies the new enables like a store, see `mmio::MmioAccess`.
 
+#[cfg(feature = "tracing")]
+use riscv::register::time;
+use crate::device_emu::mmio::MmioAccess;
 use crate::guest::vmexit::TrapContext;
+#[cfg(feature = "tracing")]
+use crate::arch::{ self, Irq };
+use crate::irqlat;
 use crate::{VmmError, VmmResult};
-use crate::{constants::MAX_CONTEXTS, page_table::PageTable, guest::page_table::GuestPageTable, hypervisor::HostVmm};
-
-pub const PLIC_OFFSET: &[(usize, usize)] = &[
-    (0x0, 0x1000), // Interrupt priority
-    (0x1000, 0x1000), // Interrupt Pending  
-    (0x2000, 0x200000 - 0x2000), // Enable 
-    (0x200000, 0x600000 - 0x200000) // threshold/claim/complete
-];
+use crate::{constants::{ MAX_CONTEXTS, PAGE_SIZE }, page_table::PageTable, guest::page_table::GuestPageTable, hypervisor::HostVmm};
 
+/// register blocks of the PLIC
+pub const PLIC_PRIORITY: usize = 0x0;
+pub const PLIC_PENDING: usize = 0x1000;
+pub const PLIC_ENABLE: usize = 0x2000;
+/// threshold/claim/complete
+pub const PLIC_CONTEXT: usize = 0x200000;
+const ENABLE_STRIDE: usize = 0x80;
+/// enables of guest contexts 0 to 31 are shadowed, the ones of 16 vcpus
+pub const PLIC_SHADOW_ENABLE_SIZE: usize = PAGE_SIZE;
 
 pub struct PlicState {
     pub base_addr: usize,
@@ -26,74 +47,229 @@ impl PlicState {
         }
     }
 
-    
+    /// give `irq` a non-zero priority and enable it for `context` on the physical PLIC
+    pub fn enable_irq(&self, context: usize, irq: usize) {
+        let priority = self.base_addr + 4 * irq;
+        let enable = self.base_addr + 0x2000 + 0x80 * context + 4 * (irq / 32);
+        unsafe{
+            core::ptr::write_volatile(priority as *mut u32, 1);
+            let bits = core::ptr::read_volatile(enable as *const u32);
+            core::ptr::write_volatile(enable as *mut u32, bits | 1 << (irq % 32));
+        }
+    }
+
+    /// disable all sources but the ones in `keep` for `context`, e.g. when its guest resets
+    pub fn clear_enables(&self, context: usize, keep: &[usize]) {
+        for word in 0..ENABLE_STRIDE / 4 {
+            let enable = self.base_addr + PLIC_ENABLE + ENABLE_STRIDE * context + 4 * word;
+            let mask = source_mask(keep, word);
+            unsafe{
+                let bits = core::ptr::read_volatile(enable as *const u32);
+                core::ptr::write_volatile(enable as *mut u32, bits & mask);
+            }
+        }
+    }
+}
+
+/// bits of the `sources` in enable word `word`
+fn source_mask(sources: &[usize], word: usize) -> u32 {
+    sources.iter()
+        .filter(|&&irq| irq / 32 == word)
+        .fold(0, |mask, &irq| mask | 1 << (irq % 32))
 }
 
 impl<P: PageTable, G: GuestPageTable> HostVmm<P, G> {
-    pub fn handle_plic_access(&mut self, ctx: &mut TrapContext ,guest_pa: usize, instrution: Instruction) -> VmmResult {
-        let host_plic = self.host_plic.as_mut().unwrap();
-        let offset = guest_pa.wrapping_sub(host_plic.base_addr);
+    /// physical PLIC context behind context `guest_context` of the running guest,
+    /// guests see S-mode context `2 * vcpu + 1` for each of their vcpus
+    fn guest_plic_context(&self, guest_context: usize) -> Option<usize> {
+        if guest_context % 2 == 0 {
+            return None
+        }
+        self.plic_contexts.context(self.guest_id, guest_context / 2)
+    }
+
+    /// store or AMO to a priority or an enable, applied to the physical PLIC and the shadow
+    /// page. Loads only trap for enables of contexts a guest cannot have.
+    fn handle_plic_config_access(&mut self, ctx: &mut TrapContext, guest_pa: usize, offset: usize, access: MmioAccess) -> VmmResult {
+        // what the guest reads, registers without a shadow read as 0
+        let old = self.guests.get(self.guest_id)
+            .and_then(|guest| guest.gpm.read_shadow(guest_pa).ok())
+            .unwrap_or(0);
+        access.complete_read(ctx, old as usize);
+        let value = match access.written(old as usize) {
+            Some(value) => value as u32,
+            None => return Ok(())
+        };
+        let base_addr = self.host_plic.as_ref().unwrap().base_addr;
+        let shadow = if offset < PLIC_PENDING {
+            let irq = offset / 4;
+            if irq == 0 || self.is_host_irq(irq) {
+                return Ok(())
+            }
+            unsafe{ core::ptr::write_volatile((base_addr + offset) as *mut u32, value) };
+            value
+        }else{
+            let guest_context = (offset - PLIC_ENABLE) / ENABLE_STRIDE;
+            let word = (offset - PLIC_ENABLE) % ENABLE_STRIDE / 4;
+            let context = match self.guest_plic_context(guest_context) {
+                Some(context) if offset < PLIC_ENABLE + PLIC_SHADOW_ENABLE_SIZE => context,
+                _ => return Ok(())
+            };
+            // the boot hart context is shared with host devices
+            let host_mask = source_mask(&self.host_irqs, word);
+            let enable = base_addr + PLIC_ENABLE + ENABLE_STRIDE * context + 4 * word;
+            unsafe{
+                let bits = core::ptr::read_volatile(enable as *const u32);
+                core::ptr::write_volatile(enable as *mut u32, bits & host_mask | value & !host_mask);
+            }
+            value & !host_mask
+        };
+        let guest = self.guests.get_mut(self.guest_id).ok_or(VmmError::NoFound)?;
+        guest.gpm.write_shadow(guest_pa, shadow)
+    }
+
+    pub fn handle_plic_access(&mut self, ctx: &mut TrapContext ,guest_pa: usize, access: MmioAccess) -> VmmResult {
+        if access.width() != 4 {
+            return Err(VmmError::UnexpectedInst)
+        }
+        let base_addr = self.host_plic.as_ref().unwrap().base_addr;
+        let offset = guest_pa.wrapping_sub(base_addr);
+        if offset < PLIC_PENDING || (offset >= PLIC_ENABLE && offset < PLIC_CONTEXT) {
+            return self.handle_plic_config_access(ctx, guest_pa, offset, access)
+        }
+        // pending bits are read-only, the ones of host devices are hidden
+        if offset < PLIC_ENABLE {
+            let word = (offset - PLIC_PENDING) / 4;
+            let pending = unsafe{ core::ptr::read_volatile((base_addr + offset) as *const u32) };
+            access.complete_read(ctx, (pending & !source_mask(&self.host_irqs, word)) as usize);
+            return Ok(())
+        }
         // threshold/claim/complete
         if offset >= 0x200000 && offset < 0x200000 + 0x1000 * MAX_CONTEXTS {
-            let hart = (offset - 0x200000) / 0x1000;
+            let hart = self.guest_plic_context((offset - 0x200000) / 0x1000).ok_or(VmmError::DeviceNotFound)?;
             let index = ((offset - 0x200000) & 0xfff) >> 2;
+            // same register of the physical context
+            let host_pa = base_addr + 0x200000 + 0x1000 * hart + 4 * index;
             if index == 0 {
                 // threshold
-                match instrution {
-                    Instruction::Sw(i) => {
-                        // guest write threshold register to plic core
-                        let value = ctx.x[i.rs2() as usize] as u32;
-                        // todo: guest pa -> host pa
-                        htracking!("write PLIC threshold reg, addr: {:#x}, value: {:#x}", guest_pa, value);
-                        unsafe{
-                            core::ptr::write_volatile(guest_pa as *mut u32, value);
-                        }
+                let old = unsafe{ core::ptr::read_volatile(host_pa as *const u32) };
+                access.complete_read(ctx, old as usize);
+                if let Some(value) = access.written(old as usize) {
+                    // guest write threshold register to plic core
+                    htracking!("write PLIC threshold reg, addr: {:#x}, value: {:#x}", guest_pa, value);
+                    unsafe{
+                        core::ptr::write_volatile(host_pa as *mut u32, value as u32);
                     }
-                    _ => return Err(VmmError::UnexpectedInst)
                 }
             }else if index == 1 {
-                // claim/complete
+                // claim/complete, an AMO claims then completes
                 // htracking!("claim/complete");
-                match instrution {
-                    Instruction::Lw(i) => {
-                        // guest read claim from plic core
-                        // htracking!("guest read plic claim: {}, addr: {:#x}", host_plic.claim_complete[hart], guest_pa);
-                        ctx.x[i.rd() as usize] = host_plic.claim_complete[hart] as usize;
-                    },
-                    Instruction::Sw(i) => {
-                        // guest write complete to plic core
-                        let value = ctx.x[i.rs2() as usize] as u32;
-                        // htracking!("guest write plic complete: {}, addr: {:#x}", value, guest_pa);
-                        // todo: guest pa -> host pa
-                        unsafe{
-                            core::ptr::write_volatile(guest_pa as *mut u32, value);
-                        }
-                        host_plic.claim_complete[hart] = 0;
-                        unsafe{ hvip::clear_vseip(); }
-                    },
-                    _ => return Err(VmmError::UnexpectedInst)
+                if !matches!(access, MmioAccess::Store { .. }) {
+                    self.claim_ahead(hart);
+                }
+                let host_plic = self.host_plic.as_mut().unwrap();
+                let irq = host_plic.claim_complete[hart];
+                if !matches!(access, MmioAccess::Store { .. }) {
+                    // guest read claim from plic core
+                    access.complete_read(ctx, irq as usize);
+                    #[cfg(feature = "tracing")]
+                    if irq != 0 {
+                        irqlat::claimed(self.guest_id, irq);
+                    }
+                }
+                if let Some(value) = access.written(irq as usize) {
+                    // guest write complete to plic core
+                    // htracking!("guest write plic complete: {}, addr: {:#x}", value, guest_pa);
+                    unsafe{
+                        core::ptr::write_volatile(host_pa as *mut u32, value as u32);
+                    }
+                    host_plic.claim_complete[hart] = 0;
+                    arch::lower_guest_irq(Irq::External);
+                    self.deliver_pending_irq();
+                    self.claim_ahead(hart);
                 }
             }
         }else{
-            panic!("Invalid address: {:#x}", guest_pa);
+            return Err(VmmError::DeviceNotFound)
         }
         Ok(())
     }
-}
 
+    /// claim the next interrupt of physical `context` for a running real-time guest as soon
+    /// as its claim register is free instead of on the trap of the interrupt, interrupts
+    /// arriving back to back then cost the guest no exit of their own
+    fn claim_ahead(&mut self, context: usize) {
+        let rt = self.guests.get(self.guest_id).map_or(false, |guest| guest.config.rt.is_some());
+        if !rt || self.current_plic_context() != Some(context) {
+            return
+        }
+        let claim_and_complete_addr = match self.host_plic.as_ref() {
+            Some(host_plic) if host_plic.claim_complete[context] == 0 => host_plic.base_addr + PLIC_CONTEXT + 4 + 0x1000 * context,
+            _ => return
+        };
+        // interrupts of host devices claimed on the way are handled here, once per device
+        for _ in 0..=self.host_irqs.len() {
+            let irq = unsafe{ core::ptr::read_volatile(claim_and_complete_addr as *const u32) };
+            if irq == 0 {
+                return
+            }
+            if !self.is_host_irq(irq as usize) {
+                self.host_plic.as_mut().unwrap().claim_complete[context] = irq;
+                #[cfg(feature = "tracing")]
+                irqlat::arrived(self.guest_id, irq, time::read());
+                if let Some(guest) = self.guests.get_mut(self.guest_id) {
+                    guest.raise_external_irq();
+                }
+                return
+            }
+            self.handle_host_irq(irq as usize);
+            unsafe{ core::ptr::write_volatile(claim_and_complete_addr as *mut u32, irq) };
+        }
+    }
 
-#[inline(always)]
-pub fn is_plic_access(addr: usize) -> bool {
-    // let host_vmm = unsafe{ HOST_VMM.get().unwrap().lock() };
-    // let plic = if let Some(plic) = &host_vmm.host_machine.plic {
-    //     plic
-    // }else{
-    //     return false
-    // };
-    // if addr >= plic.base_address && addr <= plic.base_address + plic.size { return true }
-    // else{ return false }
+    /// queue a virtual interrupt for a guest, delivered through the claim register
+    /// of its emulated PLIC context, or posted to its page if it registered one
+    pub fn inject_guest_irq(&mut self, guest_id: usize, irq: u32) {
+        let running = guest_id == self.guest_id;
+        if let Some(guest) = self.guests.get_mut(guest_id) {
+            if let Some(notify) = guest.posted_irqs.as_ref().and_then(|page| page.post(irq)) {
+                if notify {
+                    guest.raise_posted_irq(running);
+                }
+                return
+            }
+            if !guest.pending_irqs.contains(&irq) {
+                guest.pending_irqs.push_back(irq);
+                #[cfg(feature = "tracing")]
+                irqlat::arrived(guest_id, irq, time::read());
+            }
+        }
+        if guest_id == self.guest_id {
+            self.deliver_pending_irq();
+        }
+    }
 
-    // TODO: use guest machine base address
-    return addr >= 0x0c00_0000 && addr < 0x1000_0000
+    /// raise the next queued interrupt of the running guest once its claim register is free
+    pub fn deliver_pending_irq(&mut self) {
+        let guest = match self.guests.get_mut(self.guest_id) {
+            Some(guest) => guest,
+            None => return
+        };
+        let context = match self.plic_contexts.context(self.guest_id, guest.vcpu.hart) {
+            Some(context) => context,
+            None => return
+        };
+        let host_plic = match self.host_plic.as_mut() {
+            Some(host_plic) => host_plic,
+            None => return
+        };
+        let claim = &mut host_plic.claim_complete[context];
+        if *claim != 0 {
+            return
+        }
+        if let Some(irq) = guest.pending_irqs.pop_front() {
+            *claim = irq;
+            guest.raise_external_irq();
+        }
+    }
 }
-
@@ -0,0 +1,196 @@
+//! Goldfish RTC emulation
+//!
+//! Every guest gets its own RTC at the address of the goldfish RTC in its device
+//! tree. It counts host wall-clock nanoseconds plus a per-guest offset, which the
+//! guest changes by setting the time. The alarm is checked on the scheduler tick
+//! and raises the RTC interrupt through the emulated PLIC.
+
+use core::sync::atomic::{ AtomicU64, Ordering };
+use riscv::register::time;
+use riscv_decode::Instruction;
+
+use crate::arch::{ self, Irq };
+use crate::constants::CLOCK_FREQ;
+use crate::guest::page_table::GuestPageTable;
+use crate::guest::vmexit::TrapContext;
+use crate::hypervisor::HostVmm;
+use crate::hypervisor::fdt::MachineMeta;
+use crate::page_table::PageTable;
+use crate::{ VmmError, VmmResult };
+
+pub mod regs {
+    pub const TIME_LOW: usize = 0x00;
+    pub const TIME_HIGH: usize = 0x04;
+    pub const ALARM_LOW: usize = 0x08;
+    pub const ALARM_HIGH: usize = 0x0c;
+    pub const IRQ_ENABLED: usize = 0x10;
+    pub const CLEAR_ALARM: usize = 0x14;
+    pub const ALARM_STATUS: usize = 0x18;
+    pub const CLEAR_INTERRUPT: usize = 0x1c;
+}
+
+/// wall-clock nanoseconds when the `time` csr was 0
+static WALL_CLOCK_BASE: AtomicU64 = AtomicU64::new(0);
+
+fn cycles_to_ns(cycles: usize) -> u64 {
+    (cycles as u128 * 1_000_000_000 / CLOCK_FREQ as u128) as u64
+}
+
+/// host wall-clock time in nanoseconds
+pub fn wall_clock_ns() -> u64 {
+    WALL_CLOCK_BASE.load(Ordering::Relaxed).wrapping_add(cycles_to_ns(time::read()))
+}
+
+/// take the wall-clock time from the host RTC, or from `hvc.epoch=<unix seconds>`
+/// on the command line. Must run before paging is enabled, the host RTC is not mapped.
+pub fn init_wall_clock(machine: &MachineMeta) {
+    let now = match (&machine.rtc, machine.bootarg("hvc.epoch")) {
+        (_, Some(epoch)) => epoch.parse::<u64>().unwrap_or(0).saturating_mul(1_000_000_000),
+        (Some(rtc), None) => unsafe{
+            // reading the low word latches the high word
+            let low = core::ptr::read_volatile((rtc.base_address + regs::TIME_LOW) as *const u32);
+            let high = core::ptr::read_volatile((rtc.base_address + regs::TIME_HIGH) as *const u32);
+            (high as u64) << 32 | low as u64
+        },
+        (None, None) => 0
+    };
+    WALL_CLOCK_BASE.store(now.wrapping_sub(cycles_to_ns(time::read())), Ordering::Relaxed);
+    hdebug!("wall clock: {}s since epoch", now / 1_000_000_000);
+}
+
+#[derive(Debug, Default)]
+pub struct GoldfishRtc {
+    /// guest time minus host wall-clock time, in nanoseconds
+    offset: i64,
+    /// high word latched by reading `TIME_LOW`, or written before `TIME_LOW`
+    time_high: u32,
+    alarm_high: u32,
+    /// armed alarm, in guest time
+    alarm: Option<u64>,
+    irq_enabled: bool,
+    /// interrupt line, lowered by `CLEAR_INTERRUPT`
+    irq_level: bool,
+    /// interrupt raised but not yet delivered to the guest
+    irq_inject: bool
+}
+
+impl GoldfishRtc {
+    pub fn new(offset_secs: i64) -> Self {
+        Self { offset: offset_secs.saturating_mul(1_000_000_000), ..Default::default() }
+    }
+
+    pub fn time_ns(&self) -> u64 {
+        wall_clock_ns().wrapping_add(self.offset as u64)
+    }
+
+    /// power-on state of the device, the guest keeps its clock
+    pub fn reset(&mut self) {
+        *self = Self { offset: self.offset, ..Default::default() };
+    }
+
+    pub fn read(&mut self, offset: usize) -> u32 {
+        match offset {
+            regs::TIME_LOW => {
+                let now = self.time_ns();
+                self.time_high = (now >> 32) as u32;
+                now as u32
+            },
+            regs::TIME_HIGH => self.time_high,
+            regs::ALARM_LOW => self.alarm.unwrap_or(0) as u32,
+            regs::ALARM_HIGH => (self.alarm.unwrap_or(0) >> 32) as u32,
+            regs::IRQ_ENABLED => self.irq_enabled as u32,
+            regs::ALARM_STATUS => self.alarm.is_some() as u32,
+            _ => 0
+        }
+    }
+
+    pub fn write(&mut self, offset: usize, value: u32) {
+        match offset {
+            // writing the low word sets the time
+            regs::TIME_LOW => {
+                let time = (self.time_high as u64) << 32 | value as u64;
+                self.offset = time.wrapping_sub(wall_clock_ns()) as i64;
+            },
+            regs::TIME_HIGH => self.time_high = value,
+            // writing the low word arms the alarm
+            regs::ALARM_LOW => {
+                self.alarm = Some((self.alarm_high as u64) << 32 | value as u64);
+                self.check_alarm();
+            },
+            regs::ALARM_HIGH => self.alarm_high = value,
+            regs::IRQ_ENABLED => self.irq_enabled = value & 1 != 0,
+            regs::CLEAR_ALARM => self.alarm = None,
+            regs::CLEAR_INTERRUPT => {
+                self.irq_level = false;
+                self.irq_inject = false;
+            },
+            _ => {}
+        }
+    }
+
+    /// fire an expired alarm
+    pub fn check_alarm(&mut self) {
+        if let Some(alarm) = self.alarm {
+            if self.time_ns() >= alarm {
+                self.alarm = None;
+                if self.irq_enabled && !self.irq_level {
+                    self.irq_level = true;
+                    self.irq_inject = true;
+                }
+            }
+        }
+    }
+}
+
+impl<P: PageTable, G: GuestPageTable> HostVmm<P, G> {
+    pub fn handle_rtc_access(&mut self, ctx: &mut TrapContext, guest_pa: usize, instruction: Instruction) -> VmmResult {
+        let guest = self.guests.get_mut(self.guest_id).ok_or(VmmError::NoFound)?;
+        let offset = guest_pa - guest.guest_machine.rtc.as_ref().ok_or(VmmError::DeviceNotFound)?.base_address;
+        match instruction {
+            Instruction::Lw(i) | Instruction::Lwu(i) => {
+                let value = guest.rtc.read(offset) as usize;
+                if i.rd() != 0 {
+                    ctx.x[i.rd() as usize] = value;
+                }
+            },
+            Instruction::Sw(i) => guest.rtc.write(offset, ctx.x[i.rs2() as usize] as u32),
+            _ => return Err(VmmError::UnexpectedInst)
+        }
+        self.inject_rtc_irq();
+        Ok(())
+    }
+
+    /// fire the alarm of the running guest and deliver a raised RTC interrupt.
+    /// Called on the scheduler tick.
+    pub fn check_rtc_alarm(&mut self) {
+        if let Some(guest) = self.guests.get_mut(self.guest_id) {
+            guest.rtc.check_alarm();
+        }
+        self.inject_rtc_irq();
+    }
+
+    /// a raised RTC interrupt is delivered once the emulated PLIC has no other
+    /// interrupt claimable for the guest
+    fn inject_rtc_irq(&mut self) {
+        let guest = match self.guests.get_mut(self.guest_id) {
+            Some(guest) => guest,
+            None => return
+        };
+        let irq = match guest.guest_machine.rtc.as_ref().and_then(|rtc| rtc.irq) {
+            Some(irq) if guest.rtc.irq_inject => irq,
+            _ => return
+        };
+        let context = match self.plic_contexts.context(self.guest_id, guest.vcpu.hart) {
+            Some(context) => context,
+            None => return
+        };
+        if let Some(host_plic) = self.host_plic.as_mut() {
+            let claim = &mut host_plic.claim_complete[context];
+            if *claim == 0 {
+                *claim = irq as u32;
+                guest.rtc.irq_inject = false;
+                arch::raise_guest_irq(Irq::External);
+            }
+        }
+    }
+}
@@ -0,0 +1,54 @@
+//! syscon power control emulation
+//!
+//! The qemu test device doubles as the syscon region of the `syscon-reboot` and
+//! `syscon-poweroff` nodes of the guest device tree. It is not passed through,
+//! otherwise one guest could power off the machine: magic writes only reset or
+//! shut down the guest that made them.
+
+use riscv_decode::Instruction;
+
+use crate::guest::page_table::GuestPageTable;
+use crate::guest::vmexit::TrapContext;
+use crate::hypervisor::HostVmm;
+use crate::hypervisor::fdt::SysconAction;
+use crate::page_table::PageTable;
+use crate::{ VmmError, VmmResult };
+
+/// sifive test device commands, used when the device tree has no syscon nodes
+pub const TEST_FAIL: u32 = 0x3333;
+pub const TEST_PASS: u32 = 0x5555;
+pub const TEST_RESET: u32 = 0x7777;
+
+pub const DEFAULT_POWEROFF: SysconAction = SysconAction { offset: 0, value: TEST_PASS, mask: 0xffff_ffff };
+pub const DEFAULT_REBOOT: SysconAction = SysconAction { offset: 0, value: TEST_RESET, mask: 0xffff_ffff };
+
+impl<P: PageTable, G: GuestPageTable> HostVmm<P, G> {
+    pub fn handle_syscon_access(&mut self, ctx: &mut TrapContext, guest_pa: usize, instruction: Instruction) -> VmmResult {
+        let guest_id = self.guest_id;
+        let guest = self.guests.get(guest_id).ok_or(VmmError::NoFound)?;
+        let offset = guest_pa - guest.guest_machine.test_finisher_address.as_ref().ok_or(VmmError::DeviceNotFound)?.base_address;
+        let value = match instruction {
+            // registers read as zero, regmap updates read before they write
+            Instruction::Lw(i) | Instruction::Lwu(i) => {
+                if i.rd() != 0 {
+                    ctx.x[i.rd() as usize] = 0;
+                }
+                return Ok(())
+            },
+            Instruction::Sw(i) => ctx.x[i.rs2() as usize] as u32,
+            _ => return Err(VmmError::UnexpectedInst)
+        };
+        let poweroff = guest.guest_machine.syscon_poweroff.unwrap_or(DEFAULT_POWEROFF);
+        let reboot = guest.guest_machine.syscon_reboot.unwrap_or(DEFAULT_REBOOT);
+        if poweroff.matches(offset, value) {
+            self.shutdown_guest(guest_id)
+        }else if reboot.matches(offset, value) {
+            self.reset_guest(guest_id)
+        }else if offset == 0 && value & 0xffff == TEST_FAIL {
+            hwarning!("guest {} failed with exit code {}", guest_id, value >> 16);
+            self.shutdown_guest(guest_id)
+        }else{
+            Ok(())
+        }
+    }
+}
@@ -0,0 +1,854 @@
+//! Emulated virtio-mmio devices
+//!
+//! Devices served by the hypervisor instead of hardware. The guest sees a modern
+//! (version 2) virtio-mmio transport in one of the virtio slots of its device tree,
+//! buffers are processed synchronously when the guest notifies a queue.
+//!
+//! Backends work on the guest buffers in place. `Descriptor::map` translates a buffer
+//! through the stage-2 table of the guest, page by page, into the host memory it is
+//! mapped to, guest ram, a shared text page or a page granted by another guest, and maps
+//! that memory into the host, see `mm::framemap`. The resulting `MappedBuffer` borrows the
+//! guest memory set: the stage-2 table cannot change while a backend holds it, and the
+//! host mapping goes with it once the buffer is handled, so a reset, a revoked grant or a
+//! removed mapping never leaves a stale host pointer. The rings are read and written the
+//! same way, one field at a time.
+//! Device writes need a writable stage-2 mapping, a chain pointing at read-only memory is
+//! cut short there.
+//!
+//! Queues are split or packed rings, with or without indirect descriptors, as negotiated
+//! by the driver. Backends see neither: `GuestQueue::pop` returns the next `Chain` with
+//! its buffers in order and indirect tables resolved, `GuestQueue::push_used` returns it.
+//! Interrupt suppression by the driver is honoured, the event index is not offered.
+//!
+//! An asynchronous backend runs on the I/O service hart if there is one, see `ioservice`.
+//! A queue notification then only queues a job and the guest resumes, the job maps the
+//! buffers through a `Stage2Ram`, the stage-2 table rebuilt from its token, and the used
+//! buffers are signalled once `HostVmm::complete_io` applies the completion. One job per
+//! queue is in flight, a notification meanwhile is handled after it.
+//!
+//! With a `GuestConfig::irq_coalesce` setting for its interrupt line the interrupt of a
+//! device is held after a completion, `INTERRUPT_STATUS` already shows the used buffers,
+//! and injected once enough completions of that device are held or the window of its
+//! first one ended. The end of the window is a deadline of the host timer, see
+//! `flush_coalesced_irqs`.
+
+use alloc::boxed::Box;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::marker::PhantomData;
+use core::mem::size_of;
+use core::ptr::{ read_volatile, write_volatile };
+use core::sync::atomic::{ fence, Ordering };
+use riscv::register::time;
+
+use crate::device_emu::DeviceLifecycle;
+use crate::device_emu::mmio::MmioAccess;
+use crate::drivers::virtio::{ regs, status, VIRTIO_MMIO_MAGIC, VIRTIO_F_VERSION_1, VIRTIO_F_INDIRECT_DESC, VIRTIO_F_RING_PACKED };
+use crate::guest::IrqCoalesce;
+use crate::guest::page_table::GuestPageTable;
+use crate::guest::pmap::with_guest_memory;
+use crate::guest::vmexit::TrapContext;
+use crate::hypervisor::HostVmm;
+use crate::hypervisor::fdt::Device;
+use crate::ioservice::{ self, IoJob, IoCompletion };
+use crate::mm::{ GuestMemorySet, MemorySet, stage2_translate };
+use crate::mm::framemap::GuestRamMapping;
+use crate::page_table::{ AccessType, PageTable };
+use crate::constants::PAGE_SIZE;
+use crate::sync::SpinIrqSave;
+use crate::{ VmmError, VmmResult };
+
+pub mod rng;
+#[cfg(feature = "virtio_selftest")]
+pub mod selftest;
+
+/// largest queue offered to the guest
+const QUEUE_NUM_MAX: u16 = 256;
+/// "QEMU", the vendor id of qemu's own virtio-mmio devices
+const VENDOR_ID: u32 = 0x554d_4551;
+
+const VIRTQ_DESC_F_NEXT: u16 = 1;
+const VIRTQ_DESC_F_WRITE: u16 = 2;
+const VIRTQ_DESC_F_INDIRECT: u16 = 4;
+/// availability flags of a packed ring descriptor
+const VIRTQ_DESC_F_AVAIL: u16 = 1 << 7;
+const VIRTQ_DESC_F_USED: u16 = 1 << 15;
+/// avail ring flag, the driver does not want an interrupt for used buffers
+const VIRTQ_AVAIL_F_NO_INTERRUPT: u16 = 1;
+/// driver event suppression of a packed ring, no interrupts for used buffers
+const RING_EVENT_FLAGS_DISABLE: u16 = 1;
+
+/// interrupt status bit telling the guest that buffers were used
+const INTERRUPT_USED_BUFFER: u32 = 1;
+
+/// a device model behind an emulated virtio-mmio transport
+pub trait VirtioBackend: Send {
+    fn device_id(&self) -> u32;
+
+    /// device specific feature bits, `VIRTIO_F_VERSION_1` and the ring features are added
+    /// by the transport
+    fn features(&self) -> u64 {
+        0
+    }
+
+    fn num_queues(&self) -> usize;
+
+    /// consume the buffers made available on queue `index`, true if any buffer was used.
+    /// Buffers are mapped from `ram` for the duration of the call.
+    fn process(&mut self, index: usize, queue: &mut GuestQueue, ram: &dyn GuestRam) -> bool;
+
+    /// device specific configuration space
+    fn read_config(&self, _offset: usize, _width: usize) -> u64 {
+        0
+    }
+
+    /// `process` takes long enough to be worth running on the I/O service hart
+    fn asynchronous(&self) -> bool {
+        false
+    }
+
+    fn reset(&mut self) {}
+
+    /// write out what the device still holds for the guest, e.g. a write cache
+    fn flush(&mut self) {}
+}
+
+fn guest_read<T: Copy>(guest_id: usize, guest_pa: usize) -> Option<T> {
+    with_guest_memory(guest_id, guest_pa, size_of::<T>(), |bytes| unsafe{ read_volatile(bytes.as_ptr() as *const T) })
+}
+
+fn guest_write<T: Copy>(guest_id: usize, guest_pa: usize, value: T) -> Option<()> {
+    with_guest_memory(guest_id, guest_pa, size_of::<T>(), |bytes| unsafe{ write_volatile(bytes.as_mut_ptr() as *mut T, value) })
+}
+
+/// guest physical memory of the guest owning a device, as mapped by its stage-2 table
+pub trait GuestRam {
+    /// host address of `guest_pa`, `None` if the guest cannot access it that way
+    fn host_address(&self, guest_pa: usize, access: AccessType) -> Option<usize>;
+}
+
+impl<G: GuestPageTable> GuestRam for GuestMemorySet<G> {
+    fn host_address(&self, guest_pa: usize, access: AccessType) -> Option<usize> {
+        // device windows are not memory, a buffer there is not DMA-able
+        if self.mmio_device(guest_pa).is_some() {
+            return None
+        }
+        self.translate_guest_pa(guest_pa, access)
+    }
+}
+
+/// the stage-2 table of a guest without its memory set, for jobs on the I/O service hart.
+/// Valid while the guest has jobs in flight, its stage-2 table only changes after
+/// `ioservice::quiesce`.
+#[derive(Clone, Copy)]
+pub struct Stage2Ram {
+    token: usize,
+    translate: fn(usize, usize, AccessType) -> Option<usize>
+}
+
+impl Stage2Ram {
+    pub fn of<G: GuestPageTable>(gpm: &GuestMemorySet<G>) -> Self {
+        Self { token: gpm.token(), translate: stage2_host_address::<G> }
+    }
+}
+
+fn stage2_host_address<G: GuestPageTable>(token: usize, guest_pa: usize, access: AccessType) -> Option<usize> {
+    // a table rebuilt from its token owns no frames, nothing is freed on drop
+    stage2_translate(&G::from_token(token), guest_pa, access)
+}
+
+impl GuestRam for Stage2Ram {
+    fn host_address(&self, guest_pa: usize, access: AccessType) -> Option<usize> {
+        (self.translate)(self.token, guest_pa, access)
+    }
+}
+
+/// a guest buffer as host memory, one piece per page or run of host-contiguous pages,
+/// mapped into the host while the buffer lives
+pub struct MappedBuffer<'a> {
+    pieces: Vec<GuestRamMapping>,
+    _ram: PhantomData<&'a dyn GuestRam>
+}
+
+impl MappedBuffer<'_> {
+    pub fn len(&self) -> usize {
+        self.pieces.iter().map(|piece| piece.bytes().len()).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pieces.is_empty()
+    }
+
+    pub fn pieces_mut(&mut self) -> impl Iterator<Item = &mut [u8]> {
+        self.pieces.iter_mut().map(|piece| piece.bytes_mut())
+    }
+
+    pub fn pieces(&self) -> impl Iterator<Item = &[u8]> {
+        self.pieces.iter().map(|piece| piece.bytes())
+    }
+
+    /// copy `data` to the start of the buffer, returns the bytes copied
+    pub fn copy_from(&mut self, data: &[u8]) -> usize {
+        let mut copied = 0;
+        for piece in self.pieces_mut() {
+            let len = piece.len().min(data.len() - copied);
+            piece[..len].copy_from_slice(&data[copied..copied + len]);
+            copied += len;
+        }
+        copied
+    }
+}
+
+impl Drop for MappedBuffer<'_> {
+    /// the first piece holds the lock of the host mappings, it goes last
+    fn drop(&mut self) {
+        while self.pieces.pop().is_some() {}
+    }
+}
+
+/// buffer of a descriptor chain, in guest memory
+#[derive(Clone, Copy, Debug)]
+pub struct Descriptor {
+    pub addr: usize,
+    pub len: usize,
+    /// written by the device
+    pub writable: bool
+}
+
+impl Descriptor {
+    /// the buffer in host memory, `None` if part of it is not guest ram the device may
+    /// access, written to if `writable`
+    pub fn map<'a>(&self, ram: &'a dyn GuestRam) -> Option<MappedBuffer<'a>> {
+        let access = if self.writable { AccessType::Write } else { AccessType::Read };
+        let end = self.addr.checked_add(self.len)?;
+        // (host address, length) of the runs of host-contiguous pages
+        let mut runs: Vec<(usize, usize)> = Vec::new();
+        let mut addr = self.addr;
+        while addr < end {
+            let len = (PAGE_SIZE - addr % PAGE_SIZE).min(end - addr);
+            let host = ram.host_address(addr, access)?;
+            match runs.last_mut() {
+                Some((start, run_len)) if *start + *run_len == host => *run_len += len,
+                _ => runs.push((host, len))
+            }
+            addr += len;
+        }
+        let mut buffer = MappedBuffer { pieces: Vec::with_capacity(runs.len()), _ram: PhantomData };
+        for (host, len) in runs {
+            buffer.pieces.push(GuestRamMapping::new(host, len)?);
+        }
+        Some(buffer)
+    }
+}
+
+/// a descriptor as the driver wrote it, to a split or a packed ring or an indirect table
+#[derive(Clone, Copy)]
+struct RawDescriptor {
+    addr: usize,
+    len: u32,
+    flags: u16,
+    /// next descriptor of a split ring, buffer id of a packed ring
+    link: u16
+}
+
+impl RawDescriptor {
+    fn read_split(guest_id: usize, entry: usize) -> Option<Self> {
+        Some(Self { addr: guest_read::<u64>(guest_id, entry)? as usize, len: guest_read(guest_id, entry + 8)?, flags: guest_read(guest_id, entry + 12)?, link: guest_read(guest_id, entry + 14)? })
+    }
+
+    fn read_packed(guest_id: usize, entry: usize) -> Option<Self> {
+        Some(Self { addr: guest_read::<u64>(guest_id, entry)? as usize, len: guest_read(guest_id, entry + 8)?, link: guest_read(guest_id, entry + 12)?, flags: guest_read(guest_id, entry + 14)? })
+    }
+
+    fn buffer(&self) -> Descriptor {
+        Descriptor { addr: self.addr, len: self.len as usize, writable: self.flags & VIRTQ_DESC_F_WRITE != 0 }
+    }
+}
+
+/// a descriptor chain made available by the driver
+pub struct Chain {
+    /// head of a split ring chain, buffer id of a packed ring one
+    id: u16,
+    /// descriptors the chain takes in a packed ring
+    ring_len: u16,
+    descriptors: Vec<Descriptor>
+}
+
+impl Chain {
+    /// buffers of the chain in order, indirect tables resolved. A malformed chain is cut
+    /// short.
+    pub fn descriptors(&self) -> impl Iterator<Item = &Descriptor> {
+        self.descriptors.iter()
+    }
+}
+
+/// progress of the device through the rings of a queue
+#[derive(Clone, Copy, Debug, Default)]
+pub struct RingPosition {
+    /// next entry of the available ring, or next descriptor of a packed ring, to consume
+    last_avail: u16,
+    /// next descriptor of a packed ring to return used
+    next_used: u16,
+    /// wrap counters of a packed ring
+    avail_wrap: bool,
+    used_wrap: bool
+}
+
+/// virtqueue set up by the guest driver, a split or a packed one
+#[derive(Clone, Default)]
+pub struct GuestQueue {
+    /// guest whose memory holds the rings
+    guest_id: usize,
+    pub size: u16,
+    pub ready: bool,
+    /// descriptor table, or the ring of a packed queue
+    desc: usize,
+    /// available ring, or the driver event suppression of a packed queue
+    driver: usize,
+    /// used ring, or the device event suppression of a packed queue
+    device: usize,
+    packed: bool,
+    /// the driver may use indirect descriptors
+    indirect: bool,
+    position: RingPosition
+}
+
+impl GuestQueue {
+    /// use the ring layout of the negotiated `features`, from the start of the rings
+    pub fn set_layout(&mut self, features: u64) {
+        self.packed = features & VIRTIO_F_RING_PACKED != 0;
+        self.indirect = features & VIRTIO_F_INDIRECT_DESC != 0;
+        self.position = RingPosition { avail_wrap: true, used_wrap: true, ..RingPosition::default() };
+    }
+
+    pub fn position(&self) -> RingPosition {
+        self.position
+    }
+
+    /// continue from `position`, reached by a copy of the queue
+    pub fn set_position(&mut self, position: RingPosition) {
+        self.position = position;
+    }
+
+    /// next descriptor chain made available by the guest
+    pub fn pop(&mut self) -> Option<Chain> {
+        if !self.ready || self.size == 0 {
+            return None
+        }
+        if self.packed { self.pop_packed() } else { self.pop_split() }
+    }
+
+    fn pop_split(&mut self) -> Option<Chain> {
+        let avail_idx: u16 = guest_read(self.guest_id, self.driver + 2)?;
+        if avail_idx == self.position.last_avail {
+            return None
+        }
+        // read the ring entry after its index
+        fence(Ordering::Acquire);
+        let slot = (self.position.last_avail % self.size) as usize;
+        let head: u16 = guest_read(self.guest_id, self.driver + 4 + 2 * slot)?;
+        self.position.last_avail = self.position.last_avail.wrapping_add(1);
+        let mut descriptors = Vec::new();
+        let mut index = head;
+        // a looping chain ends after visiting every descriptor once
+        for _ in 0..self.size {
+            if index >= self.size {
+                break
+            }
+            let desc = match RawDescriptor::read_split(self.guest_id, self.desc + 16 * index as usize) {
+                Some(desc) => desc,
+                None => break
+            };
+            // an indirect descriptor ends the chain
+            if desc.flags & VIRTQ_DESC_F_INDIRECT != 0 {
+                self.read_indirect(&desc, &mut descriptors);
+                break
+            }
+            descriptors.push(desc.buffer());
+            if desc.flags & VIRTQ_DESC_F_NEXT == 0 {
+                break
+            }
+            index = desc.link;
+        }
+        Some(Chain { id: head, ring_len: 1, descriptors })
+    }
+
+    fn pop_packed(&mut self) -> Option<Chain> {
+        let (mut index, mut wrap) = (self.position.last_avail, self.position.avail_wrap);
+        let flags: u16 = guest_read(self.guest_id, self.desc + 16 * index as usize + 14)?;
+        if (flags & VIRTQ_DESC_F_AVAIL != 0) != wrap || (flags & VIRTQ_DESC_F_USED != 0) == wrap {
+            return None
+        }
+        // read the descriptors after the flags of the head, the driver writes those last
+        fence(Ordering::Acquire);
+        let mut descriptors = Vec::new();
+        let mut ring_len = 0;
+        let id = loop {
+            let desc = RawDescriptor::read_packed(self.guest_id, self.desc + 16 * index as usize)?;
+            ring_len += 1;
+            index += 1;
+            if index == self.size {
+                index = 0;
+                wrap = !wrap;
+            }
+            if desc.flags & VIRTQ_DESC_F_INDIRECT != 0 {
+                self.read_indirect(&desc, &mut descriptors);
+            }else{
+                descriptors.push(desc.buffer());
+            }
+            // the buffer id is in the last descriptor, a chain is never longer than the ring
+            if desc.flags & VIRTQ_DESC_F_NEXT == 0 || ring_len == self.size {
+                break desc.link
+            }
+        };
+        self.position.last_avail = index;
+        self.position.avail_wrap = wrap;
+        Some(Chain { id, ring_len, descriptors })
+    }
+
+    /// append the buffers of the indirect table of `desc`, nothing if indirect descriptors
+    /// were not negotiated
+    fn read_indirect(&self, desc: &RawDescriptor, descriptors: &mut Vec<Descriptor>) {
+        if !self.indirect {
+            return
+        }
+        let count = (desc.len as usize / 16).min(self.size as usize);
+        let mut index = 0;
+        for _ in 0..count {
+            let entry = desc.addr + 16 * index;
+            let desc = match if self.packed { RawDescriptor::read_packed(self.guest_id, entry) } else { RawDescriptor::read_split(self.guest_id, entry) } {
+                Some(desc) => desc,
+                None => break
+            };
+            // tables do not nest
+            if desc.flags & VIRTQ_DESC_F_INDIRECT != 0 {
+                break
+            }
+            descriptors.push(desc.buffer());
+            // packed tables are used in order, split ones are chained
+            index = match (self.packed, desc.flags & VIRTQ_DESC_F_NEXT != 0) {
+                (true, _) => index + 1,
+                (false, true) => desc.link as usize,
+                (false, false) => break
+            };
+            if index >= count {
+                break
+            }
+        }
+    }
+
+    /// the driver suppressed interrupts for used buffers of the queue
+    pub fn interrupt_suppressed(&self) -> bool {
+        if self.packed {
+            return guest_read::<u16>(self.guest_id, self.driver + 2).map_or(false, |flags| flags == RING_EVENT_FLAGS_DISABLE)
+        }
+        guest_read::<u16>(self.guest_id, self.driver).map_or(false, |flags| flags & VIRTQ_AVAIL_F_NO_INTERRUPT != 0)
+    }
+
+    /// return `chain` to the guest, `len` bytes were written to it
+    pub fn push_used(&mut self, chain: &Chain, len: u32) {
+        if self.packed {
+            self.push_used_packed(chain, len);
+            return
+        }
+        let used_idx: u16 = match guest_read(self.guest_id, self.device + 2) {
+            Some(idx) => idx,
+            None => return
+        };
+        let slot = (used_idx % self.size) as usize;
+        let entry = self.device + 4 + 8 * slot;
+        guest_write(self.guest_id, entry, chain.id as u32);
+        guest_write(self.guest_id, entry + 4, len);
+        // publish the ring entry before its index
+        fence(Ordering::Release);
+        guest_write(self.guest_id, self.device + 2, used_idx.wrapping_add(1));
+    }
+
+    fn push_used_packed(&mut self, chain: &Chain, len: u32) {
+        let entry = self.desc + 16 * self.position.next_used as usize;
+        guest_write(self.guest_id, entry + 8, len);
+        guest_write(self.guest_id, entry + 12, chain.id);
+        let mut flags = if len != 0 { VIRTQ_DESC_F_WRITE } else { 0 };
+        if self.position.used_wrap {
+            flags |= VIRTQ_DESC_F_AVAIL | VIRTQ_DESC_F_USED;
+        }
+        // publish the descriptor before its flags
+        fence(Ordering::Release);
+        guest_write(self.guest_id, entry + 14, flags);
+        // the used descriptor stands for the whole chain
+        let next = self.position.next_used as usize + chain.ring_len as usize;
+        if next >= self.size as usize {
+            self.position.used_wrap = !self.position.used_wrap;
+        }
+        self.position.next_used = (next % self.size as usize) as u16;
+    }
+}
+
+/// job of a queue on the I/O service hart
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum QueueIo {
+    Idle,
+    Busy,
+    /// notified again while busy
+    BusyNotified
+}
+
+pub struct EmulatedVirtio {
+    /// guest owning the device
+    guest_id: usize,
+    /// the virtio slot of the guest device tree
+    pub device: Device,
+    /// shared with the jobs of an asynchronous backend
+    backend: Arc<SpinIrqSave<Box<dyn VirtioBackend>>>,
+    asynchronous: bool,
+    /// jobs in flight by queue
+    io: Vec<QueueIo>,
+    /// resets so far, completions of jobs queued before a reset are dropped
+    generation: usize,
+    /// completions whose interrupt is held by coalescing
+    held: usize,
+    /// host time of the first held completion
+    held_since: usize,
+    device_features_sel: u32,
+    driver_features_sel: u32,
+    driver_features: u64,
+    queue_sel: usize,
+    queues: Vec<GuestQueue>,
+    status: u32,
+    interrupt_status: u32
+}
+
+impl EmulatedVirtio {
+    pub fn new(guest_id: usize, device: Device, backend: Box<dyn VirtioBackend>) -> Self {
+        let queues = alloc::vec![GuestQueue { guest_id, ..GuestQueue::default() }; backend.num_queues()];
+        Self {
+            guest_id,
+            device,
+            asynchronous: backend.asynchronous(),
+            io: alloc::vec![QueueIo::Idle; backend.num_queues()],
+            generation: 0,
+            held: 0,
+            held_since: 0,
+            backend: Arc::new(SpinIrqSave::new(backend)),
+            device_features_sel: 0,
+            driver_features_sel: 0,
+            driver_features: 0,
+            queue_sel: 0,
+            queues,
+            status: 0,
+            interrupt_status: 0
+        }
+    }
+
+    pub fn contains(&self, guest_pa: usize) -> bool {
+        guest_pa >= self.device.base_address && guest_pa < self.device.base_address + self.device.size
+    }
+
+    pub fn device_id(&self) -> u32 {
+        self.backend.lock().device_id()
+    }
+
+    /// queue notifications are handled on the I/O service hart
+    pub fn asynchronous(&self) -> bool {
+        self.asynchronous && ioservice::running()
+    }
+
+    /// back to the state after power on, the guest driver sets the device up again.
+    /// Jobs of the guest must have completed, see `ioservice::quiesce`.
+    pub fn reset(&mut self) {
+        self.generation += 1;
+        self.io.iter_mut().for_each(|io| *io = QueueIo::Idle);
+        self.held = 0;
+        self.device_features_sel = 0;
+        self.driver_features_sel = 0;
+        self.driver_features = 0;
+        self.queue_sel = 0;
+        let guest_id = self.guest_id;
+        self.queues.iter_mut().for_each(|queue| *queue = GuestQueue { guest_id, ..GuestQueue::default() });
+        self.status = 0;
+        self.interrupt_status = 0;
+        self.backend.lock().reset();
+    }
+
+    fn features(&self) -> u64 {
+        self.backend.lock().features() | VIRTIO_F_VERSION_1 | VIRTIO_F_INDIRECT_DESC | VIRTIO_F_RING_PACKED
+    }
+
+    pub fn read(&self, offset: usize, width: usize) -> u64 {
+        if offset >= regs::CONFIG {
+            return self.backend.lock().read_config(offset - regs::CONFIG, width)
+        }
+        // transport registers are 32 bits wide
+        if width != 4 {
+            return 0
+        }
+        let queue = self.queues.get(self.queue_sel);
+        let value = match offset {
+            regs::MAGIC => VIRTIO_MMIO_MAGIC,
+            regs::VERSION => 2,
+            regs::DEVICE_ID => self.device_id(),
+            regs::VENDOR_ID => VENDOR_ID,
+            regs::DEVICE_FEATURES => match self.device_features_sel {
+                0 => self.features() as u32,
+                1 => (self.features() >> 32) as u32,
+                _ => 0
+            },
+            regs::QUEUE_NUM_MAX => queue.map_or(0, |_| QUEUE_NUM_MAX as u32),
+            regs::QUEUE_READY => queue.map_or(0, |queue| queue.ready as u32),
+            regs::INTERRUPT_STATUS => self.interrupt_status,
+            regs::STATUS => self.status,
+            // includes CONFIG_GENERATION, the configuration never changes
+            _ => 0
+        };
+        value as u64
+    }
+
+    /// true if the write raised an interrupt the driver did not suppress
+    pub fn write(&mut self, offset: usize, width: usize, value: u64, ram: &dyn GuestRam) -> bool {
+        // the configuration space of the emulated devices is read only
+        if offset >= regs::CONFIG || width != 4 {
+            return false
+        }
+        let value = value as u32;
+        let set_low = |addr: &mut usize| *addr = (*addr & !0xffff_ffff) | value as usize;
+        let set_high = |addr: &mut usize| *addr = (*addr & 0xffff_ffff) | (value as usize) << 32;
+        match offset {
+            regs::DEVICE_FEATURES_SEL => self.device_features_sel = value,
+            regs::DRIVER_FEATURES_SEL => self.driver_features_sel = value,
+            regs::DRIVER_FEATURES => match self.driver_features_sel {
+                0 => self.driver_features = (self.driver_features & !0xffff_ffff) | value as u64,
+                1 => self.driver_features = (self.driver_features & 0xffff_ffff) | (value as u64) << 32,
+                _ => {}
+            },
+            regs::QUEUE_SEL => self.queue_sel = value as usize,
+            regs::QUEUE_NOTIFY => return self.notify(value as usize, ram),
+            regs::INTERRUPT_ACK => self.interrupt_status &= !value,
+            regs::STATUS => {
+                if value == 0 {
+                    self.reset();
+                }else if value & status::FEATURES_OK != 0 && self.driver_features & !self.features() != 0 {
+                    // features the device did not offer, FEATURES_OK stays clear
+                    self.status = value & !status::FEATURES_OK;
+                }else{
+                    if value & status::FEATURES_OK != 0 && self.status & status::FEATURES_OK == 0 {
+                        let features = self.driver_features;
+                        self.queues.iter_mut().for_each(|queue| queue.set_layout(features));
+                    }
+                    self.status = value;
+                }
+            },
+            _ => {
+                // packed rings need not be a power of two
+                let packed = self.driver_features & VIRTIO_F_RING_PACKED != 0;
+                let queue = match self.queues.get_mut(self.queue_sel) {
+                    Some(queue) => queue,
+                    None => return false
+                };
+                match offset {
+                    regs::QUEUE_NUM if value != 0 && value <= QUEUE_NUM_MAX as u32 && (packed || value.is_power_of_two()) => queue.size = value as u16,
+                    regs::QUEUE_READY => queue.ready = value & 1 != 0,
+                    regs::QUEUE_DESC_LOW => set_low(&mut queue.desc),
+                    regs::QUEUE_DESC_HIGH => set_high(&mut queue.desc),
+                    regs::QUEUE_DRIVER_LOW => set_low(&mut queue.driver),
+                    regs::QUEUE_DRIVER_HIGH => set_high(&mut queue.driver),
+                    regs::QUEUE_DEVICE_LOW => set_low(&mut queue.device),
+                    regs::QUEUE_DEVICE_HIGH => set_high(&mut queue.device),
+                    _ => {}
+                }
+            }
+        }
+        false
+    }
+
+    fn notify(&mut self, index: usize, ram: &dyn GuestRam) -> bool {
+        if self.status & status::DRIVER_OK == 0 {
+            return false
+        }
+        let queue = match self.queues.get_mut(index) {
+            Some(queue) => queue,
+            None => return false
+        };
+        if self.backend.lock().process(index, queue, ram) {
+            self.interrupt_status |= INTERRUPT_USED_BUFFER;
+            return !queue.interrupt_suppressed()
+        }
+        false
+    }
+
+    /// queue a job of `priority` for the buffers of queue `index` of an asynchronous backend
+    pub fn submit(&mut self, guest_id: usize, priority: usize, index: usize, ram: Stage2Ram) {
+        if self.status & status::DRIVER_OK == 0 || index >= self.queues.len() {
+            return
+        }
+        if self.io[index] != QueueIo::Idle {
+            self.io[index] = QueueIo::BusyNotified;
+            return
+        }
+        self.io[index] = QueueIo::Busy;
+        let backend = self.backend.clone();
+        let mut queue = self.queues[index].clone();
+        let (device, generation) = (self.device.base_address, self.generation);
+        ioservice::submit(IoJob {
+            guest_id,
+            priority,
+            backend: Arc::as_ptr(&backend) as *const () as usize,
+            work: Box::new(move || {
+                let used = backend.lock().process(index, &mut queue, &ram);
+                IoCompletion { guest_id, device, generation, queue: index, position: queue.position(), used }
+            })
+        });
+    }
+
+    /// apply the completion of a job, returns whether it raised an interrupt and whether
+    /// the queue was notified meanwhile
+    fn complete(&mut self, completion: &IoCompletion) -> (bool, bool) {
+        if completion.generation != self.generation {
+            return (false, false)
+        }
+        let notified = self.io[completion.queue] == QueueIo::BusyNotified;
+        self.io[completion.queue] = QueueIo::Idle;
+        self.queues[completion.queue].set_position(completion.position);
+        if completion.used {
+            self.interrupt_status |= INTERRUPT_USED_BUFFER;
+        }
+        (completion.used && !self.queues[completion.queue].interrupt_suppressed(), notified)
+    }
+
+    /// a completion raised the interrupt at `now`, returns true if it is injected now,
+    /// otherwise it is held
+    fn coalesce(&mut self, coalesce: Option<IrqCoalesce>, now: usize) -> bool {
+        let coalesce = match coalesce {
+            Some(coalesce) => coalesce,
+            None => return true
+        };
+        if self.held == 0 {
+            self.held_since = now;
+        }
+        self.held += 1;
+        if self.held >= coalesce.count || now.wrapping_sub(self.held_since) >= coalesce.window() {
+            self.held = 0;
+            return true
+        }
+        false
+    }
+
+    /// host time at which the held interrupt is due, `usize::MAX` if none is held
+    pub fn coalesce_deadline(&self, coalesce: Option<IrqCoalesce>) -> usize {
+        match coalesce {
+            Some(coalesce) if self.held != 0 => self.held_since.saturating_add(coalesce.window()),
+            _ => usize::MAX
+        }
+    }
+
+    /// release the held interrupt if it is due at `now`
+    fn take_held_irq(&mut self, coalesce: Option<IrqCoalesce>, now: usize) -> bool {
+        if self.coalesce_deadline(coalesce) > now {
+            return false
+        }
+        self.held = 0;
+        true
+    }
+}
+
+impl DeviceLifecycle for EmulatedVirtio {
+    fn reset(&mut self) {
+        EmulatedVirtio::reset(self)
+    }
+
+    /// jobs of the guest must have completed, see `ioservice::quiesce`
+    fn flush(&mut self) {
+        self.backend.lock().flush();
+    }
+}
+
+impl<P: PageTable, G: GuestPageTable> HostVmm<P, G> {
+    pub fn handle_emulated_virtio_access(&mut self, ctx: &mut TrapContext, guest_pa: usize, access: MmioAccess) -> VmmResult {
+        let guest_id = self.guest_id;
+        let guest = self.guests.get_mut(guest_id).ok_or(VmmError::NoFound)?;
+        let priority = guest.config.io_priority();
+        let ram = &guest.gpm;
+        let dev = guest.virtio.iter_mut().find(|dev| dev.contains(guest_pa)).ok_or(VmmError::DeviceNotFound)?;
+        let coalesce = guest.config.coalesce_of(dev.device.irq);
+        let offset = guest_pa - dev.device.base_address;
+        let irq = match access {
+            MmioAccess::Load { rd, width, signed } => {
+                MmioAccess::complete_load(ctx, rd, width, signed, dev.read(offset, width) as usize);
+                None
+            },
+            MmioAccess::Store { value, width: 4 } if offset == regs::QUEUE_NOTIFY && dev.asynchronous() => {
+                dev.submit(guest_id, priority, value as usize, Stage2Ram::of(ram));
+                None
+            },
+            MmioAccess::Store { value, width } if offset == regs::STATUS && value == 0 && dev.asynchronous() => {
+                // the rings go away with the reset
+                ioservice::quiesce(guest_id);
+                dev.write(offset, width, value as u64, ram);
+                None
+            },
+            MmioAccess::Store { value, width } => {
+                if dev.write(offset, width, value as u64, ram) && dev.coalesce(coalesce, time::read()) { dev.device.irq } else { None }
+            },
+            MmioAccess::Amo { .. } => return Err(VmmError::UnexpectedInst)
+        };
+        if let Some(irq) = irq {
+            self.inject_guest_irq(guest_id, irq as u32);
+        }
+        // jobs run in place without a service hart
+        self.complete_io();
+        Ok(())
+    }
+
+    /// apply the completions of the I/O service hart, on its software interrupt
+    pub fn complete_io(&mut self) {
+        for completion in ioservice::take_completions() {
+            let guest_id = completion.guest_id;
+            let guest = match self.guests.get_mut(guest_id) {
+                Some(guest) => guest,
+                None => continue
+            };
+            let priority = guest.config.io_priority();
+            let ram = Stage2Ram::of(&guest.gpm);
+            let dev = match guest.virtio.iter_mut().find(|dev| dev.device.base_address == completion.device) {
+                Some(dev) => dev,
+                None => continue
+            };
+            let coalesce = guest.config.coalesce_of(dev.device.irq);
+            let (raised, notified) = dev.complete(&completion);
+            let raised = raised && dev.coalesce(coalesce, time::read());
+            let irq = dev.device.irq;
+            if notified {
+                dev.submit(guest_id, priority, completion.queue, ram);
+            }
+            if let Some(irq) = irq.filter(|_| raised) {
+                self.inject_guest_irq(guest_id, irq as u32);
+            }
+        }
+    }
+
+    /// inject the interrupts held by coalescing whose window ended, on the timer tick
+    pub fn flush_coalesced_irqs(&mut self) {
+        let now = time::read();
+        let mut due = Vec::new();
+        for guest in self.guests.iter_mut() {
+            for dev in guest.virtio.iter_mut() {
+                let coalesce = guest.config.coalesce_of(dev.device.irq);
+                if dev.take_held_irq(coalesce, now) {
+                    if let Some(irq) = dev.device.irq {
+                        due.push((guest.guest_id, irq as u32));
+                    }
+                }
+            }
+        }
+        for (guest_id, irq) in due {
+            self.inject_guest_irq(guest_id, irq);
+        }
+    }
+
+    /// earliest host time a held interrupt is due, `usize::MAX` if none is held
+    pub fn coalesce_deadline(&self) -> usize {
+        self.guests.iter()
+            .flat_map(|guest| guest.virtio.iter().map(move |dev| dev.coalesce_deadline(guest.config.coalesce_of(dev.device.irq))))
+            .min()
+            .unwrap_or(usize::MAX)
+    }
+}
@@ -0,0 +1,39 @@
+//! virtio entropy device, buffers are filled from the host entropy source
+
+use crate::drivers::entropy;
+use crate::drivers::virtio::device_id;
+
+use super::{ GuestQueue, GuestRam, VirtioBackend };
+
+pub struct VirtioRng;
+
+impl VirtioBackend for VirtioRng {
+    fn device_id(&self) -> u32 {
+        device_id::ENTROPY
+    }
+
+    fn num_queues(&self) -> usize {
+        1
+    }
+
+    /// the seed csr or a hardware TRNG may take long to fill a large buffer
+    fn asynchronous(&self) -> bool {
+        true
+    }
+
+    fn process(&mut self, _index: usize, queue: &mut GuestQueue, ram: &dyn GuestRam) -> bool {
+        let mut used = false;
+        while let Some(chain) = queue.pop() {
+            let mut written = 0;
+            for desc in chain.descriptors().filter(|desc| desc.writable) {
+                if let Some(mut buf) = desc.map(ram) {
+                    buf.pieces_mut().for_each(entropy::fill);
+                    written += buf.len();
+                }
+            }
+            queue.push_used(&chain, written as u32);
+            used = true;
+        }
+        used
+    }
+}
@@ -0,0 +1,409 @@
+//! Conformance exerciser of the emulated virtio devices
+//!
+//! Built with the `virtio_selftest` feature, `run` plays a virtio 1.2 driver against every
+//! device model in `MODELS` at boot, before the guest exists, and checks the device side of
+//! the spec. The hypervisor only models the entropy device, virtio-blk, virtio-net and the
+//! virtio console of a guest are host devices mapped into the guest, the hypervisor never
+//! parses their rings. A model added to `MODELS` gets the same checks:
+//!
+//! - feature negotiation: `VIRTIO_F_VERSION_1` is offered, the event index the transport
+//!   does not implement is not, a driver accepting a feature that was not offered does not
+//!   get `FEATURES_OK`, a reset clears the device status
+//! - queue setup: buffers made available before `DRIVER_OK` are not used until then, a
+//!   queue is not ready after a reset
+//! - ring wraparound: ring slots wrap at the queue size, ring indices at 2^16
+//! - malformed chains: a looping chain and a buffer outside guest ram are returned without
+//!   writing anywhere, device-readable buffers are never written
+//! - indirect descriptors: a table is followed once negotiated, ignored otherwise
+//! - packed rings: a ring that is not a power of two, used descriptors and wrap counters
+//! - event suppression: `VIRTQ_AVAIL_F_NO_INTERRUPT`, or a disabled driver event of a
+//!   packed ring, suppresses the interrupt, not the used buffers
+//!
+//! Only transport and ring behaviour is checked, the driver makes writable buffers
+//! available on queue 0, device specific requests are not exercised. The rings and buffers
+//! live in the last pages of the guest ram, saved before and restored after the run, the
+//! guest image is not touched.
+
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+
+use crate::constants::layout::{ GUEST_START_PA, GUEST_DEFAULT_SIZE };
+use crate::drivers::virtio::{ regs, status, VIRTIO_MMIO_MAGIC, VIRTIO_F_VERSION_1, VIRTIO_F_EVENT_IDX };
+use crate::drivers::virtio::{ VIRTIO_F_INDIRECT_DESC, VIRTIO_F_RING_PACKED };
+use crate::guest::pmap::{ is_guest_ram, with_guest_memory };
+use crate::hypervisor::fdt::Device;
+use crate::page_table::AccessType;
+
+use super::rng::VirtioRng;
+use super::{ guest_read, guest_write, EmulatedVirtio, GuestRam, VirtioBackend };
+use super::{ INTERRUPT_USED_BUFFER, VIRTQ_AVAIL_F_NO_INTERRUPT, RING_EVENT_FLAGS_DISABLE };
+use super::{ VIRTQ_DESC_F_NEXT, VIRTQ_DESC_F_WRITE, VIRTQ_DESC_F_INDIRECT, VIRTQ_DESC_F_AVAIL, VIRTQ_DESC_F_USED };
+
+/// guest whose ram is borrowed, the first one
+const GUEST: usize = 0;
+/// guest ram borrowed for the rings and buffers
+const SCRATCH_SIZE: usize = 0x10000;
+const SCRATCH: usize = GUEST_START_PA + GUEST_DEFAULT_SIZE - SCRATCH_SIZE;
+const DESC: usize = SCRATCH;
+const AVAIL: usize = SCRATCH + 0x1000;
+const USED: usize = SCRATCH + 0x2000;
+const BUFFERS: usize = SCRATCH + 0x3000;
+const BUFFER_SIZE: usize = 64;
+/// indirect descriptor table
+const TABLE: usize = SCRATCH + 0xf000;
+/// below the guest ram
+const OUTSIDE_RAM: usize = 0x1000;
+
+fn rng() -> Box<dyn VirtioBackend> {
+    Box::new(VirtioRng)
+}
+
+/// device models exercised, by name
+const MODELS: [(&str, fn() -> Box<dyn VirtioBackend>); 1] = [("rng", rng)];
+
+/// the scratch pages, translated 1:1 like the guest ram before the guest exists
+struct ScratchRam;
+
+impl GuestRam for ScratchRam {
+    fn host_address(&self, guest_pa: usize, _access: AccessType) -> Option<usize> {
+        is_guest_ram(guest_pa, 1).then(|| guest_pa)
+    }
+}
+
+/// buffer of a chain offered by the driver
+#[derive(Clone, Copy)]
+struct Buffer {
+    addr: usize,
+    len: u32,
+    /// `VIRTQ_DESC_F_WRITE` or `VIRTQ_DESC_F_INDIRECT`, `VIRTQ_DESC_F_NEXT` is added
+    flags: u16
+}
+
+impl Buffer {
+    fn writable(index: usize) -> Self {
+        Self { addr: BUFFERS + index * BUFFER_SIZE, len: BUFFER_SIZE as u32, flags: VIRTQ_DESC_F_WRITE }
+    }
+}
+
+/// the driver side of queue 0 of a device
+struct Driver {
+    name: &'static str,
+    dev: EmulatedVirtio,
+    failures: usize,
+    size: u16,
+    avail_idx: u16,
+    used_idx: u16
+}
+
+impl Driver {
+    fn check(&mut self, ok: bool, what: &str) {
+        if !ok {
+            herror!("virtio selftest: {}: {}", self.name, what);
+            self.failures += 1;
+        }
+    }
+
+    fn read(&self, offset: usize) -> u32 {
+        self.dev.read(offset, 4) as u32
+    }
+
+    /// true if the write raised an interrupt
+    fn write(&mut self, offset: usize, value: u32) -> bool {
+        self.dev.write(offset, 4, value as u64, &ScratchRam)
+    }
+
+    fn device_features(&mut self) -> u64 {
+        self.write(regs::DEVICE_FEATURES_SEL, 0);
+        let low = self.read(regs::DEVICE_FEATURES) as u64;
+        self.write(regs::DEVICE_FEATURES_SEL, 1);
+        low | (self.read(regs::DEVICE_FEATURES) as u64) << 32
+    }
+
+    /// reset the device and accept `features`, true if it set `FEATURES_OK`
+    fn negotiate(&mut self, features: u64) -> bool {
+        self.write(regs::STATUS, 0);
+        self.write(regs::STATUS, status::ACKNOWLEDGE | status::DRIVER);
+        self.write(regs::DRIVER_FEATURES_SEL, 0);
+        self.write(regs::DRIVER_FEATURES, features as u32);
+        self.write(regs::DRIVER_FEATURES_SEL, 1);
+        self.write(regs::DRIVER_FEATURES, (features >> 32) as u32);
+        self.write(regs::STATUS, status::ACKNOWLEDGE | status::DRIVER | status::FEATURES_OK);
+        self.read(regs::STATUS) & status::FEATURES_OK != 0
+    }
+
+    /// set up queue 0 with `size` entries and empty rings
+    fn setup_queue(&mut self, size: u16) {
+        with_guest_memory(GUEST, DESC, BUFFERS - DESC, |rings| rings.fill(0)).unwrap();
+        self.write(regs::QUEUE_SEL, 0);
+        self.write(regs::QUEUE_NUM, size as u32);
+        for (low, high, addr) in [
+            (regs::QUEUE_DESC_LOW, regs::QUEUE_DESC_HIGH, DESC),
+            (regs::QUEUE_DRIVER_LOW, regs::QUEUE_DRIVER_HIGH, AVAIL),
+            (regs::QUEUE_DEVICE_LOW, regs::QUEUE_DEVICE_HIGH, USED)
+        ] {
+            self.write(low, addr as u32);
+            self.write(high, (addr >> 32) as u32);
+        }
+        self.write(regs::QUEUE_READY, 1);
+        self.size = size;
+        self.avail_idx = 0;
+        self.used_idx = 0;
+    }
+
+    /// negotiate the minimal features, set up queue 0 and go live
+    fn start(&mut self, size: u16) {
+        self.start_with(VIRTIO_F_VERSION_1, size);
+    }
+
+    fn start_with(&mut self, features: u64, size: u16) {
+        self.negotiate(features);
+        self.setup_queue(size);
+        self.write(regs::STATUS, status::ACKNOWLEDGE | status::DRIVER | status::FEATURES_OK | status::DRIVER_OK);
+    }
+
+    /// write the chain `buffers` to the descriptors from `head` on, linked to `next` after
+    /// the last one if given, and make it available
+    fn offer(&mut self, head: u16, buffers: &[Buffer], next: Option<u16>) {
+        for (i, buffer) in buffers.iter().enumerate() {
+            let index = head as usize + i;
+            let link = if i + 1 < buffers.len() { Some(index as u16 + 1) } else { next };
+            let mut flags = buffer.flags;
+            if link.is_some() {
+                flags |= VIRTQ_DESC_F_NEXT;
+            }
+            let entry = DESC + 16 * index;
+            guest_write(GUEST, entry, buffer.addr as u64);
+            guest_write(GUEST, entry + 8, buffer.len);
+            guest_write(GUEST, entry + 12, flags);
+            guest_write(GUEST, entry + 14, link.unwrap_or(0));
+        }
+        let slot = (self.avail_idx % self.size) as usize;
+        guest_write(GUEST, AVAIL + 4 + 2 * slot, head);
+        self.avail_idx = self.avail_idx.wrapping_add(1);
+        guest_write(GUEST, AVAIL + 2, self.avail_idx);
+    }
+
+    fn notify(&mut self) -> bool {
+        self.write(regs::QUEUE_NOTIFY, 0)
+    }
+
+    /// used buffers not seen so far, as (head, written bytes)
+    fn take_used(&mut self) -> Vec<(u32, u32)> {
+        let used_idx: u16 = guest_read(GUEST, USED + 2).unwrap();
+        let mut used = Vec::new();
+        while self.used_idx != used_idx {
+            let entry = USED + 4 + 8 * (self.used_idx % self.size) as usize;
+            used.push((guest_read(GUEST, entry).unwrap(), guest_read(GUEST, entry + 4).unwrap()));
+            self.used_idx = self.used_idx.wrapping_add(1);
+        }
+        used
+    }
+}
+
+fn identification(driver: &mut Driver) {
+    let (magic, version, device_id) = (driver.read(regs::MAGIC), driver.read(regs::VERSION), driver.read(regs::DEVICE_ID));
+    driver.check(magic == VIRTIO_MMIO_MAGIC, "bad magic value");
+    driver.check(version == 2, "not a modern virtio-mmio transport");
+    driver.check(device_id != 0, "device id 0");
+}
+
+fn feature_negotiation(driver: &mut Driver) {
+    let offered = driver.device_features();
+    driver.check(offered & VIRTIO_F_VERSION_1 != 0, "VIRTIO_F_VERSION_1 not offered");
+    driver.check(offered & VIRTIO_F_EVENT_IDX == 0, "offers the event index the transport does not implement");
+    driver.write(regs::DEVICE_FEATURES_SEL, 2);
+    let high = driver.read(regs::DEVICE_FEATURES);
+    driver.check(high == 0, "offers feature bits above 63");
+    // the lowest bit that was not offered
+    let unoffered = !offered & offered.wrapping_add(1);
+    let accepted = driver.negotiate(offered | unoffered);
+    driver.check(!accepted, "accepts a feature it did not offer");
+    let accepted = driver.negotiate(VIRTIO_F_VERSION_1);
+    driver.check(accepted, "refuses VIRTIO_F_VERSION_1 alone");
+    let accepted = driver.negotiate(offered);
+    driver.check(accepted, "refuses the features it offered");
+    driver.write(regs::STATUS, 0);
+    let status = driver.read(regs::STATUS);
+    driver.check(status == 0, "status survives a reset");
+}
+
+fn queue_setup(driver: &mut Driver) {
+    driver.negotiate(VIRTIO_F_VERSION_1);
+    driver.write(regs::QUEUE_SEL, 0);
+    let max = driver.read(regs::QUEUE_NUM_MAX);
+    driver.check(max != 0 && max <= 32768, "bad QueueNumMax of queue 0");
+    driver.write(regs::QUEUE_SEL, 0xffff);
+    let max = driver.read(regs::QUEUE_NUM_MAX);
+    driver.check(max == 0, "QueueNumMax of a queue that does not exist");
+    driver.setup_queue(8);
+    let ready = driver.read(regs::QUEUE_READY);
+    driver.check(ready == 1, "queue 0 not ready after setup");
+    driver.offer(0, &[Buffer::writable(0)], None);
+    driver.notify();
+    let used = driver.take_used().len();
+    driver.check(used == 0, "uses buffers before DRIVER_OK");
+    driver.write(regs::STATUS, status::ACKNOWLEDGE | status::DRIVER | status::FEATURES_OK | status::DRIVER_OK);
+    driver.notify();
+    let used = driver.take_used();
+    driver.check(used.len() == 1 && used[0].0 == 0, "buffer made available before DRIVER_OK not used");
+    driver.write(regs::STATUS, 0);
+    driver.write(regs::QUEUE_SEL, 0);
+    let ready = driver.read(regs::QUEUE_READY);
+    driver.check(ready == 0, "queue 0 ready after a reset");
+}
+
+fn ring_wraparound(driver: &mut Driver) {
+    // slots: 5 rounds of 3 buffers through a 4 entry ring
+    driver.start(4);
+    for _ in 0..5 {
+        for head in 0..3 {
+            driver.offer(head, &[Buffer::writable(head as usize)], None);
+        }
+        driver.notify();
+        let used = driver.take_used();
+        let in_order = used.len() == 3 && used.iter().enumerate().all(|(i, &(head, len))| head == i as u32 && len <= BUFFER_SIZE as u32);
+        driver.check(in_order, "wrong used buffers after the ring slots wrapped");
+    }
+    // indices: empty buffers until the ring indices wrapped and a full ring more
+    driver.write(regs::QUEUE_SEL, 0);
+    let size = driver.read(regs::QUEUE_NUM_MAX).min(256) as u16;
+    driver.start(size);
+    let empty = Buffer { addr: BUFFERS, len: 0, flags: VIRTQ_DESC_F_WRITE };
+    for _ in 0..(1 << 16) / size as usize + 1 {
+        for head in 0..size {
+            driver.offer(head, &[empty], None);
+        }
+        driver.notify();
+        let used = driver.take_used();
+        if used.len() != size as usize || used.iter().enumerate().any(|(i, &(head, _))| head != i as u32) {
+            driver.check(false, "wrong used buffers after the ring indices wrapped");
+            break
+        }
+    }
+    driver.write(regs::STATUS, 0);
+}
+
+fn malformed_chains(driver: &mut Driver) {
+    driver.start(4);
+    // two descriptors pointing at each other
+    driver.offer(0, &[Buffer::writable(0), Buffer::writable(1)], Some(0));
+    driver.notify();
+    let used = driver.take_used();
+    driver.check(used.len() == 1 && used[0].0 == 0, "looping chain not returned");
+    // below the guest ram
+    driver.offer(0, &[Buffer { addr: OUTSIDE_RAM, len: BUFFER_SIZE as u32, flags: VIRTQ_DESC_F_WRITE }], None);
+    driver.notify();
+    let used = driver.take_used();
+    driver.check(used.len() == 1 && used[0].1 == 0, "buffer outside guest ram written");
+    // device-readable buffer, its content must survive
+    with_guest_memory(GUEST, BUFFERS, BUFFER_SIZE, |pattern| pattern.fill(0xa5)).unwrap();
+    driver.offer(0, &[Buffer { addr: BUFFERS, len: BUFFER_SIZE as u32, flags: 0 }], None);
+    driver.notify();
+    driver.take_used();
+    let intact = with_guest_memory(GUEST, BUFFERS, BUFFER_SIZE, |buffer| buffer.iter().all(|&byte| byte == 0xa5)).unwrap();
+    driver.check(intact, "device-readable buffer written");
+    driver.write(regs::STATUS, 0);
+}
+
+fn indirect_descriptors(driver: &mut Driver) {
+    for (features, expected) in [(VIRTIO_F_VERSION_1, 0), (VIRTIO_F_VERSION_1 | VIRTIO_F_INDIRECT_DESC, 2 * BUFFER_SIZE as u32)] {
+        driver.start_with(features, 4);
+        // two chained writable buffers
+        for (i, next) in [(0, 1u16), (1, 0)] {
+            let entry = TABLE + 16 * i;
+            guest_write(GUEST, entry, Buffer::writable(i).addr as u64);
+            guest_write(GUEST, entry + 8, BUFFER_SIZE as u32);
+            guest_write(GUEST, entry + 12, VIRTQ_DESC_F_WRITE | if next != 0 { VIRTQ_DESC_F_NEXT } else { 0 });
+            guest_write(GUEST, entry + 14, next);
+        }
+        driver.offer(0, &[Buffer { addr: TABLE, len: 32, flags: VIRTQ_DESC_F_INDIRECT }], None);
+        driver.notify();
+        let used = driver.take_used();
+        let what = if expected == 0 { "indirect table followed without VIRTIO_F_INDIRECT_DESC" } else { "indirect table not followed" };
+        driver.check(used.len() == 1 && used[0].1 == expected, what);
+    }
+    driver.write(regs::STATUS, 0);
+}
+
+fn packed_ring(driver: &mut Driver) {
+    // 3 entries, wrapped twice
+    driver.start_with(VIRTIO_F_VERSION_1 | VIRTIO_F_RING_PACKED, 3);
+    let (mut index, mut wrap) = (0, true);
+    for id in 0..7u16 {
+        let entry = DESC + 16 * index;
+        guest_write(GUEST, entry, Buffer::writable(0).addr as u64);
+        guest_write(GUEST, entry + 8, BUFFER_SIZE as u32);
+        guest_write(GUEST, entry + 12, id);
+        guest_write(GUEST, entry + 14, VIRTQ_DESC_F_WRITE | if wrap { VIRTQ_DESC_F_AVAIL } else { VIRTQ_DESC_F_USED });
+        let raised = driver.notify();
+        let (len, used_id, flags) = (guest_read::<u32>(GUEST, entry + 8).unwrap(), guest_read::<u16>(GUEST, entry + 12).unwrap(), guest_read::<u16>(GUEST, entry + 14).unwrap());
+        let used = (flags & VIRTQ_DESC_F_AVAIL != 0) == wrap && (flags & VIRTQ_DESC_F_USED != 0) == wrap;
+        driver.check(raised && used && used_id == id && len == BUFFER_SIZE as u32, "wrong used descriptor in a packed ring");
+        index += 1;
+        if index == 3 {
+            index = 0;
+            wrap = !wrap;
+        }
+    }
+    // driver event suppression
+    guest_write(GUEST, AVAIL + 2, RING_EVENT_FLAGS_DISABLE);
+    let entry = DESC + 16 * index;
+    guest_write(GUEST, entry + 8, BUFFER_SIZE as u32);
+    guest_write(GUEST, entry + 14, VIRTQ_DESC_F_WRITE | if wrap { VIRTQ_DESC_F_AVAIL } else { VIRTQ_DESC_F_USED });
+    let raised = driver.notify();
+    driver.check(!raised, "interrupt despite a disabled driver event");
+    driver.write(regs::STATUS, 0);
+}
+
+fn event_suppression(driver: &mut Driver) {
+    driver.start(4);
+    guest_write(GUEST, AVAIL, VIRTQ_AVAIL_F_NO_INTERRUPT);
+    driver.offer(0, &[Buffer::writable(0)], None);
+    let raised = driver.notify();
+    driver.check(!raised, "interrupt despite VIRTQ_AVAIL_F_NO_INTERRUPT");
+    let used = driver.take_used().len();
+    driver.check(used == 1, "buffer not used with interrupts suppressed");
+    driver.write(regs::INTERRUPT_ACK, INTERRUPT_USED_BUFFER);
+    guest_write(GUEST, AVAIL, 0u16);
+    driver.offer(0, &[Buffer::writable(0)], None);
+    let raised = driver.notify();
+    let interrupt_status = driver.read(regs::INTERRUPT_STATUS);
+    driver.check(raised && interrupt_status & INTERRUPT_USED_BUFFER != 0, "no interrupt for used buffers");
+    driver.write(regs::INTERRUPT_ACK, INTERRUPT_USED_BUFFER);
+    let interrupt_status = driver.read(regs::INTERRUPT_STATUS);
+    driver.check(interrupt_status == 0, "interrupt status survives the acknowledge");
+    driver.write(regs::STATUS, 0);
+}
+
+const TESTS: [fn(&mut Driver); 8] = [
+    identification,
+    feature_negotiation,
+    queue_setup,
+    ring_wraparound,
+    malformed_chains,
+    indirect_descriptors,
+    packed_ring,
+    event_suppression
+];
+
+/// exercise every device model, true if all checks passed. Called once paging is on and
+/// before the guest is created.
+pub fn run() -> bool {
+    let saved = with_guest_memory(GUEST, SCRATCH, SCRATCH_SIZE, |scratch| scratch.to_vec()).unwrap();
+    let mut failures = 0;
+    for (name, model) in MODELS {
+        let device = Device { base_address: 0, size: 0x1000, irq: None, interrupt_parent: None };
+        let mut driver = Driver { name, dev: EmulatedVirtio::new(GUEST, device, model()), failures: 0, size: 0, avail_idx: 0, used_idx: 0 };
+        for test in TESTS {
+            test(&mut driver);
+        }
+        failures += driver.failures;
+    }
+    with_guest_memory(GUEST, SCRATCH, SCRATCH_SIZE, |scratch| scratch.copy_from_slice(&saved)).unwrap();
+    if failures != 0 {
+        herror!("virtio selftest: {} checks failed", failures);
+        return false
+    }
+    hdebug!("virtio selftest passed for {} device models", MODELS.len());
+    true
+}
@@ -0,0 +1,33 @@
+//! virtio-mmio slots taken by the hypervisor (e.g. for its log console) stay in the
+//! guest device tree. Their registers are reserved as `MmioDevice::HostVirtio` windows
+//! and emulated as an empty slot: a valid magic value with device id 0, which drivers skip.
+
+use riscv_decode::Instruction;
+
+use crate::drivers::virtio::VIRTIO_MMIO_MAGIC;
+use crate::guest::vmexit::TrapContext;
+use crate::{ VmmError, VmmResult };
+
+/// size of a virtio-mmio register window
+pub const VIRTIO_MMIO_SIZE: usize = 0x1000;
+
+pub fn handle_empty_virtio_access(ctx: &mut TrapContext, guest_pa: usize, instruction: Instruction) -> VmmResult {
+    let offset = guest_pa & (VIRTIO_MMIO_SIZE - 1);
+    let value = match offset {
+        0x000 => VIRTIO_MMIO_MAGIC as usize,
+        // version
+        0x004 => 2,
+        _ => 0
+    };
+    let rd = match instruction {
+        Instruction::Lb(i) | Instruction::Lbu(i) | Instruction::Lh(i) | Instruction::Lhu(i)
+        | Instruction::Lw(i) | Instruction::Lwu(i) | Instruction::Ld(i) => i.rd(),
+        // writes to an empty slot are ignored
+        Instruction::Sb(_) | Instruction::Sh(_) | Instruction::Sw(_) | Instruction::Sd(_) => return Ok(()),
+        _ => return Err(VmmError::UnexpectedInst)
+    };
+    if rd != 0 {
+        ctx.x[rd as usize] = value;
+    }
+    Ok(())
+}
@@ -0,0 +1,175 @@
+//! Virtual watchdog
+//!
+//! A guest whose device tree has a `sifive,wdog0` node gets its own emulated SiFive
+//! watchdog there, with the register layout of the always-on block of SiFive cores.
+//! The counter runs on a 32 kHz clock derived from guest time, so a guest paused with
+//! `freeze_on_pause` does not expire on resume, and it is checked on the scheduler tick
+//! while the guest runs.
+//!
+//! The guest only sees the registers, what expiry means is up to the hypervisor: with
+//! `rsten` set the guest is rebooted through `HostVmm::reset_guest`, like a reboot it
+//! asked for itself. The interrupt output is not wired, `ip0` can be polled.
+
+use riscv::register::time;
+use riscv_decode::Instruction;
+
+use crate::constants::CLOCK_FREQ;
+use crate::guest::page_table::GuestPageTable;
+use crate::guest::read_htimedelta;
+use crate::guest::vmexit::TrapContext;
+use crate::hypervisor::HostVmm;
+use crate::page_table::PageTable;
+use crate::{ VmmError, VmmResult };
+
+pub mod regs {
+    pub const CFG: usize = 0x00;
+    pub const COUNT: usize = 0x08;
+    pub const SCALED: usize = 0x10;
+    pub const FEED: usize = 0x18;
+    pub const KEY: usize = 0x1c;
+    pub const CMP0: usize = 0x20;
+}
+
+/// bits of `wdogcfg`
+pub mod cfg {
+    pub const SCALE: u32 = 0xf;
+    pub const RSTEN: u32 = 1 << 8;
+    pub const ZEROCMP: u32 = 1 << 9;
+    pub const ENALWAYS: u32 = 1 << 12;
+    pub const ENCOREAWAKE: u32 = 1 << 13;
+    pub const IP0: u32 = 1 << 28;
+    pub const MASK: u32 = SCALE | RSTEN | ZEROCMP | ENALWAYS | ENCOREAWAKE | IP0;
+}
+
+/// written to `wdogkey` to unlock the next register write
+const KEY_UNLOCK: u32 = 0x0051_f15e;
+/// written to `wdogfeed` to restart the count
+const FEED_MAGIC: u32 = 0x0d09_f00d;
+/// rate of the low-frequency clock the counter runs on
+const LFCLK_FREQ: usize = 32768;
+/// `wdogcount` is 31 bits wide
+const COUNT_MASK: u64 = (1 << 31) - 1;
+
+/// guest time of the guest on the cpu, in cycles
+fn guest_time() -> usize {
+    time::read().wrapping_add(read_htimedelta())
+}
+
+#[derive(Debug, Default)]
+pub struct SifiveWatchdog {
+    cfg: u32,
+    /// counter value at guest time `since`
+    count: u64,
+    since: usize,
+    cmp0: u32,
+    /// the next register write is allowed
+    unlocked: bool
+}
+
+impl SifiveWatchdog {
+    fn running(&self) -> bool {
+        self.cfg & (cfg::ENALWAYS | cfg::ENCOREAWAKE) != 0
+    }
+
+    fn count(&self, now: usize) -> u64 {
+        if !self.running() {
+            return self.count
+        }
+        let ticks = now.wrapping_sub(self.since) as u128 * LFCLK_FREQ as u128 / CLOCK_FREQ as u128;
+        (self.count + ticks as u64) & COUNT_MASK
+    }
+
+    fn set_count(&mut self, now: usize, count: u64) {
+        self.count = count & COUNT_MASK;
+        self.since = now;
+    }
+
+    /// `wdogs`, 16 bits of the count starting at bit `scale`
+    fn scaled(&self, now: usize) -> u32 {
+        (self.count(now) >> (self.cfg & cfg::SCALE)) as u16 as u32
+    }
+
+    /// power-on state of the device, disabled
+    pub fn reset(&mut self) {
+        *self = Self::default();
+    }
+
+    pub fn read(&self, offset: usize, now: usize) -> u32 {
+        match offset {
+            regs::CFG => self.cfg,
+            regs::COUNT => self.count(now) as u32,
+            regs::SCALED => self.scaled(now),
+            regs::KEY => self.unlocked as u32,
+            regs::CMP0 => self.cmp0,
+            _ => 0
+        }
+    }
+
+    pub fn write(&mut self, offset: usize, value: u32, now: usize) {
+        if offset == regs::KEY {
+            self.unlocked = value == KEY_UNLOCK;
+            return
+        }
+        // every other register is locked, a write uses up the key
+        if !core::mem::take(&mut self.unlocked) {
+            return
+        }
+        match offset {
+            regs::CFG => {
+                let count = self.count(now);
+                self.cfg = value & cfg::MASK;
+                self.set_count(now, count);
+            },
+            regs::COUNT => self.set_count(now, value as u64),
+            regs::FEED if value == FEED_MAGIC => self.set_count(now, 0),
+            regs::CMP0 => self.cmp0 = value & 0xffff,
+            _ => {}
+        }
+    }
+
+    /// raise `ip0` if the comparator was reached at guest time `now`,
+    /// returns whether the guest is to be reset
+    pub fn check(&mut self, now: usize) -> bool {
+        if !self.running() || self.scaled(now) < self.cmp0 {
+            return false
+        }
+        self.cfg |= cfg::IP0;
+        if self.cfg & cfg::ZEROCMP != 0 {
+            self.set_count(now, 0);
+        }
+        self.cfg & cfg::RSTEN != 0
+    }
+}
+
+impl<P: PageTable, G: GuestPageTable> HostVmm<P, G> {
+    pub fn handle_watchdog_access(&mut self, ctx: &mut TrapContext, guest_pa: usize, instruction: Instruction) -> VmmResult {
+        let guest = self.guests.get_mut(self.guest_id).ok_or(VmmError::NoFound)?;
+        let offset = guest_pa - guest.guest_machine.watchdog.as_ref().ok_or(VmmError::DeviceNotFound)?.base_address;
+        match instruction {
+            Instruction::Lw(i) | Instruction::Lwu(i) => {
+                let value = guest.watchdog.read(offset, guest_time()) as usize;
+                if i.rd() != 0 {
+                    ctx.x[i.rd() as usize] = value;
+                }
+            },
+            Instruction::Sw(i) => guest.watchdog.write(offset, ctx.x[i.rs2() as usize] as u32, guest_time()),
+            _ => return Err(VmmError::UnexpectedInst)
+        }
+        Ok(())
+    }
+
+    /// reset the running guest if its watchdog expired. Called on the scheduler tick.
+    pub fn check_watchdog(&mut self) {
+        let guest_id = self.guest_id;
+        let expired = match self.guests.get_mut(guest_id) {
+            Some(guest) if guest.guest_machine.watchdog.is_some() => guest.watchdog.check(guest_time()),
+            _ => false
+        };
+        if expired {
+            hwarning!("watchdog of guest {} expired, resetting it", guest_id);
+            if let Err(err) = self.reset_guest(guest_id) {
+                herror!("failed to reset guest {}: {:?}", guest_id, err);
+            }
+        }
+    }
+}
@@ -0,0 +1,80 @@
+//! Host entropy
+//!
+//! With `hvc.entropy=zkr` on the command line the Zkr `seed` csr is used, which
+//! requires the firmware to grant S-mode access (`mseccfg.SSEED`). Otherwise bytes
+//! come from a generator seeded with the timer, good enough to unblock guest boot
+//! but not cryptographically strong.
+
+use core::sync::atomic::{ AtomicBool, Ordering };
+use riscv::register::time;
+use crate::sync::SpinIrqSave;
+
+use crate::hypervisor::fdt::MachineMeta;
+
+/// `seed` csr
+const CSR_SEED: usize = 0x015;
+
+mod opst {
+    pub const BIST: usize = 0b00;
+    pub const WAIT: usize = 0b01;
+    pub const ES16: usize = 0b10;
+    pub const DEAD: usize = 0b11;
+}
+
+static USE_ZKR: AtomicBool = AtomicBool::new(false);
+/// xorshift64* state
+static STATE: SpinIrqSave<u64> = SpinIrqSave::new(0x9e37_79b9_7f4a_7c15);
+
+pub fn init(machine: &MachineMeta) {
+    if machine.bootarg("hvc.entropy") == Some("zkr") {
+        USE_ZKR.store(true, Ordering::Relaxed);
+        hdebug!("entropy from the Zkr seed csr");
+    }else{
+        hwarning!("no hardware entropy source, guest entropy is not cryptographically strong");
+    }
+    let seed = zkr_seed64().unwrap_or(0) ^ time::read() as u64;
+    *STATE.lock() ^= seed | 1;
+}
+
+/// 16 bits of entropy from the seed csr
+fn zkr_seed16() -> Option<u16> {
+    if !USE_ZKR.load(Ordering::Relaxed) {
+        return None
+    }
+    for _ in 0..1000 {
+        let seed: usize;
+        unsafe{ core::arch::asm!("csrrw {}, {csr}, zero", out(reg) seed, csr = const CSR_SEED) };
+        match (seed >> 30) & 0b11 {
+            opst::ES16 => return Some(seed as u16),
+            opst::DEAD => return None,
+            opst::BIST | opst::WAIT => continue,
+            _ => unreachable!()
+        }
+    }
+    None
+}
+
+fn zkr_seed64() -> Option<u64> {
+    let mut seed = 0u64;
+    for _ in 0..4 {
+        seed = seed << 16 | zkr_seed16()? as u64;
+    }
+    Some(seed)
+}
+
+fn next_u64() -> u64 {
+    let mut state = STATE.lock();
+    // stir in fresh entropy whenever there is some
+    *state ^= zkr_seed64().unwrap_or(time::read() as u64);
+    *state ^= *state >> 12;
+    *state ^= *state << 25;
+    *state ^= *state >> 27;
+    state.wrapping_mul(0x2545_f491_4f6c_dd1d)
+}
+
+pub fn fill(buf: &mut [u8]) {
+    for chunk in buf.chunks_mut(8) {
+        let bytes = next_u64().to_le_bytes();
+        chunk.copy_from_slice(&bytes[..chunk.len()]);
+    }
+}
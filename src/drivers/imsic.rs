@@ -0,0 +1,78 @@
+//! S-level IMSIC interrupt file of the boot hart
+//!
+//! Receives MSIs of PCI functions assigned to guests. Interrupt identities are
+//! handed out by `alloc_id` and routed to guests by `drivers::irq`.
+
+use crate::hypervisor::fdt::Device;
+
+mod csr {
+    pub const SISELECT: usize = 0x150;
+    pub const SIREG: usize = 0x151;
+    pub const STOPEI: usize = 0x15c;
+}
+
+/// indirectly accessed interrupt file registers
+mod iselect {
+    pub const EIDELIVERY: usize = 0x70;
+    pub const EITHRESHOLD: usize = 0x72;
+    pub const EIE0: usize = 0xc0;
+}
+
+/// identities supported by every IMSIC
+pub const MAX_IDS: usize = 63;
+
+macro_rules! write_csr {
+    ($csr: expr, $value: expr) => {
+        core::arch::asm!("csrw {csr}, {value}", csr = const $csr, value = in(reg) $value)
+    };
+}
+
+pub struct Imsic {
+    /// interrupt file of the boot hart, target address of MSIs
+    base: usize,
+    next_id: usize
+}
+
+impl Imsic {
+    pub fn new(device: &Device) -> Self {
+        let imsic = Self { base: device.base_address, next_id: 1 };
+        unsafe{
+            write_csr!(csr::SISELECT, iselect::EIDELIVERY);
+            write_csr!(csr::SIREG, 1usize);
+            write_csr!(csr::SISELECT, iselect::EITHRESHOLD);
+            write_csr!(csr::SIREG, 0usize);
+        }
+        hdebug!("IMSIC interrupt file: {:#x}", imsic.base);
+        imsic
+    }
+
+    pub fn msi_address(&self) -> usize {
+        self.base
+    }
+
+    /// allocate and enable an interrupt identity
+    pub fn alloc_id(&mut self) -> Option<usize> {
+        if self.next_id > MAX_IDS {
+            return None
+        }
+        let id = self.next_id;
+        self.next_id += 1;
+        // eie registers hold 64 identities each, only even ones exist on RV64
+        let reg = iselect::EIE0 + 2 * (id / 64);
+        unsafe{
+            write_csr!(csr::SISELECT, reg);
+            core::arch::asm!("csrs {csr}, {bit}", csr = const csr::SIREG, bit = in(reg) 1usize << (id % 64));
+        }
+        Some(id)
+    }
+
+    /// claim the highest priority pending identity
+    pub fn claim(&self) -> Option<usize> {
+        let topei: usize;
+        unsafe{ core::arch::asm!("csrrw {}, {csr}, zero", out(reg) topei, csr = const csr::STOPEI) };
+        match topei >> 16 {
+            0 => None,
+            id => Some(id)
+        }
+    }
+}
@@ -0,0 +1,177 @@
+//! IOPMP, for SoCs without an IOMMU
+//!
+//! An IOPMP checks the physical addresses of bus master accesses against a table of
+//! entries. Masters are told apart by their requester id (RRID), a memory domain (MD)
+//! groups entries, and `SRCMD_EN` of a requester selects the domains it may use.
+//!
+//! Every guest gets a memory domain holding its ram, programmed when the guest is added.
+//! A bus master assigned to the guest, a PCI function whose requester id is its B/D/F,
+//! is switched to that domain only, so its DMA reaches the guest's frames and nothing
+//! else. Entries are split evenly between the domains, with two TOR entries per ram
+//! range. Masters the hypervisor does not assign keep the firmware configuration.
+
+use crate::guest::page_table::GuestPageTable;
+use crate::hypervisor::HostVmm;
+use crate::hypervisor::fdt::Device;
+use crate::page_table::PageTable;
+use crate::{ VmmError, VmmResult };
+
+mod regs {
+    pub const HWCFG0: usize = 0x08;
+    pub const HWCFG1: usize = 0x0c;
+    pub const ENTRYOFFSET: usize = 0x14;
+    /// `MDCFG(m)` at `MDCFG + 4 * m`
+    pub const MDCFG: usize = 0x800;
+    /// `SRCMD_EN(s)` at `SRCMD + 32 * s`
+    pub const SRCMD: usize = 0x1000;
+    /// registers of an entry, `ENTRY_SIZE` apart
+    pub const ENTRY_ADDR: usize = 0x0;
+    pub const ENTRY_ADDRH: usize = 0x4;
+    pub const ENTRY_CFG: usize = 0x8;
+    pub const ENTRY_SIZE: usize = 0x10;
+}
+
+mod hwcfg0 {
+    pub const TOR_EN: u32 = 1 << 4;
+    pub const MD_NUM_SHIFT: u32 = 24;
+    pub const MD_NUM_MASK: u32 = 0x3f;
+    pub const ENABLE: u32 = 1 << 31;
+}
+
+/// bits of `ENTRY_CFG`
+mod entry_cfg {
+    pub const R: u32 = 1 << 0;
+    pub const W: u32 = 1 << 1;
+    pub const A_OFF: u32 = 0 << 3;
+    pub const A_TOR: u32 = 1 << 3;
+}
+
+/// `SRCMD_EN.L`, the requester cannot be reconfigured before reset
+const SRCMD_LOCK: u32 = 1 << 0;
+
+pub struct Iopmp {
+    base: usize,
+    rrid_num: usize,
+    md_num: usize,
+    entry_offset: usize,
+    /// entries of each memory domain
+    entries_per_md: usize
+}
+
+impl Iopmp {
+    fn read(&self, offset: usize) -> u32 {
+        unsafe{ core::ptr::read_volatile((self.base + offset) as *const u32) }
+    }
+
+    fn write(&self, offset: usize, value: u32) {
+        unsafe{ core::ptr::write_volatile((self.base + offset) as *mut u32, value) }
+    }
+
+    /// take over the IOPMP at `device`, `None` without TOR support or memory domains
+    pub fn new(device: &Device) -> Option<Self> {
+        let mut iopmp = Self { base: device.base_address, rrid_num: 0, md_num: 0, entry_offset: 0, entries_per_md: 0 };
+        let hwcfg0 = iopmp.read(regs::HWCFG0);
+        let hwcfg1 = iopmp.read(regs::HWCFG1);
+        iopmp.rrid_num = (hwcfg1 & 0xffff) as usize;
+        iopmp.md_num = ((hwcfg0 >> hwcfg0::MD_NUM_SHIFT) & hwcfg0::MD_NUM_MASK) as usize;
+        iopmp.entry_offset = iopmp.read(regs::ENTRYOFFSET) as usize;
+        let entry_num = (hwcfg1 >> 16) as usize;
+        if hwcfg0 & hwcfg0::TOR_EN == 0 || iopmp.md_num == 0 || entry_num < 2 * iopmp.md_num {
+            hwarning!("IOPMP at {:#x} is not usable, device DMA is not confined", iopmp.base);
+            return None
+        }
+        iopmp.entries_per_md = entry_num / iopmp.md_num;
+        // entries [T(m-1), T(m)) belong to domain m
+        for md in 0..iopmp.md_num {
+            iopmp.write(regs::MDCFG + 4 * md, ((md + 1) * iopmp.entries_per_md) as u32);
+        }
+        iopmp.write(regs::HWCFG0, hwcfg0 | hwcfg0::ENABLE);
+        hdebug!(
+            "IOPMP at {:#x}: {} requesters, {} domains of {} entries",
+            iopmp.base, iopmp.rrid_num, iopmp.md_num, iopmp.entries_per_md
+        );
+        Some(iopmp)
+    }
+
+    fn write_entry(&self, index: usize, addr: usize, cfg: u32) {
+        let entry = self.entry_offset + index * regs::ENTRY_SIZE;
+        // addresses are in units of 4 bytes
+        self.write(entry + regs::ENTRY_ADDR, (addr >> 2) as u32);
+        self.write(entry + regs::ENTRY_ADDRH, (addr >> 34) as u32);
+        self.write(entry + regs::ENTRY_CFG, cfg);
+    }
+
+    /// memory domain of a guest
+    fn domain(&self, guest_id: usize) -> VmmResult<usize> {
+        // `SRCMD_EN` has room for 31 domains
+        if guest_id < self.md_num.min(31) { Ok(guest_id) } else { Err(VmmError::NotSupported) }
+    }
+
+    /// allow the domain of `guest_id` to read and write the host physical `ranges` only
+    pub fn set_guest_ranges(&self, guest_id: usize, ranges: &[(usize, usize)]) -> VmmResult {
+        let md = self.domain(guest_id)?;
+        if 2 * ranges.len() > self.entries_per_md {
+            return Err(VmmError::OutOfMemory)
+        }
+        let first = md * self.entries_per_md;
+        for index in first..first + self.entries_per_md {
+            self.write_entry(index, 0, entry_cfg::A_OFF);
+        }
+        for (i, &(start, end)) in ranges.iter().enumerate() {
+            self.write_entry(first + 2 * i, start, entry_cfg::A_OFF);
+            self.write_entry(first + 2 * i + 1, end, entry_cfg::A_TOR | entry_cfg::R | entry_cfg::W);
+        }
+        Ok(())
+    }
+
+    /// confine requester `rrid` to the domain of `guest_id`
+    pub fn assign(&self, rrid: usize, guest_id: usize) -> VmmResult {
+        let md = self.domain(guest_id)?;
+        if rrid >= self.rrid_num || self.read(regs::SRCMD + 32 * rrid) & SRCMD_LOCK != 0 {
+            return Err(VmmError::NotSupported)
+        }
+        // bit 0 is the lock, domains start at bit 1
+        self.write(regs::SRCMD + 32 * rrid, 1 << (md + 1));
+        Ok(())
+    }
+
+    /// requester `rrid` loses access to all domains
+    pub fn release(&self, rrid: usize) {
+        if rrid < self.rrid_num && self.read(regs::SRCMD + 32 * rrid) & SRCMD_LOCK == 0 {
+            self.write(regs::SRCMD + 32 * rrid, 0);
+        }
+    }
+}
+
+impl<P: PageTable, G: GuestPageTable> HostVmm<P, G> {
+    /// program the domain of a new guest and confine its PCI functions to it
+    pub fn iopmp_add_guest(&mut self, guest_id: usize) -> VmmResult {
+        let iopmp = match self.host_iopmp.as_ref() {
+            Some(iopmp) => iopmp,
+            None => return Ok(())
+        };
+        let guest = self.guests.get(guest_id).ok_or(VmmError::NoFound)?;
+        iopmp.set_guest_ranges(guest_id, &guest.gpm.ram_ranges(&guest.guest_machine))?;
+        for function in guest.pci.functions.iter() {
+            iopmp.assign(function.bdf.requester_id(), guest_id)?;
+        }
+        Ok(())
+    }
+
+    /// the domain of a removed guest no longer reaches anything
+    pub fn iopmp_remove_guest(&mut self, guest_id: usize) {
+        if let Some(iopmp) = self.host_iopmp.as_ref() {
+            let _ = iopmp.set_guest_ranges(guest_id, &[]);
+        }
+    }
+
+    pub fn iopmp_assign(&self, guest_id: usize, rrid: usize) -> VmmResult {
+        self.host_iopmp.as_ref().map_or(Ok(()), |iopmp| iopmp.assign(rrid, guest_id))
+    }
+
+    pub fn iopmp_release(&self, rrid: usize) {
+        if let Some(iopmp) = self.host_iopmp.as_ref() {
+            iopmp.release(rrid);
+        }
+    }
+}
@@ -0,0 +1,153 @@
+//! Interrupts of devices owned by the hypervisor
+//!
+//! Host devices share the S-mode PLIC context of the running guest. `handle_irq`
+//! claims every interrupt; the ones listed here are handled and completed by the
+//! hypervisor instead of being injected into the guest.
+//!
+//! MSIs of PCI functions assigned to guests arrive at the IMSIC of the hypervisor
+//! and are forwarded to the owning guest as virtual PLIC interrupts.
+//!
+//! Interrupts of a vcpu are claimed from the physical PLIC context given to it in
+//! `PlicContexts`, the emulated claim register of the vcpu is kept for that context.
+
+use alloc::collections::BTreeMap;
+
+use crate::board;
+use crate::constants::MAX_CONTEXTS;
+use crate::guest::page_table::GuestPageTable;
+use crate::hypervisor::HostVmm;
+use crate::page_table::PageTable;
+
+/// destination of an MSI received by the hypervisor
+#[derive(Clone, Copy, Debug)]
+pub struct MsiRoute {
+    pub guest_id: usize,
+    /// interrupt claimed by the guest from its emulated PLIC
+    pub irq: u32
+}
+
+/// physical PLIC context of every (guest, vcpu)
+pub struct PlicContexts {
+    contexts: BTreeMap<(usize, usize), usize>
+}
+
+impl PlicContexts {
+    pub const fn new() -> Self {
+        Self { contexts: BTreeMap::new() }
+    }
+
+    /// give the vcpus of a new guest a context. Vcpus of a guest take turns on one hart,
+    /// they share the lowest S-mode context of the board no other guest uses.
+    pub fn assign_guest(&mut self, guest_id: usize, vcpus: usize) -> Option<usize> {
+        let context = board::platform().plic_s_contexts().iter().copied()
+            .filter(|&context| context < MAX_CONTEXTS)
+            .find(|context| !self.contexts.iter().any(|(&(id, _), c)| id != guest_id && c == context))?;
+        (0..vcpus).for_each(|vcpu| { self.contexts.insert((guest_id, vcpu), context); });
+        Some(context)
+    }
+
+    pub fn release_guest(&mut self, guest_id: usize) {
+        self.contexts.retain(|&(id, _), _| id != guest_id);
+    }
+
+    pub fn context(&self, guest_id: usize, vcpu: usize) -> Option<usize> {
+        self.contexts.get(&(guest_id, vcpu)).copied()
+    }
+
+    /// physical contexts used by a guest
+    pub fn guest_contexts(&self, guest_id: usize) -> impl Iterator<Item = usize> + '_ {
+        self.contexts.iter().filter(move |(&(id, _), _)| id == guest_id).map(|(_, &context)| context)
+    }
+}
+
+impl<P: PageTable, G: GuestPageTable> HostVmm<P, G> {
+    /// physical PLIC context of the running vcpu
+    pub fn current_plic_context(&self) -> Option<usize> {
+        let vcpu = self.guests.get(self.guest_id)?.vcpu.hart;
+        self.plic_contexts.context(self.guest_id, vcpu)
+    }
+
+    /// whether `irq` belongs to a device of the hypervisor
+    pub fn is_host_irq(&self, irq: usize) -> bool {
+        self.host_irqs.contains(&irq)
+    }
+
+    /// route a host interrupt to its driver
+    pub fn handle_host_irq(&mut self, irq: usize) {
+        if let Some(blk) = self.host_blk.as_mut() {
+            if self.host_blk_irq == Some(irq) {
+                blk.handle_irq();
+                return
+            }
+        }
+        #[cfg(feature = "monitor")]
+        if self.host_uart_irq == Some(irq) {
+            crate::monitor::uart_rx(self);
+            return
+        }
+        if self.host_net_irq == Some(irq) {
+            crate::net::with_stack(|stack| {
+                stack.ack_interrupt();
+                stack.poll();
+            });
+            #[cfg(feature = "monitor")]
+            crate::monitor::remote::poll(self);
+            return
+        }
+        hwarning!("unexpected host irq {}", irq);
+    }
+
+    /// forward MSIs pending at the IMSIC to their guests
+    pub fn handle_host_msis(&mut self) {
+        while let Some(id) = self.host_imsic.as_ref().and_then(|imsic| imsic.claim()) {
+            match self.msi_routes.get(&id).copied() {
+                Some(route) => self.inject_guest_irq(route.guest_id, route.irq),
+                None => hwarning!("unexpected MSI {}", id)
+            }
+        }
+    }
+
+    /// program the host MSI-X entry behind entry `index` of a guest table.
+    /// Guests have no MSI controller: the message data written by the guest is the
+    /// interrupt it claims from its emulated PLIC.
+    pub fn route_msix(&mut self, guest_id: usize, function: usize, index: usize) {
+        let function = match self.guests.get_mut(guest_id).and_then(|guest| guest.pci.functions.get_mut(function)) {
+            Some(function) => function,
+            None => return
+        };
+        let entry = match function.msix_entry(index) {
+            Some(entry) => entry,
+            None => return
+        };
+        if entry.masked {
+            function.write_host_msix(index, None);
+            return
+        }
+        let imsic = match self.host_imsic.as_mut() {
+            Some(imsic) => imsic,
+            None => {
+                hwarning!("no IMSIC, MSI-X entry {} of guest {} stays masked", index, guest_id);
+                return
+            }
+        };
+        let id = match entry.host_id.or_else(|| imsic.alloc_id()) {
+            Some(id) => id,
+            None => {
+                hwarning!("out of IMSIC identities, MSI-X entry {} of guest {} stays masked", index, guest_id);
+                return
+            }
+        };
+        function.set_msix_host_id(index, id);
+        self.msi_routes.insert(id, MsiRoute { guest_id, irq: entry.data });
+        function.write_host_msix(index, Some((imsic.msi_address(), id as u32)));
+    }
+
+    /// route `irq` of a host device to the hypervisor
+    pub fn register_host_irq(&mut self, irq: usize) {
+        if let Some(host_plic) = self.host_plic.as_ref() {
+            // S-mode context of the boot hart, shared with the running guest
+            host_plic.enable_irq(1, irq);
+        }
+        self.host_irqs.push(irq);
+    }
+}
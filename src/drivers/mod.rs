@@ -1 +1,7 @@
-pub mod iommu;
\ No newline at end of file
+pub mod iommu;
+pub mod virtio;
+pub mod uart;
+pub mod irq;
+pub mod imsic;
+pub mod iopmp;
+pub mod entropy;
@@ -0,0 +1,178 @@
+//! Early boot console: a polled MMIO uart driver independent of the SBI console
+//!
+//! The uart is picked from the host device tree (`/chosen/stdout-path`, or the first
+//! compatible node) before the heap exists, so bring-up on a new board is debuggable
+//! even if the firmware console is broken. A device tree without a supported uart gets
+//! the console of the board, see `board::Platform`. Without one, output falls back to
+//! SBI putchar. Output is always polled, input may raise an interrupt.
+
+use core::ptr::{ read_volatile, write_volatile };
+use fdt::Fdt;
+use spin::Once;
+
+use crate::board;
+
+const NS16550_COMPATIBLE: &[&str] = &["ns16550a", "ns16550", "snps,dw-apb-uart"];
+const SIFIVE_COMPATIBLE: &[&str] = &["sifive,uart0"];
+const UART_COMPATIBLE: &[&str] = &["ns16550a", "ns16550", "snps,dw-apb-uart", "sifive,uart0"];
+
+mod ns16550 {
+    pub const THR: usize = 0;
+    pub const RBR: usize = 0;
+    pub const IER: usize = 1;
+    pub const LSR: usize = 5;
+    pub const IER_RX_AVAILABLE: u8 = 1 << 0;
+    pub const LSR_DATA_READY: u8 = 1 << 0;
+    pub const LSR_THR_EMPTY: u8 = 1 << 5;
+}
+
+mod sifive {
+    pub const TXDATA: usize = 0x00;
+    pub const RXDATA: usize = 0x04;
+    pub const TXCTRL: usize = 0x08;
+    pub const RXCTRL: usize = 0x0c;
+    pub const IE: usize = 0x10;
+    /// rxctrl: watermark, the interrupt is raised while more entries are queued
+    pub const RXCNT_SHIFT: u32 = 16;
+    pub const RXCNT_MASK: u32 = 0x7 << RXCNT_SHIFT;
+    pub const IE_RXWM: u32 = 1 << 1;
+    /// txdata: fifo full, rxdata: fifo empty
+    pub const FLAG: u32 = 1 << 31;
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum UartKind {
+    Ns16550,
+    Sifive
+}
+
+#[derive(Debug)]
+pub struct Uart {
+    base: usize,
+    kind: UartKind,
+    /// register stride of ns16550 compatibles
+    reg_shift: usize
+}
+
+impl Uart {
+    pub fn base(&self) -> usize {
+        self.base
+    }
+
+    fn ns16550_read(&self, reg: usize) -> u8 {
+        unsafe{ read_volatile((self.base + (reg << self.reg_shift)) as *const u8) }
+    }
+
+    fn ns16550_write(&self, reg: usize, value: u8) {
+        unsafe{ write_volatile((self.base + (reg << self.reg_shift)) as *mut u8, value) }
+    }
+
+    fn sifive_read(&self, reg: usize) -> u32 {
+        unsafe{ read_volatile((self.base + reg) as *const u32) }
+    }
+
+    fn sifive_write(&self, reg: usize, value: u32) {
+        unsafe{ write_volatile((self.base + reg) as *mut u32, value) }
+    }
+
+    fn init(&self) {
+        if self.kind == UartKind::Sifive {
+            // the firmware normally enabled it already
+            self.sifive_write(sifive::TXCTRL, self.sifive_read(sifive::TXCTRL) | 1);
+            self.sifive_write(sifive::RXCTRL, self.sifive_read(sifive::RXCTRL) | 1);
+        }
+    }
+
+    pub fn putchar(&self, c: u8) {
+        match self.kind {
+            UartKind::Ns16550 => {
+                while self.ns16550_read(ns16550::LSR) & ns16550::LSR_THR_EMPTY == 0 {
+                    core::hint::spin_loop();
+                }
+                self.ns16550_write(ns16550::THR, c);
+            },
+            UartKind::Sifive => {
+                while self.sifive_read(sifive::TXDATA) & sifive::FLAG != 0 {
+                    core::hint::spin_loop();
+                }
+                self.sifive_write(sifive::TXDATA, c as u32);
+            }
+        }
+    }
+
+    /// raise the uart interrupt while received characters are waiting
+    pub fn enable_rx_irq(&self) {
+        match self.kind {
+            UartKind::Ns16550 => {
+                let ier = self.ns16550_read(ns16550::IER);
+                self.ns16550_write(ns16550::IER, ier | ns16550::IER_RX_AVAILABLE);
+            },
+            UartKind::Sifive => {
+                let rxctrl = self.sifive_read(sifive::RXCTRL) & !sifive::RXCNT_MASK;
+                self.sifive_write(sifive::RXCTRL, rxctrl);
+                self.sifive_write(sifive::IE, self.sifive_read(sifive::IE) | sifive::IE_RXWM);
+            }
+        }
+    }
+
+    pub fn getchar(&self) -> Option<u8> {
+        match self.kind {
+            UartKind::Ns16550 => {
+                if self.ns16550_read(ns16550::LSR) & ns16550::LSR_DATA_READY != 0 {
+                    Some(self.ns16550_read(ns16550::RBR))
+                }else{
+                    None
+                }
+            },
+            UartKind::Sifive => {
+                let data = self.sifive_read(sifive::RXDATA);
+                if data & sifive::FLAG == 0 { Some(data as u8) } else { None }
+            }
+        }
+    }
+}
+
+static EARLY_UART: Once<Uart> = Once::new();
+
+fn uart_kind(compatible: &str) -> Option<UartKind> {
+    if NS16550_COMPATIBLE.contains(&compatible) {
+        Some(UartKind::Ns16550)
+    }else if SIFIVE_COMPATIBLE.contains(&compatible) {
+        Some(UartKind::Sifive)
+    }else{
+        None
+    }
+}
+
+/// probe the uart described by the host device tree, the one of the board if there is
+/// none, needs no heap
+pub fn init(dtb: usize) {
+    let uart = probe(dtb).or_else(|| board::platform().console().map(|console| Uart {
+        base: console.base,
+        kind: console.kind,
+        reg_shift: console.reg_shift
+    }));
+    if let Some(uart) = uart {
+        EARLY_UART.call_once(|| uart).init();
+    }
+}
+
+fn probe(dtb: usize) -> Option<Uart> {
+    let fdt = unsafe{ Fdt::from_ptr(dtb as *const u8) }.ok()?;
+    // `stdout-path` may carry options after a ':'
+    let stdout = fdt.find_node("/chosen")
+        .and_then(|chosen| chosen.property("stdout-path"))
+        .and_then(|path| path.as_str())
+        .and_then(|path| path.split(':').next())
+        .and_then(|path| fdt.find_node(path));
+    let node = stdout.or_else(|| fdt.find_compatible(UART_COMPATIBLE))?;
+    let kind = node.compatible().and_then(|c| c.all().find_map(uart_kind))?;
+    let base = node.reg().and_then(|mut reg| reg.next())?.starting_address as usize;
+    let reg_shift = node.property("reg-shift").and_then(|p| p.as_usize()).unwrap_or(0);
+    Some(Uart { base, kind, reg_shift })
+}
+
+/// the early uart, `None` if the board has no supported uart
+pub fn early_uart() -> Option<&'static Uart> {
+    EARLY_UART.get()
+}
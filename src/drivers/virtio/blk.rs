@@ -0,0 +1,200 @@
+//! virtio-blk frontend for a disk owned by the hypervisor
+//!
+//! Requests are submitted with `submit` and completed either by polling (`poll`)
+//! or from the device interrupt (`handle_irq`). `read_blocks` and `write_blocks`
+//! are synchronous helpers for boot time use.
+
+use alloc::boxed::Box;
+use alloc::collections::{ BTreeMap, VecDeque };
+use alloc::vec;
+use alloc::vec::Vec;
+
+use super::{ VirtioMmio, VirtQueue, device_id };
+use crate::{ VmmError, VmmResult };
+
+pub const SECTOR_SIZE: usize = 512;
+
+const REQUEST_QUEUE: u32 = 0;
+
+/// device is read only
+const VIRTIO_BLK_F_RO: u64 = 1 << 5;
+
+const VIRTIO_BLK_T_IN: u32 = 0;
+const VIRTIO_BLK_T_OUT: u32 = 1;
+const VIRTIO_BLK_T_FLUSH: u32 = 4;
+
+pub const VIRTIO_BLK_S_OK: u8 = 0;
+pub const VIRTIO_BLK_S_IOERR: u8 = 1;
+pub const VIRTIO_BLK_S_UNSUPP: u8 = 2;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BlkOp {
+    Read,
+    Write,
+    Flush
+}
+
+#[repr(C)]
+struct BlkReqHeader {
+    req_type: u32,
+    reserved: u32,
+    sector: u64
+}
+
+/// a request owned by the driver while the device works on it
+struct BlkRequest {
+    op: BlkOp,
+    header: Box<BlkReqHeader>,
+    data: Vec<u8>,
+    status: Box<u8>
+}
+
+#[derive(Debug)]
+pub struct BlkCompletion {
+    /// token returned by `submit`
+    pub token: u16,
+    pub op: BlkOp,
+    pub status: u8,
+    /// data read from the disk, or the buffer that was written
+    pub data: Vec<u8>
+}
+
+pub struct VirtioBlk {
+    dev: VirtioMmio,
+    queue: VirtQueue,
+    /// capacity in sectors
+    capacity: u64,
+    read_only: bool,
+    inflight: BTreeMap<u16, BlkRequest>,
+    completed: VecDeque<BlkCompletion>
+}
+
+impl VirtioBlk {
+    pub fn new(dev: VirtioMmio) -> VmmResult<Self> {
+        if dev.device_id() != device_id::BLOCK {
+            return Err(VmmError::DeviceNotFound)
+        }
+        let features = dev.init(VIRTIO_BLK_F_RO)?;
+        let queue = VirtQueue::new();
+        dev.setup_queue(REQUEST_QUEUE, &queue)?;
+        dev.driver_ok();
+        // capacity is the first field of the configuration space
+        let capacity = dev.read_config::<u32>(0) as u64 | (dev.read_config::<u32>(4) as u64) << 32;
+        hdebug!("virtio-blk {:#x}: {} sectors{}", dev.base(), capacity, if features & VIRTIO_BLK_F_RO != 0 { ", read only" } else { "" });
+        Ok(Self {
+            dev,
+            queue,
+            capacity,
+            read_only: features & VIRTIO_BLK_F_RO != 0,
+            inflight: BTreeMap::new(),
+            completed: VecDeque::new()
+        })
+    }
+
+    pub fn base(&self) -> usize {
+        self.dev.base()
+    }
+
+    pub fn capacity(&self) -> u64 {
+        self.capacity
+    }
+
+    pub fn read_only(&self) -> bool {
+        self.read_only
+    }
+
+    /// queue a request, `data` is the buffer to write or the buffer to read into.
+    /// Return a token identifying the request in its completion.
+    pub fn submit(&mut self, op: BlkOp, sector: u64, data: Vec<u8>) -> VmmResult<u16> {
+        if data.len() % SECTOR_SIZE != 0 || sector + (data.len() / SECTOR_SIZE) as u64 > self.capacity {
+            return Err(VmmError::NotSupported)
+        }
+        if op == BlkOp::Write && self.read_only {
+            return Err(VmmError::NotSupported)
+        }
+        let req_type = match op {
+            BlkOp::Read => VIRTIO_BLK_T_IN,
+            BlkOp::Write => VIRTIO_BLK_T_OUT,
+            BlkOp::Flush => VIRTIO_BLK_T_FLUSH
+        };
+        let mut request = BlkRequest {
+            op,
+            header: Box::new(BlkReqHeader { req_type, reserved: 0, sector }),
+            data,
+            status: Box::new(0xff)
+        };
+        let header = unsafe{
+            core::slice::from_raw_parts(&*request.header as *const BlkReqHeader as *const u8, core::mem::size_of::<BlkReqHeader>())
+        };
+        let status = unsafe{ core::slice::from_raw_parts_mut(&mut *request.status as *mut u8, 1) };
+        let token = match op {
+            BlkOp::Read => self.queue.add(&[header], &mut [&mut request.data[..], status]),
+            BlkOp::Write => self.queue.add(&[header, &request.data[..]], &mut [status]),
+            BlkOp::Flush => self.queue.add(&[header], &mut [status])
+        }.ok_or(VmmError::NotSupported)?;
+        self.inflight.insert(token, request);
+        self.dev.notify(REQUEST_QUEUE);
+        Ok(token)
+    }
+
+    /// move requests used by the device to the completion queue
+    fn collect(&mut self) {
+        while let Some((token, _)) = self.queue.pop_used() {
+            if let Some(request) = self.inflight.remove(&token) {
+                self.completed.push_back(BlkCompletion {
+                    token,
+                    op: request.op,
+                    status: *request.status,
+                    data: request.data
+                });
+            }
+        }
+    }
+
+    /// device interrupt: acknowledge it and collect finished requests
+    pub fn handle_irq(&mut self) {
+        self.dev.ack_interrupt();
+        self.collect();
+    }
+
+    /// next finished request, if any
+    pub fn poll(&mut self) -> Option<BlkCompletion> {
+        self.collect();
+        self.completed.pop_front()
+    }
+
+    /// take the completion of `token` if the request finished
+    pub fn take_completion(&mut self, token: u16) -> Option<BlkCompletion> {
+        self.collect();
+        let index = self.completed.iter().position(|c| c.token == token)?;
+        self.completed.remove(index)
+    }
+
+    fn wait(&mut self, token: u16) -> VmmResult<BlkCompletion> {
+        loop {
+            if let Some(completion) = self.take_completion(token) {
+                if completion.status != VIRTIO_BLK_S_OK {
+                    herror!("virtio-blk request failed, status {}", completion.status);
+                    return Err(VmmError::NotSupported)
+                }
+                return Ok(completion)
+            }
+            core::hint::spin_loop();
+        }
+    }
+
+    /// read `buf.len()` bytes starting at `sector`, waiting for the device
+    pub fn read_blocks(&mut self, sector: u64, buf: &mut [u8]) -> VmmResult {
+        let token = self.submit(BlkOp::Read, sector, vec![0; buf.len()])?;
+        let completion = self.wait(token)?;
+        buf.copy_from_slice(&completion.data);
+        Ok(())
+    }
+
+    /// write `buf` starting at `sector`, waiting for the device
+    pub fn write_blocks(&mut self, sector: u64, buf: &[u8]) -> VmmResult {
+        let token = self.submit(BlkOp::Write, sector, buf.to_vec())?;
+        self.wait(token)?;
+        Ok(())
+    }
+}
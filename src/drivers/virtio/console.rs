@@ -0,0 +1,45 @@
+//! virtio console owned by the hypervisor, used as a log sink
+
+use alloc::vec;
+use alloc::vec::Vec;
+
+use super::{ VirtioMmio, VirtQueue, device_id };
+use crate::{ VmmError, VmmResult };
+
+const RX_QUEUE: u32 = 0;
+const TX_QUEUE: u32 = 1;
+/// bytes sent per transmit request
+const TX_BUFFER_SIZE: usize = 512;
+
+pub struct VirtioConsole {
+    dev: VirtioMmio,
+    rx: VirtQueue,
+    tx: VirtQueue,
+    /// bounce buffer in identity mapped memory
+    tx_buf: Vec<u8>
+}
+
+impl VirtioConsole {
+    pub fn new(dev: VirtioMmio) -> VmmResult<Self> {
+        if dev.device_id() != device_id::CONSOLE {
+            return Err(VmmError::DeviceNotFound)
+        }
+        dev.init(0)?;
+        let console = Self { dev, rx: VirtQueue::new(), tx: VirtQueue::new(), tx_buf: vec![0; TX_BUFFER_SIZE] };
+        console.dev.setup_queue(RX_QUEUE, &console.rx)?;
+        console.dev.setup_queue(TX_QUEUE, &console.tx)?;
+        console.dev.driver_ok();
+        Ok(console)
+    }
+
+    pub fn base(&self) -> usize {
+        self.dev.base()
+    }
+
+    pub fn write(&mut self, bytes: &[u8]) {
+        for chunk in bytes.chunks(TX_BUFFER_SIZE) {
+            self.tx_buf[..chunk.len()].copy_from_slice(chunk);
+            let _ = self.dev.transfer(TX_QUEUE, &mut self.tx, &[&self.tx_buf[..chunk.len()]], &mut []);
+        }
+    }
+}
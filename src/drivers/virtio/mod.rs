@@ -0,0 +1,228 @@
+//! Minimal virtio-mmio driver for devices owned by the hypervisor
+//!
+//! Both the legacy (version 1) and the modern (version 2) mmio transport are supported.
+//! Devices are driven synchronously by polling the used ring, the hypervisor never
+//! takes interrupts from them. Buffers handed to a device must live in identity mapped
+//! hypervisor memory (heap or frames), hypervisor stacks are not identity mapped.
+
+use core::ptr::{ read_volatile, write_volatile };
+
+use riscv::register::time;
+
+use crate::constants::{ PAGE_SIZE, CLOCK_FREQ };
+use crate::hypervisor::fdt::{ Device, MachineMeta };
+use crate::{ VmmError, VmmResult };
+
+mod queue;
+pub mod console;
+pub mod blk;
+pub mod net;
+
+pub use queue::{ VirtQueue, QUEUE_SIZE };
+
+pub const VIRTIO_MMIO_MAGIC: u32 = 0x7472_6976;
+
+/// a request not used by the device after this long fails (1s)
+const TRANSFER_TIMEOUT: usize = CLOCK_FREQ;
+
+/// feature bit telling a modern device that the driver follows virtio 1.0
+pub const VIRTIO_F_VERSION_1: u64 = 1 << 32;
+/// ring feature bits
+pub const VIRTIO_F_INDIRECT_DESC: u64 = 1 << 28;
+pub const VIRTIO_F_EVENT_IDX: u64 = 1 << 29;
+pub const VIRTIO_F_RING_PACKED: u64 = 1 << 34;
+
+pub mod device_id {
+    pub const NET: u32 = 1;
+    pub const BLOCK: u32 = 2;
+    pub const CONSOLE: u32 = 3;
+    pub const ENTROPY: u32 = 4;
+    pub const GPU: u32 = 16;
+}
+
+pub mod regs {
+    pub const MAGIC: usize = 0x000;
+    pub const VERSION: usize = 0x004;
+    pub const DEVICE_ID: usize = 0x008;
+    pub const VENDOR_ID: usize = 0x00c;
+    pub const DEVICE_FEATURES: usize = 0x010;
+    pub const DEVICE_FEATURES_SEL: usize = 0x014;
+    pub const DRIVER_FEATURES: usize = 0x020;
+    pub const DRIVER_FEATURES_SEL: usize = 0x024;
+    /// legacy only
+    pub const GUEST_PAGE_SIZE: usize = 0x028;
+    pub const QUEUE_SEL: usize = 0x030;
+    pub const QUEUE_NUM_MAX: usize = 0x034;
+    pub const QUEUE_NUM: usize = 0x038;
+    /// legacy only
+    pub const QUEUE_ALIGN: usize = 0x03c;
+    /// legacy only
+    pub const QUEUE_PFN: usize = 0x040;
+    pub const QUEUE_READY: usize = 0x044;
+    pub const QUEUE_NOTIFY: usize = 0x050;
+    pub const INTERRUPT_STATUS: usize = 0x060;
+    pub const INTERRUPT_ACK: usize = 0x064;
+    pub const STATUS: usize = 0x070;
+    pub const QUEUE_DESC_LOW: usize = 0x080;
+    pub const QUEUE_DESC_HIGH: usize = 0x084;
+    pub const QUEUE_DRIVER_LOW: usize = 0x090;
+    pub const QUEUE_DRIVER_HIGH: usize = 0x094;
+    pub const QUEUE_DEVICE_LOW: usize = 0x0a0;
+    pub const QUEUE_DEVICE_HIGH: usize = 0x0a4;
+    pub const CONFIG_GENERATION: usize = 0x0fc;
+    pub const CONFIG: usize = 0x100;
+}
+
+pub mod status {
+    pub const ACKNOWLEDGE: u32 = 1;
+    pub const DRIVER: u32 = 2;
+    pub const DRIVER_OK: u32 = 4;
+    pub const FEATURES_OK: u32 = 8;
+    pub const FAILED: u32 = 128;
+}
+
+pub struct VirtioMmio {
+    base: usize,
+    version: u32
+}
+
+impl VirtioMmio {
+    /// `None` if there is no virtio device (or only an empty slot) at `base`
+    pub fn probe(base: usize) -> Option<Self> {
+        let dev = Self { base, version: 0 };
+        if dev.read(regs::MAGIC) != VIRTIO_MMIO_MAGIC || dev.read(regs::DEVICE_ID) == 0 {
+            return None
+        }
+        let version = dev.read(regs::VERSION);
+        Some(Self { base, version })
+    }
+
+    pub fn base(&self) -> usize {
+        self.base
+    }
+
+    pub fn device_id(&self) -> u32 {
+        self.read(regs::DEVICE_ID)
+    }
+
+    fn read(&self, offset: usize) -> u32 {
+        unsafe{ read_volatile((self.base + offset) as *const u32) }
+    }
+
+    fn write(&self, offset: usize, value: u32) {
+        unsafe{ write_volatile((self.base + offset) as *mut u32, value) }
+    }
+
+    /// reset the device and negotiate features, `supported` are the device features the driver handles
+    pub fn init(&self, supported: u64) -> VmmResult<u64> {
+        self.write(regs::STATUS, 0);
+        let mut device_status = status::ACKNOWLEDGE;
+        self.write(regs::STATUS, device_status);
+        device_status |= status::DRIVER;
+        self.write(regs::STATUS, device_status);
+
+        self.write(regs::DEVICE_FEATURES_SEL, 0);
+        let low = self.read(regs::DEVICE_FEATURES) as u64;
+        self.write(regs::DEVICE_FEATURES_SEL, 1);
+        let high = self.read(regs::DEVICE_FEATURES) as u64;
+        let mut supported = supported;
+        if self.version >= 2 {
+            supported |= VIRTIO_F_VERSION_1;
+        }
+        let features = (low | high << 32) & supported;
+        self.write(regs::DRIVER_FEATURES_SEL, 0);
+        self.write(regs::DRIVER_FEATURES, features as u32);
+        self.write(regs::DRIVER_FEATURES_SEL, 1);
+        self.write(regs::DRIVER_FEATURES, (features >> 32) as u32);
+
+        if self.version >= 2 {
+            device_status |= status::FEATURES_OK;
+            self.write(regs::STATUS, device_status);
+            if self.read(regs::STATUS) & status::FEATURES_OK == 0 {
+                self.write(regs::STATUS, status::FAILED);
+                return Err(VmmError::NotSupported)
+            }
+        }else{
+            self.write(regs::GUEST_PAGE_SIZE, PAGE_SIZE as u32);
+        }
+        Ok(features)
+    }
+
+    /// hand virtqueue `index` to the device
+    pub fn setup_queue(&self, index: u32, queue: &VirtQueue) -> VmmResult {
+        self.write(regs::QUEUE_SEL, index);
+        let max = self.read(regs::QUEUE_NUM_MAX) as usize;
+        if max == 0 || max < QUEUE_SIZE {
+            return Err(VmmError::NotSupported)
+        }
+        self.write(regs::QUEUE_NUM, QUEUE_SIZE as u32);
+        if self.version >= 2 {
+            let (desc, driver, device) = (queue.desc_addr() as u64, queue.avail_addr() as u64, queue.used_addr() as u64);
+            self.write(regs::QUEUE_DESC_LOW, desc as u32);
+            self.write(regs::QUEUE_DESC_HIGH, (desc >> 32) as u32);
+            self.write(regs::QUEUE_DRIVER_LOW, driver as u32);
+            self.write(regs::QUEUE_DRIVER_HIGH, (driver >> 32) as u32);
+            self.write(regs::QUEUE_DEVICE_LOW, device as u32);
+            self.write(regs::QUEUE_DEVICE_HIGH, (device >> 32) as u32);
+            self.write(regs::QUEUE_READY, 1);
+        }else{
+            self.write(regs::QUEUE_ALIGN, PAGE_SIZE as u32);
+            self.write(regs::QUEUE_PFN, (queue.desc_addr() / PAGE_SIZE) as u32);
+        }
+        Ok(())
+    }
+
+    /// device setup is done, the device may now be used
+    pub fn driver_ok(&self) {
+        let device_status = self.read(regs::STATUS);
+        self.write(regs::STATUS, device_status | status::DRIVER_OK);
+    }
+
+    pub fn notify(&self, index: u32) {
+        self.write(regs::QUEUE_NOTIFY, index);
+    }
+
+    /// acknowledge all pending interrupts, return the interrupt status
+    pub fn ack_interrupt(&self) -> u32 {
+        let pending = self.read(regs::INTERRUPT_STATUS);
+        self.write(regs::INTERRUPT_ACK, pending);
+        pending
+    }
+
+    /// read device specific configuration
+    pub fn read_config<T: Copy>(&self, offset: usize) -> T {
+        unsafe{ read_volatile((self.base + regs::CONFIG + offset) as *const T) }
+    }
+
+    /// submit a buffer chain to virtqueue `index` and poll until the device used it,
+    /// return the number of bytes written by the device.
+    /// A device that does not answer within `TRANSFER_TIMEOUT` is reset, so it no longer
+    /// touches the buffers, and stays unusable.
+    pub fn transfer(&self, index: u32, queue: &mut VirtQueue, inputs: &[&[u8]], outputs: &mut [&mut [u8]]) -> VmmResult<usize> {
+        let head = queue.add(inputs, outputs).ok_or(VmmError::NotSupported)?;
+        self.notify(index);
+        let start = time::read();
+        loop {
+            if let Some((id, len)) = queue.pop_used() {
+                if id == head {
+                    return Ok(len as usize)
+                }
+            }
+            if time::read().wrapping_sub(start) > TRANSFER_TIMEOUT {
+                herror!("virtio device at {:#x} did not answer on queue {}, resetting it", self.base, index);
+                self.write(regs::STATUS, 0);
+                return Err(VmmError::Timeout)
+            }
+            core::hint::spin_loop();
+        }
+    }
+}
+
+/// take the virtio device at `base` of the host machine for the hypervisor,
+/// it is removed from the devices passed through to the guest
+pub fn claim(machine: &MachineMeta, guest_machine: &mut MachineMeta, base: usize) -> Option<(VirtioMmio, Device)> {
+    let device = machine.virtio.iter().find(|dev| dev.base_address == base)?.clone();
+    let dev = VirtioMmio::probe(base)?;
+    guest_machine.virtio.retain(|dev| dev.base_address != base);
+    Some((dev, device))
+}
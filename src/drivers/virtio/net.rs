@@ -0,0 +1,109 @@
+//! virtio-net frontend for a NIC owned by the hypervisor
+
+use alloc::collections::BTreeMap;
+use alloc::vec;
+use alloc::vec::Vec;
+
+use super::{ VirtioMmio, VirtQueue, QUEUE_SIZE, VIRTIO_F_VERSION_1, device_id };
+use crate::{ VmmError, VmmResult };
+
+const RX_QUEUE: u32 = 0;
+const TX_QUEUE: u32 = 1;
+
+/// device reports its mac address in the configuration space
+const VIRTIO_NET_F_MAC: u64 = 1 << 5;
+
+/// ethernet frame plus the virtio net header
+const RX_BUFFER_SIZE: usize = 1536 + 12;
+
+pub struct VirtioNet {
+    dev: VirtioMmio,
+    rx: VirtQueue,
+    tx: VirtQueue,
+    mac: [u8; 6],
+    /// `struct virtio_net_hdr` is 12 bytes for virtio 1.0 devices, 10 for legacy ones
+    header_len: usize,
+    /// receive buffers given to the device, by descriptor head
+    rx_bufs: BTreeMap<u16, Vec<u8>>,
+    tx_buf: Vec<u8>
+}
+
+impl VirtioNet {
+    pub fn new(dev: VirtioMmio) -> VmmResult<Self> {
+        if dev.device_id() != device_id::NET {
+            return Err(VmmError::DeviceNotFound)
+        }
+        let features = dev.init(VIRTIO_NET_F_MAC)?;
+        let mut net = Self {
+            rx: VirtQueue::new(),
+            tx: VirtQueue::new(),
+            mac: [0x52, 0x54, 0x00, 0x12, 0x34, 0x57],
+            header_len: if features & VIRTIO_F_VERSION_1 != 0 { 12 } else { 10 },
+            rx_bufs: BTreeMap::new(),
+            tx_buf: vec![0; RX_BUFFER_SIZE],
+            dev
+        };
+        if features & VIRTIO_NET_F_MAC != 0 {
+            for i in 0..6 {
+                net.mac[i] = net.dev.read_config::<u8>(i);
+            }
+        }
+        net.dev.setup_queue(RX_QUEUE, &net.rx)?;
+        net.dev.setup_queue(TX_QUEUE, &net.tx)?;
+        for _ in 0..QUEUE_SIZE {
+            net.refill_one();
+        }
+        net.dev.driver_ok();
+        net.dev.notify(RX_QUEUE);
+        hdebug!("virtio-net {:#x}: mac {:02x?}", net.dev.base(), net.mac);
+        Ok(net)
+    }
+
+    fn refill_one(&mut self) {
+        let mut buf = vec![0u8; RX_BUFFER_SIZE];
+        if let Some(head) = self.rx.add(&[], &mut [&mut buf[..]]) {
+            self.rx_bufs.insert(head, buf);
+        }
+    }
+
+    pub fn base(&self) -> usize {
+        self.dev.base()
+    }
+
+    pub fn mac(&self) -> [u8; 6] {
+        self.mac
+    }
+
+    /// send an ethernet frame, waiting until the device took it
+    pub fn send(&mut self, frame: &[u8]) -> VmmResult {
+        let len = self.header_len + frame.len();
+        if len > self.tx_buf.len() {
+            return Err(VmmError::NotSupported)
+        }
+        // all-zero header: no offloads
+        self.tx_buf[..self.header_len].fill(0);
+        self.tx_buf[self.header_len..len].copy_from_slice(frame);
+        self.dev.transfer(TX_QUEUE, &mut self.tx, &[&self.tx_buf[..len]], &mut [])?;
+        Ok(())
+    }
+
+    /// next received ethernet frame, if any
+    pub fn recv(&mut self) -> Option<Vec<u8>> {
+        let (head, len) = self.rx.pop_used()?;
+        let mut buf = self.rx_bufs.remove(&head)?;
+        self.refill_one();
+        self.dev.notify(RX_QUEUE);
+        let len = (len as usize).min(buf.len());
+        if len < self.header_len {
+            return None
+        }
+        buf.truncate(len);
+        buf.drain(..self.header_len);
+        Some(buf)
+    }
+
+    /// device interrupt, received frames are picked up with `recv`
+    pub fn ack_interrupt(&self) {
+        self.dev.ack_interrupt();
+    }
+}
@@ -0,0 +1,169 @@
+//! Split virtqueue laid out for the legacy mmio transport: descriptor table and
+//! available ring in the first page, used ring in the second one.
+
+use alloc::alloc::{ alloc_zeroed, dealloc, Layout };
+use core::ptr::{ read_volatile, write_volatile };
+use core::sync::atomic::{ fence, Ordering };
+
+use crate::constants::PAGE_SIZE;
+
+pub const QUEUE_SIZE: usize = 16;
+
+const VIRTQ_DESC_F_NEXT: u16 = 1;
+const VIRTQ_DESC_F_WRITE: u16 = 2;
+
+#[repr(C)]
+struct Descriptor {
+    addr: u64,
+    len: u32,
+    flags: u16,
+    next: u16
+}
+
+#[repr(C)]
+struct AvailRing {
+    flags: u16,
+    idx: u16,
+    ring: [u16; QUEUE_SIZE],
+    used_event: u16
+}
+
+#[repr(C)]
+struct UsedElem {
+    id: u32,
+    len: u32
+}
+
+#[repr(C)]
+struct UsedRing {
+    flags: u16,
+    idx: u16,
+    ring: [UsedElem; QUEUE_SIZE],
+    avail_event: u16
+}
+
+const AVAIL_OFFSET: usize = QUEUE_SIZE * core::mem::size_of::<Descriptor>();
+const USED_OFFSET: usize = PAGE_SIZE;
+const QUEUE_LAYOUT: (usize, usize) = (2 * PAGE_SIZE, PAGE_SIZE);
+
+pub struct VirtQueue {
+    /// base of the queue memory, identity mapped
+    mem: usize,
+    free_head: u16,
+    num_free: usize,
+    /// next available ring index
+    avail_idx: u16,
+    /// next used ring index to consume
+    last_used: u16
+}
+
+impl VirtQueue {
+    pub fn new() -> Self {
+        let mem = unsafe{ alloc_zeroed(Self::layout()) } as usize;
+        assert!(mem != 0, "virtqueue allocation failed");
+        let queue = Self { mem, free_head: 0, num_free: QUEUE_SIZE, avail_idx: 0, last_used: 0 };
+        for i in 0..QUEUE_SIZE {
+            queue.desc(i as u16).next = (i + 1) as u16;
+        }
+        queue
+    }
+
+    fn layout() -> Layout {
+        Layout::from_size_align(QUEUE_LAYOUT.0, QUEUE_LAYOUT.1).unwrap()
+    }
+
+    pub fn desc_addr(&self) -> usize {
+        self.mem
+    }
+
+    pub fn avail_addr(&self) -> usize {
+        self.mem + AVAIL_OFFSET
+    }
+
+    pub fn used_addr(&self) -> usize {
+        self.mem + USED_OFFSET
+    }
+
+    fn desc(&self, index: u16) -> &mut Descriptor {
+        unsafe{ &mut *((self.mem as *mut Descriptor).add(index as usize)) }
+    }
+
+    fn avail(&self) -> &mut AvailRing {
+        unsafe{ &mut *(self.avail_addr() as *mut AvailRing) }
+    }
+
+    fn used(&self) -> &UsedRing {
+        unsafe{ &*(self.used_addr() as *const UsedRing) }
+    }
+
+    /// add a chain of buffers read (`inputs`) and written (`outputs`) by the device,
+    /// return the head descriptor or `None` if the queue is full
+    pub fn add(&mut self, inputs: &[&[u8]], outputs: &mut [&mut [u8]]) -> Option<u16> {
+        let count = inputs.len() + outputs.len();
+        if count == 0 || count > self.num_free {
+            return None
+        }
+        let head = self.free_head;
+        let mut last = head;
+        let buffers = inputs.iter().map(|b| (b.as_ptr() as usize, b.len(), 0))
+            .chain(outputs.iter_mut().map(|b| (b.as_mut_ptr() as usize, b.len(), VIRTQ_DESC_F_WRITE)));
+        for (addr, len, flags) in buffers {
+            let desc = self.desc(self.free_head);
+            desc.addr = addr as u64;
+            desc.len = len as u32;
+            desc.flags = flags | VIRTQ_DESC_F_NEXT;
+            let next = desc.next;
+            last = self.free_head;
+            self.free_head = next;
+        }
+        self.desc(last).flags &= !VIRTQ_DESC_F_NEXT;
+        self.num_free -= count;
+
+        let slot = self.avail_idx as usize % QUEUE_SIZE;
+        self.avail_idx = self.avail_idx.wrapping_add(1);
+        let avail = self.avail();
+        avail.ring[slot] = head;
+        // descriptors must be visible before the index is published
+        fence(Ordering::SeqCst);
+        unsafe{ write_volatile(&mut avail.idx, self.avail_idx) };
+        fence(Ordering::SeqCst);
+        Some(head)
+    }
+
+    pub fn can_pop(&self) -> bool {
+        unsafe{ read_volatile(&self.used().idx) != self.last_used }
+    }
+
+    /// take a used chain back, return its head and the number of bytes the device wrote
+    pub fn pop_used(&mut self) -> Option<(u16, u32)> {
+        if !self.can_pop() {
+            return None
+        }
+        fence(Ordering::SeqCst);
+        let (head, len) = {
+            let elem = &self.used().ring[self.last_used as usize % QUEUE_SIZE];
+            unsafe{ (read_volatile(&elem.id) as u16, read_volatile(&elem.len)) }
+        };
+        self.last_used = self.last_used.wrapping_add(1);
+        // return the chain to the free list
+        let mut index = head;
+        loop {
+            self.num_free += 1;
+            let free_head = self.free_head;
+            let desc = self.desc(index);
+            if desc.flags & VIRTQ_DESC_F_NEXT == 0 {
+                desc.next = free_head;
+                break
+            }
+            index = desc.next;
+        }
+        self.free_head = head;
+        Some((head, len))
+    }
+}
+
+impl Drop for VirtQueue {
+    fn drop(&mut self) {
+        unsafe{ dealloc(self.mem as *mut u8, Self::layout()) }
+    }
+}
@@ -0,0 +1,89 @@
+//! Workarounds for silicon quirks of the H extension
+//!
+//! `init` reads `mvendorid`, `marchid` and `mimpid` of the boot hart through the SBI base
+//! extension and turns on the workarounds of the entries of `ERRATA` matching them. The
+//! code paths affected check `active` at runtime:
+//!
+//! - `HTINST`: `htinst` holds garbage after some guest page faults, faulting
+//!   instructions are always read from guest memory, see `vmexit::fetch_fault_inst`
+//! - `HFENCE`: `hfence.gvma` leaves combined VS-stage and G-stage translations behind,
+//!   every stage-2 flush is followed by an `hfence.vvma`
+//!
+//! `hvc.errata=off` turns them all off, `hvc.errata=<list>` turns on the listed ones,
+//! `htinst` and `hfence` separated by commas, besides the ones of the table: for silicon
+//! not in the table yet.
+
+use core::sync::atomic::{ AtomicU32, Ordering };
+
+use crate::hypervisor::fdt::MachineMeta;
+
+bitflags! {
+    /// workaround paths turned on
+    pub struct Workarounds: u32 {
+        /// decode faulting guest instructions from guest memory, never from `htinst`
+        const HTINST = 1 << 0;
+        /// follow stage-2 flushes with a flush of the guest translations
+        const HFENCE = 1 << 1;
+    }
+}
+
+const T_HEAD: usize = 0x5b7;
+const SIFIVE: usize = 0x489;
+
+/// cores with a quirk, `None` matches any id
+struct Erratum {
+    name: &'static str,
+    mvendorid: usize,
+    marchid: Option<usize>,
+    mimpid: Option<usize>,
+    workarounds: Workarounds
+}
+
+const ERRATA: &[Erratum] = &[
+    // C910/C920 report 0 for both ids
+    Erratum { name: "T-Head C9xx htinst", mvendorid: T_HEAD, marchid: Some(0), mimpid: None, workarounds: Workarounds::HTINST },
+    Erratum { name: "SiFive P550 hfence", mvendorid: SIFIVE, marchid: Some(0x8000_0000_0000_0008), mimpid: None, workarounds: Workarounds::HFENCE }
+];
+
+static WORKAROUNDS: AtomicU32 = AtomicU32::new(0);
+
+impl Erratum {
+    fn matches(&self, mvendorid: usize, marchid: usize, mimpid: usize) -> bool {
+        self.mvendorid == mvendorid
+            && self.marchid.map_or(true, |id| id == marchid)
+            && self.mimpid.map_or(true, |id| id == mimpid)
+    }
+}
+
+fn parse(arg: &str) -> Option<Workarounds> {
+    arg.split(',').try_fold(Workarounds::empty(), |workarounds, name| match name {
+        "htinst" => Some(workarounds | Workarounds::HTINST),
+        "hfence" => Some(workarounds | Workarounds::HFENCE),
+        _ => None
+    })
+}
+
+/// turn on the workarounds of the boot hart, before any guest runs
+pub fn init(machine: &MachineMeta) {
+    let (mvendorid, marchid, mimpid) = (sbi_rt::get_mvendorid(), sbi_rt::get_marchid(), sbi_rt::get_mimpid());
+    let mut workarounds = Workarounds::empty();
+    for erratum in ERRATA.iter().filter(|erratum| erratum.matches(mvendorid, marchid, mimpid)) {
+        hdebug!("erratum {}: workarounds {:?}", erratum.name, erratum.workarounds);
+        workarounds |= erratum.workarounds;
+    }
+    match machine.bootarg("hvc.errata") {
+        Some("off") => workarounds = Workarounds::empty(),
+        Some(arg) => match parse(arg) {
+            Some(forced) => workarounds |= forced,
+            None => hwarning!("invalid hvc.errata, ignored")
+        },
+        None => {}
+    }
+    hdebug!("cpu {:#x}/{:#x}/{:#x}, workarounds {:?}", mvendorid, marchid, mimpid, workarounds);
+    WORKAROUNDS.store(workarounds.bits(), Ordering::Release);
+}
+
+/// all of `workarounds` are turned on
+pub fn active(workarounds: Workarounds) -> bool {
+    Workarounds::from_bits_truncate(WORKAROUNDS.load(Ordering::Relaxed)).contains(workarounds)
+}
@@ -7,7 +7,12 @@ pub enum VmmError {
     DeviceNotFound,
     PseudoInst,
     DecodeInstError,
-    UnexpectedInst
+    UnexpectedInst,
+    InvalidState,
+    /// no frame left, even after the OOM policy ran
+    OutOfMemory,
+    /// a device did not complete a request in time
+    Timeout
 }
 
 pub type VmmResult<T = ()> = Result<T, VmmError>;
\ No newline at end of file
@@ -0,0 +1,153 @@
+//! Exit and entry latency benchmark
+//!
+//! The baseline for performance regressions of the trap path. Armed with the monitor
+//! `bench start [count]`, the next `count` guest exits of every class are timed with the
+//! `cycle` csr, from the first instruction of `trap_handler` to the return into the guest
+//! in `switch_to_guest`, until `bench stop`. Any guest provides the load, the guest of
+//! `scripts/bench.sh` runs a fast and a locked SBI call, an emulated PLIC load and a
+//! timer interrupt in a loop and prints its own view of each round trip. `bench` prints min, median, 99th percentile, average and max cycles per
+//! class, next to the interrupt injection latency kept by `irqlat`.
+
+use alloc::vec::Vec;
+use core::fmt::Write;
+use core::sync::atomic::{ AtomicUsize, Ordering };
+use riscv::register::cycle;
+
+use crate::arch::{ GuestExit, Irq };
+use crate::irqlat;
+use crate::sync::SpinIrqSave;
+use crate::{ VmmError, VmmResult };
+
+/// exits timed per class by default
+pub const DEFAULT_COUNT: usize = 10_000;
+/// most exits timed per class, the samples of all classes take 560 KiB of the hypervisor
+/// heap and as much again while they are reported
+pub const MAX_COUNT: usize = 20_000;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ExitClass {
+    /// SBI call handled by `fastpath` without taking the lock
+    SbiFast,
+    SbiCall,
+    /// guest page fault, mostly MMIO emulation
+    GuestPageFault,
+    VirtualInstruction,
+    ExternalIrq,
+    Timer,
+    Other
+}
+
+const CLASSES: usize = 7;
+const CLASS_NAMES: [&str; CLASSES] = ["sbi fast", "sbi", "page fault", "virt inst", "ext irq", "timer", "other"];
+
+/// exits still timed per class, 0 if the benchmark is not armed
+static COUNT: AtomicUsize = AtomicUsize::new(0);
+/// cycles of the timed exits by class
+static SAMPLES: SpinIrqSave<[Vec<u32>; CLASSES]> = SpinIrqSave::new([
+    Vec::new(), Vec::new(), Vec::new(), Vec::new(), Vec::new(), Vec::new(), Vec::new()
+]);
+
+per_cpu! {
+    /// `cycle` at the trap entry
+    static EXIT_START: usize = 0;
+    /// class of the exit being timed, `CLASSES` if none
+    static EXIT_CLASS: usize = CLASSES;
+}
+
+impl ExitClass {
+    fn of(exit: GuestExit) -> Self {
+        match exit {
+            GuestExit::Hypercall => ExitClass::SbiCall,
+            GuestExit::GuestPageFault(_) => ExitClass::GuestPageFault,
+            GuestExit::VirtualInstruction => ExitClass::VirtualInstruction,
+            GuestExit::Irq(Irq::External) => ExitClass::ExternalIrq,
+            GuestExit::Irq(Irq::Timer) => ExitClass::Timer,
+            _ => ExitClass::Other
+        }
+    }
+}
+
+/// time the next `count` exits of every class, at most `MAX_COUNT`, earlier results are
+/// dropped. Fails if the hypervisor heap cannot hold the samples.
+pub fn start(count: usize) -> VmmResult {
+    let count = count.min(MAX_COUNT);
+    COUNT.store(0, Ordering::Relaxed);
+    let mut samples = SAMPLES.lock();
+    for class in samples.iter_mut() {
+        *class = Vec::new();
+    }
+    for class in samples.iter_mut() {
+        if class.try_reserve_exact(count).is_err() {
+            *samples = Default::default();
+            return Err(VmmError::OutOfMemory)
+        }
+    }
+    COUNT.store(count, Ordering::Relaxed);
+    Ok(())
+}
+
+/// stop timing exits, the results are kept
+pub fn stop() {
+    COUNT.store(0, Ordering::Relaxed);
+}
+
+/// first thing on a guest trap
+#[inline(always)]
+pub fn exit_taken(exit: GuestExit) {
+    if COUNT.load(Ordering::Relaxed) == 0 {
+        return
+    }
+    EXIT_START.set(cycle::read());
+    EXIT_CLASS.set(ExitClass::of(exit) as usize);
+}
+
+/// the exit being timed was handled as `class`, e.g. by the SBI fast path
+pub fn reclassify(class: ExitClass) {
+    if EXIT_CLASS.get() != CLASSES {
+        EXIT_CLASS.set(class as usize);
+    }
+}
+
+/// last thing before the guest is entered again
+#[inline(always)]
+pub fn entering_guest() {
+    let class = EXIT_CLASS.get();
+    if class == CLASSES {
+        return
+    }
+    let cycles = cycle::read().wrapping_sub(EXIT_START.get());
+    EXIT_CLASS.set(CLASSES);
+    let count = COUNT.load(Ordering::Relaxed);
+    let mut samples = SAMPLES.lock();
+    if samples[class].len() < count {
+        samples[class].push(cycles.min(u32::MAX as usize) as u32);
+    }
+}
+
+/// write the statistics of the timed exits to `out`
+pub fn report_to(out: &mut dyn Write) {
+    // copied first, output written to the memory sink must not wait for the samples
+    let samples = SAMPLES.lock().clone();
+    let count = COUNT.load(Ordering::Relaxed);
+    if count != 0 {
+        let _ = writeln!(out, "running, up to {} exits per class", count);
+    }
+    let _ = writeln!(
+        out, "{:>10} {:>8} {:>8} {:>8} {:>8} {:>8} {:>8}",
+        "exit", "count", "min", "median", "p99", "avg", "max"
+    );
+    for (name, mut cycles) in CLASS_NAMES.iter().zip(samples) {
+        if cycles.is_empty() {
+            continue;
+        }
+        cycles.sort_unstable();
+        let total: usize = cycles.iter().map(|&c| c as usize).sum();
+        let _ = writeln!(
+            out, "{:>10} {:>8} {:>8} {:>8} {:>8} {:>8} {:>8}",
+            name, cycles.len(), cycles[0], cycles[cycles.len() / 2], cycles[cycles.len() * 99 / 100],
+            total / cycles.len(), cycles[cycles.len() - 1]
+        );
+    }
+    let _ = writeln!(out, "(cycles from trap entry to guest entry)");
+    irqlat::report_to(out);
+}
@@ -0,0 +1,162 @@
+//! Guest address space switches
+//!
+//! State the hypervisor derives from guest page tables, like cached GVA translations or
+//! the address space shown by a debugger, goes stale when a vcpu switches `vsatp` or
+//! flushes its TLB. Such users register an `AddrSpaceHook` on the vcpus they follow.
+//!
+//! Switches are noticed at the next exit by comparing `vsatp` with the value seen at the
+//! previous one. With `GuestConfig::trap_vsatp` the guest runs with `hstatus.VTVM` set:
+//! its `satp` accesses and `sfence.vma` trap and are emulated, so switches are seen when
+//! they happen and TLB flushes are seen at all.
+
+use alloc::vec::Vec;
+use riscv::register::vsatp;
+use riscv_decode::Instruction;
+
+use super::Guest;
+use super::csrcheck;
+use super::page_table::GuestPageTable;
+use super::pmap::{ fetch_guest_inst, decode_inst };
+use super::vmexit::TrapContext;
+use crate::arch;
+use crate::hypervisor::HostVmm;
+use crate::page_table::{ PageTable, WalkContext };
+use crate::{ VmmError, VmmResult };
+
+/// csr number of `satp`, accessed as `vsatp` by a guest
+const CSR_SATP: u32 = 0x180;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AddrSpaceEvent {
+    /// `vsatp` changed from `old` to `new`
+    Switch { old: usize, new: usize },
+    /// the guest executed `sfence.vma`, `None` fields stand for all addresses or address spaces
+    Flush { vaddr: Option<usize>, asid: Option<usize> }
+}
+
+/// called with the guest id and the virtual hart id
+pub type AddrSpaceHook = fn(usize, usize, AddrSpaceEvent);
+
+/// address space hooks of a vcpu
+#[derive(Default)]
+pub struct AddrSpaceHooks {
+    hooks: Vec<AddrSpaceHook>,
+    /// `vsatp` seen at the last exit
+    last_vsatp: usize
+}
+
+impl AddrSpaceHooks {
+    pub fn register(&mut self, hook: AddrSpaceHook) {
+        if !self.hooks.iter().any(|&h| h as usize == hook as usize) {
+            self.hooks.push(hook);
+        }
+    }
+
+    pub fn unregister(&mut self, hook: AddrSpaceHook) {
+        self.hooks.retain(|&h| h as usize != hook as usize);
+    }
+
+    fn notify(&self, guest_id: usize, hart: usize, event: AddrSpaceEvent) {
+        self.hooks.iter().for_each(|hook| hook(guest_id, hart, event));
+    }
+}
+
+impl<G: GuestPageTable> Guest<G> {
+    /// follow the address spaces of all vcpus of the guest
+    pub fn register_addr_space_hook(&mut self, hook: AddrSpaceHook) {
+        self.vcpu.addr_space.register(hook);
+        self.harts.iter_mut().for_each(|vhart| vhart.addr_space.register(hook));
+    }
+
+    pub fn unregister_addr_space_hook(&mut self, hook: AddrSpaceHook) {
+        self.vcpu.addr_space.unregister(hook);
+        self.harts.iter_mut().for_each(|vhart| vhart.addr_space.unregister(hook));
+    }
+
+    /// report a `vsatp` switch of the running hart since the last exit
+    pub fn check_vsatp(&mut self) {
+        let vsatp = vsatp::read().bits();
+        let hooks = &mut self.vcpu.addr_space;
+        if vsatp == hooks.last_vsatp {
+            return
+        }
+        let old = hooks.last_vsatp;
+        hooks.last_vsatp = vsatp;
+        hooks.notify(self.guest_id, self.vcpu.hart, AddrSpaceEvent::Switch { old, new: vsatp });
+    }
+
+    fn notify_flush(&self, vaddr: Option<usize>, asid: Option<usize>) {
+        self.vcpu.addr_space.notify(self.guest_id, self.vcpu.hart, AddrSpaceEvent::Flush { vaddr, asid });
+    }
+}
+
+/// read the instruction of a virtual instruction exception, from `stval` or guest memory
+pub fn trapped_inst<P: PageTable, G: GuestPageTable>(host_vmm: &HostVmm<P, G>, ctx: &TrapContext) -> VmmResult<(usize, Instruction)> {
+    let mut inst = ctx.stval;
+    if inst == 0 {
+        inst = fetch_guest_inst(host_vmm.guest_id, ctx.sepc, &WalkContext::current())
+            .ok_or(VmmError::TranslationError)?;
+    }
+    match decode_inst(inst) {
+        (len, Some(inst)) => Ok((len, inst)),
+        (_, None) => Err(VmmError::DecodeInstError)
+    }
+}
+
+/// `satp` read-modify-write of the guest, returns the old value
+fn write_vsatp(value: impl FnOnce(usize) -> Option<usize>) -> usize {
+    let old = vsatp::read().bits();
+    if let Some(new) = value(old).and_then(csrcheck::vsatp) {
+        // unsupported modes are ignored, like a write to `satp`
+        unsafe{ core::arch::asm!("csrw vsatp, {}", in(reg) new) };
+    }
+    old
+}
+
+/// emulate a `satp` access or `sfence.vma` trapped by `hstatus.VTVM`
+pub fn handle_vtvm_inst<P: PageTable, G: GuestPageTable>(host_vmm: &mut HostVmm<P, G>, ctx: &mut TrapContext, len: usize, inst: Instruction) -> VmmResult {
+    let guest = host_vmm.guests.get_mut(host_vmm.guest_id).ok_or(VmmError::NoFound)?;
+    let reg = |ctx: &TrapContext, index: u32| ctx.x[index as usize];
+    let (rd, old) = match inst {
+        Instruction::SfenceVma(i) => {
+            let vaddr = if i.rs1() != 0 { Some(reg(ctx, i.rs1())) } else { None };
+            let asid = if i.rs2() != 0 { Some(reg(ctx, i.rs2())) } else { None };
+            // the guest TLB is tagged with its VMID, only this guest is flushed
+            arch::flush_guest_tlb();
+            guest.notify_flush(vaddr, asid);
+            ctx.sepc += len;
+            return Ok(())
+        },
+        Instruction::Csrrw(i) if i.csr() == CSR_SATP => {
+            let src = reg(ctx, i.rs1());
+            (i.rd(), write_vsatp(|_| Some(src)))
+        },
+        Instruction::Csrrs(i) if i.csr() == CSR_SATP => {
+            let src = reg(ctx, i.rs1());
+            (i.rd(), write_vsatp(|old| if i.rs1() != 0 { Some(old | src) } else { None }))
+        },
+        Instruction::Csrrc(i) if i.csr() == CSR_SATP => {
+            let src = reg(ctx, i.rs1());
+            (i.rd(), write_vsatp(|old| if i.rs1() != 0 { Some(old & !src) } else { None }))
+        },
+        Instruction::Csrrwi(i) if i.csr() == CSR_SATP => {
+            let imm = i.zimm() as usize;
+            (i.rd(), write_vsatp(|_| Some(imm)))
+        },
+        Instruction::Csrrsi(i) if i.csr() == CSR_SATP => {
+            let imm = i.zimm() as usize;
+            (i.rd(), write_vsatp(|old| if imm != 0 { Some(old | imm) } else { None }))
+        },
+        Instruction::Csrrci(i) if i.csr() == CSR_SATP => {
+            let imm = i.zimm() as usize;
+            (i.rd(), write_vsatp(|old| if imm != 0 { Some(old & !imm) } else { None }))
+        },
+        _ => return Err(VmmError::UnexpectedInst)
+    };
+    if rd != 0 {
+        ctx.x[rd as usize] = old;
+    }
+    guest.check_vsatp();
+    ctx.sepc += len;
+    Ok(())
+}
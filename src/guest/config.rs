@@ -0,0 +1,168 @@
+use alloc::vec::Vec;
+use crate::constants::CLOCK_FREQ;
+use crate::cmdline::parse_duration;
+use crate::constants::sched::{ DEFAULT_POLICY, DEFAULT_WEIGHT, DEFAULT_PRIORITY, RT_MAJOR_FRAME };
+use crate::sched::SchedPolicy;
+use super::isa::IsaMask;
+use super::counters::CounterMask;
+use super::envcfg::EnvCfg;
+use super::wfi::WfiPolicy;
+use super::quirks::{ GuestOs, Quirks };
+use super::loader::KernelLayout;
+
+/// ARINC 653 style time window of a real-time guest inside each major frame
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RtPartition {
+    /// window start, in cycles from the beginning of the major frame
+    pub offset: usize,
+    /// window length in cycles
+    pub length: usize
+}
+
+impl RtPartition {
+    /// the window of a guest owning the hart, the whole major frame
+    pub const DEDICATED: Self = Self { offset: 0, length: RT_MAJOR_FRAME };
+
+    /// parse `<offset>,<length>` in `ms` or `us`, or `dedicated`
+    pub fn parse(arg: &str) -> Option<Self> {
+        if arg == "dedicated" {
+            return Some(Self::DEDICATED)
+        }
+        let (offset, length) = arg.split_once(',')?;
+        let rt = Self { offset: parse_duration(offset.trim())?, length: parse_duration(length.trim())? };
+        (rt.length > 0 && rt.offset.checked_add(rt.length)? <= RT_MAJOR_FRAME).then_some(rt)
+    }
+
+    /// the guest owns the hart
+    pub fn dedicated(&self) -> bool {
+        *self == Self::DEDICATED
+    }
+}
+
+/// interrupt coalescing of an emulated virtio device: its interrupt is injected once
+/// `count` completions are held or `usecs` microseconds after the first one held,
+/// whichever comes first. Each device holds its own completions.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct IrqCoalesce {
+    pub count: usize,
+    pub usecs: usize,
+    /// interrupt line of the devices it applies to, `None` for the devices without a
+    /// setting of their own
+    pub irq: Option<usize>
+}
+
+impl IrqCoalesce {
+    /// parse `<count>,<usecs>[@<irq>]`
+    pub fn parse(arg: &str) -> Option<Self> {
+        let (arg, irq) = match arg.split_once('@') {
+            Some((arg, irq)) => (arg, Some(irq.trim().parse().ok()?)),
+            None => (arg, None)
+        };
+        let (count, usecs) = arg.split_once(',')?;
+        let coalesce = Self { count: count.trim().parse().ok()?, usecs: usecs.trim().parse().ok()?, irq };
+        (coalesce.count != 0).then_some(coalesce)
+    }
+
+    /// parse settings separated by `;`
+    pub fn parse_list(arg: &str) -> Option<Vec<Self>> {
+        arg.split(';').map(Self::parse).collect()
+    }
+
+    /// the window in cycles of the host timer
+    pub fn window(&self) -> usize {
+        self.usecs * (CLOCK_FREQ / 1_000_000)
+    }
+}
+
+/// Static configuration of a guest, decided before the guest is created.
+#[derive(Clone, Debug)]
+pub struct GuestConfig {
+    /// how the cpu time of the guest is shared with guests of the same priority, see `sched`
+    pub policy: SchedPolicy,
+    /// scheduling weight, a guest's cpu share is proportional to its weight
+    pub weight: usize,
+    /// scheduling priority, a runnable guest preempts guests of lower priority
+    pub priority: usize,
+    /// real-time guest: runs exactly inside its window and is never preempted there
+    pub rt: Option<RtPartition>,
+    /// hard cap in percent of a hart per `CAP_PERIOD`, the guest is not scheduled once
+    /// it used it up, even on an otherwise idle hart
+    pub cap: Option<usize>,
+    /// seconds added to the host wall-clock time in the guest RTC
+    pub rtc_offset: i64,
+    /// extensions hidden from the guest
+    pub hidden_isa: IsaMask,
+    /// counters read without a trap, the others are emulated, see `counters`
+    pub counters: CounterMask,
+    /// behaviors of `henvcfg` the guest may use, see `envcfg`
+    pub envcfg: EnvCfg,
+    /// number of vcpus, the guest sees hart ids `0..vcpus`
+    pub vcpus: usize,
+    /// stop guest time while the guest is paused, so its timers and watchdogs
+    /// do not all expire on resume
+    pub freeze_on_pause: bool,
+    /// trap `satp` accesses and `sfence.vma` of the guest, see `addrspace`
+    pub trap_vsatp: bool,
+    /// whether `wfi` traps and how long the hypervisor spins on it, see `wfi`
+    pub wfi: WfiPolicy,
+    /// OS of the guest, `Unknown` to detect it, see `quirks`
+    pub os: GuestOs,
+    /// quirks replacing the profile of the OS
+    pub quirks: Option<Quirks>,
+    /// the guest boots its own M-mode firmware, see `vmachine`
+    pub firmware: bool,
+    /// bytes at the start of the kernel mapped read-only from the shared kernel image,
+    /// 0 to give the guest its own copy
+    pub shared_text: usize,
+    /// enclave guest: pages leaving it are encrypted, frames taken from it are scrubbed
+    pub enclave: bool,
+    /// flush caches and TLBs when switching away from the guest, see `paranoid`
+    pub paranoid_switch: bool,
+    /// coalescing of the interrupts of emulated virtio devices by interrupt line, see
+    /// `coalesce_of`
+    pub irq_coalesce: Vec<IrqCoalesce>,
+    /// where the kernel is loaded and entered, see `loader`
+    pub kernel: KernelLayout,
+}
+
+impl GuestConfig {
+    /// priority of the I/O jobs of the guest, those of real-time guests run first
+    pub fn io_priority(&self) -> usize {
+        if self.rt.is_some() { usize::MAX } else { self.priority }
+    }
+
+    /// coalescing of the device on interrupt line `irq`, the setting for its line or else
+    /// the one for any line. `None` injects one interrupt per completion.
+    pub fn coalesce_of(&self, irq: Option<usize>) -> Option<IrqCoalesce> {
+        let own = self.irq_coalesce.iter().find(|coalesce| coalesce.irq.is_some() && coalesce.irq == irq);
+        own.or_else(|| self.irq_coalesce.iter().find(|coalesce| coalesce.irq.is_none())).copied()
+    }
+}
+
+impl Default for GuestConfig {
+    fn default() -> Self {
+        Self {
+            policy: DEFAULT_POLICY,
+            weight: DEFAULT_WEIGHT,
+            priority: DEFAULT_PRIORITY,
+            rt: None,
+            cap: None,
+            rtc_offset: 0,
+            hidden_isa: IsaMask::empty(),
+            counters: CounterMask::all(),
+            envcfg: EnvCfg::all(),
+            vcpus: 1,
+            freeze_on_pause: true,
+            trap_vsatp: false,
+            wfi: WfiPolicy::default(),
+            os: GuestOs::Unknown,
+            quirks: None,
+            firmware: false,
+            shared_text: 0,
+            enclave: false,
+            paranoid_switch: false,
+            irq_coalesce: Vec::new(),
+            kernel: KernelLayout::default()
+        }
+    }
+}
@@ -3,6 +3,8 @@ use memoffset::offset_of;
 use core::mem::size_of;
 use core::arch::global_asm;
 
+use super::envcfg;
+
 use riscv::register::{
     sstatus::{self, Sstatus, SPP },
     hstatus::{self, Hstatus }
@@ -25,10 +27,35 @@ pub struct TrapContext {
     /// Addr of trap_handler function
     pub trap_handler: usize,
     /// CSR hstatus
-    pub hstatus: Hstatus
+    pub hstatus: Hstatus,
+    /// tp of the hypervisor while the guest runs, see `percpu`
+    pub hart_tp: usize,
+    /// CSR scause of the last trap
+    pub scause: usize,
+    /// CSR stval of the last trap
+    pub stval: usize,
+    /// CSR htval of the last trap
+    pub htval: usize,
+    /// CSR htinst of the last trap
+    pub htinst: usize
 }
 
 impl TrapContext {
+    /// guest physical address of the last guest page fault
+    pub fn fault_guest_pa(&self) -> usize {
+        self.htval << 2
+    }
+
+    /// the saved `sstatus` as a plain word
+    pub fn sstatus_bits(&self) -> usize {
+        unsafe{ *(&self.sstatus as *const Sstatus as *const usize) }
+    }
+
+    /// clear `bits` of the saved `sstatus`, which the trap entry stores as a plain word
+    pub fn clear_sstatus_bits(&mut self, bits: usize) {
+        unsafe{ *(&mut self.sstatus as *mut Sstatus as *mut usize) &= !bits };
+    }
+
     /// set stack pointer to x_2 reg (sp)
     pub fn set_sp(&mut self, sp: usize) {
         self.x[2] = sp;
@@ -54,13 +81,25 @@ impl TrapContext {
             hgatp,  // addr of page table
             kernel_sp,    // kernel stack
             trap_handler, // addr of trap_handler function
-            hstatus
+            hstatus,
+            hart_tp: 0,
+            scause: 0,
+            stval: 0,
+            htval: 0,
+            htinst: 0
         };
         cx.set_sp(sp); // app's user stack pointer
         cx // return initial Trap Context of app
     }
 }
 
+/// `htimedelta` of the running guest
+pub fn read_htimedelta() -> usize {
+    let htimedelta: usize;
+    unsafe{ core::arch::asm!("csrr {}, htimedelta", out(reg) htimedelta) };
+    htimedelta
+}
+
 /// Hypervisor GPR and CSR state which must be saved/restored when entering/exiting virtualization.
 #[derive(Default)]
 #[repr(C)]
@@ -99,6 +138,66 @@ pub struct GuestVsCsrs {
     vstval: u64,
     vsatp: u64,
     vstimecmp: u64,
+    /// not banked, the guest writes the `scounteren` of the hart, see `counters`
+    scounteren: u64,
+    /// not banked either, only switched when implemented, see `envcfg`
+    senvcfg: u64,
+}
+
+impl GuestVsCsrs {
+    /// Save VS-level CSRs of the guest leaving the cpu.
+    /// `vstimecmp` is left alone because it requires Sstc.
+    pub fn save(&mut self) {
+        unsafe{
+            core::arch::asm!("csrr {}, htimedelta", out(reg) self.htimedelta);
+            core::arch::asm!("csrr {}, vsstatus", out(reg) self.vsstatus);
+            core::arch::asm!("csrr {}, vsie", out(reg) self.vsie);
+            core::arch::asm!("csrr {}, vstvec", out(reg) self.vstvec);
+            core::arch::asm!("csrr {}, vsscratch", out(reg) self.vsscratch);
+            core::arch::asm!("csrr {}, vsepc", out(reg) self.vsepc);
+            core::arch::asm!("csrr {}, vscause", out(reg) self.vscause);
+            core::arch::asm!("csrr {}, vstval", out(reg) self.vstval);
+            core::arch::asm!("csrr {}, vsatp", out(reg) self.vsatp);
+            core::arch::asm!("csrr {}, scounteren", out(reg) self.scounteren);
+            if envcfg::present() {
+                core::arch::asm!("csrr {}, senvcfg", out(reg) self.senvcfg);
+            }
+        }
+    }
+
+    /// `htimedelta` of a descheduled guest
+    pub fn htimedelta(&self) -> usize {
+        self.htimedelta as usize
+    }
+
+    /// VS-level CSRs of a hart entering the guest, sharing the time base of its siblings
+    pub fn with_htimedelta(htimedelta: usize) -> Self {
+        Self { htimedelta: htimedelta as u64, ..Self::default() }
+    }
+
+    /// shift guest time backwards by `cycles`
+    pub fn rewind_time(&mut self, cycles: usize) {
+        self.htimedelta = self.htimedelta.wrapping_sub(cycles as u64);
+    }
+
+    /// Restore VS-level CSRs of the guest entering the cpu.
+    pub fn restore(&self) {
+        unsafe{
+            core::arch::asm!("csrw htimedelta, {}", in(reg) self.htimedelta);
+            core::arch::asm!("csrw vsstatus, {}", in(reg) self.vsstatus);
+            core::arch::asm!("csrw vsie, {}", in(reg) self.vsie);
+            core::arch::asm!("csrw vstvec, {}", in(reg) self.vstvec);
+            core::arch::asm!("csrw vsscratch, {}", in(reg) self.vsscratch);
+            core::arch::asm!("csrw vsepc, {}", in(reg) self.vsepc);
+            core::arch::asm!("csrw vscause, {}", in(reg) self.vscause);
+            core::arch::asm!("csrw vstval, {}", in(reg) self.vstval);
+            core::arch::asm!("csrw vsatp, {}", in(reg) self.vsatp);
+            core::arch::asm!("csrw scounteren, {}", in(reg) self.scounteren);
+            if envcfg::present() {
+                core::arch::asm!("csrw senvcfg, {}", in(reg) self.senvcfg);
+            }
+        }
+    }
 }
 
 /// Virtualized HS-level CSRs that are used to emulate (part of) the hypervisor extension for the
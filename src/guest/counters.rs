@@ -0,0 +1,117 @@
+//! Per-guest access to the counters `cycle`, `time`, `instret` and `hpmcounter3..31`
+//!
+//! `GuestConfig::counters` lists the counters a guest reads directly, the hypervisor loads
+//! it into `hcounteren` whenever the guest is switched in. A read of any other counter
+//! raises a virtual instruction exception and is emulated: `time` is the guest time,
+//! `cycle` and `instret` the host counters, all rounded down to `TRAPPED_RESOLUTION`
+//! cycles so that a guest cannot time the hypervisor or its neighbours with them, and
+//! `hpmcounter3..31` read as 0. A trapped read from VU-mode is an illegal instruction
+//! when the guest cleared the counter in its own `scounteren`, as on real hardware.
+//!
+//! `scounteren` has no VS-level copy, a guest writes the one of the hart. It is switched
+//! with the other guest CSRs, see `GuestVsCsrs`.
+//!
+//! `hvc.counters=<list>` sets the counters of the guest, `cy`, `tm`, `ir` and `hpm`
+//! separated by commas, `all` or `none`. All of them by default.
+
+use riscv::register::time;
+use riscv_decode::Instruction;
+
+use super::context::read_htimedelta;
+use super::vmexit::{ TrapContext, inject_exception };
+use crate::arch::GuestException;
+use crate::constants::CLOCK_FREQ;
+use crate::page_table::{ Privilege, WalkContext };
+
+/// csr numbers of `cycle` and `hpmcounter31`, counter n is `CSR_CYCLE + n`
+const CSR_CYCLE: u32 = 0xc00;
+const CSR_HPMCOUNTER31: u32 = 0xc1f;
+
+/// trapped counter reads are rounded down to this many cycles (1us)
+pub const TRAPPED_RESOLUTION: usize = CLOCK_FREQ / 1_000_000;
+
+/// counters a guest reads without a trap, bit n is counter `CSR_CYCLE + n` as in `hcounteren`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CounterMask(u32);
+
+impl CounterMask {
+    pub const CY: Self = Self(1 << 0);
+    pub const TM: Self = Self(1 << 1);
+    pub const IR: Self = Self(1 << 2);
+    pub const HPM: Self = Self(!0b111);
+
+    pub const fn all() -> Self {
+        Self(u32::MAX)
+    }
+
+    pub const fn none() -> Self {
+        Self(0)
+    }
+
+    /// `all`, `none` or names among `cy`, `tm`, `ir` and `hpm` separated by commas
+    pub fn parse(arg: &str) -> Option<Self> {
+        match arg {
+            "all" => return Some(Self::all()),
+            "none" => return Some(Self::none()),
+            _ => {}
+        }
+        arg.split(',').try_fold(Self::none(), |mask, name| match name {
+            "cy" => Some(Self(mask.0 | Self::CY.0)),
+            "tm" => Some(Self(mask.0 | Self::TM.0)),
+            "ir" => Some(Self(mask.0 | Self::IR.0)),
+            "hpm" => Some(Self(mask.0 | Self::HPM.0)),
+            _ => None
+        })
+    }
+
+    pub fn bits(&self) -> u32 {
+        self.0
+    }
+}
+
+impl Default for CounterMask {
+    fn default() -> Self {
+        Self::all()
+    }
+}
+
+/// load the counters of a guest being switched in
+pub fn load(mask: CounterMask) {
+    unsafe{ core::arch::asm!("csrw hcounteren, {}", in(reg) mask.bits() as usize) };
+}
+
+/// destination register and counter of an instruction that only reads a counter
+pub fn counter_read(inst: &Instruction) -> Option<(u32, u32)> {
+    let (rd, csr) = match inst {
+        Instruction::Csrrs(i) | Instruction::Csrrc(i) if i.rs1() == 0 => (i.rd(), i.csr()),
+        Instruction::Csrrsi(i) | Instruction::Csrrci(i) if i.zimm() == 0 => (i.rd(), i.csr()),
+        _ => return None
+    };
+    (CSR_CYCLE..=CSR_HPMCOUNTER31).contains(&csr).then_some((rd, csr - CSR_CYCLE))
+}
+
+/// `scounteren` of the running guest
+fn guest_scounteren() -> usize {
+    let scounteren: usize;
+    unsafe{ core::arch::asm!("csrr {}, scounteren", out(reg) scounteren) };
+    scounteren
+}
+
+/// emulate the read of `counter` into `rd` by the instruction of `len` bytes at `sepc`
+pub fn emulate_read(ctx: &mut TrapContext, len: usize, rd: u32, counter: u32) {
+    if WalkContext::current().privilege == Privilege::User && guest_scounteren() & 1 << counter == 0 {
+        let inst = ctx.stval;
+        inject_exception(ctx, GuestException::IllegalInstruction, inst);
+        return
+    }
+    let value = match counter {
+        0 => riscv::register::cycle::read(),
+        1 => time::read().wrapping_add(read_htimedelta()),
+        2 => riscv::register::instret::read(),
+        _ => 0
+    };
+    if rd != 0 {
+        ctx.x[rd as usize] = value - value % TRAPPED_RESOLUTION.max(1);
+    }
+    ctx.sepc += len;
+}
@@ -0,0 +1,81 @@
+//! Validation of guest-influenced CSR values
+//!
+//! Every value a guest gets written into a hypervisor or VS-level CSR on its behalf goes
+//! through a validator here first: `vsatp` written through a trapped `satp` access, the
+//! timer deadline of an SBI `set_timer`, and `hvip` restored when the guest is scheduled.
+//! A validator returns the value to write, possibly clamped, or `None` to drop the write.
+//! Reserved bit patterns are dropped like the hardware ignores unsupported `satp` modes.
+//!
+//! Built with the `csr_fuzz` feature, `HYPERCALL_CSR_CHECK_FID` runs a validator on a
+//! value chosen by the guest, so a fuzzing test guest can drive them directly.
+
+use core::sync::atomic::{ AtomicUsize, Ordering };
+
+use super::pmap::is_guest_ram;
+use crate::constants::PAGE_SIZE;
+
+/// validator index of `HYPERCALL_CSR_CHECK_FID`
+pub const CHECK_VSATP: usize = 0;
+pub const CHECK_TIMER: usize = 1;
+pub const CHECK_HVIP: usize = 2;
+
+/// `satp.MODE` values
+const SATP_MODE_BARE: usize = 0;
+const SATP_MODE_SV39: usize = 8;
+const SATP_MODE_SV48: usize = 9;
+const SATP_MODE_SV57: usize = 10;
+const SATP_PPN_MASK: usize = (1 << 44) - 1;
+
+/// VSSIP, VSTIP and VSEIP, the only writable bits of `hvip` without AIA, and LCOFIP
+/// injected through `hvien`, see `pmu`
+const HVIP_MASK: usize = 1 << 2 | 1 << 6 | 1 << 10 | 1 << 13;
+
+/// a deadline further ahead than this is taken as no deadline at all, it would only
+/// wrap around once converted to host time
+const MAX_TIMER_AHEAD: usize = 1 << 62;
+
+/// writes dropped or clamped since boot
+static REJECTED: AtomicUsize = AtomicUsize::new(0);
+
+fn reject<T>(csr: &str, value: usize, result: Option<T>) -> Option<T> {
+    REJECTED.fetch_add(1, Ordering::Relaxed);
+    htracking!("guest {} write {:#x} sanitized", csr, value);
+    result
+}
+
+pub fn rejected() -> usize {
+    REJECTED.load(Ordering::Relaxed)
+}
+
+/// `vsatp`: a supported mode, and a root page table in guest ram
+pub fn vsatp(value: usize) -> Option<usize> {
+    match value >> 60 {
+        SATP_MODE_BARE if value == 0 => Some(0),
+        SATP_MODE_BARE => reject("vsatp", value, Some(0)),
+        SATP_MODE_SV39 | SATP_MODE_SV48 | SATP_MODE_SV57 => {
+            let root = (value & SATP_PPN_MASK) * PAGE_SIZE;
+            if is_guest_ram(root, PAGE_SIZE) {
+                Some(value)
+            }else{
+                reject("vsatp", value, None)
+            }
+        },
+        _ => reject("vsatp", value, None)
+    }
+}
+
+/// timer deadline in guest time, `now` is the current guest time
+pub fn timer(stime: usize, now: usize) -> usize {
+    if stime != usize::MAX && stime > now && stime - now > MAX_TIMER_AHEAD {
+        return reject("timer", stime, Some(usize::MAX)).unwrap()
+    }
+    stime
+}
+
+/// `hvip` of a vcpu
+pub fn hvip(value: usize) -> usize {
+    if value & !HVIP_MASK != 0 {
+        return reject("hvip", value, Some(value & HVIP_MASK)).unwrap()
+    }
+    value
+}
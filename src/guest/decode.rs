@@ -0,0 +1,18 @@
+//! Decoding of trapped guest instructions
+//!
+//! Works on raw encodings only, so it is also built for the host by `hosted-test`.
+
+use riscv_decode::Instruction;
+
+/// decode risc-v instruction, return (inst len, inst)
+pub fn decode_inst(inst: usize) -> (usize, Option<Instruction>) {
+    let i1 = inst as u16;
+    let len = riscv_decode::instruction_length(i1);
+    let inst = match len {
+        2 => i1 as u32,
+        4 => inst as u32,
+        // longer encodings are not used by any supported extension
+        _ => return (len, None)
+    };
+    (len, riscv_decode::decode(inst).ok())
+}
@@ -0,0 +1,122 @@
+//! Per-guest environment configuration, `henvcfg`
+//!
+//! `henvcfg` decides which optional behaviors VS-mode and VU-mode get: cache block
+//! management (CBIE, CBCFE, CBZE), Svpbmt memory types in VS-stage page tables (PBMTE)
+//! and the Sstc timer (STCE). `GuestConfig::envcfg` lists those a guest may use, the
+//! hypervisor writes them into `henvcfg` whenever the guest is switched in. Bits the
+//! hardware does not implement read back as zero and the guest sees the feature missing,
+//! as it would on that hardware.
+//!
+//! CBIE is granted as flush: `cbo.inval` of a guest writes back dirty lines like
+//! `cbo.flush`, it never discards data that a previous owner of the memory left in the
+//! cache. STCE stays clear: guest timers are emulated through SBI and `hvip.VSTIP`,
+//! which Sstc would take over.
+//!
+//! `senvcfg` has no VS-level copy, a guest writes the one of the hart. It is switched
+//! with the other guest CSRs, see `GuestVsCsrs`. Both CSRs only exist since privileged
+//! spec 1.12, on older harts nothing is written and guests get none of these behaviors.
+//!
+//! `hvc.envcfg=<list>` sets the behaviors of the guest, `cbie`, `cbcfe`, `cbze` and
+//! `pbmte` separated by commas, `all` or `none`. All of them by default, e.g.
+//! `hvc.envcfg=cbie,cbcfe,cbze` keeps an untrusted guest away from Svpbmt.
+
+use core::sync::atomic::{ AtomicBool, Ordering };
+
+use crate::detect;
+
+/// `henvcfg` and `senvcfg` are implemented
+static ENVCFG: AtomicBool = AtomicBool::new(false);
+
+const HENVCFG_CBIE_FLUSH: usize = 0b01 << 4;
+const HENVCFG_CBCFE: usize = 1 << 6;
+const HENVCFG_CBZE: usize = 1 << 7;
+const HENVCFG_PBMTE: usize = 1 << 62;
+
+/// behaviors of `henvcfg` granted to a guest
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct EnvCfg {
+    /// `cbo.inval`, executed as `cbo.flush`
+    pub cbie: bool,
+    /// `cbo.clean` and `cbo.flush`
+    pub cbcfe: bool,
+    /// `cbo.zero`
+    pub cbze: bool,
+    /// Svpbmt memory types in VS-stage page tables
+    pub pbmte: bool
+}
+
+impl EnvCfg {
+    pub const fn all() -> Self {
+        Self { cbie: true, cbcfe: true, cbze: true, pbmte: true }
+    }
+
+    pub const fn none() -> Self {
+        Self { cbie: false, cbcfe: false, cbze: false, pbmte: false }
+    }
+
+    /// `all`, `none` or names among `cbie`, `cbcfe`, `cbze` and `pbmte` separated by commas
+    pub fn parse(arg: &str) -> Option<Self> {
+        match arg {
+            "all" => return Some(Self::all()),
+            "none" => return Some(Self::none()),
+            _ => {}
+        }
+        arg.split(',').try_fold(Self::none(), |mut cfg, name| {
+            match name {
+                "cbie" => cfg.cbie = true,
+                "cbcfe" => cfg.cbcfe = true,
+                "cbze" => cfg.cbze = true,
+                "pbmte" => cfg.pbmte = true,
+                _ => return None
+            }
+            Some(cfg)
+        })
+    }
+
+    /// value of `henvcfg` while the guest runs
+    pub fn henvcfg(&self) -> usize {
+        let mut bits = 0;
+        if self.cbie {
+            bits |= HENVCFG_CBIE_FLUSH;
+        }
+        if self.cbcfe {
+            bits |= HENVCFG_CBCFE;
+        }
+        if self.cbze {
+            bits |= HENVCFG_CBZE;
+        }
+        if self.pbmte {
+            bits |= HENVCFG_PBMTE;
+        }
+        bits
+    }
+}
+
+impl Default for EnvCfg {
+    fn default() -> Self {
+        Self::all()
+    }
+}
+
+/// detect `henvcfg`, called once at boot
+pub fn init() {
+    let present = detect::detect_envcfg();
+    ENVCFG.store(present, Ordering::Relaxed);
+    if present {
+        hdebug!("henvcfg: guest environment configured per guest");
+    }else{
+        hdebug!("no henvcfg: guests get no cache block management, Svpbmt or Sstc");
+    }
+}
+
+/// `henvcfg` and `senvcfg` can be accessed
+pub fn present() -> bool {
+    ENVCFG.load(Ordering::Relaxed)
+}
+
+/// load the environment of a guest being switched in
+pub fn load(cfg: EnvCfg) {
+    if present() {
+        unsafe{ core::arch::asm!("csrw henvcfg, {}", in(reg) cfg.henvcfg()) };
+    }
+}
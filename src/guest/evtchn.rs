@@ -0,0 +1,230 @@
+//! Event channels, notifications between guests and from the hypervisor
+//!
+//! A guest first registers a page with `HYPERCALL_EVTCHN_INIT_FID`, together with the
+//! virtual interrupt it wants for upcalls. The page holds two bitmaps of `EVTCHN_PORTS`
+//! bits, one bit per port:
+//!
+//! - `EVTCHN_PENDING`: set by the sender, cleared by the guest with an atomic and
+//! - `EVTCHN_MASK`: written by the guest, a masked port becomes pending without upcall
+//!
+//! Port 0 is never allocated. A guest offers a channel to another one with
+//! `HYPERCALL_EVTCHN_ALLOC_FID`, which binds its end with `HYPERCALL_EVTCHN_BIND_FID`, the
+//! port numbers are exchanged out of band, e.g. through the device tree or a shared page.
+//! `HYPERCALL_EVTCHN_SEND_FID` sets the pending bit of the remote end and injects the
+//! upcall interrupt if the bit was clear and the port is not masked. Closing one end leaves
+//! the other one unbound, ready to be bound again.
+//!
+//! Backends in the hypervisor allocate their own channels with `evtchn_alloc_hypervisor`,
+//! signal the guest with `evtchn_notify` and find the guest's kicks with `evtchn_take_kick`.
+
+use alloc::vec::Vec;
+use core::sync::atomic::{ AtomicU32, Ordering };
+
+use super::page_table::GuestPageTable;
+use super::pmap::{ is_guest_ram, with_guest_memory };
+use crate::constants::PAGE_SIZE;
+use crate::hypervisor::HostVmm;
+use crate::page_table::PageTable;
+use crate::sbi::{ SBI_ERR_FAILUER, SBI_ERR_INAVLID_PARAM, SBI_ERR_INVALID_ADDRESS, SBI_ERR_DENIED };
+
+/// ports of a guest, port 0 included
+pub const EVTCHN_PORTS: usize = 1024;
+/// offset of the pending bitmap in the page
+pub const EVTCHN_PENDING: usize = 0;
+/// offset of the mask bitmap in the page
+pub const EVTCHN_MASK: usize = EVTCHN_PORTS / 8;
+const EVTCHN_PAGE_SIZE: usize = EVTCHN_MASK + EVTCHN_PORTS / 8;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Channel {
+    Free,
+    /// offered to guest `remote`, waiting for it to bind
+    Unbound { remote: usize },
+    /// connected to `port` of guest `remote`
+    Interdomain { remote: usize, port: usize },
+    /// connected to a backend in the hypervisor, `kicked` once the guest sent on it
+    Hypervisor { kicked: bool }
+}
+
+#[derive(Default)]
+pub struct EventChannels {
+    channels: Vec<Channel>,
+    /// guest address of the registered page
+    page: Option<usize>,
+    upcall_irq: u32
+}
+
+impl EventChannels {
+    /// register the page at `guest_pa` and the upcall interrupt, pending bits are kept
+    pub fn init(&mut self, guest_pa: usize, upcall_irq: u32) -> Result<(), isize> {
+        if guest_pa % PAGE_SIZE != 0 {
+            return Err(SBI_ERR_INVALID_ADDRESS)
+        }
+        if !is_guest_ram(guest_pa, EVTCHN_PAGE_SIZE) {
+            return Err(SBI_ERR_INVALID_ADDRESS)
+        }
+        self.page = Some(guest_pa);
+        self.upcall_irq = upcall_irq;
+        Ok(())
+    }
+
+    pub fn get(&self, port: usize) -> Option<Channel> {
+        self.channels.get(port).copied().filter(|&channel| channel != Channel::Free)
+    }
+
+    fn set(&mut self, port: usize, channel: Channel) {
+        self.channels[port] = channel;
+    }
+
+    /// the lowest free port, allocated as `channel`
+    fn alloc(&mut self, channel: Channel) -> Result<usize, isize> {
+        if self.page.is_none() {
+            return Err(SBI_ERR_DENIED)
+        }
+        if self.channels.is_empty() {
+            self.channels.push(Channel::Free);
+        }
+        let port = match self.channels.iter().skip(1).position(|&channel| channel == Channel::Free) {
+            Some(index) => index + 1,
+            None if self.channels.len() < EVTCHN_PORTS => {
+                self.channels.push(Channel::Free);
+                self.channels.len() - 1
+            },
+            None => return Err(SBI_ERR_FAILUER)
+        };
+        self.channels[port] = channel;
+        Ok(port)
+    }
+
+    /// set `port` of guest `guest_id` pending, returns true if the guest takes an upcall
+    /// for it
+    fn set_pending(&self, guest_id: usize, port: usize) -> bool {
+        let page = match self.page {
+            Some(page) => page,
+            None => return false
+        };
+        let (index, bit) = (port / 32, 1 << (port % 32));
+        with_guest_memory(guest_id, page, EVTCHN_PAGE_SIZE, |bytes| {
+            let words = unsafe{ core::slice::from_raw_parts(bytes.as_ptr() as *const AtomicU32, EVTCHN_PAGE_SIZE / 4) };
+            let old = words[EVTCHN_PENDING / 4 + index].fetch_or(bit, Ordering::AcqRel);
+            old & bit == 0 && words[EVTCHN_MASK / 4 + index].load(Ordering::Acquire) & bit == 0
+        }).unwrap_or(false)
+    }
+
+    /// forget the page and all channels, the peers have to be closed by the caller
+    fn clear(&mut self) -> Vec<(usize, Channel)> {
+        self.page = None;
+        self.channels.drain(..).enumerate()
+            .filter(|&(_, channel)| channel != Channel::Free)
+            .collect()
+    }
+}
+
+impl<P: PageTable, G: GuestPageTable> HostVmm<P, G> {
+    fn evtchn(&mut self, guest_id: usize) -> Result<&mut EventChannels, isize> {
+        self.guests.get_mut(guest_id).map(|guest| &mut guest.evtchn).ok_or(SBI_ERR_INAVLID_PARAM)
+    }
+
+    /// offer a channel of `guest_id` to guest `remote`, returns the local port
+    pub fn evtchn_alloc(&mut self, guest_id: usize, remote: usize) -> Result<usize, isize> {
+        if !self.guests.contains(remote) {
+            return Err(SBI_ERR_INAVLID_PARAM)
+        }
+        self.evtchn(guest_id)?.alloc(Channel::Unbound { remote })
+    }
+
+    /// bind a channel of `guest_id` to `remote_port` of `remote`, offered to `guest_id`
+    pub fn evtchn_bind(&mut self, guest_id: usize, remote: usize, remote_port: usize) -> Result<usize, isize> {
+        if self.evtchn(remote)?.get(remote_port) != Some(Channel::Unbound { remote: guest_id }) {
+            return Err(SBI_ERR_DENIED)
+        }
+        let port = self.evtchn(guest_id)?.alloc(Channel::Interdomain { remote, port: remote_port })?;
+        self.evtchn(remote)?.set(remote_port, Channel::Interdomain { remote: guest_id, port });
+        Ok(port)
+    }
+
+    /// a channel of `guest_id` signalled by a backend in the hypervisor
+    pub fn evtchn_alloc_hypervisor(&mut self, guest_id: usize) -> Result<usize, isize> {
+        self.evtchn(guest_id)?.alloc(Channel::Hypervisor { kicked: false })
+    }
+
+    /// close `port` of `guest_id`, the other end of the channel becomes unbound
+    pub fn evtchn_close(&mut self, guest_id: usize, port: usize) -> Result<(), isize> {
+        let channel = self.evtchn(guest_id)?.get(port).ok_or(SBI_ERR_INAVLID_PARAM)?;
+        self.evtchn(guest_id)?.set(port, Channel::Free);
+        self.evtchn_unbind_peer(guest_id, channel);
+        Ok(())
+    }
+
+    fn evtchn_unbind_peer(&mut self, guest_id: usize, channel: Channel) {
+        if let Channel::Interdomain { remote, port } = channel {
+            if let Ok(evtchn) = self.evtchn(remote) {
+                evtchn.set(port, Channel::Unbound { remote: guest_id });
+            }
+        }
+    }
+
+    /// close all channels of a guest being reset, its peers keep their end for the
+    /// rebooted guest to bind again
+    pub fn evtchn_close_all(&mut self, guest_id: usize) {
+        let channels = match self.evtchn(guest_id) {
+            Ok(evtchn) => evtchn.clear(),
+            Err(_) => return
+        };
+        for (_, channel) in channels {
+            self.evtchn_unbind_peer(guest_id, channel);
+        }
+    }
+
+    /// close all channels of a guest being killed, the offers to it are withdrawn since
+    /// its id is reused
+    pub fn evtchn_release_guest(&mut self, guest_id: usize) {
+        self.evtchn_close_all(guest_id);
+        for guest in self.guests.iter_mut() {
+            for channel in guest.evtchn.channels.iter_mut() {
+                if *channel == (Channel::Unbound { remote: guest_id }) {
+                    *channel = Channel::Free;
+                }
+            }
+        }
+    }
+
+    /// send on `port` of `guest_id`
+    pub fn evtchn_send(&mut self, guest_id: usize, port: usize) -> Result<(), isize> {
+        match self.evtchn(guest_id)?.get(port).ok_or(SBI_ERR_INAVLID_PARAM)? {
+            Channel::Interdomain { remote, port } => {
+                self.evtchn_notify(remote, port);
+                Ok(())
+            },
+            Channel::Hypervisor { .. } => {
+                self.evtchn(guest_id)?.set(port, Channel::Hypervisor { kicked: true });
+                Ok(())
+            },
+            // nobody listens yet
+            Channel::Unbound { .. } => Ok(()),
+            Channel::Free => Err(SBI_ERR_INAVLID_PARAM)
+        }
+    }
+
+    /// set `port` of `guest_id` pending and inject its upcall interrupt if needed
+    pub fn evtchn_notify(&mut self, guest_id: usize, port: usize) {
+        let upcall_irq = match self.evtchn(guest_id) {
+            Ok(evtchn) if evtchn.set_pending(guest_id, port) => evtchn.upcall_irq,
+            _ => return
+        };
+        self.inject_guest_irq(guest_id, upcall_irq);
+    }
+
+    /// the guest sent on the hypervisor channel `port` since the last call
+    pub fn evtchn_take_kick(&mut self, guest_id: usize, port: usize) -> bool {
+        let evtchn = match self.evtchn(guest_id) {
+            Ok(evtchn) => evtchn,
+            Err(_) => return false
+        };
+        if evtchn.get(port) != Some(Channel::Hypervisor { kicked: true }) {
+            return false
+        }
+        evtchn.set(port, Channel::Hypervisor { kicked: false });
+        true
+    }
+}
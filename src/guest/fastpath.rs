@@ -0,0 +1,148 @@
+//! Lock-free fast path for hot SBI calls
+//!
+//! Console output and set_timer only touch the state of the running vcpu, so they are
+//! handled in `trap_handler` before `HOST_VMM` is locked. While a vcpu is on the cpu
+//! its timer deadline lives here instead of in `VCpu::vtimecmp`, and the scheduler
+//! mirrors the end of the current slice whenever it reprograms the timer. This state
+//! belongs to the hart, it is kept in hart-local variables.
+
+use riscv::register::time;
+
+use super::context::read_htimedelta;
+use super::csrcheck;
+use super::sbi::{ SbiRet, sbi_dbcn_write };
+use super::vmexit::TrapContext;
+use crate::arch::{ self, Irq };
+use crate::console;
+use crate::constants::riscv_regs::GprIndex;
+use crate::sbi::leagcy::{ SBI_SET_TIMER, SBI_SHUTDOWN };
+use crate::sbi::{
+    set_timer, SBI_CONSOLE_PUTCHAR, SBI_EXTID_TIME, SBI_SET_TIMER_FID, SBI_SUCCESS,
+    SBI_EXTID_DBCN, SBI_DBCN_WRITE_FID, SBI_DBCN_WRITE_BYTE_FID
+};
+
+per_cpu! {
+    /// timer deadline of the running vcpu in guest time, `usize::MAX` if no timer is armed
+    static VTIMECMP: usize = usize::MAX;
+    /// end of the current slice in host time
+    static SLICE_DEADLINE: usize = usize::MAX;
+    /// id of the running guest
+    static CURRENT_GUEST: usize = 0;
+    /// legacy SBI calls of the running guest are served here, see `quirks`
+    static LEGACY_FAST: bool = true;
+    /// SBI calls of the running guest go to its own firmware, see `vmachine`
+    static FIRMWARE: bool = false;
+    /// exits handled by the fast path, charged to the running vcpu once the lock is taken
+    static EXITS: usize = 0;
+}
+
+pub fn vtimecmp() -> usize {
+    VTIMECMP.get()
+}
+
+pub fn set_vtimecmp(vtimecmp: usize) {
+    VTIMECMP.set(vtimecmp);
+}
+
+pub fn current_guest() -> usize {
+    CURRENT_GUEST.get()
+}
+
+pub fn set_current_guest(guest_id: usize) {
+    CURRENT_GUEST.set(guest_id);
+}
+
+pub fn set_legacy_fast(enable: bool) {
+    LEGACY_FAST.set(enable);
+}
+
+pub fn set_guest_firmware(firmware: bool) {
+    FIRMWARE.set(firmware);
+}
+
+pub fn slice_deadline() -> usize {
+    SLICE_DEADLINE.get()
+}
+
+pub fn set_slice_deadline(deadline: usize) {
+    SLICE_DEADLINE.set(deadline);
+}
+
+pub fn take_exits() -> usize {
+    let exits = EXITS.get();
+    EXITS.set(0);
+    exits
+}
+
+/// earlier of the guest timer and the slice end in host time
+pub fn timer_deadline() -> usize {
+    let vtimecmp = vtimecmp();
+    // guest time = host time + htimedelta
+    let vtimecmp = if vtimecmp == usize::MAX { vtimecmp } else { vtimecmp.wrapping_sub(read_htimedelta()) };
+    vtimecmp.min(slice_deadline())
+}
+
+/// program the physical timer with the earlier of the guest timer and the slice end
+pub fn program_timer() {
+    let deadline = timer_deadline();
+    // the next sample while the profiler runs in the hypervisor
+    #[cfg(feature = "profiler")]
+    let deadline = deadline.min(crate::profiler::next_sample());
+    if deadline == usize::MAX {
+        arch::mask_irq(Irq::Timer);
+    }else{
+        set_timer(deadline);
+        arch::unmask_irq(Irq::Timer);
+    }
+}
+
+/// running guest programs its timer, `stime` is in guest time
+pub fn set_guest_timer(stime: usize) {
+    set_vtimecmp(csrcheck::timer(stime, time::read().wrapping_add(read_htimedelta())));
+    // clear guest timer interrupt pending
+    arch::lower_guest_irq(Irq::Timer);
+    program_timer();
+}
+
+/// handle a VS-mode ecall without locking `HOST_VMM`, return false if it needs the slow path
+pub fn try_handle_sbi(ctx: &mut TrapContext) -> bool {
+    if FIRMWARE.get() {
+        return false
+    }
+    let ext_id = ctx.x[GprIndex::A7 as usize];
+    let fid = ctx.x[GprIndex::A6 as usize];
+    let arg0 = ctx.x[GprIndex::A0 as usize];
+    let ok = SbiRet { error: SBI_SUCCESS, value: 0 };
+    let ret = match ext_id {
+        SBI_CONSOLE_PUTCHAR if LEGACY_FAST.get() => {
+            console::guest_putchar(current_guest(), arg0 as u8);
+            ok
+        },
+        SBI_SET_TIMER if LEGACY_FAST.get() => {
+            set_guest_timer(arg0);
+            ok
+        },
+        SBI_EXTID_TIME if fid == SBI_SET_TIMER_FID => {
+            set_guest_timer(arg0);
+            ok
+        },
+        SBI_EXTID_DBCN if fid == SBI_DBCN_WRITE_BYTE_FID => {
+            console::guest_putchar(current_guest(), arg0 as u8);
+            ok
+        },
+        SBI_EXTID_DBCN if fid == SBI_DBCN_WRITE_FID => {
+            sbi_dbcn_write(current_guest(), arg0, ctx.x[GprIndex::A1 as usize], ctx.x[GprIndex::A2 as usize])
+        },
+        _ => return false
+    };
+    if ext_id <= SBI_SHUTDOWN {
+        // legacy calls return a single value in a0, as in `sbi_vs_handler`
+        ctx.x[GprIndex::A0 as usize] = if ret.error != SBI_SUCCESS { ret.error } else { ret.value };
+    }else{
+        ctx.x[GprIndex::A0 as usize] = ret.error;
+        ctx.x[GprIndex::A1 as usize] = ret.value;
+    }
+    ctx.sepc += 4;
+    EXITS.set(EXITS.get() + 1);
+    true
+}
@@ -0,0 +1,187 @@
+//! Floating point and vector state of a guest hart
+//!
+//! The registers are saved lazily, following `sstatus.FS` and `sstatus.VS` of the guest
+//! saved by the trap entry: they are only stored when the guest leaves the cpu with the
+//! state dirty, which is then marked clean. A hart entering the cpu gets its own state
+//! loaded whenever the state is not off, zeroes if it never saved any, so no guest sees
+//! the registers of another. The vector state covers `v0..v31`, `vstart`, `vl`, `vtype`
+//! and `vcsr`, its buffer is allocated on the first save.
+//!
+//! A guest without F and D, or without V, in its virtual `misa` has the state kept off,
+//! its instructions raise illegal instruction exceptions forwarded to the guest, see `isa`.
+
+use alloc::vec::Vec;
+use core::sync::atomic::{ AtomicUsize, Ordering };
+
+use super::vmexit::TrapContext;
+use crate::detect;
+
+/// `sstatus.FS` and `sstatus.VS`, off is 0, dirty is all ones, the low bit cleared is clean
+const SSTATUS_VS: usize = 0b11 << 9;
+const SSTATUS_VS_LOW: usize = 0b01 << 9;
+const SSTATUS_FS: usize = 0b11 << 13;
+const SSTATUS_FS_LOW: usize = 0b01 << 13;
+
+/// bytes of a vector register, 0 without V
+static VLENB: AtomicUsize = AtomicUsize::new(0);
+
+/// detect V and the vector register length, called once at boot
+pub fn init() {
+    let vlenb = detect::detect_vlenb();
+    VLENB.store(vlenb, Ordering::Relaxed);
+    if vlenb != 0 {
+        hdebug!("V: {} bit vector registers, saved per guest hart", vlenb * 8);
+    }
+}
+
+#[derive(Default)]
+struct VectorState {
+    vstart: usize,
+    vl: usize,
+    vtype: usize,
+    vcsr: usize,
+    /// `v0..v31`, empty until the first save
+    regs: Vec<u8>
+}
+
+/// FP and vector registers of a guest hart that is not on the cpu
+#[derive(Default)]
+pub struct FpuState {
+    f: [u64; 32],
+    fcsr: usize,
+    vector: VectorState
+}
+
+impl FpuState {
+    /// store the registers the guest dirtied, `ctx` is the trap context saved for it
+    pub fn save(&mut self, ctx: &mut TrapContext) {
+        let sstatus = ctx.sstatus_bits();
+        if sstatus & SSTATUS_FS == SSTATUS_FS {
+            unsafe{ self.save_fp() };
+            ctx.clear_sstatus_bits(SSTATUS_FS_LOW);
+        }
+        let vlenb = VLENB.load(Ordering::Relaxed);
+        if vlenb != 0 && sstatus & SSTATUS_VS == SSTATUS_VS {
+            self.vector.regs.resize(32 * vlenb, 0);
+            unsafe{ self.save_vector(vlenb) };
+            ctx.clear_sstatus_bits(SSTATUS_VS_LOW);
+        }
+    }
+
+    /// load the registers of a guest entering the cpu with the trap context `ctx`
+    pub fn restore(&self, ctx: &TrapContext) {
+        let sstatus = ctx.sstatus_bits();
+        if sstatus & SSTATUS_FS != 0 {
+            unsafe{ self.restore_fp() };
+        }
+        let vlenb = VLENB.load(Ordering::Relaxed);
+        if vlenb != 0 && sstatus & SSTATUS_VS != 0 {
+            unsafe{ self.restore_vector(vlenb) };
+        }
+    }
+
+    unsafe fn save_fp(&mut self) {
+        core::arch::asm!(
+            "csrs sstatus, {fs}",
+            ".irp n, 0,1,2,3,4,5,6,7,8,9,10,11,12,13,14,15,16,17,18,19,20,21,22,23,24,25,26,27,28,29,30,31",
+            "fsd f\\n, \\n*8({f})",
+            ".endr",
+            "frcsr {fcsr}",
+            fs = in(reg) SSTATUS_FS,
+            f = in(reg) self.f.as_mut_ptr(),
+            fcsr = out(reg) self.fcsr
+        );
+    }
+
+    unsafe fn restore_fp(&self) {
+        // the state of the hypervisor may be off, the trap return loads the one of the guest
+        core::arch::asm!(
+            "csrs sstatus, {fs}",
+            ".irp n, 0,1,2,3,4,5,6,7,8,9,10,11,12,13,14,15,16,17,18,19,20,21,22,23,24,25,26,27,28,29,30,31",
+            "fld f\\n, \\n*8({f})",
+            ".endr",
+            "fscsr {fcsr}",
+            fs = in(reg) SSTATUS_FS,
+            f = in(reg) self.f.as_ptr(),
+            fcsr = in(reg) self.fcsr
+        );
+    }
+
+    unsafe fn save_vector(&mut self, vlenb: usize) {
+        let vector = &mut self.vector;
+        // whole register stores ignore `vl` and `vtype`
+        core::arch::asm!(
+            ".option push",
+            ".option arch, +v",
+            "csrs sstatus, {vs}",
+            "csrr {vstart}, vstart",
+            "csrr {vl}, vl",
+            "csrr {vtype}, vtype",
+            "csrr {vcsr}, vcsr",
+            "vs8r.v v0, ({regs})",
+            "add {regs}, {regs}, {step}",
+            "vs8r.v v8, ({regs})",
+            "add {regs}, {regs}, {step}",
+            "vs8r.v v16, ({regs})",
+            "add {regs}, {regs}, {step}",
+            "vs8r.v v24, ({regs})",
+            ".option pop",
+            vs = in(reg) SSTATUS_VS,
+            vstart = out(reg) vector.vstart,
+            vl = out(reg) vector.vl,
+            vtype = out(reg) vector.vtype,
+            vcsr = out(reg) vector.vcsr,
+            regs = inout(reg) vector.regs.as_mut_ptr() => _,
+            step = in(reg) 8 * vlenb
+        );
+    }
+
+    unsafe fn restore_vector(&self, vlenb: usize) {
+        let vector = &self.vector;
+        if vector.regs.is_empty() {
+            core::arch::asm!(
+                ".option push",
+                ".option arch, +v",
+                "csrs sstatus, {vs}",
+                "vsetvli {tmp}, x0, e8, m8, ta, ma",
+                "vmv.v.i v0, 0",
+                "vmv.v.i v8, 0",
+                "vmv.v.i v16, 0",
+                "vmv.v.i v24, 0",
+                ".option pop",
+                vs = in(reg) SSTATUS_VS,
+                tmp = out(reg) _
+            );
+        }else{
+            core::arch::asm!(
+                ".option push",
+                ".option arch, +v",
+                "csrs sstatus, {vs}",
+                "vl8r.v v0, ({regs})",
+                "add {regs}, {regs}, {step}",
+                "vl8r.v v8, ({regs})",
+                "add {regs}, {regs}, {step}",
+                "vl8r.v v16, ({regs})",
+                "add {regs}, {regs}, {step}",
+                "vl8r.v v24, ({regs})",
+                ".option pop",
+                vs = in(reg) SSTATUS_VS,
+                regs = inout(reg) vector.regs.as_ptr() => _,
+                step = in(reg) 8 * vlenb
+            );
+        }
+        // `vsetvl` comes first, it clears `vstart`
+        core::arch::asm!(
+            ".option push",
+            ".option arch, +v",
+            "vsetvl x0, {vl}, {vtype}",
+            "csrw vstart, {vstart}",
+            "csrw vcsr, {vcsr}",
+            ".option pop",
+            vl = in(reg) vector.vl,
+            vtype = in(reg) vector.vtype,
+            vstart = in(reg) vector.vstart,
+            vcsr = in(reg) vector.vcsr
+        );
+    }
+}
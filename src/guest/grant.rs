@@ -0,0 +1,195 @@
+//! Grant tables, pages a guest explicitly shares with another guest or the hypervisor
+//!
+//! The granting guest enters one of its ram pages in its table with
+//! `HYPERCALL_GRANT_FID`, naming the grantee, a guest id or `GRANT_HYPERVISOR` for a
+//! backend in the hypervisor, and gets back a grant reference. The grantee guest maps the
+//! page into a hole of its guest physical space with `HYPERCALL_GRANT_MAP_FID`, a linear
+//! stage-2 mapping of the granter's frame, and drops it with `HYPERCALL_GRANT_UNMAP_FID`.
+//! A backend reaches the page through `with_grant_page`, checked on every use and mapped
+//! into the host for the time of the call, it keeps no mapping.
+//!
+//! `HYPERCALL_GRANT_REVOKE_FID` removes the grant, a page still mapped by the grantee is
+//! unmapped from it first. Every hart flushes its stage-2 TLB before its next guest entry,
+//! see `vmexit::request_stage2_flush`, the granter reuses the page only after that. Grants
+//! of a guest being reset or killed are revoked, and the mappings it held are forgotten
+//! with its stage-2 table.
+
+use alloc::vec::Vec;
+
+use super::page_table::GuestPageTable;
+use super::pmap::{ is_guest_ram, with_guest_memory };
+use super::vmexit::request_stage2_flush;
+use crate::arch;
+use crate::constants::PAGE_SIZE;
+use crate::hypervisor::HostVmm;
+use crate::ioservice;
+use crate::mm::{ MapArea, MapPermission, MapType, MemorySet };
+use crate::page_table::{ AccessType, PageTable, VirtAddr };
+use crate::sbi::{ SBI_ERR_FAILUER, SBI_ERR_INAVLID_PARAM, SBI_ERR_INVALID_ADDRESS, SBI_ERR_DENIED, SBI_ERR_ALREADY_AVAILABLE };
+
+/// grant references per guest
+pub const GRANT_REFS: usize = 256;
+/// grantee of a page shared with a backend in the hypervisor
+pub const GRANT_HYPERVISOR: usize = usize::MAX;
+/// flag of `HYPERCALL_GRANT_FID`, the grantee only reads the page
+pub const GRANT_READONLY: usize = 1 << 0;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Grant {
+    /// page of the granter
+    pub guest_pa: usize,
+    /// guest id, or `GRANT_HYPERVISOR`
+    pub grantee: usize,
+    pub writable: bool,
+    /// guest physical address of the page in the grantee
+    pub mapped_at: Option<usize>
+}
+
+/// pages granted by a guest, indexed by grant reference
+#[derive(Default)]
+pub struct GrantTable {
+    grants: Vec<Option<Grant>>
+}
+
+impl GrantTable {
+    pub fn get(&self, grant_ref: usize) -> Option<Grant> {
+        self.grants.get(grant_ref).copied().flatten()
+    }
+
+    fn insert(&mut self, grant: Grant) -> Result<usize, isize> {
+        if let Some(grant_ref) = self.grants.iter().position(Option::is_none) {
+            self.grants[grant_ref] = Some(grant);
+            return Ok(grant_ref)
+        }
+        if self.grants.len() == GRANT_REFS {
+            return Err(SBI_ERR_FAILUER)
+        }
+        self.grants.push(Some(grant));
+        Ok(self.grants.len() - 1)
+    }
+}
+
+impl<P: PageTable, G: GuestPageTable> HostVmm<P, G> {
+    /// grant the ram page at `guest_pa` of `guest_id` to `grantee`, returns the reference
+    pub fn grant(&mut self, guest_id: usize, guest_pa: usize, grantee: usize, flags: usize) -> Result<usize, isize> {
+        if guest_pa % PAGE_SIZE != 0 || !is_guest_ram(guest_pa, PAGE_SIZE) {
+            return Err(SBI_ERR_INVALID_ADDRESS)
+        }
+        if grantee != GRANT_HYPERVISOR && (grantee == guest_id || !self.guests.contains(grantee)) {
+            return Err(SBI_ERR_INAVLID_PARAM)
+        }
+        let guest = self.guests.get_mut(guest_id).ok_or(SBI_ERR_INAVLID_PARAM)?;
+        guest.grants.insert(Grant { guest_pa, grantee, writable: flags & GRANT_READONLY == 0, mapped_at: None })
+    }
+
+    /// map the page of `granter` granted to `guest_id` at `guest_pa` of `guest_id`
+    pub fn grant_map(&mut self, guest_id: usize, granter: usize, grant_ref: usize, guest_pa: usize) -> Result<(), isize> {
+        let source = self.guests.get(granter).ok_or(SBI_ERR_INAVLID_PARAM)?;
+        let grant = source.grants.get(grant_ref).filter(|grant| grant.grantee == guest_id).ok_or(SBI_ERR_DENIED)?;
+        if grant.mapped_at.is_some() {
+            return Err(SBI_ERR_ALREADY_AVAILABLE)
+        }
+        let host_pa = source.gpm.translate_guest_pa(grant.guest_pa, AccessType::Read).ok_or(SBI_ERR_FAILUER)?;
+        let guest = self.guests.get_mut(guest_id).ok_or(SBI_ERR_INAVLID_PARAM)?;
+        let vpn = VirtAddr::from(guest_pa).floor();
+        // only into a hole of the guest physical space
+        if guest_pa % PAGE_SIZE != 0 || guest_pa >> 41 != 0 || guest.gpm.areas.iter().any(|area| area.contains(vpn)) {
+            return Err(SBI_ERR_INVALID_ADDRESS)
+        }
+        let mut perm = MapPermission::R | MapPermission::U;
+        if grant.writable {
+            perm |= MapPermission::W;
+        }
+        guest.gpm.try_push(MapArea::new(
+            guest_pa.into(),
+            (guest_pa + PAGE_SIZE).into(),
+            Some(host_pa.into()),
+            Some((host_pa + PAGE_SIZE).into()),
+            MapType::Linear,
+            perm
+        ), None).map_err(|_| SBI_ERR_FAILUER)?;
+        let source = self.guests.get_mut(granter).unwrap();
+        source.grants.grants[grant_ref].as_mut().unwrap().mapped_at = Some(guest_pa);
+        Ok(())
+    }
+
+    /// unmap the page of `granter` mapped by `guest_id`
+    pub fn grant_unmap(&mut self, guest_id: usize, granter: usize, grant_ref: usize) -> Result<(), isize> {
+        let grant = self.guests.get(granter)
+            .and_then(|source| source.grants.get(grant_ref))
+            .filter(|grant| grant.grantee == guest_id)
+            .ok_or(SBI_ERR_DENIED)?;
+        if grant.mapped_at.is_none() {
+            return Err(SBI_ERR_INAVLID_PARAM)
+        }
+        self.grant_unmap_from_grantee(granter, grant_ref);
+        Ok(())
+    }
+
+    fn grant_unmap_from_grantee(&mut self, granter: usize, grant_ref: usize) {
+        let grant = match self.guests.get_mut(granter).and_then(|source| source.grants.grants[grant_ref].as_mut()) {
+            Some(grant) => grant,
+            None => return
+        };
+        let (grantee, mapped_at) = match grant.mapped_at.take() {
+            Some(mapped_at) => (grant.grantee, mapped_at),
+            None => return
+        };
+        // a job of the grantee may be working on the page
+        ioservice::quiesce(grantee);
+        if let Some(guest) = self.guests.get_mut(grantee) {
+            guest.gpm.remove_area(mapped_at.into());
+        }
+        if grantee == self.guest_id {
+            arch::flush_stage2_tlb();
+        }
+        request_stage2_flush();
+    }
+
+    /// revoke a grant of `guest_id`, unmapping the page from the grantee
+    pub fn grant_revoke(&mut self, guest_id: usize, grant_ref: usize) -> Result<(), isize> {
+        let guest = self.guests.get(guest_id).ok_or(SBI_ERR_INAVLID_PARAM)?;
+        guest.grants.get(grant_ref).ok_or(SBI_ERR_INAVLID_PARAM)?;
+        self.grant_unmap_from_grantee(guest_id, grant_ref);
+        self.guests.get_mut(guest_id).unwrap().grants.grants[grant_ref] = None;
+        Ok(())
+    }
+
+    /// run `f` on the page of `granter` granted to the hypervisor, for a backend
+    pub fn with_grant_page<R>(&self, granter: usize, grant_ref: usize, write: bool, f: impl FnOnce(&mut [u8]) -> R) -> Option<R> {
+        let grant = self.guests.get(granter)?.grants.get(grant_ref)?;
+        if grant.grantee != GRANT_HYPERVISOR || (write && !grant.writable) {
+            return None
+        }
+        with_guest_memory(granter, grant.guest_pa, PAGE_SIZE, f)
+    }
+
+    /// revoke the grants of a guest being reset and forget the pages it mapped, its stage-2
+    /// table is rebuilt by the caller. Pages granted to it stay granted.
+    pub fn grant_reset_guest(&mut self, guest_id: usize) {
+        let refs = self.guests.get(guest_id).map_or(0, |guest| guest.grants.grants.len());
+        for grant_ref in 0..refs {
+            let _ = self.grant_revoke(guest_id, grant_ref);
+        }
+        for guest in self.guests.iter_mut() {
+            for grant in guest.grants.grants.iter_mut().flatten() {
+                if grant.grantee == guest_id {
+                    grant.mapped_at = None;
+                }
+            }
+        }
+    }
+
+    /// like `grant_reset_guest` for a guest being killed, the pages granted to it are
+    /// withdrawn since its id is reused
+    pub fn grant_release_guest(&mut self, guest_id: usize) {
+        self.grant_reset_guest(guest_id);
+        for guest in self.guests.iter_mut() {
+            for grant in guest.grants.grants.iter_mut() {
+                if grant.map_or(false, |grant| grant.grantee == guest_id) {
+                    *grant = None;
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,278 @@
+//! Virtual harts of SMP guests
+//!
+//! Virtual hart ids start at 0 in every guest, whatever physical hart the guest runs on.
+//! Hart 0 boots the guest, the others are started by the guest through SBI HSM. The
+//! harts of a guest share its slices: only one of them is on the cpu at a time, and its
+//! registers live in `Guest::trap_ctx`, `Guest::vs_csrs` and `Guest::fpu` like those of a
+//! single-hart guest. IPIs and remote fences between harts are emulated, external interrupts are
+//! taken by hart 0 which owns the guest's PLIC context.
+//!
+//! A hart spinning on a lock held by a hart off the cpu spins until its slice ends.
+//! `HYPERCALL_YIELD_FID` gives the rest of the slice to the other harts of the guest, or
+//! to the other guests if the hart is alone, `HYPERCALL_YIELD_TO_FID` hands it to one
+//! hart, e.g. the lock holder. A retentive `hart_suspend` yields like `HYPERCALL_YIELD_FID`
+//! and resumes once the hart is back on the cpu. The switch happens at the end of the
+//! trap, the yielding hart gets the result of its call first. There is no pause-loop
+//! exiting: `pause` of Zihintpause is a hint that never traps, spin loops of the guest call
+//! the hypercalls themselves.
+
+use core::mem;
+use riscv::register::time;
+
+use super::{ isa, Guest };
+use super::context::{ read_htimedelta, GuestVsCsrs };
+use super::page_table::GuestPageTable;
+use super::vcpu::{ HartState, VHart };
+use super::vmexit::TrapContext;
+use crate::arch::{ self, Irq };
+use crate::constants::layout::TRAP_CONTEXT;
+use crate::constants::sched::{ VCPU_SLICE, VCPU_MIN_RUN };
+use crate::hypervisor::HostVmm;
+use crate::page_table::PageTable;
+use crate::sbi::{ SBI_SUCCESS, SBI_ERR_FAILUER, SBI_ERR_INAVLID_PARAM, SBI_ERR_ALREADY_AVAILABLE };
+
+/// `hart` is selected by an SBI hart mask, `hart_mask_base == usize::MAX` selects all harts
+fn hart_selected(hart: usize, hart_mask: usize, hart_mask_base: usize) -> bool {
+    if hart_mask_base == usize::MAX {
+        return true
+    }
+    hart >= hart_mask_base && hart - hart_mask_base < usize::BITS as usize
+        && hart_mask >> (hart - hart_mask_base) & 1 != 0
+}
+
+impl<G: GuestPageTable> Guest<G> {
+    /// swap the registers of the running hart with those saved for `hart`
+    fn exchange(&mut self, hart: usize) {
+        let saved = &mut self.harts[hart];
+        mem::swap(&mut self.trap_ctx, &mut saved.trap_ctx);
+        mem::swap(&mut self.vs_csrs, &mut saved.vs_csrs);
+        mem::swap(&mut self.fpu, &mut saved.fpu);
+        mem::swap(&mut self.triggers, &mut saved.triggers);
+        mem::swap(&mut self.vcpu.vtimecmp, &mut saved.vtimecmp);
+        mem::swap(&mut self.vcpu.hvip, &mut saved.hvip);
+        mem::swap(&mut self.vcpu.addr_space, &mut saved.addr_space);
+        mem::swap(&mut self.vcpu.stats, &mut saved.stats);
+    }
+
+    /// put `next` on the cpu in place of the running hart, the guest is on the cpu. A hart
+    /// yielded to gets the rest of the slice of the yielding one, at least `VCPU_MIN_RUN`.
+    pub fn switch_hart(&mut self, ctx: &mut TrapContext, next: usize) {
+        let prev = self.vcpu.hart;
+        let donated = self.vcpu.yield_to.take().filter(|&hart| hart == next).map(|_| self.vcpu.slice_start);
+        let now = time::read();
+        // the time since the last switch is the one of the leaving hart
+        self.vcpu.stats.run_time += now.saturating_sub(self.vcpu.last_switch);
+        self.vcpu.last_switch = now;
+        self.save_state(ctx);
+        self.exchange(prev);
+        self.exchange(next);
+        self.vcpu.hart = next;
+        self.vcpu.slice_start = match donated {
+            Some(slice_start) => slice_start.max((now + VCPU_MIN_RUN).saturating_sub(VCPU_SLICE)),
+            None => now
+        };
+        self.restore_state(ctx);
+        // the harts of a guest share its VMID
+        arch::flush_guest_tlb();
+    }
+
+    /// started harts other than the running one, in round-robin order
+    fn other_harts(&self) -> impl Iterator<Item = usize> + Clone + '_ {
+        let count = self.harts.len();
+        let current = self.vcpu.hart;
+        (1..count).map(move |i| (current + i) % count)
+            .filter(move |&hart| self.harts[hart].state == HartState::Started)
+    }
+
+    /// hart that should replace the running one at host time `now`, the guest is on the cpu.
+    /// A hart yielded to goes first, then one with a pending interrupt or an expired timer.
+    pub fn next_hart(&self, now: usize) -> Option<usize> {
+        if let Some(hart) = self.vcpu.yield_to.filter(|&hart| self.harts[hart].state == HartState::Started) {
+            return Some(hart)
+        }
+        let guest_now = now.wrapping_add(read_htimedelta());
+        let mut others = self.other_harts();
+        let with_work = others.clone().find(|&hart| self.harts[hart].has_work(guest_now));
+        let ran = now.saturating_sub(self.vcpu.slice_start);
+        if self.harts[self.vcpu.hart].state == HartState::Stopped || ran >= VCPU_SLICE {
+            return with_work.or_else(|| others.next())
+        }
+        if ran >= VCPU_MIN_RUN {
+            return with_work
+        }
+        None
+    }
+
+    /// host time at which another hart is due, `usize::MAX` if the running hart is alone
+    pub fn hart_deadline(&self) -> usize {
+        let htimedelta = read_htimedelta();
+        self.other_harts().fold(usize::MAX, |deadline, hart| {
+            let vtimecmp = self.harts[hart].vtimecmp;
+            let timer = if vtimecmp == usize::MAX {
+                usize::MAX
+            }else{
+                vtimecmp.wrapping_sub(htimedelta).max(self.vcpu.slice_start + VCPU_MIN_RUN)
+            };
+            deadline.min(self.vcpu.slice_start + VCPU_SLICE).min(timer)
+        })
+    }
+
+    /// raise the external interrupt of hart 0
+    pub fn raise_external_irq(&mut self) {
+        if self.vcpu.hart == 0 {
+            arch::raise_guest_irq(Irq::External);
+        }else{
+            self.harts[0].hvip |= arch::guest_irq_line(Irq::External);
+        }
+    }
+
+    /// raise the software interrupt of hart 0 for a posted interrupt, `running` if the
+    /// guest is on the cpu
+    pub fn raise_posted_irq(&mut self, running: bool) {
+        if self.vcpu.hart != 0 {
+            self.harts[0].hvip |= arch::guest_irq_line(Irq::Software);
+        }else if running {
+            arch::raise_guest_irq(Irq::Software);
+        }else{
+            self.vcpu.hvip |= arch::guest_irq_line(Irq::Software);
+        }
+    }
+
+    /// raise the counter overflow interrupt of the running hart, `running` if the guest is
+    /// on the cpu
+    pub fn raise_counter_overflow(&mut self, running: bool) {
+        if running {
+            arch::raise_guest_irq(Irq::CounterOverflow);
+        }else{
+            self.vcpu.hvip |= arch::guest_irq_line(Irq::CounterOverflow);
+        }
+    }
+
+    /// SBI HSM hart_start, called by the running hart.
+    /// `hart` enters the guest at `start_addr` with a0 = hart id and a1 = `opaque`.
+    pub fn hart_start(&mut self, hart: usize, start_addr: usize, opaque: usize) -> isize {
+        let (hgatp, kernel_sp) = (self.trap_ctx.hgatp, self.trap_ctx.kernel_sp);
+        let sstatus_clear_bits = isa::sstatus_clear_bits(self.misa);
+        let trap_vsatp = self.config.trap_vsatp;
+        let trap_wfi = self.config.wfi.traps();
+        let vhart = match self.harts.get_mut(hart) {
+            Some(vhart) => vhart,
+            None => return SBI_ERR_INAVLID_PARAM
+        };
+        if vhart.state != HartState::Stopped {
+            return SBI_ERR_ALREADY_AVAILABLE
+        }
+        let mut trap_ctx = Self::boot_context(hart, start_addr, opaque, hgatp, kernel_sp);
+        trap_ctx.clear_sstatus_bits(sstatus_clear_bits);
+        trap_ctx.hstatus.set_vtvm(trap_vsatp);
+        trap_ctx.hstatus.set_vtw(trap_wfi);
+        let addr_space = mem::take(&mut vhart.addr_space);
+        let stats = vhart.stats;
+        *vhart = VHart::new(HartState::Started, trap_ctx);
+        vhart.addr_space = addr_space;
+        vhart.stats = stats;
+        // all harts of a guest see the same time
+        vhart.vs_csrs = GuestVsCsrs::with_htimedelta(read_htimedelta());
+        hdebug!("guest {} hart {} started at {:#x}", self.guest_id, hart, start_addr);
+        SBI_SUCCESS as isize
+    }
+
+    /// SBI HSM hart_stop of the running hart, which leaves the cpu at the end of the trap.
+    /// The last started hart cannot be stopped, the guest shuts down instead.
+    pub fn hart_stop(&mut self) -> isize {
+        if self.other_harts().next().is_none() {
+            return SBI_ERR_FAILUER
+        }
+        self.harts[self.vcpu.hart].state = HartState::Stopped;
+        SBI_SUCCESS as isize
+    }
+
+    /// the running hart gives up the rest of its slice to the other started harts at host
+    /// time `now`, `false` if it is alone
+    pub fn yield_hart(&mut self, now: usize) -> bool {
+        if self.other_harts().next().is_none() {
+            return false
+        }
+        self.vcpu.slice_start = now.saturating_sub(VCPU_SLICE);
+        true
+    }
+
+    /// the running hart hands the rest of its slice to the started hart `hart`
+    pub fn yield_to_hart(&mut self, hart: usize) -> isize {
+        match self.harts.get(hart) {
+            Some(vhart) if vhart.state == HartState::Started => {
+                if hart != self.vcpu.hart {
+                    self.vcpu.yield_to = Some(hart);
+                }
+                SBI_SUCCESS as isize
+            },
+            _ => SBI_ERR_INAVLID_PARAM
+        }
+    }
+
+    pub fn hart_status(&self, hart: usize) -> Option<HartState> {
+        self.harts.get(hart).map(|vhart| vhart.state)
+    }
+
+    /// make a software interrupt pending on the selected started harts
+    pub fn send_ipi(&mut self, hart_mask: usize, hart_mask_base: usize) -> isize {
+        if hart_mask_base != usize::MAX && hart_mask != 0 && hart_mask_base >= self.harts.len() {
+            return SBI_ERR_INAVLID_PARAM
+        }
+        let current = self.vcpu.hart;
+        for (hart, vhart) in self.harts.iter_mut().enumerate() {
+            if vhart.state != HartState::Started || !hart_selected(hart, hart_mask, hart_mask_base) {
+                continue
+            }
+            if hart == current {
+                arch::raise_guest_irq(Irq::Software);
+            }else{
+                vhart.hvip |= arch::guest_irq_line(Irq::Software);
+            }
+        }
+        SBI_SUCCESS as isize
+    }
+}
+
+impl<P: PageTable, G: GuestPageTable> HostVmm<P, G> {
+    /// give the cpu to another hart of the running guest if one is due,
+    /// return whether the running hart changed
+    pub fn schedule_harts(&mut self, now: usize) -> bool {
+        if self.sched.current != Some(self.guest_id) {
+            return false
+        }
+        let guest = match self.guests.get_mut(self.guest_id) {
+            Some(guest) => guest,
+            None => return false
+        };
+        match guest.next_hart(now) {
+            Some(next) => {
+                let ctx = unsafe{ (TRAP_CONTEXT as *mut TrapContext).as_mut().unwrap() };
+                guest.switch_hart(ctx, next);
+                true
+            },
+            None => false
+        }
+    }
+
+    /// `HYPERCALL_YIELD_FID` or a retentive `hart_suspend` of the running hart: the other
+    /// harts of the guest get the rest of its slice, the other guests if it is alone
+    pub fn yield_hart(&mut self) {
+        let now = time::read();
+        let alone = match self.guests.get_mut(self.guest_id) {
+            Some(guest) => !guest.yield_hart(now),
+            None => return
+        };
+        if alone {
+            self.yield_vcpu(now);
+        }
+    }
+
+    /// host time at which another hart of the running guest is due
+    pub fn hart_deadline(&self) -> usize {
+        if self.sched.current != Some(self.guest_id) {
+            return usize::MAX
+        }
+        self.guests.get(self.guest_id).map_or(usize::MAX, |guest| guest.hart_deadline())
+    }
+}
@@ -0,0 +1,286 @@
+//! hypocaust-2 specific hypercalls (SBI extension `SBI_EXTID_HYPERCALL`)
+
+use super::vmexit::TrapContext;
+use super::page_table::GuestPageTable;
+use super::SbiRet;
+use super::pmap::{ is_guest_ram, with_guest_memory };
+use super::posted::PostedIrqPage;
+use super::sealing::{ self, SEALED_HEADER_SIZE, SEAL_MAX_LEN };
+#[cfg(feature = "csr_fuzz")]
+use super::csrcheck;
+use crate::constants::riscv_regs::GprIndex;
+use crate::bootprof;
+use crate::constants::CLOCK_FREQ;
+use crate::hypervisor::HostVmm;
+use crate::info::{ IMPL_ID, VERSION };
+use crate::page_table::PageTable;
+use crate::sbi::{ SBI_SUCCESS, SBI_ERR_NOT_SUPPORTED, SBI_ERR_INAVLID_PARAM, SBI_ERR_DENIED, SBI_ERR_INVALID_ADDRESS };
+use crate::sbi::{ HYPERCALL_VCPU_STATS_FID, HYPERCALL_FRAMEBUFFER_FID, HYPERCALL_DEVICE_EVENT_FID, HYPERCALL_INFO_FID, HYPERCALL_SEAL_FID, HYPERCALL_UNSEAL_FID };
+use crate::sbi::HYPERCALL_POSTED_IRQ_FID;
+use crate::sbi::{ HYPERCALL_EVTCHN_INIT_FID, HYPERCALL_EVTCHN_ALLOC_FID, HYPERCALL_EVTCHN_BIND_FID, HYPERCALL_EVTCHN_CLOSE_FID, HYPERCALL_EVTCHN_SEND_FID };
+use crate::sbi::{ HYPERCALL_GRANT_FID, HYPERCALL_GRANT_REVOKE_FID, HYPERCALL_GRANT_MAP_FID, HYPERCALL_GRANT_UNMAP_FID };
+use crate::sbi::{ HYPERCALL_YIELD_FID, HYPERCALL_YIELD_TO_FID };
+#[cfg(feature = "csr_fuzz")]
+use crate::sbi::HYPERCALL_CSR_CHECK_FID;
+#[cfg(feature = "trap_test")]
+use crate::sbi::{ HYPERCALL_TRAP_TEST_FID, HYPERCALL_WALK_TEST_FID };
+#[cfg(feature = "trap_test")]
+use crate::nested;
+#[cfg(feature = "trap_test")]
+use crate::page_table::{ AccessType, WalkContext, WalkFault };
+#[cfg(feature = "trap_test")]
+use super::pmap::{ read_host, two_stage_translation };
+#[cfg(feature = "profiler")]
+use crate::sbi::HYPERCALL_PROFILE_FID;
+#[cfg(feature = "profiler")]
+use crate::profiler;
+
+/// statistic index of `HYPERCALL_VCPU_STATS_FID`
+pub const VCPU_STAT_RUN_TIME: usize = 0;
+pub const VCPU_STAT_WAIT_TIME: usize = 1;
+pub const VCPU_STAT_PREEMPTIONS: usize = 2;
+pub const VCPU_STAT_EXITS: usize = 3;
+pub const VCPU_STAT_MMIO_EXITS: usize = 4;
+
+/// field index of `HYPERCALL_FRAMEBUFFER_FID`
+pub const FB_INFO_BASE: usize = 0;
+pub const FB_INFO_SIZE: usize = 1;
+pub const FB_INFO_WIDTH: usize = 2;
+pub const FB_INFO_HEIGHT: usize = 3;
+pub const FB_INFO_STRIDE: usize = 4;
+pub const FB_INFO_BPP: usize = 5;
+
+/// field index of `HYPERCALL_INFO_FID`
+pub const INFO_IMPL_ID: usize = 0;
+pub const INFO_VERSION: usize = 1;
+/// bitmap of `info::feature`
+pub const INFO_FEATURES: usize = 2;
+/// milliseconds since the hypervisor started
+pub const INFO_UPTIME_MS: usize = 3;
+
+pub fn hypercall_handler<P: PageTable, G: GuestPageTable>(host_vmm: &mut HostVmm<P, G>, fid: usize, ctx: &TrapContext) -> SbiRet {
+    let a0 = ctx.x[GprIndex::A0 as usize];
+    let a1 = ctx.x[GprIndex::A1 as usize];
+    let a2 = ctx.x[GprIndex::A2 as usize];
+    let a3 = ctx.x[GprIndex::A3 as usize];
+    match fid {
+        HYPERCALL_VCPU_STATS_FID => hypercall_vcpu_stats(host_vmm, a0, a1),
+        HYPERCALL_FRAMEBUFFER_FID => hypercall_framebuffer(host_vmm, a0),
+        HYPERCALL_DEVICE_EVENT_FID => hypercall_device_event(host_vmm),
+        HYPERCALL_INFO_FID => hypercall_info(host_vmm, a0),
+        HYPERCALL_SEAL_FID => hypercall_seal(host_vmm, (a0, a1), (a2, a3), true),
+        HYPERCALL_UNSEAL_FID => hypercall_seal(host_vmm, (a0, a1), (a2, a3), false),
+        HYPERCALL_POSTED_IRQ_FID => hypercall_posted_irq(host_vmm, a0),
+        HYPERCALL_EVTCHN_INIT_FID => hypercall_evtchn_init(host_vmm, a0, a1),
+        HYPERCALL_EVTCHN_ALLOC_FID => sbi_ret(host_vmm.evtchn_alloc(host_vmm.guest_id, a0)),
+        HYPERCALL_EVTCHN_BIND_FID => sbi_ret(host_vmm.evtchn_bind(host_vmm.guest_id, a0, a1)),
+        HYPERCALL_EVTCHN_CLOSE_FID => sbi_ret(host_vmm.evtchn_close(host_vmm.guest_id, a0).map(|_| 0)),
+        HYPERCALL_EVTCHN_SEND_FID => sbi_ret(host_vmm.evtchn_send(host_vmm.guest_id, a0).map(|_| 0)),
+        HYPERCALL_GRANT_FID => sbi_ret(host_vmm.grant(host_vmm.guest_id, a0, a1, a2)),
+        HYPERCALL_GRANT_REVOKE_FID => sbi_ret(host_vmm.grant_revoke(host_vmm.guest_id, a0).map(|_| 0)),
+        HYPERCALL_GRANT_MAP_FID => sbi_ret(host_vmm.grant_map(host_vmm.guest_id, a0, a1, a2).map(|_| 0)),
+        HYPERCALL_GRANT_UNMAP_FID => sbi_ret(host_vmm.grant_unmap(host_vmm.guest_id, a0, a1).map(|_| 0)),
+        // the harts switch at the end of the trap, see `hsm`
+        HYPERCALL_YIELD_FID => {
+            host_vmm.yield_hart();
+            SbiRet { error: SBI_SUCCESS, value: 0 }
+        },
+        HYPERCALL_YIELD_TO_FID => match host_vmm.guests.get_mut(host_vmm.guest_id) {
+            Some(guest) => SbiRet { error: guest.yield_to_hart(a0) as usize, value: 0 },
+            None => SbiRet { error: SBI_ERR_INAVLID_PARAM as usize, value: 0 }
+        },
+        #[cfg(feature = "csr_fuzz")]
+        HYPERCALL_CSR_CHECK_FID => hypercall_csr_check(a0, a1),
+        // `HOST_VMM` is locked here, as in most trap handlers
+        #[cfg(feature = "trap_test")]
+        HYPERCALL_TRAP_TEST_FID => SbiRet { error: SBI_SUCCESS, value: nested::self_test() },
+        #[cfg(feature = "trap_test")]
+        HYPERCALL_WALK_TEST_FID => hypercall_walk_test(host_vmm, a0),
+        #[cfg(feature = "profiler")]
+        HYPERCALL_PROFILE_FID => hypercall_profile(a0),
+        _ => SbiRet { error: SBI_ERR_NOT_SUPPORTED as usize, value: 0 }
+    }
+}
+
+/// a guest only reads the statistics of its own harts, the ones of the others are for the monitor
+fn hypercall_vcpu_stats<P: PageTable, G: GuestPageTable>(host_vmm: &HostVmm<P, G>, hart: usize, index: usize) -> SbiRet {
+    let stats = match host_vmm.hart_stats(host_vmm.guest_id, hart) {
+        Some(stats) => stats,
+        None => return SbiRet { error: SBI_ERR_INAVLID_PARAM as usize, value: 0 }
+    };
+    let value = match index {
+        VCPU_STAT_RUN_TIME => stats.run_time,
+        VCPU_STAT_WAIT_TIME => stats.wait_time,
+        VCPU_STAT_PREEMPTIONS => stats.preemptions,
+        VCPU_STAT_EXITS => stats.exits,
+        VCPU_STAT_MMIO_EXITS => stats.mmio_exits,
+        _ => return SbiRet { error: SBI_ERR_INAVLID_PARAM as usize, value: 0 }
+    };
+    SbiRet { error: SBI_SUCCESS, value }
+}
+
+/// exception codes of a faulting `hlv.d`
+#[cfg(feature = "trap_test")]
+const LOAD_PAGE_FAULT: usize = 13;
+#[cfg(feature = "trap_test")]
+const LOAD_GUEST_PAGE_FAULT: usize = 21;
+
+/// read `guest_va` of the calling guest with the software walk and with `hlv.d`, the
+/// guest state of both is the one of the hypercall
+#[cfg(feature = "trap_test")]
+fn hypercall_walk_test<P: PageTable, G: GuestPageTable>(host_vmm: &HostVmm<P, G>, guest_va: usize) -> SbiRet {
+    let guest = match host_vmm.guests.get(host_vmm.guest_id) {
+        Some(guest) => guest,
+        None => return SbiRet { error: SBI_ERR_INAVLID_PARAM as usize, value: 0 }
+    };
+    if guest_va % 8 != 0 {
+        return SbiRet { error: SBI_ERR_INVALID_ADDRESS as usize, value: 0 }
+    }
+    let walked = two_stage_translation(guest_va, AccessType::Read, &WalkContext::current(), &guest.gpm)
+        .map(read_host::<usize>);
+    let loaded = nested::test_guest_load(guest_va);
+    let agree = match (walked, loaded) {
+        (Ok(walked), Ok(loaded)) => walked == Some(loaded),
+        (Err(WalkFault::PageFault), Err(scause)) => scause == LOAD_PAGE_FAULT,
+        (Err(WalkFault::GuestPageFault { .. }), Err(scause)) => scause == LOAD_GUEST_PAGE_FAULT,
+        _ => false
+    };
+    if !agree {
+        hwarning!("walk test: {:#x} walked to {:?}, hlv.d gave {:?}", guest_va, walked, loaded);
+    }
+    SbiRet { error: SBI_SUCCESS, value: agree as usize }
+}
+
+/// the guest device tree is fixed, a guest finds its framebuffer with this call
+fn hypercall_framebuffer<P: PageTable, G: GuestPageTable>(host_vmm: &HostVmm<P, G>, index: usize) -> SbiRet {
+    let fb = match host_vmm.guests.get(host_vmm.guest_id).and_then(|guest| guest.guest_machine.framebuffer.as_ref()) {
+        Some(fb) => fb,
+        None => return SbiRet { error: SBI_ERR_NOT_SUPPORTED as usize, value: 0 }
+    };
+    let value = match index {
+        FB_INFO_BASE => fb.device.base_address,
+        FB_INFO_SIZE => fb.device.size,
+        FB_INFO_WIDTH => fb.width as usize,
+        FB_INFO_HEIGHT => fb.height as usize,
+        FB_INFO_STRIDE => fb.stride as usize,
+        FB_INFO_BPP => fb.bits_per_pixel().unwrap_or(0) as usize,
+        _ => return SbiRet { error: SBI_ERR_INAVLID_PARAM as usize, value: 0 }
+    };
+    SbiRet { error: SBI_SUCCESS, value }
+}
+
+/// see `DeviceEvent::encode`
+fn hypercall_device_event<P: PageTable, G: GuestPageTable>(host_vmm: &mut HostVmm<P, G>) -> SbiRet {
+    let guest_id = host_vmm.guest_id;
+    let event = host_vmm.guests.get_mut(guest_id).and_then(|guest| guest.device_events.pop_front());
+    SbiRet { error: SBI_SUCCESS, value: event.map_or(0, |event| event.encode()) }
+}
+
+fn hypercall_info<P: PageTable, G: GuestPageTable>(host_vmm: &HostVmm<P, G>, index: usize) -> SbiRet {
+    let value = match index {
+        INFO_IMPL_ID => IMPL_ID,
+        INFO_VERSION => VERSION,
+        INFO_FEATURES => host_vmm.features(),
+        INFO_UPTIME_MS => bootprof::uptime() / (CLOCK_FREQ / 1000),
+        _ => return SbiRet { error: SBI_ERR_INAVLID_PARAM as usize, value: 0 }
+    };
+    SbiRet { error: SBI_SUCCESS, value }
+}
+
+/// seal or unseal the guest buffer `input` into `output`, both given as guest physical
+/// address and length. Only enclave guests have sealed storage. An output buffer too small
+/// fails with `SBI_ERR_INAVLID_PARAM` and the size needed, a blob that does not unseal
+/// with `SBI_ERR_DENIED`.
+fn hypercall_seal<P: PageTable, G: GuestPageTable>(
+    host_vmm: &HostVmm<P, G>, input: (usize, usize), output: (usize, usize), seal: bool
+) -> SbiRet {
+    let guest = match host_vmm.guests.get(host_vmm.guest_id) {
+        Some(guest) if guest.config.enclave => guest,
+        _ => return SbiRet { error: SBI_ERR_NOT_SUPPORTED as usize, value: 0 }
+    };
+    if input.1 > SEAL_MAX_LEN + if seal { 0 } else { SEALED_HEADER_SIZE } {
+        return SbiRet { error: SBI_ERR_INAVLID_PARAM as usize, value: 0 }
+    }
+    if !is_guest_ram(output.0, output.1) {
+        return SbiRet { error: SBI_ERR_INVALID_ADDRESS as usize, value: 0 }
+    }
+    let result = with_guest_memory(host_vmm.guest_id, input.0, input.1, |input| if seal {
+        Ok(sealing::seal(&guest.measurement, input))
+    }else{
+        sealing::unseal(&guest.measurement, input)
+    });
+    let result = match result {
+        Some(Ok(data)) => data,
+        Some(Err(_)) => return SbiRet { error: SBI_ERR_DENIED as usize, value: 0 },
+        None => return SbiRet { error: SBI_ERR_INVALID_ADDRESS as usize, value: 0 }
+    };
+    if result.len() > output.1 {
+        return SbiRet { error: SBI_ERR_INAVLID_PARAM as usize, value: result.len() }
+    }
+    with_guest_memory(host_vmm.guest_id, output.0, result.len(), |output| output.copy_from_slice(&result));
+    SbiRet { error: SBI_SUCCESS, value: result.len() }
+}
+
+/// register the page of the calling guest for posted interrupts, or unregister it with 0.
+/// Bits already pending in an earlier page are not carried over.
+fn hypercall_posted_irq<P: PageTable, G: GuestPageTable>(host_vmm: &mut HostVmm<P, G>, guest_pa: usize) -> SbiRet {
+    let guest = match host_vmm.guests.get_mut(host_vmm.guest_id) {
+        Some(guest) => guest,
+        None => return SbiRet { error: SBI_ERR_NOT_SUPPORTED as usize, value: 0 }
+    };
+    if guest_pa == 0 {
+        guest.posted_irqs = None;
+        return SbiRet { error: SBI_SUCCESS, value: 0 }
+    }
+    match PostedIrqPage::new(guest.guest_id, guest_pa) {
+        Some(page) => {
+            guest.posted_irqs = Some(page);
+            SbiRet { error: SBI_SUCCESS, value: 0 }
+        },
+        None => SbiRet { error: SBI_ERR_INVALID_ADDRESS as usize, value: 0 }
+    }
+}
+
+/// register the event channel page of the calling guest and its upcall interrupt
+fn hypercall_evtchn_init<P: PageTable, G: GuestPageTable>(host_vmm: &mut HostVmm<P, G>, guest_pa: usize, irq: usize) -> SbiRet {
+    match host_vmm.guests.get_mut(host_vmm.guest_id) {
+        Some(guest) => sbi_ret(guest.evtchn.init(guest_pa, irq as u32).map(|_| 0)),
+        None => SbiRet { error: SBI_ERR_NOT_SUPPORTED as usize, value: 0 }
+    }
+}
+
+/// `evtchn` and `grant` errors are SBI error codes
+fn sbi_ret(result: Result<usize, isize>) -> SbiRet {
+    match result {
+        Ok(value) => SbiRet { error: SBI_SUCCESS, value },
+        Err(error) => SbiRet { error: error as usize, value: 0 }
+    }
+}
+
+/// run a CSR validator on a guest chosen value, for fuzzing them from a test guest
+#[cfg(feature = "csr_fuzz")]
+fn hypercall_csr_check(check: usize, value: usize) -> SbiRet {
+    let checked = match check {
+        csrcheck::CHECK_VSATP => csrcheck::vsatp(value),
+        // relative to guest time 0, the guest picks both ends of the range
+        csrcheck::CHECK_TIMER => Some(csrcheck::timer(value, 0)),
+        csrcheck::CHECK_HVIP => Some(csrcheck::hvip(value)),
+        _ => return SbiRet { error: SBI_ERR_INAVLID_PARAM as usize, value: 0 }
+    };
+    match checked {
+        Some(value) => SbiRet { error: SBI_SUCCESS, value },
+        None => SbiRet { error: SBI_ERR_DENIED as usize, value: csrcheck::rejected() }
+    }
+}
+
+/// start the hypervisor profiler at `rate` Hz, or stop it with 0, so a guest can profile
+/// the hypervisor around a workload
+#[cfg(feature = "profiler")]
+fn hypercall_profile(rate: usize) -> SbiRet {
+    if rate == 0 {
+        profiler::stop();
+    }else if profiler::start(rate).is_err() {
+        return SbiRet { error: SBI_ERR_INAVLID_PARAM as usize, value: 0 }
+    }
+    SbiRet { error: SBI_SUCCESS, value: profiler::samples() }
+}
@@ -0,0 +1,147 @@
+//! Pristine copies of guest images, used to reload a guest in place on reset
+//!
+//! Guests booting the same kernel hold the same image. The first pages of the kernel,
+//! its text and read-only data, can be mapped read-only from the image frames into
+//! every such guest as a `SharedText` instead of from the guest's own memory.
+//!
+//! Image frames are hidden from the hypervisor once filled, and so is guest ram, see
+//! `mm::framemap`.
+
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use crate::constants::PAGE_SIZE;
+use crate::hyp_alloc::{ FrameTracker, frame_alloc };
+use crate::mm::framemap::{ GuestRamMapping, with_frame, with_guest_ram };
+use crate::page_table::PhysPageNum;
+use super::KernelLayout;
+use super::pmap::gpa2hpa;
+use crate::{ VmmError, VmmResult };
+
+pub struct GuestImage {
+    frames: Vec<FrameTracker>,
+    len: usize
+}
+
+impl GuestImage {
+    /// copy `data` into hypervisor owned frames
+    pub fn new(data: &[u8]) -> VmmResult<Self> {
+        let mut frames = Vec::new();
+        for chunk in data.chunks(PAGE_SIZE) {
+            let mut frame = frame_alloc().ok_or(VmmError::OutOfMemory)?;
+            frame.ppn.get_bytes_array()[..chunk.len()].copy_from_slice(chunk);
+            frame.hide();
+            frames.push(frame);
+        }
+        Ok(Self { frames, len: data.len() })
+    }
+
+    /// `len` zero bytes in hypervisor owned frames, filled later with `write`
+    pub fn zeroed(len: usize) -> VmmResult<Self> {
+        let mut frames = Vec::new();
+        for _ in 0..(len + PAGE_SIZE - 1) / PAGE_SIZE {
+            let mut frame = frame_alloc().ok_or(VmmError::OutOfMemory)?;
+            frame.hide();
+            frames.push(frame);
+        }
+        Ok(Self { frames, len })
+    }
+
+    /// copy `data` into the image at `offset`
+    pub fn write(&self, offset: usize, data: &[u8]) -> VmmResult {
+        if offset.checked_add(data.len()).map_or(true, |end| end > self.len) {
+            return Err(VmmError::InvalidState)
+        }
+        let mut done = 0;
+        while done < data.len() {
+            let page_offset = (offset + done) % PAGE_SIZE;
+            let size = (PAGE_SIZE - page_offset).min(data.len() - done);
+            with_frame(self.frames[(offset + done) / PAGE_SIZE].ppn, |bytes| {
+                bytes[page_offset..page_offset + size].copy_from_slice(&data[done..done + size])
+            });
+            done += size;
+        }
+        Ok(())
+    }
+
+    /// the first bytes of the image, at most a page
+    pub fn head(&self) -> Vec<u8> {
+        let size = PAGE_SIZE.min(self.len);
+        match self.frames.first() {
+            Some(frame) => with_frame(frame.ppn, |bytes| bytes[..size].to_vec()),
+            None => Vec::new()
+        }
+    }
+
+    /// frames held by the image
+    pub fn frames(&self) -> usize {
+        self.frames.len()
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// call `f` on the bytes of the image, a page at a time
+    pub fn for_each_chunk(&self, mut f: impl FnMut(&[u8])) {
+        for (i, frame) in self.frames.iter().enumerate() {
+            let size = PAGE_SIZE.min(self.len - i * PAGE_SIZE);
+            with_frame(frame.ppn, |bytes| f(&bytes[..size]));
+        }
+    }
+
+    /// copy the image to guest ram at host physical address `pa`
+    pub fn load(&self, pa: usize) {
+        self.copy_to(0, pa, self.len);
+    }
+
+    /// place the segments of a kernel image in the ram of guest `guest_id`, the loader
+    /// checked they fit
+    pub fn load_kernel(&self, guest_id: usize, layout: &KernelLayout) {
+        for segment in layout.segments.iter() {
+            let pa = gpa2hpa(segment.guest_pa, guest_id);
+            self.copy_to(segment.offset, pa, segment.file_size);
+            with_guest_ram(pa + segment.file_size, segment.mem_size - segment.file_size, |bss| bss.fill(0))
+                .expect("kernel segment outside of guest ram");
+        }
+    }
+
+    /// copy `len` bytes at `offset` of the image to guest ram at host physical address
+    /// `pa`, a page of the image at a time under the mappings of both
+    fn copy_to(&self, offset: usize, pa: usize, len: usize) {
+        let mut done = 0;
+        while done < len {
+            let page_offset = (offset + done) % PAGE_SIZE;
+            let size = (PAGE_SIZE - page_offset).min(len - done);
+            let mut ram = GuestRamMapping::new(pa + done, size).expect("guest image loaded outside of guest ram");
+            with_frame(self.frames[(offset + done) / PAGE_SIZE].ppn, |bytes| {
+                ram.bytes_mut().copy_from_slice(&bytes[page_offset..page_offset + size])
+            });
+            done += size;
+        }
+    }
+}
+
+/// The first pages of a kernel image, mapped read-only into the guests booting it.
+/// A guest storing to one of them gets its own copy, see `GuestMemorySet::break_cow`.
+#[derive(Clone)]
+pub struct SharedText {
+    image: Arc<GuestImage>,
+    pages: usize
+}
+
+impl SharedText {
+    /// share the first `len` bytes of `image`, rounded up to pages
+    pub fn new(image: &Arc<GuestImage>, len: usize) -> Self {
+        let pages = ((len + PAGE_SIZE - 1) / PAGE_SIZE).min(image.frames.len());
+        Self { image: image.clone(), pages }
+    }
+
+    pub fn pages(&self) -> usize {
+        self.pages
+    }
+
+    /// frame holding page `index` of the text
+    pub fn ppn(&self, index: usize) -> PhysPageNum {
+        self.image.frames[index].ppn
+    }
+}
@@ -0,0 +1,96 @@
+//! Per-guest ISA masking
+//!
+//! Extensions hidden from a guest are removed from `riscv,isa` in its device tree.
+//! What is left is the virtual `misa` of the guest. F, D and V are enforced as well:
+//! without them in `misa`, `sstatus.FS` and `sstatus.VS` stay off while the guest runs,
+//! so its floating point and vector instructions raise illegal instruction exceptions
+//! that are forwarded to the guest. With them the registers are switched with the guest,
+//! see `fpu`. Guests never get H. Other extensions can only be hidden from the ISA string.
+
+use alloc::string::String;
+
+/// `sstatus.VS`
+const SSTATUS_VS: usize = 0b11 << 9;
+/// `sstatus.FS`
+const SSTATUS_FS: usize = 0b11 << 13;
+/// `misa.MXL` of a 64-bit hart
+const MISA_MXL_64: usize = 2 << 62;
+/// assumed when the device tree has no `riscv,isa`
+const DEFAULT_ISA: &str = "rv64imafdc";
+
+/// `misa` bit of extension `ext`
+const fn misa_bit(ext: char) -> usize {
+    1 << (ext as u32 - 'a' as u32)
+}
+
+/// single letter extensions hidden from a guest, bit n is extension `'a' + n`
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct IsaMask(u32);
+
+impl IsaMask {
+    pub const fn empty() -> Self {
+        Self(0)
+    }
+
+    /// extension letters, e.g. `vh`
+    pub fn parse(letters: &str) -> Option<Self> {
+        letters.chars().try_fold(Self::empty(), |mask, ext| match ext.to_ascii_lowercase() {
+            ext @ 'a'..='z' => Some(Self(mask.0 | 1 << (ext as u32 - 'a' as u32))),
+            _ => None
+        })
+    }
+
+    pub fn hides(&self, ext: char) -> bool {
+        match ext.to_ascii_lowercase() {
+            ext @ 'a'..='z' => self.0 & 1 << (ext as u32 - 'a' as u32) != 0,
+            _ => false
+        }
+    }
+
+    /// `isa` without the hidden extensions. A multi-letter extension goes with the
+    /// single letter one it extends, e.g. `zvl128b` with `v`.
+    pub fn apply(&self, isa: &str) -> String {
+        let mut parts = isa.split('_');
+        let base = parts.next().unwrap_or("");
+        let (prefix, letters) = base.split_at(base.len().min(4));
+        let mut masked = String::from(prefix);
+        masked.extend(letters.chars().filter(|&ext| !self.hides(ext)));
+        for ext in parts {
+            let extends = ext.strip_prefix('z').and_then(|rest| rest.chars().next());
+            if extends.map_or(false, |ext| self.hides(ext)) {
+                continue
+            }
+            masked.push('_');
+            masked.push_str(ext);
+        }
+        masked
+    }
+
+    /// virtual `misa` of a guest hart with `isa` in its device tree, `g` stands for `imafd`
+    pub fn misa(&self, isa: &str) -> usize {
+        let isa = if isa.is_empty() { DEFAULT_ISA } else { isa };
+        let base = isa.split('_').next().unwrap_or("");
+        let mut misa = 0;
+        for ext in base.get(4..).unwrap_or("").chars() {
+            match ext.to_ascii_lowercase() {
+                'g' => misa |= misa_bit('i') | misa_bit('m') | misa_bit('a') | misa_bit('f') | misa_bit('d'),
+                ext @ 'a'..='z' => misa |= misa_bit(ext),
+                _ => {}
+            }
+        }
+        // hidden extensions have the bits of `misa`
+        MISA_MXL_64 | misa & !(self.0 as usize | misa_bit('h'))
+    }
+}
+
+/// `sstatus` bits kept clear while a guest with the virtual `misa` runs
+pub fn sstatus_clear_bits(misa: usize) -> usize {
+    let mut bits = 0;
+    if misa & (misa_bit('f') | misa_bit('d')) == 0 {
+        bits |= SSTATUS_FS;
+    }
+    if misa & misa_bit('v') == 0 {
+        bits |= SSTATUS_VS;
+    }
+    bits
+}
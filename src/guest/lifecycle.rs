@@ -0,0 +1,332 @@
+//! Guest lifecycle operations driven by the monitor, SBI or hypercalls
+//!
+//! Emulated devices follow the guest through `DeviceLifecycle`: they are flushed when the
+//! guest pauses, powers off or reboots, reset when it boots again and destroyed when it is
+//! killed, always in the order of `Guest::devices_mut`.
+
+use alloc::vec::Vec;
+use riscv::register::time;
+
+use super::{ isa, Guest, GuestState, SharedText };
+use super::context::GuestVsCsrs;
+use super::fpu::FpuState;
+use super::page_table::GuestPageTable;
+use super::pmap::gpa2hpa;
+use super::vmachine::{ self, VirtMachine };
+use super::vmexit::{ TrapContext, request_fence_i, request_stage2_flush };
+use crate::arch;
+use crate::constants::{ KERNEL_STACK_SIZE, PAGE_SIZE };
+use crate::constants::layout::TRAP_CONTEXT;
+use crate::console;
+use crate::device_emu::DeviceLifecycle;
+use crate::device_emu::hotplug::HotplugDevice;
+use crate::hypervisor::{ fdt, HostVmm };
+use crate::hypervisor::stack::{ hstack_position, hstack_free };
+use crate::hyp_alloc::{ live_frames, scrub_policy, ScrubPolicy };
+use crate::ioservice;
+use crate::mm::GuestMemorySet;
+use crate::page_table::PageTable;
+use crate::sbi::{ reboot, shutdown };
+use crate::{ VmmError, VmmResult };
+
+impl<G: GuestPageTable> Guest<G> {
+    /// emulated devices in teardown order: virtio devices first, their last requests still
+    /// find the platform devices, then PCI functions, the watchdog and the RTC
+    pub fn devices_mut(&mut self) -> Vec<&mut dyn DeviceLifecycle> {
+        let mut devices: Vec<&mut dyn DeviceLifecycle> = Vec::new();
+        for dev in self.virtio.iter_mut() {
+            devices.push(dev);
+        }
+        devices.push(&mut self.pci);
+        devices.push(&mut self.watchdog);
+        devices.push(&mut self.rtc);
+        devices
+    }
+}
+
+impl<P: PageTable, G: GuestPageTable> HostVmm<P, G> {
+    /// stop scheduling the guest's vcpu and quiesce its emulated devices
+    pub fn pause_guest(&mut self, guest_id: usize) -> VmmResult {
+        let guest = self.guests.get(guest_id).ok_or(VmmError::NoFound)?;
+        match guest.state {
+            GuestState::Paused => return Ok(()),
+            GuestState::Stopped => return Err(VmmError::InvalidState),
+            GuestState::Running => {}
+        }
+        self.sched.set_runnable(guest_id, false);
+        // the guest is switched out by `schedule` at the end of the current trap,
+        // the hart idles if no other guest is runnable
+        self.flush_guest_devices(guest_id);
+        let guest = self.guests.get_mut(guest_id).unwrap();
+        guest.state = GuestState::Paused;
+        guest.paused_at = time::read();
+        hdebug!("guest {} paused", guest_id);
+        Ok(())
+    }
+
+    /// reschedule a paused guest, hiding the paused time from the guest unless
+    /// its `freeze_on_pause` is off
+    pub fn resume_guest(&mut self, guest_id: usize) -> VmmResult {
+        let guest = self.guests.get_mut(guest_id).ok_or(VmmError::NoFound)?;
+        if guest.state != GuestState::Paused {
+            return Err(VmmError::InvalidState)
+        }
+        let paused_time = time::read() - guest.paused_at;
+        if guest.config.freeze_on_pause {
+            // a paused guest is never on the cpu, so its htimedelta lives in `vs_csrs`.
+            // Pending timer deadlines are in guest time and move along with it.
+            guest.vs_csrs.rewind_time(paused_time);
+            guest.harts.iter_mut().for_each(|vhart| vhart.vs_csrs.rewind_time(paused_time));
+        }
+        guest.state = GuestState::Running;
+        self.sched.set_runnable(guest_id, true);
+        hdebug!("guest {} resumed after {} cycles", guest_id, paused_time);
+        Ok(())
+    }
+
+    /// reboot a guest in place from its stored image.
+    /// A guest trapped on the cpu is reset at the end of the trap, see `finish_pending_reset`.
+    pub fn reset_guest(&mut self, guest_id: usize) -> VmmResult {
+        let guest = self.guests.get_mut(guest_id).ok_or(VmmError::NoFound)?;
+        if guest.image.is_none() {
+            return Err(VmmError::NotSupported)
+        }
+        if self.sched.current == Some(guest_id) {
+            // the trap handler still writes to the trap context of the current guest
+            guest.reset_pending = true;
+            return Ok(())
+        }
+        self.do_reset_guest(guest_id);
+        Ok(())
+    }
+
+    /// reset the current guest if a reset was requested during this trap
+    pub fn finish_pending_reset(&mut self) {
+        let guest_id = self.guest_id;
+        if let Some(guest) = self.guests.get_mut(guest_id) {
+            if guest.reset_pending {
+                guest.reset_pending = false;
+                self.do_reset_guest(guest_id);
+            }
+        }
+    }
+
+    /// the guest powered itself off: stop scheduling it, or power off the machine
+    /// if no other guest is left. Paused guests keep the machine on, they may be resumed.
+    pub fn shutdown_guest(&mut self, guest_id: usize) -> VmmResult {
+        let guest = self.guests.get_mut(guest_id).ok_or(VmmError::NoFound)?;
+        guest.state = GuestState::Stopped;
+        self.sched.set_runnable(guest_id, false);
+        // before the machine may go down below
+        self.flush_guest_devices(guest_id);
+        if self.guests.iter().all(|guest| guest.state == GuestState::Stopped) {
+            hdebug!("guest {} powered off, no guest left", guest_id);
+            shutdown()
+        }
+        hdebug!("guest {} powered off", guest_id);
+        Ok(())
+    }
+
+    /// build the stage-2 page table a reset of `guest_id` starts from, without touching
+    /// the guest
+    pub(super) fn reset_gpm(&self, guest_id: usize, shared_text: Option<&SharedText>) -> VmmResult<GuestMemorySet<G>> {
+        #[cfg(feature = "alloc_debug")]
+        let _owner = crate::hyp_alloc::leak::OwnerScope::guest(guest_id);
+        let guest = self.guests.get(guest_id).ok_or(VmmError::NoFound)?;
+        let mut gpm = GuestMemorySet::try_new_guest_without_load(guest_id, &guest.guest_machine, shared_text)?;
+        self.reserve_virtio_windows(&mut gpm, &guest.virtio, &guest.guest_machine)?;
+        if guest.config.firmware {
+            vmachine::reserve_clint(&mut gpm, &guest.guest_machine)?;
+        }
+        Ok(gpm)
+    }
+
+    /// reboot the whole machine once the devices of every guest finished their work
+    pub fn reboot_machine(&mut self) -> ! {
+        let ids: Vec<usize> = self.guests.ids().collect();
+        for guest_id in ids {
+            self.flush_guest_devices(guest_id);
+        }
+        hdebug!("rebooting the machine");
+        reboot()
+    }
+
+    fn do_reset_guest(&mut self, guest_id: usize) {
+        let current = self.sched.current == Some(guest_id);
+        // no job may write to the memory reloaded below, and the devices finish what the
+        // guest handed them before it reboots
+        self.flush_guest_devices(guest_id);
+        // the new stage-2 page table is built first, the guest is left alone if it cannot be.
+        // A relaunch built it already.
+        let gpm = match self.guests.get_mut(guest_id).and_then(|guest| guest.reset_gpm.take()) {
+            Some(gpm) => Ok(gpm),
+            None => self.retry_on_oom(guest_id, |vmm| {
+                let shared_text = vmm.guests.get(guest_id).and_then(|guest| guest.shared_text.as_ref());
+                vmm.reset_gpm(guest_id, shared_text)
+            })
+        };
+        let gpm = match gpm {
+            Ok(gpm) => gpm,
+            Err(err) => {
+                herror!("guest {} reset failed: {:?}, powering it off", guest_id, err);
+                let _ = self.shutdown_guest(guest_id);
+                return
+            }
+        };
+        let guest = self.guests.get_mut(guest_id).unwrap();
+        // reload the ram of the guest's own machine, mapped for the time of each copy
+        let dtb_addr = guest.guest_machine.guest_dtb_addr();
+        gpm.scrub_ram(&guest.guest_machine);
+        guest.image.as_ref().unwrap().load_kernel(guest_id, &guest.config.kernel);
+        if let Some(dtb) = guest.dtb_image.as_ref() {
+            dtb.load(gpa2hpa(dtb_addr, guest_id));
+            fdt::mask_isa(gpa2hpa(dtb_addr, guest_id), dtb.len(), guest.config.hidden_isa);
+        }
+        request_fence_i();
+        // the old stage-2 page table is freed on drop
+        guest.gpm = gpm;
+        // vcpus back to boot state, hart 0 boots the guest again
+        let (_, hstack_top) = hstack_position(guest_id);
+        let entry = guest.config.kernel.entry;
+        guest.trap_ctx = Guest::<G>::boot_context(0, entry, dtb_addr, guest.gpm.token(), hstack_top);
+        let mut harts = Guest::<G>::boot_harts(guest.config.vcpus, entry, dtb_addr, guest.gpm.token(), hstack_top);
+        for (vhart, old) in harts.iter_mut().zip(guest.harts.iter_mut()) {
+            vhart.addr_space = core::mem::take(&mut old.addr_space);
+            vhart.stats = old.stats;
+        }
+        guest.harts = harts;
+        if guest.vcpu.hart != 0 {
+            // the hooks of hart 0 are those of the running hart
+            core::mem::swap(&mut guest.vcpu.addr_space, &mut guest.harts[guest.vcpu.hart].addr_space);
+            core::mem::swap(&mut guest.vcpu.addr_space, &mut guest.harts[0].addr_space);
+            core::mem::swap(&mut guest.vcpu.stats, &mut guest.harts[guest.vcpu.hart].stats);
+            core::mem::swap(&mut guest.vcpu.stats, &mut guest.harts[0].stats);
+        }
+        guest.vcpu.hart = 0;
+        guest.trap_ctx.clear_sstatus_bits(isa::sstatus_clear_bits(guest.misa));
+        guest.trap_ctx.hstatus.set_vtvm(guest.config.trap_vsatp);
+        guest.trap_ctx.hstatus.set_vtw(guest.config.wfi.traps());
+        guest.vs_csrs = GuestVsCsrs::default();
+        guest.fpu = FpuState::default();
+        guest.triggers.reset();
+        guest.next_tick = 0;
+        guest.machine = guest.config.firmware.then(VirtMachine::default);
+        guest.vcpu.vtimecmp = usize::MAX;
+        guest.vcpu.hvip = 0;
+        guest.vcpu.pending_events.clear();
+        guest.reset_pending = false;
+        guest.state = GuestState::Running;
+        if current {
+            let ctx = unsafe{ (TRAP_CONTEXT as *mut TrapContext).as_mut().unwrap() };
+            guest.restore_state(ctx);
+        }
+        self.reset_guest_devices(guest_id);
+        self.sched.set_runnable(guest_id, true);
+        if current {
+            self.program_timer();
+        }
+        hdebug!("guest {} reset", guest_id);
+    }
+
+    /// put emulated devices of a guest back to their power-on state
+    fn reset_guest_devices(&mut self, guest_id: usize) {
+        // peers see their channels unbound, the rebooted guest binds them again
+        self.evtchn_close_all(guest_id);
+        self.grant_reset_guest(guest_id);
+        if let Some(guest) = self.guests.get_mut(guest_id) {
+            guest.devices_mut().into_iter().for_each(|dev| dev.reset());
+            guest.pending_irqs.clear();
+            guest.posted_irqs = None;
+            // a rebooted guest finds its devices by probing
+            guest.device_events.clear();
+            guest.pmu.reset();
+        }
+        if let Some(host_plic) = self.host_plic.as_mut() {
+            for context in self.plic_contexts.guest_contexts(guest_id) {
+                host_plic.claim_complete[context] = 0;
+                // the new shadow pages start out disabled
+                host_plic.clear_enables(context, &self.host_irqs);
+            }
+        }
+    }
+
+    /// finish emulated device work of a guest: jobs on the I/O service hart, then the
+    /// devices, then its console output. PLIC emulation completes synchronously in the trap
+    /// path.
+    fn flush_guest_devices(&mut self, guest_id: usize) {
+        ioservice::quiesce(guest_id);
+        if let Some(guest) = self.guests.get_mut(guest_id) {
+            guest.devices_mut().into_iter().for_each(|dev| dev.flush());
+        }
+        console::flush_guest(guest_id);
+    }
+
+    /// free everything a guest off the scheduler holds, its devices destroyed: the stage-2
+    /// table with the frames it maps, the hypervisor stack, images and device models.
+    /// Debug builds check that the frames of the stage-2 table and of the stack went back
+    /// to the allocator.
+    pub fn destroy_guest(&mut self, guest_id: usize) {
+        let guest = match self.guests.remove(guest_id) {
+            Some(guest) => guest,
+            None => return
+        };
+        self.free_guest(guest_id, guest);
+        // everything of the guest is gone, whatever is still tagged with it leaked
+        #[cfg(feature = "alloc_debug")]
+        crate::hyp_alloc::leak::check_guest(guest_id);
+    }
+
+    fn free_guest(&mut self, guest_id: usize, mut guest: Guest<G>) {
+        // hardware counters and debug triggers go back to the host
+        guest.pmu.reset();
+        guest.triggers.reset();
+        // guest ram is not from the frame allocator, its scrubbing is done here
+        if guest.config.enclave || scrub_policy() != ScrubPolicy::Off {
+            guest.gpm.scrub_ram(&guest.guest_machine);
+        }
+        // no hart keeps translations to the frames freed below
+        arch::flush_stage2_tlb();
+        request_stage2_flush();
+        let owned = guest.gpm.frame_count() + KERNEL_STACK_SIZE / PAGE_SIZE;
+        let live = live_frames();
+        // images may be shared with other guests, the rest of the guest goes on return
+        drop(guest.gpm);
+        hstack_free(&mut self.hpm, guest_id);
+        let freed = live.wrapping_sub(live_frames());
+        if cfg!(debug_assertions) && freed != owned {
+            herror!("guest {} held {} frames, {} were freed", guest_id, owned, freed);
+        }
+    }
+
+    /// take the guest off the machine and free everything it holds, its PCI functions
+    /// are detached
+    pub fn remove_guest(&mut self, guest_id: usize) {
+        self.sched.remove(guest_id);
+        self.destroy_guest_devices(guest_id);
+        if let Some(host_plic) = self.host_plic.as_ref() {
+            for context in self.plic_contexts.guest_contexts(guest_id) {
+                host_plic.clear_enables(context, &self.host_irqs);
+            }
+        }
+        self.plic_contexts.release_guest(guest_id);
+        let functions: Vec<_> = self.guests.get(guest_id)
+            .map(|guest| guest.pci.functions.iter().map(|function| function.bdf).collect())
+            .unwrap_or_default();
+        for bdf in functions {
+            let _ = self.detach_device(guest_id, HotplugDevice::Pci(bdf));
+        }
+        self.iopmp_remove_guest(guest_id);
+        self.evtchn_release_guest(guest_id);
+        self.grant_release_guest(guest_id);
+        self.destroy_guest(guest_id);
+    }
+
+    /// tear down the emulated devices of a guest being killed
+    pub fn destroy_guest_devices(&mut self, guest_id: usize) {
+        ioservice::quiesce(guest_id);
+        if let Some(guest) = self.guests.get_mut(guest_id) {
+            guest.devices_mut().into_iter().for_each(|dev| dev.destroy());
+        }
+        console::flush_guest(guest_id);
+    }
+}
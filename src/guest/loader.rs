@@ -0,0 +1,117 @@
+//! Placement of the guest kernel in guest memory
+//!
+//! The embedded kernel is a flat binary or an ELF file. A flat binary is loaded at
+//! `GUEST_START_PA` and entered at its first byte. The loadable segments of an ELF file
+//! are placed at their physical addresses when all of them fall in guest ram. Kernels
+//! linked elsewhere, xv6 at 0x80000000 or rCore at 0xffffffff80200000, are moved as a
+//! whole: the lowest segment lands at `GUEST_START_PA` and the others keep their distance
+//! to it.
+//!
+//! A vcpu boots with `vsatp` 0, guest virtual addresses are guest physical ones, so the
+//! entry point is moved with the segment holding it. A moved kernel must reach its own
+//! memory pc-relative until it enables paging, as medany code does.
+//!
+//! The pristine image keeps the file as it is, the layout is computed once from it and
+//! used to load the kernel at boot and on every reset, see `GuestImage::load_kernel`.
+
+use alloc::vec;
+use alloc::vec::Vec;
+use xmas_elf::ElfFile;
+use xmas_elf::program::Type;
+
+use crate::constants::layout::{ GUEST_START_PA, GUEST_DEFAULT_SIZE };
+
+/// `file_size` bytes at `offset` of the image, then zeroes up to `mem_size`
+#[derive(Clone, Copy, Debug)]
+pub struct Segment {
+    pub offset: usize,
+    pub file_size: usize,
+    pub mem_size: usize,
+    pub guest_pa: usize
+}
+
+#[derive(Clone, Debug)]
+pub struct KernelLayout {
+    /// guest physical address of the first instruction
+    pub entry: usize,
+    pub segments: Vec<Segment>
+}
+
+fn in_guest_ram(guest_pa: usize, size: usize) -> bool {
+    guest_pa >= GUEST_START_PA && guest_pa.checked_add(size).map_or(false, |end| end <= GUEST_START_PA + GUEST_DEFAULT_SIZE)
+}
+
+impl KernelLayout {
+    /// a flat binary of `len` bytes
+    pub fn flat(len: usize) -> Self {
+        Self {
+            entry: GUEST_START_PA,
+            segments: vec![Segment { offset: 0, file_size: len, mem_size: len, guest_pa: GUEST_START_PA }]
+        }
+    }
+
+    /// layout of the kernel image `data`, `None` if it is an ELF file that is malformed,
+    /// does not fit in guest ram or has its entry point outside of its segments
+    pub fn parse(data: &[u8]) -> Option<Self> {
+        Self::parse_head(data, data.len())
+    }
+
+    /// like `parse` from the first bytes `head` of an image of `len` bytes, which must
+    /// hold the ELF header and the program headers
+    pub fn parse_head(head: &[u8], len: usize) -> Option<Self> {
+        if !head.starts_with(b"\x7fELF") {
+            return Some(Self::flat(len))
+        }
+        let elf = ElfFile::new(head).ok()?;
+        let pt2 = &elf.header.pt2;
+        let ph_end = (pt2.ph_offset() as usize).checked_add(pt2.ph_count() as usize * pt2.ph_entry_size() as usize)?;
+        if ph_end > head.len() {
+            return None
+        }
+        let loads: Vec<_> = elf.program_iter()
+            .filter(|ph| ph.get_type() == Ok(Type::Load) && ph.mem_size() > 0)
+            .collect();
+        let by_paddr = loads.iter().all(|ph| in_guest_ram(ph.physical_addr() as usize, ph.mem_size() as usize));
+        let base = loads.iter().map(|ph| ph.virtual_addr()).min()?;
+        let mut segments = Vec::with_capacity(loads.len());
+        for ph in loads.iter() {
+            let guest_pa = if by_paddr {
+                ph.physical_addr() as usize
+            }else{
+                GUEST_START_PA + (ph.virtual_addr() - base) as usize
+            };
+            let segment = Segment {
+                offset: ph.offset() as usize,
+                file_size: ph.file_size() as usize,
+                mem_size: ph.mem_size() as usize,
+                guest_pa
+            };
+            if segment.file_size > segment.mem_size
+                || segment.offset.checked_add(segment.file_size).map_or(true, |end| end > len)
+                || !in_guest_ram(segment.guest_pa, segment.mem_size) {
+                return None
+            }
+            segments.push(segment);
+        }
+        let entry = elf.header.pt2.entry_point();
+        let entry = loads.iter().zip(segments.iter())
+            .find(|(ph, _)| entry >= ph.virtual_addr() && entry - ph.virtual_addr() < ph.mem_size())
+            .map(|(ph, segment)| segment.guest_pa + (entry - ph.virtual_addr()) as usize)?;
+        hdebug!("elf guest kernel, entry {:#x}, {} segments", entry, segments.len());
+        Some(Self { entry, segments })
+    }
+
+    /// the image is loaded as it is at `GUEST_START_PA`, like a flat binary
+    pub fn verbatim(&self) -> bool {
+        match self.segments.as_slice() {
+            [segment] => segment.offset == 0 && segment.guest_pa == GUEST_START_PA && segment.file_size == segment.mem_size,
+            _ => false
+        }
+    }
+}
+
+impl Default for KernelLayout {
+    fn default() -> Self {
+        Self::flat(0)
+    }
+}
@@ -0,0 +1,103 @@
+//! Emulation of misaligned guest loads and stores
+//!
+//! Load and store address misaligned exceptions are not delegated, hardware without
+//! misaligned access support traps them to the hypervisor. The access is replayed one
+//! byte at a time: every byte is translated on its own through both stages with the
+//! privilege of the guest, so accesses crossing a page boundary work and a byte the
+//! guest cannot reach is reported to it as the page fault the access would have raised.
+//! All bytes are translated before the first one is written, a faulting store leaves
+//! memory untouched.
+//!
+//! Integer loads and stores, compressed ones included, are emulated. Floating point
+//! and atomic accesses are forwarded to the guest as they are.
+
+use alloc::vec::Vec;
+
+use super::page_table::GuestPageTable;
+use super::pmap::{ two_stage_translation, fetch_guest_inst, decode_inst, read_host, write_host };
+use super::vmexit::{ TrapContext, forward_exception, inject_exception };
+use crate::device_emu::mmio::MmioAccess;
+use crate::arch::GuestException;
+use crate::hypervisor::HostVmm;
+use crate::mm::GuestMemorySet;
+use crate::page_table::{ PageTable, AccessType, WalkContext, WalkFault };
+use crate::{ VmmError, VmmResult };
+
+
+/// host addresses of the `width` bytes at `guest_va`, or the exception and address of the
+/// first byte that cannot be reached
+fn translate_bytes<G: GuestPageTable>(
+    guest_va: usize, width: usize, access: AccessType, walk: &WalkContext, gpm: &GuestMemorySet<G>
+) -> Result<Vec<usize>, (GuestException, usize)> {
+    let store = access == AccessType::Write;
+    (0..width).map(|i| {
+        let byte_va = guest_va.wrapping_add(i);
+        two_stage_translation(byte_va, access, walk, gpm).map_err(|fault| match (fault, store) {
+            (WalkFault::PageFault, false) => (GuestException::LoadPageFault, byte_va),
+            (WalkFault::PageFault, true) => (GuestException::StorePageFault, byte_va),
+            // emulated MMIO and holes in guest memory, nothing answers a byte access there
+            (WalkFault::GuestPageFault { .. }, false) => (GuestException::LoadAccessFault, byte_va),
+            (WalkFault::GuestPageFault { .. }, true) => (GuestException::StoreAccessFault, byte_va)
+        })
+    }).collect()
+}
+
+/// emulate the load or store of a load/store address misaligned exception
+pub fn misaligned_access_handler<P: PageTable, G: GuestPageTable>(host_vmm: &HostVmm<P, G>, ctx: &mut TrapContext) -> VmmResult {
+    let guest_va = ctx.stval;
+    let walk = WalkContext::current();
+    let raw = fetch_guest_inst(host_vmm.guest_id, ctx.sepc, &walk).ok_or(VmmError::TranslationError)?;
+    let (len, access) = match decode_inst(raw) {
+        (2, _) => (2, MmioAccess::decode_compressed(ctx, raw)),
+        (len, Some(inst)) => (len, MmioAccess::decode(ctx, inst).ok()),
+        (len, None) => (len, None)
+    };
+    let access = match access {
+        Some(MmioAccess::Amo { .. }) | None => {
+            // not an integer access we know, the guest may handle it itself
+            forward_exception(ctx);
+            return Ok(())
+        },
+        Some(access) => access
+    };
+    let gpm = &host_vmm.guests.get(host_vmm.guest_id).ok_or(VmmError::NoFound)?.gpm;
+    let (width, access_type) = match access {
+        MmioAccess::Load { width, .. } => (width, AccessType::Read),
+        MmioAccess::Store { width, .. } | MmioAccess::Amo { width, .. } => (width, AccessType::Write)
+    };
+    let bytes = match translate_bytes(guest_va, width, access_type, &walk, gpm) {
+        Ok(bytes) => bytes,
+        Err((exception, fault_va)) => {
+            inject_exception(ctx, exception, fault_va);
+            return Ok(())
+        }
+    };
+    // each byte is mapped into the host for its access, a byte the host cannot map faults
+    // like a hole in guest memory
+    match access {
+        MmioAccess::Load { rd, width, signed } => {
+            let value = bytes.iter().enumerate().try_fold(0, |value, (i, &host_pa)| {
+                read_host::<u8>(host_pa).map(|byte| value | (byte as usize) << (8 * i)).ok_or(i)
+            });
+            match value {
+                Ok(value) => MmioAccess::complete_load(ctx, rd, width, signed, value),
+                Err(i) => {
+                    inject_exception(ctx, GuestException::LoadAccessFault, guest_va.wrapping_add(i));
+                    return Ok(())
+                }
+            }
+        },
+        MmioAccess::Store { value, .. } => {
+            for (i, &host_pa) in bytes.iter().enumerate() {
+                if write_host(host_pa, (value >> (8 * i)) as u8).is_none() {
+                    inject_exception(ctx, GuestException::StoreAccessFault, guest_va.wrapping_add(i));
+                    return Ok(())
+                }
+            }
+        },
+        // forwarded to the guest above
+        MmioAccess::Amo { .. } => {}
+    }
+    ctx.sepc += len;
+    Ok(())
+}
@@ -1,148 +1,381 @@
-use crate::constants::layout::{TRAP_CONTEXT, GUEST_START_VA};
+use alloc::boxed::Box;
+use alloc::collections::VecDeque;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use crate::constants::riscv_regs::GprIndex;
 use crate::hypervisor::fdt::MachineMeta;
-use crate::mm::{ GuestMemorySet, MemorySet };
+use crate::mm::{ GuestMemorySet, MemorySet, PageTransform, XorTransform, TAG_SIZE };
 use crate::hypervisor::{ stack::hstack_alloc};
+use crate::device_emu::rtc::GoldfishRtc;
+use crate::device_emu::watchdog::SifiveWatchdog;
+use crate::device_emu::pci::VirtualEcam;
+use crate::device_emu::virtio::EmulatedVirtio;
+use crate::device_emu::hotplug::DeviceEvent;
+use crate::secure_boot::BootVerdict;
+use riscv::register::time;
+use crate::arch::{ self, Irq };
 use vmexit::{TrapContext, trap_handler};
 
+use self::context::GuestVsCsrs;
+use self::fpu::FpuState;
+pub use self::context::read_htimedelta;
 use self::page_table::GuestPageTable;
-use self::vcpu::VCpu;
+use self::vcpu::{ VCpu, VHart, HartState };
+use self::pmu::VirtualPmu;
+use self::triggers::VirtualTriggers;
+use self::quirks::{ GuestOs, Quirks };
+use self::vmachine::VirtMachine;
+use self::posted::PostedIrqPage;
+use self::evtchn::EventChannels;
+use self::grant::GrantTable;
+use self::slots::StandbySlot;
+pub use self::vcpu::VCpuStats;
 pub use sbi::SbiRet;
+pub use config::{ GuestConfig, IrqCoalesce, RtPartition };
+pub use image::{ GuestImage, SharedText };
+pub use loader::KernelLayout;
+pub use isa::IsaMask;
+pub use table::{ GuestTable, GuestId };
 
 mod context;
 mod vcpu;
 mod sbi;
+mod config;
+mod hypercall;
+mod lifecycle;
+mod image;
+mod loader;
+mod slots;
+mod isa;
+pub mod fpu;
+mod hsm;
+pub mod pmu;
+pub mod triggers;
+mod table;
+mod misaligned;
+mod decode;
+pub mod counters;
+pub mod envcfg;
+pub mod wfi;
+pub mod quirks;
+pub mod vmachine;
+pub mod sealing;
+pub mod paranoid;
+pub mod csrcheck;
+pub mod addrspace;
+pub mod fastpath;
 pub mod vmexit;
+pub mod posted;
+pub mod evtchn;
+pub mod grant;
 
 
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum GuestState {
+    Running,
+    /// not scheduled until resumed
+    Paused,
+    /// guest shut itself down
+    Stopped
+}
+
 pub struct Guest<G: GuestPageTable> {
     pub guest_machine: MachineMeta,
     /// guest memory set
     pub gpm: GuestMemorySet<G>,
     /// guest id
     pub guest_id: usize,
-    /// virtual cpu status
-    pub vcpu: VCpu
+    /// virtual cpu status, `vcpu.hart` is the virtual hart on the cpu
+    pub vcpu: VCpu,
+    /// all virtual harts of the guest, indexed by virtual hart id
+    pub harts: Vec<VHart>,
+    /// guest configuration
+    pub config: GuestConfig,
+    /// saved trap context of the running hart while the guest is descheduled
+    pub trap_ctx: TrapContext,
+    /// saved VS-level CSRs of the running hart while the guest is descheduled
+    pub vs_csrs: GuestVsCsrs,
+    /// saved FP and vector registers of the running hart while the guest is descheduled
+    pub fpu: FpuState,
+    /// virtual `misa` of the guest harts, see `isa`
+    pub misa: usize,
+    pub state: GuestState,
+    /// time at which the guest was paused
+    pub paused_at: usize,
+    /// pristine guest kernel image, required to reset the guest, shared by the guests booting it
+    pub image: Option<Arc<GuestImage>>,
+    /// kernel staged for a relaunch, or the one booted before it, see `slots`
+    pub standby: StandbySlot,
+    /// kernel text mapped from `image` instead of guest memory, kept across resets
+    pub shared_text: Option<SharedText>,
+    /// pristine guest device tree
+    pub dtb_image: Option<GuestImage>,
+    /// reset requested while the guest was on the cpu, done at the end of the trap
+    pub reset_pending: bool,
+    /// stage-2 page table a relaunch built for the next reset, see `slots`
+    pub reset_gpm: Option<GuestMemorySet<G>>,
+    /// emulated goldfish RTC
+    pub rtc: GoldfishRtc,
+    /// emulated watchdog, used if the guest device tree has one
+    pub watchdog: SifiveWatchdog,
+    /// PCI functions assigned to the guest
+    pub pci: VirtualEcam,
+    /// virtual interrupts waiting for the claim register of the emulated PLIC
+    pub pending_irqs: VecDeque<u32>,
+    /// page the guest registered for posted interrupts, see `posted`
+    pub posted_irqs: Option<PostedIrqPage>,
+    /// event channels of the guest, see `evtchn`
+    pub evtchn: EventChannels,
+    /// pages the guest granted to other guests or the hypervisor, see `grant`
+    pub grants: GrantTable,
+    /// virtio devices emulated by the hypervisor
+    pub virtio: Vec<EmulatedVirtio>,
+    /// devices attached or detached at runtime, not yet fetched by the guest
+    pub device_events: VecDeque<DeviceEvent>,
+    /// firmware counters of the virtual SBI PMU
+    pub pmu: VirtualPmu,
+    /// debug triggers of the running hart, see `triggers`
+    pub triggers: VirtualTriggers,
+    /// OS of the guest as far as it is known, see `quirks`
+    pub os: GuestOs,
+    /// behaviors the guest OS expects
+    pub quirks: Quirks,
+    /// guest time of the next tick of the `tick` quirk, 0 until it is armed
+    pub next_tick: usize,
+    /// emulated machine mode of a guest booting its own firmware, see `vmachine`
+    pub machine: Option<VirtMachine>,
+    /// encrypts pages leaving an enclave guest, see `mm::transform`
+    pub transform: Option<Box<dyn PageTransform>>,
+    /// hash of the guest images, sealed storage is bound to it, see `sealing`
+    pub measurement: [u8; TAG_SIZE],
+    /// signature check of the guest images, see `secure_boot`
+    pub boot_verdict: BootVerdict
 }
 
 impl<G: GuestPageTable> Guest<G> {
-    pub fn new(guest_id: usize, gpm: GuestMemorySet<G>, guest_machine: MachineMeta) -> Self {
+    pub fn new(guest_id: usize, mut gpm: GuestMemorySet<G>, guest_machine: MachineMeta, config: GuestConfig) -> Self {
         // 分配 hypervisor 内核栈
+        // the stack and the host page table frames mapping it belong to the hypervisor
         let hstack = hstack_alloc(guest_id);
         let hstack_top = hstack.get_top();
-        // 获取 trap context
-        let trap_ctx: &mut TrapContext = unsafe{ (TRAP_CONTEXT as *mut TrapContext).as_mut().unwrap() };
+        // 在 guest 被调度时才会被加载到 TRAP_CONTEXT
+        // 虚拟 hart id 从 0 开始，与物理 hart 无关
+        let dtb = guest_machine.guest_dtb_addr();
+        let mut trap_ctx = Self::boot_context(0, config.kernel.entry, dtb, gpm.token(), hstack_top);
+        let misa = config.hidden_isa.misa(guest_machine.cpus.first().map_or("", |cpu| cpu.isa.as_str()));
+        trap_ctx.clear_sstatus_bits(isa::sstatus_clear_bits(misa));
+        trap_ctx.hstatus.set_vtvm(config.trap_vsatp);
+        trap_ctx.hstatus.set_vtw(config.wfi.traps());
+        let harts = Self::boot_harts(config.vcpus, config.kernel.entry, dtb, gpm.token(), hstack_top);
+        if config.enclave {
+            gpm.set_scrub_frames();
+        }
+        let transform: Option<Box<dyn PageTransform>> = if config.enclave { Some(Box::new(XorTransform::new())) } else { None };
+        Self {
+            guest_id,
+            gpm,
+            guest_machine,
+            vcpu: VCpu::new(0),
+            harts,
+            trap_ctx,
+            vs_csrs: GuestVsCsrs::default(),
+            fpu: FpuState::default(),
+            misa,
+            state: GuestState::Running,
+            paused_at: 0,
+            image: None,
+            standby: StandbySlot::default(),
+            shared_text: None,
+            dtb_image: None,
+            reset_pending: false,
+            reset_gpm: None,
+            rtc: GoldfishRtc::new(config.rtc_offset),
+            watchdog: SifiveWatchdog::default(),
+            pci: VirtualEcam::default(),
+            pending_irqs: VecDeque::new(),
+            posted_irqs: None,
+            evtchn: EventChannels::default(),
+            grants: GrantTable::default(),
+            virtio: Vec::new(),
+            device_events: VecDeque::new(),
+            pmu: VirtualPmu::default(),
+            triggers: VirtualTriggers::default(),
+            os: config.os,
+            quirks: config.quirks.unwrap_or(config.os.profile()),
+            next_tick: 0,
+            machine: config.firmware.then(VirtMachine::default),
+            transform,
+            measurement: [0; TAG_SIZE],
+            boot_verdict: BootVerdict::Unchecked,
+            config
+        }
+    }
+
+    /// trap context of a vcpu following the boot protocol: a0 = hart id, a1 = `arg`, the
+    /// device tree at boot, entered at guest physical address `entry` with `vsatp` 0
+    pub fn boot_context(hart: usize, entry: usize, arg: usize, hgatp: usize, hstack_top: usize) -> TrapContext {
         // 初始化 trap context 的环境
         // 包括入口地址/栈寄存器/satp/内核栈寄存器/trap处理地址
-        *trap_ctx = TrapContext::initialize_context(
-            GUEST_START_VA,
+        let mut trap_ctx = TrapContext::initialize_context(
+            entry,
             0,
-            gpm.token(),
+            hgatp,
             hstack_top,
             trap_handler as usize
         );
-        Self {
-            guest_id,
-            gpm,
-            guest_machine,
-            vcpu: VCpu::new(guest_id),
-        }
+        trap_ctx.x[GprIndex::A0 as usize] = hart;
+        trap_ctx.x[GprIndex::A1 as usize] = arg;
+        trap_ctx
     }
 
+    /// `count` virtual harts at power-on: hart 0 boots the guest, the others wait for HSM hart_start
+    pub fn boot_harts(count: usize, entry: usize, dtb: usize, hgatp: usize, hstack_top: usize) -> Vec<VHart> {
+        (0..count.max(1)).map(|hart| {
+            let state = if hart == 0 { HartState::Started } else { HartState::Stopped };
+            VHart::new(state, Self::boot_context(hart, entry, dtb, hgatp, hstack_top))
+        }).collect()
+    }
 
-    pub fn run(&mut self) {
-        todo!()
+    /// save guest state when the guest leaves the cpu
+    pub fn save_state(&mut self, ctx: &TrapContext) {
+        unsafe{ core::ptr::copy_nonoverlapping(ctx as *const TrapContext, &mut self.trap_ctx as *mut TrapContext, 1) };
+        self.vs_csrs.save();
+        self.fpu.save(&mut self.trap_ctx);
+        self.pmu.switch_out();
+        self.triggers.switch_out();
+        self.vcpu.hvip = arch::guest_irqs();
+        self.vcpu.vtimecmp = fastpath::vtimecmp();
+    }
+
+    /// restore guest state when the guest enters the cpu
+    pub fn restore_state(&mut self, ctx: &mut TrapContext) {
+        unsafe{ core::ptr::copy_nonoverlapping(&self.trap_ctx as *const TrapContext, ctx as *mut TrapContext, 1) };
+        self.vs_csrs.restore();
+        self.fpu.restore(&self.trap_ctx);
+        self.pmu.switch_in();
+        self.triggers.switch_in(self.guest_id);
+        arch::set_guest_irqs(csrcheck::hvip(self.vcpu.hvip));
+        // guest timer may have expired while the guest was descheduled
+        if time::read().wrapping_add(self.vs_csrs.htimedelta()) >= self.vcpu.vtimecmp {
+            self.vcpu.vtimecmp = usize::MAX;
+            arch::raise_guest_irq(Irq::Timer);
+        }
+        fastpath::set_vtimecmp(self.vcpu.vtimecmp);
+        fastpath::set_current_guest(self.guest_id);
+        fastpath::set_legacy_fast(self.legacy_fast());
+        fastpath::set_guest_firmware(self.machine.is_some());
+        counters::load(self.config.counters);
+        envcfg::load(self.config.envcfg);
     }
 }
 
 
 pub mod page_table {
     use crate::page_table::PageTable;
+    use crate::VmmResult;
 
     pub trait GuestPageTable: PageTable {
-        fn new_guest() -> Self;
+        /// root page table of a guest, panics if no frame is left
+        fn new_guest() -> Self {
+            Self::try_new_guest().expect("out of frames for guest page table")
+        }
+        fn try_new_guest() -> VmmResult<Self>;
     }
 }
 
 pub mod pmap {
+    use core::mem::size_of;
     use riscv_decode::Instruction;
 
-    use crate::{mm::{MemorySet, GuestMemorySet}, page_table::translate_guest_va};
+    use crate::mm::GuestMemorySet;
+    use crate::mm::framemap::with_guest_ram;
+    use crate::page_table::{ AccessType, WalkContext, WalkFault, walk_guest };
+    use crate::constants::layout::{ GUEST_DTB_ADDR, GUEST_START_PA, GUEST_DEFAULT_SIZE, GUEST_WINDOW_SIZE };
     use super::page_table::GuestPageTable;
+    pub use super::decode::decode_inst;
     // use riscv_decode;
 
-    #[allow(unused)]
-    mod segment_layout {
-        pub const GUEST_SEGMENT_SIZE: usize = 128 * 1024 * 1024;
-    }
-
+    /// host address of guest ram at `va` of guest `guest_id`, see `GUEST_WINDOW_SIZE`
     pub fn gpa2hpa(va: usize, guest_id: usize) -> usize {
-        va + guest_id * segment_layout::GUEST_SEGMENT_SIZE
+        va + guest_id * GUEST_WINDOW_SIZE
     }
 
     pub fn hpa2gpa(pa: usize, guest_id: usize) -> usize {
-        pa - guest_id * segment_layout::GUEST_SEGMENT_SIZE
-    }
-
-    pub fn two_stage_translation<G: GuestPageTable>(guest_id: usize, guest_va: usize, vsatp: usize, gpm: &GuestMemorySet<G>) -> Option<usize> {
-        let guest_root = (vsatp & 0x3ff_ffff_ffff) << 12;
-        let guest_pa;
-        if guest_root != 0 {
-            if let Some(translation) = translate_guest_va::<G>(guest_id, guest_root, guest_va) {
-                guest_pa = translation.guest_pa;
-                // htracking!("guest pa: {:#x}", guest_pa);
-            }else{
-                return None
-            }
-        }else{
-            guest_pa = guest_va;
-        }
-        if let Some(host_va) = gpm.translate_va(guest_pa) {
-            Some(host_va)
-        }else{
+        pa - guest_id * GUEST_WINDOW_SIZE
+    }
+
+    /// whether `[guest_pa, guest_pa + len)` is guest ram
+    pub fn is_guest_ram(guest_pa: usize, len: usize) -> bool {
+        guest_pa >= GUEST_DTB_ADDR && guest_pa.checked_add(len).map_or(false, |end| end <= GUEST_START_PA + GUEST_DEFAULT_SIZE)
+    }
+
+    /// run `f` on guest physical memory of guest `guest_id`, mapped into the host for the
+    /// time of the call. `None` if the range is not guest ram.
+    pub fn with_guest_memory<R>(guest_id: usize, guest_pa: usize, len: usize, f: impl FnOnce(&mut [u8]) -> R) -> Option<R> {
+        if !is_guest_ram(guest_pa, len) {
             return None
         }
-        
-    }
-
-    pub fn fast_two_stage_translation<G: GuestPageTable>(guest_id: usize, guest_va: usize, vsatp: usize) -> Option<usize> {
-        let guest_root = (vsatp & 0x3ff_ffff_ffff) << 12;
-        let guest_pa;
-        if guest_root != 0 {
-            if let Some(translation) = translate_guest_va::<G>(guest_id, guest_root, guest_va) {
-                guest_pa = translation.guest_pa;
-                // htracking!("guest pa: {:#x}", guest_pa);
-            }else{
-                return None
-            }
-        }else{
-            guest_pa = guest_va;
-        }
-        Some(guest_pa)
-        
+        with_guest_ram(gpa2hpa(guest_pa, guest_id), len, f)
     }
 
+    /// read a `T` at `host_pa`, a host address a translation of guest memory ended at
+    pub fn read_host<T: Copy>(host_pa: usize) -> Option<T> {
+        with_guest_ram(host_pa, size_of::<T>(), |bytes| unsafe{ core::ptr::read_unaligned(bytes.as_ptr() as *const T) })
+    }
 
-    pub fn decode_inst_at_addr(host_va: usize) -> (usize, Option<Instruction>) {
-        let i1 = unsafe{ core::ptr::read(host_va as *const u16) };
-        let len = riscv_decode::instruction_length(i1);
-        let inst = match len {
-            2 => i1 as u32, 
-            4 => unsafe{ core::ptr::read(host_va as *const u32) },
-            _ => unreachable!()
+    /// write a `T` at `host_pa`, a host address a translation of guest memory ended at
+    pub fn write_host<T: Copy>(host_pa: usize, value: T) -> Option<()> {
+        with_guest_ram(host_pa, size_of::<T>(), |bytes| unsafe{ core::ptr::write_unaligned(bytes.as_mut_ptr() as *mut T, value) })
+    }
+
+    /// a guest page table entry, an entry the host cannot map reads as invalid
+    fn read_host_pte(host_pa: usize) -> usize {
+        read_host(host_pa).unwrap_or(0)
+    }
+
+    /// translate `guest_va` for `access` through both stages of the guest, return the host address
+    pub fn two_stage_translation<G: GuestPageTable>(
+        guest_va: usize, access: AccessType, walk: &WalkContext, gpm: &GuestMemorySet<G>
+    ) -> Result<usize, WalkFault> {
+        walk_guest(guest_va, access, walk, |guest_pa, access| gpm.translate_guest_pa(guest_pa, access), read_host_pte)
+            .map(|translation| translation.host_pa)
+    }
+
+    /// like `two_stage_translation` without the stage-2 table, the ram of every guest is
+    /// its window of host memory
+    pub fn fast_two_stage_translation(guest_id: usize, guest_va: usize, access: AccessType, walk: &WalkContext) -> Option<usize> {
+        let stage2 = |guest_pa, _| is_guest_ram(guest_pa, 1).then(|| gpa2hpa(guest_pa, guest_id));
+        walk_guest(guest_va, access, walk, stage2, read_host_pte)
+            .ok()
+            .map(|translation| translation.host_pa)
+    }
+
+    /// read the instruction at `guest_va`, each half translated on its own: with paging
+    /// the halves of an instruction crossing a page may be far apart, with `vsatp` Bare
+    /// they are adjacent guest physical addresses
+    pub fn fetch_guest_inst(guest_id: usize, guest_va: usize, walk: &WalkContext) -> Option<usize> {
+        let read_half = |guest_va: usize| {
+            fast_two_stage_translation(guest_id, guest_va, AccessType::Execute, walk)
+                .and_then(read_host::<u16>)
+                .map(|half| half as usize)
         };
-        (len, riscv_decode::decode(inst).ok())
+        let low = read_half(guest_va)?;
+        if riscv_decode::instruction_length(low as u16) == 4 {
+            Some(read_half(guest_va + 2)? << 16 | low)
+        }else{
+            Some(low)
+        }
     }
 
-    /// decode risc-v instruction, return (inst len, inst)
-    pub fn decode_inst(inst: usize) -> (usize, Option<Instruction>) {
-        let i1 = inst as u16;
-        let len = riscv_decode::instruction_length(i1);
-        let inst = match len {
-            2 => i1 as u32,
-            4 => inst as u32,
-            _ => unreachable!()
+
+    pub fn decode_inst_at_addr(host_va: usize) -> (usize, Option<Instruction>) {
+        let low = read_host::<u16>(host_va).unwrap_or(0) as usize;
+        // the upper half may be on the next page, only read it for 32-bit instructions
+        let high = match riscv_decode::instruction_length(low as u16) {
+            4 => read_host::<u16>(host_va + 2).unwrap_or(0) as usize,
+            _ => 0
         };
-        (len, riscv_decode::decode(inst).ok())
+        decode_inst(high << 16 | low)
     }
 }
 
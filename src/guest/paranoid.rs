@@ -0,0 +1,47 @@
+//! Paranoid switch, a side-channel hardening mode for security experiments
+//!
+//! When the scheduler switches away from a guest with `GuestConfig::paranoid_switch`, the
+//! hart is cleaned of what the guest left in it: the instruction cache with `fence.i`, all
+//! stage-2, VS-stage and host TLB entries, and the L1 data cache by reading an eviction
+//! buffer larger than it, there is no standard instruction to flush a whole cache. The
+//! switch is then padded to a fixed length, so the next guest cannot time how much state
+//! the previous one had.
+//!
+//! Guests are all time-sliced on the boot hart, two guests never run at the same time on
+//! sibling harts, so no core scheduling is needed on top.
+
+use core::sync::atomic::{ fence, Ordering };
+use riscv::register::time;
+
+use crate::arch;
+
+/// twice the largest L1 data cache of supported cores
+const L1_EVICT_SIZE: usize = 128 * 1024;
+/// cache line size assumed for the eviction reads
+const CACHE_LINE: usize = 64;
+/// fixed length of a paranoid switch, in timer ticks
+const PARANOID_SWITCH_TICKS: usize = 2000;
+
+static EVICT_BUFFER: [u8; L1_EVICT_SIZE] = [0; L1_EVICT_SIZE];
+
+/// flush caches and TLBs of the hart after a paranoid guest ran
+pub fn flush_hart() {
+    arch::sync_icache();
+    arch::flush_stage2_tlb();
+    arch::flush_guest_tlb();
+    arch::flush_host_tlb();
+    for offset in (0..L1_EVICT_SIZE).step_by(CACHE_LINE) {
+        unsafe{ core::ptr::read_volatile(EVICT_BUFFER.as_ptr().add(offset)) };
+    }
+    fence(Ordering::SeqCst);
+}
+
+/// wait until the switch started at `start` took `PARANOID_SWITCH_TICKS`
+pub fn pad_switch(start: usize) {
+    if time::read() - start > PARANOID_SWITCH_TICKS {
+        hwarning!("paranoid switch took longer than {} ticks", PARANOID_SWITCH_TICKS);
+    }
+    while time::read() - start < PARANOID_SWITCH_TICKS {
+        core::hint::spin_loop();
+    }
+}
@@ -0,0 +1,353 @@
+//! Virtual SBI PMU
+//!
+//! Firmware counters count hypervisor activity on behalf of the guest. They are configured
+//! with the SBI platform-specific firmware event `SBI_PMU_EVENT_FW_PLATFORM`, `event_data`
+//! selects one of the `PMU_EVENT_*` events. Guest perf can put them next to its own events
+//! to tell whether the hypervisor is the reason the guest is slow.
+//!
+//! The hardware counters of the host SBI PMU come first, with their host indexes, and are
+//! configured through the host SBI on behalf of the guest. A guest only starts, stops and
+//! releases the hardware counters it configured, they are stopped while it is off the cpu
+//! and it reads them directly, see `counters`. The firmware counters follow them.
+//!
+//! With Sscofpmf the overflow of a hardware counter raises a local counter overflow
+//! interrupt (LCOFI) in the hypervisor, which is injected into the guest owning the
+//! counter through `hvien`, so guest perf can sample. The guest finds the counter in
+//! `scountovf` itself. Without Sscofpmf or AIA hardware counters only count.
+//!
+//! Counters are shared by all harts of a guest.
+
+use core::sync::atomic::{ AtomicBool, AtomicUsize, Ordering };
+
+use super::page_table::GuestPageTable;
+use super::vcpu::VCpuStats;
+use crate::arch::{ self, Irq };
+use crate::detect;
+use crate::hypervisor::HostVmm;
+use crate::page_table::PageTable;
+use crate::sbi::{
+    self, SBI_SUCCESS, SBI_ERR_NOT_SUPPORTED, SBI_ERR_INAVLID_PARAM, SBI_ERR_ALREADY_STARTED, SBI_ERR_ALREADY_STOPPED,
+    SBI_PMU_NUM_COUNTERS_FID, SBI_PMU_COUNTER_GET_INFO_FID, SBI_PMU_COUNTER_CONFIG_MATCHING_FID,
+    SBI_PMU_COUNTER_START_FID, SBI_PMU_COUNTER_STOP_FID,
+    SBI_PMU_EVENT_FW_PLATFORM, PMU_EVENT_EXITS, PMU_EVENT_STEAL_CYCLES, PMU_EVENT_MMIO_EXITS
+};
+
+/// firmware counters of a guest
+pub const PMU_COUNTERS: usize = 8;
+/// hardware counters are the first ones of the host, at most one per counter csr
+const MAX_HW_COUNTERS: usize = 32;
+/// `event_idx` type of firmware events
+const EVENT_TYPE_FW: usize = 0xf;
+
+/// hardware counters of the host SBI PMU
+static HW_COUNTERS: AtomicUsize = AtomicUsize::new(0);
+/// counter overflows are taken and injected into guests
+static VIRTUAL_LCOFI: AtomicBool = AtomicBool::new(false);
+
+/// `counter_config_matching` flags
+const CFG_FLAG_SKIP_MATCH: usize = 1 << 0;
+const CFG_FLAG_CLEAR_VALUE: usize = 1 << 1;
+const CFG_FLAG_AUTO_START: usize = 1 << 2;
+/// `counter_start` flags
+const START_FLAG_SET_INIT_VALUE: usize = 1 << 0;
+/// `counter_stop` flags
+const STOP_FLAG_RESET: usize = 1 << 0;
+
+/// `counter_get_info` of a firmware counter: type bit set, 64 bits wide
+const FW_COUNTER_INFO: usize = 1 << 63 | 63 << 12;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HypEvent {
+    /// traps into the hypervisor
+    Exits,
+    /// cycles runnable but not on the cpu
+    StealCycles,
+    /// guest page faults emulated as MMIO accesses
+    MmioExits
+}
+
+impl HypEvent {
+    fn from_event_data(data: usize) -> Option<Self> {
+        match data {
+            PMU_EVENT_EXITS => Some(HypEvent::Exits),
+            PMU_EVENT_STEAL_CYCLES => Some(HypEvent::StealCycles),
+            PMU_EVENT_MMIO_EXITS => Some(HypEvent::MmioExits),
+            _ => None
+        }
+    }
+
+    fn read(&self, stats: &VCpuStats) -> usize {
+        match self {
+            HypEvent::Exits => stats.exits,
+            HypEvent::StealCycles => stats.wait_time,
+            HypEvent::MmioExits => stats.mmio_exits
+        }
+    }
+}
+
+#[derive(Clone, Copy, Default)]
+struct Counter {
+    /// `None` while the counter is free
+    event: Option<HypEvent>,
+    running: bool,
+    /// value counted before the last start
+    value: usize,
+    /// event value at the last start
+    start: usize
+}
+
+impl Counter {
+    fn read(&self, stats: &VCpuStats) -> usize {
+        match self.event {
+            Some(event) if self.running => self.value.wrapping_add(event.read(stats).wrapping_sub(self.start)),
+            _ => self.value
+        }
+    }
+}
+
+/// find the hardware counters of the host and whether their overflows can be injected,
+/// called once at boot
+pub fn init() {
+    let (error, total) = sbi::pmu_call(SBI_PMU_NUM_COUNTERS_FID, [0; 5]);
+    if error != SBI_SUCCESS as isize {
+        hdebug!("no host SBI PMU, guests get firmware counters only");
+        return
+    }
+    // the type bit is clear for hardware counters
+    let hw = (0..total.min(MAX_HW_COUNTERS))
+        .take_while(|&counter| matches!(sbi::pmu_call(SBI_PMU_COUNTER_GET_INFO_FID, [counter, 0, 0, 0, 0]), (0, info) if info >> 63 == 0))
+        .count();
+    HW_COUNTERS.store(hw, Ordering::Relaxed);
+    let lcofi = detect::detect_lcofi();
+    if !lcofi {
+        arch::mask_irq(Irq::CounterOverflow);
+    }
+    VIRTUAL_LCOFI.store(lcofi, Ordering::Relaxed);
+    hdebug!("{} hardware counters for guests, overflow interrupts {}", hw, if lcofi { "injected" } else { "unavailable" });
+}
+
+fn hw_counters() -> usize {
+    HW_COUNTERS.load(Ordering::Relaxed)
+}
+
+/// host counters that overflowed, the pending overflow interrupt is cleared
+fn take_overflow() -> usize {
+    let overflowed: usize;
+    unsafe{ core::arch::asm!("csrr {}, 0xda0", out(reg) overflowed) }; // 0xda0 => scountovf
+    arch::clear_irq(Irq::CounterOverflow);
+    overflowed
+}
+
+#[derive(Default)]
+pub struct VirtualPmu {
+    counters: [Counter; PMU_COUNTERS],
+    /// host hardware counters configured by the guest
+    hw_owned: usize,
+    /// host hardware counters started by the guest
+    hw_running: usize
+}
+
+/// counters selected by `counter_idx_base` and `counter_idx_mask`: the hardware ones as a
+/// mask of host counters, the firmware ones as indexes of `VirtualPmu::counters`
+fn selected(base: usize, mask: usize) -> Result<(usize, impl Iterator<Item = usize> + Clone), isize> {
+    let hw = hw_counters();
+    let total = hw + PMU_COUNTERS;
+    let last = usize::BITS as usize - mask.leading_zeros() as usize;
+    if mask == 0 || base >= total || last > total - base {
+        return Err(SBI_ERR_INAVLID_PARAM)
+    }
+    let counters = (0..last).filter(move |bit| mask >> bit & 1 != 0).map(move |bit| base + bit);
+    let hw_mask = counters.clone().filter(|&counter| counter < hw).fold(0, |hw_mask, counter| hw_mask | 1 << counter);
+    Ok((hw_mask, counters.filter(move |&counter| counter >= hw).map(move |counter| counter - hw)))
+}
+
+impl VirtualPmu {
+    pub fn num_counters(&self) -> usize {
+        hw_counters() + PMU_COUNTERS
+    }
+
+    pub fn counter_info(&self, counter: usize) -> Result<usize, isize> {
+        if counter < hw_counters() {
+            return match sbi::pmu_call(SBI_PMU_COUNTER_GET_INFO_FID, [counter, 0, 0, 0, 0]) {
+                (0, info) => Ok(info),
+                (error, _) => Err(error)
+            }
+        }
+        if counter >= self.num_counters() {
+            return Err(SBI_ERR_INAVLID_PARAM)
+        }
+        Ok(FW_COUNTER_INFO)
+    }
+
+    /// count the hardware event `event_idx` with a free host counter among `hw_mask`
+    fn config_hw(&mut self, hw_mask: usize, flags: usize, event_idx: usize, event_data: usize) -> Result<usize, isize> {
+        // a matched counter is free on the host, a reused one must be one of ours
+        if hw_mask == 0 || flags & CFG_FLAG_SKIP_MATCH != 0 && hw_mask & !self.hw_owned != 0 {
+            return Err(if hw_mask == 0 { SBI_ERR_NOT_SUPPORTED } else { SBI_ERR_INAVLID_PARAM })
+        }
+        let counter = match sbi::pmu_call(SBI_PMU_COUNTER_CONFIG_MATCHING_FID, [0, hw_mask, flags, event_idx, event_data]) {
+            (0, counter) => counter,
+            (error, _) => return Err(error)
+        };
+        self.hw_owned |= 1 << counter;
+        if flags & CFG_FLAG_AUTO_START != 0 {
+            self.hw_running |= 1 << counter;
+        }
+        Ok(counter)
+    }
+
+    /// find a free counter among the selected ones and count `event_idx` with it
+    pub fn config_matching(
+        &mut self, base: usize, mask: usize, flags: usize,
+        event_idx: usize, event_data: usize, stats: &VCpuStats
+    ) -> Result<usize, isize> {
+        let (hw_mask, mut candidates) = selected(base, mask)?;
+        if event_idx >> 16 != EVENT_TYPE_FW {
+            return self.config_hw(hw_mask, flags, event_idx, event_data)
+        }
+        let counter = if flags & CFG_FLAG_SKIP_MATCH != 0 {
+            // the guest reuses the counter it configured before
+            candidates.next().filter(|&counter| self.counters[counter].event.is_some())
+                .ok_or(SBI_ERR_INAVLID_PARAM)?
+        }else{
+            let event = match event_idx {
+                SBI_PMU_EVENT_FW_PLATFORM => HypEvent::from_event_data(event_data).ok_or(SBI_ERR_NOT_SUPPORTED)?,
+                _ => return Err(SBI_ERR_NOT_SUPPORTED)
+            };
+            let counter = candidates.find(|&counter| self.counters[counter].event.is_none())
+                .ok_or(SBI_ERR_NOT_SUPPORTED)?;
+            self.counters[counter] = Counter { event: Some(event), ..Counter::default() };
+            counter
+        };
+        if flags & CFG_FLAG_CLEAR_VALUE != 0 {
+            self.counters[counter].value = 0;
+        }
+        if flags & CFG_FLAG_AUTO_START != 0 && !self.counters[counter].running {
+            self.start_one(counter, stats);
+        }
+        Ok(hw_counters() + counter)
+    }
+
+    fn start_one(&mut self, counter: usize, stats: &VCpuStats) {
+        let counter = &mut self.counters[counter];
+        counter.start = counter.event.map_or(0, |event| event.read(stats));
+        counter.running = true;
+    }
+
+    pub fn start(&mut self, base: usize, mask: usize, flags: usize, initial_value: usize, stats: &VCpuStats) -> isize {
+        let (hw_mask, counters) = match selected(base, mask) {
+            Ok(counters) => counters,
+            Err(error) => return error
+        };
+        if hw_mask & !self.hw_owned != 0 {
+            return SBI_ERR_INAVLID_PARAM
+        }
+        let mut error = SBI_SUCCESS as isize;
+        if hw_mask != 0 {
+            error = sbi::pmu_call(SBI_PMU_COUNTER_START_FID, [0, hw_mask, flags, initial_value, 0]).0;
+            if error == SBI_SUCCESS as isize || error == SBI_ERR_ALREADY_STARTED {
+                self.hw_running |= hw_mask;
+            }
+        }
+        for counter in counters {
+            match self.counters[counter] {
+                Counter { event: None, .. } => return SBI_ERR_INAVLID_PARAM,
+                Counter { running: true, .. } => error = SBI_ERR_ALREADY_STARTED,
+                _ => {
+                    if flags & START_FLAG_SET_INIT_VALUE != 0 {
+                        self.counters[counter].value = initial_value;
+                    }
+                    self.start_one(counter, stats);
+                }
+            }
+        }
+        error
+    }
+
+    /// stop the selected counters, with `STOP_FLAG_RESET` they are also released
+    pub fn stop(&mut self, base: usize, mask: usize, flags: usize, stats: &VCpuStats) -> isize {
+        let (hw_mask, counters) = match selected(base, mask) {
+            Ok(counters) => counters,
+            Err(error) => return error
+        };
+        if hw_mask & !self.hw_owned != 0 {
+            return SBI_ERR_INAVLID_PARAM
+        }
+        let mut error = SBI_SUCCESS as isize;
+        if hw_mask != 0 {
+            // counters stopped while the guest was off the cpu are already stopped on the host
+            if self.hw_running & hw_mask != 0 || flags & STOP_FLAG_RESET != 0 {
+                error = sbi::pmu_call(SBI_PMU_COUNTER_STOP_FID, [0, hw_mask, flags, 0, 0]).0;
+            }else{
+                error = SBI_ERR_ALREADY_STOPPED;
+            }
+            self.hw_running &= !hw_mask;
+            if flags & STOP_FLAG_RESET != 0 {
+                self.hw_owned &= !hw_mask;
+                error = SBI_SUCCESS as isize;
+            }
+        }
+        for counter in counters {
+            let state = &mut self.counters[counter];
+            if state.event.is_none() {
+                return SBI_ERR_INAVLID_PARAM
+            }
+            if state.running {
+                state.value = state.read(stats);
+                state.running = false;
+            }else if flags & STOP_FLAG_RESET == 0 {
+                error = SBI_ERR_ALREADY_STOPPED;
+            }
+            if flags & STOP_FLAG_RESET != 0 {
+                *state = Counter::default();
+            }
+        }
+        error
+    }
+
+    /// value of a firmware counter, the guest reads hardware counters directly
+    pub fn read(&self, counter: usize, stats: &VCpuStats) -> Result<usize, isize> {
+        match counter.checked_sub(hw_counters()).and_then(|counter| self.counters.get(counter)) {
+            Some(state) if state.event.is_some() => Ok(state.read(stats)),
+            _ => Err(SBI_ERR_INAVLID_PARAM)
+        }
+    }
+
+    /// release all counters, on guest reset
+    pub fn reset(&mut self) {
+        if self.hw_owned != 0 {
+            sbi::pmu_call(SBI_PMU_COUNTER_STOP_FID, [0, self.hw_owned, STOP_FLAG_RESET, 0, 0]);
+        }
+        *self = Self::default();
+    }
+
+    /// stop the hardware counters of a guest leaving the cpu
+    pub fn switch_out(&self) {
+        if self.hw_running != 0 {
+            sbi::pmu_call(SBI_PMU_COUNTER_STOP_FID, [0, self.hw_running, 0, 0, 0]);
+        }
+    }
+
+    /// restart the hardware counters of a guest entering the cpu, from their values
+    pub fn switch_in(&self) {
+        if self.hw_running != 0 {
+            sbi::pmu_call(SBI_PMU_COUNTER_START_FID, [0, self.hw_running, 0, 0, 0]);
+        }
+    }
+}
+
+impl<P: PageTable, G: GuestPageTable> HostVmm<P, G> {
+    /// local counter overflow interrupt: raise the virtual one of the guests owning the
+    /// counters that overflowed
+    pub fn handle_counter_overflow(&mut self) {
+        let overflowed = take_overflow();
+        if !VIRTUAL_LCOFI.load(Ordering::Relaxed) {
+            return
+        }
+        let current = self.sched.current;
+        for guest in self.guests.iter_mut().filter(|guest| guest.pmu.hw_owned & overflowed != 0) {
+            let running = current == Some(guest.guest_id);
+            guest.raise_counter_overflow(running);
+        }
+    }
+}
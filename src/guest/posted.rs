@@ -0,0 +1,65 @@
+//! Posted interrupts for paravirtualized guests
+//!
+//! A virtual interrupt delivered through the emulated PLIC costs the guest an exit for the
+//! claim and another one for the complete, too much for a busy virtio-net queue. A guest
+//! with a small driver registers a page of its memory with `HYPERCALL_POSTED_IRQ_FID`
+//! instead and picks the sources posted there, the layout is
+//!
+//! - `POSTED_PENDING`: 1024 bits, one per source, set by the hypervisor
+//! - `POSTED_ENABLED`: 1024 bits, written by the guest, the sources it wants posted
+//! - `POSTED_SUPPRESS`: a word the guest sets while it polls the page anyway
+//!
+//! An interrupt of an enabled source sets its pending bit with an atomic or. If the bit
+//! was clear and notifications are not suppressed, a software interrupt is raised on hart
+//! 0. The guest clears `sip.SSIP` itself and takes the pending bits with an atomic swap of
+//! each word, neither exits. Interrupts of physical devices passed through to the guest
+//! and disabled sources still go through the claim register.
+
+use core::sync::atomic::{ AtomicU32, Ordering };
+
+use crate::constants::PAGE_SIZE;
+use super::pmap::{ is_guest_ram, with_guest_memory };
+
+/// offset of the pending bitmap in the page
+pub const POSTED_PENDING: usize = 0;
+/// offset of the bitmap of posted sources
+pub const POSTED_ENABLED: usize = 0x80;
+/// offset of the notification suppress word
+pub const POSTED_SUPPRESS: usize = 0x100;
+/// sources that can be posted
+pub const POSTED_SOURCES: usize = 1024;
+const POSTED_SIZE: usize = POSTED_SUPPRESS + 4;
+
+/// page registered by a guest for posted interrupts, mapped into the host for each post
+pub struct PostedIrqPage {
+    guest_id: usize,
+    guest_pa: usize
+}
+
+impl PostedIrqPage {
+    /// the page at `guest_pa` of guest `guest_id`, which must be page aligned guest ram
+    pub fn new(guest_id: usize, guest_pa: usize) -> Option<Self> {
+        if guest_pa % PAGE_SIZE != 0 {
+            return None
+        }
+        is_guest_ram(guest_pa, POSTED_SIZE).then(|| Self { guest_id, guest_pa })
+    }
+
+    /// post `irq` if the guest enabled it. Returns `None` for a source delivered through
+    /// the PLIC, otherwise whether the guest has to be notified
+    pub fn post(&self, irq: u32) -> Option<bool> {
+        let irq = irq as usize;
+        if irq >= POSTED_SOURCES {
+            return None
+        }
+        let (offset, bit) = (irq / 32 * 4, 1 << (irq % 32));
+        with_guest_memory(self.guest_id, self.guest_pa, POSTED_SIZE, |bytes| {
+            let word = |offset: usize| unsafe{ &*(bytes[offset..].as_ptr() as *const AtomicU32) };
+            if word(POSTED_ENABLED + offset).load(Ordering::Relaxed) & bit == 0 {
+                return None
+            }
+            let old = word(POSTED_PENDING + offset).fetch_or(bit, Ordering::AcqRel);
+            Some(old & bit == 0 && word(POSTED_SUPPRESS).load(Ordering::Acquire) == 0)
+        }).flatten()
+    }
+}
@@ -0,0 +1,251 @@
+//! Guest OS detection and per-OS quirks
+//!
+//! Linux, xv6 and rCore-like teaching kernels expect slightly different things from the
+//! machine below them. `hvc.os=linux|xv6|rcore` names the OS of the guest, `hvc.os=auto`,
+//! the default, guesses it from the kernel image: the RISC-V Linux image header, a `Linux`
+//! ELF note, or the boot banner of one of them. A kernel the image does not tell is decided
+//! by its first SBI call: Linux starts with the base extension, teaching kernels with a
+//! legacy call.
+//!
+//! The OS picks a profile of quirks, `hvc.quirks=<list>|none` replaces it, with names among
+//! `legacy`, `crlf` and `tick` separated by commas:
+//!
+//! - `legacy`: SBI v0.1 calls are served, without it they fail with `SBI_ERR_NOT_SUPPORTED`
+//!   as on a firmware dropping them
+//! - `crlf`: bare `\n` line ends of console output are written out as `\r\n`
+//! - `tick`: a tick is raised every `TICK` as a supervisor software interrupt, which the
+//!   guest acks by clearing `sip.SSIP`. This is how xv6 gets its timer, forwarded by its own
+//!   machine-mode code, so a port that leaves the timer to the firmware needs no SBI timer.
+
+use alloc::vec::Vec;
+use xmas_elf::ElfFile;
+use xmas_elf::program::Type;
+
+use super::{ Guest, GuestImage };
+use super::context::read_htimedelta;
+use super::fastpath;
+use super::page_table::GuestPageTable;
+use crate::console;
+use crate::constants::CLOCK_FREQ;
+use crate::hypervisor::HostVmm;
+use crate::page_table::PageTable;
+use crate::sbi::SBI_EXTID_BASE;
+use crate::sbi::leagcy::SBI_SHUTDOWN;
+
+/// period of the `tick` quirk, the 100ms of xv6
+pub const TICK: usize = CLOCK_FREQ / 10;
+
+bitflags! {
+    /// behaviors a guest OS expects
+    pub struct Quirks: u32 {
+        /// serve SBI v0.1 calls
+        const LEGACY_SBI = 1 << 0;
+        /// write `\n` of console output as `\r\n`
+        const CONSOLE_CRLF = 1 << 1;
+        /// raise a periodic tick as supervisor software interrupt
+        const SOFT_TICK = 1 << 2;
+    }
+}
+
+impl Quirks {
+    /// `none` or names among `legacy`, `crlf` and `tick` separated by commas
+    pub fn parse(arg: &str) -> Option<Self> {
+        if arg == "none" {
+            return Some(Self::empty())
+        }
+        arg.split(',').try_fold(Self::empty(), |quirks, name| match name {
+            "legacy" => Some(quirks | Self::LEGACY_SBI),
+            "crlf" => Some(quirks | Self::CONSOLE_CRLF),
+            "tick" => Some(quirks | Self::SOFT_TICK),
+            _ => None
+        })
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum GuestOs {
+    /// not known yet, or not one of the others
+    Unknown,
+    Linux,
+    Xv6,
+    RCore
+}
+
+impl Default for GuestOs {
+    fn default() -> Self {
+        GuestOs::Unknown
+    }
+}
+
+/// `magic2` of the RISC-V Linux image header at offset 0x38
+const LINUX_IMAGE_MAGIC: &[u8] = b"RSC\x05";
+const LINUX_IMAGE_MAGIC_OFFSET: usize = 0x38;
+/// owner of the ELF notes of a Linux kernel, with its terminating nul
+const LINUX_NOTE_OWNER: &[u8] = b"Linux\0";
+/// bytes of the note segments looked at
+const MAX_NOTES: usize = 4096;
+
+const BANNERS: &[(&[u8], GuestOs)] = &[
+    (b"Linux version ", GuestOs::Linux),
+    (b"xv6 kernel is booting", GuestOs::Xv6),
+    (b"[kernel] Hello, world!", GuestOs::RCore)
+];
+/// bytes kept across chunks so that a banner split by a page boundary is found
+const BANNER_CARRY: usize = 32;
+
+/// `true` if the ELF notes `notes` have a note owned by Linux
+fn linux_note(mut notes: &[u8]) -> bool {
+    let word = |bytes: &[u8], at: usize| u32::from_le_bytes(bytes[at..at + 4].try_into().unwrap()) as usize;
+    let align = |size: usize| (size + 3) & !3;
+    while notes.len() >= 12 {
+        let (namesz, descsz) = (word(notes, 0), word(notes, 4));
+        let name_end = 12 + namesz;
+        if notes.len() < name_end {
+            return false
+        }
+        if &notes[12..name_end] == LINUX_NOTE_OWNER {
+            return true
+        }
+        notes = &notes[(12 + align(namesz) + align(descsz)).min(notes.len())..];
+    }
+    false
+}
+
+fn banner(bytes: &[u8]) -> Option<GuestOs> {
+    BANNERS.iter()
+        .find(|(banner, _)| bytes.windows(banner.len()).any(|window| window == *banner))
+        .map(|&(_, os)| os)
+}
+
+impl GuestOs {
+    /// `linux`, `xv6`, `rcore`, or `auto` for `Unknown`
+    pub fn parse(arg: &str) -> Option<Self> {
+        match arg {
+            "auto" => Some(GuestOs::Unknown),
+            "linux" => Some(GuestOs::Linux),
+            "xv6" => Some(GuestOs::Xv6),
+            "rcore" => Some(GuestOs::RCore),
+            _ => None
+        }
+    }
+
+    /// guess the OS of a kernel image, `Unknown` if nothing in it tells
+    pub fn detect(image: &GuestImage) -> Self {
+        let head = image.head();
+        if head.get(LINUX_IMAGE_MAGIC_OFFSET..LINUX_IMAGE_MAGIC_OFFSET + LINUX_IMAGE_MAGIC.len()) == Some(LINUX_IMAGE_MAGIC) {
+            return GuestOs::Linux
+        }
+        // file ranges of the note segments, read along with the banners
+        let note_ranges: Vec<(usize, usize)> = match ElfFile::new(&head) {
+            Ok(elf) if head.starts_with(b"\x7fELF") => elf.program_iter()
+                .filter(|ph| ph.get_type() == Ok(Type::Note))
+                .map(|ph| (ph.offset() as usize, ph.offset() as usize + (ph.file_size() as usize).min(MAX_NOTES)))
+                .collect(),
+            _ => Vec::new()
+        };
+        let mut notes = Vec::new();
+        let mut found = None;
+        let mut carry = Vec::with_capacity(2 * BANNER_CARRY);
+        let mut offset = 0;
+        image.for_each_chunk(|chunk| {
+            for &(start, end) in note_ranges.iter() {
+                let (start, end) = (start.max(offset), end.min(offset + chunk.len()));
+                if start < end {
+                    notes.extend_from_slice(&chunk[start - offset..end - offset]);
+                }
+            }
+            offset += chunk.len();
+            if found.is_some() {
+                return
+            }
+            carry.extend_from_slice(&chunk[..chunk.len().min(BANNER_CARRY)]);
+            found = banner(&carry).or_else(|| banner(chunk));
+            carry.clear();
+            carry.extend_from_slice(&chunk[chunk.len().saturating_sub(BANNER_CARRY)..]);
+        });
+        if linux_note(&notes) {
+            return GuestOs::Linux
+        }
+        found.unwrap_or(GuestOs::Unknown)
+    }
+
+    /// quirks the OS expects
+    pub fn profile(&self) -> Quirks {
+        match self {
+            GuestOs::Unknown | GuestOs::Linux => Quirks::LEGACY_SBI,
+            GuestOs::Xv6 => Quirks::LEGACY_SBI | Quirks::CONSOLE_CRLF | Quirks::SOFT_TICK,
+            GuestOs::RCore => Quirks::LEGACY_SBI | Quirks::CONSOLE_CRLF
+        }
+    }
+}
+
+impl<G: GuestPageTable> Guest<G> {
+    /// take `os` as the OS of the guest and apply its quirks, unless `hvc.quirks` set them
+    pub fn set_os(&mut self, os: GuestOs) {
+        self.os = os;
+        self.quirks = self.config.quirks.unwrap_or(os.profile());
+        self.next_tick = 0;
+        console::set_guest_crlf(self.guest_id, self.quirks.contains(Quirks::CONSOLE_CRLF));
+        hdebug!("guest {} runs {:?}, quirks {:?}", self.guest_id, os, self.quirks);
+    }
+
+    /// the OS of the configuration, or the one of the kernel image with `hvc.os=auto`
+    pub fn detect_os(&mut self) {
+        let os = match (self.config.os, self.image.as_ref()) {
+            (GuestOs::Unknown, Some(image)) => GuestOs::detect(image),
+            (os, _) => os
+        };
+        self.set_os(os);
+    }
+
+    /// legacy SBI calls of the guest are served by the fast path: the guest has the
+    /// `legacy` quirk and its OS is decided
+    pub fn legacy_fast(&self) -> bool {
+        self.os != GuestOs::Unknown && self.quirks.contains(Quirks::LEGACY_SBI)
+    }
+
+    /// decide an unknown OS by the SBI call `ext_id` of the guest on the cpu, then refuse
+    /// the call if the guest does not get it
+    pub fn filter_sbi_call(&mut self, ext_id: usize) -> bool {
+        let legacy = ext_id <= SBI_SHUTDOWN;
+        if self.os == GuestOs::Unknown && (legacy || ext_id == SBI_EXTID_BASE) {
+            self.set_os(if legacy { GuestOs::RCore } else { GuestOs::Linux });
+            fastpath::set_legacy_fast(self.legacy_fast());
+        }
+        !legacy || self.quirks.contains(Quirks::LEGACY_SBI)
+    }
+}
+
+impl<P: PageTable, G: GuestPageTable> HostVmm<P, G> {
+    /// host time of the next tick of the running guest, `usize::MAX` without one
+    pub fn tick_deadline(&self) -> usize {
+        if self.sched.current != Some(self.guest_id) {
+            return usize::MAX
+        }
+        match self.guests.get(self.guest_id) {
+            Some(guest) if guest.quirks.contains(Quirks::SOFT_TICK) && guest.next_tick != 0 => {
+                guest.next_tick.wrapping_sub(read_htimedelta())
+            },
+            _ => usize::MAX
+        }
+    }
+
+    /// raise the tick of the running guest when it is due, the first check arms it
+    pub fn check_tick(&mut self) {
+        if self.sched.current != Some(self.guest_id) {
+            return
+        }
+        let guest = match self.guests.get_mut(self.guest_id) {
+            Some(guest) if guest.quirks.contains(Quirks::SOFT_TICK) => guest,
+            _ => return
+        };
+        let now = riscv::register::time::read().wrapping_add(read_htimedelta());
+        if guest.next_tick == 0 {
+            guest.next_tick = now + TICK;
+        }else if now >= guest.next_tick {
+            guest.raise_posted_irq(true);
+            // ticks missed while descheduled are not made up for
+            guest.next_tick = if guest.next_tick + TICK > now { guest.next_tick + TICK } else { now + TICK };
+        }
+    }
+}
@@ -1,19 +1,46 @@
+use alloc::vec::Vec;
+
 use super::vmexit::TrapContext;
+use super::page_table::GuestPageTable;
 use crate::VmmResult;
+use crate::arch::{ self, Irq };
+use crate::hypervisor::HostVmm;
+use crate::page_table::PageTable;
 use crate::constants::riscv_regs::GprIndex;
-use crate::sbi::leagcy::SBI_SET_TIMER;
+use crate::sbi::leagcy::{
+    SBI_SET_TIMER, SBI_CLEAR_IPI, SBI_SEND_IPI, SBI_REMOTE_FENCE_I, SBI_REMOTE_SFENCE_VMA,
+    SBI_REMOTE_SFENCE_VMA_ASID, SBI_SHUTDOWN
+};
+use crate::page_table::{ AccessType, WalkContext };
 use crate::sbi::{
     SBI_EXTID_BASE, SBI_GET_SBI_SPEC_VERSION_FID, SBI_SUCCESS, 
     SBI_PROBE_EXTENSION_FID, SBI_EXTID_TIME, SBI_SET_TIMER_FID, 
-    SBI_ERR_NOT_SUPPORTED, console_putchar, console_getchar, set_timer, SBI_CONSOLE_PUTCHAR, SBI_CONSOLE_GETCHAR, 
+    SBI_ERR_NOT_SUPPORTED, SBI_CONSOLE_PUTCHAR, SBI_CONSOLE_GETCHAR, 
     SBI_GET_SBI_IMPL_ID_FID, SBI_GET_SBI_IMPL_VERSION_FID, SBI_GET_MVENDORID_FID, SBI_GET_MARCHID_FID, SBI_GET_MIMPID_FID,
+    SBI_EXTID_HYPERCALL, SBI_ERR_INAVLID_PARAM, SBI_EXTID_SRST, SBI_SYSTEM_RESET_FID,
+    SBI_RESET_TYPE_SHUTDOWN, SBI_RESET_TYPE_COLD_REBOOT, SBI_RESET_TYPE_WARM_REBOOT,
+    SBI_EXTID_DBCN, SBI_DBCN_WRITE_FID, SBI_DBCN_READ_FID, SBI_DBCN_WRITE_BYTE_FID, SBI_ERR_INVALID_ADDRESS,
+    SBI_EXTID_HSM, SBI_HART_START_FID, SBI_HART_STOP_FID, SBI_HART_STATUS_FID, SBI_HART_SUSPEND_FID,
+    SBI_HSM_SUSPEND_NON_RETENTIVE, SBI_EXTID_IPI, SBI_SEND_IPI_FID, SBI_EXTID_RFNC,
+    SBI_REMOTE_FENCE_I_FID, SBI_REMOTE_SFENCE_VMA_FID, SBI_REMOTE_SFENCE_VMA_ASID_FID,
+    SBI_EXTID_PMU, SBI_PMU_NUM_COUNTERS_FID, SBI_PMU_COUNTER_GET_INFO_FID, SBI_PMU_COUNTER_CONFIG_MATCHING_FID,
+    SBI_PMU_COUNTER_START_FID, SBI_PMU_COUNTER_STOP_FID, SBI_PMU_COUNTER_FW_READ_FID, SBI_PMU_COUNTER_FW_READ_HI_FID,
+    SBI_EXTID_DBTR, SBI_DBTR_NUM_TRIGGERS_FID, SBI_DBTR_SET_SHMEM_FID, SBI_DBTR_READ_TRIGGERS_FID,
+    SBI_DBTR_INSTALL_TRIGGERS_FID, SBI_DBTR_UPDATE_TRIGGERS_FID, SBI_DBTR_UNINSTALL_TRIGGERS_FID,
+    SBI_DBTR_ENABLE_TRIGGERS_FID, SBI_DBTR_DISABLE_TRIGGERS_FID,
 };
+use super::triggers;
+use super::quirks::Quirks;
+use super::pmap::{ is_guest_ram, read_host, two_stage_translation, with_guest_memory };
+use crate::console;
+use super::hypercall::hypercall_handler;
+#[cfg(feature = "monitor")]
+use crate::monitor;
 use sbi_rt;
 
-use riscv::register::{ hvip, sie };
 pub struct SbiRet {
-    error: usize,
-    value: usize
+    pub error: usize,
+    pub value: usize
 }
 
 #[inline(always)]
@@ -31,58 +58,202 @@ pub(crate) fn sbi_call_1(eid: usize, fid: usize, arg0: usize) -> SbiRet {
     SbiRet { error, value }
 }
 
-pub fn sbi_vs_handler(ctx: &mut TrapContext) -> VmmResult {
+pub fn sbi_vs_handler<P: PageTable, G: GuestPageTable>(host_vmm: &mut HostVmm<P, G>, ctx: &mut TrapContext) -> VmmResult {
     let ext_id: usize = ctx.x[GprIndex::A7 as usize];
     let fid: usize = ctx.x[GprIndex::A6 as usize];
     let sbi_ret;
 
+    let allowed = host_vmm.guests.get_mut(host_vmm.guest_id).map_or(true, |guest| guest.filter_sbi_call(ext_id));
+    let legacy = host_vmm.guests.get(host_vmm.guest_id).map_or(true, |guest| guest.quirks.contains(Quirks::LEGACY_SBI));
     match ext_id {
-        SBI_EXTID_BASE => sbi_ret = sbi_base_handler(fid, ctx),
-        SBI_EXTID_TIME => sbi_ret = sbi_time_handler(ctx.x[GprIndex::A0 as usize], fid),
-        SBI_CONSOLE_PUTCHAR => sbi_ret = sbi_console_putchar_handler(ctx.x[GprIndex::A0 as usize]),
-        SBI_CONSOLE_GETCHAR => sbi_ret = sbi_console_getchar_handler(),
-        SBI_SET_TIMER => sbi_ret = sbi_legacy_set_time(ctx.x[GprIndex::A0 as usize]),
-        _ => panic!("Unsupported SBI call id {:#x}", ext_id)
+        _ if !allowed => sbi_ret = sbi_error(SBI_ERR_NOT_SUPPORTED),
+        SBI_EXTID_BASE => sbi_ret = sbi_base_handler(fid, ctx, legacy),
+        SBI_EXTID_TIME => sbi_ret = sbi_time_handler(host_vmm, ctx.x[GprIndex::A0 as usize], fid),
+        SBI_CONSOLE_PUTCHAR => sbi_ret = sbi_console_putchar_handler(host_vmm.guest_id, ctx.x[GprIndex::A0 as usize]),
+        SBI_CONSOLE_GETCHAR => sbi_ret = sbi_console_getchar_handler(host_vmm),
+        SBI_EXTID_HYPERCALL => sbi_ret = hypercall_handler(host_vmm, fid, ctx),
+        SBI_EXTID_DBCN => sbi_ret = sbi_dbcn_handler(host_vmm, fid, ctx),
+        SBI_EXTID_SRST => sbi_ret = sbi_srst_handler(host_vmm, fid, ctx.x[GprIndex::A0 as usize]),
+        SBI_EXTID_HSM => sbi_ret = sbi_hsm_handler(host_vmm, fid, ctx),
+        SBI_EXTID_IPI => sbi_ret = sbi_ipi_handler(host_vmm, fid, ctx),
+        SBI_EXTID_RFNC => sbi_ret = sbi_rfence_handler(fid),
+        SBI_EXTID_PMU => sbi_ret = sbi_pmu_handler(host_vmm, fid, ctx),
+        SBI_EXTID_DBTR => sbi_ret = sbi_dbtr_handler(host_vmm, fid, ctx),
+        SBI_SET_TIMER => sbi_ret = sbi_legacy_set_time(host_vmm, ctx.x[GprIndex::A0 as usize]),
+        SBI_CLEAR_IPI => sbi_ret = sbi_legacy_clear_ipi(),
+        SBI_SEND_IPI => sbi_ret = sbi_legacy_send_ipi(host_vmm, ctx.x[GprIndex::A0 as usize]),
+        // the hart mask does not matter, see `sbi_rfence_handler`
+        SBI_REMOTE_FENCE_I => sbi_ret = sbi_rfence_handler(SBI_REMOTE_FENCE_I_FID),
+        SBI_REMOTE_SFENCE_VMA | SBI_REMOTE_SFENCE_VMA_ASID => sbi_ret = sbi_rfence_handler(SBI_REMOTE_SFENCE_VMA_FID),
+        SBI_SHUTDOWN => sbi_ret = sbi_srst_handler(host_vmm, SBI_SYSTEM_RESET_FID, SBI_RESET_TYPE_SHUTDOWN),
+        _ => {
+            htracking!("guest {} called unknown SBI extension {:#x}", host_vmm.guest_id, ext_id);
+            sbi_ret = SbiRet { error: SBI_ERR_NOT_SUPPORTED as usize, value: 0 }
+        }
+    }
+    if ext_id <= SBI_SHUTDOWN {
+        // legacy calls return a single value in a0, negative on error
+        ctx.x[GprIndex::A0 as usize] = if sbi_ret.error != SBI_SUCCESS { sbi_ret.error } else { sbi_ret.value };
+    }else{
+        ctx.x[GprIndex::A0 as usize] = sbi_ret.error;
+        ctx.x[GprIndex::A1 as usize] = sbi_ret.value;
     }
-    ctx.x[GprIndex::A0 as usize] = sbi_ret.error;
-    ctx.x[GprIndex::A1 as usize] = sbi_ret.value;
 
     Ok(())
     
 }
 
-pub fn sbi_base_handler(fid: usize, ctx: &TrapContext) -> SbiRet {
+/// `major.minor` encoded like the SBI spec version, the major number in bits 30:24
+const fn sbi_version(major: usize, minor: usize) -> usize {
+    major << 24 | minor
+}
+
+/// extensions served to guests, each with the version of the SBI spec that has all the
+/// functions served. Nothing of the host firmware is passed through, a guest sees these
+/// extensions only, whatever the firmware below the hypervisor implements.
+const SBI_EXTENSIONS: &[(usize, usize)] = &[
+    (SBI_EXTID_BASE, sbi_version(0, 2)),
+    (SBI_EXTID_TIME, sbi_version(0, 2)),
+    (SBI_EXTID_IPI, sbi_version(0, 2)),
+    (SBI_EXTID_RFNC, sbi_version(0, 2)),
+    // retentive `hart_suspend`
+    (SBI_EXTID_HSM, sbi_version(0, 3)),
+    (SBI_EXTID_SRST, sbi_version(0, 3)),
+    // `counter_fw_read_hi`
+    (SBI_EXTID_PMU, sbi_version(2, 0)),
+    (SBI_EXTID_DBCN, sbi_version(2, 0)),
+    (SBI_EXTID_DBTR, sbi_version(3, 0))
+];
+
+/// revision of the hypercall interface reported by `probe_extension`, see `hypercall`
+const HYPERCALL_REVISION: usize = 1;
+
+/// version of `extension` served to the guest, 0 if it is not. Debug triggers need
+/// hardware triggers, legacy calls the `legacy` quirk.
+fn sbi_extension_version(extension: usize, legacy: bool) -> usize {
+    match extension {
+        SBI_EXTID_DBTR if triggers::available() == 0 => 0,
+        SBI_EXTID_HYPERCALL => HYPERCALL_REVISION,
+        // SBI v0.1 has no version of its own
+        _ if extension <= SBI_SHUTDOWN => legacy as usize,
+        _ => SBI_EXTENSIONS.iter().find(|&&(id, _)| id == extension).map_or(0, |&(_, version)| version)
+    }
+}
+
+/// SBI spec version shown to the guest: the newest of the extensions it gets
+fn sbi_spec_version() -> usize {
+    SBI_EXTENSIONS.iter()
+        .map(|&(extension, _)| sbi_extension_version(extension, false))
+        .max()
+        .unwrap_or(sbi_version(0, 2))
+}
+
+pub fn sbi_base_handler(fid: usize, ctx: &TrapContext, legacy: bool) -> SbiRet {
     let mut sbi_ret = SbiRet{
         error: SBI_SUCCESS,
         value: 0
     };
     match fid {
-        SBI_GET_SBI_SPEC_VERSION_FID => sbi_ret = sbi_call_1(SBI_EXTID_BASE, fid, 0),
+        SBI_GET_SBI_SPEC_VERSION_FID => sbi_ret.value = sbi_spec_version(),
         SBI_GET_SBI_IMPL_ID_FID => sbi_ret.value = sbi_rt::get_sbi_impl_id(),
         SBI_GET_SBI_IMPL_VERSION_FID => sbi_ret.value = sbi_rt::get_sbi_impl_version(),
-        SBI_PROBE_EXTENSION_FID => {
-            let extension = ctx.x[GprIndex::A0 as usize];
-            sbi_ret = sbi_call_1(SBI_EXTID_BASE, fid, extension);
-        },
+        // the version of a served extension, non-zero as the spec asks
+        SBI_PROBE_EXTENSION_FID => sbi_ret.value = sbi_extension_version(ctx.x[GprIndex::A0 as usize], legacy),
         SBI_GET_MVENDORID_FID => sbi_ret.value = sbi_rt::get_mvendorid(),
         SBI_GET_MARCHID_FID => sbi_ret.value = sbi_rt::get_marchid(),
         SBI_GET_MIMPID_FID => sbi_ret.value = sbi_rt::get_mimpid(),
-        _ => panic!("sbi base handler fid: {}", fid)
+        _ => sbi_ret.error = SBI_ERR_NOT_SUPPORTED as usize
     }
     sbi_ret
 }
 
-pub fn sbi_console_putchar_handler(c: usize) -> SbiRet {
-    console_putchar(c);
+pub fn sbi_console_putchar_handler(guest_id: usize, c: usize) -> SbiRet {
+    console::guest_putchar(guest_id, c as u8);
     return SbiRet { error: SBI_SUCCESS, value: 0 };
 }
 
-pub fn sbi_console_getchar_handler() -> SbiRet {
-    let c = console_getchar();
+/// console input of the running guest
+#[cfg(feature = "monitor")]
+fn guest_getchar<P: PageTable, G: GuestPageTable>(host_vmm: &mut HostVmm<P, G>) -> usize {
+    monitor::guest_getchar(host_vmm)
+}
+
+#[cfg(not(feature = "monitor"))]
+fn guest_getchar<P: PageTable, G: GuestPageTable>(_host_vmm: &mut HostVmm<P, G>) -> usize {
+    crate::sbi::console_getchar()
+}
+
+pub fn sbi_console_getchar_handler<P: PageTable, G: GuestPageTable>(host_vmm: &mut HostVmm<P, G>) -> SbiRet {
+    // guest is waiting for input, show its prompt
+    console::flush_guest(host_vmm.guest_id);
+    // escape sequences are consumed by the hypervisor
+    let c = guest_getchar(host_vmm);
     return SbiRet { error: SBI_SUCCESS, value: c };
 }
 
-pub fn sbi_time_handler(stime: usize, fid: usize) -> SbiRet {
+/// run `f` on a DBCN buffer in the memory of guest `guest_id`
+fn with_guest_buffer<R>(guest_id: usize, num_bytes: usize, base_lo: usize, base_hi: usize, f: impl FnOnce(&mut [u8]) -> R) -> Option<R> {
+    if base_hi != 0 {
+        return None
+    }
+    with_guest_memory(guest_id, base_lo, num_bytes, f)
+}
+
+/// DBCN console write, used by both the fast path and `sbi_dbcn_handler`
+pub fn sbi_dbcn_write(guest_id: usize, num_bytes: usize, base_lo: usize, base_hi: usize) -> SbiRet {
+    match with_guest_buffer(guest_id, num_bytes, base_lo, base_hi, |bytes| console::guest_write(guest_id, bytes)) {
+        Some(()) => SbiRet { error: SBI_SUCCESS, value: num_bytes },
+        None => SbiRet { error: SBI_ERR_INVALID_ADDRESS as usize, value: 0 }
+    }
+}
+
+pub fn sbi_dbcn_handler<P: PageTable, G: GuestPageTable>(host_vmm: &mut HostVmm<P, G>, fid: usize, ctx: &TrapContext) -> SbiRet {
+    let a0 = ctx.x[GprIndex::A0 as usize];
+    let a1 = ctx.x[GprIndex::A1 as usize];
+    let a2 = ctx.x[GprIndex::A2 as usize];
+    match fid {
+        SBI_DBCN_WRITE_FID => sbi_dbcn_write(host_vmm.guest_id, a0, a1, a2),
+        SBI_DBCN_WRITE_BYTE_FID => sbi_console_putchar_handler(host_vmm.guest_id, a0),
+        SBI_DBCN_READ_FID => {
+            if a2 != 0 || !is_guest_ram(a1, a0) {
+                return SbiRet { error: SBI_ERR_INVALID_ADDRESS as usize, value: 0 }
+            }
+            console::flush_guest(host_vmm.guest_id);
+            // the input is taken first, the buffer is only mapped to copy it
+            let mut input = Vec::new();
+            while input.len() < a0 {
+                let c = guest_getchar(host_vmm);
+                if c == usize::MAX {
+                    break
+                }
+                input.push(c as u8);
+            }
+            with_guest_buffer(host_vmm.guest_id, input.len(), a1, a2, |bytes| bytes.copy_from_slice(&input));
+            SbiRet { error: SBI_SUCCESS, value: input.len() }
+        },
+        _ => SbiRet { error: SBI_ERR_NOT_SUPPORTED as usize, value: 0 }
+    }
+}
+
+/// system reset of a guest only affects the guest itself
+pub fn sbi_srst_handler<P: PageTable, G: GuestPageTable>(host_vmm: &mut HostVmm<P, G>, fid: usize, reset_type: usize) -> SbiRet {
+    if fid != SBI_SYSTEM_RESET_FID {
+        return SbiRet { error: SBI_ERR_NOT_SUPPORTED as usize, value: 0 }
+    }
+    let guest_id = host_vmm.guest_id;
+    let result = match reset_type as u32 as usize {
+        SBI_RESET_TYPE_SHUTDOWN => host_vmm.shutdown_guest(guest_id),
+        SBI_RESET_TYPE_COLD_REBOOT | SBI_RESET_TYPE_WARM_REBOOT => host_vmm.reset_guest(guest_id),
+        _ => return SbiRet { error: SBI_ERR_INAVLID_PARAM as usize, value: 0 }
+    };
+    match result {
+        // on success the guest does not return from the call
+        Ok(()) => SbiRet { error: SBI_SUCCESS, value: 0 },
+        Err(_) => SbiRet { error: SBI_ERR_NOT_SUPPORTED as usize, value: 0 }
+    }
+}
+
+pub fn sbi_time_handler<P: PageTable, G: GuestPageTable>(host_vmm: &mut HostVmm<P, G>, stime: usize, fid: usize) -> SbiRet {
     let mut sbi_ret = SbiRet {
         error: SBI_SUCCESS,
         value: 0
@@ -92,31 +263,143 @@ pub fn sbi_time_handler(stime: usize, fid: usize) -> SbiRet {
         return sbi_ret
     }
 
-    set_timer(stime);
-    unsafe{ 
-        // clear guest timer interrupt pending
-        hvip::clear_vstip(); 
-        // enable timer interrupt
-        sie::set_stimer();
-    }
+    host_vmm.set_guest_timer(stime);
     return sbi_ret
 }
 
-// pub fn sbi_rfence_handler(fid: usize) {
+fn sbi_error(error: isize) -> SbiRet {
+    SbiRet { error: error as usize, value: 0 }
+}
+
+/// hart state management of the guest's virtual harts
+pub fn sbi_hsm_handler<P: PageTable, G: GuestPageTable>(host_vmm: &mut HostVmm<P, G>, fid: usize, ctx: &TrapContext) -> SbiRet {
+    let a0 = ctx.x[GprIndex::A0 as usize];
+    let guest = host_vmm.guests.get_mut(host_vmm.guest_id).unwrap();
+    match fid {
+        SBI_HART_START_FID => sbi_error(guest.hart_start(a0, ctx.x[GprIndex::A1 as usize], ctx.x[GprIndex::A2 as usize])),
+        SBI_HART_STOP_FID => sbi_error(guest.hart_stop()),
+        SBI_HART_STATUS_FID => match guest.hart_status(a0) {
+            Some(state) => SbiRet { error: SBI_SUCCESS, value: state as usize },
+            None => sbi_error(SBI_ERR_INAVLID_PARAM)
+        },
+        SBI_HART_SUSPEND_FID => match a0 as u32 as usize {
+            // retentive suspend may wake up at any time, the hart resumes once it is back
+            // on the cpu
+            0 => {
+                host_vmm.yield_hart();
+                SbiRet { error: SBI_SUCCESS, value: 0 }
+            },
+            SBI_HSM_SUSPEND_NON_RETENTIVE => sbi_error(SBI_ERR_NOT_SUPPORTED),
+            _ => sbi_error(SBI_ERR_INAVLID_PARAM)
+        },
+        _ => sbi_error(SBI_ERR_NOT_SUPPORTED)
+    }
+}
+
+pub fn sbi_ipi_handler<P: PageTable, G: GuestPageTable>(host_vmm: &mut HostVmm<P, G>, fid: usize, ctx: &TrapContext) -> SbiRet {
+    if fid != SBI_SEND_IPI_FID {
+        return sbi_error(SBI_ERR_NOT_SUPPORTED)
+    }
+    let guest = host_vmm.guests.get_mut(host_vmm.guest_id).unwrap();
+    sbi_error(guest.send_ipi(ctx.x[GprIndex::A0 as usize], ctx.x[GprIndex::A1 as usize]))
+}
+
+/// The harts of a guest share one physical hart, which flushes the VS-stage TLB on
+/// every hart switch, so a local fence covers all of them.
+pub fn sbi_rfence_handler(fid: usize) -> SbiRet {
+    match fid {
+        SBI_REMOTE_FENCE_I_FID => arch::sync_icache(),
+        SBI_REMOTE_SFENCE_VMA_FID | SBI_REMOTE_SFENCE_VMA_ASID_FID => arch::flush_guest_tlb(),
+        _ => return sbi_error(SBI_ERR_NOT_SUPPORTED)
+    }
+    SbiRet { error: SBI_SUCCESS, value: 0 }
+}
+
+/// firmware counters of the virtual PMU, see `pmu`
+pub fn sbi_pmu_handler<P: PageTable, G: GuestPageTable>(host_vmm: &mut HostVmm<P, G>, fid: usize, ctx: &TrapContext) -> SbiRet {
+    let guest_id = host_vmm.guest_id;
+    let hart = host_vmm.guests.get(guest_id).map_or(0, |guest| guest.vcpu.hart);
+    let stats = host_vmm.hart_stats(guest_id, hart).unwrap_or_default();
+    let guest = host_vmm.guests.get_mut(guest_id).unwrap();
+    let a = |reg: GprIndex| ctx.x[reg as usize];
+    let result = match fid {
+        SBI_PMU_NUM_COUNTERS_FID => Ok(guest.pmu.num_counters()),
+        SBI_PMU_COUNTER_GET_INFO_FID => guest.pmu.counter_info(a(GprIndex::A0)),
+        SBI_PMU_COUNTER_CONFIG_MATCHING_FID => guest.pmu.config_matching(
+            a(GprIndex::A0), a(GprIndex::A1), a(GprIndex::A2), a(GprIndex::A3), a(GprIndex::A4), &stats
+        ),
+        SBI_PMU_COUNTER_START_FID => return sbi_error(
+            guest.pmu.start(a(GprIndex::A0), a(GprIndex::A1), a(GprIndex::A2), a(GprIndex::A3), &stats)
+        ),
+        SBI_PMU_COUNTER_STOP_FID => return sbi_error(
+            guest.pmu.stop(a(GprIndex::A0), a(GprIndex::A1), a(GprIndex::A2), &stats)
+        ),
+        SBI_PMU_COUNTER_FW_READ_FID => guest.pmu.read(a(GprIndex::A0), &stats),
+        // counters are 64 bits wide on rv64
+        SBI_PMU_COUNTER_FW_READ_HI_FID => guest.pmu.read(a(GprIndex::A0), &stats).map(|_| 0),
+        _ => Err(SBI_ERR_NOT_SUPPORTED)
+    };
+    match result {
+        Ok(value) => SbiRet { error: SBI_SUCCESS, value },
+        Err(error) => sbi_error(error)
+    }
+}
 
-// }
+/// debug triggers of the running hart, see `triggers`
+pub fn sbi_dbtr_handler<P: PageTable, G: GuestPageTable>(host_vmm: &mut HostVmm<P, G>, fid: usize, ctx: &TrapContext) -> SbiRet {
+    let guest_id = host_vmm.guest_id;
+    let guest = host_vmm.guests.get_mut(guest_id).unwrap();
+    let a = |reg: GprIndex| ctx.x[reg as usize];
+    if triggers::available() == 0 {
+        return sbi_error(SBI_ERR_NOT_SUPPORTED)
+    }
+    let result = match fid {
+        SBI_DBTR_NUM_TRIGGERS_FID => Ok(guest.triggers.num_triggers(a(GprIndex::A0))),
+        SBI_DBTR_SET_SHMEM_FID => guest.triggers.set_shmem(a(GprIndex::A0), a(GprIndex::A1), a(GprIndex::A2), guest_id),
+        SBI_DBTR_READ_TRIGGERS_FID => guest.triggers.read(a(GprIndex::A0), a(GprIndex::A1)),
+        SBI_DBTR_INSTALL_TRIGGERS_FID => guest.triggers.install(a(GprIndex::A0), guest_id),
+        SBI_DBTR_UPDATE_TRIGGERS_FID => guest.triggers.update(a(GprIndex::A0), guest_id),
+        SBI_DBTR_UNINSTALL_TRIGGERS_FID => guest.triggers.uninstall(a(GprIndex::A0), a(GprIndex::A1)),
+        SBI_DBTR_ENABLE_TRIGGERS_FID => guest.triggers.enable(a(GprIndex::A0), a(GprIndex::A1), true, guest_id),
+        SBI_DBTR_DISABLE_TRIGGERS_FID => guest.triggers.enable(a(GprIndex::A0), a(GprIndex::A1), false, guest_id),
+        _ => Err(SBI_ERR_NOT_SUPPORTED)
+    };
+    match result {
+        Ok(value) => SbiRet { error: SBI_SUCCESS, value },
+        Err(error) => sbi_error(error)
+    }
+}
 
-pub fn sbi_legacy_set_time(stime: usize) -> SbiRet {
+pub fn sbi_legacy_set_time<P: PageTable, G: GuestPageTable>(host_vmm: &mut HostVmm<P, G>, stime: usize) -> SbiRet {
     let sbi_ret = SbiRet {
         error: SBI_SUCCESS,
         value: 0
     };
-    set_timer(stime);
-    unsafe{ 
-        // clear guest timer interrupt pending
-        hvip::clear_vstip(); 
-        // enable timer interrupt
-        sie::set_stimer();
-    }
+    host_vmm.set_guest_timer(stime);
     return sbi_ret
-}
\ No newline at end of file
+}
+
+pub fn sbi_legacy_clear_ipi() -> SbiRet {
+    arch::lower_guest_irq(Irq::Software);
+    SbiRet { error: SBI_SUCCESS, value: 0 }
+}
+
+/// legacy calls pass the hart mask by its address in the guest kernel
+fn legacy_hart_mask<P: PageTable, G: GuestPageTable>(host_vmm: &HostVmm<P, G>, mask_addr: usize) -> Option<usize> {
+    let guest = host_vmm.guests.get(host_vmm.guest_id)?;
+    let host_pa = two_stage_translation(mask_addr, AccessType::Read, &WalkContext::current(), &guest.gpm).ok()?;
+    read_host(host_pa)
+}
+
+pub fn sbi_legacy_send_ipi<P: PageTable, G: GuestPageTable>(host_vmm: &mut HostVmm<P, G>, mask_addr: usize) -> SbiRet {
+    let (hart_mask, hart_mask_base) = match mask_addr {
+        // no mask, all harts
+        0 => (0, usize::MAX),
+        _ => match legacy_hart_mask(host_vmm, mask_addr) {
+            Some(hart_mask) => (hart_mask, 0),
+            None => return sbi_error(SBI_ERR_INVALID_ADDRESS)
+        }
+    };
+    let guest = host_vmm.guests.get_mut(host_vmm.guest_id).unwrap();
+    sbi_error(guest.send_ipi(hart_mask, hart_mask_base))
+}
@@ -0,0 +1,123 @@
+//! Sealed storage of enclave guests
+//!
+//! An enclave guest seals a secret with `HYPERCALL_SEAL_FID` and stores the sealed blob
+//! wherever it likes, on a disk it does not trust for instance. Only a guest with the same
+//! measurement, the SHA-512 of the kernel image and device tree it booted truncated to
+//! `TAG_SIZE` bytes, on a hypervisor with the same root key gets the secret back with
+//! `HYPERCALL_UNSEAL_FID`.
+//!
+//! Keys form a small hierarchy: the root key comes from `hvc.sealkey=<32 hex digits>`,
+//! or is random when it is not set, and sealed blobs then only live until the next boot.
+//! The sealing key of a guest is derived from the root key and the guest measurement, and
+//! every blob is encrypted under it with a fresh random nonce. Encryption and tag of the
+//! blobs use the prototype primitives of `mm::transform`, they are not cryptographically
+//! secure.
+
+use alloc::vec::Vec;
+use ed25519_compact::sha512::Hash;
+use spin::Once;
+
+use super::Guest;
+use super::page_table::GuestPageTable;
+use crate::drivers::entropy;
+use crate::hypervisor::fdt::MachineMeta;
+use crate::mm::{ KeyedHash, xor_keystream, tags_equal, TAG_SIZE };
+use crate::{ VmmError, VmmResult };
+
+/// "SEAL"
+const SEALED_MAGIC: u32 = 0x4c41_4553;
+/// magic, data length, nonce and tag in front of the encrypted data
+pub const SEALED_HEADER_SIZE: usize = 16 + TAG_SIZE;
+/// largest secret sealed in one hypercall
+pub const SEAL_MAX_LEN: usize = 64 * 1024;
+
+static ROOT_KEY: Once<[u64; 2]> = Once::new();
+
+/// set up the root key from `hvc.sealkey`, called after `entropy::init`
+pub fn init(machine: &MachineMeta) {
+    let key = machine.bootarg("hvc.sealkey")
+        .filter(|key| key.len() == 32)
+        .and_then(|key| Some([
+            u64::from_str_radix(&key[..16], 16).ok()?,
+            u64::from_str_radix(&key[16..], 16).ok()?
+        ]));
+    ROOT_KEY.call_once(|| match key {
+        Some(key) => key,
+        None => {
+            if machine.bootarg("hvc.sealkey").is_some() {
+                hwarning!("invalid hvc.sealkey");
+            }
+            hwarning!("random sealing root key, sealed data does not survive a reboot");
+            let mut bytes = [0u8; 16];
+            entropy::fill(&mut bytes);
+            [u64::from_le_bytes(bytes[..8].try_into().unwrap()), u64::from_le_bytes(bytes[8..].try_into().unwrap())]
+        }
+    });
+}
+
+/// encryption and tag keys of the guests with `measurement`
+fn sealing_key(measurement: &[u8; TAG_SIZE]) -> [u64; 2] {
+    let mut hash = KeyedHash::new(*ROOT_KEY.get().expect("sealing root key not set up"));
+    hash.update(b"seal");
+    hash.update(measurement);
+    let key = hash.finish();
+    [u64::from_le_bytes(key[..8].try_into().unwrap()), u64::from_le_bytes(key[8..].try_into().unwrap())]
+}
+
+/// tag of the header fields and the encrypted data
+fn blob_tag(key: u64, header: &[u8], data: &[u8]) -> [u8; TAG_SIZE] {
+    let mut hash = KeyedHash::new([key, !key]);
+    hash.update(header);
+    hash.update(data);
+    hash.finish()
+}
+
+/// encrypt `data` for the guests with `measurement`
+pub fn seal(measurement: &[u8; TAG_SIZE], data: &[u8]) -> Vec<u8> {
+    let key = sealing_key(measurement);
+    let mut nonce = [0u8; 8];
+    entropy::fill(&mut nonce);
+    let mut blob = Vec::with_capacity(SEALED_HEADER_SIZE + data.len());
+    blob.extend_from_slice(&SEALED_MAGIC.to_le_bytes());
+    blob.extend_from_slice(&(data.len() as u32).to_le_bytes());
+    blob.extend_from_slice(&nonce);
+    blob.extend_from_slice(&[0; TAG_SIZE]);
+    blob.extend_from_slice(data);
+    xor_keystream(key[0], u64::from_le_bytes(nonce), &mut blob[SEALED_HEADER_SIZE..]);
+    let tag = blob_tag(key[1], &blob[..16], &blob[SEALED_HEADER_SIZE..]);
+    blob[16..SEALED_HEADER_SIZE].copy_from_slice(&tag);
+    blob
+}
+
+/// check and decrypt a blob sealed for the guests with `measurement`, fails with
+/// `InvalidState` if it is malformed or was not sealed for them
+pub fn unseal(measurement: &[u8; TAG_SIZE], blob: &[u8]) -> VmmResult<Vec<u8>> {
+    if blob.len() < SEALED_HEADER_SIZE || blob[..4] != SEALED_MAGIC.to_le_bytes() {
+        return Err(VmmError::InvalidState)
+    }
+    let len = u32::from_le_bytes(blob[4..8].try_into().unwrap()) as usize;
+    if blob.len() != SEALED_HEADER_SIZE + len {
+        return Err(VmmError::InvalidState)
+    }
+    let key = sealing_key(measurement);
+    let tag = blob_tag(key[1], &blob[..16], &blob[SEALED_HEADER_SIZE..]);
+    if !tags_equal(&tag, blob[16..SEALED_HEADER_SIZE].try_into().unwrap()) {
+        return Err(VmmError::InvalidState)
+    }
+    let mut data = blob[SEALED_HEADER_SIZE..].to_vec();
+    xor_keystream(key[0], u64::from_le_bytes(blob[8..16].try_into().unwrap()), &mut data);
+    Ok(data)
+}
+
+impl<G: GuestPageTable> Guest<G> {
+    /// hash the pristine kernel image and device tree into `measurement`,
+    /// called once the images are attached
+    pub fn measure(&mut self) {
+        let mut hash = Hash::new();
+        for image in self.image.as_deref().into_iter().chain(self.dtb_image.as_ref()) {
+            hash.update((image.len() as u64).to_le_bytes());
+            image.for_each_chunk(|chunk| hash.update(chunk));
+        }
+        self.measurement.copy_from_slice(&hash.finalize()[..TAG_SIZE]);
+    }
+}
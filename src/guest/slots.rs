@@ -0,0 +1,136 @@
+//! Standby kernel slot of a guest, for a fast relaunch on a new kernel
+//!
+//! A guest boots from its active kernel image. Another kernel is staged in its standby
+//! slot while the guest keeps running: `stage_begin` sizes it, `stage_write` fills it from
+//! monitor requests, `stage_from_disk` from the hypervisor disk, and `stage_commit` checks
+//! its layout. `relaunch` swaps the two slots and reboots the guest in place from the new
+//! kernel, the previous one stays in the standby slot and a second `relaunch` goes back
+//! to it. The hypervisor and the other guests keep running.
+//!
+//! The device tree is kept. Staged kernels carry no signature: with the `secure_boot`
+//! feature they only start with `hvc.secureboot=warn`.
+
+use alloc::sync::Arc;
+use alloc::vec;
+
+use super::{ GuestImage, KernelLayout, SharedText };
+use super::page_table::GuestPageTable;
+use crate::constants::layout::GUEST_DEFAULT_SIZE;
+use crate::drivers::virtio::blk::SECTOR_SIZE;
+use crate::hypervisor::HostVmm;
+use crate::page_table::PageTable;
+use crate::secure_boot::{ self, BootVerdict };
+use crate::{ VmmError, VmmResult };
+
+/// sectors read from the disk at a time
+const DISK_CHUNK_SECTORS: usize = 64;
+
+/// a kernel a guest can boot
+pub struct KernelSlot {
+    pub image: Arc<GuestImage>,
+    pub kernel: KernelLayout,
+    pub verdict: BootVerdict
+}
+
+#[derive(Default)]
+pub struct StandbySlot {
+    /// kernel being staged
+    staging: Option<GuestImage>,
+    /// kernel waiting for `relaunch`
+    ready: Option<KernelSlot>
+}
+
+impl StandbySlot {
+    /// bytes of the kernel being staged, or of the kernel waiting for `relaunch`
+    pub fn status(&self) -> (Option<usize>, Option<usize>) {
+        (self.staging.as_ref().map(GuestImage::len), self.ready.as_ref().map(|slot| slot.image.len()))
+    }
+}
+
+impl<P: PageTable, G: GuestPageTable> HostVmm<P, G> {
+    fn staging(&self, guest_id: usize) -> VmmResult<&GuestImage> {
+        let guest = self.guests.get(guest_id).ok_or(VmmError::NoFound)?;
+        guest.standby.staging.as_ref().ok_or(VmmError::InvalidState)
+    }
+
+    /// start staging a kernel of `len` bytes for `guest_id`, dropping one being staged
+    pub fn stage_begin(&mut self, guest_id: usize, len: usize) -> VmmResult {
+        let guest = self.guests.get_mut(guest_id).ok_or(VmmError::NoFound)?;
+        if len == 0 || len > GUEST_DEFAULT_SIZE {
+            return Err(VmmError::NotSupported)
+        }
+        // the frames of a previous staging are freed first
+        guest.standby.staging = None;
+        guest.standby.staging = Some(GuestImage::zeroed(len)?);
+        Ok(())
+    }
+
+    /// copy `data` at `offset` of the kernel being staged
+    pub fn stage_write(&mut self, guest_id: usize, offset: usize, data: &[u8]) -> VmmResult {
+        self.staging(guest_id)?.write(offset, data)
+    }
+
+    /// stage `len` bytes of the hypervisor disk starting at `sector`
+    pub fn stage_from_disk(&mut self, guest_id: usize, sector: u64, len: usize) -> VmmResult {
+        self.stage_begin(guest_id, len)?;
+        let chunk = DISK_CHUNK_SECTORS * SECTOR_SIZE;
+        let mut buf = vec![0; chunk];
+        for offset in (0..len).step_by(chunk) {
+            let size = chunk.min(len - offset);
+            let sectors = (size + SECTOR_SIZE - 1) / SECTOR_SIZE;
+            let blk = self.host_blk.as_mut().ok_or(VmmError::DeviceNotFound)?;
+            blk.read_blocks(sector + (offset / SECTOR_SIZE) as u64, &mut buf[..sectors * SECTOR_SIZE])?;
+            self.staging(guest_id)?.write(offset, &buf[..size])?;
+        }
+        Ok(())
+    }
+
+    /// check the layout of the staged kernel and make it the one `relaunch` boots
+    pub fn stage_commit(&mut self, guest_id: usize) -> VmmResult {
+        let guest = self.guests.get_mut(guest_id).ok_or(VmmError::NoFound)?;
+        let image = guest.standby.staging.take().ok_or(VmmError::InvalidState)?;
+        let kernel = match KernelLayout::parse_head(&image.head(), image.len()) {
+            Some(kernel) => kernel,
+            None => {
+                hwarning!("staged kernel of guest {} does not fit in guest memory", guest_id);
+                return Err(VmmError::NotSupported)
+            }
+        };
+        let verdict = if cfg!(feature = "secure_boot") { BootVerdict::Rejected } else { BootVerdict::Unchecked };
+        guest.standby.ready = Some(KernelSlot { image: Arc::new(image), kernel, verdict });
+        Ok(())
+    }
+
+    /// reboot `guest_id` from the kernel of its standby slot, which gets the active one.
+    /// The guest keeps running its kernel if the new stage-2 page table cannot be built.
+    pub fn relaunch(&mut self, guest_id: usize) -> VmmResult {
+        let guest = self.guests.get(guest_id).ok_or(VmmError::NoFound)?;
+        let standby = guest.standby.ready.as_ref().ok_or(VmmError::InvalidState)?;
+        if !secure_boot::allowed(&self.host_machine, standby.verdict) {
+            return Err(VmmError::NotSupported)
+        }
+        let shared_text = if guest.config.shared_text > 0 && standby.kernel.verbatim() {
+            Some(SharedText::new(&standby.image, guest.config.shared_text))
+        }else{
+            None
+        };
+        let gpm = self.retry_on_oom(guest_id, |vmm| vmm.reset_gpm(guest_id, shared_text.as_ref()))?;
+        let guest = self.guests.get_mut(guest_id).unwrap();
+        let standby = guest.standby.ready.take().unwrap();
+        guest.standby.ready = guest.image.take().map(|image| KernelSlot {
+            image,
+            kernel: guest.config.kernel.clone(),
+            verdict: guest.boot_verdict
+        });
+        guest.shared_text = shared_text;
+        guest.image = Some(standby.image);
+        guest.config.kernel = standby.kernel;
+        guest.boot_verdict = standby.verdict;
+        guest.reset_gpm = Some(gpm);
+        guest.detect_os();
+        // sealed storage of the previous kernel stays sealed
+        guest.measure();
+        hdebug!("guest {} relaunched from its standby kernel", guest_id);
+        self.reset_guest(guest_id)
+    }
+}
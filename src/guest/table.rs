@@ -0,0 +1,82 @@
+//! Guests of the machine
+//!
+//! Guests are created and destroyed at runtime. A guest keeps its id for its whole life:
+//! ids index per-guest resources like PLIC contexts and memory segments, so an id is only
+//! given out again once its guest is gone. Slots are allocated on demand up to `MAX_GUESTS`.
+
+use alloc::vec::Vec;
+
+use super::Guest;
+use super::page_table::GuestPageTable;
+use crate::constants::MAX_GUESTS;
+use crate::{ VmmError, VmmResult };
+
+pub type GuestId = usize;
+
+pub struct GuestTable<G: GuestPageTable> {
+    slots: Vec<Option<Guest<G>>>
+}
+
+impl<G: GuestPageTable> GuestTable<G> {
+    pub const fn new() -> Self {
+        Self { slots: Vec::new() }
+    }
+
+    /// lowest id without a guest, `None` if the table is full
+    pub fn free_id(&self) -> Option<GuestId> {
+        (0..MAX_GUESTS).find(|&guest_id| !self.contains(guest_id))
+    }
+
+    /// add `guest` under its id, fails if the id is out of range or taken
+    pub fn insert(&mut self, guest: Guest<G>) -> VmmResult {
+        let guest_id = guest.guest_id;
+        if guest_id >= MAX_GUESTS || self.contains(guest_id) {
+            return Err(VmmError::InvalidState)
+        }
+        if self.slots.len() <= guest_id {
+            self.slots.resize_with(guest_id + 1, || None);
+        }
+        self.slots[guest_id] = Some(guest);
+        Ok(())
+    }
+
+    /// take a guest out of the table, its id becomes free
+    pub fn remove(&mut self, guest_id: GuestId) -> Option<Guest<G>> {
+        let guest = self.slots.get_mut(guest_id)?.take();
+        while let Some(None) = self.slots.last() {
+            self.slots.pop();
+        }
+        guest
+    }
+
+    pub fn get(&self, guest_id: GuestId) -> Option<&Guest<G>> {
+        self.slots.get(guest_id)?.as_ref()
+    }
+
+    pub fn get_mut(&mut self, guest_id: GuestId) -> Option<&mut Guest<G>> {
+        self.slots.get_mut(guest_id)?.as_mut()
+    }
+
+    pub fn contains(&self, guest_id: GuestId) -> bool {
+        self.get(guest_id).is_some()
+    }
+
+    /// number of guests
+    pub fn len(&self) -> usize {
+        self.iter().count()
+    }
+
+    /// ids of all guests in increasing order
+    pub fn ids(&self) -> impl Iterator<Item = GuestId> + '_ {
+        self.iter().map(|guest| guest.guest_id)
+    }
+
+    /// all guests in increasing id order
+    pub fn iter(&self) -> impl Iterator<Item = &Guest<G>> {
+        self.slots.iter().flatten()
+    }
+
+    pub fn iter_mut(&mut self) -> impl Iterator<Item = &mut Guest<G>> {
+        self.slots.iter_mut().flatten()
+    }
+}
@@ -0,0 +1,342 @@
+//! Virtual SBI debug triggers (DBTR)
+//!
+//! Every guest hart gets its own triggers, indexed from 0, as many as the host SBI offers
+//! up to `MAX_TRIGGERS`. They are kept by the hypervisor and only the enabled triggers of
+//! the hart on the cpu are installed through the host SBI: they are uninstalled when the
+//! hart leaves the cpu and installed again when it comes back, so the triggers of one
+//! guest never fire in another one or in the hypervisor.
+//!
+//! Only `mcontrol6` triggers with a breakpoint action are accepted. `tdata1` is sanitized
+//! before it reaches the host: the S and U bits the guest sets become VS and VU, M, S and
+//! U are cleared and triggers are not chained. The guest reads back what it wrote. When
+//! the hart has `hcontext`, it holds the id of the guest on the cpu and `textra` of every
+//! trigger is tagged with it, so a trigger only matches in its owning guest even if the
+//! switch missed it. The S-level part of `textra` is the guest's own.
+//!
+//! Breakpoint exceptions are delegated, a trigger firing goes to the guest directly.
+
+use alloc::vec::Vec;
+use core::sync::atomic::{ AtomicBool, AtomicUsize, Ordering };
+
+use super::pmap::{ is_guest_ram, with_guest_memory };
+use crate::constants::MAX_GUESTS;
+use crate::detect;
+use crate::sbi::{
+    self, SBI_ERR_INAVLID_PARAM, SBI_ERR_INVALID_ADDRESS, SBI_ERR_NO_SHMEM, SBI_ERR_FAILUER,
+    SBI_DBTR_NUM_TRIGGERS_FID, SBI_DBTR_SET_SHMEM_FID, SBI_DBTR_INSTALL_TRIGGERS_FID, SBI_DBTR_UNINSTALL_TRIGGERS_FID
+};
+
+/// triggers of a guest hart
+pub const MAX_TRIGGERS: usize = 16;
+
+/// host triggers given to guests, 0 without host SBI debug triggers
+static TRIGGERS: AtomicUsize = AtomicUsize::new(0);
+/// `textra` is tagged with the guest id kept in `hcontext`
+static HCONTEXT: AtomicBool = AtomicBool::new(false);
+
+per_cpu! {
+    /// shared memory of the host SBI debug triggers, one entry
+    static HOST_ENTRY: [usize; 4] = [0; 4];
+}
+
+/// a shared memory entry is `tstate` or the trigger index, then `tdata1..3`
+const ENTRY_SIZE: usize = 4 * core::mem::size_of::<usize>();
+/// `tstate`: the trigger is installed
+const TSTATE_MAPPED: usize = 1 << 0;
+
+/// `tdata1` type of `mcontrol6`
+const TYPE_MCONTROL6: usize = 6;
+const TDATA1_TYPE_SHIFT: usize = 60;
+const TDATA1_DMODE: usize = 1 << 59;
+const MC6_VS: usize = 1 << 24;
+const MC6_VU: usize = 1 << 23;
+const MC6_HIT: usize = 1 << 25 | 1 << 22;
+const MC6_ACTION: usize = 0xf << 12;
+const MC6_CHAIN: usize = 1 << 11;
+const MC6_M: usize = 1 << 6;
+const MC6_S: usize = 1 << 4;
+const MC6_U: usize = 1 << 3;
+
+/// `textra64`: `mhvalue` and `mhselect`, the rest is S-level
+const TEXTRA_MHVALUE_SHIFT: usize = 51;
+const TEXTRA_MHSELECT_SHIFT: usize = 48;
+const TEXTRA_MH: usize = !0 << TEXTRA_MHSELECT_SHIFT;
+/// `mhselect`: match when `hcontext` equals `{mhvalue, mhselect[2]}`
+const MHSELECT_HCONTEXT: usize = 1;
+
+/// find the debug triggers of the host and register the shared memory of the boot hart,
+/// which runs the guests, called once at boot
+pub fn init() {
+    let (error, total) = sbi::dbtr_call(SBI_DBTR_NUM_TRIGGERS_FID, [0; 3]);
+    if error != 0 || total == 0 {
+        hdebug!("no host SBI debug triggers, guests get none");
+        return
+    }
+    let entry = HOST_ENTRY.as_ptr() as usize;
+    if sbi::dbtr_call(SBI_DBTR_SET_SHMEM_FID, [entry, 0, 0]).0 != 0 {
+        hwarning!("host SBI debug triggers refused the shared memory, guests get none");
+        return
+    }
+    let hcontext = detect::detect_hcontext(MAX_GUESTS);
+    HCONTEXT.store(hcontext, Ordering::Relaxed);
+    TRIGGERS.store(total.min(MAX_TRIGGERS), Ordering::Relaxed);
+    hdebug!(
+        "{} debug triggers for guests, {}",
+        total.min(MAX_TRIGGERS), if hcontext { "tagged with hcontext" } else { "without hcontext" }
+    );
+}
+
+/// triggers of a guest hart
+pub fn available() -> usize {
+    TRIGGERS.load(Ordering::Relaxed)
+}
+
+/// `tdata1` the host gets for the guest's `tdata1`, `None` if it is not a breakpoint
+/// `mcontrol6` trigger
+fn host_tdata1(tdata1: usize) -> Option<usize> {
+    if tdata1 >> TDATA1_TYPE_SHIFT != TYPE_MCONTROL6 || tdata1 & (TDATA1_DMODE | MC6_ACTION) != 0 {
+        return None
+    }
+    let mut host = tdata1 & !(MC6_VS | MC6_VU | MC6_HIT | MC6_CHAIN | MC6_M | MC6_S | MC6_U);
+    if tdata1 & MC6_S != 0 {
+        host |= MC6_VS;
+    }
+    if tdata1 & MC6_U != 0 {
+        host |= MC6_VU;
+    }
+    Some(host)
+}
+
+/// `textra` the host gets for the guest's, tagged with `guest_id` when `hcontext` exists
+fn host_tdata3(tdata3: usize, guest_id: usize) -> usize {
+    let tdata3 = tdata3 & !TEXTRA_MH;
+    if !HCONTEXT.load(Ordering::Relaxed) {
+        return tdata3
+    }
+    let context = guest_id + 1;
+    let mhselect = MHSELECT_HCONTEXT | (context & 1) << 2;
+    tdata3 | (context >> 1) << TEXTRA_MHVALUE_SHIFT | mhselect << TEXTRA_MHSELECT_SHIFT
+}
+
+/// install `trigger` of `guest_id` in the host, returns its host index
+fn host_install(trigger: &Trigger, guest_id: usize) -> Option<usize> {
+    let tdata1 = host_tdata1(trigger.tdata1)?;
+    HOST_ENTRY.set([0, tdata1, trigger.tdata2, host_tdata3(trigger.tdata3, guest_id)]);
+    match sbi::dbtr_call(SBI_DBTR_INSTALL_TRIGGERS_FID, [1, 0, 0]) {
+        (0, _) => Some(HOST_ENTRY.get()[0]),
+        _ => None
+    }
+}
+
+fn host_uninstall(index: usize) {
+    sbi::dbtr_call(SBI_DBTR_UNINSTALL_TRIGGERS_FID, [index, 1, 0]);
+}
+
+/// triggers selected by `trig_idx_base` and `trig_idx_mask`
+fn selected(base: usize, mask: usize) -> Result<impl Iterator<Item = usize> + Clone, isize> {
+    let last = usize::BITS as usize - mask.leading_zeros() as usize;
+    if base >= available() || last > available() - base {
+        return Err(SBI_ERR_INAVLID_PARAM)
+    }
+    Ok((0..last).filter(move |bit| mask >> bit & 1 != 0).map(move |bit| base + bit))
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+struct Trigger {
+    /// as written by the guest
+    tdata1: usize,
+    tdata2: usize,
+    tdata3: usize,
+    enabled: bool,
+    /// host index while installed in the host
+    host: Option<usize>
+}
+
+#[derive(Default)]
+pub struct VirtualTriggers {
+    slots: [Option<Trigger>; MAX_TRIGGERS],
+    /// guest and guest physical address of the shared memory of the hart
+    shmem: Option<(usize, usize)>
+}
+
+impl VirtualTriggers {
+    /// triggers of the type of `tdata1`, all of them for 0
+    pub fn num_triggers(&self, tdata1: usize) -> usize {
+        if tdata1 == 0 || host_tdata1(tdata1).is_some() { available() } else { 0 }
+    }
+
+    pub fn set_shmem(&mut self, lo: usize, hi: usize, flags: usize, guest_id: usize) -> Result<usize, isize> {
+        if flags != 0 {
+            return Err(SBI_ERR_INAVLID_PARAM)
+        }
+        if lo == usize::MAX && hi == usize::MAX {
+            self.shmem = None;
+            return Ok(0)
+        }
+        if lo % core::mem::size_of::<usize>() != 0 {
+            return Err(SBI_ERR_INAVLID_PARAM)
+        }
+        if hi != 0 || !is_guest_ram(lo, ENTRY_SIZE) {
+            return Err(SBI_ERR_INVALID_ADDRESS)
+        }
+        self.shmem = Some((guest_id, lo));
+        Ok(0)
+    }
+
+    /// run `f` on the first `count` entries of the shared memory, at most one per trigger
+    fn with_entries<R>(&self, count: usize, f: impl FnOnce(&mut [[usize; 4]]) -> R) -> Result<R, isize> {
+        let (guest_id, shmem) = self.shmem.ok_or(SBI_ERR_NO_SHMEM)?;
+        if count > available() {
+            return Err(SBI_ERR_INAVLID_PARAM)
+        }
+        // aligned by `set_shmem`
+        with_guest_memory(guest_id, shmem, count * ENTRY_SIZE, |bytes| f(unsafe{ core::slice::from_raw_parts_mut(bytes.as_mut_ptr() as *mut [usize; 4], count) }))
+            .ok_or(SBI_ERR_INVALID_ADDRESS)
+    }
+
+    /// the first `count` entries of the shared memory, copied out of it
+    fn entries(&self, count: usize) -> Result<Vec<[usize; 4]>, isize> {
+        self.with_entries(count, |entries| entries.to_vec())
+    }
+
+    /// state of `count` triggers from `base` into the shared memory
+    pub fn read(&self, base: usize, count: usize) -> Result<usize, isize> {
+        if base.checked_add(count).map_or(true, |end| end > available()) {
+            return Err(SBI_ERR_INAVLID_PARAM)
+        }
+        self.with_entries(count, |entries| {
+            for (entry, slot) in entries.iter_mut().zip(self.slots[base..base + count].iter()) {
+                *entry = match slot {
+                    Some(trigger) => [TSTATE_MAPPED, trigger.tdata1, trigger.tdata2, trigger.tdata3],
+                    None => [0; 4]
+                };
+            }
+        })?;
+        Ok(0)
+    }
+
+    fn set_enabled(&mut self, index: usize, enabled: bool, guest_id: usize) -> Result<(), isize> {
+        let trigger = self.slots[index].as_mut().unwrap();
+        trigger.enabled = enabled;
+        match (enabled, trigger.host) {
+            (true, None) => {
+                trigger.host = Some(host_install(trigger, guest_id).ok_or(SBI_ERR_FAILUER)?);
+            },
+            (false, Some(host)) => {
+                host_uninstall(host);
+                trigger.host = None;
+            },
+            _ => {}
+        }
+        Ok(())
+    }
+
+    fn free_slot(&self) -> Option<usize> {
+        (0..available()).find(|&index| self.slots[index].is_none())
+    }
+
+    /// drop trigger `index`, uninstalled from the host
+    fn remove(&mut self, index: usize) {
+        if let Some(Trigger { host: Some(host), .. }) = self.slots[index].take() {
+            host_uninstall(host);
+        }
+    }
+
+    /// install the `count` triggers of the shared memory, enabled, and write back their
+    /// indexes. Nothing is installed if one of them is refused.
+    pub fn install(&mut self, count: usize, guest_id: usize) -> Result<usize, isize> {
+        if count > available() {
+            return Err(SBI_ERR_FAILUER)
+        }
+        let mut entries = self.entries(count)?;
+        if entries.iter().any(|entry| host_tdata1(entry[1]).is_none()) {
+            return Err(SBI_ERR_INAVLID_PARAM)
+        }
+        if (0..available()).filter(|&index| self.slots[index].is_none()).count() < count {
+            return Err(SBI_ERR_FAILUER)
+        }
+        for installed in 0..count {
+            let index = self.free_slot().unwrap();
+            let [_, tdata1, tdata2, tdata3] = entries[installed];
+            self.slots[index] = Some(Trigger { tdata1, tdata2, tdata3, ..Trigger::default() });
+            if let Err(error) = self.set_enabled(index, true, guest_id) {
+                self.slots[index] = None;
+                entries[..installed].iter().for_each(|entry| self.remove(entry[0]));
+                return Err(error)
+            }
+            entries[installed][0] = index;
+        }
+        self.with_entries(count, |shared| shared.copy_from_slice(&entries))?;
+        Ok(0)
+    }
+
+    /// replace `tdata1..3` of the `count` installed triggers of the shared memory, which
+    /// keep their enabled state
+    pub fn update(&mut self, count: usize, guest_id: usize) -> Result<usize, isize> {
+        let entries = self.entries(count)?;
+        let valid = |entry: &[usize; 4]| {
+            entry[0] < available() && self.slots[entry[0]].is_some() && host_tdata1(entry[1]).is_some()
+        };
+        if !entries.iter().all(valid) {
+            return Err(SBI_ERR_INAVLID_PARAM)
+        }
+        for &[index, tdata1, tdata2, tdata3] in entries.iter() {
+            let enabled = self.slots[index].map_or(false, |trigger| trigger.enabled);
+            self.remove(index);
+            self.slots[index] = Some(Trigger { tdata1, tdata2, tdata3, ..Trigger::default() });
+            if enabled {
+                self.set_enabled(index, true, guest_id)?;
+            }
+        }
+        Ok(0)
+    }
+
+    pub fn uninstall(&mut self, base: usize, mask: usize) -> Result<usize, isize> {
+        let selected = selected(base, mask)?;
+        if selected.clone().any(|index| self.slots[index].is_none()) {
+            return Err(SBI_ERR_INAVLID_PARAM)
+        }
+        selected.for_each(|index| self.remove(index));
+        Ok(0)
+    }
+
+    pub fn enable(&mut self, base: usize, mask: usize, enabled: bool, guest_id: usize) -> Result<usize, isize> {
+        let selected = selected(base, mask)?;
+        if selected.clone().any(|index| self.slots[index].is_none()) {
+            return Err(SBI_ERR_INAVLID_PARAM)
+        }
+        for index in selected {
+            self.set_enabled(index, enabled, guest_id)?;
+        }
+        Ok(0)
+    }
+
+    /// drop all triggers and the shared memory, on guest reset
+    pub fn reset(&mut self) {
+        (0..MAX_TRIGGERS).for_each(|index| self.remove(index));
+        *self = Self::default();
+    }
+
+    /// uninstall the triggers of a hart leaving the cpu
+    pub fn switch_out(&mut self) {
+        for trigger in self.slots.iter_mut().flatten() {
+            if let Some(host) = trigger.host.take() {
+                host_uninstall(host);
+            }
+        }
+    }
+
+    /// install the enabled triggers of a hart of `guest_id` entering the cpu. A trigger the
+    /// host has no room for stays off until the next switch.
+    pub fn switch_in(&mut self, guest_id: usize) {
+        if available() == 0 {
+            return
+        }
+        if HCONTEXT.load(Ordering::Relaxed) {
+            unsafe{ core::arch::asm!("csrw 0x6a8, {}", in(reg) guest_id + 1) }; // 0x6a8 => hcontext
+        }
+        for trigger in self.slots.iter_mut().flatten().filter(|trigger| trigger.enabled && trigger.host.is_none()) {
+            trigger.host = host_install(trigger, guest_id);
+        }
+    }
+}
@@ -1,16 +1,120 @@
 use alloc::collections::VecDeque;
+use riscv::register::time;
+
+use super::addrspace::AddrSpaceHooks;
+use super::context::GuestVsCsrs;
+use super::fpu::FpuState;
+use super::triggers::VirtualTriggers;
+use super::vmexit::TrapContext;
+
+/// Scheduling statistics of a vcpu, times are in cycles of the `time` csr
+#[derive(Clone, Copy, Debug, Default)]
+pub struct VCpuStats {
+    /// time spent on the cpu
+    pub run_time: usize,
+    /// time spent runnable but descheduled
+    pub wait_time: usize,
+    /// times the vcpu was switched out while still runnable
+    pub preemptions: usize,
+    /// number of traps into the hypervisor
+    pub exits: usize,
+    /// guest page faults emulated as MMIO accesses
+    pub mmio_exits: usize
+}
 
 pub struct VCpu {
+    /// virtual hart id of the vcpu on the cpu, virtual hart ids start at 0 in every guest
     pub hart: usize,
     /// pending interrupts
-    pub pending_events: VecDeque<u32>
+    pub pending_events: VecDeque<u32>,
+    /// guest timer deadline while descheduled, `usize::MAX` if no timer is armed.
+    /// The deadline of the running vcpu is kept by `fastpath`.
+    pub vtimecmp: usize,
+    /// saved `hvip` while the vcpu is descheduled
+    pub hvip: usize,
+    /// scheduling statistics of `hart`, those of the other harts are in their `VHart`
+    pub stats: VCpuStats,
+    /// time of the last switch in or out of the cpu
+    pub last_switch: usize,
+    /// time at which `hart` got the cpu, vcpus of a guest share the guest's slices
+    pub slice_start: usize,
+    /// hart `hart` yielded to, put on the cpu at the end of the trap, see `hsm`
+    pub yield_to: Option<usize>,
+    /// address space hooks of `hart`
+    pub addr_space: AddrSpaceHooks
 }
 
 impl VCpu {
     pub fn new(hart: usize) -> Self {
         Self{
             hart,
-            pending_events: VecDeque::new()
+            pending_events: VecDeque::new(),
+            vtimecmp: usize::MAX,
+            hvip: 0,
+            stats: VCpuStats::default(),
+            last_switch: time::read(),
+            slice_start: 0,
+            yield_to: None,
+            addr_space: AddrSpaceHooks::default()
         }
     }
-}
\ No newline at end of file
+
+    /// statistics including the time since the last switch
+    pub fn current_stats(&self, running: bool, now: usize) -> VCpuStats {
+        let mut stats = self.stats;
+        let elapsed = now.saturating_sub(self.last_switch);
+        if running {
+            stats.run_time += elapsed;
+        }else{
+            stats.wait_time += elapsed;
+        }
+        stats
+    }
+}
+
+/// SBI HSM state of a virtual hart, the values are those of `hart_get_status`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HartState {
+    Started = 0,
+    Stopped = 1
+}
+
+/// A virtual hart of a guest. The registers are only valid while the hart is not
+/// the one the guest runs, those of the running hart are kept in `Guest`.
+pub struct VHart {
+    pub state: HartState,
+    pub trap_ctx: TrapContext,
+    pub vs_csrs: GuestVsCsrs,
+    pub fpu: FpuState,
+    /// guest timer deadline, `usize::MAX` if no timer is armed
+    pub vtimecmp: usize,
+    /// pending VS-level interrupts
+    pub hvip: usize,
+    /// debug triggers, never installed in the host
+    pub triggers: VirtualTriggers,
+    /// address space hooks, kept across hart restarts
+    pub addr_space: AddrSpaceHooks,
+    /// scheduling statistics, kept across hart restarts
+    pub stats: VCpuStats
+}
+
+impl VHart {
+    pub fn new(state: HartState, trap_ctx: TrapContext) -> Self {
+        Self {
+            state,
+            trap_ctx,
+            vs_csrs: GuestVsCsrs::default(),
+            fpu: FpuState::default(),
+            vtimecmp: usize::MAX,
+            hvip: 0,
+            triggers: VirtualTriggers::default(),
+            addr_space: AddrSpaceHooks::default(),
+            stats: VCpuStats::default()
+        }
+    }
+
+    /// the hart has an interrupt to take or its timer expired at guest time `now`
+    pub fn has_work(&self, now: usize) -> bool {
+        self.hvip != 0 || now >= self.vtimecmp
+    }
+}
@@ -0,0 +1,476 @@
+//! Emulated machine mode for guests booting their own firmware
+//!
+//! With `hvc.firmware=on` the guest image is an M-mode firmware carrying the kernel, e.g.
+//! an unmodified OpenSBI `fw_payload`. The guest runs in VS-mode all the time, a virtual
+//! machine mode, vM, is emulated on top of it:
+//!
+//! - the guest starts in vM. Its accesses to machine CSRs and its `mret` raise illegal
+//!   instruction traps, which are emulated while it is in vM. `mstatus`, `mie` and `mip`
+//!   share their supervisor bits with `vsstatus`, `vsie` and `hvip`
+//! - `mret` leaves vM for the privilege in `mstatus.MPP`. An ecall of the kernel, an
+//!   illegal instruction not delegated by `medeleg` and the machine timer and software
+//!   interrupts enter vM again at `mtvec`. `vsatp` is 0 and `vsie` is masked while in vM
+//! - the CLINT of the guest is emulated instead of passed through: `msip`, `mtimecmp` and
+//!   `mtime` of hart 0, `mtime` is the time of the guest
+//!
+//! The other traps of the guest go to VS-mode as if the firmware delegated them, `medeleg`
+//! and `mideleg` keep what the firmware writes but are not followed for them. `mstatus.MPRV`
+//! and PMP are not emulated, PMP registers read as 0. Exceptions of vM other than illegal
+//! instructions are not taken by the firmware, its ecalls are SBI calls to the hypervisor.
+//! The guest has one vcpu.
+
+use core::arch::asm;
+use riscv::register::{ time, vsatp };
+use riscv::register::sstatus::SPP;
+use riscv_decode::Instruction;
+
+use super::addrspace;
+use super::context::read_htimedelta;
+use super::csrcheck;
+use super::page_table::GuestPageTable;
+use super::vmexit::{ TrapContext, forward_exception };
+use crate::arch::{ self, GuestException };
+use crate::device_emu::mmio::{ MmioAccess, MmioDevice };
+use crate::hypervisor::HostVmm;
+use crate::hypervisor::fdt::MachineMeta;
+use crate::mm::GuestMemorySet;
+use crate::page_table::PageTable;
+use crate::{ VmmError, VmmResult };
+
+const PRV_U: usize = 0;
+const PRV_S: usize = 1;
+const PRV_M: usize = 3;
+
+const MSTATUS_MIE: usize = 1 << 3;
+const MSTATUS_MPIE: usize = 1 << 7;
+const MSTATUS_MPP_SHIFT: usize = 11;
+const MSTATUS_MPP: usize = 0b11 << MSTATUS_MPP_SHIFT;
+/// machine bits of `mstatus` kept by vM: MIE, MPIE, MPP, MPRV, TVM, TW and TSR
+const MSTATUS_MACHINE: usize = MSTATUS_MIE | MSTATUS_MPIE | MSTATUS_MPP | 1 << 17 | 0b111 << 20;
+/// bits of `mstatus` that are the ones of `vsstatus`
+const MSTATUS_SUPERVISOR: usize = 1 << 1 | 0b11 << 5 | 1 << 8 | 0b11 << 9 | 0b1111 << 13 | 0b11 << 18 | 0b11 << 32 | 1 << 63;
+/// of them, the bits the firmware may write: SIE, SPIE, SPP, VS, FS, SUM and MXR
+const MSTATUS_SUPERVISOR_WRITABLE: usize = 1 << 1 | 1 << 5 | 1 << 8 | 0b11 << 9 | 0b11 << 13 | 0b11 << 18;
+
+/// machine software and timer interrupts, bits of `mip` and `mie`
+const MIP_MSIP: usize = 1 << 3;
+const MIP_MTIP: usize = 1 << 7;
+/// supervisor interrupts of `mip` and `mie`, shifted left by one in `hvip`
+const MIP_SUPERVISOR: usize = 1 << 1 | 1 << 5 | 1 << 9;
+/// supervisor bits of `mip` the firmware may write, SEIP stays the one of the interrupt controller
+const MIP_WRITABLE: usize = 1 << 1 | 1 << 5;
+
+const INTERRUPT: usize = 1 << (usize::BITS - 1);
+const IRQ_M_SOFT: usize = 3;
+const IRQ_M_TIMER: usize = 7;
+const ECALL_FROM_S: usize = 9;
+const ECALL_FROM_M: usize = 11;
+
+const CSR_MSTATUS: u32 = 0x300;
+const CSR_MISA: u32 = 0x301;
+const CSR_MEDELEG: u32 = 0x302;
+const CSR_MIDELEG: u32 = 0x303;
+const CSR_MIE: u32 = 0x304;
+const CSR_MTVEC: u32 = 0x305;
+const CSR_MCOUNTEREN: u32 = 0x306;
+const CSR_MENVCFG: u32 = 0x30a;
+const CSR_MCOUNTINHIBIT: u32 = 0x320;
+const CSR_MHPMEVENT3: u32 = 0x323;
+const CSR_MHPMEVENT31: u32 = 0x33f;
+const CSR_MSCRATCH: u32 = 0x340;
+const CSR_MEPC: u32 = 0x341;
+const CSR_MCAUSE: u32 = 0x342;
+const CSR_MTVAL: u32 = 0x343;
+const CSR_MIP: u32 = 0x344;
+const CSR_PMPCFG0: u32 = 0x3a0;
+const CSR_PMPADDR63: u32 = 0x3ef;
+const CSR_MCYCLE: u32 = 0xb00;
+const CSR_MHPMCOUNTER31: u32 = 0xb1f;
+const CSR_MVENDORID: u32 = 0xf11;
+const CSR_MHARTID: u32 = 0xf14;
+const CSR_MCONFIGPTR: u32 = 0xf15;
+
+/// registers of hart 0 in the CLINT window
+const CLINT_MSIP: usize = 0x0;
+const CLINT_MTIMECMP: usize = 0x4000;
+const CLINT_MTIME: usize = 0xbff8;
+
+/// machine mode state of the guest
+#[derive(Clone, Debug)]
+pub struct VirtMachine {
+    /// the guest runs its firmware
+    pub in_machine: bool,
+    /// machine bits of `mstatus`, the supervisor ones are in `vsstatus`
+    mstatus: usize,
+    medeleg: usize,
+    mideleg: usize,
+    /// machine bits of `mie`, the supervisor ones are in `vsie`
+    mie: usize,
+    mtvec: usize,
+    mcounteren: usize,
+    mscratch: usize,
+    mepc: usize,
+    mcause: usize,
+    mtval: usize,
+    /// CLINT `msip` of hart 0
+    msip: bool,
+    /// CLINT `mtimecmp` of hart 0, in guest time
+    mtimecmp: usize,
+    /// `vsatp` and `vsie` of the kernel while the guest is in vM
+    saved_vsatp: usize,
+    saved_vsie: usize
+}
+
+impl Default for VirtMachine {
+    /// state at reset, the guest enters its firmware
+    fn default() -> Self {
+        Self {
+            in_machine: true,
+            mstatus: 0,
+            medeleg: 0,
+            mideleg: 0,
+            mie: 0,
+            mtvec: 0,
+            mcounteren: 0,
+            mscratch: 0,
+            mepc: 0,
+            mcause: 0,
+            mtval: 0,
+            msip: false,
+            mtimecmp: usize::MAX,
+            saved_vsatp: 0,
+            saved_vsie: 0
+        }
+    }
+}
+
+fn guest_time() -> usize {
+    time::read().wrapping_add(read_htimedelta())
+}
+
+fn read_vsstatus() -> usize {
+    let vsstatus: usize;
+    unsafe{ asm!("csrr {}, vsstatus", out(reg) vsstatus) };
+    vsstatus
+}
+
+fn write_vsstatus(vsstatus: usize) {
+    unsafe{ asm!("csrw vsstatus, {}", in(reg) vsstatus) };
+}
+
+/// replace `vsatp` and `vsie` of the guest with `satp` and `sie`, return the old ones
+fn swap_kernel_csrs(satp: usize, sie: usize) -> (usize, usize) {
+    let old_vsatp = vsatp::read().bits();
+    let old_vsie: usize;
+    unsafe{
+        asm!(
+            "csrw vsatp, {vsatp}",
+            "csrrw {old_vsie}, vsie, {vsie}",
+            vsatp = in(reg) satp,
+            vsie = in(reg) sie,
+            old_vsie = out(reg) old_vsie
+        )
+    }
+    (old_vsatp, old_vsie)
+}
+
+/// how a CSR instruction writes its CSR
+#[derive(Clone, Copy)]
+enum CsrWrite {
+    Write(usize),
+    Set(usize),
+    Clear(usize)
+}
+
+impl CsrWrite {
+    fn apply(self, old: usize) -> usize {
+        match self {
+            CsrWrite::Write(value) => value,
+            CsrWrite::Set(bits) => old | bits,
+            CsrWrite::Clear(bits) => old & !bits
+        }
+    }
+}
+
+/// CSR, `rd` and write of a CSR instruction, no write for `csrrs` and `csrrc` with `x0`
+/// or a zero immediate
+fn decode_csr_inst(ctx: &TrapContext, inst: &Instruction) -> Option<(u32, u32, Option<CsrWrite>)> {
+    let reg = |index: u32| ctx.x[index as usize];
+    let access = match inst {
+        Instruction::Csrrw(i) => (i.csr(), i.rd(), Some(CsrWrite::Write(reg(i.rs1())))),
+        Instruction::Csrrs(i) => (i.csr(), i.rd(), (i.rs1() != 0).then(|| CsrWrite::Set(reg(i.rs1())))),
+        Instruction::Csrrc(i) => (i.csr(), i.rd(), (i.rs1() != 0).then(|| CsrWrite::Clear(reg(i.rs1())))),
+        Instruction::Csrrwi(i) => (i.csr(), i.rd(), Some(CsrWrite::Write(i.zimm() as usize))),
+        Instruction::Csrrsi(i) => (i.csr(), i.rd(), (i.zimm() != 0).then(|| CsrWrite::Set(i.zimm() as usize))),
+        Instruction::Csrrci(i) => (i.csr(), i.rd(), (i.zimm() != 0).then(|| CsrWrite::Clear(i.zimm() as usize))),
+        _ => return None
+    };
+    Some(access)
+}
+
+impl VirtMachine {
+    /// machine interrupts pending and enabled, taken unless the guest is in vM with `mstatus.MIE` clear
+    fn pending_irqs(&self) -> usize {
+        let msip = if self.msip { MIP_MSIP } else { 0 };
+        let mtip = if guest_time() >= self.mtimecmp { MIP_MTIP } else { 0 };
+        (msip | mtip) & self.mie
+    }
+
+    fn irqs_enabled(&self) -> bool {
+        !self.in_machine || self.mstatus & MSTATUS_MIE != 0
+    }
+
+    /// trap into the firmware with `cause` and `tval` at `ctx.sepc`
+    pub fn enter(&mut self, ctx: &mut TrapContext, cause: usize, tval: usize) {
+        let prv = if self.in_machine {
+            PRV_M
+        }else if ctx.sstatus.spp() == SPP::Supervisor {
+            PRV_S
+        }else{
+            PRV_U
+        };
+        let mpie = if self.mstatus & MSTATUS_MIE != 0 { MSTATUS_MPIE } else { 0 };
+        self.mstatus = self.mstatus & !(MSTATUS_MIE | MSTATUS_MPIE | MSTATUS_MPP) | mpie | prv << MSTATUS_MPP_SHIFT;
+        self.mepc = ctx.sepc;
+        self.mcause = cause;
+        self.mtval = tval;
+        if !self.in_machine {
+            (self.saved_vsatp, self.saved_vsie) = swap_kernel_csrs(0, 0);
+            self.in_machine = true;
+        }
+        let base = self.mtvec & !0b11;
+        ctx.sepc = if self.mtvec & 1 != 0 && cause & INTERRUPT != 0 {
+            base + 4 * (cause & !INTERRUPT)
+        }else{
+            base
+        };
+        ctx.sstatus.set_spp(SPP::Supervisor);
+    }
+
+    /// `mret`, back to the privilege in `mstatus.MPP`
+    fn mret(&mut self, ctx: &mut TrapContext) {
+        let prv = (self.mstatus & MSTATUS_MPP) >> MSTATUS_MPP_SHIFT;
+        let mie = if self.mstatus & MSTATUS_MPIE != 0 { MSTATUS_MIE } else { 0 };
+        self.mstatus = self.mstatus & !(MSTATUS_MIE | MSTATUS_MPP) | mie | MSTATUS_MPIE | PRV_U << MSTATUS_MPP_SHIFT;
+        ctx.sepc = self.mepc;
+        if prv != PRV_M {
+            swap_kernel_csrs(self.saved_vsatp, self.saved_vsie);
+            self.in_machine = false;
+            ctx.sstatus.set_spp(if prv == PRV_S { SPP::Supervisor } else { SPP::User });
+        }
+    }
+
+    /// value of machine CSR `csr`, `None` if it is not one
+    fn read_csr(&self, csr: u32, misa: usize, hart: usize) -> Option<usize> {
+        let value = match csr {
+            CSR_MSTATUS => self.mstatus | read_vsstatus() & MSTATUS_SUPERVISOR,
+            CSR_MISA => misa,
+            CSR_MEDELEG => self.medeleg,
+            CSR_MIDELEG => self.mideleg,
+            CSR_MIE => self.mie | self.saved_vsie & MIP_SUPERVISOR,
+            CSR_MTVEC => self.mtvec,
+            CSR_MCOUNTEREN => self.mcounteren,
+            CSR_MSCRATCH => self.mscratch,
+            CSR_MEPC => self.mepc,
+            CSR_MCAUSE => self.mcause,
+            CSR_MTVAL => self.mtval,
+            CSR_MIP => {
+                let msip = if self.msip { MIP_MSIP } else { 0 };
+                let mtip = if guest_time() >= self.mtimecmp { MIP_MTIP } else { 0 };
+                msip | mtip | arch::guest_irqs() >> 1 & MIP_SUPERVISOR
+            },
+            CSR_MHARTID => hart,
+            // no environment configuration, counters, PMP or identification
+            CSR_MENVCFG | CSR_MCOUNTINHIBIT | CSR_MHPMEVENT3..=CSR_MHPMEVENT31 => 0,
+            CSR_PMPCFG0..=CSR_PMPADDR63 | CSR_MCYCLE..=CSR_MHPMCOUNTER31 => 0,
+            CSR_MVENDORID..=CSR_MCONFIGPTR => 0,
+            _ => return None
+        };
+        Some(value)
+    }
+
+    /// write `value` to machine CSR `csr`, fields the firmware cannot change are left alone
+    fn write_csr(&mut self, csr: u32, value: usize) {
+        match csr {
+            CSR_MSTATUS => {
+                // MPP is WARL, the reserved hypervisor level reads as U
+                let value = if (value & MSTATUS_MPP) >> MSTATUS_MPP_SHIFT == 2 { value & !MSTATUS_MPP } else { value };
+                self.mstatus = value & MSTATUS_MACHINE;
+                write_vsstatus(read_vsstatus() & !MSTATUS_SUPERVISOR_WRITABLE | value & MSTATUS_SUPERVISOR_WRITABLE);
+            },
+            CSR_MEDELEG => self.medeleg = value & !(1 << ECALL_FROM_M),
+            CSR_MIDELEG => self.mideleg = value & MIP_SUPERVISOR,
+            CSR_MIE => {
+                self.mie = value & (MIP_MSIP | MIP_MTIP);
+                self.saved_vsie = self.saved_vsie & !MIP_SUPERVISOR | value & MIP_SUPERVISOR;
+            },
+            // vectored or direct
+            CSR_MTVEC => self.mtvec = value & !0b10,
+            CSR_MCOUNTEREN => self.mcounteren = value & 0xffff_ffff,
+            CSR_MSCRATCH => self.mscratch = value,
+            CSR_MEPC => self.mepc = value & !1,
+            CSR_MCAUSE => self.mcause = value,
+            CSR_MTVAL => self.mtval = value,
+            CSR_MIP => {
+                let hvip = arch::guest_irqs() & !(MIP_WRITABLE << 1) | (value & MIP_WRITABLE) << 1;
+                arch::set_guest_irqs(csrcheck::hvip(hvip));
+            },
+            _ => {}
+        }
+    }
+
+    /// emulate `inst` of the firmware, `false` if it is not an instruction of vM
+    fn emulate(&mut self, ctx: &mut TrapContext, len: usize, inst: Instruction, misa: usize, hart: usize) -> bool {
+        if let Instruction::Mret = inst {
+            self.mret(ctx);
+            return true
+        }
+        let (csr, rd, write) = match decode_csr_inst(ctx, &inst) {
+            Some(access) => access,
+            None => return false
+        };
+        let old = match self.read_csr(csr, misa, hart) {
+            Some(old) => old,
+            None => return false
+        };
+        if let Some(write) = write {
+            // the top two bits of a read-only CSR number are set
+            if csr >> 10 == 0b11 {
+                return false
+            }
+            self.write_csr(csr, write.apply(old));
+        }
+        if rd != 0 {
+            ctx.x[rd as usize] = old;
+        }
+        ctx.sepc += len;
+        true
+    }
+}
+
+/// give a guest booting its own firmware the emulated CLINT instead of the real one,
+/// a guest without one has no machine timer
+pub fn reserve_clint<G: GuestPageTable>(gpm: &mut GuestMemorySet<G>, guest_machine: &MachineMeta) -> VmmResult {
+    match guest_machine.clint.as_ref() {
+        Some(clint) => {
+            gpm.remove_area(clint.base_address.into());
+            gpm.reserve_mmio(clint.base_address, clint.size, MmioDevice::Clint)
+        },
+        None => Ok(())
+    }
+}
+
+impl<P: PageTable, G: GuestPageTable> HostVmm<P, G> {
+    /// illegal instruction of the running guest: emulated in vM, a trap into the firmware
+    /// otherwise, unless the firmware delegated it or the guest has no firmware
+    pub fn handle_machine_inst(&mut self, ctx: &mut TrapContext) -> VmmResult {
+        let guest = self.guests.get(self.guest_id).ok_or(VmmError::NoFound)?;
+        let in_machine = match guest.machine.as_ref() {
+            Some(machine) => machine.in_machine,
+            None => {
+                forward_exception(ctx);
+                return Ok(())
+            }
+        };
+        // the instruction is decoded before the guest is borrowed mutably
+        let inst = if in_machine { addrspace::trapped_inst(self, ctx).ok() } else { None };
+        let guest = self.guests.get_mut(self.guest_id).ok_or(VmmError::NoFound)?;
+        let (misa, hart) = (guest.misa, guest.vcpu.hart);
+        let machine = guest.machine.as_mut().unwrap();
+        let emulated = match inst {
+            Some((len, inst)) => machine.emulate(ctx, len, inst, misa, hart),
+            None => false
+        };
+        if !emulated {
+            let tval = ctx.stval;
+            let cause = arch::exception_cause(GuestException::IllegalInstruction);
+            if !in_machine && machine.medeleg & 1 << cause != 0 {
+                forward_exception(ctx);
+            }else{
+                machine.enter(ctx, cause, tval);
+            }
+        }
+        self.program_timer();
+        Ok(())
+    }
+
+    /// an SBI call of the kernel of a guest with its own firmware enters the firmware,
+    /// `false` if the running guest has none or the call comes from the firmware itself
+    pub fn machine_ecall(&mut self, ctx: &mut TrapContext) -> bool {
+        let machine = match self.guests.get_mut(self.guest_id).and_then(|guest| guest.machine.as_mut()) {
+            Some(machine) if !machine.in_machine => machine,
+            _ => return false
+        };
+        machine.enter(ctx, ECALL_FROM_S, 0);
+        self.program_timer();
+        true
+    }
+
+    /// host time at which the machine timer of the running guest can be taken, `usize::MAX`
+    /// if it is not armed or enabled
+    pub fn machine_deadline(&self) -> usize {
+        if self.sched.current != Some(self.guest_id) {
+            return usize::MAX
+        }
+        match self.guests.get(self.guest_id).and_then(|guest| guest.machine.as_ref()) {
+            Some(machine) if machine.mie & MIP_MTIP != 0 && machine.irqs_enabled() && machine.mtimecmp != usize::MAX => {
+                machine.mtimecmp.wrapping_sub(read_htimedelta())
+            },
+            _ => usize::MAX
+        }
+    }
+
+    /// take a pending machine interrupt of the running guest, called at the end of every trap
+    pub fn deliver_machine_irq(&mut self, ctx: &mut TrapContext) {
+        if self.sched.current != Some(self.guest_id) {
+            return
+        }
+        let machine = match self.guests.get_mut(self.guest_id).and_then(|guest| guest.machine.as_mut()) {
+            Some(machine) if machine.irqs_enabled() => machine,
+            _ => return
+        };
+        let pending = machine.pending_irqs();
+        let irq = if pending & MIP_MSIP != 0 {
+            IRQ_M_SOFT
+        }else if pending & MIP_MTIP != 0 {
+            IRQ_M_TIMER
+        }else{
+            return
+        };
+        machine.enter(ctx, INTERRUPT | irq, 0);
+        // the machine timer is masked until the firmware returns
+        self.program_timer();
+    }
+
+    /// access of the running guest to its emulated CLINT, words and double words of hart 0
+    pub fn handle_clint_access(&mut self, ctx: &mut TrapContext, guest_pa: usize, access: MmioAccess) -> VmmResult {
+        let guest = self.guests.get_mut(self.guest_id).ok_or(VmmError::NoFound)?;
+        let offset = guest_pa - guest.guest_machine.clint.as_ref().ok_or(VmmError::DeviceNotFound)?.base_address;
+        let machine = guest.machine.as_mut().ok_or(VmmError::DeviceNotFound)?;
+        let width = access.width();
+        if width < 4 || offset % width != 0 {
+            return Err(VmmError::UnexpectedInst)
+        }
+        // the double word holding the register and the part of it accessed
+        let (reg, shift) = (offset & !7, 8 * (offset & 7));
+        let mask = if width == 8 { usize::MAX } else { 0xffff_ffff << shift };
+        let old = match reg {
+            CLINT_MSIP => machine.msip as usize,
+            CLINT_MTIMECMP => machine.mtimecmp,
+            CLINT_MTIME => guest_time(),
+            _ => 0
+        };
+        access.complete_read(ctx, (old & mask) >> shift);
+        if let Some(value) = access.written((old & mask) >> shift) {
+            let new = old & !mask | value << shift & mask;
+            match reg {
+                CLINT_MSIP => machine.msip = new & 1 != 0,
+                CLINT_MTIMECMP => machine.mtimecmp = new,
+                // `mtime` is the time of the guest, set by the hypervisor
+                _ => {}
+            }
+        }
+        self.program_timer();
+        Ok(())
+    }
+}
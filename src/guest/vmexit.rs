@@ -1,22 +1,38 @@
-use core::arch::{ global_asm, asm };
+use core::arch::asm;
+use core::sync::atomic::{ AtomicBool, AtomicUsize, Ordering };
 
+use crate::arch::{ self, GuestException, GuestExit, HostTrap, Irq };
 use crate::constants::layout::{ TRAMPOLINE, TRAP_CONTEXT, GUEST_DTB_ADDR };
-use crate::device_emu::plic::is_plic_access;
+use crate::device_emu::virtio_slot::handle_empty_virtio_access;
+use crate::device_emu::mmio::{ MmioAccess, MmioDevice };
 use crate::guest::page_table::GuestPageTable;
 use crate::guest::pmap::{ two_stage_translation, decode_inst };
-use crate::page_table::{PageTable, PageTableSv39};
+use crate::page_table::{PageTable, AccessType, WalkContext};
 use crate::hypervisor::{HOST_VMM, HostVmm};
 use crate::{ VmmError, VmmResult };
-
-
-use riscv::register::{ stvec, sscratch, scause, sepc, stval, sie, hgatp, vsatp, htval, htinst, hvip, vstvec };
-use riscv::register::scause::{ Trap, Exception, Interrupt };
+use crate::sbi::SBI_ERR_FAILUER;
+use crate::nested::{ self, NestRule };
+use crate::errata::{ self, Workarounds };
+use crate::constants::riscv_regs::GprIndex;
+use crate::bootprof::{ self, BootPhase };
+#[cfg(feature = "tracing")]
+use crate::{ irqlat, exitlat };
+use crate::mm::{ adbits, framemap };
+#[cfg(feature = "profiler")]
+use crate::profiler;
+
+
+#[cfg(feature = "tracing")]
+use riscv::register::time;
+use riscv_decode::Instruction;
 
 pub use super::context::TrapContext;
-use super::pmap::fast_two_stage_translation;
+use super::pmap::fetch_guest_inst;
 use super::sbi::sbi_vs_handler;
-
-global_asm!(include_str!("trap.S"));
+use super::fastpath;
+use super::addrspace;
+use super::counters;
+use super::misaligned::misaligned_access_handler;
 
 
 /// initialize CSR `stvec` as the entry of `__alltraps`
@@ -24,13 +40,13 @@ pub fn trap_init() {
     set_kernel_trap_entry();
 }
 
-/// enable timer interrupt in sie CSR
+/// let the timer interrupt reach the hypervisor
 pub fn enable_timer_interrupt() {
-    unsafe { sie::set_stimer(); }
+    arch::unmask_irq(Irq::Timer);
 }
 
 pub fn disable_timer_interrupt() {
-    unsafe{ sie::clear_stimer(); }
+    arch::mask_irq(Irq::Timer);
 }
 
 fn set_kernel_trap_entry() {
@@ -39,196 +55,414 @@ fn set_kernel_trap_entry() {
         fn __alltraps_k();
     }
     let __alltraps_k_va = __alltraps_k as usize - __alltraps as usize + TRAMPOLINE;
-    unsafe {
-        stvec::write(__alltraps_k_va, stvec::TrapMode::Direct);
-        sscratch::write(trap_from_kernel as usize);
-    }
+    arch::set_host_trap_entry(__alltraps_k_va, nested::stack_top());
 }
 
+/// vectored trap entry of the guest, see `__vectors` in `trap.S`
 fn set_user_trap_entry() {
-    unsafe {
-        stvec::write(TRAMPOLINE as usize, stvec::TrapMode::Direct);
+    extern "C" {
+        fn __alltraps();
+        fn __vectors();
     }
+    let vectors_va = __vectors as usize - __alltraps as usize + TRAMPOLINE;
+    arch::set_guest_trap_entry(vectors_va);
 }
 
 
 
-fn privileged_inst_handler(_ctx: &mut TrapContext) -> VmmResult {
-    todo!()
+/// counter reads denied by `hcounteren` and instructions trapped by `hstatus.VTVM` and
+/// `hstatus.VTW` are emulated
+fn privileged_inst_handler<P: PageTable, G: GuestPageTable>(host_vmm: &mut HostVmm<P, G>, ctx: &mut TrapContext) -> VmmResult {
+    let (len, inst) = addrspace::trapped_inst(host_vmm, ctx)?;
+    if let Instruction::Wfi = inst {
+        return host_vmm.handle_wfi(ctx, len)
+    }
+    match counters::counter_read(&inst) {
+        Some((rd, counter)) => {
+            counters::emulate_read(ctx, len, rd, counter);
+            Ok(())
+        },
+        None => addrspace::handle_vtvm_inst(host_vmm, ctx, len, inst)
+    }
 }
 
 
-pub fn guest_page_fault_handler<P: PageTable, G: GuestPageTable>(host_vmm: &mut HostVmm<P, G>, ctx: &mut TrapContext) -> VmmResult {
-    let addr = htval::read() << 2;
-    if is_plic_access(addr) {
-        let mut inst = htinst::read();
-        if inst == 0 {
-            // If htinst does not provide information about the trap,
-            // we must read the instruction from guest's memory manually
-            let inst_addr = ctx.sepc;
-            // let gpm = &host_vmm.guests[host_vmm.guest_id].as_ref().unwrap().gpm;
-            if let Some(host_inst_addr) = fast_two_stage_translation::<PageTableSv39>(
-                host_vmm.guest_id, 
-                inst_addr, 
-                vsatp::read().bits()
-            ) {
-                inst = unsafe{ core::ptr::read(host_inst_addr as *const usize) };
-                
-            }else{
-                herror!("inst addr: {:#x}", inst_addr);
-                return Err(VmmError::TranslationError)
-            }
-        }else if inst == 0x3020 || inst == 0x3000 {
-            // TODO: we should reinject this in the guest as a fault access
-            herror!("fault on 1st stage page table walk");
-            return Err(VmmError::PseudoInst)
+/// fetch the instruction that caused a guest page fault, return its length and its
+/// encoding, a compressed instruction read from guest memory is left compressed
+fn fetch_fault_inst<P: PageTable, G: GuestPageTable>(host_vmm: &HostVmm<P, G>, ctx: &TrapContext) -> VmmResult<(usize, usize)> {
+    let inst = ctx.htinst;
+    if inst == 0 || errata::active(Workarounds::HTINST) {
+        // If htinst does not provide information about the trap,
+        // we must read the instruction from guest's memory manually
+        let inst_addr = ctx.sepc;
+        if let Some(inst) = fetch_guest_inst(host_vmm.guest_id, inst_addr, &WalkContext::current()) {
+            Ok((riscv_decode::instruction_length(inst as u16), inst))
         }else{
-            // If htinst is valid and is not a pseudo instructon make sure
-            // the opcode is valid even if it was a compressed instruction,
-            // but before save the real instruction size.
+            herror!("inst addr: {:#x}", inst_addr);
+            return Err(VmmError::TranslationError)
         }
-        let (len, inst) = decode_inst(inst);
-        if let Some(inst) = inst {
-            // htracking!("inst: {:?}", inst);
-            host_vmm.handle_plic_access(ctx, addr, inst)?;
-            ctx.sepc += len;         
-        }else{
-            return Err(VmmError::DecodeInstError)
-        }
-        Ok(())
+    }else if inst == 0x3020 || inst == 0x3000 {
+        // the guest page tables are not in its memory, `handle_guest_error` reinjects
+        // the fault as an access fault
+        herror!("fault on 1st stage page table walk");
+        return Err(VmmError::PseudoInst)
     }else{
-        herror!("addr: {:#x}, sepc: {:#x}", addr, ctx.sepc);
-        Err(VmmError::DeviceNotFound)
-        // todo: handle other device
+        // If htinst is valid and is not a pseudo instructon make sure
+        // the opcode is valid even if it was a compressed instruction,
+        // but before save the real instruction size.
+        let len = if inst & 0b10 == 0 { 2 } else { 4 };
+        Ok((len, inst | 0b10))
     }
 }
 
+fn decode_fault_inst(inst: usize) -> VmmResult<Instruction> {
+    decode_inst(inst).1.ok_or(VmmError::DecodeInstError)
+}
+
+/// decode the access of a load, store or AMO to a device, `riscv_decode` does not expand
+/// compressed loads and stores
+fn decode_fault_access(ctx: &TrapContext, inst: usize) -> VmmResult<MmioAccess> {
+    if inst & 0b11 != 0b11 {
+        return MmioAccess::decode_compressed(ctx, inst).ok_or(VmmError::DecodeInstError)
+    }
+    MmioAccess::decode(ctx, decode_fault_inst(inst)?)
+}
+
+pub fn guest_page_fault_handler<P: PageTable, G: GuestPageTable>(host_vmm: &mut HostVmm<P, G>, ctx: &mut TrapContext) -> VmmResult {
+    let addr = ctx.fault_guest_pa();
+    let store = arch::guest_exit(ctx.scause) == GuestExit::GuestPageFault(AccessType::Write);
+    let guest = host_vmm.guests.get_mut(host_vmm.guest_id).ok_or(VmmError::NoFound)?;
+    // first write to a shared kernel text page, the guest gets its own copy
+    if store && guest.gpm.break_cow(addr)? {
+        arch::flush_stage2_tlb();
+        return Ok(())
+    }
+    // first access to a page without Svadu, see `mm::adbits`
+    let access = if store { AccessType::Write } else { AccessType::Read };
+    if !adbits::hardware_ad_update() && guest.gpm.update_ad(addr, access) {
+        arch::flush_stage2_tlb();
+        return Ok(())
+    }
+    let device = host_vmm.guests.get_mut(host_vmm.guest_id).and_then(|guest| {
+        guest.vcpu.stats.mmio_exits += 1;
+        guest.gpm.mmio_device(addr)
+    });
+    let device = match device {
+        Some(device) => device,
+        None => {
+            herror!("addr: {:#x}, sepc: {:#x}", addr, ctx.sepc);
+            return Err(VmmError::DeviceNotFound)
+        }
+    };
+    let (len, inst) = fetch_fault_inst(host_vmm, ctx)?;
+    match device {
+        MmioDevice::Plic => {
+            let access = decode_fault_access(ctx, inst)?;
+            host_vmm.handle_plic_access(ctx, addr, access)?
+        },
+        MmioDevice::Syscon => {
+            let inst = decode_fault_inst(inst)?;
+            // a reset or power off by the guest replaces the trap context
+            ctx.sepc += len;
+            return host_vmm.handle_syscon_access(ctx, addr, inst)
+        },
+        MmioDevice::Rtc => host_vmm.handle_rtc_access(ctx, addr, decode_fault_inst(inst)?)?,
+        MmioDevice::Watchdog => host_vmm.handle_watchdog_access(ctx, addr, decode_fault_inst(inst)?)?,
+        MmioDevice::Pci => {
+            let access = decode_fault_access(ctx, inst)?;
+            host_vmm.handle_pci_access(ctx, addr, access)?
+        },
+        MmioDevice::Virtio => {
+            let access = decode_fault_access(ctx, inst)?;
+            host_vmm.handle_emulated_virtio_access(ctx, addr, access)?
+        },
+        MmioDevice::HostVirtio => handle_empty_virtio_access(ctx, addr, decode_fault_inst(inst)?)?,
+        MmioDevice::Clint => {
+            let access = decode_fault_access(ctx, inst)?;
+            host_vmm.handle_clint_access(ctx, addr, access)?
+        }
+    }
+    ctx.sepc += len;
+    Ok(())
+}
+
 
 
 /// handle interrupt request(current only external interrupt)
 pub fn handle_irq<P: PageTable, G: GuestPageTable>(host_vmm: &mut HostVmm<P, G>, _ctx: &mut TrapContext) {
     // TODO: handle other irq
     // check external interrupt && handle
-    let host_plic = host_vmm.host_plic.as_mut().unwrap();
-    // get current guest context id
-    let context_id = 2 * host_vmm.guest_id + 1;
+    #[cfg(feature = "tracing")]
+    let arrival = time::read();
+    if host_vmm.host_imsic.is_some() {
+        host_vmm.handle_host_msis();
+    }
+    let context_id = match host_vmm.current_plic_context() {
+        Some(context_id) => context_id,
+        None => return
+    };
+    let host_plic = match host_vmm.host_plic.as_mut() {
+        Some(host_plic) => host_plic,
+        None => return
+    };
     let claim_and_complete_addr = host_plic.base_addr + 0x0020_0004 + 0x1000 * context_id;
     let irq = unsafe{
         core::ptr::read(claim_and_complete_addr as *const u32)
     };
+    if irq != 0 && host_vmm.is_host_irq(irq as usize) {
+        // device of the hypervisor, never seen by the guest
+        host_vmm.handle_host_irq(irq as usize);
+        unsafe{ core::ptr::write_volatile(claim_and_complete_addr as *mut u32, irq) };
+        return
+    }
+    let host_plic = host_vmm.host_plic.as_mut().unwrap();
     host_plic.claim_complete[context_id] = irq; 
+    #[cfg(feature = "tracing")]
+    if irq != 0 {
+        irqlat::arrived(host_vmm.guest_id, irq, arrival);
+    }
 
     // set external interrupt pending, which trigger guest interrupt
-    unsafe{ hvip::set_vseip() };
+    if let Some(guest) = host_vmm.guests.get_mut(host_vmm.guest_id) {
+        guest.raise_external_irq();
+    }
     
     // set irq pending in host vmm
     host_vmm.irq_pending = true;
 } 
 
-/// forward exception by setting `vsepc` & `vscause`
+/// hand the trap to the guest trap handler with its own cause and `tval`
 pub fn forward_exception(ctx: &mut TrapContext) {
-    unsafe{
-        asm!(
-            "csrw vsepc, {sepc}",
-            "csrw vscause, {scause}",
-            sepc = in(reg) ctx.sepc,
-            scause = in(reg) scause::read().bits()
-        )
+    ctx.sepc = arch::enter_guest_handler(ctx.sepc, ctx.scause, ctx.stval);
+}
+
+/// enter the guest trap handler with `exception` at `tval`, as if raised at `ctx.sepc`
+pub fn inject_exception(ctx: &mut TrapContext, exception: GuestException, tval: usize) {
+    ctx.sepc = arch::enter_guest_handler(ctx.sepc, arch::exception_cause(exception), tval);
+}
+
+/// a trap of the guest the hypervisor could not handle, e.g. an access to a hole of its
+/// memory map, a device access of a bad width or an instruction that does not decode.
+/// The guest sees what real hardware would raise: an SBI call fails, an access or a
+/// fetch faults, an instruction is illegal. A guest out of memory is powered off. The
+/// hypervisor and the other guests run on either way.
+fn handle_guest_error<P: PageTable, G: GuestPageTable>(host_vmm: &mut HostVmm<P, G>, ctx: &mut TrapContext, cause: GuestExit, err: VmmError) {
+    let guest_id = host_vmm.guest_id;
+    let exception = match (cause, &err) {
+        (_, VmmError::OutOfMemory) => None,
+        (GuestExit::Hypercall, _) => {
+            // `sepc` already points past the ecall
+            herror!("guest {} SBI call {:#x}:{:#x} failed: {:?}", guest_id, ctx.x[GprIndex::A7 as usize], ctx.x[GprIndex::A6 as usize], err);
+            ctx.x[GprIndex::A0 as usize] = SBI_ERR_FAILUER as usize;
+            return
+        },
+        (GuestExit::VirtualInstruction, _) => Some(GuestException::IllegalInstruction),
+        (GuestExit::GuestPageFault(AccessType::Execute), _) => Some(GuestException::FetchAccessFault),
+        (GuestExit::GuestPageFault(AccessType::Read), _)
+            | (GuestExit::Misaligned(AccessType::Read), _) => Some(GuestException::LoadAccessFault),
+        (GuestExit::GuestPageFault(AccessType::Write), _)
+            | (GuestExit::Misaligned(AccessType::Write), _) => Some(GuestException::StoreAccessFault),
+        _ => None
+    };
+    match exception {
+        Some(exception) => {
+            hwarning!("guest {} trap {:?} at {:#x}: {:?}, exception {:?} injected", guest_id, cause, ctx.sepc, err, exception);
+            // `stval` holds the faulting address, or the bits of a virtual instruction
+            let tval = ctx.stval;
+            inject_exception(ctx, exception, tval);
+        },
+        None => {
+            herror!("guest {} trap {:?} at {:#x}: {:?}, powering it off", guest_id, cause, ctx.sepc, err);
+            let _ = host_vmm.shutdown_guest(guest_id);
+        }
     }
-    ctx.sepc = vstvec::read().bits();
 }
 
-pub fn handle_internal_vmm_error(err: VmmError) {
-    panic!("err: {:?}", err);
+
+/// traps without a handler of their own in `trap.S`
+#[no_mangle]
+pub unsafe fn trap_handler() -> ! {
+    let ctx = (TRAP_CONTEXT as *mut TrapContext).as_ref().unwrap();
+    handle_trap(arch::guest_exit(ctx.scause))
+}
+
+/// VS-mode ecalls, `scause` is not decoded again
+#[no_mangle]
+pub unsafe fn vs_ecall_trap() -> ! {
+    handle_trap(GuestExit::Hypercall)
 }
 
+/// load and store guest page faults
+#[no_mangle]
+pub unsafe fn guest_page_fault_trap() -> ! {
+    let ctx = (TRAP_CONTEXT as *mut TrapContext).as_ref().unwrap();
+    handle_trap(arch::guest_exit(ctx.scause))
+}
 
+/// supervisor external interrupts, entered from their own vector
 #[no_mangle]
+pub unsafe fn external_irq_trap() -> ! {
+    handle_trap(GuestExit::Irq(Irq::External))
+}
+
 #[allow(unreachable_code)]
-pub unsafe fn trap_handler() -> ! {
+unsafe fn handle_trap(cause: GuestExit) -> ! {
     set_kernel_trap_entry();
     let ctx = (TRAP_CONTEXT as *mut TrapContext).as_mut().unwrap();
-    let scause = scause::read();
+    #[cfg(feature = "tracing")]
+    exitlat::exit_taken(cause);
+    // hot SBI calls only touch the running vcpu, handle them without taking the lock
+    if cause == GuestExit::Hypercall && fastpath::try_handle_sbi(ctx) {
+        #[cfg(feature = "tracing")]
+        exitlat::reclassify(exitlat::ExitClass::SbiFast);
+        switch_to_guest()
+    }
+    #[cfg(feature = "profiler")]
+    profiler::enter_hypervisor();
     let host_vmm = HOST_VMM.get_mut().unwrap();
     let mut host_vmm = host_vmm.lock();
+    let guest_id = host_vmm.guest_id;
+    if let Some(guest) = host_vmm.guests.get_mut(guest_id) {
+        guest.vcpu.stats.exits += 1 + fastpath::take_exits();
+        guest.check_vsatp();
+    }
     let mut err = None;
-    match scause.cause() {
-        Trap::Exception(Exception::UserEnvCall) => {
+    match cause {
+        GuestExit::UserCall => {
             panic!("U-mode/VU-mode env call from VS-mode?");
         },
-        Trap::Exception(Exception::VirtualSupervisorEnvCall) => {
-            if let Err(vmm_err) = sbi_vs_handler(ctx) {
+        // SBI calls of a kernel running on its own firmware
+        GuestExit::Hypercall if host_vmm.machine_ecall(ctx) => {},
+        GuestExit::Hypercall => {
+            if let Err(vmm_err) = sbi_vs_handler(&mut host_vmm, ctx) {
                 err = Some(vmm_err);
             }
             ctx.sepc += 4;
         },
-        Trap::Exception(Exception::VirtualInstruction) => {
-            if let Err(vmm_err) = privileged_inst_handler(ctx) {
+        GuestExit::VirtualInstruction => {
+            if let Err(vmm_err) = privileged_inst_handler(&mut host_vmm, ctx) {
                 err  = Some(vmm_err);
             }
         },
-        Trap::Exception(Exception::InstructionGuestPageFault) => { 
-            let host_vmm = unsafe{ HOST_VMM.get().unwrap().lock() };
-            let guest_id = host_vmm.guest_id;
-            let gpm = &host_vmm.guests[guest_id].as_ref().unwrap().gpm;
-            if let Some(host_va) = two_stage_translation(guest_id, ctx.sepc, vsatp::read().bits(), gpm) {
-                herror!("host va: {:#x}", host_va);
+        GuestExit::IllegalInstruction => {
+            if let Err(vmm_err) = host_vmm.handle_machine_inst(ctx) {
+                err = Some(vmm_err);
+            }
+        },
+        GuestExit::GuestPageFault(AccessType::Execute) => {
+            let gpm = &mut host_vmm.guests.get_mut(guest_id).unwrap().gpm;
+            // first fetch from a page without Svadu, see `mm::adbits`
+            if adbits::hardware_ad_update() || !gpm.update_ad(ctx.fault_guest_pa(), AccessType::Execute) {
+                match two_stage_translation(ctx.sepc, AccessType::Execute, &WalkContext::current(), gpm) {
+                    Ok(host_va) => herror!("host va: {:#x}", host_va),
+                    Err(fault) => herror!("Fail to translate exception pc: {:?}", fault)
+                }
+                herror!("InstructionGuestPageFault: sepc -> {:#x}, hgatp -> {:#x}", ctx.sepc, ctx.hgatp);
+                err = Some(VmmError::TranslationError);
             }else{
-                herror!("Fail to translate exception pc.");
+                arch::flush_stage2_tlb();
             }
-            panic!(
-                "InstructionGuestPageFault: sepc -> {:#x}, hgatp -> {:#x}", 
-                ctx.sepc, hgatp::read().bits()
-            );
     },
-    Trap::Exception(Exception::LoadGuestPageFault) | Trap::Exception(Exception::StoreGuestPageFault) => {
+    GuestExit::GuestPageFault(_) => {
         if let Err(vmm_err) = guest_page_fault_handler(&mut host_vmm, ctx) {
             err = Some(vmm_err);
         }
         host_vmm.guest_page_falut += 1;
         if host_vmm.guest_page_falut % 1000 == 0 {
-            htracking!("guest page fault: {}, addr: {:#x}", host_vmm.guest_page_falut, htval::read() << 2);
+            htracking!("guest page fault: {}, addr: {:#x}", host_vmm.guest_page_falut, ctx.fault_guest_pa());
+        }
+    },
+    GuestExit::Misaligned(_) => {
+        if let Err(vmm_err) = misaligned_access_handler(&host_vmm, ctx) {
+            err = Some(vmm_err);
         }
     },
-    Trap::Interrupt(Interrupt::SupervisorExternal) => {
+    GuestExit::Irq(Irq::External) => {
         handle_irq(&mut host_vmm, ctx);
         host_vmm.external_irq += 1;
         // htracking!("external irq: {}", host_vmm.external_irq);
     },
-    Trap::Interrupt(Interrupt::SupervisorTimer) => {
-        // set guest timer interrupt pending
-        hvip::set_vstip();
-        // disable timer interrupt
-        sie::clear_stimer();
+    GuestExit::Irq(Irq::CounterOverflow) => {
+        host_vmm.handle_counter_overflow();
+    },
+    GuestExit::Irq(Irq::Software) => {
+        // raised by the I/O service hart
+        host_vmm.complete_io();
+    },
+    GuestExit::Irq(Irq::Timer) => {
+        // deliver guest timer and preempt guest if its slice is over
+        bootprof::mark(BootPhase::FirstTimer);
+        host_vmm.handle_timer_irq();
         host_vmm.timer_irq += 1;
         // if host_vmm.timer_irq % 1000 == 0 {
         //     htracking!("timer irq: {}", host_vmm.timer_irq);
         // }
     },
-    _ => forward_exception(ctx),
+    GuestExit::Other => forward_exception(ctx),
     }
-    drop(host_vmm);
+    // errors caused by the guest cost the guest, not the hypervisor
     if let Some(err) = err {
-        // TODO: handler vmm error
-        handle_internal_vmm_error(err)
+        handle_guest_error(&mut host_vmm, ctx, cause, err);
     }
+    // machine interrupts of a guest running its own firmware, see `vmachine`
+    host_vmm.deliver_machine_irq(ctx);
+    // a reset of the trapped guest overrides the trap context written above
+    host_vmm.finish_pending_reset();
+    // switch guest if its slice is over or it was paused while handling the trap
+    host_vmm.schedule();
+    drop(host_vmm);
     switch_to_guest()
 }
 
 
 
+per_cpu! {
+    /// hgatp currently loaded on this hart, 0 before the first guest entry
+    static LOADED_HGATP: usize = 0;
+    /// `STAGE2_GENERATION` at the last stage-2 flush of this hart
+    static FLUSHED_GENERATION: usize = 0;
+}
+/// bumped when stage-2 mappings of a guest are removed, see `request_stage2_flush`
+static STAGE2_GENERATION: AtomicUsize = AtomicUsize::new(0);
+/// the hypervisor wrote instructions into guest memory since the last entry
+static NEED_FENCE_I: AtomicBool = AtomicBool::new(true);
+
+/// guest memory was (re)loaded by the hypervisor, execute `fence.i` before the next entry
+pub fn request_fence_i() {
+    NEED_FENCE_I.store(true, Ordering::Relaxed);
+}
+
+/// stage-2 mappings were removed while the guest may run on other harts, each hart
+/// flushes its stage-2 TLB before its next guest entry
+pub fn request_stage2_flush() {
+    STAGE2_GENERATION.fetch_add(1, Ordering::Release);
+}
+
+/// per-entry work that is only needed when the guest or its memory changed
+#[inline(always)]
+unsafe fn prepare_entry(ctx: &TrapContext) {
+    // hgatp: set page table for guest physical address translation, only changes on guest switch
+    if LOADED_HGATP.get() != ctx.hgatp {
+        arch::load_stage2(ctx.hgatp);
+        LOADED_HGATP.set(ctx.hgatp);
+    }
+    let generation = STAGE2_GENERATION.load(Ordering::Acquire);
+    if FLUSHED_GENERATION.get() != generation {
+        arch::flush_stage2_tlb();
+        FLUSHED_GENERATION.set(generation);
+    }
+    if NEED_FENCE_I.swap(false, Ordering::Relaxed) {
+        arch::sync_icache();
+    }
+}
+
 pub unsafe fn hart_entry_1() -> ! {
     set_user_trap_entry();
     // get guest context
     let ctx = (TRAP_CONTEXT as *mut TrapContext).as_mut().unwrap();
 
-    // hgatp: set page table for guest physical address translation
-    if riscv::register::hgatp::read().bits() != ctx.hgatp {
-        let hgatp = riscv::register::hgatp::Hgatp::from_bits(ctx.hgatp);
-        hgatp.write(); 
-        core::arch::riscv64::hfence_gvma_all();
-        assert_eq!(hgatp.bits(), riscv::register::hgatp::read().bits());
-    }
+    prepare_entry(ctx);
+    bootprof::mark(BootPhase::FirstEntry);
     hart_entry_2()
 }
 
@@ -236,7 +470,6 @@ pub unsafe fn hart_entry_1() -> ! {
 #[naked]
 pub unsafe extern "C" fn hart_entry_2() -> ! {
     core::arch::asm!(
-        "fence.i",
         "li a0, {trap_context}",
         "csrw sscratch, a0",
         "mv sp, a0",
@@ -289,18 +522,17 @@ pub unsafe extern "C" fn hart_entry_2() -> ! {
 /// set the reg a0 = trap_cx_ptr, reg a1 = phy addr of usr page table,
 /// finally, jump to new addr of __restore asm function
 pub unsafe fn switch_to_guest() -> ! {
+    // interrupts must be masked before the trap entry of the guest is installed
+    #[cfg(feature = "profiler")]
+    profiler::leave_hypervisor();
     set_user_trap_entry();
     // get guest context
     let ctx = (TRAP_CONTEXT as *mut TrapContext).as_mut().unwrap();
     // hdebug!("ctx sp: {:#x}, scause: {:?}", ctx.x[2], scause::read().cause());
 
-    // hgatp: set page table for guest physical address translation
-    if riscv::register::hgatp::read().bits() != ctx.hgatp {
-        let hgatp = riscv::register::hgatp::Hgatp::from_bits(ctx.hgatp);
-        hgatp.write(); 
-        core::arch::riscv64::hfence_gvma_all();
-        assert_eq!(hgatp.bits(), riscv::register::hgatp::read().bits());
-    }
+    prepare_entry(ctx);
+    #[cfg(feature = "tracing")]
+    exitlat::entering_guest();
 
     extern "C" {
         fn __alltraps();
@@ -309,7 +541,6 @@ pub unsafe fn switch_to_guest() -> ! {
     let restore_va = __restore as usize - __alltraps as usize + TRAMPOLINE;
     unsafe {
         asm!(
-            "fence.i",
             "jr {restore_va}",             // jump to new addr of __restore asm function
             restore_va = in(reg) restore_va,
             in("a0") TRAP_CONTEXT,           // a0 = virt addr of Trap Context
@@ -319,15 +550,28 @@ pub unsafe fn switch_to_guest() -> ! {
 }
 
 
+/// trap taken by the hypervisor itself, returns to the interrupted code through
+/// `__restore_k` if it was expected. The handlers it may run depend on how deeply it is
+/// nested, see `nested`
 #[no_mangle]
-pub fn trap_from_kernel(_trap_cx: &TrapContext) -> ! {
-    let scause= scause::read();
-    let sepc = sepc::read();
-    match scause.cause() {
-        Trap::Exception(Exception::StoreFault) | Trap::Exception(Exception::LoadFault) | Trap::Exception(Exception::LoadPageFault)=> {
-            let stval = stval::read();
-            panic!("scause: {:?}, sepc: {:#x}, stval: {:#x}", scause.cause(), _trap_cx.sepc, stval);
+pub fn trap_from_kernel(_trap_cx: &mut TrapContext) {
+    let (trap, info) = arch::host_trap();
+    let rule = nested::enter();
+    match (rule, trap) {
+        (NestRule::Stop, _) => nested::stop(info.cause, info.pc, info.addr),
+        #[cfg(feature = "trap_test")]
+        (_, trap) if !matches!(trap, HostTrap::Irq(_)) && nested::test_load_fault(&mut _trap_cx.sepc, info.cause) => {},
+        #[cfg(feature = "profiler")]
+        (NestRule::Any, HostTrap::Irq(irq)) if profiler::kernel_interrupt(irq, _trap_cx) => {},
+        (_, HostTrap::PageFault) if framemap::spurious_fault(info.addr) => {},
+        #[cfg(feature = "trap_test")]
+        (_, HostTrap::Breakpoint) if nested::test_breakpoint(&mut _trap_cx.sepc) => {},
+        // the interrupted handler may hold any lock, panicking could deadlock
+        (NestRule::LockFree, _) => nested::stop(info.cause, info.pc, info.addr),
+        (_, HostTrap::AccessFault) | (_, HostTrap::PageFault) => {
+            panic!("trap: {:?} ({:#x}), sepc: {:#x}, stval: {:#x} ({})", trap, info.cause, _trap_cx.sepc, info.addr, framemap::region(info.addr));
         },
-        _ => { panic!("scause: {:?}, spec: {:#x}, stval: {:#x}", scause.cause(), sepc, stval::read())}
+        _ => { panic!("trap: {:?} ({:#x}), sepc: {:#x}, stval: {:#x}", trap, info.cause, info.pc, info.addr)}
     }
+    nested::leave();
 }
\ No newline at end of file
@@ -0,0 +1,99 @@
+//! `wfi` of guests
+//!
+//! By default a guest runs with `hstatus.VTW` clear: its `wfi` stalls the hart until an
+//! interrupt is pending, one of the guest or of the hypervisor, as on bare metal. The guest
+//! wakes up without a trap but keeps the hart until the next scheduler tick.
+//!
+//! With `WfiPolicy::Trap` the guest runs with `VTW` set and its `wfi` traps. The
+//! hypervisor spins for up to `spin` cycles waiting for an interrupt, then gives the rest
+//! of the slice to the other harts of the guest and to the other guests. When nothing
+//! else may run the hart waits in `wfi` in the hypervisor. A short spin keeps the wakeup
+//! latency of a guest with bursty interrupts low, no spin gives the others the most time.
+//!
+//! `hvc.wfi=native|yield|spin,<usecs>` sets the policy of the guest.
+
+use riscv::register::time;
+
+use super::page_table::GuestPageTable;
+use super::vmexit::{ TrapContext, inject_exception };
+use crate::arch::{ self, GuestException };
+use crate::constants::CLOCK_FREQ;
+use crate::constants::sched::VCPU_MIN_RUN;
+use crate::hypervisor::HostVmm;
+use crate::page_table::{ PageTable, Privilege, WalkContext };
+use crate::VmmResult;
+
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WfiPolicy {
+    /// `wfi` runs on the hart
+    Native,
+    /// `wfi` traps, spin for `spin` cycles before yielding
+    Trap { spin: usize }
+}
+
+impl WfiPolicy {
+    /// `native`, `yield` or `spin,<usecs>`
+    pub fn parse(arg: &str) -> Option<Self> {
+        match arg {
+            "native" => Some(Self::Native),
+            "yield" => Some(Self::Trap { spin: 0 }),
+            _ => {
+                let usecs = arg.strip_prefix("spin,")?.parse::<usize>().ok()?;
+                Some(Self::Trap { spin: usecs.checked_mul(CLOCK_FREQ / 1_000_000)? })
+            }
+        }
+    }
+
+    /// value of `hstatus.VTW`
+    pub fn traps(&self) -> bool {
+        matches!(self, Self::Trap { .. })
+    }
+}
+
+impl Default for WfiPolicy {
+    fn default() -> Self {
+        Self::Native
+    }
+}
+
+impl<P: PageTable, G: GuestPageTable> HostVmm<P, G> {
+    /// the running hart has nothing to do: the other harts of the guest and the other
+    /// guests get the rest of its slice
+    pub fn yield_vcpu(&mut self, now: usize) {
+        if let Some(guest) = self.guests.get_mut(self.guest_id) {
+            guest.vcpu.slice_start = guest.vcpu.slice_start.min(now.saturating_sub(VCPU_MIN_RUN));
+        }
+        self.sched.yield_slice(now);
+    }
+
+    /// `wfi` of `len` bytes trapped by `hstatus.VTW`
+    pub fn handle_wfi(&mut self, ctx: &mut TrapContext, len: usize) -> VmmResult {
+        // an illegal instruction in U-mode
+        if WalkContext::current().privilege == Privilege::User {
+            let inst = ctx.stval;
+            inject_exception(ctx, GuestException::IllegalInstruction, inst);
+            return Ok(())
+        }
+        ctx.sepc += len;
+        let spin = match self.guests.get(self.guest_id).map(|guest| guest.config.wfi) {
+            Some(WfiPolicy::Trap { spin }) => spin,
+            _ => 0
+        };
+        let deadline = time::read() + spin;
+        while time::read() < deadline {
+            if arch::irq_pending() {
+                return Ok(())
+            }
+            core::hint::spin_loop();
+        }
+        let now = time::read();
+        self.yield_vcpu(now);
+        let hart_due = self.guests.get(self.guest_id).map_or(false, |guest| guest.next_hart(now).is_some());
+        if !hart_due && !self.sched.need_resched(now) && !arch::irq_pending() {
+            // the interrupt is taken once the guest runs again
+            arch::wait_for_interrupt();
+        }
+        Ok(())
+    }
+}
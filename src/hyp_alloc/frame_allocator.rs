@@ -1,26 +1,66 @@
 //! Implementation of [`FrameAllocator`] which
 //! controls all the frames in the operating system.
+//!
+//! Each hart keeps a small cache of free frames, refilled from and flushed to the
+//! global pool in batches, so that allocating or freeing a single frame, e.g. in a
+//! guest page fault, usually takes no lock.
+//!
+//! Frames are zeroed when they are handed out. `ScrubPolicy` also zeroes them when they
+//! are freed, so that a guest's data does not sit in free memory: right away, or queued
+//! and zeroed by `scrub_pending` while the hart idles. Queued frames are not reused before
+//! they are scrubbed. Frames marked with `FrameTracker::set_scrub`, those of enclave
+//! guests, are zeroed on free whatever the policy.
+//!
+//! Debug builds count the live trackers, see `live_frames`, for the leak check of guest
+//! teardown. The `alloc_debug` feature also tags every frame with its owner and allocation
+//! site and catches double frees, see `leak`.
+//!
+//! A frame is mapped in the hypervisor only from `frame_alloc` until it is back in the
+//! allocator, and not while it is hidden, see `mm::framemap`.
 
 use crate::page_table::{PhysPageNum, PhysAddr};
 use crate::constants::layout::MEMORY_END;
+use crate::mm::framemap;
 use alloc::vec::Vec;
-use spin::{Once, Mutex};
+use spin::Once;
+use crate::sync::{ SpinIrqSave, with_irq_masked };
 use core::fmt::{self, Debug, Formatter};
+use core::sync::atomic::{ AtomicU8, AtomicUsize, Ordering };
 
 /// manage a frame which has the same lifecycle as the tracker
 #[derive(Clone)]
 pub struct FrameTracker {
     pub ppn: PhysPageNum,
+    /// zeroed on free even with `ScrubPolicy::Off`
+    scrub: bool,
+    /// unmapped from the hypervisor, see `hide`
+    hidden: bool
 }
 
 impl FrameTracker {
+    #[cfg_attr(feature = "alloc_debug", track_caller)]
     pub fn new(ppn: PhysPageNum) -> Self {
         // page cleaning
         let bytes_array = ppn.get_bytes_array();
         for i in bytes_array {
             *i = 0;
         }
-        Self { ppn }
+        #[cfg(debug_assertions)]
+        LIVE_FRAMES.fetch_add(1, Ordering::Relaxed);
+        #[cfg(feature = "alloc_debug")]
+        super::leak::track(ppn, core::panic::Location::caller());
+        Self { ppn, scrub: false, hidden: false }
+    }
+
+    /// the frame holds data of an enclave guest
+    pub fn set_scrub(&mut self) {
+        self.scrub = true;
+    }
+
+    /// unmap the frame from the hypervisor until it is freed, it is reached with
+    /// `framemap::with_frame` meanwhile
+    pub fn hide(&mut self) {
+        self.hidden = framemap::hide_frame(self.ppn);
     }
 }
 
@@ -32,8 +72,84 @@ impl Debug for FrameTracker {
 
 impl Drop for FrameTracker {
     fn drop(&mut self) {
-        frame_dealloc(self.ppn);
+        #[cfg(feature = "alloc_debug")]
+        super::leak::untrack(self.ppn);
+        #[cfg(debug_assertions)]
+        LIVE_FRAMES.fetch_sub(1, Ordering::Relaxed);
+        // scrubbing and the scrub queue write to the frame, unmapped again by `frame_dealloc`
+        if self.hidden {
+            framemap::map_frame(self.ppn);
+        }
+        match (scrub_policy(), self.scrub) {
+            (ScrubPolicy::Lazy, _) => {
+                // queued frames are linked through their first word, queueing never allocates
+                let mut head = SCRUB_QUEUE.lock();
+                self.ppn.get_bytes_array()[..8].copy_from_slice(&head.to_le_bytes());
+                *head = self.ppn.0;
+            },
+            (ScrubPolicy::Immediate, _) | (ScrubPolicy::Off, true) => {
+                self.ppn.get_bytes_array().fill(0);
+                frame_dealloc(self.ppn);
+            },
+            (ScrubPolicy::Off, false) => frame_dealloc(self.ppn)
+        }
+    }
+}
+
+/// when freed frames are zeroed
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ScrubPolicy {
+    /// only when they are handed out again
+    Off,
+    /// when they are freed
+    Immediate,
+    /// in the background, see `scrub_pending`
+    Lazy
+}
+
+/// frame trackers alive, in debug builds
+static LIVE_FRAMES: AtomicUsize = AtomicUsize::new(0);
+
+/// frames held by trackers, always 0 in release builds
+pub fn live_frames() -> usize {
+    LIVE_FRAMES.load(Ordering::Relaxed)
+}
+
+static SCRUB_POLICY: AtomicU8 = AtomicU8::new(ScrubPolicy::Off as u8);
+/// first of the freed frames waiting to be zeroed with `ScrubPolicy::Lazy`, 0 if none
+static SCRUB_QUEUE: SpinIrqSave<usize> = SpinIrqSave::new(0);
+
+pub fn set_scrub_policy(policy: ScrubPolicy) {
+    SCRUB_POLICY.store(policy as u8, Ordering::Relaxed);
+}
+
+pub fn scrub_policy() -> ScrubPolicy {
+    match SCRUB_POLICY.load(Ordering::Relaxed) {
+        1 => ScrubPolicy::Immediate,
+        2 => ScrubPolicy::Lazy,
+        _ => ScrubPolicy::Off
+    }
+}
+
+/// zero up to `budget` queued frames and give them back, returns how many were scrubbed
+pub fn scrub_pending(budget: usize) -> usize {
+    let mut scrubbed = 0;
+    while scrubbed < budget {
+        // the queue is not locked while a frame is zeroed
+        let ppn = {
+            let mut head = SCRUB_QUEUE.lock();
+            if *head == 0 {
+                break
+            }
+            let ppn = PhysPageNum::from(*head);
+            *head = usize::from_le_bytes(ppn.get_bytes_array()[..8].try_into().unwrap());
+            ppn
+        };
+        ppn.get_bytes_array().fill(0);
+        frame_dealloc(ppn);
+        scrubbed += 1;
     }
+    scrubbed
 }
 
 trait FrameAllocator {
@@ -44,6 +160,7 @@ trait FrameAllocator {
 
 /// an implementation for frame allocator
 pub struct StackFrameAllocator {
+    start: usize,
     current: usize,
     end: usize,
     recycled: Vec<usize>,
@@ -51,6 +168,7 @@ pub struct StackFrameAllocator {
 
 impl StackFrameAllocator {
     pub fn init(&mut self, l: PhysPageNum, r: PhysPageNum) {
+        self.start = l.0;
         self.current = l.0;
         self.end = r.0;
     }
@@ -58,6 +176,7 @@ impl StackFrameAllocator {
 impl FrameAllocator for StackFrameAllocator {
     fn new() -> Self {
         Self {
+            start: 0,
             current: 0,
             end: 0,
             recycled: Vec::new(),
@@ -88,7 +207,7 @@ type FrameAllocatorImpl = StackFrameAllocator;
 
 
 
-pub static mut FRAME_ALLOCATOR: Once<Mutex<FrameAllocatorImpl>> = Once::new();
+pub static mut FRAME_ALLOCATOR: Once<SpinIrqSave<FrameAllocatorImpl>> = Once::new();
 
 /// initiate the frame allocator using `ekernel` and `MEMORY_END`
 pub fn init_frame_allocator() {
@@ -102,31 +221,117 @@ pub fn init_frame_allocator() {
                 PhysAddr::from(ekernel as usize).ceil(),
                 PhysAddr::from(MEMORY_END).floor(),
             );
-            Mutex::new(frame_allocator)
+            SpinIrqSave::new(frame_allocator)
         }); 
     }
 }
 
+/// frames managed by the allocator and frames left in the global pool,
+/// frames cached by harts count as used
+pub fn frame_usage() -> (usize, usize) {
+    let frame_allocator = unsafe{ FRAME_ALLOCATOR.get().unwrap().lock() };
+    let total = frame_allocator.end - frame_allocator.start;
+    let free = frame_allocator.end - frame_allocator.current + frame_allocator.recycled.len();
+    (total, free)
+}
+
+/// call `f` on the frames of the global pool and of the cache of the running hart
+pub fn for_each_free_frame(mut f: impl FnMut(PhysPageNum)) {
+    let frame_allocator = unsafe{ FRAME_ALLOCATOR.get().unwrap().lock() };
+    (frame_allocator.current..frame_allocator.end)
+        .chain(frame_allocator.recycled.iter().copied())
+        .for_each(|ppn| f(ppn.into()));
+    drop(frame_allocator);
+    with_irq_masked(|| {
+        let cache = unsafe{ FRAME_CACHE.as_mut() };
+        cache.frames[..cache.len].iter().for_each(|&ppn| f(ppn.into()));
+    });
+}
+
+/// frames kept by each hart
+const FRAME_CACHE_SIZE: usize = 64;
+/// frames moved between a hart cache and the global pool at once
+const FRAME_CACHE_BATCH: usize = FRAME_CACHE_SIZE / 2;
+
+/// free frames owned by one hart
+struct FrameCache {
+    frames: [usize; FRAME_CACHE_SIZE],
+    len: usize
+}
+
+impl FrameCache {
+    const fn new() -> Self {
+        Self { frames: [0; FRAME_CACHE_SIZE], len: 0 }
+    }
+
+    /// take up to a batch of frames from the global pool
+    fn refill(&mut self) {
+        let mut frame_allocator = unsafe{ FRAME_ALLOCATOR.get().unwrap().lock() };
+        while self.len < FRAME_CACHE_BATCH {
+            match frame_allocator.alloc() {
+                Some(ppn) => {
+                    self.frames[self.len] = ppn.0;
+                    self.len += 1;
+                },
+                None => break
+            }
+        }
+    }
+
+    /// give the oldest batch of frames back to the global pool
+    fn flush(&mut self) {
+        let mut frame_allocator = unsafe{ FRAME_ALLOCATOR.get().unwrap().lock() };
+        for &ppn in &self.frames[..FRAME_CACHE_BATCH] {
+            frame_allocator.dealloc(ppn.into());
+        }
+        self.frames.copy_within(FRAME_CACHE_BATCH..self.len, 0);
+        self.len -= FRAME_CACHE_BATCH;
+    }
+}
+
+per_cpu! {
+    static FRAME_CACHE: FrameCache = FrameCache::new();
+}
+
 /// allocate a frame
+#[cfg_attr(feature = "alloc_debug", track_caller)]
 pub fn frame_alloc() -> Option<FrameTracker> {
-    // FRAME_ALLOCATOR
-    //     .exclusive_access()
-    //     .alloc()
-    //     .map(FrameTracker::new)
-    unsafe{
-        let mut frame_allocator = FRAME_ALLOCATOR.get_mut();
-        let mut frame_allocator = frame_allocator.as_mut().unwrap().lock();
-        frame_allocator.alloc().map(FrameTracker::new)
-    }
+    let ppn = frame_alloc_ppn().or_else(|| {
+        // out of clean frames, scrub the queued ones now
+        if scrub_pending(usize::MAX) > 0 { frame_alloc_ppn() } else { None }
+    })?;
+    framemap::map_frame(ppn.into());
+    Some(FrameTracker::new(ppn.into()))
+}
+
+fn frame_alloc_ppn() -> Option<usize> {
+    with_irq_masked(|| {
+        let cache = unsafe{ FRAME_CACHE.as_mut() };
+        if cache.len == 0 {
+            cache.refill();
+        }
+        if cache.len == 0 {
+            return None
+        }
+        cache.len -= 1;
+        Some(cache.frames[cache.len])
+    })
 }
 
 /// deallocate a frame
 pub fn frame_dealloc(ppn: PhysPageNum) {
-    unsafe{
-        let mut frame_allocator = FRAME_ALLOCATOR.get_mut();
-        let mut frame_allocator = frame_allocator.as_mut().unwrap().lock();
-        frame_allocator.dealloc(ppn);
-    }
+    with_irq_masked(|| {
+        let cache = unsafe{ FRAME_CACHE.as_mut() };
+        if cache.frames[..cache.len].contains(&ppn.0) {
+            panic!("Frame ppn={:#x} has not been allocated!", ppn.0);
+        }
+        framemap::unmap_frame(ppn);
+        if cache.len == FRAME_CACHE_SIZE {
+            cache.flush();
+        }
+        cache.frames[cache.len] = ppn.0;
+        cache.len += 1;
+    });
 }
 
 #[allow(unused)]
@@ -1,11 +1,69 @@
 //! The global allocator
 
-use crate::constants::KERNEL_HEAP_SIZE;
-use buddy_system_allocator::LockedHeap;
+use core::alloc::{ GlobalAlloc, Layout };
+use core::fmt::Write;
+use core::ptr::null_mut;
+use crate::constants::{ KERNEL_HEAP_SIZE, PAGE_SIZE };
+use crate::page_table::PhysAddr;
+use crate::sync::SpinIrqSave;
+use super::frame_allocator::frame_alloc;
+use super::slab::SlabHeap;
+
+/// Called when the heap cannot serve `layout`. Returns a memory region `(start, size)`
+/// handed to the heap for good, `None` if nothing could be reclaimed.
+pub type OomHook = fn(Layout) -> Option<(usize, usize)>;
+
+pub struct LockedSlabHeap {
+    heap: SpinIrqSave<SlabHeap>,
+    oom_hook: SpinIrqSave<Option<OomHook>>
+}
+
+unsafe impl GlobalAlloc for LockedSlabHeap {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        if let Some(ptr) = self.heap.lock().alloc(layout) {
+            return ptr.as_ptr()
+        }
+        // empty slabs first, then whatever the hook reclaims
+        {
+            let mut heap = self.heap.lock();
+            if heap.shrink() > 0 {
+                if let Some(ptr) = heap.alloc(layout) {
+                    return ptr.as_ptr()
+                }
+            }
+        }
+        // the hook may allocate frames, the heap is not locked meanwhile
+        let hook = *self.oom_hook.lock();
+        let mut heap = match hook.and_then(|hook| hook(layout)) {
+            Some((start, size)) => {
+                let mut heap = self.heap.lock();
+                heap.add_region(start, size);
+                heap
+            },
+            None => self.heap.lock()
+        };
+        match heap.alloc(layout) {
+            Some(ptr) => ptr.as_ptr(),
+            None => {
+                heap.record_failure();
+                null_mut()
+            }
+        }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        if let Some(ptr) = core::ptr::NonNull::new(ptr) {
+            self.heap.lock().dealloc(ptr, layout);
+        }
+    }
+}
 
 #[global_allocator]
 /// heap allocator instance
-static HEAP_ALLOCATOR: LockedHeap = LockedHeap::empty();
+static HEAP_ALLOCATOR: LockedSlabHeap = LockedSlabHeap {
+    heap: SpinIrqSave::new(SlabHeap::new()),
+    oom_hook: SpinIrqSave::new(None)
+};
 
 #[alloc_error_handler]
 /// panic when heap allocation error occurs
@@ -16,13 +74,38 @@ pub fn handle_alloc_error(layout: core::alloc::Layout) -> ! {
 /// heap space ([u8; KERNEL_HEAP_SIZE])
 static mut HEAP_SPACE: [u8; KERNEL_HEAP_SIZE] = [0; KERNEL_HEAP_SIZE];
 
+/// default OOM hook: grow the heap by one frame, enough for a slab or a small allocation
+fn grow_with_frame(layout: Layout) -> Option<(usize, usize)> {
+    if layout.size().max(layout.align()) > PAGE_SIZE {
+        return None
+    }
+    let frame = frame_alloc()?;
+    let start = PhysAddr::from(frame.ppn).0;
+    // owned by the heap from now on
+    #[cfg(feature = "alloc_debug")]
+    super::leak::disown(frame.ppn);
+    core::mem::forget(frame);
+    Some((start, PAGE_SIZE))
+}
+
 /// initiate heap allocator
 pub fn init_heap() {
     unsafe {
-        HEAP_ALLOCATOR
+        HEAP_ALLOCATOR.heap
             .lock()
-            .init(HEAP_SPACE.as_ptr() as usize, KERNEL_HEAP_SIZE);
+            .add_region(HEAP_SPACE.as_ptr() as usize, KERNEL_HEAP_SIZE);
     }
+    set_oom_hook(grow_with_frame);
+}
+
+/// replace the hook called when the heap is exhausted
+pub fn set_oom_hook(hook: OomHook) {
+    *HEAP_ALLOCATOR.oom_hook.lock() = Some(hook);
+}
+
+/// print heap usage per size class
+pub fn heap_report(out: &mut dyn Write) {
+    HEAP_ALLOCATOR.heap.lock().report_to(out);
 }
 
 #[allow(unused)]
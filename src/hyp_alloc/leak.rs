@@ -0,0 +1,163 @@
+//! Frame ownership tracking, built with the `alloc_debug` feature
+//!
+//! Every frame handed out by `frame_alloc` is tagged with its owner and the caller that
+//! allocated it, the first function up the stack not marked `#[track_caller]`. The owner
+//! is the one set on the allocating hart: guest creation and reset set the guest whose
+//! stage-2 table they build with `OwnerScope`, everything else belongs to the hypervisor,
+//! also what is allocated while handling an exit, as host page table frames outlive the
+//! guest. Frames kept by the hypervisor
+//! for good, like heap growth, are handed over with `disown`.
+//!
+//! - freeing a frame twice, e.g. through two clones of a `FrameTracker`, panics with the
+//!   site that allocated it
+//! - `check_guest` at guest teardown reports the frames still tagged with the guest,
+//!   grouped by site, they are kept as orphans since the guest id is reused
+//! - `report_to` lists the live frames per owner and site, the monitor `leaks` command
+//!
+//! The tags live in a table with one entry per frame of ram, nothing here allocates from
+//! the heap while the table is locked, heap growth allocates frames itself.
+
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+use core::fmt::Write;
+use core::panic::Location;
+
+use crate::constants::PAGE_SIZE;
+use crate::constants::layout::{ MEMORY_END, MEMORY_START };
+use crate::page_table::PhysPageNum;
+use crate::sync::SpinIrqSave;
+
+/// owner of frames allocated outside of any guest
+pub const HYPERVISOR: usize = usize::MAX;
+/// owner of frames left behind by a destroyed guest
+pub const ORPHAN: usize = usize::MAX - 1;
+
+const TRACKED_FRAMES: usize = (MEMORY_END - MEMORY_START) / PAGE_SIZE;
+
+#[derive(Clone, Copy)]
+struct FrameRecord {
+    owner: usize,
+    /// kept after the frame is freed, to report a double free
+    site: Option<&'static Location<'static>>,
+    live: bool
+}
+
+impl FrameRecord {
+    /// all zero, the table stays in `.bss`
+    const UNUSED: Self = Self { owner: 0, site: None, live: false };
+}
+
+static RECORDS: SpinIrqSave<[FrameRecord; TRACKED_FRAMES]> = SpinIrqSave::new([FrameRecord::UNUSED; TRACKED_FRAMES]);
+
+per_cpu! {
+    /// owner of the frames allocated by this hart
+    static OWNER: usize = HYPERVISOR;
+}
+
+fn index(ppn: PhysPageNum) -> usize {
+    let index = ppn.0.wrapping_sub(MEMORY_START / PAGE_SIZE);
+    assert!(index < TRACKED_FRAMES, "frame ppn={:#x} outside of ram", ppn.0);
+    index
+}
+
+/// charges the frames allocated by this hart to a guest until dropped
+pub struct OwnerScope {
+    previous: usize
+}
+
+impl OwnerScope {
+    pub fn guest(guest_id: usize) -> Self {
+        let previous = OWNER.get();
+        OWNER.set(guest_id);
+        Self { previous }
+    }
+}
+
+impl Drop for OwnerScope {
+    fn drop(&mut self) {
+        OWNER.set(self.previous);
+    }
+}
+
+/// tag a frame being handed out
+pub fn track(ppn: PhysPageNum, site: &'static Location<'static>) {
+    let mut records = RECORDS.lock();
+    let record = &mut records[index(ppn)];
+    if record.live {
+        let previous = record.site.unwrap();
+        drop(records);
+        panic!("frame ppn={:#x} allocated at {} handed out again at {}", ppn.0, previous, site);
+    }
+    *record = FrameRecord { owner: OWNER.get(), site: Some(site), live: true };
+}
+
+/// untag a frame being freed, panics if it is not live
+pub fn untrack(ppn: PhysPageNum) {
+    let mut records = RECORDS.lock();
+    let record = &mut records[index(ppn)];
+    if !record.live {
+        let site = record.site;
+        drop(records);
+        match site {
+            Some(site) => panic!("double free of frame ppn={:#x} allocated at {}", ppn.0, site),
+            None => panic!("free of frame ppn={:#x} never allocated", ppn.0)
+        }
+    }
+    record.live = false;
+}
+
+/// the frame is kept by the hypervisor for good
+pub fn disown(ppn: PhysPageNum) {
+    RECORDS.lock()[index(ppn)].owner = HYPERVISOR;
+}
+
+/// live frames of `owner` counted per allocation site, largest first
+fn frames_by_site(owner: Option<usize>) -> Vec<(usize, &'static Location<'static>, usize)> {
+    // the table is not locked while the heap may grow
+    let live = RECORDS.lock().iter().filter(|record| record.live).count();
+    let mut sites: Vec<(usize, &'static Location<'static>)> = Vec::with_capacity(live);
+    for record in RECORDS.lock().iter().filter(|record| record.live && owner.map_or(true, |owner| record.owner == owner)) {
+        if sites.len() == sites.capacity() {
+            break
+        }
+        sites.push((record.owner, record.site.unwrap()));
+    }
+    let mut counts = BTreeMap::new();
+    for (owner, site) in sites {
+        counts.entry((owner, site.file(), site.line())).or_insert((site, 0)).1 += 1;
+    }
+    let mut frames: Vec<_> = counts.into_iter().map(|((owner, _, _), (site, count))| (owner, site, count)).collect();
+    frames.sort_by(|a, b| b.2.cmp(&a.2));
+    frames
+}
+
+/// report the frames of a destroyed guest that were not freed, returns their number.
+/// They become orphans, a later guest with the same id starts clean.
+pub fn check_guest(guest_id: usize) -> usize {
+    let leaked = frames_by_site(Some(guest_id));
+    let total = leaked.iter().map(|&(_, _, count)| count).sum();
+    if total == 0 {
+        return 0
+    }
+    herror!("guest {} leaked {} frames", guest_id, total);
+    for (_, site, count) in leaked {
+        herror!("  {:>6} allocated at {}", count, site);
+    }
+    for record in RECORDS.lock().iter_mut().filter(|record| record.live && record.owner == guest_id) {
+        record.owner = ORPHAN;
+    }
+    total
+}
+
+/// print the live frames per owner and allocation site
+pub fn report_to(out: &mut dyn Write) {
+    let frames = frames_by_site(None);
+    let _ = writeln!(out, "{:>6} {:>10}  site", "frames", "owner");
+    for (owner, site, count) in frames {
+        let _ = match owner {
+            HYPERVISOR => writeln!(out, "{:>6} {:>10}  {}", count, "hypervisor", site),
+            ORPHAN => writeln!(out, "{:>6} {:>10}  {}", count, "orphan", site),
+            guest_id => writeln!(out, "{:>6} {:>10}  {}", count, guest_id, site)
+        };
+    }
+}
@@ -1,7 +1,12 @@
 mod frame_allocator;
 mod heap_allocator;
+mod slab;
+#[cfg(feature = "alloc_debug")]
+pub mod leak;
 
-pub use frame_allocator::{frame_alloc, frame_dealloc, FrameTracker};
+pub use frame_allocator::{frame_alloc, frame_dealloc, frame_usage, live_frames, for_each_free_frame, FrameTracker};
+pub use frame_allocator::{ ScrubPolicy, set_scrub_policy, scrub_policy, scrub_pending };
+pub use heap_allocator::{ set_oom_hook, heap_report, OomHook };
 
 /// initiate heap allocator, frame allocator and kernel space
 pub fn heap_init() {
@@ -0,0 +1,323 @@
+//! Slab allocator behind the hypervisor heap
+//!
+//! Small allocations are served from per-size-class slabs: pages carved into objects of
+//! one power-of-two size, with a free list threaded through the free objects. A slab
+//! page starts with its `Slab` header, the header of an object is found by masking its
+//! address. Larger allocations and the slab pages themselves come from a buddy heap.
+//! Empty slabs beyond one per class are given back to the buddy heap at once.
+//!
+//! With the `alloc_debug` feature freeing an object already on the free list of its slab
+//! panics, larger allocations are not checked.
+
+use core::alloc::Layout;
+use core::fmt::Write;
+use core::mem::size_of;
+use core::ptr::{ null_mut, NonNull };
+
+use buddy_system_allocator::Heap;
+
+use crate::constants::PAGE_SIZE;
+
+/// size of a slab, a slab page is aligned to its size
+const SLAB_SIZE: usize = PAGE_SIZE;
+const MIN_CLASS_SHIFT: usize = 4;
+const MAX_CLASS_SHIFT: usize = 10;
+/// size classes 16, 32, .. 1024 bytes
+const NUM_CLASSES: usize = MAX_CLASS_SHIFT - MIN_CLASS_SHIFT + 1;
+
+struct FreeObject {
+    next: *mut FreeObject
+}
+
+/// header at the start of every slab page
+#[repr(C)]
+struct Slab {
+    /// next slab with free objects in the same class
+    next: *mut Slab,
+    prev: *mut Slab,
+    free: *mut FreeObject,
+    in_use: usize,
+    capacity: usize
+}
+
+#[derive(Clone, Copy, Default)]
+struct SizeClass {
+    /// slabs with at least one free object
+    partial: Option<NonNull<Slab>>,
+    slabs: usize,
+    empty_slabs: usize,
+    objects_in_use: usize,
+    /// bytes requested by the allocations in use, the rest of their objects is wasted
+    requested: usize,
+    allocs: usize
+}
+
+/// Allocation statistics of one size class
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ClassStats {
+    pub size: usize,
+    pub slabs: usize,
+    pub objects_in_use: usize,
+    pub objects_free: usize,
+    pub requested: usize,
+    pub allocs: usize
+}
+
+pub struct SlabHeap {
+    classes: [SizeClass; NUM_CLASSES],
+    backing: Heap,
+    /// allocations served by the buddy heap directly
+    large_in_use: usize,
+    large_bytes: usize,
+    /// allocations that could not be served
+    failures: usize
+}
+
+unsafe impl Send for SlabHeap {}
+
+/// size class of `layout`, `None` for allocations served by the buddy heap
+fn class_of(layout: &Layout) -> Option<usize> {
+    let size = layout.size().max(layout.align()).max(1 << MIN_CLASS_SHIFT).next_power_of_two();
+    let shift = size.trailing_zeros() as usize;
+    if shift > MAX_CLASS_SHIFT {
+        return None
+    }
+    Some(shift - MIN_CLASS_SHIFT)
+}
+
+const fn class_size(class: usize) -> usize {
+    1 << (class + MIN_CLASS_SHIFT)
+}
+
+/// offset of the first object of a slab, objects are aligned to their size
+const fn first_object(class: usize) -> usize {
+    let size = class_size(class);
+    (size_of::<Slab>() + size - 1) / size * size
+}
+
+impl SlabHeap {
+    pub const fn new() -> Self {
+        Self {
+            classes: [SizeClass {
+                partial: None, slabs: 0, empty_slabs: 0, objects_in_use: 0, requested: 0, allocs: 0
+            }; NUM_CLASSES],
+            backing: Heap::new(),
+            large_in_use: 0,
+            large_bytes: 0,
+            failures: 0
+        }
+    }
+
+    /// add `[start, start + size)` to the memory of the heap
+    pub unsafe fn add_region(&mut self, start: usize, size: usize) {
+        self.backing.add_to_heap(start, start + size);
+    }
+
+    pub fn alloc(&mut self, layout: Layout) -> Option<NonNull<u8>> {
+        match class_of(&layout) {
+            Some(class) => self.alloc_object(class, layout.size()),
+            None => {
+                let ptr = self.backing.alloc(layout).ok();
+                if ptr.is_some() {
+                    self.large_in_use += 1;
+                    self.large_bytes += layout.size();
+                }
+                ptr
+            }
+        }
+    }
+
+    /// an allocation failed even after reclaiming memory
+    pub fn record_failure(&mut self) {
+        self.failures += 1;
+    }
+
+    pub unsafe fn dealloc(&mut self, ptr: NonNull<u8>, layout: Layout) {
+        match class_of(&layout) {
+            Some(class) => self.dealloc_object(class, ptr, layout.size()),
+            None => {
+                self.backing.dealloc(ptr, layout);
+                self.large_in_use -= 1;
+                self.large_bytes -= layout.size();
+            }
+        }
+    }
+
+    fn alloc_object(&mut self, class: usize, requested: usize) -> Option<NonNull<u8>> {
+        let slab = match self.classes[class].partial {
+            Some(slab) => slab,
+            None => self.new_slab(class)?
+        };
+        let size_class = &mut self.classes[class];
+        unsafe{
+            let slab = &mut *slab.as_ptr();
+            let object = slab.free;
+            slab.free = (*object).next;
+            if slab.in_use == 0 {
+                size_class.empty_slabs -= 1;
+            }
+            slab.in_use += 1;
+            if slab.free.is_null() {
+                // full slabs are not tracked
+                Self::unlink(size_class, slab);
+            }
+            size_class.objects_in_use += 1;
+            size_class.requested += requested;
+            size_class.allocs += 1;
+            NonNull::new(object as *mut u8)
+        }
+    }
+
+    unsafe fn dealloc_object(&mut self, class: usize, ptr: NonNull<u8>, requested: usize) {
+        let size_class = &mut self.classes[class];
+        let slab = &mut *((ptr.as_ptr() as usize & !(SLAB_SIZE - 1)) as *mut Slab);
+        let object = ptr.as_ptr() as *mut FreeObject;
+        #[cfg(feature = "alloc_debug")]
+        {
+            let mut free = slab.free;
+            while !free.is_null() {
+                if free == object {
+                    panic!("double free of heap object {:#x} of {} bytes", object as usize, class_size(class));
+                }
+                free = (*free).next;
+            }
+        }
+        if slab.free.is_null() {
+            // was full
+            slab.next = size_class.partial.map_or(null_mut(), |p| p.as_ptr());
+            slab.prev = null_mut();
+            if let Some(head) = size_class.partial {
+                (*head.as_ptr()).prev = slab;
+            }
+            size_class.partial = NonNull::new(slab);
+        }
+        (*object).next = slab.free;
+        slab.free = object;
+        slab.in_use -= 1;
+        size_class.objects_in_use -= 1;
+        size_class.requested -= requested;
+        if slab.in_use == 0 {
+            size_class.empty_slabs += 1;
+            if size_class.empty_slabs > 1 {
+                self.release_slab(class, slab);
+            }
+        }
+    }
+
+    /// take a slab page from the buddy heap and put all its objects on its free list
+    fn new_slab(&mut self, class: usize) -> Option<NonNull<Slab>> {
+        let layout = Layout::from_size_align(SLAB_SIZE, SLAB_SIZE).unwrap();
+        let page = self.backing.alloc(layout).ok()?.as_ptr() as usize;
+        let size = class_size(class);
+        let mut free = null_mut::<FreeObject>();
+        let mut capacity = 0;
+        let mut offset = SLAB_SIZE - size;
+        while offset >= first_object(class) {
+            let object = (page + offset) as *mut FreeObject;
+            unsafe{ (*object).next = free };
+            free = object;
+            capacity += 1;
+            offset -= size;
+        }
+        let slab = page as *mut Slab;
+        let size_class = &mut self.classes[class];
+        unsafe{
+            slab.write(Slab {
+                next: size_class.partial.map_or(null_mut(), |p| p.as_ptr()),
+                prev: null_mut(),
+                free,
+                in_use: 0,
+                capacity
+            });
+            if let Some(head) = size_class.partial {
+                (*head.as_ptr()).prev = slab;
+            }
+        }
+        size_class.partial = NonNull::new(slab);
+        size_class.slabs += 1;
+        size_class.empty_slabs += 1;
+        size_class.partial
+    }
+
+    unsafe fn unlink(size_class: &mut SizeClass, slab: &mut Slab) {
+        if slab.prev.is_null() {
+            size_class.partial = NonNull::new(slab.next);
+        }else{
+            (*slab.prev).next = slab.next;
+        }
+        if !slab.next.is_null() {
+            (*slab.next).prev = slab.prev;
+        }
+        slab.next = null_mut();
+        slab.prev = null_mut();
+    }
+
+    /// give an empty slab back to the buddy heap
+    unsafe fn release_slab(&mut self, class: usize, slab: &mut Slab) {
+        let size_class = &mut self.classes[class];
+        Self::unlink(size_class, slab);
+        size_class.slabs -= 1;
+        size_class.empty_slabs -= 1;
+        let layout = Layout::from_size_align(SLAB_SIZE, SLAB_SIZE).unwrap();
+        self.backing.dealloc(NonNull::new_unchecked(slab as *mut Slab as *mut u8), layout);
+    }
+
+    /// give all empty slabs back to the buddy heap, return the number of bytes released
+    pub fn shrink(&mut self) -> usize {
+        let mut released = 0;
+        for class in 0..NUM_CLASSES {
+            let mut slab = self.classes[class].partial.map_or(null_mut(), |p| p.as_ptr());
+            while !slab.is_null() {
+                unsafe{
+                    let next = (*slab).next;
+                    if (*slab).in_use == 0 {
+                        self.release_slab(class, &mut *slab);
+                        released += SLAB_SIZE;
+                    }
+                    slab = next;
+                }
+            }
+        }
+        released
+    }
+
+    pub fn class_stats(&self) -> impl Iterator<Item = ClassStats> + '_ {
+        self.classes.iter().enumerate().map(|(class, size_class)| {
+            let capacity = (SLAB_SIZE - first_object(class)) / class_size(class);
+            ClassStats {
+                size: class_size(class),
+                slabs: size_class.slabs,
+                objects_in_use: size_class.objects_in_use,
+                objects_free: size_class.slabs * capacity - size_class.objects_in_use,
+                requested: size_class.requested,
+                allocs: size_class.allocs
+            }
+        })
+    }
+
+    /// print per-class usage and fragmentation
+    pub fn report_to(&self, out: &mut dyn Write) {
+        let _ = writeln!(out, "{:>6} {:>6} {:>8} {:>8} {:>10} {:>8}", "class", "slabs", "in use", "free", "allocs", "waste%");
+        let mut slab_bytes = 0;
+        let mut wasted = 0;
+        for stats in self.class_stats() {
+            let used = stats.objects_in_use * stats.size;
+            let waste = used - stats.requested + stats.objects_free * stats.size;
+            slab_bytes += stats.slabs * SLAB_SIZE;
+            wasted += waste;
+            let _ = writeln!(
+                out, "{:>6} {:>6} {:>8} {:>8} {:>10} {:>7}%",
+                stats.size, stats.slabs, stats.objects_in_use, stats.objects_free, stats.allocs,
+                if stats.slabs == 0 { 0 } else { waste * 100 / (stats.slabs * SLAB_SIZE) }
+            );
+        }
+        let _ = writeln!(out, "slabs: {} bytes, {} bytes unused or padding", slab_bytes, wasted);
+        let _ = writeln!(out, "large: {} allocations, {} bytes", self.large_in_use, self.large_bytes);
+        let total = self.backing.stats_total_bytes();
+        let actual = self.backing.stats_alloc_actual();
+        let _ = writeln!(
+            out, "heap: {} of {} bytes used, buddy rounding {} bytes, {} failed allocations",
+            actual, total, actual - self.backing.stats_alloc_user(), self.failures
+        );
+    }
+}
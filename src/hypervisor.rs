@@ -3,7 +3,9 @@ pub mod stack {
         PAGE_SIZE, KERNEL_STACK_SIZE,
         layout::TRAP_CONTEXT
     }, mm::MapPermission};
-    use crate::mm::MemorySet;
+    use crate::arch;
+    use crate::mm::{ HostMemorySet, MemorySet };
+    use crate::page_table::PageTable;
     use super::HOST_VMM;
     pub struct HypervisorStack(pub usize);
 
@@ -25,6 +27,13 @@ pub mod stack {
         HypervisorStack(guest_id)
     }
 
+    /// unmap and free the stack of a guest being destroyed, the id can be used again
+    pub fn hstack_free<P: PageTable>(hpm: &mut HostMemorySet<P>, guest_id: usize) {
+        let (hstack_bottom, _) = hstack_position(guest_id);
+        hpm.remove_area(hstack_bottom.into());
+        arch::flush_host_tlb();
+    }
+
     impl HypervisorStack {
         pub fn get_top(&self) -> usize {
             let (_, hstack_top) = hstack_position(self.0);
@@ -37,20 +46,174 @@ pub mod stack {
 pub mod fdt {
 ///! ref: https://github.com/mit-pdos/RVirt/blob/HEAD/src/fdt.rs
 
+use alloc::string::{ String, ToString };
+use alloc::vec::Vec;
 use arrayvec::ArrayVec;
 use fdt::Fdt;
+use fdt::node::FdtNode;
+use crate::constants::layout::GUEST_DTB_SIZE;
 
 #[derive(Clone, Debug)]
 pub struct Device {
     pub base_address: usize,
+    pub size: usize,
+    /// first interrupt of the device
+    pub irq: Option<usize>,
+    /// phandle of the interrupt controller `irq` belongs to
+    pub interrupt_parent: Option<u32>
+}
+
+/// register write of a `syscon-reboot` or `syscon-poweroff` node
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SysconAction {
+    pub offset: usize,
+    pub value: u32,
+    pub mask: u32
+}
+
+impl SysconAction {
+    pub fn matches(&self, offset: usize, value: u32) -> bool {
+        offset == self.offset && value & self.mask == self.value & self.mask
+    }
+
+    fn parse(node: &FdtNode) -> Option<Self> {
+        let offset = node.property("offset")?.as_usize()?;
+        let mask = node.property("mask").and_then(|p| p.as_usize()).unwrap_or(0xffff_ffff) as u32;
+        // `value` is optional when `mask` is given, the mask is written then
+        let value = node.property("value").and_then(|p| p.as_usize()).map_or(mask, |v| v as u32);
+        Some(Self { offset, value, mask })
+    }
+}
+
+/// linear framebuffer set up before the hypervisor, e.g. by firmware
+#[derive(Clone, Debug)]
+pub struct Framebuffer {
+    pub device: Device,
+    pub width: u32,
+    pub height: u32,
+    /// bytes per line
+    pub stride: u32,
+    /// pixel format, named as in the `simple-framebuffer` binding (e.g. `a8r8g8b8`)
+    pub format: String
+}
+
+impl Framebuffer {
+    /// `simple-framebuffer` node
+    fn parse(node: &FdtNode) -> Option<Self> {
+        let reg = node.reg()?.next()?;
+        let prop = |name: &str| node.property(name).and_then(|p| p.as_usize()).map(|v| v as u32);
+        Some(Self {
+            device: Device { base_address: reg.starting_address as usize, size: reg.size?, irq: first_irq(node), interrupt_parent: interrupt_parent(node) },
+            width: prop("width")?,
+            height: prop("height")?,
+            stride: prop("stride")?,
+            format: node.property("format")?.as_str()?.to_string()
+        })
+    }
+
+    /// `<base>,<width>x<height>` of an `a8r8g8b8` framebuffer without a device tree node
+    pub fn from_bootarg(arg: &str) -> Option<Self> {
+        let (base, mode) = arg.split_once(',')?;
+        let (width, height) = mode.split_once('x')?;
+        let base = usize::from_str_radix(base.trim_start_matches("0x"), 16).ok()?;
+        let (width, height): (u32, u32) = (width.parse().ok()?, height.parse().ok()?);
+        let stride = width.checked_mul(4)?;
+        let size = (stride as usize * height as usize + 0xfff) & !0xfff;
+        Some(Self {
+            device: Device { base_address: base, size, irq: None, interrupt_parent: None },
+            width,
+            height,
+            stride,
+            format: "a8r8g8b8".to_string()
+        })
+    }
+
+    pub fn bits_per_pixel(&self) -> Option<u32> {
+        match self.format.as_str() {
+            "a8r8g8b8" | "x8r8g8b8" | "a8b8g8r8" | "x8b8g8r8" | "a2r10g10b10" | "x2r10g10b10" => Some(32),
+            "r8g8b8" => Some(24),
+            "r5g6b5" | "a1r5g5b5" | "x1r5g5b5" => Some(16),
+            _ => None
+        }
+    }
+}
+
+/// hart of the `/cpus` node
+#[derive(Clone, Debug)]
+pub struct CpuMeta {
+    pub hart_id: usize,
+    /// `riscv,isa`, e.g. `rv64imafdch_zicsr_zifencei`
+    pub isa: String,
+    /// `mmu-type`, e.g. `riscv,sv39`
+    pub mmu_type: Option<String>,
+    /// `status` is absent or `okay`
+    pub enabled: bool
+}
+
+impl CpuMeta {
+    fn parse(node: &FdtNode) -> Option<Self> {
+        let string = |name: &str| node.property(name).and_then(|p| p.as_str()).map(|s| s.to_string());
+        Some(Self {
+            hart_id: node.property("reg")?.as_usize()?,
+            isa: string("riscv,isa").unwrap_or_default(),
+            mmu_type: string("mmu-type"),
+            enabled: string("status").map_or(true, |status| status == "okay" || status == "ok")
+        })
+    }
+
+    /// single letter extension, e.g. `'h'`
+    pub fn has_extension(&self, ext: char) -> bool {
+        let base = self.isa.split('_').next().unwrap_or("");
+        base.strip_prefix("rv64").or_else(|| base.strip_prefix("rv32")).map_or(false, |exts| exts.contains(ext))
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct MemoryRegion {
+    pub base: usize,
     pub size: usize
 }
 
+/// `interrupt-map` entry of the PCI host bridge: INTx pin of a device -> parent interrupt
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct InterruptMapEntry {
+    /// high cell of the child unit address, holds bus/device/function
+    pub child_address: u32,
+    /// INTA..INTD as 1..4
+    pub child_pin: u32,
+    /// phandle of the interrupt controller
+    pub parent: u32,
+    pub parent_irq: u32
+}
+
+/// raw big endian cells of a property
+fn cells<'a>(node: &FdtNode<'_, 'a>, name: &str) -> Option<impl Iterator<Item = u32> + 'a> {
+    let value = node.property(name)?.value;
+    Some(value.chunks_exact(4).map(|cell| u32::from_be_bytes([cell[0], cell[1], cell[2], cell[3]])))
+}
+
+fn cell_count(node: &FdtNode, name: &str) -> Option<usize> {
+    node.property(name).and_then(|p| p.as_usize())
+}
+
+fn interrupt_parent(node: &FdtNode) -> Option<u32> {
+    node.property("interrupt-parent").and_then(|p| p.as_usize()).map(|phandle| phandle as u32)
+}
+
 #[derive(Clone, Debug, Default)]
 pub struct MachineMeta{
+    /// first memory bank
     pub physical_memory_offset: usize,
     pub physical_memory_size: usize,
 
+    /// all memory banks, lowest address first
+    pub memory: Vec<MemoryRegion>,
+
+    pub cpus: Vec<CpuMeta>,
+
+    /// `timebase-frequency` of `/cpus`
+    pub timebase_frequency: Option<usize>,
+
     pub virtio: ArrayVec<Device, 16>,
 
     pub test_finisher_address: Option<Device>,
@@ -61,18 +224,349 @@ pub struct MachineMeta{
 
     pub plic: Option<Device>,
 
+    /// phandle of the PLIC, the `interrupt_parent` of devices wired to it
+    pub plic_phandle: Option<u32>,
+
     pub pci: Option<Device>,
+
+    /// INTx routing of the PCI host bridge
+    pub pci_interrupt_map: Vec<InterruptMapEntry>,
+
+    /// `interrupt-map-mask` of the PCI host bridge, address high cell and pin
+    pub pci_interrupt_map_mask: (u32, u32),
+
+    /// goldfish RTC
+    pub rtc: Option<Device>,
+
+    /// SiFive watchdog, emulated for guests
+    pub watchdog: Option<Device>,
+
+    pub syscon_reboot: Option<SysconAction>,
+
+    pub syscon_poweroff: Option<SysconAction>,
+
+    /// S-level IMSIC of the boot hart
+    pub imsic: Option<Device>,
+
+    /// IOPMP confining DMA of bus masters assigned to guests
+    pub iopmp: Option<Device>,
+
+    /// `simple-framebuffer`, of the guest only if assigned to it
+    pub framebuffer: Option<Framebuffer>,
+
+    /// `/chosen/bootargs`
+    pub bootargs: Option<String>,
+}
+
+fn first_irq(node: &FdtNode) -> Option<usize> {
+    node.interrupts().and_then(|mut irqs| irqs.next())
+}
+
+/// structural problem of a flattened device tree
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DtbError {
+    Misaligned(usize),
+    BadMagic(u32),
+    /// `totalsize` larger than the memory the blob may occupy
+    TooLarge(usize),
+    UnsupportedVersion(u32),
+    /// a block or a token reaches past the end of the blob
+    Truncated,
+    /// unknown token or unbalanced nodes at this offset of the structure block
+    BadStructure(usize),
+    /// property name offset outside the strings block
+    BadStringOffset(u32),
+}
+
+const FDT_MAGIC: u32 = 0xd00d_feed;
+const FDT_HEADER_SIZE: usize = 40;
+const FDT_BEGIN_NODE: u32 = 1;
+const FDT_END_NODE: u32 = 2;
+const FDT_PROP: u32 = 3;
+const FDT_NOP: u32 = 4;
+const FDT_END: u32 = 9;
+
+fn be32(bytes: &[u8], offset: usize) -> Option<u32> {
+    let cell = bytes.get(offset..offset.checked_add(4)?)?;
+    Some(u32::from_be_bytes([cell[0], cell[1], cell[2], cell[3]]))
+}
+
+/// check the header, the memory reservation map and the structure block of the
+/// blob at `dtb`, which may span at most `limit` bytes. Returns `totalsize`.
+pub fn validate(dtb: usize, limit: usize) -> Result<usize, DtbError> {
+    if dtb % 4 != 0 {
+        return Err(DtbError::Misaligned(dtb))
+    }
+    if limit < FDT_HEADER_SIZE {
+        return Err(DtbError::Truncated)
+    }
+    let header = unsafe{ core::slice::from_raw_parts(dtb as *const u8, FDT_HEADER_SIZE) };
+    let field = |index: usize| be32(header, 4 * index).unwrap_or(0);
+    let magic = field(0);
+    if magic != FDT_MAGIC {
+        return Err(DtbError::BadMagic(magic))
+    }
+    let total_size = field(1) as usize;
+    if total_size > limit {
+        return Err(DtbError::TooLarge(total_size))
+    }
+    if total_size < FDT_HEADER_SIZE {
+        return Err(DtbError::Truncated)
+    }
+    // version 17 readers accept blobs down to version 16
+    let (version, last_compatible) = (field(5), field(6));
+    if version < 16 || last_compatible > 17 {
+        return Err(DtbError::UnsupportedVersion(version))
+    }
+    let blob = unsafe{ core::slice::from_raw_parts(dtb as *const u8, total_size) };
+    let block = |offset: u32, size: u32| -> Result<&[u8], DtbError> {
+        let start = offset as usize;
+        blob.get(start..start.checked_add(size as usize).ok_or(DtbError::Truncated)?).ok_or(DtbError::Truncated)
+    };
+    let structure = block(field(2), field(9))?;
+    let strings = block(field(3), field(8))?;
+
+    // memory reservation map, (address, size) pairs ending with an empty one
+    let mut entry = field(4) as usize;
+    loop {
+        let address = (be32(blob, entry), be32(blob, entry + 4));
+        let size = (be32(blob, entry + 8), be32(blob, entry + 12));
+        match (address, size) {
+            ((Some(0), Some(0)), (Some(0), Some(0))) => break,
+            ((Some(_), Some(_)), (Some(_), Some(_))) => entry += 16,
+            _ => return Err(DtbError::Truncated)
+        }
+    }
+
+    let align = |offset: usize| (offset + 3) & !3;
+    let mut offset = 0;
+    let mut depth = 0usize;
+    loop {
+        let token = be32(structure, offset).ok_or(DtbError::Truncated)?;
+        let token_offset = offset;
+        offset += 4;
+        match token {
+            FDT_BEGIN_NODE => {
+                let name = structure.get(offset..).ok_or(DtbError::Truncated)?;
+                let len = name.iter().position(|&c| c == 0).ok_or(DtbError::Truncated)?;
+                offset = align(offset + len + 1);
+                depth += 1;
+            },
+            FDT_END_NODE => {
+                depth = depth.checked_sub(1).ok_or(DtbError::BadStructure(token_offset))?;
+            },
+            FDT_PROP => {
+                let len = be32(structure, offset).ok_or(DtbError::Truncated)? as usize;
+                let name_offset = be32(structure, offset + 4).ok_or(DtbError::Truncated)?;
+                let name = strings.get(name_offset as usize..).ok_or(DtbError::BadStringOffset(name_offset))?;
+                if !name.contains(&0) {
+                    return Err(DtbError::BadStringOffset(name_offset))
+                }
+                offset = align(offset + 8 + len);
+                if offset > structure.len() {
+                    return Err(DtbError::Truncated)
+                }
+            },
+            FDT_NOP => {},
+            FDT_END if depth == 0 && token_offset > 0 => return Ok(total_size),
+            _ => return Err(DtbError::BadStructure(token_offset))
+        }
+    }
+}
+
+/// rewrite the string properties called `name` of the valid `blob` in place.
+/// The property length is kept, a shorter value is padded with NULs and a longer
+/// one is not written.
+pub fn patch_string_property(blob: &mut [u8], name: &str, patch: impl Fn(&str) -> String) {
+    patch_node_strings(blob, name, |_, old| Some(patch(old)));
+}
+
+/// `patch_string_property` given the name of the node of each property, a property
+/// `patch` returns `None` for is left as it is
+fn patch_node_strings(blob: &mut [u8], name: &str, patch: impl Fn(&str, &str) -> Option<String>) {
+    let field = |index: usize| be32(blob, 4 * index).unwrap_or(0) as usize;
+    let (structure, strings, structure_size) = (field(2), field(3), field(9));
+    let mut offset = structure;
+    // properties come before the subnodes, they belong to the node begun last
+    let mut node = 0..0;
+    while offset < structure + structure_size {
+        let token = be32(blob, offset).unwrap_or(FDT_END);
+        offset += 4;
+        match token {
+            FDT_BEGIN_NODE => {
+                let len = blob[offset..].iter().position(|&c| c == 0).unwrap_or(0);
+                node = offset..offset + len;
+                offset = (offset + len + 1 + 3) & !3;
+            },
+            FDT_PROP => {
+                let value_len = be32(blob, offset).unwrap_or(0) as usize;
+                let name_offset = strings + be32(blob, offset + 4).unwrap_or(0) as usize;
+                let value_start = offset + 8;
+                offset = (value_start + value_len + 3) & !3;
+                let prop_name = blob[name_offset..].split(|&c| c == 0).next().unwrap_or(&[]);
+                if prop_name != name.as_bytes() {
+                    continue
+                }
+                let node_name = core::str::from_utf8(&blob[node.clone()]).unwrap_or("");
+                let value = &blob[value_start..value_start + value_len];
+                let old = value.split(|&c| c == 0).next().and_then(|s| core::str::from_utf8(s).ok()).unwrap_or("");
+                let new = match patch(node_name, old) {
+                    Some(new) => new,
+                    None => continue
+                };
+                if new.len() >= value_len {
+                    hwarning!("no room for {} = {} in the device tree", name, new);
+                    continue
+                }
+                let value = &mut blob[value_start..value_start + value_len];
+                value[..new.len()].copy_from_slice(new.as_bytes());
+                value[new.len()..].fill(0);
+            },
+            FDT_END => break,
+            _ => {}
+        }
+    }
+}
+
+/// blank the `compatible` of the nodes whose unit address is in `hidden`, no driver of
+/// the guest binds to the devices of the valid `blob` it does not get
+pub fn hide_devices(blob: &mut [u8], hidden: &[usize]) {
+    patch_node_strings(blob, "compatible", |node, _| {
+        let address = node.split_once('@').and_then(|(_, unit)| usize::from_str_radix(unit, 16).ok())?;
+        hidden.contains(&address).then(String::new)
+    });
+}
+
+/// remove the extensions hidden from a guest from the `riscv,isa` of its device tree,
+/// the blob of `len` bytes at `dtb` in guest ram
+pub fn mask_isa(dtb: usize, len: usize, mask: crate::guest::IsaMask) {
+    if mask != crate::guest::IsaMask::empty() {
+        crate::mm::framemap::with_guest_ram(dtb, len, |blob| patch_string_property(blob, "riscv,isa", |isa| mask.apply(isa)));
+    }
 }
 
 impl MachineMeta {
+    /// validate and parse the device tree at `dtb`, a blob that fails validation is
+    /// replaced with the built-in description of the board
+    pub fn from_dtb(dtb: usize, limit: usize) -> Self {
+        match validate(dtb, limit) {
+            Ok(_) => Self::parse(dtb),
+            Err(err) => {
+                herror!("invalid device tree at {:#x}: {:?}, using the built-in machine description", dtb, err);
+                let board = crate::board::platform();
+                board.fallback_machine()
+                    .unwrap_or_else(|| panic!("no built-in machine description of {}", board.name()))
+            }
+        }
+    }
+
+    /// value of a `key=value` option on the command line
+    pub fn bootarg(&self, key: &str) -> Option<&str> {
+        self.bootargs.as_deref()?
+            .split_whitespace()
+            .find_map(|arg| arg.strip_prefix(key).and_then(|rest| rest.strip_prefix('=')))
+    }
+
+    /// guest physical address of the device tree of a guest with this machine, at the
+    /// start of the window below its first memory bank
+    pub fn guest_dtb_addr(&self) -> usize {
+        self.physical_memory_offset - GUEST_DTB_SIZE
+    }
+
+    /// harts that may be started
+    pub fn hart_count(&self) -> usize {
+        self.cpus.iter().filter(|cpu| cpu.enabled).count()
+    }
+
+    /// parent interrupt of INTx `pin` (1..4) of the PCI function at `bdf_address`
+    /// (bus/device/function as in the high cell of a PCI unit address)
+    pub fn pci_intx_irq(&self, bdf_address: u32, pin: u32) -> Option<u32> {
+        let (address_mask, pin_mask) = self.pci_interrupt_map_mask;
+        self.pci_interrupt_map.iter()
+            .find(|entry| entry.child_address == bdf_address & address_mask && entry.child_pin == pin & pin_mask)
+            .map(|entry| entry.parent_irq)
+    }
+
+    /// entries whose parent takes one interrupt cell, e.g. the PLIC
+    fn parse_interrupt_map(fdt: &Fdt, node: &FdtNode) -> Vec<InterruptMapEntry> {
+        let mut entries = Vec::new();
+        let address_cells = cell_count(node, "#address-cells").unwrap_or(3);
+        let interrupt_cells = cell_count(node, "#interrupt-cells").unwrap_or(1);
+        let mut map = match cells(node, "interrupt-map") {
+            Some(map) => map,
+            None => return entries
+        };
+        loop {
+            let child: Vec<u32> = map.by_ref().take(address_cells + interrupt_cells).collect();
+            let parent = match (child.len() == address_cells + interrupt_cells, map.next()) {
+                (true, Some(parent)) => parent,
+                _ => break
+            };
+            let controller = match fdt.find_phandle(parent) {
+                Some(controller) => controller,
+                None => break
+            };
+            let parent_address_cells = cell_count(&controller, "#address-cells").unwrap_or(0);
+            let parent_interrupt_cells = cell_count(&controller, "#interrupt-cells").unwrap_or(1);
+            let parent_cells: Vec<u32> = map.by_ref().take(parent_address_cells + parent_interrupt_cells).collect();
+            if parent_cells.len() != parent_address_cells + parent_interrupt_cells || parent_interrupt_cells == 0 {
+                break
+            }
+            entries.push(InterruptMapEntry {
+                child_address: child[0],
+                child_pin: child[address_cells],
+                parent,
+                parent_irq: parent_cells[parent_address_cells]
+            });
+        }
+        entries
+    }
+
     pub fn parse(dtb: usize) -> Self {
         let fdt = unsafe{ Fdt::from_ptr(dtb as *const u8) }.unwrap();
-        let memory = fdt.memory();
         let mut meta = MachineMeta::default();
-        for region in memory.regions() {
-            meta.physical_memory_offset = region.starting_address as usize;
-            meta.physical_memory_size = region.size.unwrap();
+        // every node of device_type "memory", not only `/memory`
+        for node in fdt.all_nodes() {
+            if node.property("device_type").and_then(|p| p.as_str()) != Some("memory") {
+                continue
+            }
+            for reg in node.reg().into_iter().flatten() {
+                if let Some(size) = reg.size {
+                    meta.memory.push(MemoryRegion { base: reg.starting_address as usize, size });
+                }
+            }
+        }
+        if meta.memory.is_empty() {
+            // `/memory` without device_type
+            for region in fdt.memory().regions() {
+                if let Some(size) = region.size {
+                    meta.memory.push(MemoryRegion { base: region.starting_address as usize, size });
+                }
+            }
+        }
+        meta.memory.sort_unstable_by_key(|region| region.base);
+        if let Some(region) = meta.memory.first() {
+            meta.physical_memory_offset = region.base;
+            meta.physical_memory_size = region.size;
+        }
+        for region in meta.memory.iter() {
+            hdebug!("memory addr: {:#x}, size: {:#x}", region.base, region.size);
         }
+
+        if let Some(cpus) = fdt.find_node("/cpus") {
+            meta.timebase_frequency = cpus.property("timebase-frequency").and_then(|p| p.as_usize());
+            for node in cpus.children() {
+                if node.property("device_type").and_then(|p| p.as_str()) != Some("cpu") {
+                    continue
+                }
+                if let Some(cpu) = CpuMeta::parse(&node) {
+                    hdebug!("hart {}: {} {}", cpu.hart_id, cpu.isa, cpu.mmu_type.as_deref().unwrap_or(""));
+                    meta.cpus.push(cpu);
+                }
+            }
+            meta.cpus.sort_unstable_by_key(|cpu| cpu.hart_id);
+        }
+
         // probe virtio mmio device
         for node in fdt.find_all_nodes("/soc/virtio_mmio") {
             if let Some(reg) = node.reg().and_then(|mut reg| reg.next()) {
@@ -80,7 +574,7 @@ impl MachineMeta {
                 let size = reg.size.unwrap();
                 hdebug!("virtio mmio addr: {:#x}, size: {:#x}", paddr, size);
                 meta.virtio.push(
-                    Device { base_address: paddr, size }
+                    Device { base_address: paddr, size, irq: first_irq(&node), interrupt_parent: interrupt_parent(&node) }
                 )
             }
         }
@@ -92,7 +586,7 @@ impl MachineMeta {
                 let base_addr = reg.starting_address as usize;
                 let size = reg.size.unwrap();
                 hdebug!("test addr: {:#x}, size: {:#x}", base_addr, size);
-                meta.test_finisher_address = Some(Device { base_address: base_addr, size});
+                meta.test_finisher_address = Some(Device { base_address: base_addr, size, irq: first_irq(&node), interrupt_parent: interrupt_parent(&node) });
             }
         }
 
@@ -102,7 +596,7 @@ impl MachineMeta {
                 let base_addr = reg.starting_address as usize;
                 let size = reg.size.unwrap();
                 hdebug!("UART addr: {:#x}, size: {:#x}", base_addr, size);
-                meta.uart = Some(Device { base_address: base_addr, size});
+                meta.uart = Some(Device { base_address: base_addr, size, irq: first_irq(&node), interrupt_parent: interrupt_parent(&node) });
             }
         }
 
@@ -112,7 +606,7 @@ impl MachineMeta {
                 let base_addr = reg.starting_address as usize;
                 let size = reg.size.unwrap();
                 hdebug!("CLINT addr: {:#x}, size: {:#x}", base_addr, size);
-                meta.clint = Some(Device { base_address: base_addr, size});
+                meta.clint = Some(Device { base_address: base_addr, size, irq: first_irq(&node), interrupt_parent: interrupt_parent(&node) });
             }
         }
 
@@ -122,7 +616,8 @@ impl MachineMeta {
                 let base_addr = reg.starting_address as usize;
                 let size = reg.size.unwrap();
                 hdebug!("PLIC addr: {:#x}, size: {:#x}", base_addr, size);
-                meta.plic = Some(Device { base_address: base_addr, size});
+                meta.plic_phandle = node.property("phandle").and_then(|p| p.as_usize()).map(|phandle| phandle as u32);
+                meta.plic = Some(Device { base_address: base_addr, size, irq: first_irq(&node), interrupt_parent: interrupt_parent(&node) });
             }
         }
 
@@ -131,10 +626,66 @@ impl MachineMeta {
                 let base_addr = reg.starting_address as usize;
                 let size = reg.size.unwrap();
                 hdebug!("PCI addr: {:#x}, size: {:#x}", base_addr, size);
-                meta.pci = Some(Device { base_address: base_addr, size});
+                meta.pci_interrupt_map = Self::parse_interrupt_map(&fdt, &node);
+                let mask: Vec<u32> = cells(&node, "interrupt-map-mask").map_or(Vec::new(), |mask| mask.collect());
+                meta.pci_interrupt_map_mask = (mask.first().copied().unwrap_or(u32::MAX), mask.last().copied().unwrap_or(u32::MAX));
+                meta.pci = Some(Device { base_address: base_addr, size, irq: first_irq(&node), interrupt_parent: interrupt_parent(&node) });
+            }
+        }
+
+        for node in fdt.find_all_nodes("/soc/rtc") {
+            if let Some(reg) = node.reg().and_then(|mut reg| reg.next()) {
+                let base_addr = reg.starting_address as usize;
+                let size = reg.size.unwrap();
+                hdebug!("RTC addr: {:#x}, size: {:#x}", base_addr, size);
+                meta.rtc = Some(Device { base_address: base_addr, size, irq: first_irq(&node), interrupt_parent: interrupt_parent(&node) });
+            }
+        }
+
+        if let Some(node) = fdt.find_compatible(&["sifive,wdog0"]) {
+            if let Some(reg) = node.reg().and_then(|mut reg| reg.next()) {
+                let base_addr = reg.starting_address as usize;
+                let size = reg.size.unwrap();
+                hdebug!("watchdog addr: {:#x}, size: {:#x}", base_addr, size);
+                meta.watchdog = Some(Device { base_address: base_addr, size, irq: first_irq(&node), interrupt_parent: interrupt_parent(&node) });
             }
         }
 
+        if let Some(node) = fdt.find_compatible(&["riscv,iopmp"]) {
+            if let Some(reg) = node.reg().and_then(|mut reg| reg.next()) {
+                let base_addr = reg.starting_address as usize;
+                let size = reg.size.unwrap();
+                hdebug!("IOPMP addr: {:#x}, size: {:#x}", base_addr, size);
+                meta.iopmp = Some(Device { base_address: base_addr, size, irq: first_irq(&node), interrupt_parent: interrupt_parent(&node) });
+            }
+        }
+
+        meta.syscon_reboot = fdt.find_compatible(&["syscon-reboot"]).and_then(|node| SysconAction::parse(&node));
+        meta.syscon_poweroff = fdt.find_compatible(&["syscon-poweroff"]).and_then(|node| SysconAction::parse(&node));
+
+        // S-level interrupt files are the ones wired to the supervisor external interrupt (9)
+        for node in fdt.all_nodes() {
+            let is_imsic = node.compatible().map_or(false, |c| c.all().any(|c| c == "riscv,imsics"));
+            let s_level = node.property("interrupts-extended")
+                .map_or(false, |p| p.value.len() >= 8 && u32::from_be_bytes([p.value[4], p.value[5], p.value[6], p.value[7]]) == 9);
+            if let (true, true, Some(reg)) = (is_imsic, s_level, node.reg().and_then(|mut reg| reg.next())) {
+                let base_addr = reg.starting_address as usize;
+                let size = reg.size.unwrap();
+                hdebug!("IMSIC addr: {:#x}, size: {:#x}", base_addr, size);
+                meta.imsic = Some(Device { base_address: base_addr, size, irq: None, interrupt_parent: None });
+            }
+        }
+
+        meta.framebuffer = fdt.find_compatible(&["simple-framebuffer"]).and_then(|node| Framebuffer::parse(&node));
+        if let Some(fb) = meta.framebuffer.as_ref() {
+            hdebug!("framebuffer addr: {:#x}, size: {:#x}, {}x{} {}", fb.device.base_address, fb.device.size, fb.width, fb.height, fb.format);
+        }
+
+        meta.bootargs = fdt.find_node("/chosen")
+            .and_then(|chosen| chosen.property("bootargs"))
+            .and_then(|bootargs| bootargs.as_str())
+            .map(|bootargs| bootargs.to_string());
+
         meta
     }
 }
@@ -143,31 +694,65 @@ impl MachineMeta {
 }
 
 
-use arrayvec::ArrayVec;
-use riscv::register::{ hvip, sie };
-use spin::{ Once, Mutex };
-use crate::constants::MAX_GUESTS;
+use alloc::vec::Vec;
+use spin::Once;
+use crate::sync::SpinNoIrq;
+use crate::arch::{ self, Irq };
 use crate::constants::csr::{hedeleg, hideleg, hcounteren};
 use crate::device_emu::plic::PlicState;
-use crate::guest::{ page_table::GuestPageTable, Guest };
+use crate::drivers::virtio::blk::VirtioBlk;
+use crate::drivers::imsic::Imsic;
+use crate::drivers::iopmp::Iopmp;
+use crate::drivers::irq::{ MsiRoute, PlicContexts };
+use crate::device_emu::pci::BarAllocator;
+use alloc::collections::BTreeMap;
+use crate::guest::{ page_table::GuestPageTable, envcfg, fpu, pmu, triggers, Guest, GuestTable };
 use crate::page_table::{ PageTable, PageTableSv39 };
-use crate::mm::HostMemorySet;
+use crate::mm::{ HostMemorySet, adbits };
+use crate::sched::Scheduler;
+use crate::cmdline;
+use crate::{ VmmError, VmmResult };
 
 use self::fdt::MachineMeta;
+use self::stack::hstack_free;
 
 
-pub static mut HOST_VMM: Once<Mutex<HostVmm<PageTableSv39, PageTableSv39>>> = Once::new();
+/// only locked with interrupts masked: in trap handlers and during boot
+pub static mut HOST_VMM: Once<SpinNoIrq<HostVmm<PageTableSv39, PageTableSv39>>> = Once::new();
 
 pub struct HostVmm<P: PageTable, G: GuestPageTable> {
     pub host_machine: MachineMeta,
     /// hypervisor memory
     pub hpm: HostMemorySet<P>,
     /// all guest structs
-    pub guests: ArrayVec<Option<Guest<G>>, MAX_GUESTS>,
+    pub guests: GuestTable<G>,
     /// current run guest id(single core)
     pub guest_id: usize,
     /// hypervisor emulated plic
     pub host_plic: Option<PlicState>,
+    /// virtio-mmio slots used by the hypervisor itself, guests see them as empty slots
+    pub host_virtio: Vec<usize>,
+    /// interrupts of devices owned by the hypervisor
+    pub host_irqs: Vec<usize>,
+    /// disk owned by the hypervisor
+    pub host_blk: Option<VirtioBlk>,
+    pub host_blk_irq: Option<usize>,
+    /// interrupt of the NIC of the hypervisor, the NIC itself is owned by `net`
+    pub host_net_irq: Option<usize>,
+    /// RX interrupt of the console uart, see `monitor::uart_rx`
+    pub host_uart_irq: Option<usize>,
+    /// receives MSIs of PCI functions assigned to guests
+    pub host_imsic: Option<Imsic>,
+    /// confines DMA of PCI functions assigned to guests
+    pub host_iopmp: Option<Iopmp>,
+    /// IMSIC identity -> guest interrupt
+    pub msi_routes: BTreeMap<usize, MsiRoute>,
+    /// (guest, vcpu) -> physical PLIC context
+    pub plic_contexts: PlicContexts,
+    /// host addresses of BARs, also used by functions attached at runtime
+    pub bar_allocator: BarAllocator,
+    /// guest scheduler
+    pub sched: Scheduler,
 
     pub irq_pending: bool,
 
@@ -176,12 +761,37 @@ pub struct HostVmm<P: PageTable, G: GuestPageTable> {
     pub guest_page_falut: usize,
 }
 
-pub fn add_guest_queue(guest: Guest<PageTableSv39>) {
+/// schedule `guest`, an error if its scheduling configuration is invalid or the DMA of its
+/// PCI functions cannot be confined to it
+pub fn add_guest_queue(mut guest: Guest<PageTableSv39>) -> VmmResult {
     let host_vmm = unsafe{ HOST_VMM.get_mut().unwrap() };
     let mut host_vmm = host_vmm.lock();
     let guest_id = guest.guest_id;
-    assert!(guest_id < MAX_GUESTS);
-    host_vmm.guests[guest_id] = Some(guest);
+    if let Err(err) = host_vmm.sched.add(guest_id, &guest.config) {
+        hstack_free(&mut host_vmm.hpm, guest_id);
+        return Err(err)
+    }
+    let placed = host_vmm.reserve_virtio_windows(&mut guest.gpm, &guest.virtio, &guest.guest_machine)
+        .and_then(|_| host_vmm.plic_contexts.assign_guest(guest_id, guest.harts.len()).ok_or(VmmError::NoFound));
+    if let Err(err) = placed {
+        herror!("no virtio slots or PLIC context for guest {}: {:?}", guest_id, err);
+        host_vmm.sched.remove(guest_id);
+        host_vmm.plic_contexts.release_guest(guest_id);
+        hstack_free(&mut host_vmm.hpm, guest_id);
+        return Err(err)
+    }
+    host_vmm.guests.insert(guest).expect("guest id out of range or taken");
+    if let Err(err) = host_vmm.iopmp_add_guest(guest_id) {
+        // the DMA of its devices would reach any memory
+        herror!("cannot confine the DMA of the devices of guest {}: {:?}, not starting it", guest_id, err);
+        host_vmm.remove_guest(guest_id);
+        return Err(err)
+    }
+    if host_vmm.sched.current.is_none() {
+        // the first guest is loaded into TRAP_CONTEXT to be entered by `hart_entry_1`
+        host_vmm.switch_guest(guest_id);
+    }
+    Ok(())
 }
 
 
@@ -203,10 +813,8 @@ pub unsafe fn init_vmm(hpm: HostMemorySet<PageTableSv39>, host_machine: MachineM
         hideleg::VSTIP
     );
 
-    // hvip: clear all interrupts
-    hvip::clear_vseip();
-    hvip::clear_vssip();
-    hvip::clear_vstip();
+    // no interrupt pending in the guest
+    arch::set_guest_irqs(0);
 
     // When the hypervisor is initialized, it is necessary to write the `hcounteren` register to all 1, because it is possible to read the `time` register in VU mode or VS mode.(refs: The counter-enable register `hcounteren` is a 32-bit register that controls the availability of the hardware performance monitoring counters to the guest virtual machine.  
     // When the CY, TM, IR, or HPMn bit in the hcounteren register is clear, attempts to read the
@@ -217,12 +825,28 @@ pub unsafe fn init_vmm(hpm: HostMemorySet<PageTableSv39>, host_machine: MachineM
     // `hcounteren` must be implemented. However, any of the bits may be read-only zero, indicating
     // reads to the corresponding counter will cause an exception when V=1. Hence, they are effectively
     // WARL fields.) 
+    // every guest then loads its own mask when switched in, see `guest::counters`
     hcounteren::write(0xffff_ffff);
 
+    // stage-2 A/D bits are updated by the hardware or on guest page faults
+    adbits::init();
+
+    // `henvcfg` is loaded per guest when it exists
+    envcfg::init();
+
+    // vector registers are saved per guest hart when the hart has V
+    fpu::init();
+
+    // hardware counters of the host and their overflow interrupts for the virtual PMU
+    pmu::init();
+
+    // debug triggers of the host for the virtual SBI debug triggers
+    triggers::init();
+
     // enable all interupts
-    sie::set_sext();
-    sie::set_ssoft();
-    sie::set_stimer();
+    arch::unmask_irq(Irq::External);
+    arch::unmask_irq(Irq::Software);
+    arch::unmask_irq(Irq::Timer);
 
     core::arch::asm!(
         "csrw vsatp, 0"
@@ -230,24 +854,31 @@ pub unsafe fn init_vmm(hpm: HostMemorySet<PageTableSv39>, host_machine: MachineM
 
     // initialize HOST_VMM
     HOST_VMM.call_once(|| {
-        let mut guests: ArrayVec<Option<Guest<PageTableSv39>>, MAX_GUESTS> = ArrayVec::new_const();
-        for _ in 0..MAX_GUESTS{
-            guests.push(None)
-        }
-
         let host_plic;
         if let Some(plic) = host_machine.clone().plic {
             host_plic = Some(PlicState::new(plic.base_address));
         }else{
             host_plic = None;
         }
-        Mutex::new(
+        SpinNoIrq::new(
             HostVmm { 
                 host_machine,
                 hpm,
-                guests,
+                guests: GuestTable::new(),
                 guest_id: 0,
                 host_plic,
+                host_virtio: Vec::new(),
+                host_irqs: Vec::new(),
+                host_blk: None,
+                host_blk_irq: None,
+                host_net_irq: None,
+                host_uart_irq: None,
+                host_imsic: None,
+                host_iopmp: None,
+                msi_routes: BTreeMap::new(),
+                plic_contexts: PlicContexts::new(),
+                bar_allocator: BarAllocator::new(),
+                sched: Scheduler::new(cmdline::get().time_slice),
                 irq_pending: false,
                 timer_irq: 0,
                 external_irq: 0,
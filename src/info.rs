@@ -0,0 +1,134 @@
+//! Identification of the running hypervisor
+//!
+//! Guests read it field by field with `HYPERCALL_INFO_FID`, operators get the whole
+//! picture, with uptime, memory usage and guests, from the monitor `info` command.
+
+use core::fmt::Write;
+
+use crate::arch::{ self, Arch };
+use crate::bootprof;
+use crate::constants::{ CLOCK_FREQ, PAGE_SIZE, MAX_GUESTS };
+use crate::guest::page_table::GuestPageTable;
+use crate::hyp_alloc::frame_usage;
+use crate::hypervisor::HostVmm;
+use crate::mm::adbits;
+use crate::page_table::PageTable;
+
+/// implementation id, "HC2"
+pub const IMPL_ID: usize = 0x0048_4332;
+
+const fn parse_decimal(s: &str) -> usize {
+    let bytes = s.as_bytes();
+    let mut value = 0;
+    let mut i = 0;
+    while i < bytes.len() {
+        value = value * 10 + (bytes[i] - b'0') as usize;
+        i += 1;
+    }
+    value
+}
+
+/// crate version as `major << 16 | minor << 8 | patch`
+pub const VERSION: usize = parse_decimal(env!("CARGO_PKG_VERSION_MAJOR")) << 16
+    | parse_decimal(env!("CARGO_PKG_VERSION_MINOR")) << 8
+    | parse_decimal(env!("CARGO_PKG_VERSION_PATCH"));
+
+/// bits of the feature bitmap
+pub mod feature {
+    /// the hardware updates stage-2 A/D bits
+    pub const SVADU: usize = 1 << 0;
+    /// MSIs of assigned PCI functions go through an IMSIC
+    pub const IMSIC: usize = 1 << 1;
+    /// PCI functions can be assigned to guests
+    pub const PCI: usize = 1 << 2;
+    /// console input is interrupt driven and routed by focus
+    pub const UART_IRQ: usize = 1 << 3;
+    /// built with the `lock_debug` feature
+    pub const LOCK_DEBUG: usize = 1 << 4;
+    /// built with the guest kernel embedded
+    pub const EMBED_GUEST: usize = 1 << 5;
+    /// guest images are checked against signatures before launch
+    pub const SECURE_BOOT: usize = 1 << 6;
+    /// built with the monitor shell
+    pub const MONITOR: usize = 1 << 7;
+    /// built with tracing messages and interrupt latency sampling
+    pub const TRACING: usize = 1 << 8;
+    /// built with the sampling profiler
+    pub const PROFILER: usize = 1 << 9;
+}
+
+/// build profile and cargo features
+fn build_info() -> (&'static str, &'static str, &'static str, &'static str) {
+    let profile = if cfg!(debug_assertions) { "debug" } else { "release" };
+    let lock_debug = if cfg!(feature = "lock_debug") { " lock_debug" } else { "" };
+    let embed_guest = if cfg!(feature = "embed_guest_kernel") { " embed_guest_kernel" } else { "" };
+    let secure_boot = if cfg!(feature = "secure_boot") { " secure_boot" } else { "" };
+    (profile, lock_debug, embed_guest, secure_boot)
+}
+
+fn cycles_to_ms(cycles: usize) -> usize {
+    cycles / (CLOCK_FREQ / 1000)
+}
+
+impl<P: PageTable, G: GuestPageTable> HostVmm<P, G> {
+    pub fn features(&self) -> usize {
+        let mut features = 0;
+        if adbits::hardware_ad_update() {
+            features |= feature::SVADU;
+        }
+        if self.host_imsic.is_some() {
+            features |= feature::IMSIC;
+        }
+        if self.host_machine.pci.is_some() {
+            features |= feature::PCI;
+        }
+        if self.host_uart_irq.is_some() {
+            features |= feature::UART_IRQ;
+        }
+        if cfg!(feature = "lock_debug") {
+            features |= feature::LOCK_DEBUG;
+        }
+        if cfg!(feature = "embed_guest_kernel") {
+            features |= feature::EMBED_GUEST;
+        }
+        if cfg!(feature = "secure_boot") {
+            features |= feature::SECURE_BOOT;
+        }
+        if cfg!(feature = "monitor") {
+            features |= feature::MONITOR;
+        }
+        if cfg!(feature = "tracing") {
+            features |= feature::TRACING;
+        }
+        if cfg!(feature = "profiler") {
+            features |= feature::PROFILER;
+        }
+        features
+    }
+
+    /// write version, uptime, harts, memory usage and guests to `out`
+    pub fn info_report_to(&self, out: &mut dyn Write) {
+        let (profile, lock_debug, embed_guest, secure_boot) = build_info();
+        let _ = writeln!(
+            out, "hypocaust-2 {} ({}, {}{}{}{})",
+            env!("CARGO_PKG_VERSION"), arch::Current::NAME, profile, lock_debug, embed_guest, secure_boot
+        );
+        let _ = writeln!(out, "impl id:  {:#x}, version {:#x}, features {:#x}", IMPL_ID, VERSION, self.features());
+        let uptime = cycles_to_ms(bootprof::uptime());
+        let _ = writeln!(out, "uptime:   {}.{:03} s", uptime / 1000, uptime % 1000);
+        let _ = writeln!(out, "harts:    {}", self.host_machine.hart_count());
+        let (total, free) = frame_usage();
+        let _ = writeln!(
+            out, "frames:   {} of {} used ({} KiB free)",
+            total - free, total, free * PAGE_SIZE / 1024
+        );
+        let _ = writeln!(out, "guests:   {} of {}", self.guests.len(), MAX_GUESTS);
+        for guest in self.guests.iter() {
+            let _ = writeln!(
+                out, "  guest {}: {} vcpus, {} MiB at {:#x}, {:?}, boot {:?}",
+                guest.guest_id, guest.harts.len(), guest.guest_machine.physical_memory_size >> 20,
+                guest.guest_machine.physical_memory_offset, guest.state, guest.boot_verdict
+            );
+        }
+    }
+}
@@ -0,0 +1,179 @@
+//! I/O service hart
+//!
+//! Device models that take long to fill a buffer hold the vcpu that notified them for the
+//! whole trap. With `hvc.iohart=<hart>` that hart is started at boot and does nothing but
+//! run I/O jobs: the trap path queues the work of an asynchronous device with `submit`
+//! and resumes the guest, the service hart runs it off the `HOST_VMM` lock and queues
+//! the completion, then raises a software interrupt on the boot hart, see `boothart`,
+//! which applies the completions with `HostVmm::complete_io` in that interrupt, injecting
+//! the device interrupt of the guest. Without a service hart jobs run in place.
+//!
+//! Jobs carry the priority of their guest, real-time guests above all others, and the
+//! service hart runs the most urgent one first so bulk I/O of a low-priority guest does
+//! not starve them. The jobs of one backend run in the order they were queued, a job
+//! waiting behind jobs of a lower priority on the same backend lends them its priority
+//! until they ran, as with priority inheritance on a lock.
+//!
+//! Jobs reach guest memory through its stage-2 table. Whoever changes the memory or the
+//! stage-2 table of a guest, a reset, a grant revoke or a device detach, calls `quiesce`
+//! first, which waits until no job of the guest is queued or running.
+
+use alloc::boxed::Box;
+use alloc::collections::VecDeque;
+use core::sync::atomic::{ AtomicUsize, Ordering };
+
+use crate::arch::{ self, Irq };
+use crate::boothart;
+use crate::constants::MAX_HARTS;
+use crate::device_emu::virtio::RingPosition;
+use crate::hypervisor::fdt::MachineMeta;
+use crate::sbi;
+use crate::sync::SpinIrqSave;
+
+/// used buffers of a queue of an emulated virtio device, see `device_emu::virtio`
+pub struct IoCompletion {
+    pub guest_id: usize,
+    /// base address of the device in the guest
+    pub device: usize,
+    /// reset count of the device when the job was queued, stale completions are dropped
+    pub generation: usize,
+    pub queue: usize,
+    /// where the job left the rings of the queue
+    pub position: RingPosition,
+    /// buffers were returned to the guest
+    pub used: bool
+}
+
+pub struct IoJob {
+    pub guest_id: usize,
+    /// see `GuestConfig::io_priority`
+    pub priority: usize,
+    /// identity of the backend, its jobs run in order
+    pub backend: usize,
+    pub work: Box<dyn FnOnce() -> IoCompletion + Send>
+}
+
+/// no service hart, or no job running
+const NONE: usize = usize::MAX;
+
+/// hart running the jobs, `NONE` if they run in place
+static SERVICE_HART: AtomicUsize = AtomicUsize::new(NONE);
+/// guest of the job being run by the service hart
+static RUNNING: AtomicUsize = AtomicUsize::new(NONE);
+static JOBS: SpinIrqSave<VecDeque<IoJob>> = SpinIrqSave::new(VecDeque::new());
+static COMPLETIONS: SpinIrqSave<VecDeque<IoCompletion>> = SpinIrqSave::new(VecDeque::new());
+
+/// pick the service hart of `hvc.iohart=<hart>`
+pub fn init(machine: &MachineMeta) {
+    let hart = match machine.bootarg("hvc.iohart") {
+        Some(hart) => hart,
+        None => return
+    };
+    match hart.parse::<usize>() {
+        Ok(hart) if hart != boothart::boot_hart() && hart < machine.hart_count().min(MAX_HARTS) => SERVICE_HART.store(hart, Ordering::Release),
+        _ => hwarning!("invalid hvc.iohart, device models run on the trap path")
+    }
+}
+
+/// start the service hart, called by the boot hart once paging and the trap entry are
+/// set up. A hart entered by firmware is already running, it waits for `boothart::set_ready`
+pub fn start() {
+    extern "C" {
+        fn _start();
+    }
+    let hart = SERVICE_HART.load(Ordering::Acquire);
+    if hart == NONE {
+        return
+    }
+    let error = sbi::hart_start(hart, _start as usize, 0);
+    if error != sbi::SBI_SUCCESS as isize && error != sbi::SBI_ERR_ALREADY_AVAILABLE {
+        SERVICE_HART.store(NONE, Ordering::Release);
+        hwarning!("failed to start I/O hart {}: {}", hart, error);
+    }
+}
+
+/// the hart was started for I/O jobs
+pub fn is_service_hart(hart_id: usize) -> bool {
+    SERVICE_HART.load(Ordering::Acquire) == hart_id
+}
+
+/// jobs run on a service hart
+pub fn running() -> bool {
+    SERVICE_HART.load(Ordering::Acquire) != NONE
+}
+
+/// run `job` on the service hart, or in place without one
+pub fn submit(job: IoJob) {
+    let hart = SERVICE_HART.load(Ordering::Acquire);
+    if hart == NONE {
+        let completion = (job.work)();
+        COMPLETIONS.lock().push_back(completion);
+        return
+    }
+    JOBS.lock().push_back(job);
+    sbi::send_ipi(hart);
+}
+
+/// completions not yet applied by the boot hart, clears the software interrupt raised for them
+pub fn take_completions() -> VecDeque<IoCompletion> {
+    arch::clear_irq(Irq::Software);
+    core::mem::take(&mut *COMPLETIONS.lock())
+}
+
+/// the first job of the backend with the most urgent job queued, the earliest such
+/// backend among equals
+fn next_job(jobs: &mut VecDeque<IoJob>) -> Option<IoJob> {
+    let urgent = jobs.iter().rev().max_by_key(|job| job.priority)?;
+    let (backend, priority) = (urgent.backend, urgent.priority);
+    let index = jobs.iter().position(|job| job.backend == backend)?;
+    let job = jobs.remove(index)?;
+    if job.priority < priority {
+        htracking!("I/O job of guest {} inherits priority {} from a job behind it", job.guest_id, priority);
+    }
+    Some(job)
+}
+
+/// wait until no job of `guest_id` is queued or running
+pub fn quiesce(guest_id: usize) {
+    if !running() {
+        return
+    }
+    loop {
+        let busy = RUNNING.load(Ordering::Acquire) == guest_id
+            || JOBS.lock().iter().any(|job| job.guest_id == guest_id);
+        if !busy {
+            break
+        }
+        core::hint::spin_loop();
+    }
+}
+
+/// main loop of the service hart, sleeps in `wfi` until the boot hart queues a job
+pub fn service_main(hart_id: usize) -> ! {
+    hdebug!("hart {} serves I/O jobs", hart_id);
+    arch::unmask_irq(Irq::Software);
+    loop {
+        let job = {
+            let mut jobs = JOBS.lock();
+            let job = next_job(&mut jobs);
+            // set under the lock, `quiesce` never misses a job between queue and run
+            if let Some(job) = job.as_ref() {
+                RUNNING.store(job.guest_id, Ordering::Release);
+            }
+            job
+        };
+        let job = match job {
+            Some(job) => job,
+            None => {
+                // `sstatus.SIE` is clear, `wfi` returns once the IPI is pending
+                arch::wait_for_interrupt();
+                arch::clear_irq(Irq::Software);
+                continue
+            }
+        };
+        let completion = (job.work)();
+        COMPLETIONS.lock().push_back(completion);
+        RUNNING.store(NONE, Ordering::Release);
+        sbi::send_ipi(boothart::boot_hart());
+    }
+}
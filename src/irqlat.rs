@@ -0,0 +1,102 @@
+//! Interrupt injection latency
+//!
+//! An interrupt is stamped with the `time` csr when it reaches the hypervisor, in
+//! `handle_irq` for interrupts passed through the PLIC and on injection for queued
+//! ones. The first read of the emulated claim register returning it closes the
+//! sample. Samples are kept per interrupt source as min/avg/max and a histogram
+//! with power-of-two microsecond buckets, shown by the monitor `irqlat` command.
+
+use alloc::collections::BTreeMap;
+use core::fmt::Write;
+use riscv::register::time;
+
+use crate::constants::CLOCK_FREQ;
+use crate::sync::SpinIrqSave;
+
+/// bucket `i` counts latencies below `2^i` us, the last one everything above
+const BUCKETS: usize = 16;
+
+#[derive(Clone, Copy)]
+struct IrqLatency {
+    count: usize,
+    total: usize,
+    min: usize,
+    max: usize,
+    buckets: [usize; BUCKETS]
+}
+
+impl IrqLatency {
+    const fn new() -> Self {
+        Self { count: 0, total: 0, min: usize::MAX, max: 0, buckets: [0; BUCKETS] }
+    }
+
+    fn record(&mut self, cycles: usize) {
+        self.count += 1;
+        self.total += cycles;
+        self.min = self.min.min(cycles);
+        self.max = self.max.max(cycles);
+        let us = cycles_to_us(cycles);
+        let bucket = (usize::BITS - us.leading_zeros()) as usize;
+        self.buckets[bucket.min(BUCKETS - 1)] += 1;
+    }
+}
+
+struct LatencyStats {
+    /// arrival time of interrupts not claimed yet, by guest and source
+    arrivals: BTreeMap<(usize, u32), usize>,
+    /// samples by source
+    sources: BTreeMap<u32, IrqLatency>
+}
+
+static STATS: SpinIrqSave<LatencyStats> = SpinIrqSave::new(LatencyStats {
+    arrivals: BTreeMap::new(),
+    sources: BTreeMap::new()
+});
+
+fn cycles_to_us(cycles: usize) -> usize {
+    cycles * 1_000_000 / CLOCK_FREQ
+}
+
+/// `irq` for `guest_id` reached the hypervisor at `stamp`
+pub fn arrived(guest_id: usize, irq: u32, stamp: usize) {
+    STATS.lock().arrivals.insert((guest_id, irq), stamp);
+}
+
+/// the guest read `irq` from its claim register
+pub fn claimed(guest_id: usize, irq: u32) {
+    let now = time::read();
+    let mut stats = STATS.lock();
+    if let Some(stamp) = stats.arrivals.remove(&(guest_id, irq)) {
+        stats.sources.entry(irq).or_insert(IrqLatency::new()).record(now.wrapping_sub(stamp));
+    }
+}
+
+/// drop all samples
+pub fn reset() {
+    let mut stats = STATS.lock();
+    stats.arrivals.clear();
+    stats.sources.clear();
+}
+
+/// write the latency of every source seen so far to `out`
+pub fn report_to(out: &mut dyn Write) {
+    // copied first, output written to the memory sink must not wait for the stats
+    let sources = STATS.lock().sources.clone();
+    let _ = writeln!(out, "{:>5} {:>8} {:>8} {:>8} {:>8}", "irq", "count", "min(us)", "avg(us)", "max(us)");
+    for (irq, latency) in sources.iter() {
+        let _ = writeln!(
+            out, "{:>5} {:>8} {:>8} {:>8} {:>8}",
+            irq, latency.count, cycles_to_us(latency.min),
+            cycles_to_us(latency.total / latency.count), cycles_to_us(latency.max)
+        );
+        let _ = write!(out, "      ");
+        for (bucket, &count) in latency.buckets.iter().enumerate().filter(|&(_, &count)| count != 0) {
+            if bucket == BUCKETS - 1 {
+                let _ = write!(out, " >={}us:{}", 1usize << (bucket - 1), count);
+            }else{
+                let _ = write!(out, " <{}us:{}", 1usize << bucket, count);
+            }
+        }
+        let _ = writeln!(out);
+    }
+}
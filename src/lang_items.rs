@@ -1,11 +1,16 @@
 //! The panic handler
 
+use crate::nested;
 use crate::sbi::shutdown;
 use core::panic::PanicInfo;
 
 #[panic_handler]
 /// panic handler
 fn panic(info: &PanicInfo) -> ! {
+    // a panic from the panic path, e.g. a fault while printing, is not reported again
+    if !nested::begin_panic() {
+        shutdown()
+    }
     if let Some(location) = info.location() {
         println!(
             "\x1b[1;31m[hypervisor] Panicked at {}:{} {}\x1b[0m",
@@ -12,14 +12,19 @@ extern crate bitflags;
 
 extern crate alloc;
 
-#[path = "boards/qemu.rs"]
+#[path = "boards/mod.rs"]
 mod board;
 
 #[macro_use]
 mod console;
+#[macro_use]
+mod percpu;
 mod sbi;
 mod lang_items;
 mod detect;
+mod errata;
+mod nested;
+mod arch;
 mod page_table;
 mod constants;
 mod hyp_alloc;
@@ -27,18 +32,29 @@ mod sync;
 mod mm;
 mod guest;
 mod hypervisor;
+mod sched;
+mod cmdline;
+mod bootprof;
+mod boothart;
+mod boot;
+#[cfg(feature = "tracing")]
+mod irqlat;
+#[cfg(feature = "tracing")]
+mod exitlat;
+#[cfg(feature = "profiler")]
+mod profiler;
+mod ioservice;
+mod info;
+mod secure_boot;
+#[cfg(feature = "monitor")]
+mod monitor;
 mod device_emu;
 mod error;
 mod drivers;
+mod net;
 
 
-use crate::constants::PAGE_SIZE;
-use crate::mm::{HostMemorySet, GuestMemorySet};
-use crate::constants::layout::{GUEST_DEFAULT_SIZE, GUEST_START_PA};
-use crate::page_table::PageTableSv39;
-use crate::guest::Guest;
-use crate::guest::vmexit::hart_entry_1;
-use crate::hypervisor::{ init_vmm, HOST_VMM, add_guest_queue };
+use crate::constants::{ PAGE_SIZE, MAX_HARTS };
 
 pub use error::{ VmmError, VmmResult };
 
@@ -69,7 +85,7 @@ const BOOT_STACK_SIZE: usize = 16 * PAGE_SIZE;
 
 #[link_section = ".bss.stack"]
 /// hypervisor boot stack
-static BOOT_STACK: [u8; BOOT_STACK_SIZE] = [0u8; BOOT_STACK_SIZE];
+static BOOT_STACK: [u8; BOOT_STACK_SIZE * MAX_HARTS] = [0u8; BOOT_STACK_SIZE * MAX_HARTS];
 
 #[link_section = ".text.entry"]
 #[export_name = "_start"]
@@ -77,6 +93,17 @@ static BOOT_STACK: [u8; BOOT_STACK_SIZE] = [0u8; BOOT_STACK_SIZE];
 /// hypervisor entrypoint
 pub unsafe extern "C" fn start() -> ! {
     core::arch::asm!(
+        // harts without a boot stack are stopped, see `boothart`
+        "li t0, {max_harts}",
+        "bltu a0, t0, 1f",
+        "li a7, {hsm}",
+        "li a6, {hart_stop}",
+        "ecall",
+        "2: wfi",
+        "j 2b",
+        "1:",
+        // hart-local variables use the template until `percpu::init`
+        "la tp, spercpu",
         // prepare stack
         "la sp, {boot_stack}",
         "li t2, {boot_stack_size}",
@@ -85,72 +112,25 @@ pub unsafe extern "C" fn start() -> ! {
         "add sp, sp, t2",
         // enter hentry
         "call hentry",
+        max_harts = const MAX_HARTS,
+        hsm = const sbi::SBI_EXTID_HSM,
+        hart_stop = const sbi::SBI_HART_STOP_FID,
         boot_stack = sym BOOT_STACK,
         boot_stack_size = const BOOT_STACK_SIZE,
         options(noreturn)
     )
 }
 
-/// clear BSS segment
-fn clear_bss() {
-    extern "C" {
-        fn sbss();
-        fn ebss();
-    }
-    unsafe {
-        core::slice::from_raw_parts_mut(sbss as usize as *mut u8, ebss as usize - sbss as usize)
-            .fill(0);
-    }
-}
-
-
-
-
 #[no_mangle]
 unsafe fn hentry(hart_id: usize, dtb: usize) -> ! {
-    if hart_id == 0 {
-        clear_bss();
-        hdebug!("guest entry: {:#x}, guest size: {:#x}", GUEST.as_ptr() as usize, GUEST.len());
-        hdebug!("guest dtb addr: {:#x}", GUEST_DTB.as_ptr() as usize);
-        hdebug!("Hello Hypocaust-2!");
-        hdebug!("hart id: {}, dtb: {:#x}", hart_id, dtb);
-        // detect h extension
-        if sbi_rt::probe_extension(sbi_rt::Hsm).is_unavailable() {
-            panic!("no HSM extension exist on current SBI environment");
-        }
-        if !detect::detect_h_extension() {
-            panic!("no RISC-V hypervisor H extension on current environment")
-        }
-        hdebug!("Hypocaust-2 > running with hardware RISC-V H ISA acceration!");
-
-        // initialize heap
-        hyp_alloc::heap_init();
-        hdebug!("host dtb: {:#x}", dtb);
-        let machine = hypervisor::fdt::MachineMeta::parse(dtb);
-        // parse guest fdt
-        hdebug!("guest dtb: {:#x}", GUEST_DTB.as_ptr() as usize);
-        let guest_machine = hypervisor::fdt::MachineMeta::parse(GUEST_DTB.as_ptr() as usize);
-        // initialize vmm
-        let hpm = HostMemorySet::<PageTableSv39>::new_host_vmm(&machine);
-        init_vmm(hpm, machine);
-        // create guest memory set
-        let gpm = GuestMemorySet::<PageTableSv39>::new_guest_without_load(&guest_machine);
-
-        let mut host_vmm = HOST_VMM.get_mut().unwrap().lock();
-        host_vmm.hpm.map_guest(GUEST_START_PA, GUEST_DEFAULT_SIZE);
-        drop(host_vmm);
-        // hypervisor enable paging
-        mm::enable_paging();
-        // trap init
-        guest::vmexit::trap_init();
-        // memory translation test
-        mm::remap_test();
-        // create guest struct
-        let guest = Guest::new(0, gpm, guest_machine);
-        add_guest_queue(guest);
-        hdebug!("Jump to guest......");
-        hart_entry_1()
+    // the first hart in sets the hypervisor up, firmware may start any of them
+    if boothart::elect(hart_id) {
+        let mut boot = boot::early(hart_id, dtb);
+        boot::claim_devices(&mut boot);
+        boot::configure_guest(&mut boot);
+        boot::host(&mut boot);
+        boot::late(boot)
     }else{
-        unreachable!()
+        boot::secondary(hart_id)
     }
 }
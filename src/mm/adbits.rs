@@ -0,0 +1,78 @@
+//! Accessed and dirty bits of stage-2 page tables
+//!
+//! Stage-2 leaves are created with A and D clear. With Svadu the hardware sets them on the
+//! first access; without it the access takes a guest page fault instead. Such a fault is
+//! told apart from a real one by its leaf: the leaf is valid and allows the access, only
+//! the A bit, or the D bit of a store, is missing. `GuestMemorySet::update_ad` then sets
+//! the bits in software and the guest retries the access.
+//!
+//! Either way the D bits end up telling which guest pages were written since they were
+//! last cleared, `GuestMemorySet::take_dirty_pages` collects them for dirty tracking.
+
+use alloc::vec::Vec;
+use core::sync::atomic::{ AtomicBool, Ordering };
+
+use super::GuestMemorySet;
+use crate::arch;
+use crate::detect;
+use crate::guest::page_table::GuestPageTable;
+use crate::page_table::{ PageTable, PTEFlags, VirtAddr, AccessType };
+
+/// the hardware updates stage-2 A/D bits
+static SVADU: AtomicBool = AtomicBool::new(false);
+
+/// detect Svadu, called once at boot
+pub fn init() {
+    let svadu = detect::detect_svadu();
+    SVADU.store(svadu, Ordering::Relaxed);
+    if svadu {
+        hdebug!("Svadu: stage-2 A/D bits are updated by the hardware");
+    }else{
+        hdebug!("no Svadu: stage-2 A/D bits are updated on guest page faults");
+    }
+}
+
+pub fn hardware_ad_update() -> bool {
+    SVADU.load(Ordering::Relaxed)
+}
+
+impl<G: GuestPageTable> GuestMemorySet<G> {
+    /// set the A bit, and the D bit for a write, of the leaf mapping `guest_pa` when they are
+    /// all `access` is missing. Returns whether the fault was one, the caller flushes the TLB.
+    pub fn update_ad(&mut self, guest_pa: usize, access: AccessType) -> bool {
+        let vpn = VirtAddr::from(guest_pa).floor();
+        let pte = match self.page_table.translate(vpn) {
+            Some(pte) if pte.is_valid() && pte.is_user() => pte,
+            _ => return false
+        };
+        let (permitted, needed) = match access {
+            AccessType::Read => (pte.readable(), PTEFlags::A),
+            AccessType::Write => (pte.writable(), PTEFlags::A | PTEFlags::D),
+            AccessType::Execute => (pte.executable(), PTEFlags::A)
+        };
+        if !permitted || pte.flags().contains(needed) {
+            return false
+        }
+        self.page_table.set_flags(vpn, pte.flags() | needed)
+    }
+
+    /// guest physical addresses of the pages written since the last call, their D bits are
+    /// cleared so that the next write to them is seen again
+    pub fn take_dirty_pages(&mut self) -> Vec<usize> {
+        let mut dirty = Vec::new();
+        let page_table = &mut self.page_table;
+        for area in self.areas.iter().filter(|area| !area.is_mmio()) {
+            for vpn in area.vpn_range {
+                if let Some(pte) = page_table.translate(vpn).filter(|pte| pte.is_valid() && pte.dirty()) {
+                    page_table.set_flags(vpn, pte.flags() - PTEFlags::D);
+                    dirty.push(usize::from(vpn) << 12);
+                }
+            }
+        }
+        if !dirty.is_empty() {
+            // a cached translation would let further writes skip the D bit
+            arch::flush_stage2_tlb();
+        }
+        dirty
+    }
+}
@@ -0,0 +1,261 @@
+//! Host mappings of the frame pool and of guest ram, and what a stray hypervisor access hit
+//!
+//! The hypervisor maps its image, the frames it allocated, the stacks of the vcpus and the
+//! devices it drives. Nothing else is mapped: the first page, free frames, the gap above
+//! the pool, guest ram and the guard page below every hypervisor stack fault at once, and
+//! `trap_from_kernel` names the region of the address with `region`.
+//!
+//! The host page table is built with the whole pool `[ekernel, MEMORY_END)` mapped, then
+//! every free frame is unmapped before paging is enabled. `frame_alloc` maps a frame
+//! before handing it out and `frame_dealloc` unmaps it when it is back in the allocator,
+//! so a write through a pointer to a freed frame faults instead of corrupting the next
+//! owner of the frame. The leaf tables of the pool are kept, mapping a frame never
+//! allocates. `hvc.framemap=linear` keeps the whole pool and guest ram mapped.
+//!
+//! Frames holding guest data the hypervisor only touches now and then, the pristine guest
+//! images, are unmapped as well once filled, see `FrameTracker::hide`. The hypervisor
+//! reaches them through a temporary mapping with `with_frame`. Guest ram, `GUEST_RAM`, is
+//! never mapped for good: its leaf tables are built at boot by
+//! `HostMemorySet::reserve_guest`, and the loader, the device models and the SBI calls
+//! reach it through a `GuestRamMapping` living for the time of one access, or
+//! `with_guest_ram`. A stray write of the hypervisor faults instead of landing in a guest.
+//!
+//! Temporary mappings are serialized by one lock and nest on the hart holding it, an
+//! image frame can be copied to guest ram under both mappings. Nested mappings are dropped
+//! in the reverse order of their creation. Only the hart unmapping a page flushes its
+//! TLB, another hart may still reach the page through a cached translation until its next
+//! flush, the mappings are identity ones and such an entry still names the right page. A
+//! hart faulting on a page another hart mapped meanwhile flushes and retries, see
+//! `spurious_fault`.
+
+use alloc::vec::Vec;
+use core::fmt::{ self, Display, Formatter };
+use core::ops::Range;
+use core::sync::atomic::{ AtomicUsize, Ordering };
+
+use crate::arch;
+use crate::constants::{ KERNEL_STACK_SIZE, MAX_GUESTS, PAGE_SIZE };
+use crate::constants::layout::{ GUEST_DTB_ADDR, GUEST_WINDOW_SIZE, MEMORY_END, TRAP_CONTEXT };
+use crate::hyp_alloc::for_each_free_frame;
+use crate::page_table::{ PageTable, PageTableSv39, PhysPageNum, PTEFlags, VirtPageNum };
+use crate::percpu;
+use crate::sync::{ IrqSave, SpinGuard, SpinIrqSave };
+
+/// host addresses of guest ram, the dtb windows included, of all the guests there may be
+pub const GUEST_RAM: Range<usize> = GUEST_DTB_ADDR..GUEST_DTB_ADDR + MAX_GUESTS * GUEST_WINDOW_SIZE;
+
+/// host page table token with the pool mapped on demand, 0 if it is mapped linearly
+static DEMAND_TOKEN: AtomicUsize = AtomicUsize::new(0);
+/// held while a hidden frame or guest ram is mapped for the time of an access
+static TEMP_MAPPING: SpinIrqSave<()> = SpinIrqSave::new(());
+
+/// take `TEMP_MAPPING`, `None` if this hart holds it already for an outer mapping
+fn temp_mapping_lock() -> Option<SpinGuard<'static, IrqSave, ()>> {
+    // a hart holding the lock runs with interrupts masked, the owner cannot change under it
+    (TEMP_MAPPING.owner() != Some(percpu::hart_id())).then(|| TEMP_MAPPING.lock())
+}
+
+fn is_mapped(page_table: &PageTableSv39, page: usize) -> bool {
+    page_table.translate(VirtPageNum::from(page)).map_or(false, |pte| pte.is_valid())
+}
+
+fn host_table() -> Option<PageTableSv39> {
+    match DEMAND_TOKEN.load(Ordering::Acquire) {
+        0 => None,
+        token => Some(PageTableSv39::from_token(token))
+    }
+}
+
+fn pool_start() -> usize {
+    extern "C" {
+        fn ekernel();
+    }
+    ekernel as usize
+}
+
+/// unmap the free frames of the host page table `token`, called by the boot hart before paging
+/// is enabled and before frames are hidden, while no other hart allocates
+pub fn map_on_demand(token: usize) {
+    let mut page_table = PageTableSv39::from_token(token);
+    let mut unmapped = 0;
+    for_each_free_frame(|ppn| {
+        page_table.unmap(VirtPageNum::from(ppn.0));
+        unmapped += 1;
+    });
+    DEMAND_TOKEN.store(token, Ordering::Release);
+    hdebug!("frame pool mapped on demand, {} free frames unmapped", unmapped);
+}
+
+/// map a frame being handed out
+pub fn map_frame(ppn: PhysPageNum) {
+    if let Some(mut page_table) = host_table() {
+        page_table.map(VirtPageNum::from(ppn.0), ppn, PTEFlags::R | PTEFlags::W);
+    }
+}
+
+/// unmap a frame back in the allocator
+pub fn unmap_frame(ppn: PhysPageNum) {
+    if let Some(mut page_table) = host_table() {
+        page_table.unmap(VirtPageNum::from(ppn.0));
+        arch::flush_host_page(ppn.0 * PAGE_SIZE);
+    }
+}
+
+/// unmap a frame the hypervisor keeps but rarely touches, returns false if the pool is
+/// mapped linearly and the frame stays mapped
+pub fn hide_frame(ppn: PhysPageNum) -> bool {
+    match host_table() {
+        Some(_) => {
+            unmap_frame(ppn);
+            true
+        },
+        None => false
+    }
+}
+
+/// run `f` on the bytes of a frame, mapped for the time of the call if it is hidden
+pub fn with_frame<R>(ppn: PhysPageNum, f: impl FnOnce(&mut [u8]) -> R) -> R {
+    let page_table = match host_table() {
+        Some(page_table) => page_table,
+        None => return f(ppn.get_bytes_array())
+    };
+    // taken first, the frame may be mapped for another hart that unmaps it any time
+    let _mapping = temp_mapping_lock();
+    if is_mapped(&page_table, ppn.0) {
+        return f(ppn.get_bytes_array())
+    }
+    map_frame(ppn);
+    let result = f(ppn.get_bytes_array());
+    unmap_frame(ppn);
+    result
+}
+
+/// whether the host maps guest ram only for the time of an access,
+/// `HostMemorySet::reserve_guest` has to build its leaf tables then
+pub fn on_demand() -> bool {
+    host_table().is_some()
+}
+
+/// pages of guest ram mapped into the host for as long as the mapping lives. Mappings nest
+/// on the hart holding one, pages an outer mapping holds are left to it.
+pub struct GuestRamMapping {
+    base: usize,
+    len: usize,
+    /// pages mapped by this mapping, unmapped when it is dropped
+    mapped: Vec<usize>,
+    _lock: Option<SpinGuard<'static, IrqSave, ()>>
+}
+
+impl GuestRamMapping {
+    /// map `[pa, pa + len)`, `None` if part of it is neither guest ram, a frame of the pool
+    /// nor mapped by the host. Frames of the pool a guest maps are hidden image frames.
+    pub fn new(pa: usize, len: usize) -> Option<Self> {
+        let end = pa.checked_add(len)?;
+        let mut mapping = Self { base: pa, len, mapped: Vec::new(), _lock: None };
+        let mut page_table = match host_table() {
+            Some(page_table) => page_table,
+            None => return Some(mapping)
+        };
+        mapping._lock = temp_mapping_lock();
+        for page in pa / PAGE_SIZE..(end + PAGE_SIZE - 1) / PAGE_SIZE {
+            let addr = page * PAGE_SIZE;
+            // the leaf tables of guest ram and of the pool are built at boot, a page without
+            // one is not mapped here, that would allocate a table the host table does not own
+            match page_table.translate(VirtPageNum::from(page)) {
+                Some(pte) if pte.is_valid() => continue,
+                Some(_) if GUEST_RAM.contains(&addr) || (pool_start()..MEMORY_END).contains(&addr) => {},
+                // the pages mapped so far are unmapped on drop
+                _ => return None
+            }
+            page_table.map(VirtPageNum::from(page), PhysPageNum::from(page), PTEFlags::R | PTEFlags::W);
+            mapping.mapped.push(page);
+        }
+        Some(mapping)
+    }
+
+    pub fn bytes(&self) -> &[u8] {
+        unsafe{ core::slice::from_raw_parts(self.base as *const u8, self.len) }
+    }
+
+    pub fn bytes_mut(&mut self) -> &mut [u8] {
+        unsafe{ core::slice::from_raw_parts_mut(self.base as *mut u8, self.len) }
+    }
+}
+
+impl Drop for GuestRamMapping {
+    fn drop(&mut self) {
+        if let Some(mut page_table) = host_table() {
+            for &page in self.mapped.iter() {
+                page_table.unmap(VirtPageNum::from(page));
+                arch::flush_host_page(page * PAGE_SIZE);
+            }
+        }
+    }
+}
+
+/// run `f` on `[pa, pa + len)`, mapped for the time of the call, see `GuestRamMapping::new`
+pub fn with_guest_ram<R>(pa: usize, len: usize, f: impl FnOnce(&mut [u8]) -> R) -> Option<R> {
+    let mut mapping = GuestRamMapping::new(pa, len)?;
+    Some(f(mapping.bytes_mut()))
+}
+
+/// a page fault of the hypervisor at `addr` on a frame mapped by another hart since the
+/// faulting hart cached the old entry, the TLB is flushed and the access can be retried
+pub fn spurious_fault(addr: usize) -> bool {
+    let page_table = match host_table() {
+        Some(page_table) => page_table,
+        None => return false
+    };
+    if !(pool_start()..MEMORY_END).contains(&addr) && !GUEST_RAM.contains(&addr) {
+        return false
+    }
+    let mapped = is_mapped(&page_table, addr / PAGE_SIZE);
+    if mapped {
+        arch::flush_host_page(addr);
+    }
+    mapped
+}
+
+/// region of the hypervisor address space an address falls in, for fault reports
+pub enum Region {
+    NullPage,
+    /// guard page below the hypervisor stack of a guest
+    StackGuard(usize),
+    /// frame of the pool not handed out, or hidden
+    FreeFrame(usize),
+    /// guest ram outside of a `GuestRamMapping`
+    GuestRam(usize),
+    Other
+}
+
+impl Display for Region {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Region::NullPage => write!(f, "null page"),
+            Region::StackGuard(guest_id) => write!(f, "guard page of the stack of guest {}, stack overflow", guest_id),
+            Region::FreeFrame(ppn) => write!(f, "frame ppn={:#x} free or hidden, used after free or never allocated", ppn),
+            Region::GuestRam(addr) => write!(f, "guest ram at {:#x} outside of a temporary mapping", addr),
+            Region::Other => write!(f, "unmapped")
+        }
+    }
+}
+
+pub fn region(addr: usize) -> Region {
+    if addr < PAGE_SIZE {
+        return Region::NullPage
+    }
+    let slot = KERNEL_STACK_SIZE + PAGE_SIZE;
+    if addr < TRAP_CONTEXT {
+        let guest_id = (TRAP_CONTEXT - 1 - addr) / slot;
+        if guest_id < MAX_GUESTS && addr < TRAP_CONTEXT - guest_id * slot - KERNEL_STACK_SIZE {
+            return Region::StackGuard(guest_id)
+        }
+    }
+    if host_table().is_some() && (pool_start()..MEMORY_END).contains(&addr) {
+        return Region::FreeFrame(addr / PAGE_SIZE)
+    }
+    if host_table().is_some() && GUEST_RAM.contains(&addr) {
+        return Region::GuestRam(addr)
+    }
+    Region::Other
+}
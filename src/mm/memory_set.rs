@@ -1,15 +1,21 @@
 //! Implementation of [`MapArea`] and [`MemorySet`].
 
+use crate::device_emu::mmio::MmioDevice;
+use crate::device_emu::plic::{ PLIC_PRIORITY, PLIC_PENDING, PLIC_ENABLE, PLIC_SHADOW_ENABLE_SIZE };
+use crate::guest::SharedText;
+use crate::guest::pmap::gpa2hpa;
 use crate::guest::page_table::GuestPageTable;
 use crate::hyp_alloc::{ FrameTracker, frame_alloc };
-use crate::page_table::{PTEFlags, PageTable};
+use crate::page_table::{PTEFlags, PageTable, AccessType};
 use crate::page_table::{PhysAddr, PhysPageNum, VirtAddr, VirtPageNum};
 use crate::page_table::{StepByOne, VPNRange, PPNRange};
 use crate::constants::{
     PAGE_SIZE,
+    pci::ECAM_HOST_WINDOW,
     layout::{ TRAMPOLINE, TRAP_CONTEXT, MEMORY_END, GUEST_START_PA, GUEST_START_VA }
 };
 use crate::hypervisor::{ fdt::MachineMeta, HOST_VMM };
+use crate::{ VmmError, VmmResult };
 use alloc::collections::BTreeMap;
 use alloc::vec::Vec;
 use super::MemorySet;
@@ -43,6 +49,10 @@ pub struct HostMemorySet<P: PageTable> {
 pub struct GuestMemorySet<G: GuestPageTable> {
     pub page_table: G,
     pub areas: Vec<MapArea<G>>,
+    /// frames of an enclave guest, zeroed when they are freed
+    pub scrub_frames: bool,
+    /// host address of guest ram minus its guest physical address, see `pmap::gpa2hpa`
+    pub ram_offset: usize,
 }
 
 impl<P: PageTable> HostMemorySet<P> {
@@ -53,6 +63,19 @@ impl<P: PageTable> HostMemorySet<P> {
         }
     }
 
+    /// Mention that trampoline is not collected by areas.
+    /// Only the hypervisor maps it, guests get the whole guest physical address space.
+    fn map_trampoline(&mut self) {
+        extern "C" {
+            fn strampoline();
+        }
+        self.page_table.map(
+            VirtAddr::from(TRAMPOLINE).into(),
+            PhysAddr::from(strampoline as usize).into(),
+            PTEFlags::R | PTEFlags::X,
+        )
+    }
+
     /// Without kernel stacks.
     /// 内核虚拟地址映射
     /// 映射了内核代码段和数据段以及跳板页，没有映射内核栈
@@ -150,7 +173,22 @@ impl<P: PageTable> HostMemorySet<P> {
             );
         }
 
-        for virtio_dev in machine.virtio.iter() {
+        // early uart keeps working after paging is enabled
+        if let Some(uart) = crate::drivers::uart::early_uart() {
+            hpm.push(
+                MapArea::new(
+                    uart.base().into(),
+                    (uart.base() + PAGE_SIZE).into(),
+                    Some(uart.base().into()),
+                    Some((uart.base() + PAGE_SIZE).into()),
+                    MapType::Linear,
+                    MapPermission::R | MapPermission::W,
+                ), 
+                None
+            );
+        }
+
+                for virtio_dev in machine.virtio.iter() {
             hpm.push(
                 MapArea::new(
                     virtio_dev.base_address.into(),
@@ -177,6 +215,35 @@ impl<P: PageTable> HostMemorySet<P> {
                 None
             );
         }
+
+        if let Some(iopmp) = &machine.iopmp {
+            hpm.push(
+                MapArea::new(
+                    iopmp.base_address.into(),
+                    (iopmp.base_address + iopmp.size).into(),
+                    Some(iopmp.base_address.into()),
+                    Some((iopmp.base_address + iopmp.size).into()),
+                    MapType::Linear,
+                    MapPermission::R | MapPermission::W,
+                ),
+                None
+            );
+        }
+
+        // config space of devices assigned to guests
+        if let Some(pci) = &machine.pci {
+            hpm.push(
+                MapArea::new(
+                    pci.base_address.into(),
+                    (pci.base_address + ECAM_HOST_WINDOW).into(),
+                    Some(pci.base_address.into()),
+                    Some((pci.base_address + ECAM_HOST_WINDOW).into()),
+                    MapType::Linear,
+                    MapPermission::R | MapPermission::W,
+                ),
+                None
+            );
+        }
         hpm
     }
 
@@ -192,6 +259,15 @@ impl<P: PageTable> HostMemorySet<P> {
         }
     }
 
+    /// unmap and drop the area starting at `start_va`, the caller flushes the TLB
+    pub fn remove_area(&mut self, start_va: VirtAddr) {
+        let start_vpn: VirtPageNum = start_va.floor();
+        if let Some(index) = self.areas.iter().position(|area| area.vpn_range.get_start() == start_vpn) {
+            let mut area = self.areas.remove(index);
+            area.unmap(&mut self.page_table);
+        }
+    }
+
     pub fn map_guest(&mut self, start_pa: usize, gpm_size: usize) {
         self.push(
             MapArea::new(
@@ -206,9 +282,18 @@ impl<P: PageTable> HostMemorySet<P> {
         );
     }
 
+    /// build the leaf tables of `[start_pa, start_pa + size)` and leave it unmapped, it is
+    /// mapped page by page for the time of an access, see `framemap::GuestRamMapping`
+    pub fn reserve_guest(&mut self, start_pa: usize, size: usize) {
+        for page in start_pa / PAGE_SIZE..(start_pa + size) / PAGE_SIZE {
+            self.page_table.map(VirtPageNum::from(page), PhysPageNum::from(page), PTEFlags::R | PTEFlags::W);
+            self.page_table.unmap(VirtPageNum::from(page));
+        }
+    }
+
     /// 加载客户操作系统
     pub fn map_gpm(&mut self, gpm: &GuestMemorySet<impl GuestPageTable>) {
-        for area in gpm.areas.iter() {
+        for area in gpm.areas.iter().filter(|area| !area.is_mmio()) {
             // 修改虚拟地址与物理地址相同
             let ppn_range = area.ppn_range.unwrap();
             let start_pa: PhysAddr = ppn_range.get_start().into();
@@ -230,14 +315,161 @@ impl<P: PageTable> HostMemorySet<P> {
 
 }
 
+impl<G: GuestPageTable> Drop for GuestMemorySet<G> {
+    /// the frames of the areas go first, scrubbed if they must be, then the stage-2 table
+    fn drop(&mut self) {
+        self.areas.clear();
+    }
+}
+
 impl<G: GuestPageTable> GuestMemorySet<G> {
+    /// unmap and drop the area starting at `start_va`, the caller flushes the guest TLB
+    pub fn remove_area(&mut self, start_va: VirtAddr) {
+        let start_vpn: VirtPageNum = start_va.floor();
+        if let Some(index) = self.areas.iter().position(|area| area.vpn_range.get_start() == start_vpn) {
+            let mut area = self.areas.remove(index);
+            area.unmap(&mut self.page_table);
+        }
+    }
+
+    /// frames owned by the memory set: its stage-2 table and the frames of its framed areas
+    pub fn frame_count(&self) -> usize {
+        self.page_table.frame_count() + self.areas.iter().map(|area| area.data_frames.len()).sum::<usize>()
+    }
+
+    /// frames owned by the memory set now and later are zeroed when they are freed
+    pub fn set_scrub_frames(&mut self) {
+        self.scrub_frames = true;
+        for area in self.areas.iter_mut() {
+            area.data_frames.values_mut().for_each(|frame| frame.set_scrub());
+        }
+    }
+
+    /// host physical ranges of the guest ram mapped linearly, device windows excluded
+    pub fn ram_ranges(&self, guest_machine: &MachineMeta) -> Vec<(usize, usize)> {
+        // the device tree sits below the first memory bank
+        let ram_start = guest_machine.guest_dtb_addr();
+        let ram_end = guest_machine.physical_memory_offset + guest_machine.physical_memory_size;
+        self.areas.iter()
+            .filter(|area| {
+                area.map_type == MapType::Linear
+                    && VirtAddr::from(area.vpn_range.get_start()).0 >= ram_start
+                    && VirtAddr::from(area.vpn_range.get_end()).0 <= ram_end
+            })
+            .map(|area| {
+                let ppn_range = area.ppn_range.unwrap();
+                (PhysAddr::from(ppn_range.get_start()).0, PhysAddr::from(ppn_range.get_end()).0)
+            })
+            .collect()
+    }
+
+    /// zero the guest ram behind the linear areas of the memory set, it does not come from
+    /// the frame allocator and is left to the next guest otherwise. Mapped into the host a
+    /// run of pages at a time, other harts mapping guest ram meanwhile are not held long.
+    pub fn scrub_ram(&self, guest_machine: &MachineMeta) {
+        const SCRUB_CHUNK: usize = 64 * PAGE_SIZE;
+        for (start, end) in self.ram_ranges(guest_machine) {
+            for chunk in (start..end).step_by(SCRUB_CHUNK) {
+                super::framemap::with_guest_ram(chunk, SCRUB_CHUNK.min(end - chunk), |ram| ram.fill(0))
+                    .expect("guest ram outside of the guest ram window");
+            }
+        }
+    }
+
     /// 为 guest page table 新建根页表
     /// 需要分配 16 KiB 对齐的页表
     pub fn new_guest_bare() -> Self {
         Self {
             page_table: GuestPageTable::new_guest(),
-            areas: Vec::new()
+            areas: Vec::new(),
+            scrub_frames: false,
+            ram_offset: 0
+        }
+    }
+
+    /// whether `[base, base + size)` overlaps no area: ram, device windows and mapped BARs
+    pub fn is_free(&self, base: usize, size: usize) -> bool {
+        let (start, end): (VirtPageNum, VirtPageNum) = (VirtAddr::from(base).floor(), VirtAddr::from(base + size).ceil());
+        self.areas.iter().all(|area| area.vpn_range.get_end() <= start || end <= area.vpn_range.get_start())
+    }
+
+    /// reserve `[base, base + size)` for an emulated device, see `device_emu::mmio`
+    pub fn reserve_mmio(&mut self, base: usize, size: usize, device: MmioDevice) -> VmmResult {
+        self.try_push(
+            MapArea::new(base.into(), (base + size).into(), None, None, MapType::Mmio(device), MapPermission::empty()),
+            None
+        )
+    }
+
+    /// Give the guest a writable copy of the shared text page it stored to, in its own
+    /// memory at the same address: guest memory is mapped linearly by the host, the
+    /// page is where the kernel was loaded. Returns whether `guest_pa` was a shared
+    /// page, the caller flushes the guest TLB.
+    pub fn break_cow(&mut self, guest_pa: usize) -> VmmResult<bool> {
+        let vpn = VirtAddr::from(guest_pa).floor();
+        let area = match self.areas.iter().find(|area| area.map_type == MapType::Shared && area.contains(vpn)) {
+            Some(area) => area,
+            None => return Ok(false)
+        };
+        if self.page_table.translate(vpn).map_or(true, |pte| pte.writable()) {
+            return Ok(false)
         }
+        let index = usize::from(vpn) - usize::from(area.vpn_range.get_start());
+        let shared = area.shared.as_ref().unwrap().ppn(index);
+        let private = PhysPageNum::from(usize::from(vpn) + self.ram_offset / PAGE_SIZE);
+        // image frames are hidden from the hypervisor
+        super::framemap::with_frame(shared, |bytes| private.get_bytes_array().copy_from_slice(bytes));
+        let flags = PTEFlags::from_bits((area.map_perm | MapPermission::W).bits).unwrap();
+        self.page_table.unmap(vpn);
+        // the intermediate tables are in place, nothing is allocated
+        self.page_table.try_map(vpn, private, flags)?;
+        Ok(true)
+    }
+
+    /// stage-2 translation of `guest_pa` for `access`, `None` where the guest would take
+    /// a guest page fault. Guest memory is never mapped execute-only, MXR does not matter.
+    pub fn translate_guest_pa(&self, guest_pa: usize, access: AccessType) -> Option<usize> {
+        stage2_translate(&self.page_table, guest_pa, access)
+    }
+
+    /// device owning the MMIO window at `guest_pa`
+    pub fn mmio_device(&self, guest_pa: usize) -> Option<MmioDevice> {
+        let vpn = VirtAddr::from(guest_pa).floor();
+        self.areas.iter().find_map(|area| match area.map_type {
+            MapType::Mmio(device) | MapType::Shadow(device) if area.contains(vpn) => Some(device),
+            _ => None
+        })
+    }
+
+    /// the 32-bit register at `guest_pa` of a shadow page, see `MapType::Shadow`
+    fn shadow_register(&self, guest_pa: usize) -> VmmResult<&'static mut [u8]> {
+        let vpn = VirtAddr::from(guest_pa).floor();
+        let frame = self.areas.iter()
+            .find(|area| matches!(area.map_type, MapType::Shadow(_)) && area.contains(vpn))
+            .and_then(|area| area.data_frames.get(&vpn))
+            .ok_or(VmmError::NoFound)?;
+        let offset = guest_pa % PAGE_SIZE & !3;
+        Ok(&mut frame.ppn.get_bytes_array()[offset..offset + 4])
+    }
+
+    /// read the 32-bit register at `guest_pa` of a shadow page
+    pub fn read_shadow(&self, guest_pa: usize) -> VmmResult<u32> {
+        Ok(u32::from_le_bytes((&*self.shadow_register(guest_pa)?).try_into().unwrap()))
+    }
+
+    /// write the 32-bit register at `guest_pa` of a shadow page
+    pub fn write_shadow(&mut self, guest_pa: usize, value: u32) -> VmmResult {
+        self.shadow_register(guest_pa)?.copy_from_slice(&value.to_le_bytes());
+        Ok(())
+    }
+
+    pub fn try_new_guest_bare() -> VmmResult<Self> {
+        Ok(Self {
+            page_table: GuestPageTable::try_new_guest()?,
+            areas: Vec::new(),
+            scrub_frames: false,
+            ram_offset: 0
+        })
     }
 
     pub fn new_guest(
@@ -271,8 +503,10 @@ impl<G: GuestPageTable> GuestMemorySet<G> {
                     map_perm |= MapPermission::X;
                 }
                 // 将内存拷贝到对应的物理内存上
+                let segment = &guest_data[ph.offset() as usize..(ph.offset() + ph.file_size()) as usize];
+                super::framemap::with_guest_ram(paddr as usize, segment.len(), |ram| ram.copy_from_slice(segment))
+                    .expect("guest segment outside of guest ram");
                 unsafe{
-                    core::ptr::copy(guest_data.as_ptr().add(ph.offset() as usize), paddr, ph.file_size() as usize);
                     let page_align_size = ((ph.mem_size() as usize + PAGE_SIZE - 1) >> 12) << 12;
                     paddr = paddr.add(page_align_size);
                 }
@@ -308,22 +542,7 @@ impl<G: GuestPageTable> GuestMemorySet<G> {
         );
         hdebug!("guest va -> [{:#x}: {:#x}), guest pa -> [{:#x}: {:#x})", GUEST_START_VA, guest_end_va, GUEST_START_PA, guest_end_pa);
 
-        gpm.map_trampoline();
-        
-        // map qemu test
-        if let Some(test) = &guest_machine.test_finisher_address {
-            gpm.push(
-                MapArea::new(
-                    test.base_address.into(),
-                    (test.base_address + test.size).into(),
-                    Some(test.base_address.into()),
-                    Some((test.base_address + test.size).into()),
-                    MapType::Linear,
-                    MapPermission::R | MapPermission::W | MapPermission::U,
-                ), 
-                None
-            );
-        }
+        // qemu test device and the goldfish RTC are emulated, see `device_emu`
 
         // map virtio device
         for virtio_dev in guest_machine.virtio.iter() {
@@ -340,6 +559,20 @@ impl<G: GuestPageTable> GuestMemorySet<G> {
             )
         }
 
+        // assigned framebuffer, never executable
+        if let Some(fb) = &guest_machine.framebuffer {
+            gpm.push(
+                MapArea::new(
+                    fb.device.base_address.into(),
+                    (fb.device.base_address + fb.device.size).into(),
+                    Some(fb.device.base_address.into()),
+                    Some((fb.device.base_address + fb.device.size).into()),
+                    MapType::Linear,
+                    MapPermission::R | MapPermission::W | MapPermission::U,
+                ),
+                None,
+            )
+        }
 
         if let Some(uart) = &guest_machine.uart {
             gpm.push(
@@ -386,42 +619,58 @@ impl<G: GuestPageTable> GuestMemorySet<G> {
         gpm
     }
 
-    pub fn new_guest_without_load(guest_machine: &MachineMeta) -> Self {
-        let mut gpm = Self::new_guest_bare();
+    /// stage-2 memory set of a guest whose images are already in place,
+    /// panics if frames run out
+    pub fn new_guest_without_load(guest_id: usize, guest_machine: &MachineMeta, shared_text: Option<&SharedText>) -> Self {
+        Self::try_new_guest_without_load(guest_id, guest_machine, shared_text).expect("out of frames for guest memory set")
+    }
+
+    /// the ram of guest `guest_id` is its window of host memory, `shared_text` is mapped
+    /// at the start of guest memory, where the kernel is loaded
+    pub fn try_new_guest_without_load(guest_id: usize, guest_machine: &MachineMeta, shared_text: Option<&SharedText>) -> VmmResult<Self> {
+        let mut gpm = Self::try_new_guest_bare()?;
+        gpm.ram_offset = gpa2hpa(0, guest_id);
 
         htracking!("map guest: [{:#x}: {:#x}]", guest_machine.physical_memory_offset, guest_machine.physical_memory_offset + guest_machine.physical_memory_size);
-        gpm.push(MapArea::new(
-                VirtAddr(guest_machine.physical_memory_offset -0x20_0000), 
-                VirtAddr(guest_machine.physical_memory_offset + guest_machine.physical_memory_size), 
-                Some(PhysAddr(guest_machine.physical_memory_offset - 0x20_0000)), 
-                Some(PhysAddr(guest_machine.physical_memory_offset + guest_machine.physical_memory_size)), 
+        let ram_perm = MapPermission::R | MapPermission::W | MapPermission::U | MapPermission::X;
+        let ram_start = guest_machine.guest_dtb_addr();
+        let ram_end = guest_machine.physical_memory_offset + guest_machine.physical_memory_size;
+        let (text_start, text_end) = match shared_text {
+            Some(text) => (guest_machine.physical_memory_offset, guest_machine.physical_memory_offset + text.pages() * PAGE_SIZE),
+            None => (ram_end, ram_end)
+        };
+        gpm.try_push(MapArea::new(
+                VirtAddr(ram_start), 
+                VirtAddr(text_start), 
+                Some(PhysAddr(ram_start + gpm.ram_offset)), 
+                Some(PhysAddr(text_start + gpm.ram_offset)), 
                 MapType::Linear, 
-                MapPermission::R | MapPermission::W | MapPermission::U | MapPermission::X
+                ram_perm
             ),
             None
-        );
-        hdebug!("guest va -> [{:#x}: {:#x}), guest pa -> [{:#x}: {:#x})", guest_machine.physical_memory_offset, guest_machine.physical_memory_offset + guest_machine.physical_memory_size, guest_machine.physical_memory_offset, guest_machine.physical_memory_offset + guest_machine.physical_memory_size);
-
-        gpm.map_trampoline();
-        
-        // map qemu test
-        if let Some(test) = &guest_machine.test_finisher_address {
-            gpm.push(
-                MapArea::new(
-                    test.base_address.into(),
-                    (test.base_address + test.size + 0x1000).into(),
-                    Some(test.base_address.into()),
-                    Some((test.base_address + test.size + 0x1000).into()),
-                    MapType::Linear,
-                    MapPermission::R | MapPermission::W | MapPermission::U | MapPermission::X,
-                ), 
-                None
-            );
+        )?;
+        if let Some(text) = shared_text {
+            gpm.try_push(MapArea::new_shared(VirtAddr(text_start), text.clone(), ram_perm), None)?;
+            if text_end < ram_end {
+                gpm.try_push(MapArea::new(
+                        VirtAddr(text_end), 
+                        VirtAddr(ram_end), 
+                        Some(PhysAddr(text_end + gpm.ram_offset)), 
+                        Some(PhysAddr(ram_end + gpm.ram_offset)), 
+                        MapType::Linear, 
+                        ram_perm
+                    ),
+                    None
+                )?;
+            }
         }
+        hdebug!("guest va -> [{:#x}: {:#x}), guest pa -> [{:#x}: {:#x})", guest_machine.physical_memory_offset, guest_machine.physical_memory_offset + guest_machine.physical_memory_size, guest_machine.physical_memory_offset + gpm.ram_offset, ram_end + gpm.ram_offset);
+
+        // qemu test device, goldfish RTC and PCI ECAM are reserved below, see `device_emu`
 
         // map virtio device
         for virtio_dev in guest_machine.virtio.iter() {
-            gpm.push(
+            gpm.try_push(
                 MapArea::new(
                     virtio_dev.base_address.into(),
                     (virtio_dev.base_address + virtio_dev.size).into(),
@@ -431,12 +680,26 @@ impl<G: GuestPageTable> GuestMemorySet<G> {
                     MapPermission::R | MapPermission::W | MapPermission::U,
                 ),
                 None,
-            )
+            )?;
         }
 
+        // assigned framebuffer, never executable
+        if let Some(fb) = &guest_machine.framebuffer {
+            gpm.try_push(
+                MapArea::new(
+                    fb.device.base_address.into(),
+                    (fb.device.base_address + fb.device.size).into(),
+                    Some(fb.device.base_address.into()),
+                    Some((fb.device.base_address + fb.device.size).into()),
+                    MapType::Linear,
+                    MapPermission::R | MapPermission::W | MapPermission::U,
+                ),
+                None,
+            )?;
+        }
 
         if let Some(uart) = &guest_machine.uart {
-            gpm.push(
+            gpm.try_push(
                 MapArea::new(
                     uart.base_address.into(),
                     (uart.base_address + uart.size).into(),
@@ -446,11 +709,11 @@ impl<G: GuestPageTable> GuestMemorySet<G> {
                     MapPermission::R | MapPermission::W | MapPermission::U,
                 ), 
                 None
-            );
+            )?;
         }
 
         if let Some(clint) = &guest_machine.clint {
-            gpm.push(
+            gpm.try_push(
                 MapArea::new(
                     clint.base_address.into(),
                     (clint.base_address + clint.size).into(),
@@ -460,41 +723,64 @@ impl<G: GuestPageTable> GuestMemorySet<G> {
                     MapPermission::R | MapPermission::W | MapPermission::U,
                 ), 
                 None
-            );
+            )?;
         }
 
         if let Some(plic) = &guest_machine.plic {
-            gpm.push(
-                MapArea::new(
-                    plic.base_address.into(),
-                    (plic.base_address + 0x0020_0000).into(),
-                    Some(plic.base_address.into()),
-                    Some((plic.base_address + 0x0020_0000).into()),
-                    MapType::Linear,
-                    MapPermission::R | MapPermission::W | MapPermission::U,
-                ), 
-                None
+            // priorities and the enables of the guest contexts are read from shadow pages,
+            // writes and the other registers are emulated, see `device_emu::plic`
+            let base = plic.base_address;
+            let shadow = |start: usize, end: usize| MapArea::new(
+                (base + start).into(), (base + end).into(), None, None,
+                MapType::Shadow(MmioDevice::Plic), MapPermission::R | MapPermission::U
             );
+            gpm.try_push(shadow(PLIC_PRIORITY, PLIC_PENDING), None)?;
+            gpm.reserve_mmio(base + PLIC_PENDING, PLIC_ENABLE - PLIC_PENDING, MmioDevice::Plic)?;
+            gpm.try_push(shadow(PLIC_ENABLE, PLIC_ENABLE + PLIC_SHADOW_ENABLE_SIZE), None)?;
+            let reserved = PLIC_ENABLE + PLIC_SHADOW_ENABLE_SIZE;
+            gpm.reserve_mmio(base + reserved, plic.size.saturating_sub(reserved), MmioDevice::Plic)?;
         }
 
+        // emulated devices of the machine description, the virtio slots emulated for
+        // the guest are reserved by `HostVmm::reserve_virtio_windows`
+        if let Some(test) = &guest_machine.test_finisher_address {
+            gpm.reserve_mmio(test.base_address, test.size, MmioDevice::Syscon)?;
+        }
+        if let Some(rtc) = &guest_machine.rtc {
+            gpm.reserve_mmio(rtc.base_address, rtc.size, MmioDevice::Rtc)?;
+        }
+        if let Some(watchdog) = &guest_machine.watchdog {
+            gpm.reserve_mmio(watchdog.base_address, watchdog.size, MmioDevice::Watchdog)?;
+        }
+        // BARs of assigned devices are mapped when the guest places them
         if let Some(pci) = &guest_machine.pci {
-            gpm.push(
-                MapArea::new(
-                    pci.base_address.into(),
-                    (pci.base_address + 0x0020_0000).into(),
-                    Some(pci.base_address.into()),
-                    Some((pci.base_address + 0x0020_0000).into()),
-                    MapType::Linear,
-                    MapPermission::R | MapPermission::W | MapPermission::U,
-                ), 
-                None
-            );
+            gpm.reserve_mmio(pci.base_address, pci.size, MmioDevice::Pci)?;
         }
 
-        gpm
+        Ok(gpm)
     }
 }
 
+/// `GuestMemorySet::translate_guest_pa` on a bare stage-2 table, e.g. one rebuilt from
+/// its token by a hart not holding the memory set
+pub fn stage2_translate<G: PageTable>(page_table: &G, guest_pa: usize, access: AccessType) -> Option<usize> {
+    // Sv39x4 guest physical addresses are 41 bits wide
+    if guest_pa >> 41 != 0 {
+        return None
+    }
+    let pte = page_table.translate(VirtAddr::from(guest_pa).floor())?;
+    let permitted = match access {
+        AccessType::Read => pte.readable(),
+        AccessType::Write => pte.writable(),
+        AccessType::Execute => pte.executable()
+    };
+    // stage-2 leaves are always accessed with user permission
+    if !pte.is_valid() || !pte.is_user() || !permitted {
+        return None
+    }
+    Some((pte.ppn().0 << 12) | (guest_pa & 0xfff))
+}
+
 /// map area structure, controls a contiguous piece of virtual memory
 #[derive(Clone)]
 pub struct MapArea<P: PageTable> {
@@ -503,6 +789,8 @@ pub struct MapArea<P: PageTable> {
     pub data_frames: BTreeMap<VirtPageNum, FrameTracker>,
     pub map_type: MapType,
     pub map_perm: MapPermission,
+    /// frames of a `MapType::Shared` area
+    pub shared: Option<SharedText>,
     _marker: PhantomData<P>
 }
 
@@ -526,6 +814,7 @@ impl<P> MapArea<P> where P: PageTable {
                 data_frames: BTreeMap::new(),
                 map_type,
                 map_perm,
+                shared: None,
                 _marker: PhantomData
             }
         }
@@ -535,35 +824,57 @@ impl<P> MapArea<P> where P: PageTable {
             data_frames: BTreeMap::new(),
             map_type,
             map_perm,
+            shared: None,
             _marker: PhantomData
         }
     }
-    pub fn map_one(&mut self, page_table: &mut P, vpn: VirtPageNum, ppn_: Option<PhysPageNum>) {
+    /// map `text` read-only at `start_va`
+    pub fn new_shared(start_va: VirtAddr, text: SharedText, map_perm: MapPermission) -> Self {
+        let end_va = VirtAddr(start_va.0 + text.pages() * PAGE_SIZE);
+        let mut area = Self::new(start_va, end_va, None, None, MapType::Shared, map_perm - MapPermission::W);
+        area.shared = Some(text);
+        area
+    }
+    pub fn map_one(&mut self, page_table: &mut P, vpn: VirtPageNum, ppn_: Option<PhysPageNum>) -> VmmResult {
         let ppn: PhysPageNum;
         match self.map_type {
             // 线性映射
             MapType::Linear => {
                 ppn = ppn_.unwrap();
             },
-            MapType::Framed => {
-                let frame = frame_alloc().unwrap();
+            MapType::Framed | MapType::Shadow(_) => {
+                let frame = frame_alloc().ok_or(VmmError::OutOfMemory)?;
                 ppn = frame.ppn;
                 self.data_frames.insert(vpn, frame);
-            }
+            },
+            MapType::Shared => {
+                let index = usize::from(vpn) - usize::from(self.vpn_range.get_start());
+                ppn = self.shared.as_ref().unwrap().ppn(index);
+            },
+            MapType::Mmio(_) => return Ok(())
         }
         let pte_flags = PTEFlags::from_bits(self.map_perm.bits).unwrap();
-        page_table.map(vpn, ppn, pte_flags);
+        page_table.try_map(vpn, ppn, pte_flags).map_err(|err| {
+            self.data_frames.remove(&vpn);
+            err
+        })
     }
     #[allow(unused)]
     pub fn unmap_one(&mut self, page_table: &mut P, vpn: VirtPageNum) {
-        if self.map_type == MapType::Framed {
-            self.data_frames.remove(&vpn);
+        match self.map_type {
+            MapType::Framed | MapType::Shadow(_) => { self.data_frames.remove(&vpn); },
+            MapType::Mmio(_) => return,
+            MapType::Linear | MapType::Shared => {}
         }
         page_table.unmap(vpn);
     }
-    pub fn map(&mut self, page_table: &mut P) {
+    /// map the whole area, or nothing if frames run out on the way
+    pub fn map(&mut self, page_table: &mut P) -> VmmResult {
+        if let MapType::Mmio(_) = self.map_type {
+            return Ok(())
+        }
         let vpn_range = self.vpn_range;
-        if let Some(ppn_range) = self.ppn_range {
+        let result = if let Some(ppn_range) = self.ppn_range {
             let ppn_start: usize = ppn_range.get_start().into();
             let ppn_end: usize = ppn_range.get_end().into();
             let vpn_start: usize = vpn_range.get_start().into();
@@ -572,18 +883,26 @@ impl<P> MapArea<P> where P: PageTable {
             let mut ppn = ppn_range.get_start();
             let mut vpn = vpn_range.get_start();
             loop {
-                self.map_one(page_table, vpn, Some(ppn));
+                if let Err(err) = self.map_one(page_table, vpn, Some(ppn)) {
+                    break Err((vpn, err))
+                }
                 ppn.step();
                 vpn.step();
                 if ppn == ppn_range.get_end() && vpn == vpn_range.get_end() {
-                    break;
+                    break Ok(())
                 }
             }
         }else{
-            for vpn in self.vpn_range {
-                self.map_one(page_table, vpn, None)
+            self.vpn_range.into_iter()
+                .try_for_each(|vpn| self.map_one(page_table, vpn, None).map_err(|err| (vpn, err)))
+        };
+        result.map_err(|(failed, err)| {
+            // roll back the pages mapped before the failure
+            for vpn in self.vpn_range.into_iter().take_while(|&vpn| vpn != failed) {
+                self.unmap_one(page_table, vpn);
             }
-        }
+            err
+        })
     }
     #[allow(unused)]
     pub fn unmap(&mut self, page_table: &mut P) {
@@ -593,6 +912,19 @@ impl<P> MapArea<P> where P: PageTable {
     }
     /// data: start-aligned but maybe with shorter length
     /// assume that all frames were cleared before
+    pub fn contains(&self, vpn: VirtPageNum) -> bool {
+        vpn >= self.vpn_range.get_start() && vpn < self.vpn_range.get_end()
+    }
+
+    pub fn overlaps(&self, other: &Self) -> bool {
+        self.vpn_range.get_start() < other.vpn_range.get_end() && other.vpn_range.get_start() < self.vpn_range.get_end()
+    }
+
+    /// an emulated device window, mapped or not
+    pub fn is_mmio(&self) -> bool {
+        matches!(self.map_type, MapType::Mmio(_) | MapType::Shadow(_))
+    }
+
     pub fn copy_data(&mut self, page_table: &mut P, data: &[u8]) {
         assert_eq!(self.map_type, MapType::Framed);
         let mut start: usize = 0;
@@ -617,10 +949,16 @@ impl<P> MapArea<P> where P: PageTable {
 }
 
 #[derive(Copy, Clone, PartialEq, Debug)]
-/// map type for memory set: identical or framed
+/// map type for memory set: identical or framed, read-only shared frames of a kernel
+/// image, an MMIO window that is never mapped, or one whose reads hit shadow frames
 pub enum MapType {
     Framed,
-    Linear
+    Linear,
+    Shared,
+    Mmio(MmioDevice),
+    /// frames of the hypervisor mapped read-only, the device model keeps them in sync
+    /// with its registers and emulates the writes
+    Shadow(MmioDevice)
 }
 
 bitflags! {
@@ -657,6 +995,24 @@ pub fn remap_test() {
         .unwrap()
         .executable(),);
     unsafe{ core::ptr::read(TRAMPOLINE as *const usize) };
+    // stray pointers fault: the first page, the gap above the frame pool and the guard
+    // page below the stack of guest 0 are not mapped
+    let (stack_bottom, _) = crate::hypervisor::stack::hstack_position(0);
+    for addr in [0, MEMORY_END, stack_bottom - PAGE_SIZE] {
+        assert!(!kernel_space
+            .page_table
+            .translate(VirtAddr::from(addr).floor())
+            .map_or(false, |pte| pte.is_valid()),
+            "{:#x} is mapped", addr);
+    }
+    // guest ram is only mapped for the time of an access
+    if super::framemap::on_demand() {
+        assert!(!kernel_space
+            .page_table
+            .translate(VirtAddr::from(GUEST_START_PA).floor())
+            .map_or(false, |pte| pte.is_valid()),
+            "guest ram is mapped");
+    }
     // 测试 guest ketnel
     hdebug!("remap test passed!");
     drop(host_vmm);
@@ -1,12 +1,18 @@
 mod memory_set;
+mod oom;
+pub mod adbits;
+pub mod framemap;
+mod transform;
 
-pub use memory_set::{HostMemorySet, GuestMemorySet, MapArea, remap_test, MapPermission};
+pub use memory_set::{HostMemorySet, GuestMemorySet, MapArea, remap_test, MapPermission, MapType, stage2_translate};
+pub use oom::OomAction;
+pub use transform::{ PageTransform, SealedPage, XorTransform, TAG_SIZE };
+pub use transform::{ KeyedHash, xor_keystream, tags_equal };
 
-use memory_set::MapType;
 use crate::guest::page_table::GuestPageTable;
-use crate::page_table::{VirtAddr, PageTable, VirtPageNum, PageTableEntry, PhysAddr, PTEFlags};
-use crate::constants::layout::TRAMPOLINE;
+use crate::page_table::{VirtAddr, PageTable, VirtPageNum, PageTableEntry};
 use crate::hypervisor::HOST_VMM;
+use crate::{ VmmError, VmmResult };
 
 pub fn enable_paging() {
     let host_vmm = unsafe{ HOST_VMM.get().unwrap().lock() };
@@ -23,13 +29,21 @@ pub trait MemorySet<P: PageTable>{
         end_va: VirtAddr,
         permission: MapPermission,
     );
+    /// map `map_area` and copy `data` into it, panics if frames run out
     fn push(
         &mut self, 
         map_area: MapArea<P>, 
         data: Option<&[u8]>
-    );
+    ) {
+        self.try_push(map_area, data).expect("out of frames for memory set");
+    }
+    /// map `map_area` and copy `data` into it, nothing is mapped if frames run out
+    fn try_push(
+        &mut self,
+        map_area: MapArea<P>,
+        data: Option<&[u8]>
+    ) -> VmmResult;
 
-    fn map_trampoline(&mut self);
     fn translate(&self, vpn: VirtPageNum) -> Option<PageTableEntry>;
     fn translate_va(&self, va: usize) -> Option<usize>;
 }
@@ -53,27 +67,15 @@ impl<P: PageTable> MemorySet<P> for HostMemorySet<P> {
     }
 
     /// 将内存区域 push 到页表中，并映射内存区域
-    fn push(&mut self, mut map_area: MapArea<P>, data: Option<&[u8]>) {
-        map_area.map(&mut self.page_table);
+    fn try_push(&mut self, mut map_area: MapArea<P>, data: Option<&[u8]>) -> VmmResult {
+        map_area.map(&mut self.page_table)?;
         if let Some(data) = data {
             map_area.copy_data(&mut self.page_table, data);
         }
         self.areas.push(map_area);
+        Ok(())
     }
 
-    /// Mention that trampoline is not collected by areas.
-    fn map_trampoline(&mut self) {
-        extern "C" {
-            fn strampoline();
-        }
-        self.page_table.map(
-            VirtAddr::from(TRAMPOLINE).into(),
-            PhysAddr::from(strampoline as usize).into(),
-            PTEFlags::R | PTEFlags::X,
-        );
-    }
-
-    
     /// 将虚拟页号翻译成页表项
     fn translate(&self, vpn: VirtPageNum) -> Option<PageTableEntry> {
         self.page_table.translate(vpn)
@@ -103,26 +105,24 @@ impl<P: GuestPageTable> MemorySet<P> for GuestMemorySet<P> {
     }
 
     /// 将内存区域 push 到页表中，并映射内存区域
-    fn push(&mut self, mut map_area: MapArea<P>, data: Option<&[u8]>) {
-        map_area.map(&mut self.page_table);
+    /// MMIO windows overlap nothing, an area that would is refused with `InvalidState`.
+    /// Other areas are not checked against each other, ranges placed by the guest are
+    /// checked with `is_free` first.
+    fn try_push(&mut self, mut map_area: MapArea<P>, data: Option<&[u8]>) -> VmmResult {
+        if self.areas.iter().any(|area| area.overlaps(&map_area) && (area.is_mmio() || map_area.is_mmio())) {
+            return Err(VmmError::InvalidState)
+        }
+        map_area.map(&mut self.page_table)?;
+        if self.scrub_frames {
+            map_area.data_frames.values_mut().for_each(|frame| frame.set_scrub());
+        }
         if let Some(data) = data {
             map_area.copy_data(&mut self.page_table, data);
         }
         self.areas.push(map_area);
+        Ok(())
     }
 
-    /// Mention that trampoline is not collected by areas.
-    fn map_trampoline(&mut self) {
-        extern "C" {
-            fn strampoline();
-        }
-        self.page_table.map(
-            VirtAddr::from(TRAMPOLINE).into(),
-            PhysAddr::from(strampoline as usize).into(),
-            PTEFlags::R | PTEFlags::X,
-        );
-    }
-    
     /// 将虚拟页号翻译成页表项
     fn translate(&self, vpn: VirtPageNum) -> Option<PageTableEntry> {
         self.page_table.translate(vpn)
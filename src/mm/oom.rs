@@ -0,0 +1,91 @@
+//! Out-of-memory policy
+//!
+//! Work done for a guest at runtime (resets, BAR mappings, hotplug) returns
+//! `VmmError::OutOfMemory` when frames run out instead of panicking the hypervisor.
+//! `HostVmm::retry_on_oom` then runs the steps of `OOM_POLICY` in order, retrying the
+//! operation after every step that freed memory, and gives up with the error once the
+//! policy is exhausted. Boot-time allocations still panic, there is nothing to reclaim yet.
+//!
+//! There is no balloon device and no swap, so the policy reclaims memory the hypervisor
+//! holds on behalf of guests: first what stopped guests do not need, then a whole guest.
+
+use alloc::sync::Arc;
+
+use crate::guest::GuestState;
+use crate::guest::page_table::GuestPageTable;
+use crate::hypervisor::HostVmm;
+use crate::page_table::PageTable;
+use crate::{ VmmError, VmmResult };
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OomAction {
+    /// drop the pristine images of stopped guests, they can no longer be reset
+    DropStoppedImages,
+    /// kill the stopped or lowest-priority guest, never the requester or the guest on the cpu
+    KillGuest
+}
+
+/// steps in order, a step is repeated as long as it frees something
+const OOM_POLICY: [OomAction; 2] = [OomAction::DropStoppedImages, OomAction::KillGuest];
+
+impl<P: PageTable, G: GuestPageTable> HostVmm<P, G> {
+    /// run `op` for `guest_id`, reclaiming memory and retrying while it runs out of frames
+    pub fn retry_on_oom<T>(&mut self, guest_id: usize, mut op: impl FnMut(&mut Self) -> VmmResult<T>) -> VmmResult<T> {
+        let mut step = 0;
+        loop {
+            match op(self) {
+                Err(VmmError::OutOfMemory) => {},
+                result => return result
+            }
+            while !self.reclaim(OOM_POLICY[step], guest_id) {
+                step += 1;
+                if step == OOM_POLICY.len() {
+                    herror!("out of memory for guest {}, nothing left to reclaim", guest_id);
+                    return Err(VmmError::OutOfMemory)
+                }
+            }
+        }
+    }
+
+    /// run one step of the policy, return whether it freed anything
+    fn reclaim(&mut self, action: OomAction, requester: usize) -> bool {
+        match action {
+            OomAction::DropStoppedImages => {
+                let mut frames = 0;
+                for guest in self.guests.iter_mut().filter(|guest| guest.state == GuestState::Stopped) {
+                    // an image shared with other guests or with the text mapping stays
+                    frames += guest.image.take()
+                        .map_or(0, |image| if Arc::strong_count(&image) == 1 { image.frames() } else { 0 });
+                    frames += guest.dtb_image.take().map_or(0, |image| image.frames());
+                }
+                if frames > 0 {
+                    hwarning!("out of memory: dropped images of stopped guests, {} frames", frames);
+                }
+                frames > 0
+            },
+            OomAction::KillGuest => match self.oom_victim(requester) {
+                Some(victim) => {
+                    self.kill_guest(victim);
+                    true
+                },
+                None => false
+            }
+        }
+    }
+
+    /// stopped guests go first, then the guest with the lowest priority
+    fn oom_victim(&self, requester: usize) -> Option<usize> {
+        self.guests.iter()
+            .filter(|guest| guest.guest_id != requester && self.sched.current != Some(guest.guest_id))
+            .min_by_key(|guest| {
+                let priority = self.sched.entity(guest.guest_id).map_or(0, |entity| entity.priority);
+                (guest.state != GuestState::Stopped, priority)
+            })
+            .map(|guest| guest.guest_id)
+    }
+
+    fn kill_guest(&mut self, guest_id: usize) {
+        hwarning!("out of memory: killing guest {}", guest_id);
+        self.remove_guest(guest_id);
+    }
+}
@@ -0,0 +1,185 @@
+//! Page transforms of enclave guests
+//!
+//! Memory of an enclave guest must not leave its stage-2 mapping in the clear. Every
+//! path that moves guest pages to storage the guest does not control (swap, snapshots,
+//! migration streams) exports them with `Guest::export_page`, which runs the page
+//! through the guest's `PageTransform`: the page is encrypted with a per-guest key and
+//! gets an integrity tag bound to its guest physical address and a fresh nonce, so a
+//! page cannot be modified, moved to another address or decrypted by another guest.
+//! `Guest::import_page` checks the tag before anything is written to the guest. An older
+//! export of the same page still passes, the caller keeps track of the latest nonce.
+//!
+//! Frames of an enclave guest are zeroed when they are freed, see
+//! `GuestMemorySet::set_scrub_frames`.
+//!
+//! `XorTransform` is a software prototype for research: an xor keystream and a keyed
+//! hash built on the splitmix64 finalizer. It shows where a real cipher (AES-GCM, or
+//! the Zvkned vector crypto instructions) plugs in, it is not cryptographically secure.
+
+use alloc::boxed::Box;
+use core::sync::atomic::{ AtomicU64, Ordering };
+
+use crate::constants::PAGE_SIZE;
+use crate::drivers::entropy;
+use crate::guest::Guest;
+use crate::guest::page_table::GuestPageTable;
+use crate::mm::framemap::with_guest_ram;
+use crate::page_table::AccessType;
+use crate::{ VmmError, VmmResult };
+
+pub const TAG_SIZE: usize = 16;
+
+/// a guest page outside of the guest
+pub struct SealedPage {
+    pub guest_pa: usize,
+    /// never reused by the transform that sealed the page
+    pub nonce: u64,
+    pub data: Box<[u8; PAGE_SIZE]>,
+    pub tag: [u8; TAG_SIZE]
+}
+
+pub trait PageTransform: Send {
+    fn name(&self) -> &'static str;
+    /// encrypt `page` of `guest_pa` in place, returns its nonce and tag
+    fn seal(&self, guest_pa: usize, page: &mut [u8; PAGE_SIZE]) -> (u64, [u8; TAG_SIZE]);
+    /// check the tag of `page` and decrypt it in place, fails with `InvalidState` on a mismatch
+    fn open(&self, guest_pa: usize, nonce: u64, page: &mut [u8; PAGE_SIZE], tag: &[u8; TAG_SIZE]) -> VmmResult;
+}
+
+/// splitmix64 finalizer
+fn mix(mut x: u64) -> u64 {
+    x = (x ^ (x >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    x ^ (x >> 31)
+}
+
+/// keyed 128-bit hash built on `mix`, of the same prototype quality as `XorTransform`
+pub struct KeyedHash {
+    h: [u64; 2],
+    /// bytes of the word being filled
+    word: [u8; 8],
+    len: usize
+}
+
+impl KeyedHash {
+    pub fn new(key: [u64; 2]) -> Self {
+        Self { h: [mix(key[0]), mix(!key[1])], word: [0; 8], len: 0 }
+    }
+
+    fn absorb(&mut self, word: u64) {
+        self.h[0] = mix(self.h[0] ^ word);
+        self.h[1] = mix(self.h[1].rotate_left(17) ^ word);
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        for &byte in data {
+            self.word[self.len % 8] = byte;
+            self.len += 1;
+            if self.len % 8 == 0 {
+                self.absorb(u64::from_le_bytes(self.word));
+            }
+        }
+    }
+
+    pub fn finish(mut self) -> [u8; TAG_SIZE] {
+        // the length tells apart data ending in zero bytes
+        if self.len % 8 != 0 {
+            self.word[self.len % 8..].fill(0);
+            self.absorb(u64::from_le_bytes(self.word));
+        }
+        self.absorb(self.len as u64);
+        let mut tag = [0u8; TAG_SIZE];
+        tag[..8].copy_from_slice(&self.h[0].to_le_bytes());
+        tag[8..].copy_from_slice(&self.h[1].to_le_bytes());
+        tag
+    }
+}
+
+/// xor `data` with the keystream of `key` and `seed`
+pub fn xor_keystream(key: u64, seed: u64, data: &mut [u8]) {
+    let base = mix(key ^ mix(seed));
+    for (i, chunk) in data.chunks_mut(8).enumerate() {
+        let stream = mix(base.wrapping_add(i as u64)).to_le_bytes();
+        chunk.iter_mut().zip(stream.iter()).for_each(|(byte, key)| *byte ^= key);
+    }
+}
+
+/// constant time comparison of two tags
+pub fn tags_equal(a: &[u8; TAG_SIZE], b: &[u8; TAG_SIZE]) -> bool {
+    // no early exit, the time taken tells nothing about the tags
+    a.iter().zip(b.iter()).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+/// software prototype, see the module documentation
+pub struct XorTransform {
+    /// keystream key and tag key
+    key: [u64; 2],
+    next_nonce: AtomicU64
+}
+
+impl XorTransform {
+    /// transform with a fresh random key
+    pub fn new() -> Self {
+        let mut bytes = [0u8; 16];
+        entropy::fill(&mut bytes);
+        let word = |i: usize| u64::from_le_bytes(bytes[i * 8..i * 8 + 8].try_into().unwrap());
+        Self { key: [word(0), word(1)], next_nonce: AtomicU64::new(1) }
+    }
+
+    fn apply_keystream(&self, guest_pa: usize, nonce: u64, page: &mut [u8; PAGE_SIZE]) {
+        xor_keystream(self.key[0], guest_pa as u64 ^ mix(nonce), page);
+    }
+
+    /// keyed hash of the encrypted page, its address and nonce
+    fn tag(&self, guest_pa: usize, nonce: u64, page: &[u8; PAGE_SIZE]) -> [u8; TAG_SIZE] {
+        let mut hash = KeyedHash::new([self.key[1] ^ guest_pa as u64, self.key[1] ^ nonce]);
+        hash.update(page);
+        hash.finish()
+    }
+}
+
+impl PageTransform for XorTransform {
+    fn name(&self) -> &'static str {
+        "xor"
+    }
+
+    fn seal(&self, guest_pa: usize, page: &mut [u8; PAGE_SIZE]) -> (u64, [u8; TAG_SIZE]) {
+        let nonce = self.next_nonce.fetch_add(1, Ordering::Relaxed);
+        self.apply_keystream(guest_pa, nonce, page);
+        (nonce, self.tag(guest_pa, nonce, page))
+    }
+
+    fn open(&self, guest_pa: usize, nonce: u64, page: &mut [u8; PAGE_SIZE], tag: &[u8; TAG_SIZE]) -> VmmResult {
+        if !tags_equal(&self.tag(guest_pa, nonce, page), tag) {
+            return Err(VmmError::InvalidState)
+        }
+        self.apply_keystream(guest_pa, nonce, page);
+        Ok(())
+    }
+}
+
+impl<G: GuestPageTable> Guest<G> {
+    /// copy the page at `guest_pa` out of the guest, sealed if it is an enclave guest
+    pub fn export_page(&self, guest_pa: usize) -> VmmResult<SealedPage> {
+        let guest_pa = guest_pa & !(PAGE_SIZE - 1);
+        let host_pa = self.gpm.translate_guest_pa(guest_pa, AccessType::Read).ok_or(VmmError::NoFound)?;
+        let mut data = Box::new([0u8; PAGE_SIZE]);
+        with_guest_ram(host_pa, PAGE_SIZE, |page| data.copy_from_slice(page)).ok_or(VmmError::NoFound)?;
+        let (nonce, tag) = match self.transform.as_ref() {
+            Some(transform) => transform.seal(guest_pa, &mut data),
+            None => (0, [0; TAG_SIZE])
+        };
+        Ok(SealedPage { guest_pa, nonce, data, tag })
+    }
+
+    /// write an exported page back to its address, an enclave guest only takes pages
+    /// sealed by itself for that address
+    pub fn import_page(&mut self, mut page: SealedPage) -> VmmResult {
+        // written through the stage-2 mapping, read-only pages are refused
+        let host_pa = self.gpm.translate_guest_pa(page.guest_pa, AccessType::Write).ok_or(VmmError::NoFound)?;
+        if let Some(transform) = self.transform.as_ref() {
+            transform.open(page.guest_pa, page.nonce, &mut page.data, &page.tag)?;
+        }
+        with_guest_ram(host_pa, PAGE_SIZE, |bytes| bytes.copy_from_slice(&*page.data)).ok_or(VmmError::NoFound)
+    }
+}
@@ -0,0 +1,457 @@
+//! Hypervisor monitor shell
+//!
+//! Console input of guests goes through a two-key escape protocol: `Ctrl-A` starts
+//! an escape sequence, the next key says what to do with it.
+//!
+//! - `Ctrl-A c` enters the monitor
+//! - `Ctrl-A h` replays the recent output of the guest reading the console
+//! - `Ctrl-A Ctrl-A` sends a literal `Ctrl-A` to the guest
+//!
+//! Other keys after `Ctrl-A` are dropped. Guests are stopped while in the monitor, until
+//! it is left with `exit`. The same commands are served over the network by `remote`.
+//!
+//! Console input is polled through SBI by default, by whichever guest asks for it. With
+//! `hvc.uartirq=on` the hypervisor owns the uart instead: each RX interrupt drains it
+//! through the escape protocol and queues the rest for the guest chosen with `focus`.
+
+use alloc::collections::VecDeque;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt::Write;
+use core::sync::atomic::{ AtomicBool, AtomicUsize, Ordering };
+
+use crate::bootprof;
+#[cfg(feature = "tracing")]
+use crate::{ irqlat, exitlat };
+#[cfg(feature = "profiler")]
+use crate::profiler;
+use crate::hyp_alloc;
+use crate::console::{ self, UartWriter };
+use crate::drivers::uart::early_uart;
+use crate::device_emu::hotplug::HotplugDevice;
+use crate::constants::{ CLOCK_FREQ, MAX_GUESTS };
+use crate::constants::layout::TRAP_CONTEXT;
+use crate::guest::page_table::GuestPageTable;
+use crate::guest::vmexit::TrapContext;
+use crate::hypervisor::HostVmm;
+use crate::page_table::PageTable;
+use crate::sbi::console_getchar;
+use crate::sched::SchedPolicy;
+use crate::sync::SpinIrqSave;
+use crate::VmmResult;
+
+pub mod remote;
+mod xmodem;
+
+/// `Ctrl-A`, starts an escape sequence
+pub const MONITOR_ESCAPE: usize = 0x01;
+/// lines of guest output replayed by `Ctrl-A h` and by `history` by default
+const HISTORY_LINES: usize = 20;
+
+/// characters queued for the focused guest, older ones are kept when it is full
+const GUEST_INPUT_SIZE: usize = 256;
+
+/// `Ctrl-A` was read, the next key completes the escape sequence
+static ESCAPE_PENDING: AtomicBool = AtomicBool::new(false);
+/// console input comes from uart interrupts, see `uart_rx`
+static IRQ_INPUT: AtomicBool = AtomicBool::new(false);
+/// guest receiving console input with `IRQ_INPUT`
+static FOCUS: AtomicUsize = AtomicUsize::new(0);
+static GUEST_INPUT: SpinIrqSave<VecDeque<u8>> = SpinIrqSave::new(VecDeque::new());
+
+/// write a line of command output, output errors are of no interest
+macro_rules! outln {
+    ($out: expr) => {
+        { let _ = writeln!($out); }
+    };
+    ($out: expr, $($arg: tt)+) => {
+        { let _ = writeln!($out, $($arg)+); }
+    }
+}
+
+pub fn enter<P: PageTable, G: GuestPageTable>(host_vmm: &mut HostVmm<P, G>) {
+    // the monitor talks to the uart whatever the log sink is
+    let out = &mut UartWriter;
+    outln!(out);
+    outln!(out, "[Hypervisor] enter monitor, type `help` for commands");
+    let mut line = String::new();
+    loop {
+        let _ = write!(out, "monitor> ");
+        read_line(&mut line);
+        let mut args = line.split_whitespace();
+        // raw transfers only work on the serial console
+        if args.next() == Some("upload") {
+            match (parse_guest_id(args.next()), parse_number(args.next())) {
+                (Some(guest_id), Some(len)) => {
+                    outln!(out, "send {} bytes with XMODEM now", len);
+                    report(out, xmodem::upload(host_vmm, guest_id, len));
+                },
+                _ => outln!(out, "usage: upload <id> <len>")
+            }
+            continue;
+        }
+        if !run_command(host_vmm, line.trim(), out) {
+            break;
+        }
+    }
+    outln!(out, "[Hypervisor] leave monitor");
+}
+
+/// pass a character read from the console through the escape protocol, returns the
+/// character for the guest or `usize::MAX` if there is none
+pub fn console_input<P: PageTable, G: GuestPageTable>(host_vmm: &mut HostVmm<P, G>, c: usize) -> usize {
+    if c == usize::MAX {
+        return c
+    }
+    if !ESCAPE_PENDING.swap(false, Ordering::Relaxed) {
+        if c == MONITOR_ESCAPE {
+            ESCAPE_PENDING.store(true, Ordering::Relaxed);
+            return usize::MAX
+        }
+        return c
+    }
+    match c {
+        MONITOR_ESCAPE => MONITOR_ESCAPE,
+        c if c == b'c' as usize => {
+            enter(host_vmm);
+            usize::MAX
+        },
+        c if c == b'h' as usize => {
+            let out = &mut UartWriter;
+            outln!(out);
+            console::replay_guest_history(input_guest(host_vmm), HISTORY_LINES, out);
+            usize::MAX
+        },
+        _ => usize::MAX
+    }
+}
+
+/// guest the console input is for
+fn input_guest<P: PageTable, G: GuestPageTable>(host_vmm: &HostVmm<P, G>) -> usize {
+    if IRQ_INPUT.load(Ordering::Relaxed) {
+        FOCUS.load(Ordering::Relaxed)
+    }else{
+        host_vmm.guest_id
+    }
+}
+
+/// take console input from uart RX interrupts instead of polling SBI
+pub fn set_irq_input(enable: bool) {
+    IRQ_INPUT.store(enable, Ordering::Relaxed);
+}
+
+/// drain the uart after an RX interrupt: escape sequences are handled at once,
+/// other characters are queued for the focused guest
+pub fn uart_rx<P: PageTable, G: GuestPageTable>(host_vmm: &mut HostVmm<P, G>) {
+    let uart = match early_uart() {
+        Some(uart) => uart,
+        None => return
+    };
+    while let Some(c) = uart.getchar() {
+        let c = console_input(host_vmm, c as usize);
+        if c == usize::MAX {
+            continue;
+        }
+        let mut input = GUEST_INPUT.lock();
+        if input.len() < GUEST_INPUT_SIZE {
+            input.push_back(c as u8);
+        }
+    }
+}
+
+/// next console character for the running guest, `usize::MAX` if there is none
+pub fn guest_getchar<P: PageTable, G: GuestPageTable>(host_vmm: &mut HostVmm<P, G>) -> usize {
+    if !IRQ_INPUT.load(Ordering::Relaxed) {
+        return console_input(host_vmm, console_getchar())
+    }
+    if host_vmm.guest_id != FOCUS.load(Ordering::Relaxed) {
+        return usize::MAX
+    }
+    GUEST_INPUT.lock().pop_front().map_or(usize::MAX, |c| c as usize)
+}
+
+/// the monitor runs with interrupts masked, it polls the uart itself
+fn monitor_getchar() -> usize {
+    match early_uart() {
+        Some(uart) if IRQ_INPUT.load(Ordering::Relaxed) => uart.getchar().map_or(usize::MAX, |c| c as usize),
+        _ => console_getchar()
+    }
+}
+
+fn read_line(line: &mut String) {
+    line.clear();
+    loop {
+        match monitor_getchar() {
+            // no input yet
+            usize::MAX => continue,
+            0x0d | 0x0a => {
+                outln!(UartWriter);
+                return
+            },
+            // backspace/delete
+            0x08 | 0x7f => {
+                if line.pop().is_some() {
+                    let _ = write!(UartWriter, "\x08 \x08");
+                }
+            },
+            c => {
+                let c = c as u8 as char;
+                line.push(c);
+                let _ = write!(UartWriter, "{}", c);
+            }
+        }
+    }
+}
+
+fn parse_guest_id(arg: Option<&str>) -> Option<usize> {
+    arg.and_then(|arg| arg.parse().ok())
+}
+
+/// decimal, or hexadecimal with a `0x` prefix
+fn parse_number(arg: Option<&str>) -> Option<usize> {
+    let arg = arg?;
+    match arg.strip_prefix("0x") {
+        Some(hex) => usize::from_str_radix(hex, 16).ok(),
+        None => arg.parse().ok()
+    }
+}
+
+/// bytes written as pairs of hex digits
+fn parse_hex(hex: &str) -> Option<Vec<u8>> {
+    if hex.len() % 2 != 0 {
+        return None
+    }
+    (0..hex.len()).step_by(2).map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok()).collect()
+}
+
+fn report(out: &mut dyn Write, result: VmmResult) {
+    if let Err(err) = result {
+        outln!(out, "error: {:?}", err);
+    }
+}
+
+fn cycles_to_ms(cycles: usize) -> usize {
+    cycles / (CLOCK_FREQ / 1000)
+}
+
+/// print the saved registers of a guest, the registers of the running guest are
+/// those of the trap being handled
+fn dump_guest<P: PageTable, G: GuestPageTable>(host_vmm: &HostVmm<P, G>, guest_id: usize, out: &mut dyn Write) {
+    let guest = match host_vmm.guests.get(guest_id) {
+        Some(guest) => guest,
+        None => return outln!(out, "error: {:?}", crate::VmmError::NoFound)
+    };
+    let ctx = if host_vmm.sched.current == Some(guest_id) {
+        unsafe{ &*(TRAP_CONTEXT as *const TrapContext) }
+    }else{
+        &guest.trap_ctx
+    };
+    outln!(out, "guest {} {:?} hart {} of {}", guest_id, guest.state, guest.vcpu.hart, guest.harts.len());
+    outln!(out, "sepc    {:#018x} hgatp   {:#018x}", ctx.sepc, ctx.hgatp);
+    outln!(out, "sstatus {:x?}", ctx.sstatus);
+    outln!(out, "hstatus {:x?}", ctx.hstatus);
+    outln!(out, "scause  {:#018x} stval   {:#018x}", ctx.scause, ctx.stval);
+    outln!(out, "htval   {:#018x} htinst  {:#018x}", ctx.htval, ctx.htinst);
+    for (i, pair) in ctx.x.chunks(2).enumerate() {
+        outln!(out, "x{:<2}     {:#018x} x{:<2}     {:#018x}", 2 * i, pair[0], 2 * i + 1, pair[1]);
+    }
+}
+
+/// execute one monitor command, return false if the monitor should be left
+pub fn run_command<P: PageTable, G: GuestPageTable>(host_vmm: &mut HostVmm<P, G>, line: &str, out: &mut dyn Write) -> bool {
+    let mut args = line.split_whitespace();
+    match args.next() {
+        None => {},
+        Some("help") => {
+            outln!(out, "help          show this message");
+            outln!(out, "info          show hypervisor version, uptime and resources");
+            outln!(out, "list          list guests");
+            outln!(out, "dump <id>     show the registers of a guest");
+            outln!(out, "stats         show scheduling statistics of guest harts");
+            outln!(out, "pause <id>    stop scheduling a guest");
+            outln!(out, "resume <id>   resume a paused guest");
+            outln!(out, "reset <id>    reboot a guest from its image");
+            outln!(out, "cap <id> <percent>|off");
+            outln!(out, "              cap the cpu time of a guest per 100ms period");
+            outln!(out, "stage <id> [new <len> | put <offset> <hex> | disk <sector> <len> | commit]");
+            outln!(out, "              stage a kernel in the standby slot of a guest");
+            outln!(out, "relaunch <id> reboot a guest from its standby kernel, swapping the slots");
+            outln!(out, "upload <id> <len>");
+            outln!(out, "              receive a kernel with XMODEM and relaunch a guest from it, serial only");
+            outln!(out, "attach <id> rng [slot] | pci <bdf>");
+            outln!(out, "              add a device to a running guest");
+            outln!(out, "detach <id> rng <slot> | pci <bdf>");
+            outln!(out, "              remove a device from a running guest");
+            outln!(out, "prefix on|off tag guest output with the guest id");
+            outln!(out, "bootprof      show guest boot profile");
+            if cfg!(feature = "tracing") {
+                outln!(out, "irqlat [reset]");
+                outln!(out, "              show interrupt injection latency per source");
+                outln!(out, "bench [start [count] | stop]");
+                outln!(out, "              time guest exits, show the latency per exit class");
+            }
+            if cfg!(feature = "profiler") {
+                outln!(out, "profile [folded] | start <hz> | stop");
+                outln!(out, "              show or control the hypervisor profiler");
+            }
+            outln!(out, "history <id> [lines]");
+            outln!(out, "              show the recent console output of a guest");
+            outln!(out, "focus <id>    send console input to a guest, with hvc.uartirq=on");
+            outln!(out, "log           show the hypervisor trace buffer");
+            outln!(out, "heap          show hypervisor heap usage");
+            if cfg!(feature = "alloc_debug") {
+                outln!(out, "leaks         show live frames per owner and allocation site");
+            }
+            outln!(out, "reboot        reboot the machine");
+            outln!(out, "exit          leave monitor and resume guests");
+        },
+        Some("info") => host_vmm.info_report_to(out),
+        Some("list") => {
+            outln!(out, "{:>3} {:>6} {:>8} {:>8} {:>4} {:>4} {:>8}", "id", "policy", "weight", "priority", "rt", "cap", "state");
+            for guest in host_vmm.guests.iter() {
+                let current = if host_vmm.sched.current == Some(guest.guest_id) { "*" } else { " " };
+                let policy = match guest.config.policy {
+                    SchedPolicy::RoundRobin => "rr",
+                    SchedPolicy::Stride => "stride"
+                };
+                outln!(
+                    out, "{:>3} {:>6} {:>8} {:>8} {:>4} {:>4} {:>8} {}",
+                    guest.guest_id, policy, guest.config.weight, guest.config.priority,
+                    if guest.config.rt.is_some() { "yes" } else { "no" },
+                    guest.config.cap.map_or(String::from("-"), |cap| alloc::format!("{}%", cap)),
+                    format_args!("{:?}", guest.state), current
+                );
+            }
+        },
+        Some("stats") => {
+            outln!(out, "{:>3} {:>4} {:>10} {:>10} {:>8} {:>10}", "id", "hart", "run(ms)", "wait(ms)", "preempt", "exits");
+            for guest_id in host_vmm.guests.ids() {
+                let harts = host_vmm.guests.get(guest_id).map_or(0, |guest| guest.harts.len());
+                for hart in 0..harts {
+                    if let Some(stats) = host_vmm.hart_stats(guest_id, hart) {
+                        outln!(
+                            out, "{:>3} {:>4} {:>10} {:>10} {:>8} {:>10}",
+                            guest_id, hart, cycles_to_ms(stats.run_time), cycles_to_ms(stats.wait_time),
+                            stats.preemptions, stats.exits
+                        );
+                    }
+                }
+            }
+            outln!(out, "idle: {} ms", cycles_to_ms(host_vmm.sched.idle_time));
+        },
+        Some("dump") => match parse_guest_id(args.next()) {
+            Some(guest_id) => dump_guest(host_vmm, guest_id, out),
+            None => outln!(out, "usage: dump <id>")
+        },
+        Some("pause") => match parse_guest_id(args.next()) {
+            Some(guest_id) => report(out, host_vmm.pause_guest(guest_id)),
+            None => outln!(out, "usage: pause <id>")
+        },
+        Some("resume") => match parse_guest_id(args.next()) {
+            Some(guest_id) => report(out, host_vmm.resume_guest(guest_id)),
+            None => outln!(out, "usage: resume <id>")
+        },
+        Some("reset") => match parse_guest_id(args.next()) {
+            Some(guest_id) => report(out, host_vmm.reset_guest(guest_id)),
+            None => outln!(out, "usage: reset <id>")
+        },
+        Some("cap") => match (parse_guest_id(args.next()), args.next().map(|cap| cap.trim_end_matches('%'))) {
+            (Some(guest_id), Some("off")) => report(out, host_vmm.set_guest_cap(guest_id, None)),
+            (Some(guest_id), Some(cap)) => match cap.parse::<usize>() {
+                Ok(cap) => report(out, host_vmm.set_guest_cap(guest_id, Some(cap))),
+                Err(_) => outln!(out, "usage: cap <id> <percent>|off")
+            },
+            _ => outln!(out, "usage: cap <id> <percent>|off")
+        },
+        Some("stage") => match (parse_guest_id(args.next()), args.next(), parse_number(args.next()), args.next()) {
+            (Some(guest_id), None, None, None) if host_vmm.guests.contains(guest_id) => {
+                let (staging, ready) = host_vmm.guests.get(guest_id).unwrap().standby.status();
+                match staging {
+                    Some(len) => outln!(out, "staging {} bytes", len),
+                    None => outln!(out, "staging none")
+                }
+                match ready {
+                    Some(len) => outln!(out, "standby {} bytes", len),
+                    None => outln!(out, "standby none")
+                }
+            },
+            (Some(guest_id), Some("new"), Some(len), None) => report(out, host_vmm.stage_begin(guest_id, len)),
+            (Some(guest_id), Some("put"), Some(offset), Some(hex)) => match parse_hex(hex) {
+                Some(data) => report(out, host_vmm.stage_write(guest_id, offset, &data)),
+                None => outln!(out, "error: invalid hex data")
+            },
+            (Some(guest_id), Some("disk"), Some(sector), Some(len)) => match parse_number(Some(len)) {
+                Some(len) => report(out, host_vmm.stage_from_disk(guest_id, sector as u64, len)),
+                None => outln!(out, "usage: stage <id> disk <sector> <len>")
+            },
+            (Some(guest_id), Some("commit"), None, None) => report(out, host_vmm.stage_commit(guest_id)),
+            _ => outln!(out, "usage: stage <id> [new <len> | put <offset> <hex> | disk <sector> <len> | commit]")
+        },
+        Some("relaunch") => match parse_guest_id(args.next()) {
+            Some(guest_id) => report(out, host_vmm.relaunch(guest_id)),
+            None => outln!(out, "usage: relaunch <id>")
+        },
+        Some("upload") => outln!(out, "error: upload only works on the serial console"),
+        Some("attach") => match (parse_guest_id(args.next()), HotplugDevice::parse(&mut args)) {
+            (Some(guest_id), Some(device)) => report(out, host_vmm.attach_device(guest_id, device)),
+            _ => outln!(out, "usage: attach <id> rng [slot] | pci <bdf>")
+        },
+        Some("detach") => match (parse_guest_id(args.next()), HotplugDevice::parse(&mut args)) {
+            (Some(guest_id), Some(device)) => report(out, host_vmm.detach_device(guest_id, device)),
+            _ => outln!(out, "usage: detach <id> rng <slot> | pci <bdf>")
+        },
+        Some("prefix") => match args.next() {
+            Some("on") => console::set_guest_prefix(true),
+            Some("off") => console::set_guest_prefix(false),
+            _ => outln!(out, "usage: prefix on|off")
+        },
+        Some("history") => match (parse_guest_id(args.next()), args.next().map(|lines| lines.parse::<usize>())) {
+            (Some(guest_id), None) if guest_id < MAX_GUESTS => console::replay_guest_history(guest_id, HISTORY_LINES, out),
+            (Some(guest_id), Some(Ok(lines))) if guest_id < MAX_GUESTS => console::replay_guest_history(guest_id, lines, out),
+            _ => outln!(out, "usage: history <id> [lines]")
+        },
+        Some("focus") => match parse_guest_id(args.next()) {
+            Some(guest_id) if host_vmm.guests.contains(guest_id) => {
+                FOCUS.store(guest_id, Ordering::Relaxed);
+                // typed for the previous guest
+                GUEST_INPUT.lock().clear();
+            },
+            _ => outln!(out, "usage: focus <id>")
+        },
+        Some("bootprof") => bootprof::report_to(out),
+        #[cfg(feature = "tracing")]
+        Some("irqlat") => match args.next() {
+            None => irqlat::report_to(out),
+            Some("reset") => irqlat::reset(),
+            _ => outln!(out, "usage: irqlat [reset]")
+        },
+        #[cfg(feature = "profiler")]
+        Some("profile") => match (args.next(), args.next().map(|rate| rate.parse::<usize>())) {
+            (None, None) => profiler::report_to(out, false),
+            (Some("folded"), None) => profiler::report_to(out, true),
+            (Some("start"), Some(Ok(rate))) => report(out, profiler::start(rate)),
+            (Some("stop"), None) => profiler::stop(),
+            _ => outln!(out, "usage: profile [folded] | start <hz> | stop")
+        },
+        #[cfg(feature = "tracing")]
+        Some("bench") => match (args.next(), args.next().map(|count| count.parse::<usize>())) {
+            (None, None) => exitlat::report_to(out),
+            (Some("start"), None) => report(out, exitlat::start(exitlat::DEFAULT_COUNT)),
+            (Some("start"), Some(Ok(count))) if count > 0 => report(out, exitlat::start(count)),
+            (Some("stop"), None) => exitlat::stop(),
+            _ => outln!(out, "usage: bench [start [count] | stop]")
+        },
+        Some("heap") => hyp_alloc::heap_report(out),
+        #[cfg(feature = "alloc_debug")]
+        Some("leaks") => hyp_alloc::leak::report_to(out),
+        Some("log") => {
+            // copied first, output written to the memory sink must not wait for the trace buffer
+            let trace = console::trace_buffer_contents();
+            let _ = out.write_str(&String::from_utf8_lossy(&trace));
+        },
+        Some("reboot") => host_vmm.reboot_machine(),
+        Some("exit") | Some("quit") => return false,
+        Some(cmd) => outln!(out, "unknown command: {}", cmd)
+    }
+    true
+}
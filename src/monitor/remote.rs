@@ -0,0 +1,168 @@
+//! Monitor commands over UDP
+//!
+//! Keeps the control plane off the serial line shared with guest consoles. Enabled
+//! with `hvc.monitor=<udp port>` next to a hypervisor NIC (`hvc.net`) and a shared key
+//! `hvc.monitor.key=<32 hex digits>`, the monitor is not served without a key.
+//!
+//! A requester first fetches the boot nonce, random at every boot, with the datagram
+//! `magic: u16 | seq: u64` where `seq` is 0, answered as a command whose output is the
+//! nonce as a u64. A request is then one datagram `magic: u16 | seq: u64 | mac: u64 |
+//! command line`, where `mac` is SipHash-2-4 under the key over the nonce, `seq` and the
+//! command line. Requests with a wrong mac or a `seq` not above the last accepted one of
+//! the boot are dropped without answer, a request of an earlier boot does not verify.
+//! The output of the command is sent back to the requester in one or more datagrams
+//! `magic: u16 | seq: u64 | fragment: u8 | flags: u8 | output`, the last one has
+//! `FLAG_LAST` set. All integers are big endian. Requests are served from the NIC
+//! interrupt and the timer tick, guests keep running.
+//!
+//! A guest kernel is uploaded to the standby slot of a guest with one `stage <id> put`
+//! request per piece of the image, see `guest::slots`.
+
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::hash::Hasher;
+use core::sync::atomic::{ AtomicU16, AtomicU64, Ordering };
+
+use spin::Once;
+
+use crate::drivers::entropy;
+use crate::guest::page_table::GuestPageTable;
+use crate::hypervisor::HostVmm;
+use crate::net::{ self, Datagram, UDP_MAX_PAYLOAD };
+use crate::page_table::PageTable;
+use crate::VmmResult;
+
+/// `HM`
+pub const MONITOR_MAGIC: u16 = 0x484d;
+pub const FLAG_LAST: u8 = 1;
+
+const NONCE_REQUEST_LEN: usize = 10;
+const REQUEST_HEADER_LEN: usize = 18;
+const RESPONSE_HEADER_LEN: usize = 12;
+
+/// bound udp port, 0 if the remote monitor is disabled
+static PORT: AtomicU16 = AtomicU16::new(0);
+/// shared key of the requesters
+static KEY: Once<(u64, u64)> = Once::new();
+/// random at every boot, bound into the mac of the requests
+static NONCE: Once<u64> = Once::new();
+/// `seq` of the last accepted request, older ones are replays
+static LAST_SEQ: AtomicU64 = AtomicU64::new(0);
+
+/// parse `hvc.monitor.key`, 128 bits as 32 hex digits
+pub fn parse_key(arg: &str) -> Option<(u64, u64)> {
+    if arg.len() != 32 {
+        return None
+    }
+    let key = u128::from_str_radix(arg, 16).ok()?;
+    Some(((key >> 64) as u64, key as u64))
+}
+
+/// serve monitor commands on udp `port` to requesters knowing `key`
+pub fn init(port: u16, key: (u64, u64)) -> VmmResult {
+    net::with_stack(|stack| stack.bind(port)).unwrap_or(Err(crate::VmmError::DeviceNotFound))?;
+    KEY.call_once(|| key);
+    NONCE.call_once(|| {
+        let mut nonce = [0u8; 8];
+        entropy::fill(&mut nonce);
+        u64::from_le_bytes(nonce)
+    });
+    PORT.store(port, Ordering::Relaxed);
+    hdebug!("remote monitor on udp port {}", port);
+    Ok(())
+}
+
+#[allow(deprecated)]
+fn mac(key: (u64, u64), nonce: u64, seq: [u8; 8], line: &[u8]) -> u64 {
+    let mut hasher = core::hash::SipHasher::new_with_keys(key.0, key.1);
+    hasher.write(&nonce.to_be_bytes());
+    hasher.write(&seq);
+    hasher.write(line);
+    hasher.finish()
+}
+
+/// check the mac and freshness of a request, the comparison takes the same time for any mac
+fn authenticate(seq: [u8; 8], expected: [u8; 8], line: &[u8]) -> bool {
+    let (key, nonce) = match (KEY.get(), NONCE.get()) {
+        (Some(&key), Some(&nonce)) => (key, nonce),
+        _ => return false
+    };
+    let diff = mac(key, nonce, seq, line).to_be_bytes().iter().zip(expected.iter())
+        .fold(0, |diff, (a, b)| diff | (a ^ b));
+    // a 64-bit `seq` does not wrap within a boot, and the nonce changes across boots
+    let seq = u64::from_be_bytes(seq);
+    let fresh = seq > LAST_SEQ.load(Ordering::Relaxed);
+    if diff != 0 || !fresh {
+        return false
+    }
+    LAST_SEQ.store(seq, Ordering::Relaxed);
+    true
+}
+
+/// serve pending requests
+pub fn poll<P: PageTable, G: GuestPageTable>(host_vmm: &mut HostVmm<P, G>) {
+    let port = PORT.load(Ordering::Relaxed);
+    if port == 0 {
+        return
+    }
+    // the stack is not held while commands run, they may log to the network
+    while let Some(request) = net::with_stack(|stack| stack.recv_from(port)).flatten() {
+        serve(host_vmm, port, request);
+    }
+}
+
+fn serve<P: PageTable, G: GuestPageTable>(host_vmm: &mut HostVmm<P, G>, port: u16, request: Datagram) {
+    let payload = &request.payload;
+    if payload.len() < NONCE_REQUEST_LEN || u16::from_be_bytes([payload[0], payload[1]]) != MONITOR_MAGIC {
+        return
+    }
+    let mut seq = [0; 8];
+    seq.copy_from_slice(&payload[2..NONCE_REQUEST_LEN]);
+    if payload.len() == NONCE_REQUEST_LEN && seq == [0; 8] {
+        // the nonce is no secret, it only tells requests of this boot apart
+        let nonce = NONCE.get().copied().unwrap_or_default().to_be_bytes();
+        respond(port, &request, seq, &nonce);
+        return
+    }
+    if payload.len() < REQUEST_HEADER_LEN {
+        return
+    }
+    let mut expected = [0; 8];
+    expected.copy_from_slice(&payload[NONCE_REQUEST_LEN..REQUEST_HEADER_LEN]);
+    if !authenticate(seq, expected, &payload[REQUEST_HEADER_LEN..]) {
+        hwarning!("remote monitor: dropped unauthenticated request from {}.{}.{}.{}",
+            request.src_ip[0], request.src_ip[1], request.src_ip[2], request.src_ip[3]);
+        return
+    }
+    let mut output = String::new();
+    match core::str::from_utf8(&payload[REQUEST_HEADER_LEN..]) {
+        // `exit` has nothing to leave here
+        Ok(line) => { super::run_command(host_vmm, line.trim(), &mut output); },
+        Err(_) => output.push_str("error: command is not utf-8\n")
+    }
+    respond(port, &request, seq, output.as_bytes());
+}
+
+/// send `output` back to the requester of `request`
+fn respond(port: u16, request: &Datagram, seq: [u8; 8], output: &[u8]) {
+    let mut chunks: Vec<&[u8]> = output.chunks(UDP_MAX_PAYLOAD - RESPONSE_HEADER_LEN).collect();
+    if chunks.is_empty() {
+        // commands without output are acknowledged too
+        chunks.push(&[]);
+    }
+    let magic = MONITOR_MAGIC.to_be_bytes();
+    for (fragment, chunk) in chunks.iter().enumerate() {
+        let flags = if fragment + 1 == chunks.len() { FLAG_LAST } else { 0 };
+        let mut response = Vec::with_capacity(RESPONSE_HEADER_LEN + chunk.len());
+        response.extend_from_slice(&magic);
+        response.extend_from_slice(&seq);
+        response.push(fragment as u8);
+        response.push(flags);
+        response.extend_from_slice(chunk);
+        let sent = net::with_stack(|stack| stack.send_to(port, request.src_ip, request.src_port, &response));
+        if !matches!(sent, Some(Ok(()))) {
+            hwarning!("remote monitor: failed to send response");
+            return
+        }
+    }
+}
@@ -0,0 +1,156 @@
+//! Guest kernel upload over the serial console, for boards without network or disk
+//!
+//! `upload <id> <len>` on the serial monitor receives an image of `len` bytes with
+//! XMODEM-CRC, 128-byte blocks or 1 KiB blocks as sent by `sx --1k`, into the standby
+//! slot of the guest, then relaunches the guest from it, see `guest::slots`. Every block
+//! carries a CRC-16/XMODEM, a bad one is sent again, the padding of the last block is
+//! dropped. The transfer is aborted after `MAX_RETRIES` bad blocks in a row, on a timeout
+//! or when the sender cancels it.
+//!
+//! Nothing else may write to the uart meanwhile: the monitor holds the `HOST_VMM` lock
+//! and guests are stopped.
+
+use riscv::register::time;
+
+use crate::console::uart_write;
+use crate::constants::CLOCK_FREQ;
+use crate::guest::page_table::GuestPageTable;
+use crate::hypervisor::HostVmm;
+use crate::page_table::PageTable;
+use crate::{ VmmError, VmmResult };
+
+const SOH: u8 = 0x01;
+const STX: u8 = 0x02;
+const EOT: u8 = 0x04;
+const ACK: u8 = 0x06;
+const NAK: u8 = 0x15;
+const CAN: u8 = 0x18;
+/// asks the sender for blocks with a CRC instead of a checksum
+const CRC_MODE: u8 = b'C';
+
+const MAX_RETRIES: usize = 10;
+/// the sender is asked `START_TRIES` times, a second apart
+const START_TRIES: usize = 60;
+const START_TIMEOUT_MS: usize = 1000;
+const BYTE_TIMEOUT_MS: usize = 1000;
+
+/// next byte from the uart, `None` after `timeout_ms`
+fn read_byte(timeout_ms: usize) -> Option<u8> {
+    let deadline = time::read() + timeout_ms * (CLOCK_FREQ / 1000);
+    while time::read() < deadline {
+        match super::monitor_getchar() {
+            usize::MAX => core::hint::spin_loop(),
+            c => return Some(c as u8)
+        }
+    }
+    None
+}
+
+/// drop input until the sender has been quiet for a while
+fn drain() {
+    while read_byte(BYTE_TIMEOUT_MS).is_some() {}
+}
+
+fn crc16(data: &[u8]) -> u16 {
+    data.iter().fold(0, |crc, &byte| {
+        (0..8).fold(crc ^ (byte as u16) << 8, |crc, _| {
+            if crc & 0x8000 != 0 { crc << 1 ^ 0x1021 } else { crc << 1 }
+        })
+    })
+}
+
+/// a block read after its start byte, `None` on a timeout or a bad CRC
+fn read_block(size: usize, buf: &mut [u8]) -> Option<u8> {
+    let number = read_byte(BYTE_TIMEOUT_MS)?;
+    let complement = read_byte(BYTE_TIMEOUT_MS)?;
+    for byte in buf[..size].iter_mut() {
+        *byte = read_byte(BYTE_TIMEOUT_MS)?;
+    }
+    let crc = (read_byte(BYTE_TIMEOUT_MS)? as u16) << 8 | read_byte(BYTE_TIMEOUT_MS)? as u16;
+    if number != !complement || crc != crc16(&buf[..size]) {
+        return None
+    }
+    Some(number)
+}
+
+fn cancel() {
+    uart_write(&[CAN, CAN, CAN]);
+    drain();
+}
+
+/// receive `len` bytes, `store` gets the data of each block with its offset
+fn receive(len: usize, mut store: impl FnMut(usize, &[u8]) -> VmmResult) -> VmmResult {
+    let mut buf = [0u8; 1024];
+    let mut start = None;
+    for _ in 0..START_TRIES {
+        uart_write(&[CRC_MODE]);
+        start = read_byte(START_TIMEOUT_MS).filter(|&c| c == SOH || c == STX);
+        if start.is_some() {
+            break
+        }
+    }
+    let mut start = start.ok_or(VmmError::InvalidState)?;
+    let mut expected: u8 = 1;
+    let mut received = 0;
+    let mut retries = 0;
+    loop {
+        match start {
+            SOH | STX => {
+                let size = if start == SOH { 128 } else { 1024 };
+                match read_block(size, &mut buf) {
+                    Some(number) if number == expected => {
+                        let size = size.min(len - received);
+                        if let Err(err) = store(received, &buf[..size]) {
+                            cancel();
+                            return Err(err)
+                        }
+                        received += size;
+                        expected = expected.wrapping_add(1);
+                        retries = 0;
+                        uart_write(&[ACK]);
+                    },
+                    // the sender missed our ACK
+                    Some(number) if number == expected.wrapping_sub(1) => uart_write(&[ACK]),
+                    _ => {
+                        retries += 1;
+                        if retries == MAX_RETRIES {
+                            cancel();
+                            return Err(VmmError::InvalidState)
+                        }
+                        drain();
+                        uart_write(&[NAK]);
+                    }
+                }
+            },
+            EOT => {
+                uart_write(&[ACK]);
+                return if received == len { Ok(()) } else { Err(VmmError::InvalidState) }
+            },
+            CAN => return Err(VmmError::InvalidState),
+            _ => {
+                retries += 1;
+                if retries == MAX_RETRIES {
+                    cancel();
+                    return Err(VmmError::InvalidState)
+                }
+                drain();
+                uart_write(&[NAK]);
+            }
+        }
+        start = match read_byte(BYTE_TIMEOUT_MS * MAX_RETRIES) {
+            Some(c) => c,
+            None => {
+                cancel();
+                return Err(VmmError::InvalidState)
+            }
+        };
+    }
+}
+
+/// receive a kernel of `len` bytes for `guest_id` and relaunch the guest from it
+pub fn upload<P: PageTable, G: GuestPageTable>(host_vmm: &mut HostVmm<P, G>, guest_id: usize, len: usize) -> VmmResult {
+    host_vmm.stage_begin(guest_id, len)?;
+    receive(len, |offset, data| host_vmm.stage_write(guest_id, offset, data))?;
+    host_vmm.stage_commit(guest_id)?;
+    host_vmm.relaunch(guest_id)
+}
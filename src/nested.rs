@@ -0,0 +1,180 @@
+//! Traps taken by the hypervisor itself
+//!
+//! A trap in hypervisor code enters `__alltraps_k`, which moves to the trap stack of the
+//! hart: its top is kept in `sscratch` while the hypervisor runs, so a fault caused by an
+//! overflow of the hypervisor stack is still handled instead of faulting again. A trap
+//! taken while one is handled stays on the trap stack, `sscratch` is 0 meanwhile.
+//!
+//! `TRAP_DEPTH` counts the traps a hart is handling, the interrupted code may hold any
+//! lock, `HOST_VMM` included. Handlers are picked by `NestRule`:
+//!
+//! - the first trap may run any handler of `trap_from_kernel`, the ones that cannot
+//!   recover panic
+//! - a nested trap may only run handlers that take no lock, a spurious fault on a frame
+//!   mapped by another hart; anything else stops the hart
+//! - beyond `MAX_DEPTH` the hart stops
+//!
+//! A stopped hart reports the trap straight to the uart and powers the machine off. The
+//! panic handler does the same for a panic raised while the hart already panics.
+//!
+//! Built with the `trap_test` feature, `HYPERCALL_TRAP_TEST_FID` forces a nested trap
+//! with `HOST_VMM` locked, see `self_test`, and `HYPERCALL_WALK_TEST_FID` checks the
+//! software walk of guest addresses against `hlv.d`, whose faults `test_load_fault` skips.
+
+use core::fmt::Write;
+
+use crate::console::UartWriter;
+use crate::constants::MAX_HARTS;
+use crate::percpu;
+use crate::sbi::shutdown;
+
+/// bytes of the trap stack of each hart
+const TRAP_STACK_SIZE: usize = 4096 * 2;
+/// traps a hart handles at once, a deeper one stops it
+pub const MAX_DEPTH: usize = 3;
+
+#[repr(C, align(16))]
+struct TrapStack([u8; TRAP_STACK_SIZE]);
+
+const EMPTY_STACK: TrapStack = TrapStack([0; TRAP_STACK_SIZE]);
+static mut TRAP_STACKS: [TrapStack; MAX_HARTS] = [EMPTY_STACK; MAX_HARTS];
+
+per_cpu! {
+    /// traps of the hypervisor this hart is handling
+    static TRAP_DEPTH: usize = 0;
+    /// the hart is panicking
+    static PANICKING: bool = false;
+    /// deepest trap seen by `self_test`
+    #[cfg(feature = "trap_test")]
+    static TEST_DEPTH: usize = 0;
+    /// scause of the fault of the last `test_guest_load`, `usize::MAX` if it read
+    #[cfg(feature = "trap_test")]
+    static TEST_LOAD_CAUSE: usize = usize::MAX;
+}
+
+/// handlers a trap may run, decided by its depth
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum NestRule {
+    /// the first trap: any handler
+    Any,
+    /// taken while handling another: handlers that take no lock
+    LockFree,
+    /// too deep, stop the hart
+    Stop
+}
+
+/// top of the trap stack of the running hart, `sscratch` while the hypervisor runs
+pub fn stack_top() -> usize {
+    unsafe{ TRAP_STACKS[percpu::hart_id()].0.as_ptr() as usize + TRAP_STACK_SIZE }
+}
+
+/// count a trap of the hypervisor, returns the rule it is handled under
+pub fn enter() -> NestRule {
+    let depth = TRAP_DEPTH.get() + 1;
+    TRAP_DEPTH.set(depth);
+    match depth {
+        1 => NestRule::Any,
+        depth if depth < MAX_DEPTH => NestRule::LockFree,
+        _ => NestRule::Stop
+    }
+}
+
+/// the trap counted by `enter` is handled
+pub fn leave() {
+    TRAP_DEPTH.set(TRAP_DEPTH.get() - 1);
+}
+
+pub fn depth() -> usize {
+    TRAP_DEPTH.get()
+}
+
+/// stop on a trap that cannot be handled at its depth, without taking a lock
+pub fn stop(scause: usize, sepc: usize, stval: usize) -> ! {
+    let _ = write!(
+        UartWriter,
+        "\x1b[1;31m[hypervisor] hart {} stopped on a nested trap, depth {}, scause {:#x}, sepc {:#x}, stval {:#x}\x1b[0m\n",
+        percpu::hart_id(), depth(), scause, sepc, stval
+    );
+    shutdown()
+}
+
+/// called first by the panic handler, false if the hart already panics
+pub fn begin_panic() -> bool {
+    let first = !PANICKING.get();
+    PANICKING.set(true);
+    first
+}
+
+/// `ebreak` of `self_test` taken at the current depth: the first one raises the
+/// second one from its handler, both are skipped
+#[cfg(feature = "trap_test")]
+pub fn test_breakpoint(sepc: &mut usize) -> bool {
+    if *sepc != test_ebreak as usize {
+        return false
+    }
+    TEST_DEPTH.set(TEST_DEPTH.get().max(depth()));
+    if depth() == 1 {
+        unsafe{ test_ebreak() };
+    }
+    *sepc += 4;
+    true
+}
+
+#[cfg(feature = "trap_test")]
+#[naked]
+unsafe extern "C" fn test_ebreak() {
+    core::arch::asm!(
+        ".option push",
+        ".option norvc",
+        "ebreak",
+        ".option pop",
+        "ret",
+        options(noreturn)
+    );
+}
+
+/// raise a trap in the hypervisor and one more from its handler, returns the deepest
+/// trap handled, 2 if nesting works
+#[cfg(feature = "trap_test")]
+pub fn self_test() -> usize {
+    TEST_DEPTH.set(0);
+    unsafe{ test_ebreak() };
+    TEST_DEPTH.get()
+}
+
+/// fault of the `hlv.d` of `test_guest_load`: record the cause and skip the load
+#[cfg(feature = "trap_test")]
+pub fn test_load_fault(sepc: &mut usize, scause: usize) -> bool {
+    if *sepc != test_hlv_d as usize {
+        return false
+    }
+    TEST_LOAD_CAUSE.set(scause);
+    *sepc += 4;
+    true
+}
+
+#[cfg(feature = "trap_test")]
+#[naked]
+unsafe extern "C" fn test_hlv_d(_guest_va: usize) -> usize {
+    core::arch::asm!(
+        ".option push",
+        ".option norvc",
+        // hlv.d a0, (a0)
+        ".insn r 0x73, 0x4, 0x36, a0, a0, zero",
+        ".option pop",
+        "ret",
+        options(noreturn)
+    );
+}
+
+/// `hlv.d` of `guest_va` with the state of the guest whose trap is handled, the scause
+/// of the fault if it faults
+#[cfg(feature = "trap_test")]
+pub fn test_guest_load(guest_va: usize) -> Result<usize, usize> {
+    TEST_LOAD_CAUSE.set(usize::MAX);
+    let value = unsafe{ test_hlv_d(guest_va) };
+    match TEST_LOAD_CAUSE.get() {
+        usize::MAX => Ok(value),
+        scause => Err(scause)
+    }
+}
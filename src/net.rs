@@ -0,0 +1,258 @@
+//! Minimal network stack of the hypervisor: ethernet, ARP and UDP over IPv4
+//!
+//! Just enough for the migration protocol, remote monitoring and the remote log
+//! sink. There is no routing and no fragmentation: peers must be on the local
+//! link, datagrams must fit into one frame.
+
+use alloc::collections::{ BTreeMap, VecDeque };
+use alloc::vec::Vec;
+use crate::sync::SpinIrqSave;
+
+use crate::drivers::virtio::net::VirtioNet;
+use crate::{ VmmError, VmmResult };
+
+pub type Ipv4Addr = [u8; 4];
+pub type MacAddr = [u8; 6];
+
+const BROADCAST_MAC: MacAddr = [0xff; 6];
+
+const ETHERTYPE_IPV4: u16 = 0x0800;
+const ETHERTYPE_ARP: u16 = 0x0806;
+const ETH_HEADER_LEN: usize = 14;
+
+const ARP_REQUEST: u16 = 1;
+const ARP_REPLY: u16 = 2;
+const ARP_PACKET_LEN: usize = 28;
+
+const IP_PROTO_UDP: u8 = 17;
+const IPV4_HEADER_LEN: usize = 20;
+const UDP_HEADER_LEN: usize = 8;
+/// largest udp payload fitting into one ethernet frame
+pub const UDP_MAX_PAYLOAD: usize = 1500 - IPV4_HEADER_LEN - UDP_HEADER_LEN;
+
+/// frames polled while waiting for an ARP reply
+const ARP_RESOLVE_POLLS: usize = 100_000;
+/// datagrams queued per bound port
+const SOCKET_QUEUE_LEN: usize = 32;
+
+#[derive(Debug)]
+pub struct Datagram {
+    pub src_ip: Ipv4Addr,
+    pub src_port: u16,
+    pub payload: Vec<u8>
+}
+
+pub struct NetStack {
+    nic: VirtioNet,
+    ip: Ipv4Addr,
+    arp_cache: BTreeMap<Ipv4Addr, MacAddr>,
+    /// received datagrams by bound local port
+    sockets: BTreeMap<u16, VecDeque<Datagram>>
+}
+
+static NET: SpinIrqSave<Option<NetStack>> = SpinIrqSave::new(None);
+
+/// install the network stack of the hypervisor
+pub fn init(nic: VirtioNet, ip: Ipv4Addr) {
+    hdebug!("hypervisor ip {}.{}.{}.{}", ip[0], ip[1], ip[2], ip[3]);
+    *NET.lock() = Some(NetStack { nic, ip, arp_cache: BTreeMap::new(), sockets: BTreeMap::new() });
+}
+
+/// run `f` on the network stack, `None` if there is no NIC or the stack is busy
+/// (e.g. a message is logged from inside the stack)
+pub fn with_stack<T>(f: impl FnOnce(&mut NetStack) -> T) -> Option<T> {
+    let mut net = NET.try_lock()?;
+    net.as_mut().map(f)
+}
+
+/// `a.b.c.d`
+pub fn parse_ipv4(s: &str) -> Option<Ipv4Addr> {
+    let mut ip = [0u8; 4];
+    let mut parts = s.split('.');
+    for byte in ip.iter_mut() {
+        *byte = parts.next()?.parse().ok()?;
+    }
+    if parts.next().is_some() {
+        return None
+    }
+    Some(ip)
+}
+
+/// `a.b.c.d:port`
+pub fn parse_endpoint(s: &str) -> Option<(Ipv4Addr, u16)> {
+    let (ip, port) = s.split_once(':')?;
+    Some((parse_ipv4(ip)?, port.parse().ok()?))
+}
+
+fn read_u16(buf: &[u8], offset: usize) -> u16 {
+    u16::from_be_bytes([buf[offset], buf[offset + 1]])
+}
+
+fn ipv4_checksum(header: &[u8]) -> u16 {
+    let mut sum: u32 = header.chunks(2).map(|w| u16::from_be_bytes([w[0], w[1]]) as u32).sum();
+    while sum >> 16 != 0 {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+impl NetStack {
+    pub fn ip(&self) -> Ipv4Addr {
+        self.ip
+    }
+
+    pub fn mac(&self) -> MacAddr {
+        self.nic.mac()
+    }
+
+    pub fn ack_interrupt(&self) {
+        self.nic.ack_interrupt();
+    }
+
+    /// receive datagrams on `port`
+    pub fn bind(&mut self, port: u16) -> VmmResult {
+        if self.sockets.contains_key(&port) {
+            return Err(VmmError::InvalidState)
+        }
+        self.sockets.insert(port, VecDeque::new());
+        Ok(())
+    }
+
+    pub fn unbind(&mut self, port: u16) {
+        self.sockets.remove(&port);
+    }
+
+    /// next datagram received on a bound `port`
+    pub fn recv_from(&mut self, port: u16) -> Option<Datagram> {
+        self.poll();
+        self.sockets.get_mut(&port)?.pop_front()
+    }
+
+    /// process all received frames
+    pub fn poll(&mut self) {
+        while let Some(frame) = self.nic.recv() {
+            self.handle_frame(&frame);
+        }
+    }
+
+    fn send_frame(&mut self, dst: MacAddr, ethertype: u16, payload: &[u8]) -> VmmResult {
+        let mut frame = Vec::with_capacity(ETH_HEADER_LEN + payload.len());
+        frame.extend_from_slice(&dst);
+        frame.extend_from_slice(&self.nic.mac());
+        frame.extend_from_slice(&ethertype.to_be_bytes());
+        frame.extend_from_slice(payload);
+        self.nic.send(&frame)
+    }
+
+    fn send_arp(&mut self, op: u16, target_mac: MacAddr, target_ip: Ipv4Addr) -> VmmResult {
+        let mut arp = Vec::with_capacity(ARP_PACKET_LEN);
+        // ethernet / IPv4
+        arp.extend_from_slice(&[0x00, 0x01, 0x08, 0x00, 6, 4]);
+        arp.extend_from_slice(&op.to_be_bytes());
+        arp.extend_from_slice(&self.nic.mac());
+        arp.extend_from_slice(&self.ip);
+        arp.extend_from_slice(&target_mac);
+        arp.extend_from_slice(&target_ip);
+        let dst = if op == ARP_REQUEST { BROADCAST_MAC } else { target_mac };
+        self.send_frame(dst, ETHERTYPE_ARP, &arp)
+    }
+
+    fn handle_frame(&mut self, frame: &[u8]) {
+        if frame.len() < ETH_HEADER_LEN {
+            return
+        }
+        let payload = &frame[ETH_HEADER_LEN..];
+        match read_u16(frame, 12) {
+            ETHERTYPE_ARP => self.handle_arp(payload),
+            ETHERTYPE_IPV4 => self.handle_ipv4(payload),
+            _ => {}
+        }
+    }
+
+    fn handle_arp(&mut self, arp: &[u8]) {
+        if arp.len() < ARP_PACKET_LEN {
+            return
+        }
+        let mut sender_mac = [0u8; 6];
+        let mut sender_ip = [0u8; 4];
+        sender_mac.copy_from_slice(&arp[8..14]);
+        sender_ip.copy_from_slice(&arp[14..18]);
+        let target_ip = &arp[24..28];
+        self.arp_cache.insert(sender_ip, sender_mac);
+        if read_u16(arp, 6) == ARP_REQUEST && target_ip == self.ip {
+            let _ = self.send_arp(ARP_REPLY, sender_mac, sender_ip);
+        }
+    }
+
+    fn handle_ipv4(&mut self, packet: &[u8]) {
+        if packet.len() < IPV4_HEADER_LEN || packet[0] >> 4 != 4 {
+            return
+        }
+        let header_len = ((packet[0] & 0xf) as usize) * 4;
+        let total_len = read_u16(packet, 2) as usize;
+        if header_len < IPV4_HEADER_LEN || total_len > packet.len() || total_len < header_len
+            || packet[9] != IP_PROTO_UDP || &packet[16..20] != self.ip {
+            return
+        }
+        let udp = &packet[header_len..total_len];
+        if udp.len() < UDP_HEADER_LEN {
+            return
+        }
+        let src_port = read_u16(udp, 0);
+        let dst_port = read_u16(udp, 2);
+        let udp_len = (read_u16(udp, 4) as usize).min(udp.len());
+        if let Some(queue) = self.sockets.get_mut(&dst_port) {
+            if queue.len() < SOCKET_QUEUE_LEN && udp_len >= UDP_HEADER_LEN {
+                let mut src_ip = [0u8; 4];
+                src_ip.copy_from_slice(&packet[12..16]);
+                queue.push_back(Datagram { src_ip, src_port, payload: udp[UDP_HEADER_LEN..udp_len].to_vec() });
+            }
+        }
+    }
+
+    /// mac address of a peer on the local link
+    fn resolve(&mut self, ip: Ipv4Addr) -> VmmResult<MacAddr> {
+        if ip == [255; 4] {
+            return Ok(BROADCAST_MAC)
+        }
+        if let Some(&mac) = self.arp_cache.get(&ip) {
+            return Ok(mac)
+        }
+        self.send_arp(ARP_REQUEST, [0; 6], ip)?;
+        for _ in 0..ARP_RESOLVE_POLLS {
+            self.poll();
+            if let Some(&mac) = self.arp_cache.get(&ip) {
+                return Ok(mac)
+            }
+        }
+        Err(VmmError::NoFound)
+    }
+
+    pub fn send_to(&mut self, src_port: u16, dst_ip: Ipv4Addr, dst_port: u16, payload: &[u8]) -> VmmResult {
+        if payload.len() > UDP_MAX_PAYLOAD {
+            return Err(VmmError::NotSupported)
+        }
+        let dst_mac = self.resolve(dst_ip)?;
+        let udp_len = UDP_HEADER_LEN + payload.len();
+        let total_len = IPV4_HEADER_LEN + udp_len;
+        let mut packet = Vec::with_capacity(total_len);
+        // version 4, 20 byte header, no options
+        packet.extend_from_slice(&[0x45, 0]);
+        packet.extend_from_slice(&(total_len as u16).to_be_bytes());
+        // identification, don't fragment
+        packet.extend_from_slice(&[0, 0, 0x40, 0]);
+        // ttl, protocol, checksum placeholder
+        packet.extend_from_slice(&[64, IP_PROTO_UDP, 0, 0]);
+        packet.extend_from_slice(&self.ip);
+        packet.extend_from_slice(&dst_ip);
+        let checksum = ipv4_checksum(&packet[..IPV4_HEADER_LEN]);
+        packet[10..12].copy_from_slice(&checksum.to_be_bytes());
+        packet.extend_from_slice(&src_port.to_be_bytes());
+        packet.extend_from_slice(&dst_port.to_be_bytes());
+        packet.extend_from_slice(&(udp_len as u16).to_be_bytes());
+        // udp checksum is optional over IPv4
+        packet.extend_from_slice(&[0, 0]);
+        packet.extend_from_slice(payload);
+        self.send_frame(dst_mac, ETHERTYPE_IPV4, &packet)
+    }
+}
@@ -1,15 +1,16 @@
 mod address;
 mod pte;
 mod sv39;
+mod walk;
 
 use alloc::vec::Vec;
 
 pub use pte::{ PTEFlags, PageTableEntry };
 pub use address::{ PhysPageNum, VirtPageNum, PhysAddr, VirtAddr, StepByOne, VPNRange, PPNRange };
 pub use sv39::PageTableSv39;
+pub use walk::{ AccessType, Privilege, WalkContext, WalkFault, GuestTranslation, walk_guest };
 
-use crate::guest::page_table::GuestPageTable;
-use crate::guest::pmap::gpa2hpa;
+use crate::VmmResult;
 
 #[derive(Debug, Copy, Clone, PartialEq, Eq)]
 pub enum PageTableLevel {
@@ -31,26 +32,21 @@ pub struct PageWalk {
     pub pa: usize
 }
 
-#[derive(Debug)]
-pub struct AddressTranslation {
-    pub pte: PageTableEntry,
-    pub pte_addr: usize,
-    pub guest_pa: usize,
-    pub level: PageTableLevel,
-    pub page_walk: PageWalk
-}
-
-
-
 pub trait PageTable: Clone {
     /// build new bare page table
     fn new() -> Self;
     /// build page table from
     fn from_token(satp: usize) -> Self;
-    /// map virt page into phys page
-    fn map(&mut self, vpn: VirtPageNum, ppn: PhysPageNum, flags: PTEFlags);
+    /// map virt page into phys page, panics if no frame is left for the page table
+    fn map(&mut self, vpn: VirtPageNum, ppn: PhysPageNum, flags: PTEFlags) {
+        self.try_map(vpn, ppn, flags).expect("out of frames for page table");
+    }
+    /// map virt page into phys page, fails if no frame is left for the page table
+    fn try_map(&mut self, vpn: VirtPageNum, ppn: PhysPageNum, flags: PTEFlags) -> VmmResult;
     /// unmap virt page
     fn unmap(&mut self, vpn: VirtPageNum);
+    /// replace the flags of a mapped page, returns false if it is not mapped
+    fn set_flags(&mut self, vpn: VirtPageNum, flags: PTEFlags) -> bool;
     /// page walk and renturn all walked ptes
     fn walk_page_table<R: Fn(usize) -> usize>(root: usize, va: usize, read_pte: R) -> Option<PageWalk>;
     /// translate virt page into physical page
@@ -59,20 +55,6 @@ pub trait PageTable: Clone {
     fn translate_va(&self, va: usize) -> Option<usize>;
     /// get page table root token
     fn token(&self) -> usize;
+    /// frames of the page table itself, root and intermediate tables
+    fn frame_count(&self) -> usize;
 }
-
-pub fn translate_guest_va<P: GuestPageTable>(guest_id: usize, root: usize, guest_va: usize) -> Option<AddressTranslation> {
-    P::walk_page_table(root, guest_va, |va| {
-        let pa = gpa2hpa(va, guest_id);
-        // htracking!("pa: {:#x}", pa);
-        unsafe{ core::ptr::read(pa as *const usize) }
-    }).map(|t| {
-        AddressTranslation { 
-            pte: t.path[t.path.len() - 1].pte,
-            pte_addr: t.path[t.path.len() - 1].addr,
-            level: t.path[t.path.len() - 1].level,
-            guest_pa: t.pa,
-            page_walk: t
-        }
-    })
-}
\ No newline at end of file
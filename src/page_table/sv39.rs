@@ -1,5 +1,6 @@
 use crate::guest::page_table::GuestPageTable;
 use crate::hyp_alloc::{ FrameTracker, frame_alloc };
+use crate::{ VmmError, VmmResult };
 
 use super::{ PhysPageNum, VirtPageNum, PageTable, PageTableLevel, PTEFlags, PageTableEntry, PteWrapper, PageWalk };
 
@@ -42,7 +43,7 @@ impl PageTableSv39 {
                 break;
             }
             if !pte.is_valid() {
-                let frame = frame_alloc().unwrap();
+                let frame = frame_alloc()?;
                 *pte = PageTableEntry::new(frame.ppn, PTEFlags::V);
                 self.frames.push(frame);
             }
@@ -57,24 +58,24 @@ impl PageTableSv39 {
 impl GuestPageTable for PageTableSv39 {
     /// 新建 guest 根目录页表,需要分配 16 KiB 的内存
     /// 并且 16 KiB 内存对齐
-    fn new_guest() -> Self {
+    fn try_new_guest() -> VmmResult<Self> {
         let mut frames = vec![];
-        let mut root_frame = frame_alloc().unwrap();
+        let mut root_frame = frame_alloc().ok_or(VmmError::OutOfMemory)?;
         while root_frame.ppn.0 & 0x3 != 0 {
             hdebug!("page {:#x} was allocated, but is does not follow 16KiB buundary.", root_frame.ppn.0);
             frames.push(root_frame);
-            root_frame = frame_alloc().unwrap();
+            root_frame = frame_alloc().ok_or(VmmError::OutOfMemory)?;
         }
         hdebug!("Guest root page table: {:#x}", root_frame.ppn.0);
         let root_ppn = root_frame.ppn;
         frames.push(root_frame);
         for _ in 0..3 {
-            frames.push(frame_alloc().unwrap());
+            frames.push(frame_alloc().ok_or(VmmError::OutOfMemory)?);
         }
-        Self {
+        Ok(Self {
             root_ppn: root_ppn,
             frames
-        }
+        })
     }
 }
 
@@ -98,11 +99,15 @@ impl PageTable for PageTableSv39 {
         8usize << 60 | self.root_ppn.0
     }
 
-    #[allow(unused)]
-    fn map(&mut self, vpn: VirtPageNum, ppn: PhysPageNum, flags: PTEFlags) {
-        let pte = self.find_pte_create(vpn).unwrap();
+    fn frame_count(&self) -> usize {
+        self.frames.len()
+    }
+
+    fn try_map(&mut self, vpn: VirtPageNum, ppn: PhysPageNum, flags: PTEFlags) -> VmmResult {
+        let pte = self.find_pte_create(vpn).ok_or(VmmError::OutOfMemory)?;
         assert!(!pte.is_valid(), "vpn {:?} is mapped before mapping", vpn);
         *pte = PageTableEntry::new(ppn, flags | PTEFlags::V);
+        Ok(())
     }
     
     #[allow(unused)]
@@ -112,6 +117,16 @@ impl PageTable for PageTableSv39 {
         *pte = PageTableEntry::empty();
     }
 
+    fn set_flags(&mut self, vpn: VirtPageNum, flags: PTEFlags) -> bool {
+        match self.find_pte(vpn) {
+            Some(pte) if pte.is_valid() => {
+                *pte = PageTableEntry::new(pte.ppn(), flags | PTEFlags::V);
+                true
+            },
+            _ => false
+        }
+    }
+
     fn translate(&self, vpn: VirtPageNum) -> Option<PageTableEntry> {
         self.find_pte(vpn).map(|pte| *pte)
     }
@@ -0,0 +1,169 @@
+//! Software two-stage walk of guest virtual addresses
+//!
+//! Follows the translation algorithm of the privileged spec for a guest running Sv39 or
+//! Sv48 under Sv39x4: every VS-stage page table access is itself a guest physical read
+//! translated by stage 2, leaves may be superpages at any level, and both stages check
+//! permissions for the access type and privilege. Accessed and dirty bits are never
+//! written, a leaf that would need an update faults like on hardware without Svadu.
+//! With `vsatp` Bare, as during early boot, the guest virtual address is the guest
+//! physical one and only stage 2 applies.
+//!
+//! The result matches what `hlv`/`hlvx`/`hsv` would do for the same access, a failure
+//! tells which stage faulted so the caller can report it to the right party.
+//!
+//! The walk itself reads no CSRs and no memory: guest state comes in a `WalkContext` and
+//! page tables are read through the caller's closures, so synthetic tables work as well:
+//! `hosted-test` builds it for the host and checks it against such tables.
+
+use super::PageTableEntry;
+
+/// VS-stage modes of `vsatp`
+const SATP_MODE_BARE: usize = 0;
+const SATP_MODE_SV39: usize = 8;
+const SATP_MODE_SV48: usize = 9;
+
+/// `vsstatus` bits that change VS-stage permission checks
+const SSTATUS_SUM: usize = 1 << 18;
+const SSTATUS_MXR: usize = 1 << 19;
+/// `hstatus.SPVP`, privilege of the guest when it trapped
+const HSTATUS_SPVP: usize = 1 << 8;
+
+/// PTE bits 60:54 are reserved, N and PBMT are left to the hardware
+const PTE_RESERVED: usize = 0x7f << 54;
+const PTE_PPN_MASK: usize = (1 << 44) - 1;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AccessType {
+    Read,
+    Write,
+    Execute
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Privilege {
+    User,
+    Supervisor
+}
+
+/// guest state a walk depends on
+#[derive(Clone, Copy, Debug)]
+pub struct WalkContext {
+    pub vsatp: usize,
+    /// privilege the access is made with
+    pub privilege: Privilege,
+    /// `vsstatus.SUM`, supervisor accesses to user pages
+    pub sum: bool,
+    /// `vsstatus.MXR`, loads from execute-only pages
+    pub mxr: bool
+}
+
+impl WalkContext {
+    /// guest state from the raw values of its csrs
+    pub fn from_csrs(vsatp: usize, vsstatus: usize, hstatus: usize) -> Self {
+        let privilege = if hstatus & HSTATUS_SPVP != 0 { Privilege::Supervisor } else { Privilege::User };
+        Self { vsatp, privilege, sum: vsstatus & SSTATUS_SUM != 0, mxr: vsstatus & SSTATUS_MXR != 0 }
+    }
+
+    /// state of the running guest at its last trap, for accesses made on its behalf
+    #[cfg(target_arch = "riscv64")]
+    pub fn current() -> Self {
+        let (vsatp, vsstatus, hstatus): (usize, usize, usize);
+        unsafe{
+            core::arch::asm!("csrr {}, vsatp", out(reg) vsatp);
+            core::arch::asm!("csrr {}, vsstatus", out(reg) vsstatus);
+            core::arch::asm!("csrr {}, hstatus", out(reg) hstatus);
+        }
+        Self::from_csrs(vsatp, vsstatus, hstatus)
+    }
+
+    /// same guest state for an access with another privilege
+    pub fn with_privilege(self, privilege: Privilege) -> Self {
+        Self { privilege, ..self }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WalkFault {
+    /// VS-stage page fault, belongs to the guest
+    PageFault,
+    /// stage-2 fault on `guest_pa`, `implicit` when the VS-stage walk itself faulted
+    GuestPageFault { guest_pa: usize, implicit: bool }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct GuestTranslation {
+    pub guest_pa: usize,
+    pub host_pa: usize,
+    /// VS-stage leaf, `None` in Bare mode
+    pub pte: Option<PageTableEntry>,
+    /// level of the leaf, 0 for a 4 KiB page, 1 for 2 MiB, 2 for 1 GiB, 3 for 512 GiB
+    pub level: usize
+}
+
+/// whether a VS-stage leaf allows `access` with the permissions of `walk`
+fn leaf_allows(pte: &PageTableEntry, access: AccessType, walk: &WalkContext) -> bool {
+    let privileged = match walk.privilege {
+        Privilege::User => pte.is_user(),
+        // supervisor never executes user pages, reads and writes them only with SUM
+        Privilege::Supervisor => !pte.is_user() || (walk.sum && access != AccessType::Execute)
+    };
+    let permitted = match access {
+        AccessType::Read => pte.readable() || (walk.mxr && pte.executable()),
+        AccessType::Write => pte.writable(),
+        AccessType::Execute => pte.executable()
+    };
+    privileged && permitted && pte.accessed() && (access != AccessType::Write || pte.dirty())
+}
+
+/// translate `guest_va` for `access`, `stage2` translates a guest physical address
+/// for an access type into a host physical address or fails with a guest page fault,
+/// `read_pte` reads a VS-stage page table entry at the host physical address it returned
+pub fn walk_guest<S: Fn(usize, AccessType) -> Option<usize>, R: Fn(usize) -> usize>(
+    guest_va: usize, access: AccessType, walk: &WalkContext, stage2: S, read_pte: R
+) -> Result<GuestTranslation, WalkFault> {
+    let final_access = |guest_pa: usize, pte: Option<PageTableEntry>, level: usize| {
+        stage2(guest_pa, access)
+            .map(|host_pa| GuestTranslation { guest_pa, host_pa, pte, level })
+            .ok_or(WalkFault::GuestPageFault { guest_pa, implicit: false })
+    };
+    let levels = match walk.vsatp >> 60 {
+        // bits above the guest physical width fault in stage 2, they are not truncated
+        SATP_MODE_BARE => return final_access(guest_va, None, 0),
+        SATP_MODE_SV39 => 3,
+        SATP_MODE_SV48 => 4,
+        _ => return Err(WalkFault::PageFault)
+    };
+    // bits above the virtual address width must all equal its top bit
+    let va_bits = 12 + 9 * levels;
+    let top = (guest_va as isize) >> (va_bits - 1);
+    if top != 0 && top != -1 {
+        return Err(WalkFault::PageFault)
+    }
+    let mut table = (walk.vsatp & PTE_PPN_MASK) << 12;
+    for level in (0..levels).rev() {
+        let index = (guest_va >> (12 + 9 * level)) & 0x1ff;
+        let pte_gpa = table + index * 8;
+        let pte_hpa = stage2(pte_gpa, AccessType::Read)
+            .ok_or(WalkFault::GuestPageFault { guest_pa: pte_gpa, implicit: true })?;
+        let pte = PageTableEntry { bits: read_pte(pte_hpa) };
+        if !pte.is_valid() || (pte.writable() && !pte.readable()) || pte.bits & PTE_RESERVED != 0 {
+            return Err(WalkFault::PageFault)
+        }
+        let ppn = pte.bits >> 10 & PTE_PPN_MASK;
+        if !pte.readable() && !pte.executable() {
+            // a pointer to the next level, A, D and U are reserved here
+            if level == 0 || pte.is_user() || pte.accessed() || pte.dirty() {
+                return Err(WalkFault::PageFault)
+            }
+            table = ppn << 12;
+            continue;
+        }
+        // superpages must be aligned to their size
+        let offset_mask = (1usize << (12 + 9 * level)) - 1;
+        if (ppn << 12) & offset_mask != 0 || !leaf_allows(&pte, access, walk) {
+            return Err(WalkFault::PageFault)
+        }
+        return final_access((ppn << 12) | (guest_va & offset_mask), Some(pte), level)
+    }
+    unreachable!()
+}
@@ -0,0 +1,126 @@
+//! Hart-local storage
+//!
+//! Variables declared with `per_cpu!` are placed in the `.percpu` section, which is
+//! only a template: every hart gets its own copy of the section and `tp` points to the
+//! copy of the running hart while the hypervisor runs. The trap entry saves the guest's
+//! `tp` and loads the hypervisor's, so hart-local variables can be used from trap
+//! handlers before `HOST_VMM` is locked.
+//!
+//! Until `init` runs on the boot hart, `tp` points to the template itself.
+
+use core::cell::UnsafeCell;
+use core::sync::atomic::{ AtomicBool, Ordering };
+
+use crate::boothart;
+use crate::constants::MAX_HARTS;
+
+/// size of the copy of `.percpu` owned by each hart
+const PERCPU_AREA_SIZE: usize = 0x1000;
+
+#[repr(C, align(64))]
+struct PerCpuArea([u8; PERCPU_AREA_SIZE]);
+
+const EMPTY_AREA: PerCpuArea = PerCpuArea([0; PERCPU_AREA_SIZE]);
+static mut PERCPU_AREAS: [PerCpuArea; MAX_HARTS] = [EMPTY_AREA; MAX_HARTS];
+/// the boot hart copied the template into the areas of all harts
+static AREAS_READY: AtomicBool = AtomicBool::new(false);
+
+extern "C" {
+    fn spercpu();
+    fn epercpu();
+}
+
+/// A hart-local variable, declared with `per_cpu!`
+#[repr(transparent)]
+pub struct PerCpu<T>(UnsafeCell<T>);
+
+unsafe impl<T> Sync for PerCpu<T> {}
+
+impl<T> PerCpu<T> {
+    pub const fn new(value: T) -> Self {
+        Self(UnsafeCell::new(value))
+    }
+
+    fn offset(&self) -> usize {
+        self.0.get() as usize - spercpu as usize
+    }
+
+    /// copy of the running hart
+    pub fn as_ptr(&self) -> *mut T {
+        let tp: usize;
+        unsafe{ core::arch::asm!("mv {}, tp", out(reg) tp) };
+        (tp + self.offset()) as *mut T
+    }
+
+    /// copy of `hart`, e.g. to sum per-hart counters
+    pub fn remote_ptr(&self, hart: usize) -> *mut T {
+        assert!(hart < MAX_HARTS);
+        unsafe{ (PERCPU_AREAS[hart].0.as_ptr() as usize + self.offset()) as *mut T }
+    }
+
+    /// The caller makes sure that no other reference to the copy of the running hart
+    /// is alive, including one held by a trap handler which interrupted the caller.
+    pub unsafe fn as_mut(&self) -> &mut T {
+        &mut *self.as_ptr()
+    }
+}
+
+impl<T: Copy> PerCpu<T> {
+    pub fn get(&self) -> T {
+        unsafe{ core::ptr::read_volatile(self.as_ptr()) }
+    }
+
+    pub fn set(&self, value: T) {
+        unsafe{ core::ptr::write_volatile(self.as_ptr(), value) }
+    }
+}
+
+/// declare hart-local variables
+///
+/// ```ignore
+/// per_cpu! {
+///     /// exits handled by this hart
+///     pub static EXITS: usize = 0;
+/// }
+/// EXITS.set(EXITS.get() + 1);
+/// ```
+#[macro_export]
+macro_rules! per_cpu {
+    ($( $(#[$attr: meta])* $vis: vis static $name: ident: $ty: ty = $init: expr; )+) => {
+        $(
+            $(#[$attr])*
+            #[link_section = ".percpu"]
+            $vis static $name: $crate::percpu::PerCpu<$ty> = $crate::percpu::PerCpu::new($init);
+        )+
+    };
+}
+
+per_cpu! {
+    /// id of the physical hart
+    static HART_ID: usize = 0;
+}
+
+/// point `tp` to the hart-local area of `hart_id`. The boot hart first copies the template
+/// into the areas of all harts, other harts must wait for it.
+pub fn init(hart_id: usize) {
+    let template_size = epercpu as usize - spercpu as usize;
+    assert!(template_size <= PERCPU_AREA_SIZE, "per-cpu template of {:#x} bytes too large", template_size);
+    assert!(hart_id < MAX_HARTS, "hart {} beyond MAX_HARTS", hart_id);
+    if hart_id == boothart::boot_hart() {
+        for area in unsafe{ PERCPU_AREAS.iter_mut() } {
+            unsafe{ core::ptr::copy_nonoverlapping(spercpu as usize as *const u8, area.0.as_mut_ptr(), template_size) };
+        }
+        AREAS_READY.store(true, Ordering::Release);
+    }
+    while !AREAS_READY.load(Ordering::Acquire) {
+        core::hint::spin_loop();
+    }
+    let base = unsafe{ PERCPU_AREAS[hart_id].0.as_ptr() as usize };
+    unsafe{ core::arch::asm!("mv tp, {}", in(reg) base) };
+    HART_ID.set(hart_id);
+}
+
+/// id of the physical hart running the caller
+pub fn hart_id() -> usize {
+    HART_ID.get()
+}
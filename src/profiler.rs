@@ -0,0 +1,253 @@
+//! Sampling profiler of the hypervisor itself
+//!
+//! Guest traps are normally handled with interrupts masked. While the profiler runs,
+//! `enter_hypervisor` unmasks them for the rest of the trap and arms the physical timer
+//! for the next sample as well. A sample is taken in `trap_from_kernel`: the interrupted
+//! pc and the return addresses found by walking the frame pointers, `SAMPLE_DEPTH` frames
+//! at most. Other interrupts arriving meanwhile are masked and stay pending until the
+//! guest is entered again, where they trap as usual. Samples are spaced by time spent in
+//! the hypervisor, so the rate is per second of hypervisor time. The SBI fast path and
+//! the idle loop are not sampled.
+//!
+//! Started with `hvc.profile=<hz>`, the monitor `profile start <hz>` or by a guest with
+//! `HYPERCALL_PROFILE_FID`, around a benchmark for instance. The monitor `profile` command
+//! prints a flat profile, the pcs with the most samples, `profile folded` one line per
+//! distinct stack, root first, as read by flamegraph tools.
+
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+use core::fmt::Write;
+use core::sync::atomic::{ AtomicUsize, Ordering };
+use riscv::register::{ sstatus, time };
+
+use crate::arch::{ self, Irq };
+use crate::constants::{ CLOCK_FREQ, KERNEL_STACK_SIZE };
+use crate::guest::fastpath;
+use crate::guest::vmexit::TrapContext;
+use crate::hypervisor::fdt::MachineMeta;
+use crate::sbi::set_timer;
+use crate::sync::with_irq_masked;
+use crate::{ VmmError, VmmResult };
+
+/// frames recorded per sample, the interrupted pc first
+pub const SAMPLE_DEPTH: usize = 8;
+/// highest sample rate, a sample costs about as much as a trap
+pub const MAX_RATE: usize = 10_000;
+/// samples kept, later ones are only counted
+const MAX_SAMPLES: usize = 4096;
+/// lines of the flat profile
+const FLAT_LINES: usize = 30;
+/// `s0`, the frame pointer
+const FP: usize = 8;
+
+/// `sie` bits masked while sampling
+const MASKED_SSOFT: usize = 1 << 1;
+const MASKED_SEXT: usize = 1 << 9;
+const MASKED_LCOFI: usize = 1 << 13;
+
+/// sample rate in Hz, 0 if the profiler is stopped
+static RATE: AtomicUsize = AtomicUsize::new(0);
+/// samples taken since the profiler was started, may exceed `MAX_SAMPLES`
+static SAMPLE_COUNT: AtomicUsize = AtomicUsize::new(0);
+static mut SAMPLES: [[usize; SAMPLE_DEPTH]; MAX_SAMPLES] = [[0; SAMPLE_DEPTH]; MAX_SAMPLES];
+
+per_cpu! {
+    /// the hart handles a guest trap with interrupts unmasked
+    static SAMPLING: bool = false;
+    /// time of the next sample while sampling
+    static NEXT_SAMPLE: usize = usize::MAX;
+    /// hypervisor time left until the next sample, kept while a guest runs
+    static REMAINING: usize = 0;
+    /// `MASKED_*` bits of interrupts left pending until the guest is entered
+    static MASKED: usize = 0;
+}
+
+/// start the profiler at `hvc.profile=<hz>`
+pub fn init(machine: &MachineMeta) {
+    if let Some(rate) = machine.bootarg("hvc.profile") {
+        match rate.parse().map_err(|_| VmmError::NotSupported).and_then(start) {
+            Ok(()) => hdebug!("profiling the hypervisor at {} Hz", rate),
+            Err(_) => hwarning!("invalid hvc.profile, at most {} Hz", MAX_RATE)
+        }
+    }
+}
+
+/// discard earlier samples and sample at `rate` Hz
+pub fn start(rate: usize) -> VmmResult {
+    if rate == 0 || rate > MAX_RATE {
+        return Err(VmmError::NotSupported)
+    }
+    with_irq_masked(|| {
+        SAMPLE_COUNT.store(0, Ordering::Relaxed);
+        RATE.store(rate, Ordering::Relaxed);
+    });
+    Ok(())
+}
+
+/// stop sampling, the samples are kept for `report_to`
+pub fn stop() {
+    RATE.store(0, Ordering::Relaxed);
+}
+
+pub fn samples() -> usize {
+    SAMPLE_COUNT.load(Ordering::Relaxed)
+}
+
+/// the hart samples the trap being handled, interrupts may be unmasked
+pub fn sampling() -> bool {
+    SAMPLING.get()
+}
+
+/// time the physical timer fires at the latest for the profiler
+pub fn next_sample() -> usize {
+    if SAMPLING.get() { NEXT_SAMPLE.get() } else { usize::MAX }
+}
+
+fn period() -> Option<usize> {
+    match RATE.load(Ordering::Relaxed) {
+        0 => None,
+        rate => Some(CLOCK_FREQ / rate)
+    }
+}
+
+/// called once a guest trap is handled by `trap_handler`, unmasks interrupts if the
+/// profiler runs
+pub fn enter_hypervisor() {
+    let period = match period() {
+        Some(period) => period,
+        None => return
+    };
+    let remaining = match REMAINING.get() {
+        remaining if remaining == 0 || remaining > period => period,
+        remaining => remaining
+    };
+    NEXT_SAMPLE.set(time::read() + remaining);
+    SAMPLING.set(true);
+    fastpath::program_timer();
+    unsafe{ sstatus::set_sie() };
+}
+
+/// mask interrupts again before the guest is entered or the hart goes idle
+pub fn leave_hypervisor() {
+    if !SAMPLING.get() {
+        return
+    }
+    unsafe{ sstatus::clear_sie() };
+    SAMPLING.set(false);
+    REMAINING.set(NEXT_SAMPLE.get().saturating_sub(time::read()));
+    let masked = MASKED.get();
+    MASKED.set(0);
+    if masked & MASKED_SSOFT != 0 {
+        arch::unmask_irq(Irq::Software);
+    }
+    if masked & MASKED_SEXT != 0 {
+        arch::unmask_irq(Irq::External);
+    }
+    if masked & MASKED_LCOFI != 0 {
+        arch::unmask_irq(Irq::CounterOverflow);
+    }
+    fastpath::program_timer();
+}
+
+/// interrupt taken by the hypervisor itself, returns false if it is not expected
+pub fn kernel_interrupt(irq: Irq, ctx: &TrapContext) -> bool {
+    if !SAMPLING.get() {
+        return false
+    }
+    match irq {
+        Irq::Timer => {
+            let now = time::read();
+            if now >= NEXT_SAMPLE.get() {
+                record(ctx);
+                NEXT_SAMPLE.set(period().map_or(usize::MAX, |period| now + period));
+            }
+            let deadline = fastpath::timer_deadline();
+            if now >= deadline {
+                // the guest timer or the slice end, handled as a trap once the guest is entered
+                arch::mask_irq(Irq::Timer);
+            }else{
+                set_timer(deadline.min(NEXT_SAMPLE.get()));
+            }
+        },
+        Irq::Software => {
+            MASKED.set(MASKED.get() | MASKED_SSOFT);
+            arch::mask_irq(Irq::Software);
+        },
+        Irq::External => {
+            MASKED.set(MASKED.get() | MASKED_SEXT);
+            arch::mask_irq(Irq::External);
+        },
+        Irq::CounterOverflow => {
+            // counters of the guest overflow while it traps, see `guest::pmu`
+            MASKED.set(MASKED.get() | MASKED_LCOFI);
+            arch::mask_irq(Irq::CounterOverflow);
+        }
+    }
+    true
+}
+
+/// record the pc and the call stack of the interrupted code, `ctx` is on its stack
+fn record(ctx: &TrapContext) {
+    let index = SAMPLE_COUNT.fetch_add(1, Ordering::Relaxed);
+    if index >= MAX_SAMPLES {
+        return
+    }
+    let mut sample = [0; SAMPLE_DEPTH];
+    sample[0] = ctx.sepc;
+    let sp = ctx as *const TrapContext as usize;
+    let mut fp = ctx.x[FP];
+    for frame in sample[1..].iter_mut() {
+        // a frame record is the return address and the caller's frame pointer below fp
+        if fp <= sp || fp > sp + KERNEL_STACK_SIZE || fp % 8 != 0 {
+            break
+        }
+        let (ra, caller_fp) = unsafe{ (*((fp - 8) as *const usize), *((fp - 16) as *const usize)) };
+        *frame = ra;
+        if caller_fp <= fp {
+            break
+        }
+        fp = caller_fp;
+    }
+    unsafe{ SAMPLES[index] = sample };
+}
+
+/// copy of the recorded samples
+fn recorded() -> Vec<[usize; SAMPLE_DEPTH]> {
+    with_irq_masked(|| {
+        let count = samples().min(MAX_SAMPLES);
+        unsafe{ SAMPLES[..count].to_vec() }
+    })
+}
+
+/// flat profile, or the folded stacks with `folded`
+pub fn report_to(out: &mut dyn Write, folded: bool) {
+    let samples = recorded();
+    let _ = writeln!(
+        out, "{} samples at {} Hz, {} dropped",
+        samples.len(), RATE.load(Ordering::Relaxed), self::samples() - samples.len()
+    );
+    if folded {
+        let mut stacks = BTreeMap::new();
+        for sample in samples.iter() {
+            let depth = sample.iter().position(|&pc| pc == 0).unwrap_or(SAMPLE_DEPTH);
+            *stacks.entry(&sample[..depth]).or_insert(0usize) += 1;
+        }
+        for (stack, count) in stacks {
+            for (i, pc) in stack.iter().rev().enumerate() {
+                let _ = write!(out, "{}{:#x}", if i == 0 { "" } else { ";" }, pc);
+            }
+            let _ = writeln!(out, " {}", count);
+        }
+        return
+    }
+    let mut pcs = BTreeMap::new();
+    for sample in samples.iter() {
+        *pcs.entry(sample[0]).or_insert(0usize) += 1;
+    }
+    let mut pcs: Vec<_> = pcs.into_iter().collect();
+    pcs.sort_by(|a, b| b.1.cmp(&a.1));
+    let _ = writeln!(out, "{:>18} {:>8} {:>6}", "pc", "samples", "%");
+    for (pc, count) in pcs.into_iter().take(FLAT_LINES) {
+        let _ = writeln!(out, "{:#18x} {:>8} {:>6}", pc, count, count * 100 / samples.len());
+    }
+}
@@ -9,6 +9,12 @@ pub const SBI_CONSOLE_GETCHAR: usize = 2;
 
 pub mod leagcy {
     pub const SBI_SET_TIMER: usize = 0;
+    pub const SBI_CLEAR_IPI: usize = 3;
+    pub const SBI_SEND_IPI: usize = 4;
+    pub const SBI_REMOTE_FENCE_I: usize = 5;
+    pub const SBI_REMOTE_SFENCE_VMA: usize = 6;
+    pub const SBI_REMOTE_SFENCE_VMA_ASID: usize = 7;
+    pub const SBI_SHUTDOWN: usize = 8;
 }
 
 pub const SBI_SUCCESS: usize = 0;
@@ -18,6 +24,9 @@ pub const SBI_ERR_INAVLID_PARAM: isize = -3;
 pub const SBI_ERR_DENIED: isize = -4;
 pub const SBI_ERR_INVALID_ADDRESS: isize = -5;
 pub const SBI_ERR_ALREADY_AVAILABLE: isize = -6; 
+pub const SBI_ERR_ALREADY_STARTED: isize = -7;
+pub const SBI_ERR_ALREADY_STOPPED: isize = -8;
+pub const SBI_ERR_NO_SHMEM: isize = -9;
 
 pub const SBI_EXTID_BASE: usize = 0x10;
 pub const SBI_GET_SBI_SPEC_VERSION_FID: usize = 0;
@@ -38,6 +47,11 @@ pub const SBI_EXTID_HSM: usize = 0x48534D;
 pub const SBI_HART_START_FID: usize = 0;
 pub const SBI_HART_STOP_FID: usize = 1;
 pub const SBI_HART_STATUS_FID: usize = 2;
+pub const SBI_HART_SUSPEND_FID: usize = 3;
+/// default retentive suspend: the hart resumes after the suspend call, like after `wfi`
+pub const SBI_HSM_SUSPEND_RETENTIVE: usize = 0;
+/// suspend types below this value are retentive
+pub const SBI_HSM_SUSPEND_NON_RETENTIVE: usize = 0x8000_0000;
 
 pub const SBI_EXTID_RFNC: usize = 0x52464E43;
 pub const SBI_REMOTE_FENCE_I_FID: usize = 0;
@@ -48,6 +62,104 @@ pub const SBI_REMOTE_HFENCE_GVMA_VMID_FID: usize = 4;
 pub const SBI_REMOTE_HFENCE_VVMA_FIDL: usize = 5;
 pub const SBI_REMOTE_HFENCE_VVMA_ASID_FID: usize = 6;
 
+pub const SBI_EXTID_SRST: usize = 0x53525354;
+pub const SBI_SYSTEM_RESET_FID: usize = 0;
+pub const SBI_RESET_TYPE_SHUTDOWN: usize = 0;
+pub const SBI_RESET_TYPE_COLD_REBOOT: usize = 1;
+pub const SBI_RESET_TYPE_WARM_REBOOT: usize = 2;
+
+pub const SBI_EXTID_PMU: usize = 0x504D55;
+pub const SBI_PMU_NUM_COUNTERS_FID: usize = 0;
+pub const SBI_PMU_COUNTER_GET_INFO_FID: usize = 1;
+pub const SBI_PMU_COUNTER_CONFIG_MATCHING_FID: usize = 2;
+pub const SBI_PMU_COUNTER_START_FID: usize = 3;
+pub const SBI_PMU_COUNTER_STOP_FID: usize = 4;
+pub const SBI_PMU_COUNTER_FW_READ_FID: usize = 5;
+pub const SBI_PMU_COUNTER_FW_READ_HI_FID: usize = 7;
+/// firmware event type 0xf with the platform specific code 0xffff, `event_data` selects the event
+pub const SBI_PMU_EVENT_FW_PLATFORM: usize = 0xf_ffff;
+
+pub const SBI_EXTID_DBTR: usize = 0x44425452;
+pub const SBI_DBTR_NUM_TRIGGERS_FID: usize = 0;
+pub const SBI_DBTR_SET_SHMEM_FID: usize = 1;
+pub const SBI_DBTR_READ_TRIGGERS_FID: usize = 2;
+pub const SBI_DBTR_INSTALL_TRIGGERS_FID: usize = 3;
+pub const SBI_DBTR_UPDATE_TRIGGERS_FID: usize = 4;
+pub const SBI_DBTR_UNINSTALL_TRIGGERS_FID: usize = 5;
+pub const SBI_DBTR_ENABLE_TRIGGERS_FID: usize = 6;
+pub const SBI_DBTR_DISABLE_TRIGGERS_FID: usize = 7;
+
+pub const SBI_EXTID_DBCN: usize = 0x4442434E;
+pub const SBI_DBCN_WRITE_FID: usize = 0;
+pub const SBI_DBCN_READ_FID: usize = 1;
+pub const SBI_DBCN_WRITE_BYTE_FID: usize = 2;
+
+/// hypocaust-2 hypercalls, located in the SBI firmware specific extension space
+pub const SBI_EXTID_HYPERCALL: usize = 0x0A48_4332;
+/// a0: virtual hart id in the calling guest, a1: statistic index, returns the statistic of the hart
+pub const HYPERCALL_VCPU_STATS_FID: usize = 0;
+/// a0: field index, returns a field of the framebuffer assigned to the calling guest
+pub const HYPERCALL_FRAMEBUFFER_FID: usize = 1;
+/// returns the next device attached to or detached from the calling guest, 0 if none
+pub const HYPERCALL_DEVICE_EVENT_FID: usize = 2;
+/// a0: field index, returns a field identifying the hypervisor
+pub const HYPERCALL_INFO_FID: usize = 3;
+/// a0/a1: guest physical address and length of a secret, a2/a3: guest physical address and
+/// size of a buffer, seals the secret into the buffer and returns the sealed size
+pub const HYPERCALL_SEAL_FID: usize = 4;
+/// a0/a1: a sealed blob, a2/a3: a buffer, unseals the blob into the buffer and returns the
+/// length of the secret
+pub const HYPERCALL_UNSEAL_FID: usize = 5;
+/// a0: validator index, a1: value, returns the value a CSR validator lets through,
+/// `SBI_ERR_DENIED` if it drops it. Only built with the `csr_fuzz` feature
+pub const HYPERCALL_CSR_CHECK_FID: usize = 6;
+/// a0: sample rate in Hz, 0 stops the profiler, returns the samples taken since it was
+/// started. Only built with the `profiler` feature
+pub const HYPERCALL_PROFILE_FID: usize = 7;
+/// a0: guest physical address of a page for posted interrupts, 0 goes back to the PLIC
+/// for all sources, see `guest::posted`
+pub const HYPERCALL_POSTED_IRQ_FID: usize = 8;
+/// a0: guest physical address of the event channel page, a1: upcall interrupt, see
+/// `guest::evtchn`
+pub const HYPERCALL_EVTCHN_INIT_FID: usize = 9;
+/// a0: remote guest id, returns a port offered to the remote guest
+pub const HYPERCALL_EVTCHN_ALLOC_FID: usize = 10;
+/// a0: remote guest id, a1: port offered by it, returns the local port bound to it
+pub const HYPERCALL_EVTCHN_BIND_FID: usize = 11;
+/// a0: port, closes it, the remote end becomes unbound
+pub const HYPERCALL_EVTCHN_CLOSE_FID: usize = 12;
+/// a0: port, notifies the remote end
+pub const HYPERCALL_EVTCHN_SEND_FID: usize = 13;
+/// a0: guest physical address of a page, a1: grantee guest id, a2: `GRANT_READONLY` or 0,
+/// returns a grant reference, see `guest::grant`
+pub const HYPERCALL_GRANT_FID: usize = 14;
+/// a0: grant reference, revokes the grant and unmaps the page from the grantee
+pub const HYPERCALL_GRANT_REVOKE_FID: usize = 15;
+/// a0: granter guest id, a1: grant reference, a2: guest physical address, maps the granted
+/// page there
+pub const HYPERCALL_GRANT_MAP_FID: usize = 16;
+/// a0: granter guest id, a1: grant reference, unmaps the granted page
+pub const HYPERCALL_GRANT_UNMAP_FID: usize = 17;
+/// forces a nested trap in the hypervisor, returns the deepest trap handled. Only built
+/// with the `trap_test` feature, see `nested`
+pub const HYPERCALL_TRAP_TEST_FID: usize = 18;
+/// the running hart gives up the rest of its slice to the other harts of the guest, or to
+/// the other guests if it is alone
+pub const HYPERCALL_YIELD_FID: usize = 19;
+/// a0: virtual hart id, the running hart hands the rest of its slice to that started hart
+pub const HYPERCALL_YIELD_TO_FID: usize = 20;
+/// a0: guest virtual address, compares the software walk of the hypervisor with `hlv.d`
+/// on it, returns 1 if both read the same value or fault the same way. Only built with
+/// the `trap_test` feature, see `nested`
+pub const HYPERCALL_WALK_TEST_FID: usize = 21;
+
+/// `event_data` of `SBI_PMU_EVENT_FW_PLATFORM`: traps of the guest into the hypervisor
+pub const PMU_EVENT_EXITS: usize = 0;
+/// cycles the guest was runnable but not on the cpu
+pub const PMU_EVENT_STEAL_CYCLES: usize = 1;
+/// guest accesses to emulated MMIO
+pub const PMU_EVENT_MMIO_EXITS: usize = 2;
+
 
 #[inline(always)]
 /// general sbi call
@@ -81,9 +193,119 @@ pub fn set_timer(stime: usize) {
     sbi_rt::set_timer(stime as u64);
 }
 
-/// use sbi call to shutdown the kernel
+/// suspend the calling hart until an interrupt enabled in `sie` is pending,
+/// returns the SBI error code
+pub fn hart_suspend_retentive() -> isize {
+    let error: isize;
+    unsafe {
+        asm!(
+            "ecall",
+            inlateout("x10") SBI_HSM_SUSPEND_RETENTIVE => error,
+            inlateout("x11") 0usize => _,
+            in("x12") 0usize,
+            in("x16") SBI_HART_SUSPEND_FID,
+            in("x17") SBI_EXTID_HSM,
+        );
+    }
+    error
+}
+
+/// start physical hart `hart` at `start_addr` with a0 = hart id and a1 = `opaque`,
+/// returns the SBI error code
+pub fn hart_start(hart: usize, start_addr: usize, opaque: usize) -> isize {
+    let error: isize;
+    unsafe {
+        asm!(
+            "ecall",
+            inlateout("x10") hart => error,
+            inlateout("x11") start_addr => _,
+            in("x12") opaque,
+            in("x16") SBI_HART_START_FID,
+            in("x17") SBI_EXTID_HSM,
+        );
+    }
+    error
+}
+
+/// stop the calling hart, only returns the SBI error code on failure
+pub fn hart_stop() -> isize {
+    let error: isize;
+    unsafe {
+        asm!(
+            "ecall",
+            lateout("x10") error,
+            lateout("x11") _,
+            in("x16") SBI_HART_STOP_FID,
+            in("x17") SBI_EXTID_HSM,
+        );
+    }
+    error
+}
+
+/// host SBI PMU call `fid` with `args` in a0..a4, returns the SBI error code and value
+pub fn pmu_call(fid: usize, args: [usize; 5]) -> (isize, usize) {
+    let (error, value): (isize, usize);
+    unsafe {
+        asm!(
+            "ecall",
+            inlateout("x10") args[0] => error,
+            inlateout("x11") args[1] => value,
+            in("x12") args[2],
+            in("x13") args[3],
+            in("x14") args[4],
+            in("x16") fid,
+            in("x17") SBI_EXTID_PMU,
+        );
+    }
+    (error, value)
+}
+
+/// host SBI debug triggers call `fid` with `args` in a0..a2, returns the SBI error code and value
+pub fn dbtr_call(fid: usize, args: [usize; 3]) -> (isize, usize) {
+    let (error, value): (isize, usize);
+    unsafe {
+        asm!(
+            "ecall",
+            inlateout("x10") args[0] => error,
+            inlateout("x11") args[1] => value,
+            in("x12") args[2],
+            in("x16") fid,
+            in("x17") SBI_EXTID_DBTR,
+        );
+    }
+    (error, value)
+}
+
+/// raise a supervisor software interrupt on physical hart `hart`, returns the SBI error code
+pub fn send_ipi(hart: usize) -> isize {
+    let error: isize;
+    unsafe {
+        asm!(
+            "ecall",
+            inlateout("x10") 1usize => error,
+            inlateout("x11") hart => _,
+            in("x16") SBI_SEND_IPI_FID,
+            in("x17") SBI_EXTID_IPI,
+        );
+    }
+    error
+}
+
+/// use sbi call to shutdown the kernel, the register of the board without SBI SRST
 pub fn shutdown() -> ! {
     sbi_rt::system_reset(sbi_rt::Shutdown, sbi_rt::SystemFailure);
+    if let Some((base, action)) = crate::board::platform().poweroff() {
+        unsafe{ core::ptr::write_volatile((base + action.offset) as *mut u32, action.value) };
+    }
+    unreachable!()
+}
+
+/// reboot the machine through SBI, the register of the board without SBI SRST
+pub fn reboot() -> ! {
+    sbi_rt::system_reset(sbi_rt::ColdReboot, sbi_rt::NoReason);
+    if let Some((base, action)) = crate::board::platform().reboot() {
+        unsafe{ core::ptr::write_volatile((base + action.offset) as *mut u32, action.value) };
+    }
     unreachable!()
 }
 
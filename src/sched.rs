@@ -0,0 +1,529 @@
+//! Guest scheduler
+//!
+//! Guests are picked by priority first. Among runnable guests of the highest
+//! priority the one with the smallest pass runs. The policy in the config of each guest
+//! decides how its pass advances: stride scheduling by `consumed cycles * BIG_STRIDE / weight`,
+//! round robin as if the guest had `DEFAULT_WEIGHT`, so round robin guests get equal time
+//! slices in turn whatever their weight.
+//!
+//! A capped guest may only use a share of every `CAP_PERIOD`, whatever its weight. Once
+//! its budget is used up it is skipped like a blocked guest until the next period starts,
+//! the hart idles rather than run it. Periods are aligned to multiples of `CAP_PERIOD`.
+//!
+//! Real-time guests own fixed windows of every major frame (ARINC 653 style).
+//! Inside its window a real-time guest always runs and is never preempted,
+//! outside of it the guest is not scheduled at all. Guests share the one hart the
+//! hypervisor runs them on, a guest with a dedicated hart owns the whole major frame:
+//! the other guests only run while it is blocked. The interrupts of a real-time guest
+//! are claimed ahead, see `device_emu::plic`.
+//!
+//! With no runnable guest the hart idles in `wfi`. `sstatus.SIE` stays clear in HS mode,
+//! `wfi` returns anyway once an interrupt enabled in `sie` is pending and the idle loop
+//! handles it in place of the trap handler. The last guest stays loaded meanwhile.
+//! With `hvc.idle=suspend` a hart idle for `IDLE_SUSPEND_DELAY` is suspended through
+//! SBI HSM instead, a retentive suspend which the firmware may turn into a deeper sleep.
+
+use alloc::vec::Vec;
+use core::sync::atomic::{ AtomicBool, Ordering };
+use riscv::register::time;
+
+use crate::constants::layout::TRAP_CONTEXT;
+use crate::constants::sched::{ BIG_STRIDE, DEFAULT_WEIGHT, RT_MAJOR_FRAME, CAP_PERIOD, IDLE_SUSPEND_DELAY };
+use crate::guest::{ GuestConfig, RtPartition, VCpuStats, read_htimedelta, fastpath, paranoid, page_table::GuestPageTable };
+use crate::guest::vmexit::{ TrapContext, handle_irq };
+use crate::hypervisor::fdt::MachineMeta;
+use crate::hypervisor::HostVmm;
+use crate::page_table::PageTable;
+use crate::console;
+#[cfg(feature = "monitor")]
+use crate::monitor;
+use crate::sbi;
+use crate::hyp_alloc;
+use crate::arch::{ self, Irq };
+use crate::{ VmmError, VmmResult };
+
+/// suspend idle harts through SBI HSM, set by `hvc.idle=suspend`
+static SUSPEND_WHEN_IDLE: AtomicBool = AtomicBool::new(false);
+
+/// frames scrubbed between two checks for a runnable guest
+const IDLE_SCRUB_BATCH: usize = 64;
+
+/// `hvc.idle=wfi|suspend` selects how an idle hart waits, `wfi` by default
+pub fn init_idle(machine: &MachineMeta) {
+    match machine.bootarg("hvc.idle") {
+        Some("suspend") => SUSPEND_WHEN_IDLE.store(true, Ordering::Relaxed),
+        Some("wfi") | None => {},
+        Some(_) => hwarning!("invalid hvc.idle, idle harts wait in wfi")
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SchedPolicy {
+    /// the guest gets the same time slice as the other round robin guests, its weight is ignored
+    RoundRobin,
+    /// cpu share is proportional to guest weight
+    Stride
+}
+
+impl SchedPolicy {
+    /// parse `rr` or `stride`
+    pub fn parse(arg: &str) -> Option<Self> {
+        match arg {
+            "rr" => Some(SchedPolicy::RoundRobin),
+            "stride" => Some(SchedPolicy::Stride),
+            _ => None
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct SchedEntity {
+    pub guest_id: usize,
+    pub policy: SchedPolicy,
+    pub weight: usize,
+    pub priority: usize,
+    /// consumed cycles normalized by weight
+    pub pass: usize,
+    /// total cycles consumed by this guest
+    pub consumed_cycles: usize,
+    pub runnable: bool,
+    /// time window of a real-time guest
+    pub rt: Option<RtPartition>,
+    /// cycles the guest may run per `CAP_PERIOD`, `None` if it is not capped
+    pub budget: Option<usize>,
+    /// start of the period `period_used` is counted in
+    pub period_start: usize,
+    /// cycles run in the current period
+    pub period_used: usize
+}
+
+impl SchedEntity {
+    /// pass advance per consumed cycle
+    fn stride(&self) -> usize {
+        match self.policy {
+            SchedPolicy::Stride => BIG_STRIDE / self.weight,
+            SchedPolicy::RoundRobin => BIG_STRIDE / DEFAULT_WEIGHT
+        }
+    }
+
+    /// whether `now` falls into the window of this real-time guest
+    fn in_rt_window(&self, now: usize) -> bool {
+        self.rt.map_or(false, |rt| {
+            let t = now % RT_MAJOR_FRAME;
+            t >= rt.offset && t < rt.offset + rt.length
+        })
+    }
+
+    /// capped guest that used up its budget of the current period
+    fn throttled(&self) -> bool {
+        self.budget.map_or(false, |budget| self.period_used >= budget)
+    }
+}
+
+/// cycles per `CAP_PERIOD` of a cap in percent
+fn cap_budget(cap: Option<usize>) -> Option<usize> {
+    cap.map(|percent| CAP_PERIOD / 100 * percent)
+}
+
+pub struct Scheduler {
+    /// time slice of a guest in cycles
+    pub time_slice: usize,
+    pub entities: Vec<SchedEntity>,
+    /// guest currently owning the cpu
+    pub current: Option<usize>,
+    /// last time the current guest was charged
+    last_account: usize,
+    /// start time of the current slice
+    slice_start: usize,
+    /// cycles spent without a runnable guest
+    pub idle_time: usize
+}
+
+impl Scheduler {
+    pub const fn new(time_slice: usize) -> Self {
+        Self {
+            time_slice,
+            entities: Vec::new(),
+            current: None,
+            last_account: 0,
+            slice_start: 0,
+            idle_time: 0
+        }
+    }
+
+    /// schedule `guest_id` with `config`, `InvalidState` if the config can not be scheduled
+    pub fn add(&mut self, guest_id: usize, config: &GuestConfig) -> VmmResult {
+        // a heavier guest would advance its pass by 0 and never yield to stride guests
+        if !(1..=BIG_STRIDE).contains(&config.weight) {
+            hwarning!("guest {} has weight {} outside 1..={}", guest_id, config.weight, BIG_STRIDE);
+            return Err(VmmError::InvalidState)
+        }
+        if let Some(rt) = config.rt {
+            if rt.length == 0 || rt.offset > RT_MAJOR_FRAME || rt.length > RT_MAJOR_FRAME - rt.offset {
+                hwarning!("guest {} has invalid rt window", guest_id);
+                return Err(VmmError::InvalidState)
+            }
+            let overlaps = |other: &RtPartition| rt.offset < other.offset + other.length && other.offset < rt.offset + rt.length;
+            if self.entities.iter().filter_map(|e| e.rt.as_ref()).any(overlaps) {
+                hwarning!("guest {} rt window overlaps another rt guest", guest_id);
+                return Err(VmmError::InvalidState)
+            }
+        }
+        if config.rt.is_some() && config.cap.is_some() {
+            hwarning!("guest {} is real-time and capped", guest_id);
+            return Err(VmmError::InvalidState)
+        }
+        // start from the minimal pass so that a new guest can not monopolize the cpu
+        let pass = self.entities.iter().map(|e| e.pass).min().unwrap_or(0);
+        self.entities.push(SchedEntity {
+            guest_id,
+            policy: config.policy,
+            weight: config.weight,
+            priority: config.priority,
+            pass,
+            consumed_cycles: 0,
+            runnable: true,
+            rt: config.rt,
+            budget: cap_budget(config.cap),
+            period_start: 0,
+            period_used: 0
+        });
+        Ok(())
+    }
+
+    pub fn remove(&mut self, guest_id: usize) {
+        self.entities.retain(|e| e.guest_id != guest_id);
+        if self.current == Some(guest_id) {
+            self.current = None;
+        }
+    }
+
+    pub fn entity(&self, guest_id: usize) -> Option<&SchedEntity> {
+        self.entities.iter().find(|e| e.guest_id == guest_id)
+    }
+
+    pub fn entity_mut(&mut self, guest_id: usize) -> Option<&mut SchedEntity> {
+        self.entities.iter_mut().find(|e| e.guest_id == guest_id)
+    }
+
+    pub fn set_runnable(&mut self, guest_id: usize, runnable: bool) {
+        if let Some(entity) = self.entity_mut(guest_id) {
+            entity.runnable = runnable;
+        }
+    }
+
+    /// charge the cycles since the last accounting to the current guest
+    pub fn account(&mut self, now: usize) {
+        let delta = now.saturating_sub(self.last_account);
+        self.last_account = now;
+        self.refill(now);
+        if let Some(entity) = self.current.and_then(|id| self.entity_mut(id)) {
+            entity.consumed_cycles += delta;
+            entity.pass += delta * entity.stride();
+            // only the part of `delta` after the start of the period counts against its budget
+            entity.period_used += delta.min(now - entity.period_start);
+        }
+    }
+
+    /// give capped guests their budget back once a new period started at `now`
+    pub fn refill(&mut self, now: usize) {
+        let period_start = now - now % CAP_PERIOD;
+        for entity in self.entities.iter_mut().filter(|e| e.budget.is_some() && e.period_start != period_start) {
+            entity.period_start = period_start;
+            entity.period_used = 0;
+        }
+    }
+
+    /// cap `guest_id` to `cap` percent of a hart, `None` lifts the cap
+    pub fn set_cap(&mut self, guest_id: usize, cap: Option<usize>) {
+        if let Some(entity) = self.entity_mut(guest_id) {
+            entity.budget = cap_budget(cap);
+        }
+    }
+
+    /// start of the next period if a runnable guest waits for it, `usize::MAX` otherwise
+    fn next_refill(&self) -> usize {
+        self.entities.iter()
+            .filter(|e| e.runnable && e.throttled())
+            .map(|e| e.period_start + CAP_PERIOD)
+            .min()
+            .unwrap_or(usize::MAX)
+    }
+
+    /// real-time guest owning the cpu at `now`
+    fn rt_owner(&self, now: usize) -> Option<usize> {
+        self.entities.iter()
+            .find(|e| e.runnable && e.in_rt_window(now))
+            .map(|e| e.guest_id)
+    }
+
+    /// start of the next real-time window after `now`, `usize::MAX` if there is no rt guest
+    fn next_rt_window(&self, now: usize) -> usize {
+        let frame_start = now - now % RT_MAJOR_FRAME;
+        self.entities.iter()
+            .filter_map(|e| e.rt)
+            .map(|rt| {
+                let start = frame_start + rt.offset;
+                if start > now { start } else { start + RT_MAJOR_FRAME }
+            })
+            .min()
+            .unwrap_or(usize::MAX)
+    }
+
+    /// runnable guests taking part in ordinary (non real-time) scheduling
+    fn fair_entities(&self) -> impl Iterator<Item = &SchedEntity> {
+        self.entities.iter().filter(|e| e.runnable && e.rt.is_none() && !e.throttled())
+    }
+
+    fn top_priority(&self) -> Option<usize> {
+        self.fair_entities().map(|e| e.priority).max()
+    }
+
+    fn runnable_count(&self, priority: usize) -> usize {
+        self.fair_entities().filter(|e| e.priority == priority).count()
+    }
+
+    /// pick the guest to run at `now`, `None` if no guest is runnable
+    pub fn pick_next(&self, now: usize) -> Option<usize> {
+        if let Some(owner) = self.rt_owner(now) {
+            return Some(owner)
+        }
+        let top = self.top_priority()?;
+        // on equal passes the first candidate after the current guest, wrapping around
+        let after_current = |id: usize| self.current.map_or(false, |current| id <= current);
+        self.fair_entities()
+            .filter(|e| e.priority == top)
+            .min_by_key(|e| (e.pass, after_current(e.guest_id), e.guest_id))
+            .map(|e| e.guest_id)
+    }
+
+    /// whether the current guest should give up the cpu
+    pub fn need_resched(&self, now: usize) -> bool {
+        if let Some(owner) = self.rt_owner(now) {
+            // the scheduler never steals the window of a real-time guest
+            return self.current != Some(owner)
+        }
+        let current = match self.current.and_then(|id| self.entity(id)) {
+            Some(current) => current,
+            None => return self.pick_next(now).is_some()
+        };
+        if !current.runnable || current.rt.is_some() || current.throttled() {
+            return true
+        }
+        // preempted by a guest of higher priority
+        if self.top_priority().map_or(false, |top| top > current.priority) {
+            return true
+        }
+        now - self.slice_start >= self.time_slice && self.runnable_count(current.priority) > 1
+    }
+
+    /// end of the current slice, `usize::MAX` if the current guest has no competitor
+    /// and no throttled guest waits for its budget
+    pub fn slice_deadline(&self) -> usize {
+        let current = match self.current.and_then(|id| self.entity(id)) {
+            Some(current) => current,
+            None => return self.next_refill()
+        };
+        if let Some(rt) = current.rt {
+            // a dedicated hart is never taken from its guest
+            if rt.dedicated() {
+                return usize::MAX
+            }
+            // no scheduler tick inside a real-time window, only at its end
+            let frame_start = self.slice_start - self.slice_start % RT_MAJOR_FRAME;
+            return frame_start + rt.offset + rt.length
+        }
+        let slice_end = if self.runnable_count(current.priority) > 1 {
+            self.slice_start + self.time_slice
+        }else{
+            usize::MAX
+        };
+        // the current guest runs out of budget, a throttled one keeps its deadline at the refill
+        let budget_end = match current.budget {
+            Some(budget) if !current.throttled() => self.last_account + (budget - current.period_used),
+            _ => usize::MAX
+        };
+        slice_end.min(budget_end).min(self.next_rt_window(self.slice_start)).min(self.next_refill())
+    }
+
+    /// the current guest gives up the rest of its slice, charged to its pass as if it used
+    /// it. A real-time guest keeps its window.
+    pub fn yield_slice(&mut self, now: usize) {
+        let left = (self.slice_start + self.time_slice).saturating_sub(now);
+        match self.current.and_then(|id| self.entity_mut(id)) {
+            Some(entity) if entity.rt.is_none() => entity.pass += left * entity.stride(),
+            _ => return
+        }
+        self.slice_start = now.saturating_sub(self.time_slice);
+    }
+
+    /// start a new slice for `guest_id`
+    pub fn switch_to(&mut self, guest_id: usize, now: usize) {
+        self.current = Some(guest_id);
+        self.slice_start = now;
+        self.last_account = now;
+    }
+}
+
+impl<P: PageTable, G: GuestPageTable> HostVmm<P, G> {
+    /// charge the current guest and switch to another guest if its slice is over
+    /// or it is no longer runnable. Called at the end of every trap, after the
+    /// trap context of the trapped guest has been updated.
+    pub fn schedule(&mut self) {
+        let now = time::read();
+        self.sched.account(now);
+        let hart_switched = self.schedule_harts(now);
+        if !self.sched.need_resched(now) {
+            // runnable guests may have changed, keep the slice end seen by the fast path up to date
+            if hart_switched || self.timer_deadline() != fastpath::slice_deadline() {
+                self.program_timer();
+            }
+            return
+        }
+        match self.sched.pick_next(now) {
+            Some(next) => {
+                self.switch_guest(next);
+                self.program_timer();
+            },
+            None => self.idle()
+        }
+    }
+
+    /// wait for interrupts until a guest is runnable, then switch to it
+    fn idle(&mut self) {
+        // wakeups are polled from `sip` below, the idle loop is not sampled
+        #[cfg(feature = "profiler")]
+        crate::profiler::leave_hypervisor();
+        let start = time::read();
+        let ctx = unsafe{ (TRAP_CONTEXT as *mut TrapContext).as_mut().unwrap() };
+        let next = loop {
+            // wake up for the next real-time window at the latest
+            self.program_timer();
+            // frames queued by `ScrubPolicy::Lazy` are zeroed before the hart sleeps
+            let scrubbed = hyp_alloc::scrub_pending(IDLE_SCRUB_BATCH);
+            let suspend = SUSPEND_WHEN_IDLE.load(Ordering::Relaxed) && time::read() - start >= IDLE_SUSPEND_DELAY;
+            if scrubbed == 0 && (!suspend || sbi::hart_suspend_retentive() != sbi::SBI_SUCCESS as isize) {
+                arch::wait_for_interrupt();
+            }
+            if arch::irq_raised(Irq::Timer) {
+                self.handle_timer_irq();
+            }
+            if arch::irq_raised(Irq::External) {
+                handle_irq(self, ctx);
+            }
+            if arch::irq_raised(Irq::Software) {
+                self.complete_io();
+            }
+            // the idle time is not charged, budgets only refill
+            self.sched.refill(time::read());
+            if let Some(next) = self.sched.pick_next(time::read()) {
+                break next
+            }
+        };
+        let now = time::read();
+        self.sched.idle_time += now - start;
+        // the idle time is not charged to the guest left loaded
+        if let Some(guest) = self.sched.current.and_then(|id| self.guests.get_mut(id)) {
+            guest.vcpu.last_switch += now - start;
+        }
+        self.switch_guest(next);
+        self.program_timer();
+    }
+
+    /// save the state of the running guest into its `Guest` struct and load `next` into `TRAP_CONTEXT`
+    pub fn switch_guest(&mut self, next: usize) {
+        let now = time::read();
+        if self.sched.current != Some(next) {
+            let ctx = unsafe{ (TRAP_CONTEXT as *mut TrapContext).as_mut().unwrap() };
+            let mut paranoid = false;
+            if let Some(prev) = self.sched.current {
+                let preempted = self.sched.entity(prev).map_or(false, |e| e.runnable);
+                let guest = self.guests.get_mut(prev).unwrap();
+                guest.save_state(ctx);
+                guest.vcpu.stats.run_time += now.saturating_sub(guest.vcpu.last_switch);
+                if preempted {
+                    guest.vcpu.stats.preemptions += 1;
+                }
+                guest.vcpu.last_switch = now;
+                paranoid = guest.config.paranoid_switch;
+            }
+            if paranoid {
+                paranoid::flush_hart();
+            }
+            let guest = self.guests.get_mut(next).unwrap();
+            guest.restore_state(ctx);
+            guest.vcpu.stats.wait_time += now.saturating_sub(guest.vcpu.last_switch);
+            guest.vcpu.last_switch = now;
+            guest.vcpu.slice_start = now;
+            self.guest_id = next;
+            // interrupts that arrived while the guest was descheduled
+            self.deliver_pending_irq();
+            if paranoid {
+                paranoid::pad_switch(now);
+            }
+        }
+        self.sched.switch_to(next, now);
+    }
+
+    /// cap `guest_id` to `cap` percent of a hart from now on, `None` lifts the cap
+    pub fn set_guest_cap(&mut self, guest_id: usize, cap: Option<usize>) -> VmmResult {
+        let guest = self.guests.get_mut(guest_id).ok_or(VmmError::NoFound)?;
+        if cap.map_or(false, |percent| !(1..=100).contains(&percent)) || guest.config.rt.is_some() {
+            return Err(VmmError::NotSupported)
+        }
+        guest.config.cap = cap;
+        self.sched.set_cap(guest_id, cap);
+        self.program_timer();
+        Ok(())
+    }
+
+    /// scheduling statistics of the virtual hart `hart` of a guest, including the current
+    /// slice of the hart on the cpu
+    pub fn hart_stats(&self, guest_id: usize, hart: usize) -> Option<VCpuStats> {
+        let guest = self.guests.get(guest_id)?;
+        if hart == guest.vcpu.hart {
+            let running = self.sched.current == Some(guest_id);
+            Some(guest.vcpu.current_stats(running, time::read()))
+        }else{
+            guest.harts.get(hart).map(|vhart| vhart.stats)
+        }
+    }
+
+    /// guest programs its timer through SBI, `stime` is in guest time
+    pub fn set_guest_timer(&mut self, stime: usize) {
+        fastpath::set_guest_timer(stime);
+    }
+
+    /// end of the current slice of the running guest or of its running hart, the end of
+    /// a window of held device interrupts, the next tick of the guest or its machine timer
+    fn timer_deadline(&self) -> usize {
+        self.sched.slice_deadline().min(self.hart_deadline()).min(self.coalesce_deadline()).min(self.tick_deadline()).min(self.machine_deadline())
+    }
+
+    /// program the physical timer with the earlier of the guest timer and the slice end
+    pub fn program_timer(&mut self) {
+        fastpath::set_slice_deadline(self.timer_deadline());
+        fastpath::program_timer();
+    }
+
+    /// physical timer fired: deliver the guest timer, preemption is left to `schedule`
+    pub fn handle_timer_irq(&mut self) {
+        let now = time::read();
+        if now.wrapping_add(read_htimedelta()) >= fastpath::vtimecmp() {
+            fastpath::set_vtimecmp(usize::MAX);
+            // set guest timer interrupt pending
+            arch::raise_guest_irq(Irq::Timer);
+        }
+        // partial lines of guest output are written out at least once per tick
+        console::flush_guest_output();
+        self.check_rtc_alarm();
+        self.check_watchdog();
+        self.flush_coalesced_irqs();
+        self.check_tick();
+        self.deliver_pending_irq();
+        // remote monitor requests are also picked up here for NICs without an interrupt line
+        #[cfg(feature = "monitor")]
+        monitor::remote::poll(self);
+        self.program_timer();
+    }
+}
@@ -0,0 +1,74 @@
+//! Secure boot of guests
+//!
+//! Built with the `secure_boot` feature, the embedded guest kernel image and device tree
+//! are checked against Ed25519 signatures before the guest is created: `guest.sig` and
+//! `guest.dtb.sig` next to the images, verified with the public key `guest.pub` built into
+//! the hypervisor. All three are raw bytes, 64 bytes per signature and 32 for the key.
+//!
+//! `hvc.secureboot=enforce|warn` selects what a missing or bad signature does: the default
+//! `enforce` refuses to start the guest, `warn` only reports it. Either way the verdict is
+//! kept in `Guest::boot_verdict` and shown by the monitor `info` command.
+
+use crate::hypervisor::fdt::MachineMeta;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BootVerdict {
+    /// built without the `secure_boot` feature
+    Unchecked,
+    /// image and device tree are signed with the embedded key
+    Verified,
+    /// a signature is missing or does not match
+    Rejected
+}
+
+#[cfg(feature = "secure_boot")]
+mod keys {
+    pub static PUBLIC_KEY: [u8; 32] = *include_bytes!("../guest.pub");
+    pub static IMAGE_SIGNATURE: [u8; 64] = *include_bytes!("../guest.sig");
+    pub static DTB_SIGNATURE: [u8; 64] = *include_bytes!("../guest.dtb.sig");
+}
+
+#[cfg(feature = "secure_boot")]
+fn signed(data: &[u8], signature: &[u8; 64]) -> bool {
+    use ed25519_compact::{ PublicKey, Signature };
+    PublicKey::new(keys::PUBLIC_KEY).verify(data, &Signature::new(*signature)).is_ok()
+}
+
+/// check the signatures of the guest `image` and `dtb`
+#[cfg(feature = "secure_boot")]
+pub fn verify(image: &[u8], dtb: &[u8]) -> BootVerdict {
+    if image.is_empty() {
+        hwarning!("secure boot: no embedded guest kernel to verify");
+        return BootVerdict::Rejected
+    }
+    match (signed(image, &keys::IMAGE_SIGNATURE), signed(dtb, &keys::DTB_SIGNATURE)) {
+        (true, true) => BootVerdict::Verified,
+        (image_ok, _) => {
+            herror!("secure boot: bad signature of the guest {}", if image_ok { "device tree" } else { "kernel image" });
+            BootVerdict::Rejected
+        }
+    }
+}
+
+#[cfg(not(feature = "secure_boot"))]
+pub fn verify(_image: &[u8], _dtb: &[u8]) -> BootVerdict {
+    BootVerdict::Unchecked
+}
+
+/// whether a guest with `verdict` may start, following `hvc.secureboot`
+pub fn allowed(machine: &MachineMeta, verdict: BootVerdict) -> bool {
+    if verdict != BootVerdict::Rejected {
+        return true
+    }
+    match machine.bootarg("hvc.secureboot") {
+        Some("warn") => {
+            hwarning!("secure boot: starting the rejected guest, hvc.secureboot=warn");
+            true
+        },
+        Some("enforce") | None => false,
+        Some(_) => {
+            hwarning!("invalid hvc.secureboot, enforcing");
+            false
+        }
+    }
+}
@@ -1,3 +1,5 @@
 mod up;
+mod spinlock;
 
-pub use up::UPSafeCell;
\ No newline at end of file
+pub use up::UPSafeCell;
+pub use spinlock::{ IrqSave, SpinIrqSave, SpinNoIrq, SpinGuard, with_irq_masked };
\ No newline at end of file
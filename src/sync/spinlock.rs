@@ -0,0 +1,232 @@
+//! Spinlocks aware of hypervisor interrupts
+//!
+//! A trap taken while the interrupted code holds a lock the handler also takes spins
+//! forever. `SpinIrqSave` masks supervisor interrupts while it is held and restores the
+//! previous state on release, it can be used from any context. `SpinNoIrq` does not touch
+//! `sstatus` and is meant for locks only taken with interrupts already masked, e.g. in
+//! trap handlers; with lock debugging the caller is checked.
+//!
+//! The lock word records the owner hart. With the `lock_debug` feature a hart taking a
+//! lock it already holds panics, and waiting for or holding a lock too long is reported
+//! on the uart with the location of the caller.
+
+use core::cell::UnsafeCell;
+use core::marker::PhantomData;
+use core::ops::{ Deref, DerefMut };
+use core::panic::Location;
+use core::sync::atomic::{ AtomicUsize, Ordering };
+
+use riscv::register::sstatus;
+
+use crate::percpu;
+
+/// what a lock does with supervisor interrupts
+pub trait IrqPolicy {
+    /// called before the lock is taken, the result is given back to `release`
+    fn acquire() -> bool;
+    fn release(state: bool);
+}
+
+/// mask interrupts while the lock is held
+pub struct IrqSave;
+
+impl IrqPolicy for IrqSave {
+    fn acquire() -> bool {
+        let enabled = sstatus::read().sie();
+        unsafe{ sstatus::clear_sie() };
+        enabled
+    }
+
+    fn release(enabled: bool) {
+        if enabled {
+            unsafe{ sstatus::set_sie() };
+        }
+    }
+}
+
+/// run `f` with supervisor interrupts masked, e.g. to update hart-local data
+pub fn with_irq_masked<R>(f: impl FnOnce() -> R) -> R {
+    let enabled = IrqSave::acquire();
+    let result = f();
+    IrqSave::release(enabled);
+    result
+}
+
+/// the profiler unmasked interrupts in a trap handler, its interrupt handler takes no locks
+#[cfg(feature = "profiler")]
+fn interrupts_sampled() -> bool {
+    crate::profiler::sampling()
+}
+
+#[cfg(not(feature = "profiler"))]
+fn interrupts_sampled() -> bool {
+    false
+}
+
+/// interrupts are masked by the caller
+pub struct NoIrq;
+
+impl IrqPolicy for NoIrq {
+    #[track_caller]
+    fn acquire() -> bool {
+        #[cfg(feature = "lock_debug")]
+        if sstatus::read().sie() && !interrupts_sampled() {
+            panic!("SpinNoIrq taken with interrupts enabled at {}", Location::caller());
+        }
+        false
+    }
+
+    fn release(_state: bool) {}
+}
+
+pub struct BaseSpinLock<I, T: ?Sized> {
+    /// owner hart + 1, 0 if the lock is free
+    owner: AtomicUsize,
+    /// time at which the lock was taken
+    #[cfg(feature = "lock_debug")]
+    since: AtomicUsize,
+    _policy: PhantomData<I>,
+    data: UnsafeCell<T>
+}
+
+pub type SpinIrqSave<T> = BaseSpinLock<IrqSave, T>;
+pub type SpinNoIrq<T> = BaseSpinLock<NoIrq, T>;
+
+unsafe impl<I, T: ?Sized + Send> Sync for BaseSpinLock<I, T> {}
+unsafe impl<I, T: ?Sized + Send> Send for BaseSpinLock<I, T> {}
+
+impl<I, T> BaseSpinLock<I, T> {
+    pub const fn new(data: T) -> Self {
+        Self {
+            owner: AtomicUsize::new(0),
+            #[cfg(feature = "lock_debug")]
+            since: AtomicUsize::new(0),
+            _policy: PhantomData,
+            data: UnsafeCell::new(data)
+        }
+    }
+}
+
+impl<I: IrqPolicy, T: ?Sized> BaseSpinLock<I, T> {
+    #[track_caller]
+    pub fn lock(&self) -> SpinGuard<'_, I, T> {
+        let irq_state = I::acquire();
+        let me = percpu::hart_id() + 1;
+        #[cfg(feature = "lock_debug")]
+        let mut watch = debug::WaitWatch::new();
+        while self.owner.compare_exchange_weak(0, me, Ordering::Acquire, Ordering::Relaxed).is_err() {
+            #[cfg(feature = "lock_debug")]
+            watch.check(self.owner.load(Ordering::Relaxed), me, Location::caller());
+            core::hint::spin_loop();
+        }
+        self.guard(irq_state)
+    }
+
+    /// `None` if the lock is held
+    #[track_caller]
+    pub fn try_lock(&self) -> Option<SpinGuard<'_, I, T>> {
+        let irq_state = I::acquire();
+        let me = percpu::hart_id() + 1;
+        if self.owner.compare_exchange(0, me, Ordering::Acquire, Ordering::Relaxed).is_err() {
+            I::release(irq_state);
+            return None
+        }
+        Some(self.guard(irq_state))
+    }
+
+    #[track_caller]
+    fn guard(&self, irq_state: bool) -> SpinGuard<'_, I, T> {
+        #[cfg(feature = "lock_debug")]
+        self.since.store(riscv::register::time::read(), Ordering::Relaxed);
+        SpinGuard { lock: self, irq_state, location: Location::caller() }
+    }
+
+    /// hart holding the lock
+    pub fn owner(&self) -> Option<usize> {
+        self.owner.load(Ordering::Relaxed).checked_sub(1)
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        self.data.get_mut()
+    }
+}
+
+pub struct SpinGuard<'a, I: IrqPolicy, T: ?Sized> {
+    lock: &'a BaseSpinLock<I, T>,
+    irq_state: bool,
+    /// caller of `lock`
+    location: &'static Location<'static>
+}
+
+impl<I: IrqPolicy, T: ?Sized> Deref for SpinGuard<'_, I, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe{ &*self.lock.data.get() }
+    }
+}
+
+impl<I: IrqPolicy, T: ?Sized> DerefMut for SpinGuard<'_, I, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe{ &mut *self.lock.data.get() }
+    }
+}
+
+impl<I: IrqPolicy, T: ?Sized> Drop for SpinGuard<'_, I, T> {
+    fn drop(&mut self) {
+        #[cfg(feature = "lock_debug")]
+        debug::check_hold(self.lock.since.load(Ordering::Relaxed), self.location);
+        self.lock.owner.store(0, Ordering::Release);
+        I::release(self.irq_state);
+    }
+}
+
+#[cfg(feature = "lock_debug")]
+mod debug {
+    use core::fmt::Write;
+    use core::panic::Location;
+    use riscv::register::time;
+
+    use crate::console::UartWriter;
+    use crate::constants::CLOCK_FREQ;
+
+    /// waiting longer than this for a lock is reported (1s)
+    const WAIT_WARN: usize = CLOCK_FREQ;
+    /// holding a lock longer than this is reported (10ms)
+    const HOLD_WARN: usize = CLOCK_FREQ / 100;
+
+    /// Reports go straight to the uart, the log sink takes locks itself.
+    fn warn(args: core::fmt::Arguments) {
+        UartWriter.write_fmt(format_args!("[Warning] {}\n", args)).unwrap();
+    }
+
+    pub struct WaitWatch {
+        start: Option<usize>,
+        reported: bool
+    }
+
+    impl WaitWatch {
+        pub fn new() -> Self {
+            Self { start: None, reported: false }
+        }
+
+        pub fn check(&mut self, owner: usize, me: usize, location: &Location) {
+            if owner == me {
+                panic!("deadlock: hart {} takes a lock it holds at {}", me - 1, location);
+            }
+            let now = time::read();
+            let start = *self.start.get_or_insert(now);
+            if !self.reported && now - start > WAIT_WARN {
+                self.reported = true;
+                warn(format_args!("hart {} waits for a lock held by hart {} at {}", me - 1, owner.wrapping_sub(1), location));
+            }
+        }
+    }
+
+    pub fn check_hold(since: usize, location: &Location) {
+        let held = time::read() - since;
+        if held > HOLD_WARN {
+            warn(format_args!("lock taken at {} held for {} cycles", location, held));
+        }
+    }
+}